@@ -0,0 +1,168 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use qlog::analysis::Analysis;
+use qlog::analysis::Sample;
+
+const USAGE: &str = "Usage:
+  qlog-analyze [options] FILE...
+  qlog-analyze -h | --help
+
+Options:
+  --csv METRIC          Print the timeline of METRIC as CSV instead of a summary
+                        (cwnd, bytes_in_flight, smoothed_rtt, latest_rtt or min_rtt).
+  --max-loss-rate RATE  Exit with an error if the loss rate of a trace exceeds RATE.
+  -h --help             Show this screen.
+";
+
+fn main() {
+    let args = docopt::Docopt::new(USAGE)
+        .and_then(|dopt| dopt.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let csv = args.get_str("--csv");
+
+    let max_loss_rate = match args.get_str("--max-loss-rate") {
+        "" => None,
+
+        rate => Some(rate.parse::<f64>().unwrap_or_else(|_| {
+            eprintln!("invalid loss rate {}", rate);
+            std::process::exit(2);
+        })),
+    };
+
+    let mut failed = false;
+
+    for path in args.get_vec("FILE") {
+        let file = std::fs::File::open(path).unwrap_or_else(|e| {
+            eprintln!("failed to open {}: {}", path, e);
+            std::process::exit(2);
+        });
+
+        let traces = qlog::reader::read_traces(std::io::BufReader::new(file))
+            .unwrap_or_else(|e| {
+                eprintln!("failed to read {}: {:?}", path, e);
+                std::process::exit(2);
+            });
+
+        for (i, trace) in traces.iter().enumerate() {
+            let analysis = Analysis::from_trace(trace);
+
+            if csv.is_empty() {
+                print_summary(path, i, &analysis);
+            } else {
+                print_csv(path, i, csv, &analysis);
+            }
+
+            if let Some(max) = max_loss_rate {
+                if analysis.loss_rate() > max {
+                    eprintln!(
+                        "{} trace {}: loss rate {:.4} exceeds {:.4}",
+                        path,
+                        i,
+                        analysis.loss_rate(),
+                        max
+                    );
+
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn print_summary(path: &str, index: usize, analysis: &Analysis) {
+    let (sent, recv) = analysis.stream_bytes();
+
+    println!("{} trace {}:", path, index);
+    println!("  duration: {:.3} ms", analysis.duration);
+    println!(
+        "  packets: {} sent, {} received, {} lost ({:.2}%)",
+        analysis.packets_sent,
+        analysis.packets_received,
+        analysis.packets_lost,
+        analysis.loss_rate() * 100.0
+    );
+    println!("  loss episodes: {}", analysis.loss_episodes.len());
+
+    if let Some(cwnd) = analysis.cwnd.iter().map(|s| s.value).max() {
+        println!("  max cwnd: {} bytes", cwnd);
+    }
+
+    if let Some(srtt) = analysis.smoothed_rtt.last() {
+        println!("  final smoothed rtt: {:.3} ms", srtt.value);
+    }
+
+    println!(
+        "  streams: {}, {} bytes sent, {} bytes received",
+        analysis.streams.len(),
+        sent,
+        recv
+    );
+
+    for (id, stream) in &analysis.streams {
+        println!(
+            "    stream {}: {} sent ({} retransmitted), {} received, {:.3}-{:.3} ms",
+            id,
+            stream.bytes_sent,
+            stream.bytes_retransmitted,
+            stream.bytes_received,
+            stream.start.unwrap_or(0.0),
+            stream.end.unwrap_or(0.0)
+        );
+    }
+}
+
+fn print_csv(path: &str, index: usize, metric: &str, analysis: &Analysis) {
+    fn print<T: std::fmt::Display>(
+        path: &str, index: usize, timeline: &[Sample<T>],
+    ) {
+        for s in timeline {
+            println!("{},{},{},{}", path, index, s.time, s.value);
+        }
+    }
+
+    match metric {
+        "cwnd" => print(path, index, &analysis.cwnd),
+
+        "bytes_in_flight" => print(path, index, &analysis.bytes_in_flight),
+
+        "smoothed_rtt" => print(path, index, &analysis.smoothed_rtt),
+
+        "latest_rtt" => print(path, index, &analysis.latest_rtt),
+
+        "min_rtt" => print(path, index, &analysis.min_rtt),
+
+        _ => {
+            eprintln!("unknown metric {}", metric);
+            std::process::exit(2);
+        },
+    }
+}
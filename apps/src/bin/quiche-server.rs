@@ -27,8 +27,6 @@
 #[macro_use]
 extern crate log;
 
-use std::net;
-
 use std::io::prelude::*;
 
 use std::collections::HashMap;
@@ -67,7 +65,8 @@ fn main() {
     // Create the UDP listening socket, and register it with the event loop.
     let mut socket =
         mio::net::UdpSocket::bind(args.listen.parse().unwrap()).unwrap();
-    info!("listening on {:}", socket.local_addr().unwrap());
+    let local_addr = socket.local_addr().unwrap();
+    info!("listening on {:}", local_addr);
 
     poll.registry()
         .register(&mut socket, mio::Token(0), mio::Interest::READABLE)
@@ -133,6 +132,16 @@ fn main() {
     let conn_id_seed =
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
+    // Generate the key used to authenticate address validation tokens.
+    let mut token_key = [0; 16];
+    rng.fill(&mut token_key).unwrap();
+    config.set_address_token_key(&token_key).unwrap();
+
+    // Generate the key used to derive stateless reset tokens.
+    let mut reset_key = [0; 32];
+    rng.fill(&mut reset_key).unwrap();
+    config.set_stateless_reset_key(&reset_key);
+
     let mut clients = ClientMap::new();
 
     let mut pkt_count = 0;
@@ -219,17 +228,43 @@ fn main() {
             let client = if !clients.contains_key(&hdr.dcid) &&
                 !clients.contains_key(&conn_id)
             {
+                if hdr.ty == quiche::Type::Short {
+                    warn!("Sending stateless reset");
+
+                    let len = match quiche::stateless_reset(
+                        &config, &hdr.dcid, len, &mut out,
+                    ) {
+                        Ok(v) => v,
+
+                        // The packet is too small to be answered.
+                        Err(_) => continue 'read,
+                    };
+
+                    let out = &out[..len];
+
+                    if let Err(e) = socket.send_to(out, from) {
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            trace!("send() would block");
+                            break;
+                        }
+
+                        panic!("send() failed: {:?}", e);
+                    }
+                    continue 'read;
+                }
+
                 if hdr.ty != quiche::Type::Initial {
                     error!("Packet is not Initial");
                     continue 'read;
                 }
 
-                if !quiche::version_is_supported(hdr.version) {
+                if !config.version_is_supported(hdr.version) {
                     warn!("Doing version negotiation");
 
-                    let len =
-                        quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out)
-                            .unwrap();
+                    let len = quiche::negotiate_version_with_config(
+                        &config, &hdr.scid, &hdr.dcid, &mut out,
+                    )
+                    .unwrap();
 
                     let out = &out[..len];
 
@@ -253,12 +288,21 @@ fn main() {
                     // Token is always present in Initial packets.
                     let token = hdr.token.as_ref().unwrap();
 
-                    // Do stateless retry if the client didn't send a token.
-                    if token.is_empty() {
+                    let validated_token = if token.is_empty() {
+                        None
+                    } else {
+                        quiche::validate_token(&config, token, from)
+                    };
+
+                    // Do stateless retry if the client didn't send a valid
+                    // token.
+                    if validated_token.is_none() {
                         warn!("Doing stateless retry");
 
                         let scid = quiche::ConnectionId::from_ref(&scid);
-                        let new_token = mint_token(&hdr, &from);
+                        let new_token =
+                            quiche::mint_retry_token(&config, &hdr.dcid, from)
+                                .unwrap();
 
                         let len = quiche::retry(
                             &hdr.scid,
@@ -283,23 +327,22 @@ fn main() {
                         continue 'read;
                     }
 
-                    odcid = validate_token(&from, token);
+                    // The client's address was validated by a token sent in a
+                    // previous connection, so no Retry is needed.
+                    if let Some(quiche::AddressToken::Retry { odcid: v }) =
+                        validated_token
+                    {
+                        if scid.len() != hdr.dcid.len() {
+                            error!("Invalid destination connection ID");
+                            continue 'read;
+                        }
 
-                    // The token was not valid, meaning the retry failed, so
-                    // drop the packet.
-                    if odcid.is_none() {
-                        error!("Invalid address validation token");
-                        continue;
-                    }
+                        // Reuse the source connection ID we sent in the Retry
+                        // packet, instead of changing it again.
+                        scid.copy_from_slice(&hdr.dcid);
 
-                    if scid.len() != hdr.dcid.len() {
-                        error!("Invalid destination connection ID");
-                        continue 'read;
+                        odcid = Some(v);
                     }
-
-                    // Reuse the source connection ID we sent in the Retry
-                    // packet, instead of changing it again.
-                    scid.copy_from_slice(&hdr.dcid);
                 }
 
                 let scid = quiche::ConnectionId::from_vec(scid.to_vec());
@@ -307,9 +350,14 @@ fn main() {
                 debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
 
                 #[allow(unused_mut)]
-                let mut conn =
-                    quiche::accept(&scid, odcid.as_ref(), from, &mut config)
-                        .unwrap();
+                let mut conn = quiche::accept(
+                    &scid,
+                    odcid.as_ref(),
+                    local_addr,
+                    from,
+                    &mut config,
+                )
+                .unwrap();
 
                 if let Some(keylog) = &mut keylog {
                     if let Ok(keylog) = keylog.try_clone() {
@@ -353,7 +401,11 @@ fn main() {
                 }
             };
 
-            let recv_info = quiche::RecvInfo { from };
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+                ecn: quiche::EcnCodepoint::NotEct,
+            };
 
             // Process potentially coalesced packets.
             let read = match client.conn.recv(pkt_buf, recv_info) {
@@ -521,59 +573,3 @@ fn main() {
         });
     }
 }
-
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
-
-/// Validates a stateless retry token.
-///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
@@ -152,10 +152,17 @@ pub fn connect(
 
     let scid = quiche::ConnectionId::from_ref(&scid);
 
+    let local_addr = socket.local_addr().unwrap();
+
     // Create a QUIC connection and initiate handshake.
-    let mut conn =
-        quiche::connect(connect_url.domain(), &scid, peer_addr, &mut config)
-            .unwrap();
+    let mut conn = quiche::connect(
+        connect_url.domain(),
+        &scid,
+        local_addr,
+        peer_addr,
+        &mut config,
+    )
+    .unwrap();
 
     if let Some(keylog) = &mut keylog {
         if let Ok(keylog) = keylog.try_clone() {
@@ -186,9 +193,7 @@ pub fn connect(
 
     info!(
         "connecting to {:} from {:} with scid {:?}",
-        peer_addr,
-        socket.local_addr().unwrap(),
-        scid,
+        peer_addr, local_addr, scid,
     );
 
     let (write, send_info) = conn.send(&mut out).expect("initial send failed");
@@ -258,7 +263,11 @@ pub fn connect(
 
             pkt_count += 1;
 
-            let recv_info = quiche::RecvInfo { from };
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+                ecn: quiche::EcnCodepoint::NotEct,
+            };
 
             // Process potentially coalesced packets.
             let read = match conn.recv(&mut buf[..len], recv_info) {
@@ -1405,6 +1405,18 @@ impl HttpConn for Http3Conn {
                     );
                 },
 
+                Ok((
+                    stream_id,
+                    quiche::h3::Event::PushPromise { push_id, .. },
+                )) => {
+                    info!(
+                        "{} got PUSH_PROMISE with ID {} on stream {}",
+                        conn.trace_id(),
+                        push_id,
+                        stream_id
+                    );
+                },
+
                 Err(quiche::h3::Error::Done) => {
                     break;
                 },
@@ -1587,6 +1599,8 @@ impl HttpConn for Http3Conn {
                         .send_goaway(conn, self.largest_processed_request)?;
                 },
 
+                Ok((_, quiche::h3::Event::PushPromise { .. })) => (),
+
                 Err(quiche::h3::Error::Done) => {
                     break;
                 },
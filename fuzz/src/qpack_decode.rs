@@ -16,16 +16,16 @@ fuzz_target!(|data: &[u8]| {
     let mut decoder = quiche::h3::qpack::Decoder::new();
     let mut encoder = quiche::h3::qpack::Encoder::new();
 
-    let hdrs = match decoder.decode(&mut data.to_vec(), std::u64::MAX) {
+    let hdrs = match decoder.decode(0, &mut data.to_vec(), std::u64::MAX) {
         Err(_) => return,
         Ok(hdrs) => hdrs,
     };
 
     let mut encoded_hdrs = vec![0; data.len() * 10 + 1000];
-    let encoded_size = encoder.encode(&hdrs, &mut encoded_hdrs).unwrap();
+    let encoded_size = encoder.encode(0, &hdrs, &mut encoded_hdrs).unwrap();
 
     let decoded_hdrs = decoder
-        .decode(&encoded_hdrs[..encoded_size], std::u64::MAX)
+        .decode(0, &encoded_hdrs[..encoded_size], std::u64::MAX)
         .unwrap();
 
     let mut expected_hdrs = Vec::new();
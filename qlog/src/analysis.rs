@@ -0,0 +1,428 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Offline analysis of qlog traces.
+//!
+//! An [`Analysis`] rebuilds the evolution of a connection from the events of
+//! a trace, such as the congestion window, RTT and bytes in flight timelines,
+//! the progress of each stream and the episodes of packet loss. This makes it
+//! possible to compare traces collected from different runs, for example to
+//! detect performance regressions.
+//!
+//! [`Analysis`]: struct.Analysis.html
+
+use std::collections::BTreeMap;
+
+use crate::events::quic::QuicFrame;
+use crate::events::Event;
+use crate::events::EventData;
+use crate::Trace;
+
+/// The maximum time between two lost packets of the same loss episode, in
+/// milliseconds, used until an RTT sample is available. This matches QUIC's
+/// initial RTT.
+const DEFAULT_EPISODE_GAP: f32 = 333.0;
+
+/// A single value of a timeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample<T> {
+    /// The time of the sample, in milliseconds, as found in the trace.
+    pub time: f32,
+
+    /// The value of the metric.
+    pub value: T,
+}
+
+/// The progress of a single stream.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamProgress {
+    /// The highest offset of data sent on the stream.
+    pub bytes_sent: u64,
+
+    /// The amount of data that was sent again below the highest offset.
+    pub bytes_retransmitted: u64,
+
+    /// The highest offset of data received on the stream.
+    pub bytes_received: u64,
+
+    /// Whether the end of the stream was sent.
+    pub fin_sent: bool,
+
+    /// Whether the end of the stream was received.
+    pub fin_received: bool,
+
+    /// The time of the first frame sent or received on the stream.
+    pub start: Option<f32>,
+
+    /// The time of the last frame sent or received on the stream.
+    pub end: Option<f32>,
+}
+
+impl StreamProgress {
+    fn on_frame(&mut self, time: f32) {
+        self.start.get_or_insert(time);
+        self.end = Some(time);
+    }
+}
+
+/// A run of packet losses close to each other in time.
+///
+/// Losses belong to the same episode when they are declared less than one
+/// smoothed RTT apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LossEpisode {
+    /// The time the first packet of the episode was declared lost.
+    pub start: f32,
+
+    /// The time the last packet of the episode was declared lost.
+    pub end: f32,
+
+    /// The packet numbers of the lost packets.
+    pub packets: Vec<u64>,
+}
+
+/// The timelines and statistics of a connection, rebuilt from its trace.
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    /// The congestion window, in bytes.
+    pub cwnd: Vec<Sample<u64>>,
+
+    /// The number of bytes in flight.
+    pub bytes_in_flight: Vec<Sample<u64>>,
+
+    /// The smoothed RTT, in milliseconds.
+    pub smoothed_rtt: Vec<Sample<f32>>,
+
+    /// The latest RTT sample, in milliseconds.
+    pub latest_rtt: Vec<Sample<f32>>,
+
+    /// The minimum RTT, in milliseconds.
+    pub min_rtt: Vec<Sample<f32>>,
+
+    /// The progress of each stream, by stream ID.
+    pub streams: BTreeMap<u64, StreamProgress>,
+
+    /// The loss episodes, in chronological order.
+    pub loss_episodes: Vec<LossEpisode>,
+
+    /// The number of packets sent.
+    pub packets_sent: u64,
+
+    /// The number of packets received.
+    pub packets_received: u64,
+
+    /// The number of packets declared lost.
+    pub packets_lost: u64,
+
+    /// The time of the last event of the trace.
+    pub duration: f32,
+}
+
+impl Analysis {
+    /// Analyzes the events of the given trace.
+    pub fn from_trace(trace: &Trace) -> Analysis {
+        Analysis::from_events(&trace.events)
+    }
+
+    /// Analyzes a sequence of events, in the order they were logged.
+    pub fn from_events<'a, I>(events: I) -> Analysis
+    where
+        I: IntoIterator<Item = &'a Event>,
+    {
+        let mut analysis = Analysis::default();
+
+        for ev in events {
+            analysis.process(ev);
+        }
+
+        analysis
+    }
+
+    /// Returns the ratio of lost packets over sent packets.
+    pub fn loss_rate(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+
+        self.packets_lost as f64 / self.packets_sent as f64
+    }
+
+    /// Returns the total amount of stream data sent and received.
+    pub fn stream_bytes(&self) -> (u64, u64) {
+        self.streams.values().fold((0, 0), |(sent, recv), s| {
+            (sent + s.bytes_sent, recv + s.bytes_received)
+        })
+    }
+
+    fn process(&mut self, ev: &Event) {
+        let time = ev.time;
+
+        self.duration = self.duration.max(time);
+
+        match &ev.data {
+            EventData::MetricsUpdated(m) => {
+                push_sample(&mut self.cwnd, time, m.congestion_window);
+                push_sample(&mut self.bytes_in_flight, time, m.bytes_in_flight);
+                push_sample(&mut self.smoothed_rtt, time, m.smoothed_rtt);
+                push_sample(&mut self.latest_rtt, time, m.latest_rtt);
+                push_sample(&mut self.min_rtt, time, m.min_rtt);
+            },
+
+            EventData::PacketSent(pkt) => {
+                self.packets_sent += 1;
+
+                for frame in pkt.frames.iter().flatten() {
+                    self.on_stream_frame(frame, time, true);
+                }
+            },
+
+            EventData::PacketReceived(pkt) => {
+                self.packets_received += 1;
+
+                for frame in pkt.frames.iter().flatten() {
+                    self.on_stream_frame(frame, time, false);
+                }
+            },
+
+            EventData::PacketLost(pkt) => {
+                self.packets_lost += 1;
+
+                let pkt_num = pkt.header.as_ref().map(|h| h.packet_number);
+
+                self.on_packet_lost(pkt_num, time);
+            },
+
+            _ => (),
+        }
+    }
+
+    fn on_stream_frame(&mut self, frame: &QuicFrame, time: f32, sent: bool) {
+        let (stream_id, offset, length, fin) = match frame {
+            QuicFrame::Stream {
+                stream_id,
+                offset,
+                length,
+                fin,
+                ..
+            } => (*stream_id, *offset, *length, fin.unwrap_or(false)),
+
+            _ => return,
+        };
+
+        let stream = self.streams.entry(stream_id).or_default();
+
+        stream.on_frame(time);
+
+        let end = offset + length;
+
+        if sent {
+            if offset < stream.bytes_sent {
+                stream.bytes_retransmitted +=
+                    std::cmp::min(end, stream.bytes_sent) - offset;
+            }
+
+            stream.bytes_sent = std::cmp::max(stream.bytes_sent, end);
+            stream.fin_sent |= fin;
+        } else {
+            stream.bytes_received = std::cmp::max(stream.bytes_received, end);
+            stream.fin_received |= fin;
+        }
+    }
+
+    fn on_packet_lost(&mut self, pkt_num: Option<u64>, time: f32) {
+        let gap = self
+            .smoothed_rtt
+            .last()
+            .map(|s| s.value)
+            .unwrap_or(DEFAULT_EPISODE_GAP);
+
+        match self.loss_episodes.last_mut() {
+            Some(episode) if time - episode.end <= gap => {
+                episode.end = time;
+                episode.packets.extend(pkt_num);
+            },
+
+            _ => self.loss_episodes.push(LossEpisode {
+                start: time,
+                end: time,
+                packets: pkt_num.into_iter().collect(),
+            }),
+        }
+    }
+}
+
+fn push_sample<T>(timeline: &mut Vec<Sample<T>>, time: f32, value: Option<T>) {
+    if let Some(value) = value {
+        timeline.push(Sample { time, value });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::quic;
+    use crate::events::quic::PacketType;
+    use crate::testing::*;
+
+    fn metrics(time: f32, cwnd: u64, srtt: f32) -> Event {
+        Event::with_time(
+            time,
+            EventData::MetricsUpdated(quic::MetricsUpdated {
+                min_rtt: None,
+                smoothed_rtt: Some(srtt),
+                latest_rtt: None,
+                rtt_variance: None,
+                pto_count: None,
+                congestion_window: Some(cwnd),
+                bytes_in_flight: None,
+                ssthresh: None,
+                packets_in_flight: None,
+                pacing_rate: None,
+            }),
+        )
+    }
+
+    fn stream_sent(time: f32, offset: u64, length: u64, fin: bool) -> Event {
+        Event::with_time(
+            time,
+            EventData::PacketSent(quic::PacketSent {
+                header: make_pkt_hdr(PacketType::OneRtt),
+                frames: Some(vec![quic::QuicFrame::Stream {
+                    stream_id: 4,
+                    offset,
+                    length,
+                    fin: Some(fin),
+                    raw: None,
+                }]),
+                is_coalesced: None,
+                retry_token: None,
+                stateless_reset_token: None,
+                supported_versions: None,
+                raw: None,
+                datagram_id: None,
+                trigger: None,
+            }),
+        )
+    }
+
+    fn lost(time: f32, pkt_num: u64) -> Event {
+        Event::with_time(
+            time,
+            EventData::PacketLost(quic::PacketLost {
+                header: Some(quic::PacketHeader::with_type(
+                    PacketType::OneRtt,
+                    pkt_num,
+                    None,
+                    None,
+                    None,
+                )),
+                frames: None,
+                trigger: None,
+            }),
+        )
+    }
+
+    #[test]
+    fn timelines() {
+        let mut trace = make_trace();
+        trace.push_event(metrics(0.0, 12000, 100.0));
+        trace.push_event(metrics(50.0, 24000, 80.0));
+
+        let analysis = Analysis::from_trace(&trace);
+
+        assert_eq!(analysis.cwnd, vec![
+            Sample {
+                time: 0.0,
+                value: 12000
+            },
+            Sample {
+                time: 50.0,
+                value: 24000
+            },
+        ]);
+        assert_eq!(analysis.smoothed_rtt.len(), 2);
+        assert!(analysis.bytes_in_flight.is_empty());
+        assert_eq!(analysis.duration, 50.0);
+    }
+
+    #[test]
+    fn stream_progress() {
+        let events = vec![
+            stream_sent(1.0, 0, 1000, false),
+            stream_sent(2.0, 1000, 1000, false),
+            stream_sent(3.0, 500, 1000, false),
+            stream_sent(4.0, 2000, 100, true),
+        ];
+
+        let analysis = Analysis::from_events(&events);
+
+        assert_eq!(analysis.packets_sent, 4);
+        assert_eq!(analysis.streams[&4], StreamProgress {
+            bytes_sent: 2100,
+            bytes_retransmitted: 1000,
+            bytes_received: 0,
+            fin_sent: true,
+            fin_received: false,
+            start: Some(1.0),
+            end: Some(4.0),
+        });
+        assert_eq!(analysis.stream_bytes(), (2100, 0));
+    }
+
+    #[test]
+    fn loss_episodes() {
+        let events = vec![
+            stream_sent(0.0, 0, 10, false),
+            stream_sent(0.0, 10, 10, false),
+            stream_sent(0.0, 20, 10, false),
+            stream_sent(0.0, 30, 10, false),
+            lost(10.0, 1),
+            lost(200.0, 2),
+            // The RTT sample shrinks the episode gap.
+            metrics(300.0, 12000, 50.0),
+            lost(400.0, 3),
+            lost(420.0, 4),
+        ];
+
+        let analysis = Analysis::from_events(&events);
+
+        assert_eq!(analysis.packets_lost, 4);
+        assert_eq!(analysis.loss_rate(), 1.0);
+        assert_eq!(analysis.loss_episodes, vec![
+            LossEpisode {
+                start: 10.0,
+                end: 200.0,
+                packets: vec![1, 2],
+            },
+            LossEpisode {
+                start: 400.0,
+                end: 420.0,
+                packets: vec![3, 4],
+            },
+        ]);
+    }
+}
@@ -118,6 +118,12 @@ impl Event {
     pub fn importance(&self) -> EventImportance {
         self.ty.into()
     }
+
+    /// Restores the event type from the event data, as it is not part of the
+    /// serialized event.
+    pub(crate) fn restore_type(&mut self) {
+        self.ty = EventType::from(&self.data);
+    }
 }
 
 impl PartialEq for Event {
@@ -165,6 +171,9 @@ impl From<EventType> for EventImportance {
             EventType::ConnectivityEventType(
                 ConnectivityEventType::ConnectionStarted,
             ) => EventImportance::Base,
+            EventType::ConnectivityEventType(
+                ConnectivityEventType::ConnectionClosed,
+            ) => EventImportance::Base,
             EventType::ConnectivityEventType(
                 ConnectivityEventType::ConnectionIdUpdated,
             ) => EventImportance::Base,
@@ -180,8 +189,17 @@ impl From<EventType> for EventImportance {
             EventType::SecurityEventType(SecurityEventType::KeyRetired) =>
                 EventImportance::Base,
 
+            EventType::TransportEventType(
+                TransportEventType::VersionInformation,
+            ) => EventImportance::Core,
+            EventType::TransportEventType(
+                TransportEventType::AlpnInformation,
+            ) => EventImportance::Core,
             EventType::TransportEventType(TransportEventType::ParametersSet) =>
                 EventImportance::Core,
+            EventType::TransportEventType(
+                TransportEventType::ParametersRestored,
+            ) => EventImportance::Base,
             EventType::TransportEventType(
                 TransportEventType::DatagramsReceived,
             ) => EventImportance::Extra,
@@ -198,6 +216,8 @@ impl From<EventType> for EventImportance {
                 EventImportance::Base,
             EventType::TransportEventType(TransportEventType::PacketBuffered) =>
                 EventImportance::Base,
+            EventType::TransportEventType(TransportEventType::PacketsAcked) =>
+                EventImportance::Extra,
             EventType::TransportEventType(
                 TransportEventType::StreamStateUpdated,
             ) => EventImportance::Base,
@@ -224,6 +244,8 @@ impl From<EventType> for EventImportance {
 
             EventType::Http3EventType(Http3EventType::ParametersSet) =>
                 EventImportance::Base,
+            EventType::Http3EventType(Http3EventType::ParametersRestored) =>
+                EventImportance::Base,
             EventType::Http3EventType(Http3EventType::StreamTypeSet) =>
                 EventImportance::Base,
             EventType::Http3EventType(Http3EventType::FrameCreated) =>
@@ -521,7 +543,7 @@ pub enum EventData {
     #[serde(rename = "transport:packet_buffered")]
     PacketBuffered(quic::PacketBuffered),
 
-    #[serde(rename = "transport:version_information")]
+    #[serde(rename = "transport:packets_acked")]
     PacketsAcked(quic::PacketsAcked),
 
     #[serde(rename = "transport:stream_state_updated")]
@@ -62,7 +62,7 @@ pub struct KeyUpdated {
     pub key_type: KeyType,
 
     pub old: Option<Bytes>,
-    pub new: Bytes,
+    pub new: Option<Bytes>,
 
     pub generation: Option<u32>,
 
@@ -250,7 +250,8 @@
 //! Create an object with the [`Write`] trait:
 //!
 //! ```
-//! let mut file = std::fs::File::create("foo.sqlog").unwrap();
+//! let path = std::env::temp_dir().join("foo.sqlog");
+//! let mut file = std::fs::File::create(path).unwrap();
 //! ```
 //!
 //! Create a [`QlogStreamer`] and start serialization to foo.sqlog
@@ -271,7 +272,8 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.sqlog").unwrap();
+//! # let path = std::env::temp_dir().join("foo.sqlog");
+//! # let mut file = std::fs::File::create(path).unwrap();
 //! let mut streamer = qlog::streamer::QlogStreamer::new(
 //!     qlog::QLOG_VERSION.to_string(),
 //!     Some("Example qlog".to_string()),
@@ -308,7 +310,8 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.qlog").unwrap();
+//! # let path = std::env::temp_dir().join("foo.qlog");
+//! # let mut file = std::fs::File::create(path).unwrap();
 //! # let mut streamer = qlog::streamer::QlogStreamer::new(
 //! #     qlog::QLOG_VERSION.to_string(),
 //! #     Some("Example qlog".to_string()),
@@ -370,7 +373,8 @@
 //! #    }),
 //! #    None,
 //! # );
-//! # let mut file = std::fs::File::create("foo.qlog").unwrap();
+//! # let path = std::env::temp_dir().join("foo.qlog");
+//! # let mut file = std::fs::File::create(path).unwrap();
 //! # let mut streamer = qlog::streamer::QlogStreamer::new(
 //! #     qlog::QLOG_VERSION.to_string(),
 //! #     Some("Example qlog".to_string()),
@@ -964,5 +968,7 @@ mod tests {
     }
 }
 
+pub mod analysis;
 pub mod events;
+pub mod reader;
 pub mod streamer;
@@ -0,0 +1,296 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Deserialization of qlog files.
+//!
+//! Both the JSON format, holding a complete [`Qlog`], and the JSON-SEQ format
+//! written by [`QlogStreamer`] can be loaded back into the crate's data model.
+//! [`read_traces()`] detects the format automatically, while
+//! [`QlogSeqReader`] reads JSON-SEQ files one event at a time, so that large
+//! traces don't need to be held in memory.
+//!
+//! [`Qlog`]: ../struct.Qlog.html
+//! [`QlogStreamer`]: ../streamer/struct.QlogStreamer.html
+//! [`read_traces()`]: fn.read_traces.html
+//! [`QlogSeqReader`]: struct.QlogSeqReader.html
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+
+use crate::events::Event;
+use crate::Error;
+use crate::Qlog;
+use crate::QlogSeq;
+use crate::Result;
+use crate::Trace;
+
+/// The record separator that starts every JSON-SEQ record.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// The serialization format of a qlog file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A single JSON object containing all traces.
+    Json,
+
+    /// A sequence of JSON records, as defined in RFC 7464.
+    JsonSeq,
+}
+
+/// Reads the events of a JSON-SEQ qlog file one record at a time.
+///
+/// The first record, holding the log and trace metadata, is parsed when the
+/// reader is created. Events are then returned by iterating over the reader.
+///
+/// As required by RFC 7464, a truncated final record, for example because
+/// the writing application crashed, is silently ignored.
+pub struct QlogSeqReader<R: BufRead> {
+    /// The log and trace metadata.
+    pub qlog: QlogSeq,
+
+    reader: R,
+}
+
+impl<R: BufRead> QlogSeqReader<R> {
+    /// Creates a reader and parses the header record of the log.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let header = next_record(&mut reader)?.ok_or(Error::InvalidFormat)?;
+
+        let qlog =
+            serde_json::from_slice(&header).map_err(|_| Error::InvalidFormat)?;
+
+        Ok(QlogSeqReader { qlog, reader })
+    }
+
+    /// Reads the remaining events and returns them along with the trace
+    /// metadata as a single [`Trace`].
+    ///
+    /// [`Trace`]: ../struct.Trace.html
+    pub fn into_trace(self) -> Result<Trace> {
+        let trace = self.qlog.trace.clone();
+
+        let events = self.collect::<Result<Vec<Event>>>()?;
+
+        Ok(Trace {
+            vantage_point: trace.vantage_point,
+            title: trace.title,
+            description: trace.description,
+            configuration: trace.configuration,
+            common_fields: trace.common_fields,
+            events,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for QlogSeqReader<R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match next_record(&mut self.reader) {
+            Ok(Some(v)) => v,
+
+            Ok(None) => return None,
+
+            Err(e) => return Some(Err(e)),
+        };
+
+        Some(parse_event(&record))
+    }
+}
+
+/// Detects the format of a qlog file from its first bytes.
+pub fn detect_format<R: BufRead>(reader: &mut R) -> Result<Format> {
+    loop {
+        let buf = reader.fill_buf()?;
+
+        let first = match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(v) => v,
+
+            None if buf.is_empty() => return Err(Error::InvalidFormat),
+
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+                continue;
+            },
+        };
+
+        let format = match buf[first] {
+            RECORD_SEPARATOR => Format::JsonSeq,
+
+            b'{' => Format::Json,
+
+            _ => return Err(Error::InvalidFormat),
+        };
+
+        reader.consume(first);
+
+        return Ok(format);
+    }
+}
+
+/// Reads all the traces of a qlog file, in either JSON or JSON-SEQ format.
+///
+/// A JSON-SEQ file always contains a single trace.
+pub fn read_traces<R: Read>(reader: R) -> Result<Vec<Trace>> {
+    let mut reader = BufReader::new(reader);
+
+    match detect_format(&mut reader)? {
+        Format::Json => {
+            let qlog: Qlog = serde_json::from_reader(reader)
+                .map_err(|_| Error::InvalidFormat)?;
+
+            let mut traces = qlog.traces;
+
+            for ev in traces.iter_mut().flat_map(|t| t.events.iter_mut()) {
+                ev.restore_type();
+            }
+
+            Ok(traces)
+        },
+
+        Format::JsonSeq => Ok(vec![QlogSeqReader::new(reader)?.into_trace()?]),
+    }
+}
+
+/// Returns the next complete JSON-SEQ record, without its framing.
+fn next_record<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    loop {
+        let mut record = Vec::new();
+
+        if reader.read_until(RECORD_SEPARATOR, &mut record)? == 0 {
+            return Ok(None);
+        }
+
+        if record.last() == Some(&RECORD_SEPARATOR) {
+            record.pop();
+        }
+
+        // A record that isn't terminated by a line feed was truncated.
+        if record.last() != Some(&b'\n') {
+            if record.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+
+            return Ok(None);
+        }
+
+        return Ok(Some(record));
+    }
+}
+
+fn parse_event(record: &[u8]) -> Result<Event> {
+    let mut ev: Event =
+        serde_json::from_slice(record).map_err(|_| Error::InvalidFormat)?;
+
+    ev.restore_type();
+
+    Ok(ev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::EventData;
+    use crate::events::EventImportance;
+
+    const SEQ_LOG: &str = "\u{1e}{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"trace\":{\"vantage_point\":{\"type\":\"client\"}}}\n\
+        \u{1e}{\"time\":0.0,\"name\":\"recovery:metrics_updated\",\"data\":{\"smoothed_rtt\":10.0,\"congestion_window\":12000}}\n\
+        \u{1e}{\"time\":1.5,\"name\":\"transport:packet_sent\",\"data\":{\"header\":{\"packet_type\":\"1RTT\",\"packet_number\":3},\"frames\":[{\"frame_type\":\"ping\"}]}}\n";
+
+    #[test]
+    fn read_json_seq() {
+        let mut reader = QlogSeqReader::new(SEQ_LOG.as_bytes()).unwrap();
+
+        assert_eq!(reader.qlog.qlog_format, "JSON-SEQ");
+
+        let ev = reader.next().unwrap().unwrap();
+        assert!(matches!(ev.data, EventData::MetricsUpdated(_)));
+        assert!(matches!(ev.importance(), EventImportance::Core));
+
+        let ev = reader.next().unwrap().unwrap();
+        assert_eq!(ev.time, 1.5);
+        assert!(matches!(ev.data, EventData::PacketSent(_)));
+
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn read_json_seq_truncated() {
+        let log = format!("{}\u{1e}{{\"time\":2.0,\"name\":\"trans", SEQ_LOG);
+
+        let traces = read_traces(log.as_bytes()).unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].events.len(), 2);
+
+        // Invalid records in the middle of the log are errors though.
+        let log = SEQ_LOG.replacen("\"time\":0.0", "\"tim\":0.0", 1);
+        assert!(matches!(
+            read_traces(log.as_bytes()),
+            Err(Error::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn read_json() {
+        let mut trace = crate::testing::make_trace();
+        trace.push_event(Event::with_time(4.0, EventData::Message {
+            message: "hello".to_string(),
+        }));
+
+        let qlog = Qlog {
+            qlog_version: crate::QLOG_VERSION.to_string(),
+            qlog_format: "JSON".to_string(),
+            title: None,
+            description: None,
+            summary: None,
+            traces: vec![trace.clone()],
+        };
+
+        let log = serde_json::to_string_pretty(&qlog).unwrap();
+
+        let mut reader = BufReader::new(log.as_bytes());
+        assert_eq!(detect_format(&mut reader).unwrap(), Format::Json);
+
+        assert_eq!(read_traces(log.as_bytes()).unwrap(), vec![trace]);
+    }
+
+    #[test]
+    fn read_invalid() {
+        assert!(matches!(read_traces(&b""[..]), Err(Error::InvalidFormat)));
+        assert!(matches!(
+            read_traces(&b"  \n"[..]),
+            Err(Error::InvalidFormat)
+        ));
+        assert!(matches!(read_traces(&b"[]"[..]), Err(Error::InvalidFormat)));
+        assert!(matches!(
+            QlogSeqReader::new(&b"\x1e{}\n"[..]),
+            Err(Error::InvalidFormat)
+        ));
+    }
+}
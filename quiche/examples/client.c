@@ -51,6 +51,9 @@ struct conn_io {
 
     int sock;
 
+    struct sockaddr_storage local_addr;
+    socklen_t local_addr_len;
+
     quiche_conn *conn;
 };
 
@@ -122,8 +125,10 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
 
         quiche_recv_info recv_info = {
             (struct sockaddr *) &peer_addr,
-
             peer_addr_len,
+
+            (struct sockaddr *) &conn_io->local_addr,
+            conn_io->local_addr_len,
         };
 
         ssize_t done = quiche_conn_recv(conn_io->conn, buf, read, &recv_info);
@@ -282,7 +287,18 @@ int main(int argc, char *argv[]) {
         return -1;
     }
 
+    struct sockaddr_storage local_addr;
+    socklen_t local_addr_len = sizeof(local_addr);
+    if (getsockname(sock, (struct sockaddr *) &local_addr,
+                    &local_addr_len) != 0)
+    {
+        perror("failed to get local address of socket");
+        return -1;
+    };
+
     quiche_conn *conn = quiche_connect(host, (const uint8_t*) scid, sizeof(scid),
+                                       (struct sockaddr *) &local_addr,
+                                       local_addr_len,
                                        peer->ai_addr, peer->ai_addrlen, config);
 
     if (conn == NULL) {
@@ -299,6 +315,9 @@ int main(int argc, char *argv[]) {
     conn_io->sock = sock;
     conn_io->conn = conn;
 
+    memcpy(&conn_io->local_addr, &local_addr, local_addr_len);
+    conn_io->local_addr_len = local_addr_len;
+
     ev_io watcher;
 
     struct ev_loop *loop = ev_default_loop(0);
@@ -53,6 +53,9 @@ struct conn_io {
 
     int sock;
 
+    struct sockaddr_storage local_addr;
+    socklen_t local_addr_len;
+
     quiche_conn *conn;
 
     quiche_h3_conn *http3;
@@ -144,8 +147,10 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
 
         quiche_recv_info recv_info = {
             (struct sockaddr *) &peer_addr,
-
             peer_addr_len,
+
+            (struct sockaddr *) &conn_io->local_addr,
+            conn_io->local_addr_len,
         };
 
         ssize_t done = quiche_conn_recv(conn_io->conn, buf, read, &recv_info);
@@ -315,6 +320,9 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
                     fprintf(stderr, "got GOAWAY\n");
                     break;
                 }
+
+                case QUICHE_H3_EVENT_PUSH_PROMISE:
+                    break;
             }
 
             quiche_h3_event_free(ev);
@@ -414,7 +422,18 @@ int main(int argc, char *argv[]) {
         return -1;
     }
 
+    struct sockaddr_storage local_addr;
+    socklen_t local_addr_len = sizeof(local_addr);
+    if (getsockname(sock, (struct sockaddr *) &local_addr,
+                    &local_addr_len) != 0)
+    {
+        perror("failed to get local address of socket");
+        return -1;
+    };
+
     quiche_conn *conn = quiche_connect(host, (const uint8_t*) scid, sizeof(scid),
+                                       (struct sockaddr *) &local_addr,
+                                       local_addr_len,
                                        peer->ai_addr, peer->ai_addrlen, config);
 
     if (conn == NULL) {
@@ -430,6 +449,9 @@ int main(int argc, char *argv[]) {
 
     conn_io->sock = sock;
     conn_io->conn = conn;
+
+    memcpy(&conn_io->local_addr, &local_addr, local_addr_len);
+    conn_io->local_addr_len = local_addr_len;
     conn_io->host = host;
 
     ev_io watcher;
@@ -103,14 +103,17 @@ fn main() {
 
     let scid = quiche::ConnectionId::from_ref(&scid);
 
+    let local_addr = socket.local_addr().unwrap();
+
     // Create a QUIC connection and initiate handshake.
     let mut conn =
-        quiche::connect(url.domain(), &scid, peer_addr, &mut config).unwrap();
+        quiche::connect(url.domain(), &scid, local_addr, peer_addr, &mut config)
+            .unwrap();
 
     info!(
         "connecting to {:} from {:} with scid {}",
         peer_addr,
-        socket.local_addr().unwrap(),
+        local_addr,
         hex_dump(&scid)
     );
 
@@ -186,7 +189,11 @@ fn main() {
 
             debug!("got {} bytes", len);
 
-            let recv_info = quiche::RecvInfo { from };
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+                ecn: quiche::EcnCodepoint::NotEct,
+            };
 
             // Process potentially coalesced packets.
             let read = match conn.recv(&mut buf[..len], recv_info) {
@@ -281,6 +288,9 @@ fn main() {
                         info!("GOAWAY id={}", goaway_id);
                     },
 
+                    Ok((_, quiche::h3::Event::PushPromise { .. })) =>
+                        unreachable!(),
+
                     Err(quiche::h3::Error::Done) => {
                         break;
                     },
@@ -55,6 +55,9 @@
 struct connections {
     int sock;
 
+    struct sockaddr *local_addr;
+    socklen_t local_addr_len;
+
     struct conn_io *h;
 };
 
@@ -193,6 +196,8 @@ static struct conn_io *create_conn(uint8_t *scid, size_t scid_len,
 
     quiche_conn *conn = quiche_accept(conn_io->cid, LOCAL_CONN_ID_LEN,
                                       odcid, odcid_len,
+                                      conns->local_addr,
+                                      conns->local_addr_len,
                                       (struct sockaddr *) peer_addr,
                                       peer_addr_len,
                                       config);
@@ -356,8 +361,10 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
 
         quiche_recv_info recv_info = {
             (struct sockaddr *) &peer_addr,
-
             peer_addr_len,
+
+            conns->local_addr,
+            conns->local_addr_len,
         };
 
         ssize_t done = quiche_conn_recv(conn_io->conn, buf, read, &recv_info);
@@ -455,6 +462,9 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
                         fprintf(stderr, "got GOAWAY\n");
                         break;
                     }
+
+                    case QUICHE_H3_EVENT_PUSH_PROMISE:
+                        break;
                 }
 
                 quiche_h3_event_free(ev);
@@ -575,6 +585,8 @@ int main(int argc, char *argv[]) {
     struct connections c;
     c.sock = sock;
     c.h = NULL;
+    c.local_addr = local->ai_addr;
+    c.local_addr_len = local->ai_addrlen;
 
     conns = &c;
 
@@ -27,8 +27,6 @@
 #[macro_use]
 extern crate log;
 
-use std::net;
-
 use std::collections::HashMap;
 
 use ring::rand::*;
@@ -76,6 +74,8 @@ fn main() {
     // Create the UDP listening socket, and register it with the event loop.
     let mut socket =
         mio::net::UdpSocket::bind("127.0.0.1:4433".parse().unwrap()).unwrap();
+
+    let local_addr = socket.local_addr().unwrap();
     poll.registry()
         .register(&mut socket, mio::Token(0), mio::Interest::READABLE)
         .unwrap();
@@ -112,6 +112,11 @@ fn main() {
     let conn_id_seed =
         ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
 
+    // Generate the key used to authenticate address validation tokens.
+    let mut token_key = [0; 16];
+    rng.fill(&mut token_key).unwrap();
+    config.set_address_token_key(&token_key).unwrap();
+
     let mut clients = ClientMap::new();
 
     loop {
@@ -216,7 +221,9 @@ fn main() {
                 if token.is_empty() {
                     warn!("Doing stateless retry");
 
-                    let new_token = mint_token(&hdr, &from);
+                    let new_token =
+                        quiche::mint_retry_token(&config, &hdr.dcid, from)
+                            .unwrap();
 
                     let len = quiche::retry(
                         &hdr.scid,
@@ -241,14 +248,16 @@ fn main() {
                     continue 'read;
                 }
 
-                let odcid = validate_token(&from, token);
+                let odcid = match quiche::validate_token(&config, token, from) {
+                    Some(quiche::AddressToken::Retry { odcid }) => Some(odcid),
 
-                // The token was not valid, meaning the retry failed, so
-                // drop the packet.
-                if odcid.is_none() {
-                    error!("Invalid address validation token");
-                    continue 'read;
-                }
+                    // The token was not valid, meaning the retry failed, so
+                    // drop the packet.
+                    _ => {
+                        error!("Invalid address validation token");
+                        continue 'read;
+                    },
+                };
 
                 if scid.len() != hdr.dcid.len() {
                     error!("Invalid destination connection ID");
@@ -261,9 +270,14 @@ fn main() {
 
                 debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
 
-                let conn =
-                    quiche::accept(&scid, odcid.as_ref(), from, &mut config)
-                        .unwrap();
+                let conn = quiche::accept(
+                    &scid,
+                    odcid.as_ref(),
+                    local_addr,
+                    from,
+                    &mut config,
+                )
+                .unwrap();
 
                 let client = Client {
                     conn,
@@ -282,7 +296,11 @@ fn main() {
                 }
             };
 
-            let recv_info = quiche::RecvInfo { from };
+            let recv_info = quiche::RecvInfo {
+                to: local_addr,
+                from,
+                ecn: quiche::EcnCodepoint::NotEct,
+            };
 
             // Process potentially coalesced packets.
             let read = match client.conn.recv(pkt_buf, recv_info) {
@@ -366,6 +384,8 @@ fn main() {
 
                         Ok((_goaway_id, quiche::h3::Event::GoAway)) => (),
 
+                        Ok((_, quiche::h3::Event::PushPromise { .. })) => (),
+
                         Err(quiche::h3::Error::Done) => {
                             break;
                         },
@@ -435,62 +455,6 @@ fn main() {
     }
 }
 
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
-
-/// Validates a stateless retry token.
-///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
-
 /// Handles incoming HTTP/3 requests.
 fn handle_request(
     client: &mut Client, stream_id: u64, headers: &[quiche::h3::Header],
@@ -61,7 +61,7 @@ fn main() {
         if line.is_empty() {
             let mut out = [0u8; 65535];
 
-            let len = enc.encode(&headers, &mut out).unwrap();
+            let len = enc.encode(stream_id, &headers, &mut out).unwrap();
 
             debug!("Writing header block stream={} len={}", stream_id, len);
 
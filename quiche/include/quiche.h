@@ -58,6 +58,10 @@ extern "C" {
 // The current QUIC wire version.
 #define QUICHE_PROTOCOL_VERSION 0x00000001
 
+// QUIC version 1 (RFC 9000) and version 2 (RFC 9369).
+#define QUICHE_PROTOCOL_VERSION_V1 0x00000001
+#define QUICHE_PROTOCOL_VERSION_V2 0x6b3343cf
+
 // The maximum length of a connection ID.
 #define QUICHE_MAX_CONN_ID_LEN 20
 
@@ -115,6 +119,15 @@ enum quiche_error {
 
     // Error in congestion control.
     QUICHE_ERR_CONGESTION_CONTROL = -14,
+
+    // Too many identifiers were provided.
+    QUICHE_ERR_ID_LIMIT = -17,
+
+    // Not enough available identifiers.
+    QUICHE_ERR_OUT_OF_IDENTIFIERS = -18,
+
+    // The version negotiation failed.
+    QUICHE_ERR_VERSION_NEGOTIATION = -19,
 };
 
 // Returns a human readable string with the quiche version number.
@@ -163,6 +176,15 @@ int quiche_config_set_application_protos(quiche_config *config,
                                          const uint8_t *protos,
                                          size_t protos_len);
 
+// Configures the ordered list of supported versions, most preferred first.
+int quiche_config_set_supported_versions(quiche_config *config,
+                                         const uint32_t *versions,
+                                         size_t versions_len);
+
+// Returns true if the given protocol version is enabled in the configuration.
+bool quiche_config_version_is_supported(const quiche_config *config,
+                                        uint32_t version);
+
 // Sets the `max_idle_timeout` transport parameter, in milliseconds, default is
 // no timeout.
 void quiche_config_set_max_idle_timeout(quiche_config *config, uint64_t v);
@@ -197,12 +219,27 @@ void quiche_config_set_ack_delay_exponent(quiche_config *config, uint64_t v);
 // Sets the `max_ack_delay` transport parameter.
 void quiche_config_set_max_ack_delay(quiche_config *config, uint64_t v);
 
+// Sets the `min_ack_delay` transport parameter, in microseconds.
+void quiche_config_set_min_ack_delay(quiche_config *config, uint64_t v);
+
 // Sets the `disable_active_migration` transport parameter.
 void quiche_config_set_disable_active_migration(quiche_config *config, bool v);
 
+// Sets the `preferred_address` transport parameter. Either address can be
+// NULL, and `reset_token` must point to 16 bytes.
+int quiche_config_set_preferred_address(quiche_config *config,
+                                        const struct sockaddr *v4,
+                                        size_t v4_len,
+                                        const struct sockaddr *v6,
+                                        size_t v6_len,
+                                        const uint8_t *cid, size_t cid_len,
+                                        const uint8_t *reset_token);
+
 enum quiche_cc_algorithm {
     QUICHE_CC_RENO = 0,
     QUICHE_CC_CUBIC = 1,
+    QUICHE_CC_BBR = 2,
+    QUICHE_CC_BBR2 = 3,
 };
 
 // Sets the congestion control algorithm used.
@@ -211,6 +248,15 @@ void quiche_config_set_cc_algorithm(quiche_config *config, enum quiche_cc_algori
 // Configures whether to use HyStart++.
 void quiche_config_enable_hystart(quiche_config *config, bool v);
 
+// Configures whether to mark outgoing packets with ECN.
+void quiche_config_enable_ecn(quiche_config *config, bool v);
+
+// Configures whether to enable Path MTU discovery.
+void quiche_config_discover_pmtu(quiche_config *config, bool v);
+
+// Configures whether to tune the peer's ACK frequency automatically.
+void quiche_config_enable_ack_frequency(quiche_config *config, bool v);
+
 // Configures whether to enable receiving DATAGRAM frames.
 void quiche_config_enable_dgram(quiche_config *config, bool enabled,
                                 size_t recv_queue_len,
@@ -222,6 +268,31 @@ void quiche_config_set_max_connection_window(quiche_config *config, uint64_t v);
 // Sets the maximum stream window.
 void quiche_config_set_max_stream_window(quiche_config *config, uint64_t v);
 
+enum quiche_stream_scheduler {
+    QUICHE_STREAM_SCHEDULER_STRICT = 0,
+    QUICHE_STREAM_SCHEDULER_WRR = 1,
+    QUICHE_STREAM_SCHEDULER_DRR = 2,
+};
+
+// Sets the algorithm used to schedule stream data.
+void quiche_config_set_stream_scheduler(quiche_config *config,
+                                        enum quiche_stream_scheduler scheduler);
+
+// Sets the maximum number of packets sent with the same 1-RTT keys.
+void quiche_config_set_max_packets_per_key(quiche_config *config, uint64_t v);
+
+// Configures the key used to authenticate address validation tokens.
+int quiche_config_set_address_token_key(quiche_config *config,
+                                        const uint8_t *key, size_t key_len);
+
+// Sets the lifetime of tokens sent in NEW_TOKEN frames, in milliseconds.
+void quiche_config_set_address_token_lifetime(quiche_config *config,
+                                              uint64_t v);
+
+// Configures the static key used to derive stateless reset tokens.
+void quiche_config_set_stateless_reset_key(quiche_config *config,
+                                           const uint8_t *key, size_t key_len);
+
 // Frees the config object.
 void quiche_config_free(quiche_config *config);
 
@@ -239,13 +310,15 @@ typedef struct Connection quiche_conn;
 // Creates a new server-side connection.
 quiche_conn *quiche_accept(const uint8_t *scid, size_t scid_len,
                            const uint8_t *odcid, size_t odcid_len,
-                           const struct sockaddr *from, size_t from_len,
+                           const struct sockaddr *local, size_t local_len,
+                           const struct sockaddr *peer, size_t peer_len,
                            quiche_config *config);
 
 // Creates a new client-side connection.
 quiche_conn *quiche_connect(const char *server_name,
                             const uint8_t *scid, size_t scid_len,
-                            const struct sockaddr *to, size_t to_len,
+                            const struct sockaddr *local, size_t local_len,
+                            const struct sockaddr *peer, size_t peer_len,
                             quiche_config *config);
 
 // Writes a version negotiation packet.
@@ -253,6 +326,12 @@ ssize_t quiche_negotiate_version(const uint8_t *scid, size_t scid_len,
                                  const uint8_t *dcid, size_t dcid_len,
                                  uint8_t *out, size_t out_len);
 
+// Writes a version negotiation packet listing the configured versions.
+ssize_t quiche_negotiate_version_with_config(const quiche_config *config,
+                                             const uint8_t *scid, size_t scid_len,
+                                             const uint8_t *dcid, size_t dcid_len,
+                                             uint8_t *out, size_t out_len);
+
 // Writes a retry packet.
 ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
                      const uint8_t *dcid, size_t dcid_len,
@@ -260,11 +339,31 @@ ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
                      const uint8_t *token, size_t token_len,
                      uint32_t version, uint8_t *out, size_t out_len);
 
+// Mints an address validation token to be sent in a Retry packet.
+ssize_t quiche_mint_retry_token(const quiche_config *config,
+                                const uint8_t *odcid, size_t odcid_len,
+                                const struct sockaddr *peer, size_t peer_len,
+                                uint8_t *out, size_t out_len);
+
+// Writes a stateless reset for a packet of `recv_len` bytes carrying the
+// unknown destination connection ID `dcid`.
+ssize_t quiche_stateless_reset(const quiche_config *config,
+                               const uint8_t *dcid, size_t dcid_len,
+                               size_t recv_len, uint8_t *out, size_t out_len);
+
+// Validates an address validation token. On success, `odcid_len` is set to 0
+// for tokens sent in NEW_TOKEN frames.
+int quiche_validate_token(const quiche_config *config,
+                          const uint8_t *token, size_t token_len,
+                          const struct sockaddr *peer, size_t peer_len,
+                          uint8_t *odcid, size_t *odcid_len);
+
 // Returns true if the given protocol version is supported.
 bool quiche_version_is_supported(uint32_t version);
 
 quiche_conn *quiche_conn_new_with_tls(const uint8_t *scid, size_t scid_len,
                                       const uint8_t *odcid, size_t odcid_len,
+                                      const struct sockaddr *local, size_t local_len,
                                       const struct sockaddr *peer, size_t peer_len,
                                       quiche_config *config, void *ssl,
                                       bool is_server);
@@ -286,9 +385,23 @@ void quiche_conn_set_qlog_fd(quiche_conn *conn, int fd, const char *log_title,
 // Configures the given session for resumption.
 int quiche_conn_set_session(quiche_conn *conn, const uint8_t *buf, size_t buf_len);
 
+// Configures the address validation token sent in Initial packets.
+int quiche_conn_set_token(quiche_conn *conn, const uint8_t *token, size_t token_len);
+
+// Returns the next token received in a NEW_TOKEN frame.
+ssize_t quiche_conn_new_token_next(quiche_conn *conn, uint8_t *out, size_t out_len);
+
 typedef struct {
+    // The remote address the packet was received from.
     struct sockaddr *from;
     socklen_t from_len;
+
+    // The local address the packet was received on.
+    struct sockaddr *to;
+    socklen_t to_len;
+
+    // The ECN codepoint of the packet (the low 2 bits of the IP TOS field).
+    uint8_t ecn;
 } quiche_recv_info;
 
 // Processes QUIC packets received from the peer.
@@ -296,18 +409,80 @@ ssize_t quiche_conn_recv(quiche_conn *conn, uint8_t *buf, size_t buf_len,
                          const quiche_recv_info *info);
 
 typedef struct {
-    // The address the packet should be sent to.
+    // The local address the packet should be sent from.
+    struct sockaddr_storage from;
+    socklen_t from_len;
+
+    // The remote address the packet should be sent to.
     struct sockaddr_storage to;
     socklen_t to_len;
 
     // The time to send the packet out.
     struct timespec at;
+
+    // The ECN codepoint to send the packet with (the low 2 bits of the IP TOS
+    // field).
+    uint8_t ecn;
 } quiche_send_info;
 
 // Writes a single QUIC packet to be sent to the peer.
 ssize_t quiche_conn_send(quiche_conn *conn, uint8_t *out, size_t out_len,
                          quiche_send_info *out_info);
 
+// Writes a single QUIC packet to be sent to the peer on the given path. The
+// `from` and `to` addresses can be NULL to match any path.
+ssize_t quiche_conn_send_on_path(quiche_conn *conn, uint8_t *out, size_t out_len,
+                                 const struct sockaddr *from, size_t from_len,
+                                 const struct sockaddr *to, size_t to_len,
+                                 quiche_send_info *out_info);
+
+// Starts probing the given network path.
+int quiche_conn_probe_path(quiche_conn *conn,
+                           const struct sockaddr *local, size_t local_len,
+                           const struct sockaddr *peer, size_t peer_len,
+                           uint64_t *seq);
+
+// Migrates the connection to a new local address.
+int quiche_conn_migrate_source(quiche_conn *conn,
+                               const struct sockaddr *local, size_t local_len,
+                               uint64_t *seq);
+
+// Migrates the connection to the given network path.
+int quiche_conn_migrate(quiche_conn *conn,
+                        const struct sockaddr *local, size_t local_len,
+                        const struct sockaddr *peer, size_t peer_len,
+                        uint64_t *seq);
+
+// Returns 1 if the given network path has been validated, 0 if not, or a
+// negative error value if the path is unknown.
+int quiche_conn_is_path_validated(const quiche_conn *conn,
+                                  const struct sockaddr *local, size_t local_len,
+                                  const struct sockaddr *peer, size_t peer_len);
+
+typedef struct quiche_path_event quiche_path_event;
+
+enum quiche_path_event_type {
+    QUICHE_PATH_EVENT_NEW,
+    QUICHE_PATH_EVENT_VALIDATED,
+    QUICHE_PATH_EVENT_FAILED_VALIDATION,
+    QUICHE_PATH_EVENT_CLOSED,
+    QUICHE_PATH_EVENT_PEER_MIGRATED,
+};
+
+// Returns the next path event, or NULL if there is none.
+quiche_path_event *quiche_conn_path_event_next(quiche_conn *conn);
+
+// Returns the type of the path event.
+enum quiche_path_event_type quiche_path_event_type(quiche_path_event *ev);
+
+// Returns the local and peer addresses of the path the event is about.
+void quiche_path_event_addrs(quiche_path_event *ev,
+                             struct sockaddr_storage *local, socklen_t *local_len,
+                             struct sockaddr_storage *peer, socklen_t *peer_len);
+
+// Frees the path event object.
+void quiche_path_event_free(quiche_path_event *ev);
+
 // Returns the size of the send quantum, in bytes.
 size_t quiche_conn_send_quantum(quiche_conn *conn);
 
@@ -328,6 +503,10 @@ enum quiche_shutdown {
 int quiche_conn_stream_priority(quiche_conn *conn, uint64_t stream_id,
                                 uint8_t urgency, bool incremental);
 
+// Sets the weight for a stream.
+int quiche_conn_stream_weight(quiche_conn *conn, uint64_t stream_id,
+                              uint8_t weight);
+
 // Shuts down reading or writing from/to the specified stream.
 int quiche_conn_stream_shutdown(quiche_conn *conn, uint64_t stream_id,
                                 enum quiche_shutdown direction, uint64_t err);
@@ -350,6 +529,12 @@ quiche_stream_iter *quiche_conn_writable(quiche_conn *conn);
 // Returns the maximum possible size of egress UDP payloads.
 size_t quiche_conn_max_send_udp_payload_size(quiche_conn *conn);
 
+// Requests the peer to change how often it sends ACKs.
+int quiche_conn_set_ack_frequency(quiche_conn *conn,
+                                  uint64_t ack_eliciting_threshold,
+                                  uint64_t max_ack_delay_us,
+                                  uint64_t reordering_threshold);
+
 // Returns the amount of time until the next timeout event, in nanoseconds.
 uint64_t quiche_conn_timeout_as_nanos(quiche_conn *conn);
 
@@ -409,6 +594,9 @@ bool quiche_conn_is_closed(quiche_conn *conn);
 // Returns true if the connection was closed due to the idle timeout.
 bool quiche_conn_is_timed_out(quiche_conn *conn);
 
+// Returns true if the connection was closed by a stateless reset.
+bool quiche_conn_is_stateless_reset(quiche_conn *conn);
+
 // Returns true if a connection error was received, and updates the provided
 // parameters accordingly.
 bool quiche_conn_peer_error(quiche_conn *conn,
@@ -633,6 +821,12 @@ enum quiche_h3_error {
     // over HTTP/1.1.
     QUICHE_H3_ERR_VERSION_FALLBACK = -20,
 
+    // Error on the QPACK encoder stream.
+    QUICHE_H3_ERR_QPACK_ENCODER_STREAM_ERROR = -21,
+
+    // Error on the QPACK decoder stream.
+    QUICHE_H3_ERR_QPACK_DECODER_STREAM_ERROR = -22,
+
     // The following QUICHE_H3_TRANSPORT_ERR_* errors are propagated
     // from the QUIC transport layer.
 
@@ -683,6 +877,15 @@ enum quiche_h3_error {
 
     // See QUICHE_ERR_CONGESTION_CONTROL.
     QUICHE_H3_TRANSPORT_ERR_CONGESTION_CONTROL = QUICHE_ERR_CONGESTION_CONTROL - 1000,
+
+    // See QUICHE_ERR_ID_LIMIT.
+    QUICHE_H3_TRANSPORT_ERR_ID_LIMIT = QUICHE_ERR_ID_LIMIT - 1000,
+
+    // See QUICHE_ERR_OUT_OF_IDENTIFIERS.
+    QUICHE_H3_TRANSPORT_ERR_OUT_OF_IDENTIFIERS = QUICHE_ERR_OUT_OF_IDENTIFIERS - 1000,
+
+    // See QUICHE_ERR_VERSION_NEGOTIATION.
+    QUICHE_H3_TRANSPORT_ERR_VERSION_NEGOTIATION = QUICHE_ERR_VERSION_NEGOTIATION - 1000,
 };
 
 // Stores configuration shared between multiple connections.
@@ -700,6 +903,17 @@ void quiche_h3_config_set_qpack_max_table_capacity(quiche_h3_config *config, uin
 // Sets the `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
 void quiche_h3_config_set_qpack_blocked_streams(quiche_h3_config *config, uint64_t v);
 
+enum quiche_h3_qpack_insertion_policy {
+    QUICHE_H3_QPACK_INSERT_NEVER = 0,
+    QUICHE_H3_QPACK_INSERT_ALWAYS = 1,
+    QUICHE_H3_QPACK_INSERT_REPEATED = 2,
+};
+
+// Sets the policy used by the QPACK encoder to decide which header fields are
+// inserted into the dynamic table.
+void quiche_h3_config_set_qpack_insertion_policy(quiche_h3_config *config,
+                                                 enum quiche_h3_qpack_insertion_policy v);
+
 // Frees the HTTP/3 config object.
 void quiche_h3_config_free(quiche_h3_config *config);
 
@@ -722,6 +936,7 @@ enum quiche_h3_event_type {
     QUICHE_H3_EVENT_GOAWAY,
     QUICHE_H3_EVENT_RESET,
     QUICHE_H3_EVENT_PRIORITY_UPDATE,
+    QUICHE_H3_EVENT_PUSH_PROMISE,
 };
 
 typedef struct Http3Event quiche_h3_event;
@@ -758,6 +973,9 @@ int quiche_h3_for_each_setting(quiche_h3_conn *conn,
 // Check whether data will follow the headers on the stream.
 bool quiche_h3_event_headers_has_body(quiche_h3_event *ev);
 
+// Returns the push ID of a PUSH_PROMISE event.
+uint64_t quiche_h3_event_push_id(quiche_h3_event *ev);
+
 // Frees the HTTP/3 event object.
 void quiche_h3_event_free(quiche_h3_event *ev);
 
@@ -796,6 +1014,25 @@ ssize_t quiche_h3_send_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, uint8_t *body, size_t body_len,
                             bool fin);
 
+// Sends a PUSH_PROMISE frame on the specified request stream.
+int64_t quiche_h3_send_push_promise(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                                    uint64_t stream_id, quiche_h3_header *headers,
+                                    size_t headers_len);
+
+// Sends the HTTP/3 response of a promised push on a new push stream.
+int64_t quiche_h3_send_push_response(quiche_h3_conn *conn,
+                                     quiche_conn *quic_conn, uint64_t push_id,
+                                     quiche_h3_header *headers,
+                                     size_t headers_len, bool fin);
+
+// Sends a MAX_PUSH_ID frame to allow the server to push responses.
+int quiche_h3_send_max_push_id(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                               uint64_t push_id);
+
+// Cancels a server push.
+int quiche_h3_cancel_push(quiche_h3_conn *conn, quiche_conn *quic_conn,
+                          uint64_t push_id);
+
 // Reads request or response body data into the provided buffer.
 ssize_t quiche_h3_recv_body(quiche_h3_conn *conn, quiche_conn *quic_conn,
                             uint64_t stream_id, uint8_t *out, size_t out_len);
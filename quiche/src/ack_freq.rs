@@ -0,0 +1,309 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! ACK frequency extension (draft-ietf-quic-ack-frequency).
+//!
+//! The receiver of ACK_FREQUENCY frames tracks the ACK policy requested by
+//! its peer, while the sender computes the policy to request, either from
+//! explicit values or from the current congestion window.
+
+use std::cmp;
+
+use std::time::Duration;
+
+/// The largest ack-eliciting threshold requested when tuning the peer's ACK
+/// frequency automatically.
+const MAX_ACK_ELICITING_THRESHOLD: u64 = 9;
+
+/// The number of ACKs requested per congestion window when tuning the peer's
+/// ACK frequency automatically.
+const ACKS_PER_CWND: usize = 4;
+
+/// An ACK policy, as carried by the ACK_FREQUENCY frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AckPolicy {
+    /// The number of ack-eliciting packets that can be received without
+    /// sending an ACK immediately.
+    pub ack_eliciting_threshold: u64,
+
+    /// The maximum time an ACK can be delayed.
+    pub max_ack_delay: Duration,
+
+    /// The packet reordering that triggers an immediate ACK, or 0 to never
+    /// send an ACK immediately because of reordering.
+    pub reordering_threshold: u64,
+}
+
+impl AckPolicy {
+    /// The policy of RFC 9000 with quiche's default of acknowledging every
+    /// ack-eliciting packet immediately.
+    pub fn new(max_ack_delay: Duration) -> Self {
+        AckPolicy {
+            ack_eliciting_threshold: 0,
+            max_ack_delay,
+            reordering_threshold: 1,
+        }
+    }
+
+    /// Computes the policy to request from the peer, so that it sends a few
+    /// ACKs per congestion window, delayed by a fraction of the RTT.
+    pub fn from_cwnd(
+        cwnd: usize, max_datagram_size: usize, rtt: Duration,
+        min_ack_delay: Duration, max_ack_delay: Duration,
+    ) -> Self {
+        let cwnd_packets = cwnd / cmp::max(max_datagram_size, 1);
+
+        let ack_eliciting_threshold = ((cwnd_packets / ACKS_PER_CWND) as u64)
+            .saturating_sub(1)
+            .min(MAX_ACK_ELICITING_THRESHOLD);
+
+        let max_ack_delay = (rtt / ACKS_PER_CWND as u32)
+            .min(max_ack_delay)
+            .max(min_ack_delay);
+
+        AckPolicy {
+            ack_eliciting_threshold,
+            max_ack_delay,
+            reordering_threshold: 1,
+        }
+    }
+}
+
+/// ACK frequency state of a connection.
+pub struct AckFrequency {
+    /// The policy requested by the peer.
+    policy: AckPolicy,
+
+    /// The largest sequence number of the received ACK_FREQUENCY frames.
+    largest_recv_seq: Option<u64>,
+
+    /// The sequence number of the next ACK_FREQUENCY frame to send.
+    next_seq: u64,
+
+    /// The latest policy requested from the peer, and its sequence number.
+    requested: Option<(u64, AckPolicy)>,
+
+    /// Whether the latest requested policy needs to be (re)sent.
+    pending: bool,
+}
+
+impl AckFrequency {
+    pub fn new(max_ack_delay: Duration) -> Self {
+        AckFrequency {
+            policy: AckPolicy::new(max_ack_delay),
+
+            largest_recv_seq: None,
+
+            next_seq: 0,
+
+            requested: None,
+
+            pending: false,
+        }
+    }
+
+    /// Returns the ACK policy requested by the peer.
+    pub fn policy(&self) -> &AckPolicy {
+        &self.policy
+    }
+
+    /// Updates the ACK policy from a received ACK_FREQUENCY frame.
+    ///
+    /// Frames with a sequence number lower than a previously received one are
+    /// ignored, as they have been reordered.
+    pub fn on_frame_received(&mut self, seq_num: u64, policy: AckPolicy) {
+        if let Some(largest) = self.largest_recv_seq {
+            if seq_num <= largest {
+                return;
+            }
+        }
+
+        self.largest_recv_seq = Some(seq_num);
+        self.policy = policy;
+    }
+
+    /// Returns whether an ACK should be sent immediately, given the number of
+    /// ack-eliciting packets not yet acknowledged and the number of packets
+    /// missing below the packet just received.
+    pub fn should_ack_immediately(&self, unacked: u64, reordering: u64) -> bool {
+        if unacked > self.policy.ack_eliciting_threshold {
+            return true;
+        }
+
+        self.policy.reordering_threshold > 0 &&
+            reordering >= self.policy.reordering_threshold
+    }
+
+    /// Requests a new ACK policy from the peer.
+    ///
+    /// Nothing is sent if the policy is the same as the one previously
+    /// requested.
+    pub fn request(&mut self, policy: AckPolicy) {
+        if self.requested.map(|(_, p)| p) == Some(policy) {
+            return;
+        }
+
+        self.requested = Some((self.next_seq, policy));
+        self.next_seq += 1;
+
+        self.pending = true;
+    }
+
+    /// Returns the latest requested policy and its sequence number, if it
+    /// needs to be sent.
+    pub fn pending(&self) -> Option<(u64, AckPolicy)> {
+        if !self.pending {
+            return None;
+        }
+
+        self.requested
+    }
+
+    /// Returns the latest policy requested from the peer.
+    pub fn requested(&self) -> Option<AckPolicy> {
+        self.requested.map(|(_, p)| p)
+    }
+
+    pub fn on_frame_sent(&mut self) {
+        self.pending = false;
+    }
+
+    /// Schedules a lost ACK_FREQUENCY frame for retransmission, unless a
+    /// more recent policy has been requested since.
+    pub fn on_frame_lost(&mut self, seq_num: u64) {
+        if self.requested.map(|(s, _)| s) == Some(seq_num) {
+            self.pending = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_from_cwnd() {
+        let rtt = Duration::from_millis(40);
+        let min_ack_delay = Duration::from_millis(1);
+        let max_ack_delay = Duration::from_millis(25);
+
+        // Small windows are acked every packet.
+        let policy =
+            AckPolicy::from_cwnd(2400, 1200, rtt, min_ack_delay, max_ack_delay);
+        assert_eq!(policy.ack_eliciting_threshold, 0);
+        assert_eq!(policy.max_ack_delay, Duration::from_millis(10));
+
+        let policy =
+            AckPolicy::from_cwnd(12000, 1200, rtt, min_ack_delay, max_ack_delay);
+        assert_eq!(policy.ack_eliciting_threshold, 1);
+
+        // The threshold is capped on large windows.
+        let policy = AckPolicy::from_cwnd(
+            1_200_000,
+            1200,
+            rtt,
+            min_ack_delay,
+            max_ack_delay,
+        );
+        assert_eq!(policy.ack_eliciting_threshold, MAX_ACK_ELICITING_THRESHOLD);
+
+        // The delay is bounded by the peer's limits.
+        let policy = AckPolicy::from_cwnd(
+            12000,
+            1200,
+            Duration::from_millis(200),
+            min_ack_delay,
+            max_ack_delay,
+        );
+        assert_eq!(policy.max_ack_delay, max_ack_delay);
+
+        let policy = AckPolicy::from_cwnd(
+            12000,
+            1200,
+            Duration::from_micros(400),
+            min_ack_delay,
+            max_ack_delay,
+        );
+        assert_eq!(policy.max_ack_delay, min_ack_delay);
+    }
+
+    #[test]
+    fn receive_reordered_frames() {
+        let mut ack_freq = AckFrequency::new(Duration::from_millis(25));
+
+        assert!(ack_freq.should_ack_immediately(1, 0));
+
+        let policy = AckPolicy {
+            ack_eliciting_threshold: 3,
+            max_ack_delay: Duration::from_millis(10),
+            reordering_threshold: 0,
+        };
+
+        ack_freq.on_frame_received(1, policy);
+        assert_eq!(ack_freq.policy(), &policy);
+
+        assert!(!ack_freq.should_ack_immediately(3, 5));
+        assert!(ack_freq.should_ack_immediately(4, 0));
+
+        // An older frame is ignored.
+        ack_freq.on_frame_received(0, AckPolicy::new(Duration::ZERO));
+        assert_eq!(ack_freq.policy(), &policy);
+    }
+
+    #[test]
+    fn request_and_retransmit() {
+        let mut ack_freq = AckFrequency::new(Duration::from_millis(25));
+
+        assert_eq!(ack_freq.pending(), None);
+
+        let policy = AckPolicy::new(Duration::from_millis(10));
+
+        ack_freq.request(policy);
+        assert_eq!(ack_freq.pending(), Some((0, policy)));
+
+        ack_freq.on_frame_sent();
+        assert_eq!(ack_freq.pending(), None);
+
+        // The same policy isn't requested again.
+        ack_freq.request(policy);
+        assert_eq!(ack_freq.pending(), None);
+
+        ack_freq.on_frame_lost(0);
+        assert_eq!(ack_freq.pending(), Some((0, policy)));
+        ack_freq.on_frame_sent();
+
+        let new_policy = AckPolicy::new(Duration::from_millis(5));
+
+        ack_freq.request(new_policy);
+        ack_freq.on_frame_sent();
+
+        // Loss of an outdated frame doesn't trigger a retransmission.
+        ack_freq.on_frame_lost(0);
+        assert_eq!(ack_freq.pending(), None);
+
+        ack_freq.on_frame_lost(1);
+        assert_eq!(ack_freq.pending(), Some((1, new_policy)));
+    }
+}
@@ -0,0 +1,808 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cmp;
+
+use std::collections::VecDeque;
+
+use crate::Error;
+use crate::Result;
+
+use crate::packet::ConnectionId;
+
+/// A connection ID along with its associated metadata.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionIdEntry {
+    /// The connection ID.
+    pub cid: ConnectionId<'static>,
+
+    /// The sequence number of the connection ID.
+    pub seq: u64,
+
+    /// The stateless reset token associated with the connection ID. Initial
+    /// connection IDs might not have one.
+    pub reset_token: Option<u128>,
+
+    /// The identifier of the path using this connection ID, if any.
+    pub path_id: Option<usize>,
+}
+
+/// Keeps track of the connection IDs used by both endpoints.
+#[derive(Default)]
+pub struct ConnectionIdentifiers {
+    /// The source connection IDs we provided to the peer.
+    scids: VecDeque<ConnectionIdEntry>,
+
+    /// The destination connection IDs provided by the peer.
+    dcids: VecDeque<ConnectionIdEntry>,
+
+    /// Sequence numbers of source connection IDs that need to be advertised
+    /// in NEW_CONNECTION_ID frames.
+    advertise_new_scid_seqs: VecDeque<u64>,
+
+    /// Sequence numbers of destination connection IDs that need to be retired
+    /// with RETIRE_CONNECTION_ID frames.
+    retire_dcid_seqs: VecDeque<u64>,
+
+    /// Source connection IDs retired by the peer, that the application hasn't
+    /// been notified about yet.
+    retired_scids: VecDeque<ConnectionId<'static>>,
+
+    /// The sequence number to assign to the next source connection ID.
+    next_scid_seq: u64,
+
+    /// The "Retire Prior To" value to advertise to the peer.
+    retire_prior_to: u64,
+
+    /// The largest "Retire Prior To" value received from the peer.
+    largest_peer_retire_prior_to: u64,
+
+    /// The largest destination connection ID sequence number received.
+    largest_destination_seq: u64,
+
+    /// The maximum number of source connection IDs the peer accepts.
+    source_conn_id_limit: usize,
+
+    /// The maximum number of destination connection IDs we accept.
+    destination_conn_id_limit: usize,
+
+    /// Whether the local endpoint uses a zero-length connection ID.
+    zero_length_scid: bool,
+
+    /// Whether the peer uses a zero-length connection ID.
+    zero_length_dcid: bool,
+}
+
+impl ConnectionIdentifiers {
+    /// Creates a new set of connection identifiers, using `initial_scid` as the
+    /// source connection ID with sequence number 0.
+    ///
+    /// The destination connection ID is initially empty, and should be set
+    /// with [`set_initial_dcid()`] once known.
+    ///
+    /// [`set_initial_dcid()`]: #method.set_initial_dcid
+    pub fn new(
+        destination_conn_id_limit: usize, initial_scid: &ConnectionId,
+        initial_path_id: usize, reset_token: Option<u128>,
+    ) -> ConnectionIdentifiers {
+        // The active_connection_id_limit transport parameter can't be lower
+        // than 2.
+        let destination_conn_id_limit = cmp::max(2, destination_conn_id_limit);
+
+        let mut scids = VecDeque::new();
+        scids.push_back(ConnectionIdEntry {
+            cid: initial_scid.to_vec().into(),
+            seq: 0,
+            reset_token,
+            path_id: Some(initial_path_id),
+        });
+
+        let mut dcids = VecDeque::new();
+        dcids.push_back(ConnectionIdEntry {
+            cid: ConnectionId::default(),
+            seq: 0,
+            reset_token: None,
+            path_id: Some(initial_path_id),
+        });
+
+        ConnectionIdentifiers {
+            scids,
+            dcids,
+            next_scid_seq: 1,
+            source_conn_id_limit: 2,
+            destination_conn_id_limit,
+            zero_length_scid: initial_scid.is_empty(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the maximum number of source connection IDs the peer accepts, as
+    /// advertised in its active_connection_id_limit transport parameter.
+    pub fn set_source_conn_id_limit(&mut self, v: u64) {
+        // Values lower than 2 are invalid and rejected when parsing transport
+        // parameters, so they are just ignored here.
+        if v >= 2 {
+            self.source_conn_id_limit = cmp::min(v, usize::MAX as u64) as usize;
+        }
+    }
+
+    /// Sets the destination connection ID with sequence number 0.
+    ///
+    /// This replaces any previously set destination connection ID, and is
+    /// meant to be used during the handshake only.
+    pub fn set_initial_dcid(
+        &mut self, cid: ConnectionId<'static>, reset_token: Option<u128>,
+        path_id: Option<usize>,
+    ) {
+        self.zero_length_dcid = cid.is_empty();
+
+        self.dcids.clear();
+        self.dcids.push_back(ConnectionIdEntry {
+            cid,
+            seq: 0,
+            reset_token,
+            path_id,
+        });
+    }
+
+    /// Adds a new source connection ID, and returns its sequence number.
+    ///
+    /// When `advertise` is true, the new connection ID is scheduled to be sent
+    /// to the peer in a NEW_CONNECTION_ID frame.
+    ///
+    /// The peer can't have more active connection IDs than it allows in its
+    /// active_connection_id_limit transport parameter. If the limit is already
+    /// reached, the oldest source connection ID is retired when
+    /// `retire_if_needed` is set, otherwise [`IdLimit`] is returned.
+    ///
+    /// Connection IDs other than the initial one need a stateless reset token,
+    /// so [`InvalidState`] is returned if `reset_token` is `None` for them.
+    ///
+    /// If `cid` is already known its sequence number is returned, unless the
+    /// reset token differs, in which case [`InvalidState`] is returned.
+    ///
+    /// [`IdLimit`]: ../enum.Error.html#variant.IdLimit
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn new_scid(
+        &mut self, cid: ConnectionId<'static>, reset_token: Option<u128>,
+        advertise: bool, path_id: Option<usize>, retire_if_needed: bool,
+    ) -> Result<u64> {
+        if self.zero_length_scid {
+            return Err(Error::InvalidState);
+        }
+
+        if let Some(e) = self.scids.iter().find(|e| e.cid == cid) {
+            if e.reset_token != reset_token {
+                return Err(Error::InvalidState);
+            }
+
+            return Ok(e.seq);
+        }
+
+        if reset_token.is_none() && self.next_scid_seq != 0 {
+            return Err(Error::InvalidState);
+        }
+
+        let limit_exceeded =
+            self.active_source_cids() >= self.source_conn_id_limit;
+
+        if limit_exceeded {
+            if !retire_if_needed {
+                return Err(Error::IdLimit);
+            }
+
+            // Connection IDs pending retirement are still valid until the peer
+            // retires them, so we can't keep more than twice the limit around.
+            if self.scids.len() >= 2 * self.source_conn_id_limit - 1 {
+                return Err(Error::IdLimit);
+            }
+        }
+
+        let seq = self.next_scid_seq;
+
+        self.scids.push_back(ConnectionIdEntry {
+            cid,
+            seq,
+            reset_token,
+            path_id,
+        });
+
+        self.next_scid_seq += 1;
+
+        if advertise {
+            self.mark_advertise_new_scid_seq(seq, true);
+        }
+
+        // Ask the peer to retire the oldest active connection ID, to make room
+        // for the new one.
+        if limit_exceeded {
+            self.retire_prior_to = self
+                .scids
+                .iter()
+                .map(|e| e.seq)
+                .find(|&s| s >= self.retire_prior_to)
+                .map_or(self.retire_prior_to, |s| s + 1);
+        }
+
+        Ok(seq)
+    }
+
+    /// Adds a new destination connection ID received in a NEW_CONNECTION_ID
+    /// frame.
+    ///
+    /// Returns the sequence numbers of the destination connection IDs that
+    /// got retired as a result of the frame's "Retire Prior To" value, along
+    /// with the identifier of the path that was using them.
+    pub fn new_dcid(
+        &mut self, cid: ConnectionId<'static>, seq: u64, reset_token: u128,
+        retire_prior_to: u64,
+    ) -> Result<Vec<(u64, usize)>> {
+        // An endpoint that is using a zero-length connection ID can't receive
+        // NEW_CONNECTION_ID frames.
+        if self.zero_length_dcid {
+            return Err(Error::InvalidState);
+        }
+
+        let mut retired_path_ids = Vec::new();
+
+        // Receiving a connection ID already seen with a different sequence
+        // number or reset token, or a sequence number already used for a
+        // different connection ID, is a protocol violation.
+        if let Some(e) = self.dcids.iter().find(|e| e.cid == cid || e.seq == seq)
+        {
+            if e.cid != cid || e.seq != seq || e.reset_token != Some(reset_token)
+            {
+                return Err(Error::InvalidFrame);
+            }
+
+            // This is a retransmission of a known connection ID.
+            return Ok(retired_path_ids);
+        }
+
+        if retire_prior_to > seq {
+            return Err(Error::InvalidFrame);
+        }
+
+        // The connection ID has already been retired by a previous "Retire
+        // Prior To" value, so retire it immediately.
+        if seq < self.largest_peer_retire_prior_to {
+            if !self.retire_dcid_seqs.contains(&seq) {
+                self.retire_dcid_seqs.push_back(seq);
+            }
+
+            return Ok(retired_path_ids);
+        }
+
+        self.largest_destination_seq =
+            cmp::max(self.largest_destination_seq, seq);
+
+        // Ignore "Retire Prior To" values that don't increase the largest one
+        // received so far.
+        if retire_prior_to > self.largest_peer_retire_prior_to {
+            let retire_dcid_seqs = &mut self.retire_dcid_seqs;
+
+            self.dcids.retain(|e| {
+                if e.seq >= retire_prior_to {
+                    return true;
+                }
+
+                retire_dcid_seqs.push_back(e.seq);
+
+                if let Some(pid) = e.path_id {
+                    retired_path_ids.push((e.seq, pid));
+                }
+
+                false
+            });
+
+            self.largest_peer_retire_prior_to = retire_prior_to;
+        }
+
+        if self.dcids.len() >= self.destination_conn_id_limit {
+            return Err(Error::IdLimit);
+        }
+
+        self.dcids.push_back(ConnectionIdEntry {
+            cid,
+            seq,
+            reset_token: Some(reset_token),
+            path_id: None,
+        });
+
+        Ok(retired_path_ids)
+    }
+
+    /// Retires the source connection ID with the given sequence number, as
+    /// requested by a RETIRE_CONNECTION_ID frame carried by a packet with
+    /// destination connection ID `pkt_dcid`.
+    ///
+    /// Returns the identifier of the path that was using the connection ID, if
+    /// any.
+    pub fn retire_scid(
+        &mut self, seq: u64, pkt_dcid: &ConnectionId,
+    ) -> Result<Option<usize>> {
+        // The peer can't retire connection IDs that were never issued.
+        if seq >= self.next_scid_seq {
+            return Err(Error::InvalidState);
+        }
+
+        let pos = match self.scids.iter().position(|e| e.seq == seq) {
+            Some(v) => v,
+
+            // The connection ID was already retired.
+            None => return Ok(None),
+        };
+
+        // The peer can't retire the connection ID used by the packet carrying
+        // the frame.
+        if self.scids[pos].cid == *pkt_dcid {
+            return Err(Error::InvalidState);
+        }
+
+        let e = self.scids.remove(pos).ok_or(Error::InvalidState)?;
+
+        self.mark_advertise_new_scid_seq(seq, false);
+
+        self.retired_scids.push_back(e.cid);
+
+        Ok(e.path_id)
+    }
+
+    /// Retires the destination connection ID with the given sequence number.
+    ///
+    /// Returns [`OutOfIdentifiers`] when trying to retire the last destination
+    /// connection ID, and [`InvalidState`] if the sequence number is unknown.
+    ///
+    /// Returns the identifier of the path that was using the connection ID, if
+    /// any.
+    ///
+    /// [`OutOfIdentifiers`]: ../enum.Error.html#variant.OutOfIdentifiers
+    /// [`InvalidState`]: ../enum.Error.html#variant.InvalidState
+    pub fn retire_dcid(&mut self, seq: u64) -> Result<Option<usize>> {
+        if self.zero_length_dcid {
+            return Err(Error::InvalidState);
+        }
+
+        let pos = self
+            .dcids
+            .iter()
+            .position(|e| e.seq == seq)
+            .ok_or(Error::InvalidState)?;
+
+        if self.dcids.len() <= 1 {
+            return Err(Error::OutOfIdentifiers);
+        }
+
+        let e = self.dcids.remove(pos).ok_or(Error::InvalidState)?;
+
+        self.mark_retire_dcid_seq(seq, true);
+
+        Ok(e.path_id)
+    }
+
+    /// Returns the source connection ID with the given sequence number.
+    pub fn get_scid(&self, seq: u64) -> Result<&ConnectionIdEntry> {
+        self.scids
+            .iter()
+            .find(|e| e.seq == seq)
+            .ok_or(Error::InvalidState)
+    }
+
+    /// Returns the destination connection ID with the given sequence number.
+    pub fn get_dcid(&self, seq: u64) -> Result<&ConnectionIdEntry> {
+        self.dcids
+            .iter()
+            .find(|e| e.seq == seq)
+            .ok_or(Error::InvalidState)
+    }
+
+    /// Returns the oldest active source connection ID.
+    pub fn oldest_scid(&self) -> &ConnectionIdEntry {
+        // There is always at least one source connection ID, as the peer can't
+        // retire the one it is currently using.
+        &self.scids[0]
+    }
+
+    /// Returns the oldest active destination connection ID.
+    pub fn oldest_dcid(&self) -> &ConnectionIdEntry {
+        // There is always at least one destination connection ID, see
+        // `retire_dcid()`.
+        &self.dcids[0]
+    }
+
+    /// Returns true if the given token matches the stateless reset token of
+    /// a destination connection ID that is in use by a path.
+    ///
+    /// Tokens of connection IDs that were never used are ignored, as the peer
+    /// couldn't have lost state related to them.
+    ///
+    /// The token is compared in constant time against all the candidates, so
+    /// as not to leak how much of it matches a known one.
+    pub fn is_stateless_reset_token(&self, token: u128) -> bool {
+        let token = token.to_be_bytes();
+
+        let mut found = false;
+
+        for e in self.dcids.iter().filter(|e| e.path_id.is_some()) {
+            if let Some(reset_token) = e.reset_token {
+                found |= ring::constant_time::verify_slices_are_equal(
+                    &reset_token.to_be_bytes(),
+                    &token,
+                )
+                .is_ok();
+            }
+        }
+
+        found
+    }
+
+    /// Returns the sequence number and path identifier of the given source
+    /// connection ID, if known.
+    pub fn find_scid_seq(
+        &self, scid: &ConnectionId,
+    ) -> Option<(u64, Option<usize>)> {
+        self.scids
+            .iter()
+            .find(|e| e.cid == *scid)
+            .map(|e| (e.seq, e.path_id))
+    }
+
+    /// Links the source connection ID with the given sequence number to a
+    /// path.
+    pub fn link_scid_to_path_id(
+        &mut self, seq: u64, path_id: usize,
+    ) -> Result<()> {
+        let e = self
+            .scids
+            .iter_mut()
+            .find(|e| e.seq == seq)
+            .ok_or(Error::InvalidState)?;
+
+        e.path_id = Some(path_id);
+
+        Ok(())
+    }
+
+    /// Links the destination connection ID with the given sequence number to a
+    /// path.
+    pub fn link_dcid_to_path_id(
+        &mut self, seq: u64, path_id: usize,
+    ) -> Result<()> {
+        let e = self
+            .dcids
+            .iter_mut()
+            .find(|e| e.seq == seq)
+            .ok_or(Error::InvalidState)?;
+
+        e.path_id = Some(path_id);
+
+        Ok(())
+    }
+
+    /// Unlinks the destination connection ID with the given sequence number
+    /// from its path, making it available for other paths.
+    pub fn unlink_dcid(&mut self, seq: u64) {
+        if let Some(e) = self.dcids.iter_mut().find(|e| e.seq == seq) {
+            e.path_id = None;
+        }
+    }
+
+    /// Returns the lowest sequence number of the destination connection IDs
+    /// that are not used by any path.
+    pub fn lowest_available_dcid_seq(&self) -> Option<u64> {
+        self.dcids
+            .iter()
+            .filter(|e| e.path_id.is_none())
+            .map(|e| e.seq)
+            .min()
+    }
+
+    /// Returns the number of destination connection IDs not used by any path.
+    pub fn available_dcids(&self) -> usize {
+        self.dcids.iter().filter(|e| e.path_id.is_none()).count()
+    }
+
+    /// Returns the number of source connection IDs that are not pending
+    /// retirement.
+    pub fn active_source_cids(&self) -> usize {
+        self.scids
+            .iter()
+            .filter(|e| e.seq >= self.retire_prior_to)
+            .count()
+    }
+
+    /// Returns the maximum number of source connection IDs the peer accepts.
+    pub fn source_conn_id_limit(&self) -> usize {
+        self.source_conn_id_limit
+    }
+
+    /// Returns the "Retire Prior To" value to advertise to the peer.
+    pub fn retire_prior_to(&self) -> u64 {
+        self.retire_prior_to
+    }
+
+    /// Returns whether the local endpoint uses a zero-length connection ID.
+    pub fn zero_length_scid(&self) -> bool {
+        self.zero_length_scid
+    }
+
+    /// Returns whether the peer uses a zero-length connection ID.
+    pub fn zero_length_dcid(&self) -> bool {
+        self.zero_length_dcid
+    }
+
+    /// Sets whether the source connection ID with the given sequence number
+    /// needs to be advertised in a NEW_CONNECTION_ID frame.
+    pub fn mark_advertise_new_scid_seq(&mut self, seq: u64, advertise: bool) {
+        if advertise {
+            if !self.advertise_new_scid_seqs.contains(&seq) &&
+                self.scids.iter().any(|e| e.seq == seq)
+            {
+                self.advertise_new_scid_seqs.push_back(seq);
+            }
+        } else {
+            self.advertise_new_scid_seqs.retain(|&s| s != seq);
+        }
+    }
+
+    /// Sets whether the destination connection ID with the given sequence
+    /// number needs to be retired with a RETIRE_CONNECTION_ID frame.
+    pub fn mark_retire_dcid_seq(&mut self, seq: u64, retire: bool) {
+        if retire {
+            if !self.retire_dcid_seqs.contains(&seq) {
+                self.retire_dcid_seqs.push_back(seq);
+            }
+        } else {
+            self.retire_dcid_seqs.retain(|&s| s != seq);
+        }
+    }
+
+    /// Returns the sequence number of the next source connection ID to be
+    /// advertised, if any.
+    pub fn next_advertise_new_scid_seq(&self) -> Option<u64> {
+        self.advertise_new_scid_seqs.front().copied()
+    }
+
+    /// Returns the sequence number of the next destination connection ID to be
+    /// retired, if any.
+    pub fn next_retire_dcid_seq(&self) -> Option<u64> {
+        self.retire_dcid_seqs.front().copied()
+    }
+
+    /// Returns true if there are new source connection IDs to advertise.
+    pub fn has_new_scids(&self) -> bool {
+        !self.advertise_new_scid_seqs.is_empty()
+    }
+
+    /// Returns true if there are destination connection IDs to retire.
+    pub fn has_retire_dcids(&self) -> bool {
+        !self.retire_dcid_seqs.is_empty()
+    }
+
+    /// Returns the next source connection ID retired by the peer, if any.
+    pub fn pop_retired_scid(&mut self) -> Option<ConnectionId<'static>> {
+        self.retired_scids.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_cid_and_reset_token(
+        len: usize, b: u8,
+    ) -> (ConnectionId<'static>, u128) {
+        (ConnectionId::from_vec(vec![b; len]), b as u128)
+    }
+
+    #[test]
+    fn ids_new_scids() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_source_conn_id_limit(3);
+
+        assert_eq!(ids.active_source_cids(), 1);
+        assert!(!ids.has_new_scids());
+
+        let (scid2, rt2) = create_cid_and_reset_token(16, 0xbb);
+        assert_eq!(ids.new_scid(scid2, Some(rt2), true, None, false), Ok(1));
+        assert_eq!(ids.next_advertise_new_scid_seq(), Some(1));
+
+        let (scid3, rt3) = create_cid_and_reset_token(16, 0xbc);
+        assert_eq!(
+            ids.new_scid(scid3.clone(), Some(rt3), true, None, false),
+            Ok(2)
+        );
+        assert_eq!(ids.active_source_cids(), 3);
+
+        // Adding the same connection ID again returns the same sequence.
+        assert_eq!(ids.new_scid(scid3, Some(rt3), true, None, false), Ok(2));
+
+        // The limit is reached.
+        let (scid4, rt4) = create_cid_and_reset_token(16, 0xbd);
+        assert_eq!(
+            ids.new_scid(scid4.clone(), Some(rt4), true, None, false),
+            Err(Error::IdLimit)
+        );
+
+        // Unless we agree to retire the oldest one.
+        assert_eq!(ids.new_scid(scid4, Some(rt4), true, None, true), Ok(3));
+        assert_eq!(ids.retire_prior_to(), 1);
+        assert_eq!(ids.active_source_cids(), 3);
+
+        ids.mark_advertise_new_scid_seq(1, false);
+        ids.mark_advertise_new_scid_seq(2, false);
+        assert_eq!(ids.next_advertise_new_scid_seq(), Some(3));
+    }
+
+    #[test]
+    fn ids_new_scid_without_reset_token() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+
+        let (scid2, _) = create_cid_and_reset_token(16, 0xbb);
+        assert_eq!(
+            ids.new_scid(scid2, None, true, None, false),
+            Err(Error::InvalidState)
+        );
+    }
+
+    #[test]
+    fn ids_retire_scids() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+
+        let (scid2, rt2) = create_cid_and_reset_token(16, 0xbb);
+        assert_eq!(
+            ids.new_scid(scid2.clone(), Some(rt2), true, None, false),
+            Ok(1)
+        );
+
+        // Can't retire the connection ID used by the packet itself.
+        assert_eq!(ids.retire_scid(1, &scid2), Err(Error::InvalidState));
+
+        // Can't retire connection IDs that were never issued.
+        assert_eq!(ids.retire_scid(2, &scid2), Err(Error::InvalidState));
+
+        assert_eq!(ids.retire_scid(0, &scid2), Ok(Some(0)));
+        assert_eq!(ids.pop_retired_scid(), Some(scid));
+        assert_eq!(ids.pop_retired_scid(), None);
+
+        assert_eq!(ids.active_source_cids(), 1);
+        assert_eq!(ids.oldest_scid().seq, 1);
+
+        // Retiring twice is fine.
+        assert_eq!(ids.retire_scid(0, &scid2), Ok(None));
+    }
+
+    #[test]
+    fn ids_new_dcids() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+        let (dcid, _) = create_cid_and_reset_token(16, 0xca);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_initial_dcid(dcid, None, Some(0));
+
+        assert_eq!(ids.available_dcids(), 0);
+
+        let (dcid2, rt2) = create_cid_and_reset_token(16, 0xcb);
+        assert_eq!(ids.new_dcid(dcid2.clone(), 1, rt2, 0), Ok(vec![]));
+        assert_eq!(ids.available_dcids(), 1);
+        assert_eq!(ids.lowest_available_dcid_seq(), Some(1));
+
+        // Retransmissions are ignored.
+        assert_eq!(ids.new_dcid(dcid2.clone(), 1, rt2, 0), Ok(vec![]));
+
+        // But not if the sequence number doesn't match.
+        assert_eq!(ids.new_dcid(dcid2, 2, rt2, 0), Err(Error::InvalidFrame));
+
+        // Retire Prior To can't exceed the sequence number.
+        let (dcid3, rt3) = create_cid_and_reset_token(16, 0xcc);
+        assert_eq!(
+            ids.new_dcid(dcid3.clone(), 2, rt3, 3),
+            Err(Error::InvalidFrame)
+        );
+
+        // Too many connection IDs.
+        assert_eq!(ids.new_dcid(dcid3.clone(), 2, rt3, 0), Err(Error::IdLimit));
+
+        // Unless older ones are retired.
+        assert_eq!(ids.new_dcid(dcid3, 2, rt3, 1), Ok(vec![(0, 0)]));
+        assert_eq!(ids.next_retire_dcid_seq(), Some(0));
+        assert_eq!(ids.available_dcids(), 2);
+
+        // Connection IDs below the largest Retire Prior To are retired
+        // immediately.
+        let (dcid4, rt4) = create_cid_and_reset_token(16, 0xcd);
+        assert_eq!(ids.new_dcid(dcid4, 0, rt4, 0), Ok(vec![]));
+        assert_eq!(ids.get_dcid(0), Err(Error::InvalidState));
+    }
+
+    #[test]
+    fn ids_retire_dcids() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+        let (dcid, _) = create_cid_and_reset_token(16, 0xca);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_initial_dcid(dcid, None, Some(0));
+
+        // The last connection ID can't be retired.
+        assert_eq!(ids.retire_dcid(0), Err(Error::OutOfIdentifiers));
+        assert_eq!(ids.retire_dcid(1), Err(Error::InvalidState));
+
+        let (dcid2, rt2) = create_cid_and_reset_token(16, 0xcb);
+        assert_eq!(ids.new_dcid(dcid2, 1, rt2, 0), Ok(vec![]));
+
+        assert_eq!(ids.retire_dcid(0), Ok(Some(0)));
+        assert_eq!(ids.next_retire_dcid_seq(), Some(0));
+        assert!(ids.has_retire_dcids());
+
+        ids.mark_retire_dcid_seq(0, false);
+        assert!(!ids.has_retire_dcids());
+
+        assert_eq!(ids.oldest_dcid().seq, 1);
+    }
+
+    #[test]
+    fn ids_stateless_reset_token() {
+        let (scid, _) = create_cid_and_reset_token(16, 0xba);
+        let (dcid, rt) = create_cid_and_reset_token(16, 0xca);
+
+        let mut ids = ConnectionIdentifiers::new(2, &scid, 0, None);
+        ids.set_initial_dcid(dcid, Some(rt), Some(0));
+
+        assert!(ids.is_stateless_reset_token(rt));
+        assert!(!ids.is_stateless_reset_token(rt + 1));
+
+        // Tokens of unused connection IDs are ignored.
+        let (dcid2, rt2) = create_cid_and_reset_token(16, 0xcb);
+        assert_eq!(ids.new_dcid(dcid2, 1, rt2, 0), Ok(vec![]));
+        assert!(!ids.is_stateless_reset_token(rt2));
+
+        ids.link_dcid_to_path_id(1, 1).unwrap();
+        assert!(ids.is_stateless_reset_token(rt2));
+    }
+
+    #[test]
+    fn ids_zero_length() {
+        let mut ids =
+            ConnectionIdentifiers::new(2, &ConnectionId::default(), 0, None);
+        ids.set_initial_dcid(ConnectionId::default(), None, Some(0));
+
+        assert!(ids.zero_length_scid());
+        assert!(ids.zero_length_dcid());
+
+        let (cid, rt) = create_cid_and_reset_token(16, 0xbb);
+        assert_eq!(
+            ids.new_scid(cid.clone(), Some(rt), true, None, false),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(ids.new_dcid(cid, 1, rt, 0), Err(Error::InvalidState));
+    }
+}
@@ -28,6 +28,7 @@ use std::mem::MaybeUninit;
 
 use ring::aead;
 use ring::hkdf;
+use ring::hmac;
 
 use libc::c_int;
 use libc::c_void;
@@ -126,15 +127,31 @@ impl Algorithm {
             Algorithm::ChaCha20_Poly1305 => 12,
         }
     }
+
+    /// Returns the maximum number of packets that can be protected with a
+    /// single key before a key update is required.
+    pub fn confidentiality_limit(self) -> u64 {
+        match self {
+            Algorithm::AES128_GCM => 1 << 23,
+            Algorithm::AES256_GCM => 1 << 23,
+            Algorithm::ChaCha20_Poly1305 => 1 << 62,
+        }
+    }
 }
 
 pub struct Open {
     alg: Algorithm,
 
+    version: u32,
+
+    secret: Vec<u8>,
+
     ctx: EVP_AEAD_CTX,
 
     hp_key: aead::quic::HeaderProtectionKey,
 
+    hp_key_raw: Vec<u8>,
+
     nonce: Vec<u8>,
 }
 
@@ -145,6 +162,10 @@ impl Open {
         Ok(Open {
             alg,
 
+            version: crate::PROTOCOL_VERSION_V1,
+
+            secret: Vec::new(),
+
             ctx: make_aead_ctx(alg, key)?,
 
             hp_key: aead::quic::HeaderProtectionKey::new(
@@ -153,11 +174,15 @@ impl Open {
             )
             .map_err(|_| Error::CryptoFail)?,
 
+            hp_key_raw: Vec::from(hp_key),
+
             nonce: Vec::from(iv),
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Open> {
+    pub fn from_secret(
+        aead: Algorithm, secret: &[u8], version: u32,
+    ) -> Result<Open> {
         let key_len = aead.key_len();
         let nonce_len = aead.nonce_len();
 
@@ -165,11 +190,39 @@ impl Open {
         let mut iv = vec![0; nonce_len];
         let mut pn_key = vec![0; key_len];
 
-        derive_pkt_key(aead, secret, &mut key)?;
-        derive_pkt_iv(aead, secret, &mut iv)?;
-        derive_hdr_key(aead, secret, &mut pn_key)?;
+        derive_pkt_key(aead, secret, version, &mut key)?;
+        derive_pkt_iv(aead, secret, version, &mut iv)?;
+        derive_hdr_key(aead, secret, version, &mut pn_key)?;
+
+        let mut open = Open::new(aead, &key, &iv, &pn_key)?;
+        open.version = version;
+        open.secret = Vec::from(secret);
 
-        Open::new(aead, &key, &iv, &pn_key)
+        Ok(open)
+    }
+
+    /// Derives the packet protection keys for the next key phase.
+    ///
+    /// The header protection key is not updated.
+    pub fn derive_next_packet_key(&self) -> Result<Open> {
+        if self.secret.is_empty() {
+            return Err(Error::CryptoFail);
+        }
+
+        let next_secret =
+            derive_next_secret(self.alg, &self.secret, self.version)?;
+
+        let mut key = vec![0; self.alg.key_len()];
+        let mut iv = vec![0; self.alg.nonce_len()];
+
+        derive_pkt_key(self.alg, &next_secret, self.version, &mut key)?;
+        derive_pkt_iv(self.alg, &next_secret, self.version, &mut iv)?;
+
+        let mut open = Open::new(self.alg, &key, &iv, &self.hp_key_raw)?;
+        open.version = self.version;
+        open.secret = next_secret;
+
+        Ok(open)
     }
 
     pub fn open_with_u64_counter(
@@ -233,10 +286,16 @@ impl Open {
 pub struct Seal {
     alg: Algorithm,
 
+    version: u32,
+
+    secret: Vec<u8>,
+
     ctx: EVP_AEAD_CTX,
 
     hp_key: aead::quic::HeaderProtectionKey,
 
+    hp_key_raw: Vec<u8>,
+
     nonce: Vec<u8>,
 }
 
@@ -247,6 +306,10 @@ impl Seal {
         Ok(Seal {
             alg,
 
+            version: crate::PROTOCOL_VERSION_V1,
+
+            secret: Vec::new(),
+
             ctx: make_aead_ctx(alg, key)?,
 
             hp_key: aead::quic::HeaderProtectionKey::new(
@@ -255,11 +318,15 @@ impl Seal {
             )
             .map_err(|_| Error::CryptoFail)?,
 
+            hp_key_raw: Vec::from(hp_key),
+
             nonce: Vec::from(iv),
         })
     }
 
-    pub fn from_secret(aead: Algorithm, secret: &[u8]) -> Result<Seal> {
+    pub fn from_secret(
+        aead: Algorithm, secret: &[u8], version: u32,
+    ) -> Result<Seal> {
         let key_len = aead.key_len();
         let nonce_len = aead.nonce_len();
 
@@ -267,11 +334,39 @@ impl Seal {
         let mut iv = vec![0; nonce_len];
         let mut pn_key = vec![0; key_len];
 
-        derive_pkt_key(aead, secret, &mut key)?;
-        derive_pkt_iv(aead, secret, &mut iv)?;
-        derive_hdr_key(aead, secret, &mut pn_key)?;
+        derive_pkt_key(aead, secret, version, &mut key)?;
+        derive_pkt_iv(aead, secret, version, &mut iv)?;
+        derive_hdr_key(aead, secret, version, &mut pn_key)?;
+
+        let mut seal = Seal::new(aead, &key, &iv, &pn_key)?;
+        seal.version = version;
+        seal.secret = Vec::from(secret);
+
+        Ok(seal)
+    }
+
+    /// Derives the packet protection keys for the next key phase.
+    ///
+    /// The header protection key is not updated.
+    pub fn derive_next_packet_key(&self) -> Result<Seal> {
+        if self.secret.is_empty() {
+            return Err(Error::CryptoFail);
+        }
+
+        let next_secret =
+            derive_next_secret(self.alg, &self.secret, self.version)?;
+
+        let mut key = vec![0; self.alg.key_len()];
+        let mut iv = vec![0; self.alg.nonce_len()];
+
+        derive_pkt_key(self.alg, &next_secret, self.version, &mut key)?;
+        derive_pkt_iv(self.alg, &next_secret, self.version, &mut iv)?;
 
-        Seal::new(aead, &key, &iv, &pn_key)
+        let mut seal = Seal::new(self.alg, &key, &iv, &self.hp_key_raw)?;
+        seal.version = self.version;
+        seal.secret = next_secret;
+
+        Ok(seal)
     }
 
     pub fn seal_with_u64_counter(
@@ -347,6 +442,18 @@ impl Seal {
     }
 }
 
+/// Derives the stateless reset token for the connection ID `cid` from the
+/// static key `key`.
+pub fn derive_stateless_reset_token(key: &[u8], cid: &[u8]) -> u128 {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let tag = hmac::sign(&key, cid);
+
+    let mut token = [0; 16];
+    token.copy_from_slice(&tag.as_ref()[..16]);
+
+    u128::from_be_bytes(token)
+}
+
 pub fn derive_initial_key_material(
     cid: &[u8], version: u32, is_server: bool,
 ) -> Result<(Open, Seal)> {
@@ -365,9 +472,9 @@ pub fn derive_initial_key_material(
     let mut client_hp_key = vec![0; key_len];
 
     derive_client_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut client_key)?;
-    derive_pkt_iv(aead, &secret, &mut client_iv)?;
-    derive_hdr_key(aead, &secret, &mut client_hp_key)?;
+    derive_pkt_key(aead, &secret, version, &mut client_key)?;
+    derive_pkt_iv(aead, &secret, version, &mut client_iv)?;
+    derive_hdr_key(aead, &secret, version, &mut client_hp_key)?;
 
     // Server.
     let mut server_key = vec![0; key_len];
@@ -375,11 +482,11 @@ pub fn derive_initial_key_material(
     let mut server_hp_key = vec![0; key_len];
 
     derive_server_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut server_key)?;
-    derive_pkt_iv(aead, &secret, &mut server_iv)?;
-    derive_hdr_key(aead, &secret, &mut server_hp_key)?;
+    derive_pkt_key(aead, &secret, version, &mut server_key)?;
+    derive_pkt_iv(aead, &secret, version, &mut server_iv)?;
+    derive_hdr_key(aead, &secret, version, &mut server_hp_key)?;
 
-    let (open, seal) = if is_server {
+    let (mut open, mut seal) = if is_server {
         (
             Open::new(aead, &client_key, &client_iv, &client_hp_key)?,
             Seal::new(aead, &server_key, &server_iv, &server_hp_key)?,
@@ -391,6 +498,9 @@ pub fn derive_initial_key_material(
         )
     };
 
+    open.version = version;
+    seal.version = version;
+
     Ok((open, seal))
 }
 
@@ -400,20 +510,31 @@ fn derive_initial_secret(secret: &[u8], version: u32) -> hkdf::Prk {
         0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
     ];
 
+    const INITIAL_SALT_V2: [u8; 20] = [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe,
+        0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+    ];
+
+    #[cfg(feature = "draft-versions")]
     const INITIAL_SALT_DRAFT29: [u8; 20] = [
         0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1,
         0x9c, 0x61, 0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
     ];
 
+    #[cfg(feature = "draft-versions")]
     const INITIAL_SALT_DRAFT27: [u8; 20] = [
         0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43,
         0x2b, 0xb4, 0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
     ];
 
     let salt = match version {
+        crate::PROTOCOL_VERSION_V2 => &INITIAL_SALT_V2,
+
+        #[cfg(feature = "draft-versions")]
         crate::PROTOCOL_VERSION_DRAFT27 | crate::PROTOCOL_VERSION_DRAFT28 =>
             &INITIAL_SALT_DRAFT27,
 
+        #[cfg(feature = "draft-versions")]
         crate::PROTOCOL_VERSION_DRAFT29 => &INITIAL_SALT_DRAFT29,
 
         _ => &INITIAL_SALT,
@@ -434,9 +555,13 @@ fn derive_server_initial_secret(prk: &hkdf::Prk, out: &mut [u8]) -> Result<()> {
 }
 
 pub fn derive_hdr_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, secret: &[u8], version: u32, out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic hp";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 hp",
+
+        _ => b"quic hp",
+    };
 
     let key_len = aead.key_len();
 
@@ -445,13 +570,17 @@ pub fn derive_hdr_key(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(&secret, label, &mut out[..key_len])
 }
 
 pub fn derive_pkt_key(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, secret: &[u8], version: u32, out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic key";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 key",
+
+        _ => b"quic key",
+    };
 
     let key_len = aead.key_len();
 
@@ -460,13 +589,17 @@ pub fn derive_pkt_key(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    hkdf_expand_label(&secret, label, &mut out[..key_len])
 }
 
 pub fn derive_pkt_iv(
-    aead: Algorithm, secret: &[u8], out: &mut [u8],
+    aead: Algorithm, secret: &[u8], version: u32, out: &mut [u8],
 ) -> Result<()> {
-    const LABEL: &[u8] = b"quic iv";
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 iv",
+
+        _ => b"quic iv",
+    };
 
     let nonce_len = aead.nonce_len();
 
@@ -475,7 +608,24 @@ pub fn derive_pkt_iv(
     }
 
     let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..nonce_len])
+    hkdf_expand_label(&secret, label, &mut out[..nonce_len])
+}
+
+fn derive_next_secret(
+    aead: Algorithm, secret: &[u8], version: u32,
+) -> Result<Vec<u8>> {
+    let label: &[u8] = match version {
+        crate::PROTOCOL_VERSION_V2 => b"quicv2 ku",
+
+        _ => b"quic ku",
+    };
+
+    let mut next_secret = vec![0; secret.len()];
+
+    let secret = hkdf::Prk::new_less_safe(aead.get_ring_digest(), secret);
+    hkdf_expand_label(&secret, label, &mut next_secret)?;
+
+    Ok(next_secret)
 }
 
 fn make_aead_ctx(alg: Algorithm, key: &[u8]) -> Result<EVP_AEAD_CTX> {
@@ -617,21 +767,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_client_pkt_key = [
             0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef,
             0xcb, 0xe3, 0xb1, 0xa2, 0x2d,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_client_pkt_iv = [
             0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25,
             0x5c,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_client_hdr_key = [
             0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e,
             0x99, 0x33, 0xad, 0xed, 0xd2,
@@ -649,21 +817,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_server_pkt_key = [
             0xcf, 0x3a, 0x53, 0x31, 0x65, 0x3c, 0x36, 0x4c, 0x88, 0xf0, 0xf3,
             0x79, 0xb6, 0x06, 0x7e, 0x37,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_server_pkt_iv = [
             0x0a, 0xc1, 0x49, 0x3c, 0xa1, 0x90, 0x58, 0x53, 0xb0, 0xbb, 0xa0,
             0x3e,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_server_hdr_key = [
             0xc2, 0x06, 0xb8, 0xd9, 0xb9, 0xf0, 0xf3, 0x76, 0x44, 0x43, 0x0b,
             0x49, 0x0e, 0xea, 0xa3, 0x14,
@@ -672,6 +858,122 @@ mod tests {
     }
 
     #[test]
+    fn derive_initial_secrets_v2() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut secret = [0; 32];
+        let mut pkt_key = [0; 16];
+        let mut pkt_iv = [0; 12];
+        let mut hdr_key = [0; 16];
+
+        let aead = Algorithm::AES128_GCM;
+
+        let initial_secret =
+            derive_initial_secret(&dcid, crate::PROTOCOL_VERSION_V2);
+
+        // Client.
+        assert!(
+            derive_client_initial_secret(&initial_secret, &mut secret).is_ok()
+        );
+        let expected_client_initial_secret = [
+            0x14, 0xec, 0x9d, 0x6e, 0xb9, 0xfd, 0x7a, 0xf8, 0x3b, 0xf5, 0xa6,
+            0x68, 0xbc, 0x17, 0xa7, 0xe2, 0x83, 0x76, 0x6a, 0xad, 0xe7, 0xec,
+            0xd0, 0x89, 0x1f, 0x70, 0xf9, 0xff, 0x7f, 0x4b, 0xf4, 0x7b,
+        ];
+        assert_eq!(&secret, &expected_client_initial_secret);
+
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut pkt_key
+        )
+        .is_ok());
+        let expected_client_pkt_key = [
+            0x8b, 0x1a, 0x0b, 0xc1, 0x21, 0x28, 0x42, 0x90, 0xa2, 0x9e, 0x09,
+            0x71, 0xb5, 0xcd, 0x04, 0x5d,
+        ];
+        assert_eq!(&pkt_key, &expected_client_pkt_key);
+
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut pkt_iv
+        )
+        .is_ok());
+        let expected_client_pkt_iv = [
+            0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90,
+            0x9f,
+        ];
+        assert_eq!(&pkt_iv, &expected_client_pkt_iv);
+
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut hdr_key
+        )
+        .is_ok());
+        let expected_client_hdr_key = [
+            0x45, 0xb9, 0x5e, 0x15, 0x23, 0x5d, 0x6f, 0x45, 0xa6, 0xb1, 0x9c,
+            0xbc, 0xb0, 0x29, 0x4b, 0xa9,
+        ];
+        assert_eq!(&hdr_key, &expected_client_hdr_key);
+
+        // Server.
+        assert!(
+            derive_server_initial_secret(&initial_secret, &mut secret).is_ok()
+        );
+        let expected_server_initial_secret = [
+            0x02, 0x63, 0xdb, 0x17, 0x82, 0x73, 0x1b, 0xf4, 0x58, 0x8e, 0x7e,
+            0x4d, 0x93, 0xb7, 0x46, 0x39, 0x07, 0xcb, 0x8c, 0xd8, 0x20, 0x0b,
+            0x5d, 0xa5, 0x5a, 0x8b, 0xd4, 0x88, 0xea, 0xfc, 0x37, 0xc1,
+        ];
+        assert_eq!(&secret, &expected_server_initial_secret);
+
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut pkt_key
+        )
+        .is_ok());
+        let expected_server_pkt_key = [
+            0x82, 0xdb, 0x63, 0x78, 0x61, 0xd5, 0x5e, 0x1d, 0x01, 0x1f, 0x19,
+            0xea, 0x71, 0xd5, 0xd2, 0xa7,
+        ];
+        assert_eq!(&pkt_key, &expected_server_pkt_key);
+
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut pkt_iv
+        )
+        .is_ok());
+        let expected_server_pkt_iv = [
+            0xdd, 0x13, 0xc2, 0x76, 0x49, 0x9c, 0x02, 0x49, 0xd3, 0x31, 0x06,
+            0x52,
+        ];
+        assert_eq!(&pkt_iv, &expected_server_pkt_iv);
+
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V2,
+            &mut hdr_key
+        )
+        .is_ok());
+        let expected_server_hdr_key = [
+            0xed, 0xf6, 0xd0, 0x5c, 0x83, 0x12, 0x12, 0x01, 0xb4, 0x36, 0xe1,
+            0x68, 0x77, 0x59, 0x3c, 0x3a,
+        ];
+        assert_eq!(&hdr_key, &expected_server_hdr_key);
+    }
+
+    #[test]
+    #[cfg(feature = "draft-versions")]
     fn derive_initial_secrets_draft29() {
         let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
 
@@ -696,21 +998,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_client_pkt_key = [
             0x17, 0x52, 0x57, 0xa3, 0x1e, 0xb0, 0x9d, 0xea, 0x93, 0x66, 0xd8,
             0xbb, 0x79, 0xad, 0x80, 0xba,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_client_pkt_iv = [
             0x6b, 0x26, 0x11, 0x4b, 0x9c, 0xba, 0x2b, 0x63, 0xa9, 0xe8, 0xdd,
             0x4f,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_client_hdr_key = [
             0x9d, 0xdd, 0x12, 0xc9, 0x94, 0xc0, 0x69, 0x8b, 0x89, 0x37, 0x4a,
             0x9c, 0x07, 0x7a, 0x30, 0x77,
@@ -728,21 +1048,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_server_pkt_key = [
             0x14, 0x9d, 0x0b, 0x16, 0x62, 0xab, 0x87, 0x1f, 0xbe, 0x63, 0xc4,
             0x9b, 0x5e, 0x65, 0x5a, 0x5d,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_server_pkt_iv = [
             0xba, 0xb2, 0xb1, 0x2a, 0x4c, 0x76, 0x01, 0x6a, 0xce, 0x47, 0x85,
             0x6d,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT29,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_server_hdr_key = [
             0xc0, 0xc4, 0x99, 0xa6, 0x5a, 0x60, 0x02, 0x4a, 0x18, 0xa2, 0x50,
             0x97, 0x4e, 0xa0, 0x1d, 0xfa,
@@ -751,6 +1089,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn derive_initial_secrets_draft27() {
         let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
 
@@ -775,21 +1114,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_client_pkt_key = [
             0xaf, 0x7f, 0xd7, 0xef, 0xeb, 0xd2, 0x18, 0x78, 0xff, 0x66, 0x81,
             0x12, 0x48, 0x98, 0x36, 0x94,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_client_pkt_iv = [
             0x86, 0x81, 0x35, 0x94, 0x10, 0xa7, 0x0b, 0xb9, 0xc9, 0x2f, 0x04,
             0x20,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_client_hdr_key = [
             0xa9, 0x80, 0xb8, 0xb4, 0xfb, 0x7d, 0x9f, 0xbc, 0x13, 0xe8, 0x14,
             0xc2, 0x31, 0x64, 0x25, 0x3d,
@@ -807,21 +1164,39 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_server_pkt_key = [
             0x5d, 0x51, 0xda, 0x9e, 0xe8, 0x97, 0xa2, 0x1b, 0x26, 0x59, 0xcc,
             0xc7, 0xe5, 0xbf, 0xa5, 0x77,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_server_pkt_iv = [
             0x5e, 0x5a, 0xe6, 0x51, 0xfd, 0x1e, 0x84, 0x95, 0xaf, 0x13, 0x50,
             0x8b,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_DRAFT27,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_server_hdr_key = [
             0xa8, 0xed, 0x82, 0xe6, 0x66, 0x4f, 0x86, 0x5a, 0xed, 0xf6, 0x10,
             0x69, 0x43, 0xf9, 0x5f, 0xb8,
@@ -843,7 +1218,13 @@ mod tests {
         let mut pkt_iv = [0; 12];
         let mut hdr_key = [0; 32];
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_key
+        )
+        .is_ok());
         let expected_pkt_key = [
             0xc6, 0xd9, 0x8f, 0xf3, 0x44, 0x1c, 0x3f, 0xe1, 0xb2, 0x18, 0x20,
             0x94, 0xf6, 0x9c, 0xaa, 0x2e, 0xd4, 0xb7, 0x16, 0xb6, 0x54, 0x88,
@@ -851,14 +1232,26 @@ mod tests {
         ];
         assert_eq!(&pkt_key, &expected_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut pkt_iv
+        )
+        .is_ok());
         let expected_pkt_iv = [
             0xe0, 0x45, 0x9b, 0x34, 0x74, 0xbd, 0xd0, 0xe4, 0x4a, 0x41, 0xc1,
             0x44,
         ];
         assert_eq!(&pkt_iv, &expected_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key(
+            aead,
+            &secret,
+            crate::PROTOCOL_VERSION_V1,
+            &mut hdr_key
+        )
+        .is_ok());
         let expected_hdr_key = [
             0x25, 0xa2, 0x82, 0xb9, 0xe8, 0x2f, 0x06, 0xf2, 0x1f, 0x48, 0x89,
             0x17, 0xa4, 0xfc, 0x8f, 0x1b, 0x73, 0x57, 0x36, 0x85, 0x60, 0x85,
@@ -866,4 +1259,66 @@ mod tests {
         ];
         assert_eq!(&hdr_key, &expected_hdr_key);
     }
+
+    #[test]
+    fn derive_next_secret_chacha20() {
+        let secret = [
+            0x9a, 0xc3, 0x12, 0xa7, 0xf8, 0x77, 0x46, 0x8e, 0xbe, 0x69, 0x42,
+            0x27, 0x48, 0xad, 0x00, 0xa1, 0x54, 0x43, 0xf1, 0x82, 0x03, 0xa0,
+            0x7d, 0x60, 0x60, 0xf6, 0x88, 0xf3, 0x0f, 0x21, 0x63, 0x2b,
+        ];
+
+        let aead = Algorithm::ChaCha20_Poly1305;
+
+        let next_secret =
+            derive_next_secret(aead, &secret, crate::PROTOCOL_VERSION_V1)
+                .unwrap();
+        let expected_next_secret = [
+            0x12, 0x23, 0x50, 0x47, 0x55, 0x03, 0x6d, 0x55, 0x63, 0x42, 0xee,
+            0x93, 0x61, 0xd2, 0x53, 0x42, 0x1a, 0x82, 0x6c, 0x9e, 0xcd, 0xf3,
+            0xc7, 0x14, 0x86, 0x84, 0xb3, 0x6b, 0x71, 0x48, 0x81, 0xf9,
+        ];
+        assert_eq!(&next_secret[..], &expected_next_secret[..]);
+    }
+
+    #[test]
+    fn derive_next_packet_key() {
+        let secret = [0x42; 32];
+
+        let aead = Algorithm::AES128_GCM;
+
+        let open =
+            Open::from_secret(aead, &secret, crate::PROTOCOL_VERSION_V1).unwrap();
+        let seal =
+            Seal::from_secret(aead, &secret, crate::PROTOCOL_VERSION_V1).unwrap();
+
+        let open_next = open.derive_next_packet_key().unwrap();
+        let seal_next = seal.derive_next_packet_key().unwrap();
+
+        let mut buf = [0; 32];
+        buf[..16].copy_from_slice(b"hello key update");
+
+        let len = seal_next
+            .seal_with_u64_counter(0, b"ad", &mut buf, 16, None)
+            .unwrap();
+        assert_eq!(len, 32);
+
+        // Previous generation keys can't open the packet.
+        let mut tmp = buf;
+        assert_eq!(
+            open.open_with_u64_counter(0, b"ad", &mut tmp),
+            Err(Error::CryptoFail)
+        );
+
+        assert_eq!(open_next.open_with_u64_counter(0, b"ad", &mut buf), Ok(16));
+        assert_eq!(&buf[..16], b"hello key update");
+
+        // Header protection keys are not updated.
+        let sample = [0x01; 16];
+        assert_eq!(open.new_mask(&sample), open_next.new_mask(&sample));
+
+        // Keys not derived from a secret can't be updated.
+        let open = Open::new(aead, &[0; 16], &[0; 12], &[0; 16]).unwrap();
+        assert!(open.derive_next_packet_key().is_err());
+    }
 }
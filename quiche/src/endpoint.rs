@@ -0,0 +1,1054 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Server-side endpoint routing datagrams to connections.
+//!
+//! An [`Endpoint`] owns the server's [`Config`] and all the connections it
+//! accepted. Incoming datagrams are passed to [`recv()`], which routes them
+//! to the connection owning their destination connection ID, or handles
+//! them statelessly: packets with an unsupported version trigger a Version
+//! Negotiation packet, Initial packets without a valid address validation
+//! token trigger a Retry packet when retry is enabled, and short header
+//! packets for unknown connections trigger a stateless reset.
+//!
+//! New connections are queued until the application picks them up with
+//! [`accept()`], and are identified by a handle afterwards. The endpoint
+//! issues new connection IDs to the peer as the connection allows, and stops
+//! routing the ones retired by the peer. Packets for all connections, as well
+//! as stateless packets, are produced by [`send()`].
+//!
+//! [`Endpoint`]: struct.Endpoint.html
+//! [`Config`]: struct.Config.html
+//! [`recv()`]: struct.Endpoint.html#method.recv
+//! [`accept()`]: struct.Endpoint.html#method.accept
+//! [`send()`]: struct.Endpoint.html#method.send
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use std::ops::Bound;
+
+use std::time;
+
+use crate::rand;
+
+use crate::AddressToken;
+use crate::Config;
+use crate::Connection;
+use crate::ConnectionId;
+use crate::EcnCodepoint;
+use crate::Error;
+use crate::Header;
+use crate::RecvInfo;
+use crate::Result;
+use crate::SendInfo;
+use crate::Type;
+
+/// The default length of the connection IDs issued by the endpoint.
+const DEFAULT_CID_LEN: usize = 16;
+
+/// The maximum number of active connection IDs issued to each peer, even if
+/// the peer allows more.
+const MAX_ACTIVE_CIDS: usize = 4;
+
+const STATELESS_RESET_KEY_LEN: usize = 32;
+
+const MAX_STATELESS_PACKET_LEN: usize = 1350;
+
+/// The maximum number of stateless packets waiting to be sent. Responses that
+/// would be queued beyond this are dropped, as peers retransmit anyway.
+const MAX_STATELESS_QUEUE_LEN: usize = 128;
+
+struct Entry {
+    conn: Connection,
+
+    /// The connection IDs routed to the connection.
+    cids: Vec<ConnectionId<'static>>,
+}
+
+/// A server endpoint owning a set of connections.
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut buf = [0; 65535];
+/// # let mut out = [0; 1350];
+/// # let config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:4433").unwrap();
+/// let local = socket.local_addr().unwrap();
+/// let mut endpoint = quiche::Endpoint::new(config);
+/// endpoint.enable_retry(true);
+///
+/// loop {
+///     let (len, from) = socket.recv_from(&mut buf).unwrap();
+///     let info = quiche::RecvInfo {
+///         from,
+///         to: local,
+///         ecn: quiche::EcnCodepoint::NotEct,
+///     };
+///
+///     if let Ok(Some(handle)) = endpoint.recv(&mut buf[..len], info) {
+///         let conn = endpoint.conn_mut(handle).unwrap();
+///         // Handle readable streams.
+///     }
+///
+///     while let Some(handle) = endpoint.accept() {
+///         // Set up application state for the new connection.
+///     }
+///
+///     while let Ok((write, send_info)) = endpoint.send(&mut out) {
+///         socket.send_to(&out[..write], send_info.to).unwrap();
+///     }
+///
+///     while let Some((handle, conn)) = endpoint.closed_next() {
+///         // Release application state of the connection.
+///     }
+/// }
+/// # Ok::<(), quiche::Error>(())
+/// ```
+pub struct Endpoint {
+    config: Config,
+
+    cid_len: usize,
+
+    retry: bool,
+
+    conns: BTreeMap<u64, Entry>,
+
+    ids: HashMap<ConnectionId<'static>, u64>,
+
+    next_handle: u64,
+
+    send_cursor: u64,
+
+    accepted: VecDeque<u64>,
+
+    closed: BTreeSet<u64>,
+
+    stateless: VecDeque<(Vec<u8>, SendInfo)>,
+}
+
+impl Endpoint {
+    /// Creates a new endpoint accepting connections with the given
+    /// configuration.
+    ///
+    /// If no stateless reset key was configured with
+    /// [`set_stateless_reset_key()`], a random one is generated, as it is
+    /// needed to issue new connection IDs. Applications that want stateless
+    /// resets to work across restarts should configure their own key.
+    ///
+    /// [`set_stateless_reset_key()`]:
+    /// struct.Config.html#method.set_stateless_reset_key
+    pub fn new(mut config: Config) -> Endpoint {
+        if config.stateless_reset_key.is_none() {
+            let mut key = [0; STATELESS_RESET_KEY_LEN];
+            rand::rand_bytes(&mut key);
+
+            config.set_stateless_reset_key(&key);
+        }
+
+        Endpoint {
+            config,
+
+            cid_len: DEFAULT_CID_LEN,
+
+            retry: false,
+
+            conns: BTreeMap::new(),
+
+            ids: HashMap::new(),
+
+            next_handle: 0,
+
+            send_cursor: 0,
+
+            accepted: VecDeque::new(),
+
+            closed: BTreeSet::new(),
+
+            stateless: VecDeque::new(),
+        }
+    }
+
+    /// Returns the configuration used for new connections.
+    #[inline]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the configuration used for new connections, to change it.
+    ///
+    /// Existing connections are not affected.
+    #[inline]
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Sets the length of the connection IDs issued by the endpoint.
+    ///
+    /// The length is needed to parse the destination connection ID of short
+    /// header packets, so it can only be changed while the endpoint has no
+    /// connections. [`InvalidState`] is returned otherwise, or if the length
+    /// is zero or larger than [`MAX_CONN_ID_LEN`].
+    ///
+    /// The default value is `16`.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`MAX_CONN_ID_LEN`]: constant.MAX_CONN_ID_LEN.html
+    pub fn set_cid_len(&mut self, len: usize) -> Result<()> {
+        if len == 0 || len > crate::MAX_CONN_ID_LEN || !self.conns.is_empty() {
+            return Err(Error::InvalidState);
+        }
+
+        self.cid_len = len;
+
+        Ok(())
+    }
+
+    /// Configures whether clients' addresses are validated with a Retry
+    /// packet before connections are created.
+    ///
+    /// Clients presenting a valid token received in a NEW_TOKEN frame during
+    /// a previous connection skip the Retry. If no key was configured with
+    /// [`set_address_token_key()`], a random one is generated.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`set_address_token_key()`]:
+    /// struct.Config.html#method.set_address_token_key
+    pub fn enable_retry(&mut self, v: bool) {
+        if v && self.config.address_token_key.is_none() {
+            let mut key = [0; crate::token::KEY_LEN];
+            rand::rand_bytes(&mut key);
+
+            // The key has the right length, so this can't fail.
+            self.config.set_address_token_key(&key).ok();
+        }
+
+        self.retry = v;
+    }
+
+    /// Processes a datagram received from the peer.
+    ///
+    /// On success, returns the handle of the connection the datagram was
+    /// routed to, or `None` if it was handled statelessly or dropped. Any
+    /// packet generated in response, e.g. a Retry packet, is returned by
+    /// [`send()`].
+    ///
+    /// When the datagram creates a new connection, the connection is also
+    /// queued to be returned by [`accept()`]. Errors returned by the
+    /// connection while processing the datagram are passed on, and if the
+    /// connection was just created it is discarded.
+    ///
+    /// [`send()`]: struct.Endpoint.html#method.send
+    /// [`accept()`]: struct.Endpoint.html#method.accept
+    pub fn recv(
+        &mut self, buf: &mut [u8], info: RecvInfo,
+    ) -> Result<Option<u64>> {
+        self.recv_at(buf, info, time::Instant::now())
+    }
+
+    /// Processes a datagram received from the peer at the given time.
+    ///
+    /// This is like [`recv()`], but the current time is provided by the
+    /// caller.
+    ///
+    /// [`recv()`]: struct.Endpoint.html#method.recv
+    pub fn recv_at(
+        &mut self, buf: &mut [u8], info: RecvInfo, now: time::Instant,
+    ) -> Result<Option<u64>> {
+        let len = buf.len();
+
+        let hdr = Header::from_slice(buf, self.cid_len)?;
+
+        if let Some(&handle) = self.ids.get(&hdr.dcid) {
+            let entry = self.conns.get_mut(&handle).ok_or(Error::InvalidState)?;
+
+            let res = entry.conn.recv_at(buf, info, now);
+
+            if entry.conn.is_closed() {
+                self.closed.insert(handle);
+            }
+
+            self.update_cids(handle);
+
+            return res.map(|_| Some(handle));
+        }
+
+        if hdr.ty == Type::Short {
+            // The connection may have been lost, e.g. after a restart, so let
+            // the peer know.
+            let mut out = vec![0; MAX_STATELESS_PACKET_LEN];
+
+            // Packets too small to be answered are silently dropped.
+            if let Ok(written) =
+                crate::stateless_reset(&self.config, &hdr.dcid, len, &mut out)
+            {
+                self.queue_stateless(out, written, &info, now);
+            }
+
+            return Ok(None);
+        }
+
+        if hdr.ty == Type::VersionNegotiation {
+            return Ok(None);
+        }
+
+        // Datagrams carrying a client's Initial packet must be padded, which
+        // limits the amplification of the stateless responses.
+        if len < crate::MIN_CLIENT_INITIAL_LEN {
+            return Ok(None);
+        }
+
+        if !self.config.version_is_supported(hdr.version) {
+            let mut out = vec![0; MAX_STATELESS_PACKET_LEN];
+
+            let written = crate::negotiate_version_with_config(
+                &self.config,
+                &hdr.scid,
+                &hdr.dcid,
+                &mut out,
+            )?;
+
+            self.queue_stateless(out, written, &info, now);
+
+            return Ok(None);
+        }
+
+        if hdr.ty != Type::Initial {
+            return Ok(None);
+        }
+
+        let token = hdr.token.as_deref().unwrap_or(&[]);
+
+        let validated = if token.is_empty() {
+            None
+        } else {
+            crate::validate_token(&self.config, token, info.from)
+        };
+
+        if self.retry && validated.is_none() {
+            let new_scid = new_cid(&self.ids, self.cid_len);
+
+            let new_token =
+                crate::mint_retry_token(&self.config, &hdr.dcid, info.from)?;
+
+            let mut out = vec![0; MAX_STATELESS_PACKET_LEN];
+
+            let written = crate::retry(
+                &hdr.scid,
+                &hdr.dcid,
+                &new_scid,
+                &new_token,
+                hdr.version,
+                &mut out,
+            )?;
+
+            self.queue_stateless(out, written, &info, now);
+
+            return Ok(None);
+        }
+
+        let (scid, odcid) = match validated {
+            // Reuse the source connection ID sent in the Retry packet, which
+            // the client now uses as destination connection ID.
+            Some(AddressToken::Retry { odcid }) => {
+                if hdr.dcid.len() != self.cid_len {
+                    return Ok(None);
+                }
+
+                (hdr.dcid.clone().into_owned(), Some(odcid))
+            },
+
+            _ => (new_cid(&self.ids, self.cid_len), None),
+        };
+
+        let mut conn = crate::accept(
+            &scid,
+            odcid.as_ref(),
+            info.to,
+            info.from,
+            &mut self.config,
+        )?;
+
+        conn.recv_at(buf, info, now)?;
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let mut cids = vec![scid.clone()];
+
+        // The client keeps using the destination connection ID it chose until
+        // it receives the server's response, so route it as well.
+        if odcid.is_none() && hdr.dcid != scid {
+            cids.push(hdr.dcid.clone().into_owned());
+        }
+
+        for cid in &cids {
+            self.ids.insert(cid.clone(), handle);
+        }
+
+        self.conns.insert(handle, Entry { conn, cids });
+
+        self.accepted.push_back(handle);
+
+        self.update_cids(handle);
+
+        Ok(Some(handle))
+    }
+
+    /// Returns the handle of the next connection created by the endpoint, if
+    /// any.
+    ///
+    /// The connection has already processed the datagram that created it, so
+    /// the application should check whether it has data to read.
+    pub fn accept(&mut self) -> Option<u64> {
+        while let Some(handle) = self.accepted.pop_front() {
+            if self.conns.contains_key(&handle) {
+                return Some(handle);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the connection with the given handle.
+    #[inline]
+    pub fn conn(&self, handle: u64) -> Option<&Connection> {
+        self.conns.get(&handle).map(|e| &e.conn)
+    }
+
+    /// Returns the connection with the given handle, to operate on it.
+    ///
+    /// If the application closes the connection, it is returned by
+    /// [`closed_next()`] once closed.
+    ///
+    /// [`closed_next()`]: struct.Endpoint.html#method.closed_next
+    #[inline]
+    pub fn conn_mut(&mut self, handle: u64) -> Option<&mut Connection> {
+        let entry = self.conns.get_mut(&handle)?;
+
+        // The connection may be closed by the application, so it needs to be
+        // checked again by `closed_next()`.
+        self.closed.insert(handle);
+
+        Some(&mut entry.conn)
+    }
+
+    /// Returns the number of connections owned by the endpoint.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns true if the endpoint owns no connection.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// Writes a single QUIC packet to be sent to a peer.
+    ///
+    /// Pending stateless packets are returned first, followed by the packets
+    /// of each connection in turn, so that connections with a lot of data to
+    /// send don't starve the others. [`Done`] is returned when there is
+    /// nothing to send.
+    ///
+    /// If a connection fails to write a packet, it is closed with the
+    /// corresponding error code and the error is not returned.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn send(&mut self, out: &mut [u8]) -> Result<(usize, SendInfo)> {
+        self.send_at(out, time::Instant::now())
+    }
+
+    /// Writes a single QUIC packet to be sent to a peer at the given time.
+    ///
+    /// This is like [`send()`], but the current time is provided by the
+    /// caller.
+    ///
+    /// [`send()`]: struct.Endpoint.html#method.send
+    pub fn send_at(
+        &mut self, out: &mut [u8], now: time::Instant,
+    ) -> Result<(usize, SendInfo)> {
+        if let Some((pkt, info)) = self.stateless.pop_front() {
+            if out.len() < pkt.len() {
+                self.stateless.push_front((pkt, info));
+
+                return Err(Error::BufferTooShort);
+            }
+
+            out[..pkt.len()].copy_from_slice(&pkt);
+
+            return Ok((pkt.len(), info));
+        }
+
+        // Start from the connection following the last one that sent a packet,
+        // and wrap around.
+        let cursor = self.send_cursor;
+
+        let ranges = [
+            (Bound::Included(cursor), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded(cursor)),
+        ];
+
+        for range in ranges {
+            for (&handle, entry) in self.conns.range_mut(range) {
+                match entry.conn.send_at(out, now) {
+                    Ok(v) => {
+                        self.send_cursor = handle + 1;
+
+                        return Ok(v);
+                    },
+
+                    Err(Error::Done) => (),
+
+                    Err(e) => {
+                        entry.conn.close_at(false, e.to_wire(), b"", now).ok();
+
+                        if entry.conn.is_closed() {
+                            self.closed.insert(handle);
+                        }
+                    },
+                }
+            }
+        }
+
+        Err(Error::Done)
+    }
+
+    /// Returns the amount of time until the next connection timeout event.
+    ///
+    /// Once the given duration has elapsed, [`on_timeout()`] should be
+    /// called. `None` is returned if no connection has a timer armed.
+    ///
+    /// [`on_timeout()`]: struct.Endpoint.html#method.on_timeout
+    pub fn timeout(&self) -> Option<time::Duration> {
+        self.timeout_at(time::Instant::now())
+    }
+
+    /// Returns the amount of time from the given time until the next
+    /// connection timeout event.
+    pub fn timeout_at(&self, now: time::Instant) -> Option<time::Duration> {
+        self.conns
+            .values()
+            .filter_map(|e| e.conn.timeout_instant())
+            .min()
+            .map(|t| t.saturating_duration_since(now))
+    }
+
+    /// Processes the timeout events of all connections whose timer expired.
+    pub fn on_timeout(&mut self) {
+        self.on_timeout_at(time::Instant::now())
+    }
+
+    /// Processes the timeout events of all connections whose timer expired
+    /// at the given time.
+    pub fn on_timeout_at(&mut self, now: time::Instant) {
+        let expired: Vec<u64> = self
+            .conns
+            .iter()
+            .filter(
+                |(_, e)| matches!(e.conn.timeout_instant(), Some(t) if t <= now),
+            )
+            .map(|(h, _)| *h)
+            .collect();
+
+        for handle in expired {
+            if let Some(e) = self.conns.get_mut(&handle) {
+                e.conn.on_timeout_at(now);
+
+                if e.conn.is_closed() {
+                    self.closed.insert(handle);
+                }
+            }
+
+            self.update_cids(handle);
+        }
+    }
+
+    /// Removes and returns the next closed connection, if any.
+    ///
+    /// Packets for the connection's IDs are no longer routed to it, so the
+    /// application should call this after processing received datagrams and
+    /// timeouts, and release any state associated with the returned handle.
+    pub fn closed_next(&mut self) -> Option<(u64, Connection)> {
+        let handle = loop {
+            let handle = *self.closed.iter().next()?;
+
+            self.closed.remove(&handle);
+
+            if matches!(self.conns.get(&handle), Some(e) if e.conn.is_closed()) {
+                break handle;
+            }
+        };
+
+        let entry = self.conns.remove(&handle)?;
+
+        for cid in &entry.cids {
+            self.ids.remove(cid);
+        }
+
+        Some((handle, entry.conn))
+    }
+
+    /// Stops routing the connection IDs retired by the peer of a connection,
+    /// and issues new ones as allowed by the peer.
+    fn update_cids(&mut self, handle: u64) {
+        let entry = match self.conns.get_mut(&handle) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        while let Some(cid) = entry.conn.retired_scid_next() {
+            self.ids.remove(&cid);
+            entry.cids.retain(|c| c != &cid);
+        }
+
+        // New connection IDs can only be sent once the handshake is done.
+        if !entry.conn.is_established() ||
+            entry.conn.is_draining() ||
+            entry.conn.is_closed()
+        {
+            return;
+        }
+
+        while entry.conn.source_cids_left() > 0 &&
+            entry.conn.active_source_cids() < MAX_ACTIVE_CIDS
+        {
+            let cid = new_cid(&self.ids, self.cid_len);
+
+            let reset_token =
+                match crate::stateless_reset_token(&self.config, &cid) {
+                    Ok(v) => v,
+
+                    Err(_) => break,
+                };
+
+            if entry.conn.new_source_cid(&cid, reset_token, false).is_err() {
+                break;
+            }
+
+            self.ids.insert(cid.clone(), handle);
+            entry.cids.push(cid);
+        }
+    }
+
+    fn queue_stateless(
+        &mut self, mut out: Vec<u8>, len: usize, info: &RecvInfo,
+        now: time::Instant,
+    ) {
+        if self.stateless.len() >= MAX_STATELESS_QUEUE_LEN {
+            return;
+        }
+
+        out.truncate(len);
+
+        let info = SendInfo {
+            from: info.to,
+            to: info.from,
+            at: now,
+            ecn: EcnCodepoint::NotEct,
+        };
+
+        self.stateless.push_back((out, info));
+    }
+}
+
+/// Generates a random connection ID of the given length that is not routed
+/// to any connection yet.
+fn new_cid(
+    ids: &HashMap<ConnectionId<'static>, u64>, len: usize,
+) -> ConnectionId<'static> {
+    loop {
+        let mut cid = vec![0; len];
+        rand::rand_bytes(&mut cid);
+
+        let cid = ConnectionId::from_vec(cid);
+
+        if !ids.contains_key(&cid) {
+            return cid;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use crate::testing;
+
+    fn client_addr() -> SocketAddr {
+        "127.0.0.1:1234".parse().unwrap()
+    }
+
+    fn server_addr() -> SocketAddr {
+        "127.0.0.1:4321".parse().unwrap()
+    }
+
+    fn connect(config: &mut Config) -> Connection {
+        let mut scid = [0; 16];
+        rand::rand_bytes(&mut scid);
+        let scid = ConnectionId::from_ref(&scid);
+
+        crate::connect(
+            Some("quic.tech"),
+            &scid,
+            client_addr(),
+            server_addr(),
+            config,
+        )
+        .unwrap()
+    }
+
+    /// Sends the client's packets to the endpoint, and the endpoint's packets
+    /// to the client, until neither has anything to send. Returns the number
+    /// of packets sent by the endpoint.
+    fn flush(
+        client: &mut Connection, endpoint: &mut Endpoint, now: time::Instant,
+    ) -> usize {
+        let mut buf = [0; 65535];
+        let mut sent = 0;
+
+        loop {
+            let mut idle = true;
+
+            while let Ok((len, info)) = client.send_at(&mut buf, now) {
+                let info = RecvInfo {
+                    from: info.from,
+                    to: info.to,
+                    ecn: info.ecn,
+                };
+
+                endpoint.recv_at(&mut buf[..len], info, now).ok();
+                idle = false;
+            }
+
+            while let Ok((len, info)) = endpoint.send_at(&mut buf, now) {
+                let info = RecvInfo {
+                    from: info.from,
+                    to: info.to,
+                    ecn: info.ecn,
+                };
+
+                client.recv_at(&mut buf[..len], info, now).ok();
+                sent += 1;
+                idle = false;
+            }
+
+            if idle {
+                return sent;
+            }
+        }
+    }
+
+    #[test]
+    fn accept() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+        let mut client = connect(&mut testing::config(30, 15).unwrap());
+
+        flush(&mut client, &mut endpoint, now);
+
+        assert!(client.is_established());
+
+        let handle = endpoint.accept().unwrap();
+        assert_eq!(endpoint.accept(), None);
+        assert_eq!(endpoint.len(), 1);
+        assert!(endpoint.conn(handle).unwrap().is_established());
+
+        assert_eq!(client.stream_send(0, b"hello", true), Ok(5));
+        flush(&mut client, &mut endpoint, now);
+
+        let server = endpoint.conn_mut(handle).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(server.stream_recv(0, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn retry() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+        endpoint.enable_retry(true);
+
+        let mut client = connect(&mut testing::config(30, 15).unwrap());
+
+        let mut buf = [0; 65535];
+        let (len, info) = client.send_at(&mut buf, now).unwrap();
+        let info = RecvInfo {
+            from: info.from,
+            to: info.to,
+            ecn: info.ecn,
+        };
+
+        // No connection is created until the client echoes the token.
+        assert_eq!(endpoint.recv_at(&mut buf[..len], info, now), Ok(None));
+        assert!(endpoint.is_empty());
+
+        let (len, info) = endpoint.send_at(&mut buf, now).unwrap();
+        assert_eq!(info.to, client_addr());
+
+        let hdr = Header::from_slice(&mut buf[..len], 16).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+
+        let info = RecvInfo {
+            from: info.from,
+            to: info.to,
+            ecn: info.ecn,
+        };
+        assert_eq!(client.recv_at(&mut buf[..len], info, now), Ok(len));
+
+        flush(&mut client, &mut endpoint, now);
+
+        assert!(client.is_established());
+
+        let handle = endpoint.accept().unwrap();
+        assert!(endpoint.conn(handle).unwrap().is_established());
+        assert_eq!(endpoint.len(), 1);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+
+        let mut client_config = Config::new(0xbabababa).unwrap();
+        client_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        client_config.verify_peer(false);
+
+        let mut client = connect(&mut client_config);
+
+        let mut buf = [0; 65535];
+        let (len, info) = client.send_at(&mut buf, now).unwrap();
+        let info = RecvInfo {
+            from: info.from,
+            to: info.to,
+            ecn: info.ecn,
+        };
+
+        assert_eq!(endpoint.recv_at(&mut buf[..len], info, now), Ok(None));
+        assert!(endpoint.is_empty());
+
+        let (len, _) = endpoint.send_at(&mut buf, now).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], 16).unwrap();
+        assert_eq!(hdr.ty, Type::VersionNegotiation);
+        assert!(hdr.versions.unwrap().contains(&crate::PROTOCOL_VERSION));
+
+        assert_eq!(endpoint.send_at(&mut buf, now), Err(Error::Done));
+        assert_eq!(endpoint.accept(), None);
+    }
+
+    #[test]
+    fn stateless_reset() {
+        let now = time::Instant::now();
+
+        let mut server_config = testing::config(30, 15).unwrap();
+        server_config.set_stateless_reset_key(&[0xba; 32]);
+
+        let mut endpoint = Endpoint::new(server_config);
+        let mut client = connect(&mut testing::config(30, 15).unwrap());
+
+        flush(&mut client, &mut endpoint, now);
+        assert!(client.is_established());
+
+        // The server restarts, losing the connection but not the key.
+        let mut server_config = testing::config(30, 15).unwrap();
+        server_config.set_stateless_reset_key(&[0xba; 32]);
+
+        let mut endpoint = Endpoint::new(server_config);
+
+        assert_eq!(client.stream_send(0, &[42; 10], true), Ok(10));
+        flush(&mut client, &mut endpoint, now);
+
+        assert!(endpoint.is_empty());
+        assert!(client.is_stateless_reset());
+        assert!(client.is_draining());
+    }
+
+    #[test]
+    fn stateless_queue_limit() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+
+        let info = RecvInfo {
+            from: client_addr(),
+            to: server_addr(),
+            ecn: EcnCodepoint::NotEct,
+        };
+
+        // Short header packets for unknown connections each trigger a reset.
+        for _ in 0..MAX_STATELESS_QUEUE_LEN + 10 {
+            let mut pkt = [0; 1200];
+            pkt[0] = 0x40;
+            rand::rand_bytes(&mut pkt[1..1 + DEFAULT_CID_LEN]);
+
+            assert_eq!(endpoint.recv_at(&mut pkt, info, now), Ok(None));
+        }
+
+        // Resets that didn't fit in the queue were dropped.
+        let mut buf = [0; 65535];
+        let mut sent = 0;
+
+        while endpoint.send_at(&mut buf, now).is_ok() {
+            sent += 1;
+        }
+
+        assert_eq!(sent, MAX_STATELESS_QUEUE_LEN);
+    }
+
+    #[test]
+    fn connection_ids() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+
+        let mut client_config = testing::config(30, 15).unwrap();
+        client_config.set_active_connection_id_limit(8);
+
+        let mut client = connect(&mut client_config);
+
+        flush(&mut client, &mut endpoint, now);
+        assert!(client.is_established());
+
+        let handle = endpoint.accept().unwrap();
+        let server = endpoint.conn(handle).unwrap();
+
+        // The peer allows 8 connection IDs, but only a few are issued.
+        assert_eq!(server.active_source_cids(), MAX_ACTIVE_CIDS);
+        assert_eq!(client.available_dcids(), MAX_ACTIVE_CIDS - 1);
+
+        let routed = endpoint.ids.len();
+
+        // All the connection IDs issued to the client are routed, as well as
+        // the destination connection ID of the client's first Initial.
+        assert_eq!(routed, MAX_ACTIVE_CIDS + 1);
+
+        // Once the client retires one, it is replaced by a new one.
+        assert_eq!(client.retire_destination_cid(1), Ok(()));
+        flush(&mut client, &mut endpoint, now);
+
+        let server = endpoint.conn(handle).unwrap();
+        assert_eq!(server.active_source_cids(), MAX_ACTIVE_CIDS);
+        assert_eq!(client.available_dcids(), MAX_ACTIVE_CIDS - 1);
+        assert_eq!(endpoint.ids.len(), routed);
+        assert_eq!(endpoint.conns[&handle].cids.len(), routed);
+
+        // Packets using any of the new connection IDs reach the connection.
+        assert_eq!(client.stream_send(0, b"hello", true), Ok(5));
+        flush(&mut client, &mut endpoint, now);
+
+        let server = endpoint.conn_mut(handle).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(server.stream_recv(0, &mut buf), Ok((5, true)));
+    }
+
+    #[test]
+    fn closed_next() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+        let mut client = connect(&mut testing::config(30, 15).unwrap());
+
+        flush(&mut client, &mut endpoint, now);
+
+        let handle = endpoint.accept().unwrap();
+        assert!(endpoint.closed_next().is_none());
+
+        assert_eq!(client.close(true, 0x00, b"bye"), Ok(()));
+        flush(&mut client, &mut endpoint, now);
+
+        assert!(endpoint.conn(handle).unwrap().is_draining());
+        assert!(endpoint.closed_next().is_none());
+
+        // The connection is closed once the draining period is over.
+        let timeout = endpoint.timeout_at(now).unwrap();
+        endpoint.on_timeout_at(now + timeout);
+
+        let (closed, conn) = endpoint.closed_next().unwrap();
+        assert_eq!(closed, handle);
+        assert!(conn.is_closed());
+
+        assert!(endpoint.is_empty());
+        assert!(endpoint.ids.is_empty());
+        assert_eq!(endpoint.timeout_at(now), None);
+    }
+
+    #[test]
+    fn closed_next_before_recv() {
+        let now = time::Instant::now();
+
+        let mut endpoint = Endpoint::new(testing::config(30, 15).unwrap());
+
+        // Add a connection that hasn't processed any packet yet.
+        let scid = ConnectionId::from_ref(&[0xba; 16]).into_owned();
+
+        let conn = crate::accept(
+            &scid,
+            None,
+            server_addr(),
+            client_addr(),
+            &mut testing::config(30, 15).unwrap(),
+        )
+        .unwrap();
+
+        let handle = endpoint.next_handle;
+        endpoint.next_handle += 1;
+
+        endpoint.ids.insert(scid.clone(), handle);
+        endpoint.conns.insert(handle, Entry {
+            conn,
+            cids: vec![scid],
+        });
+
+        // Closing it closes it immediately, so no timer is left to report it.
+        let server = endpoint.conn_mut(handle).unwrap();
+        assert_eq!(server.close(false, 0x01, b"fail"), Ok(()));
+        assert!(server.is_closed());
+
+        assert_eq!(endpoint.timeout_at(now), None);
+
+        let (closed, conn) = endpoint.closed_next().unwrap();
+        assert_eq!(closed, handle);
+        assert!(conn.is_closed());
+
+        assert!(endpoint.is_empty());
+        assert!(endpoint.ids.is_empty());
+        assert!(endpoint.closed_next().is_none());
+    }
+}
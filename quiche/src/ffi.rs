@@ -208,6 +208,26 @@ pub extern fn quiche_config_set_application_protos(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_supported_versions(
+    config: &mut Config, versions: *const u32, versions_len: size_t,
+) -> c_int {
+    let versions = unsafe { slice::from_raw_parts(versions, versions_len) };
+
+    match config.set_supported_versions(versions) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_version_is_supported(
+    config: &Config, version: u32,
+) -> bool {
+    config.version_is_supported(version)
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_max_idle_timeout(config: &mut Config, v: u64) {
     config.set_max_idle_timeout(v);
@@ -270,6 +290,11 @@ pub extern fn quiche_config_set_max_ack_delay(config: &mut Config, v: u64) {
     config.set_max_ack_delay(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_min_ack_delay(config: &mut Config, v: u64) {
+    config.set_min_ack_delay(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_disable_active_migration(
     config: &mut Config, v: bool,
@@ -277,6 +302,43 @@ pub extern fn quiche_config_set_disable_active_migration(
     config.set_disable_active_migration(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_preferred_address(
+    config: &mut Config, v4: *const sockaddr, v4_len: socklen_t,
+    v6: *const sockaddr, v6_len: socklen_t, cid: *const u8, cid_len: size_t,
+    reset_token: *const u8,
+) -> c_int {
+    let ipv4 = match optional_std_addr_from_c(v4, v4_len) {
+        Some(SocketAddr::V4(v)) => Some(v),
+
+        Some(SocketAddr::V6(_)) => return Error::InvalidState.to_c() as c_int,
+
+        None => None,
+    };
+
+    let ipv6 = match optional_std_addr_from_c(v6, v6_len) {
+        Some(SocketAddr::V6(v)) => Some(v),
+
+        Some(SocketAddr::V4(_)) => return Error::InvalidState.to_c() as c_int,
+
+        None => None,
+    };
+
+    let cid = unsafe { slice::from_raw_parts(cid, cid_len) };
+
+    let mut token = [0; 16];
+    token.copy_from_slice(unsafe { slice::from_raw_parts(reset_token, 16) });
+
+    config.set_preferred_address(PreferredAddress {
+        ipv4,
+        ipv6,
+        cid: cid.to_vec().into(),
+        reset_token: u128::from_be_bytes(token),
+    });
+
+    0
+}
+
 #[no_mangle]
 pub extern fn quiche_config_set_cc_algorithm_name(
     config: &mut Config, name: *const c_char,
@@ -301,6 +363,21 @@ pub extern fn quiche_config_enable_hystart(config: &mut Config, v: bool) {
     config.enable_hystart(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_enable_ecn(config: &mut Config, v: bool) {
+    config.enable_ecn(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_discover_pmtu(config: &mut Config, v: bool) {
+    config.discover_pmtu(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_ack_frequency(config: &mut Config, v: bool) {
+    config.enable_ack_frequency(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_enable_dgram(
     config: &mut Config, enabled: bool, recv_queue_len: size_t,
@@ -328,6 +405,47 @@ pub extern fn quiche_config_set_max_stream_window(config: &mut Config, v: u64) {
     config.set_max_stream_window(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_stream_scheduler(
+    config: &mut Config, scheduler: StreamScheduler,
+) {
+    config.set_stream_scheduler(scheduler);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_packets_per_key(config: &mut Config, v: u64) {
+    config.set_max_packets_per_key(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_address_token_key(
+    config: &mut Config, key: *const u8, key_len: size_t,
+) -> c_int {
+    let key = unsafe { slice::from_raw_parts(key, key_len) };
+
+    match config.set_address_token_key(key) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_address_token_lifetime(
+    config: &mut Config, v: u64,
+) {
+    config.set_address_token_lifetime(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_stateless_reset_key(
+    config: &mut Config, key: *const u8, key_len: size_t,
+) {
+    let key = unsafe { slice::from_raw_parts(key, key_len) };
+
+    config.set_stateless_reset_key(key);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_free(config: *mut Config) {
     unsafe { Box::from_raw(config) };
@@ -401,7 +519,8 @@ pub extern fn quiche_header_info(
 #[no_mangle]
 pub extern fn quiche_accept(
     scid: *const u8, scid_len: size_t, odcid: *const u8, odcid_len: size_t,
-    from: &sockaddr, from_len: socklen_t, config: &mut Config,
+    local: &sockaddr, local_len: socklen_t, peer: &sockaddr, peer_len: socklen_t,
+    config: &mut Config,
 ) -> *mut Connection {
     let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
     let scid = ConnectionId::from_ref(scid);
@@ -414,9 +533,10 @@ pub extern fn quiche_accept(
         None
     };
 
-    let from = std_addr_from_c(from, from_len);
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
 
-    match accept(&scid, odcid.as_ref(), from, config) {
+    match accept(&scid, odcid.as_ref(), local, peer, config) {
         Ok(c) => Box::into_raw(Box::new(c)),
 
         Err(_) => ptr::null_mut(),
@@ -425,8 +545,9 @@ pub extern fn quiche_accept(
 
 #[no_mangle]
 pub extern fn quiche_connect(
-    server_name: *const c_char, scid: *const u8, scid_len: size_t, to: &sockaddr,
-    to_len: socklen_t, config: &mut Config,
+    server_name: *const c_char, scid: *const u8, scid_len: size_t,
+    local: &sockaddr, local_len: socklen_t, peer: &sockaddr, peer_len: socklen_t,
+    config: &mut Config,
 ) -> *mut Connection {
     let server_name = if server_name.is_null() {
         None
@@ -437,9 +558,10 @@ pub extern fn quiche_connect(
     let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
     let scid = ConnectionId::from_ref(scid);
 
-    let to = std_addr_from_c(to, to_len);
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
 
-    match connect(server_name, &scid, to, config) {
+    match connect(server_name, &scid, local, peer, config) {
         Ok(c) => Box::into_raw(Box::new(c)),
 
         Err(_) => ptr::null_mut(),
@@ -466,6 +588,26 @@ pub extern fn quiche_negotiate_version(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_negotiate_version_with_config(
+    config: &Config, scid: *const u8, scid_len: size_t, dcid: *const u8,
+    dcid_len: size_t, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
+    let scid = ConnectionId::from_ref(scid);
+
+    let dcid = unsafe { slice::from_raw_parts(dcid, dcid_len) };
+    let dcid = ConnectionId::from_ref(dcid);
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match negotiate_version_with_config(config, &scid, &dcid, out) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_version_is_supported(version: u32) -> bool {
     version_is_supported(version)
@@ -496,11 +638,89 @@ pub extern fn quiche_retry(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_mint_retry_token(
+    config: &Config, odcid: *const u8, odcid_len: size_t, peer: &sockaddr,
+    peer_len: socklen_t, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let odcid = unsafe { slice::from_raw_parts(odcid, odcid_len) };
+    let odcid = ConnectionId::from_ref(odcid);
+
+    let peer = std_addr_from_c(peer, peer_len);
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match mint_retry_token(config, &odcid, peer) {
+        Ok(token) => {
+            if token.len() > out.len() {
+                return Error::BufferTooShort.to_c();
+            }
+
+            out[..token.len()].copy_from_slice(&token);
+
+            token.len() as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_stateless_reset(
+    config: &Config, dcid: *const u8, dcid_len: size_t, recv_len: size_t,
+    out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let dcid = unsafe { slice::from_raw_parts(dcid, dcid_len) };
+    let dcid = ConnectionId::from_ref(dcid);
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match stateless_reset(config, &dcid, recv_len, out) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_validate_token(
+    config: &Config, token: *const u8, token_len: size_t, peer: &sockaddr,
+    peer_len: socklen_t, odcid: *mut u8, odcid_len: *mut size_t,
+) -> c_int {
+    let token = unsafe { slice::from_raw_parts(token, token_len) };
+
+    let peer = std_addr_from_c(peer, peer_len);
+
+    let odcid = unsafe { slice::from_raw_parts_mut(odcid, *odcid_len) };
+
+    match validate_token(config, token, peer) {
+        Some(AddressToken::Retry { odcid: v }) => {
+            if v.len() > odcid.len() {
+                return Error::BufferTooShort.to_c() as c_int;
+            }
+
+            odcid[..v.len()].copy_from_slice(&v);
+
+            unsafe { *odcid_len = v.len() };
+
+            0
+        },
+
+        Some(AddressToken::NewToken) => {
+            unsafe { *odcid_len = 0 };
+
+            0
+        },
+
+        None => Error::Done.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_new_with_tls(
     scid: *const u8, scid_len: size_t, odcid: *const u8, odcid_len: size_t,
-    peer: &sockaddr, peer_len: socklen_t, config: &mut Config, ssl: *mut c_void,
-    is_server: bool,
+    local: &sockaddr, local_len: socklen_t, peer: &sockaddr, peer_len: socklen_t,
+    config: &mut Config, ssl: *mut c_void, is_server: bool,
 ) -> *mut Connection {
     let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
     let scid = ConnectionId::from_ref(scid);
@@ -513,6 +733,7 @@ pub extern fn quiche_conn_new_with_tls(
         None
     };
 
+    let local = std_addr_from_c(local, local_len);
     let peer = std_addr_from_c(peer, peer_len);
 
     let tls = unsafe { tls::Handshake::from_ptr(ssl) };
@@ -520,6 +741,7 @@ pub extern fn quiche_conn_new_with_tls(
     match Connection::with_tls(
         &scid,
         odcid.as_ref(),
+        local,
         peer,
         config,
         tls,
@@ -625,16 +847,55 @@ pub extern fn quiche_conn_set_session(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_token(
+    conn: &mut Connection, token: *const u8, token_len: size_t,
+) -> c_int {
+    let token = unsafe { slice::from_raw_parts(token, token_len) };
+
+    match conn.set_token(token) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_new_token_next(
+    conn: &mut Connection, out: *mut u8, out_len: size_t,
+) -> ssize_t {
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.new_tokens.front() {
+        Some(token) if token.len() > out.len() => Error::BufferTooShort.to_c(),
+
+        Some(_) => {
+            let token = conn.new_token_next().unwrap();
+
+            out[..token.len()].copy_from_slice(&token);
+
+            token.len() as ssize_t
+        },
+
+        None => Error::Done.to_c(),
+    }
+}
+
 #[repr(C)]
 pub struct RecvInfo<'a> {
     from: &'a sockaddr,
     from_len: socklen_t,
+    to: &'a sockaddr,
+    to_len: socklen_t,
+    ecn: u8,
 }
 
 impl<'a> From<&RecvInfo<'a>> for crate::RecvInfo {
     fn from(info: &RecvInfo) -> crate::RecvInfo {
         crate::RecvInfo {
             from: std_addr_from_c(info.from, info.from_len),
+            to: std_addr_from_c(info.to, info.to_len),
+            ecn: info.ecn.into(),
         }
     }
 }
@@ -658,10 +919,14 @@ pub extern fn quiche_conn_recv(
 
 #[repr(C)]
 pub struct SendInfo {
+    from: sockaddr_storage,
+    from_len: socklen_t,
     to: sockaddr_storage,
     to_len: socklen_t,
 
     at: timespec,
+
+    ecn: u8,
 }
 
 #[no_mangle]
@@ -676,9 +941,33 @@ pub extern fn quiche_conn_send(
 
     match conn.send(out) {
         Ok((v, info)) => {
-            out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+            send_info_to_c(&info, out_info);
+
+            v as ssize_t
+        },
 
-            std_time_to_c(&info.at, &mut out_info.at);
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_send_on_path(
+    conn: &mut Connection, out: *mut u8, out_len: size_t, from: *const sockaddr,
+    from_len: socklen_t, to: *const sockaddr, to_len: socklen_t,
+    out_info: &mut SendInfo,
+) -> ssize_t {
+    if out_len > <ssize_t>::max_value() as usize {
+        panic!("The provided buffer is too large");
+    }
+
+    let from = optional_std_addr_from_c(from, from_len);
+    let to = optional_std_addr_from_c(to, to_len);
+
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.send_on_path(out, from, to) {
+        Ok((v, info)) => {
+            send_info_to_c(&info, out_info);
 
             v as ssize_t
         },
@@ -687,6 +976,130 @@ pub extern fn quiche_conn_send(
     }
 }
 
+fn send_info_to_c(info: &crate::SendInfo, out_info: &mut SendInfo) {
+    out_info.from_len = std_addr_to_c(&info.from, &mut out_info.from);
+    out_info.to_len = std_addr_to_c(&info.to, &mut out_info.to);
+
+    std_time_to_c(&info.at, &mut out_info.at);
+
+    out_info.ecn = info.ecn as u8;
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_probe_path(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, seq: *mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    match conn.probe_path(local, peer) {
+        Ok(v) => {
+            unsafe { *seq = v };
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_migrate_source(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t, seq: *mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+
+    match conn.migrate_source(local) {
+        Ok(v) => {
+            unsafe { *seq = v };
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_migrate(
+    conn: &mut Connection, local: &sockaddr, local_len: socklen_t,
+    peer: &sockaddr, peer_len: socklen_t, seq: *mut u64,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    match conn.migrate(local, peer) {
+        Ok(v) => {
+            unsafe { *seq = v };
+            0
+        },
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_is_path_validated(
+    conn: &Connection, local: &sockaddr, local_len: socklen_t, peer: &sockaddr,
+    peer_len: socklen_t,
+) -> c_int {
+    let local = std_addr_from_c(local, local_len);
+    let peer = std_addr_from_c(peer, peer_len);
+
+    match conn.is_path_validated(local, peer) {
+        Ok(v) => v as c_int,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_path_event_next(
+    conn: &mut Connection,
+) -> *mut PathEvent {
+    match conn.path_event_next() {
+        Some(v) => Box::into_raw(Box::new(v)),
+
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_type(ev: &PathEvent) -> u32 {
+    match ev {
+        PathEvent::New(..) => 0,
+
+        PathEvent::Validated(..) => 1,
+
+        PathEvent::FailedValidation(..) => 2,
+
+        PathEvent::Closed(..) => 3,
+
+        PathEvent::PeerMigrated(..) => 4,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_addrs(
+    ev: &PathEvent, local: &mut sockaddr_storage, local_len: &mut socklen_t,
+    peer: &mut sockaddr_storage, peer_len: &mut socklen_t,
+) {
+    let (l, p) = match ev {
+        PathEvent::New(l, p) |
+        PathEvent::Validated(l, p) |
+        PathEvent::FailedValidation(l, p) |
+        PathEvent::Closed(l, p) |
+        PathEvent::PeerMigrated(l, p) => (l, p),
+    };
+
+    *local_len = std_addr_to_c(l, local);
+    *peer_len = std_addr_to_c(p, peer);
+}
+
+#[no_mangle]
+pub extern fn quiche_path_event_free(ev: *mut PathEvent) {
+    unsafe { Box::from_raw(ev) };
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_recv(
     conn: &mut Connection, stream_id: u64, out: *mut u8, out_len: size_t,
@@ -738,6 +1151,17 @@ pub extern fn quiche_conn_stream_priority(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_stream_weight(
+    conn: &mut Connection, stream_id: u64, weight: u8,
+) -> c_int {
+    match conn.stream_weight(stream_id, weight) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_shutdown(
     conn: &mut Connection, stream_id: u64, direction: Shutdown, err: u64,
@@ -834,6 +1258,22 @@ pub extern fn quiche_conn_close(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_set_ack_frequency(
+    conn: &mut Connection, ack_eliciting_threshold: u64, max_ack_delay_us: u64,
+    reordering_threshold: u64,
+) -> c_int {
+    match conn.set_ack_frequency(
+        ack_eliciting_threshold,
+        std::time::Duration::from_micros(max_ack_delay_us),
+        reordering_threshold,
+    ) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_timeout_as_nanos(conn: &mut Connection) -> u64 {
     match conn.timeout() {
@@ -951,6 +1391,11 @@ pub extern fn quiche_conn_is_timed_out(conn: &mut Connection) -> bool {
     conn.is_timed_out()
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_is_stateless_reset(conn: &mut Connection) -> bool {
+    conn.is_stateless_reset()
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_peer_error(
     conn: &mut Connection, is_app: *mut bool, error_code: *mut u64,
@@ -1206,6 +1651,16 @@ fn std_addr_from_c(addr: &sockaddr, addr_len: socklen_t) -> SocketAddr {
     }
 }
 
+fn optional_std_addr_from_c(
+    addr: *const sockaddr, addr_len: socklen_t,
+) -> Option<SocketAddr> {
+    if addr.is_null() || addr_len == 0 {
+        return None;
+    }
+
+    Some(std_addr_from_c(unsafe { &*addr }, addr_len))
+}
+
 fn std_addr_to_c(addr: &SocketAddr, out: &mut sockaddr_storage) -> socklen_t {
     unsafe {
         match addr {
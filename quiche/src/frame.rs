@@ -47,11 +47,11 @@ pub const MAX_DGRAM_OVERHEAD: usize = 2;
 pub const MAX_STREAM_OVERHEAD: usize = 12;
 pub const MAX_STREAM_SIZE: u64 = 1 << 62;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct EcnCounts {
-    ect0_count: u64,
-    ect1_count: u64,
-    ecn_ce_count: u64,
+    pub ect0_count: u64,
+    pub ect1_count: u64,
+    pub ecn_ce_count: u64,
 }
 
 #[derive(Clone, PartialEq)]
@@ -170,6 +170,8 @@ pub enum Frame {
 
     HandshakeDone,
 
+    ImmediateAck,
+
     Datagram {
         data: Vec<u8>,
     },
@@ -177,6 +179,13 @@ pub enum Frame {
     DatagramHeader {
         length: usize,
     },
+
+    AckFrequency {
+        seq_num: u64,
+        ack_eliciting_threshold: u64,
+        request_max_ack_delay: u64,
+        reordering_threshold: u64,
+    },
 }
 
 impl Frame {
@@ -307,8 +316,17 @@ impl Frame {
 
             0x1e => Frame::HandshakeDone,
 
+            0x1f => Frame::ImmediateAck,
+
             0x30 | 0x31 => parse_datagram_frame(frame_type, b)?,
 
+            0xaf => Frame::AckFrequency {
+                seq_num: b.get_varint()?,
+                ack_eliciting_threshold: b.get_varint()?,
+                request_max_ack_delay: b.get_varint()?,
+                reordering_threshold: b.get_varint()?,
+            },
+
             _ => return Err(Error::InvalidFrame),
         };
 
@@ -564,6 +582,10 @@ impl Frame {
                 b.put_varint(0x1e)?;
             },
 
+            Frame::ImmediateAck => {
+                b.put_varint(0x1f)?;
+            },
+
             Frame::Datagram { data } => {
                 encode_dgram_header(data.len() as u64, b)?;
 
@@ -571,6 +593,20 @@ impl Frame {
             },
 
             Frame::DatagramHeader { .. } => (),
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                b.put_varint(0xaf)?;
+
+                b.put_varint(*seq_num)?;
+                b.put_varint(*ack_eliciting_threshold)?;
+                b.put_varint(*request_max_ack_delay)?;
+                b.put_varint(*reordering_threshold)?;
+            },
         }
 
         Ok(before - b.cap())
@@ -775,6 +811,10 @@ impl Frame {
                 1 // frame type
             },
 
+            Frame::ImmediateAck => {
+                1 // frame type
+            },
+
             Frame::Datagram { data } => {
                 1 + // frame type
                 2 + // length, always encode as 2-byte varint
@@ -786,6 +826,19 @@ impl Frame {
                 2 + // length, always encode as 2-byte varint
                 *length // data
             },
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                2 + // frame type
+                octets::varint_len(*seq_num) + // seq_num
+                octets::varint_len(*ack_eliciting_threshold) + // threshold
+                octets::varint_len(*request_max_ack_delay) + // max_ack_delay
+                octets::varint_len(*reordering_threshold) // reordering
+            },
         }
     }
 
@@ -800,6 +853,16 @@ impl Frame {
         )
     }
 
+    pub fn probing(&self) -> bool {
+        matches!(
+            self,
+            Frame::Padding { .. } |
+                Frame::NewConnectionId { .. } |
+                Frame::PathChallenge { .. } |
+                Frame::PathResponse { .. }
+        )
+    }
+
     #[cfg(feature = "qlog")]
     pub fn to_qlog(&self) -> QuicFrame {
         match self {
@@ -979,6 +1042,12 @@ impl Frame {
 
             Frame::HandshakeDone => QuicFrame::HandshakeDone,
 
+            Frame::ImmediateAck => QuicFrame::Unknown {
+                raw_frame_type: 0x1f,
+                raw_length: None,
+                raw: None,
+            },
+
             Frame::Datagram { data } => QuicFrame::Datagram {
                 length: data.len() as u64,
                 raw: None,
@@ -988,6 +1057,12 @@ impl Frame {
                 length: *length as u64,
                 raw: None,
             },
+
+            Frame::AckFrequency { .. } => QuicFrame::Unknown {
+                raw_frame_type: 0xaf,
+                raw_length: None,
+                raw: None,
+            },
         }
     }
 }
@@ -1110,12 +1185,21 @@ impl std::fmt::Debug for Frame {
                 write!(f, "STREAMS_BLOCKED type=uni limit={}", limit)?;
             },
 
-            Frame::NewConnectionId { .. } => {
-                write!(f, "NEW_CONNECTION_ID (TODO)")?;
+            Frame::NewConnectionId {
+                seq_num,
+                retire_prior_to,
+                conn_id,
+                reset_token,
+            } => {
+                write!(
+                    f,
+                    "NEW_CONNECTION_ID seq_num={} retire_prior_to={} conn_id={:02x?} reset_token={:02x?}",
+                    seq_num, retire_prior_to, conn_id, reset_token,
+                )?;
             },
 
-            Frame::RetireConnectionId { .. } => {
-                write!(f, "RETIRE_CONNECTION_ID (TODO)")?;
+            Frame::RetireConnectionId { seq_num } => {
+                write!(f, "RETIRE_CONNECTION_ID seq_num={}", seq_num)?;
             },
 
             Frame::PathChallenge { data } => {
@@ -1150,6 +1234,10 @@ impl std::fmt::Debug for Frame {
                 write!(f, "HANDSHAKE_DONE")?;
             },
 
+            Frame::ImmediateAck => {
+                write!(f, "IMMEDIATE_ACK")?;
+            },
+
             Frame::Datagram { data } => {
                 write!(f, "DATAGRAM len={}", data.len())?;
             },
@@ -1157,6 +1245,22 @@ impl std::fmt::Debug for Frame {
             Frame::DatagramHeader { length } => {
                 write!(f, "DATAGRAM len={}", length)?;
             },
+
+            Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                write!(
+                    f,
+                    "ACK_FREQUENCY seq_num={} thresh={} max_ack_delay={} reordering={}",
+                    seq_num,
+                    ack_eliciting_threshold,
+                    request_max_ack_delay,
+                    reordering_threshold
+                )?;
+            },
         }
 
         Ok(())
@@ -2076,4 +2180,65 @@ mod tests {
 
         assert_eq!(frame_data, data);
     }
+    #[test]
+    fn immediate_ack() {
+        let mut d = [42; 128];
+
+        let frame = Frame::ImmediateAck;
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 1);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(Frame::from_bytes(&mut b, packet::Type::Short), Ok(frame));
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
+
+    #[test]
+    fn ack_frequency() {
+        let mut d = [42; 128];
+
+        let frame = Frame::AckFrequency {
+            seq_num: 1,
+            ack_eliciting_threshold: 9,
+            request_max_ack_delay: 25_000,
+            reordering_threshold: 1,
+        };
+
+        let wire_len = {
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            frame.to_bytes(&mut b).unwrap()
+        };
+
+        assert_eq!(wire_len, 9);
+        assert_eq!(frame.wire_len(), wire_len);
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert_eq!(
+            Frame::from_bytes(&mut b, packet::Type::Short),
+            Ok(frame.clone())
+        );
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Initial).is_err());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::ZeroRTT).is_ok());
+
+        let mut b = octets::Octets::with_slice(&d);
+        assert!(Frame::from_bytes(&mut b, packet::Type::Handshake).is_err());
+    }
 }
@@ -70,6 +70,13 @@ pub extern fn quiche_h3_config_set_qpack_blocked_streams(
     config.set_qpack_blocked_streams(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_config_set_qpack_insertion_policy(
+    config: &mut h3::Config, v: h3::qpack::InsertionPolicy,
+) {
+    config.set_qpack_insertion_policy(v);
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_config_free(config: *mut h3::Config) {
     unsafe { Box::from_raw(config) };
@@ -143,6 +150,8 @@ pub extern fn quiche_h3_event_type(ev: &h3::Event) -> u32 {
         h3::Event::Reset { .. } => 5,
 
         h3::Event::PriorityUpdate { .. } => 6,
+
+        h3::Event::PushPromise { .. } => 7,
     }
 }
 
@@ -161,7 +170,7 @@ pub extern fn quiche_h3_event_for_each_header(
     argp: *mut c_void,
 ) -> c_int {
     match ev {
-        h3::Event::Headers { list, .. } =>
+        h3::Event::Headers { list, .. } | h3::Event::PushPromise { list, .. } =>
             for h in list {
                 let rc = cb(
                     h.name().as_ptr(),
@@ -191,6 +200,15 @@ pub extern fn quiche_h3_event_headers_has_body(ev: &h3::Event) -> bool {
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_event_push_id(ev: &h3::Event) -> u64 {
+    match ev {
+        h3::Event::PushPromise { push_id, .. } => *push_id,
+
+        _ => unreachable!(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_event_free(ev: *mut h3::Event) {
     unsafe { Box::from_raw(ev) };
@@ -271,6 +289,56 @@ pub extern fn quiche_h3_send_body(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_h3_send_push_promise(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
+    headers: *const Header, headers_len: size_t,
+) -> i64 {
+    let req_headers = headers_from_ptr(headers, headers_len);
+
+    match conn.send_push_promise(quic_conn, stream_id, &req_headers) {
+        Ok(v) => v as i64,
+
+        Err(e) => e.to_c() as i64,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_send_push_response(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, push_id: u64,
+    headers: *const Header, headers_len: size_t, fin: bool,
+) -> i64 {
+    let resp_headers = headers_from_ptr(headers, headers_len);
+
+    match conn.send_push_response(quic_conn, push_id, &resp_headers, fin) {
+        Ok(v) => v as i64,
+
+        Err(e) => e.to_c() as i64,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_send_max_push_id(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, push_id: u64,
+) -> c_int {
+    match conn.send_max_push_id(quic_conn, push_id) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_h3_cancel_push(
+    conn: &mut h3::Connection, quic_conn: &mut Connection, push_id: u64,
+) -> c_int {
+    match conn.cancel_push(quic_conn, push_id) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_h3_recv_body(
     conn: &mut h3::Connection, quic_conn: &mut Connection, stream_id: u64,
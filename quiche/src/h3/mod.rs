@@ -60,8 +60,9 @@
 //! ```no_run
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config).unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config).unwrap();
 //! # let h3_config = quiche::h3::Config::new()?;
 //! let h3_conn = quiche::h3::Connection::with_transport(&mut conn, &h3_config)?;
 //! # Ok::<(), quiche::h3::Error>(())
@@ -76,8 +77,9 @@
 //! ```no_run
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let to = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::connect(None, &scid, to, &mut config).unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::connect(None, &scid, local, peer, &mut config).unwrap();
 //! # let h3_config = quiche::h3::Config::new()?;
 //! # let mut h3_conn = quiche::h3::Connection::with_transport(&mut conn, &h3_config)?;
 //! let req = vec![
@@ -98,8 +100,9 @@
 //! ```no_run
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let to = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::connect(None, &scid, to, &mut config).unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::connect(None, &scid, local, peer, &mut config).unwrap();
 //! # let h3_config = quiche::h3::Config::new()?;
 //! # let mut h3_conn = quiche::h3::Connection::with_transport(&mut conn, &h3_config)?;
 //! let req = vec![
@@ -129,8 +132,9 @@
 //!
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config).unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config).unwrap();
 //! # let h3_config = quiche::h3::Config::new()?;
 //! # let mut h3_conn = quiche::h3::Connection::with_transport(&mut conn, &h3_config)?;
 //! loop {
@@ -176,6 +180,8 @@
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((_stream_id, quiche::h3::Event::PushPromise { .. })) => (),
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -197,8 +203,9 @@
 //!
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let to = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::connect(None, &scid, to, &mut config).unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::connect(None, &scid, local, peer, &mut config).unwrap();
 //! # let h3_config = quiche::h3::Config::new()?;
 //! # let mut h3_conn = quiche::h3::Connection::with_transport(&mut conn, &h3_config)?;
 //! loop {
@@ -238,6 +245,11 @@
 //!              // Peer signalled it is going away, handle it.
 //!         },
 //!
+//!         Ok((stream_id, quiche::h3::Event::PushPromise { push_id, list })) => {
+//!             // Server promised to push a response, cancel it or wait for the
+//!             // push stream to be opened.
+//!         },
+//!
 //!         Err(quiche::h3::Error::Done) => {
 //!             // Done reading.
 //!             break;
@@ -259,6 +271,16 @@
 //! repeatedly will generate an [`Event`] for each of these. The application may
 //! use these event to do additional HTTP semantic validation.
 //!
+//! ## Server push
+//!
+//! Clients opt in to server push by calling [`send_max_push_id()`]. A server
+//! can then promise a response on a request stream using
+//! [`send_push_promise()`], and fulfil it on a new push stream using
+//! [`send_push_response()`] and [`send_body()`]. The client receives an
+//! [`Event::PushPromise`] on the request stream, followed by the usual events
+//! on the push stream. Either endpoint can abort a push using
+//! [`cancel_push()`].
+//!
 //! ## HTTP/3 protocol errors
 //!
 //! Quiche is responsible for managing the HTTP/3 connection, ensuring it is in
@@ -280,7 +302,13 @@
 //! [`send_request()`]: struct.Connection.html#method.send_response
 //! [`send_response()`]: struct.Connection.html#method.send_response
 //! [`send_body()`]: struct.Connection.html#method.send_body
+//! [`send_max_push_id()`]: struct.Connection.html#method.send_max_push_id
+//! [`send_push_promise()`]: struct.Connection.html#method.send_push_promise
+//! [`send_push_response()`]: struct.Connection.html#method.send_push_response
+//! [`Event::PushPromise`]: enum.Event.html#variant.PushPromise
+//! [`cancel_push()`]: struct.Connection.html#method.cancel_push
 
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
 
 #[cfg(feature = "sfv")]
@@ -385,6 +413,12 @@ pub enum Error {
     /// QPACK Header block decompression failure.
     QpackDecompressionFailed,
 
+    /// Error on the QPACK encoder stream.
+    QpackEncoderStreamError,
+
+    /// Error on the QPACK decoder stream.
+    QpackDecoderStreamError,
+
     /// Error originated from the transport layer.
     TransportError(crate::Error),
 
@@ -430,6 +464,8 @@ impl Error {
             Error::IdError => 0x108,
             Error::MissingSettings => 0x10A,
             Error::QpackDecompressionFailed => 0x200,
+            Error::QpackEncoderStreamError => 0x201,
+            Error::QpackDecoderStreamError => 0x202,
             Error::BufferTooShort => 0x999,
             Error::TransportError { .. } => 0xFF,
             Error::StreamBlocked => 0xFF,
@@ -466,6 +502,8 @@ impl Error {
             Error::MessageError => -18,
             Error::ConnectError => -19,
             Error::VersionFallback => -20,
+            Error::QpackEncoderStreamError => -21,
+            Error::QpackDecoderStreamError => -22,
 
             Error::TransportError(quic_error) => quic_error.to_c() - 1000,
         }
@@ -505,6 +543,7 @@ pub struct Config {
     max_field_section_size: Option<u64>,
     qpack_max_table_capacity: Option<u64>,
     qpack_blocked_streams: Option<u64>,
+    qpack_insertion_policy: qpack::InsertionPolicy,
 }
 
 impl Config {
@@ -514,6 +553,7 @@ impl Config {
             max_field_section_size: None,
             qpack_max_table_capacity: None,
             qpack_blocked_streams: None,
+            qpack_insertion_policy: qpack::InsertionPolicy::Never,
         })
     }
 
@@ -543,6 +583,19 @@ impl Config {
     pub fn set_qpack_blocked_streams(&mut self, v: u64) {
         self.qpack_blocked_streams = Some(v);
     }
+
+    /// Sets the policy used by the QPACK encoder to decide which header fields
+    /// are inserted into the dynamic table.
+    ///
+    /// Fields are only inserted when the peer allows it with a non-zero
+    /// `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
+    ///
+    /// The default value is [`InsertionPolicy::Never`].
+    ///
+    /// [`InsertionPolicy::Never`]: qpack/enum.InsertionPolicy.html#variant.Never
+    pub fn set_qpack_insertion_policy(&mut self, v: qpack::InsertionPolicy) {
+        self.qpack_insertion_policy = v;
+    }
 }
 
 /// A trait for types with associated string name and value.
@@ -651,6 +704,11 @@ pub enum Event {
     /// [`take_last_priority_update()`] method to take the last received
     /// PRIORITY_UPDATE for a specified stream.
     ///
+    /// When the `sfv` feature is enabled, the received priority is also
+    /// applied to the corresponding QUIC stream automatically, so taking the
+    /// PRIORITY_UPDATE is only needed by applications that want to apply
+    /// their own prioritization logic.
+    ///
     /// This event is triggered once per stream until the last PRIORITY_UPDATE
     /// is taken. It is recommended that applications defer taking the
     /// PRIORITY_UPDATE until after [`poll()`] returns [`Done`].
@@ -662,6 +720,21 @@ pub enum Event {
 
     /// GOAWAY was received.
     GoAway,
+
+    /// PUSH_PROMISE was received.
+    ///
+    /// The event is reported on the request stream the promise was received
+    /// on. The pushed response itself is delivered on a separate push stream,
+    /// whose push ID can be retrieved using the [`stream_push_id()`] method.
+    ///
+    /// [`stream_push_id()`]: struct.Connection.html#method.stream_push_id
+    PushPromise {
+        /// The ID of the promised push.
+        push_id: u64,
+
+        /// The list of header fields of the promised request.
+        list: Vec<Header>,
+    },
 }
 
 /// Extensible Priorities parameters.
@@ -670,7 +743,7 @@ pub enum Event {
 /// Structured Fields Dictionary field value. I.e, use `TryFrom` to parse the
 /// value of a Priority header field or a PRIORITY_UPDATE frame. Using this
 /// trait requires the `sfv` feature to be enabled.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(C)]
 pub struct Priority {
     urgency: u8,
@@ -775,6 +848,25 @@ struct QpackStreams {
     pub decoder_stream_id: Option<u64>,
 }
 
+/// A header block that can't be decoded until more QPACK encoder instructions
+/// are received.
+struct BlockedHeaders {
+    stream_id: u64,
+    header_block: Vec<u8>,
+    payload_len: u64,
+    push_id: Option<u64>,
+}
+
+/// The state of a server push.
+#[derive(Default)]
+struct Push {
+    /// The ID of the push stream carrying the pushed response, if opened.
+    stream_id: Option<u64>,
+
+    /// Whether the push was cancelled by either endpoint.
+    cancelled: bool,
+}
+
 /// An HTTP/3 connection.
 pub struct Connection {
     is_server: bool,
@@ -796,7 +888,14 @@ pub struct Connection {
     local_qpack_streams: QpackStreams,
     peer_qpack_streams: QpackStreams,
 
-    max_push_id: u64,
+    qpack_blocked: VecDeque<BlockedHeaders>,
+
+    local_max_push_id: Option<u64>,
+    peer_max_push_id: Option<u64>,
+
+    next_push_id: u64,
+
+    pushes: BTreeMap<u64, Push>,
 
     finished_streams: VecDeque<u64>,
 
@@ -815,6 +914,15 @@ impl Connection {
         let initial_uni_stream_id = if is_server { 0x3 } else { 0x2 };
         let h3_datagram = if enable_dgram { Some(1) } else { None };
 
+        let mut qpack_encoder = qpack::Encoder::new();
+        qpack_encoder.set_insertion_policy(config.qpack_insertion_policy);
+
+        let mut qpack_decoder = qpack::Decoder::new();
+        qpack_decoder
+            .set_max_table_capacity(config.qpack_max_table_capacity.unwrap_or(0));
+        qpack_decoder
+            .set_max_blocked_streams(config.qpack_blocked_streams.unwrap_or(0));
+
         Ok(Connection {
             is_server,
 
@@ -843,8 +951,8 @@ impl Connection {
             control_stream_id: None,
             peer_control_stream_id: None,
 
-            qpack_encoder: qpack::Encoder::new(),
-            qpack_decoder: qpack::Decoder::new(),
+            qpack_encoder,
+            qpack_decoder,
 
             local_qpack_streams: QpackStreams {
                 encoder_stream_id: None,
@@ -856,7 +964,14 @@ impl Connection {
                 decoder_stream_id: None,
             },
 
-            max_push_id: 0,
+            qpack_blocked: VecDeque::new(),
+
+            local_max_push_id: None,
+            peer_max_push_id: None,
+
+            next_push_id: 0,
+
+            pushes: BTreeMap::new(),
 
             finished_streams: VecDeque::new(),
 
@@ -1013,6 +1128,18 @@ impl Connection {
             return Err(Error::FrameUnexpected);
         }
 
+        Self::apply_priority(conn, stream_id, priority)?;
+
+        self.send_headers(conn, stream_id, headers, fin)?;
+
+        Ok(())
+    }
+
+    /// Applies the given Extensible Priority parameters to the underlying
+    /// QUIC stream.
+    fn apply_priority(
+        conn: &mut super::Connection, stream_id: u64, priority: &Priority,
+    ) -> Result<()> {
         // Clamp and shift urgency into quiche-priority space
         let urgency = priority
             .urgency
@@ -1021,13 +1148,11 @@ impl Connection {
 
         conn.stream_priority(stream_id, urgency, priority.incremental)?;
 
-        self.send_headers(conn, stream_id, headers, fin)?;
-
         Ok(())
     }
 
     fn encode_header_block<T: NameValue>(
-        &mut self, headers: &[T],
+        &mut self, stream_id: u64, headers: &[T],
     ) -> Result<Vec<u8>> {
         let headers_len = headers
             .iter()
@@ -1036,7 +1161,7 @@ impl Connection {
         let mut header_block = vec![0; headers_len];
         let len = self
             .qpack_encoder
-            .encode(headers, &mut header_block)
+            .encode(stream_id, headers, &mut header_block)
             .map_err(|_| Error::InternalError)?;
 
         header_block.truncate(len);
@@ -1056,7 +1181,11 @@ impl Connection {
             self.frames_greased = true;
         }
 
-        let header_block = self.encode_header_block(headers)?;
+        let header_block = self.encode_header_block(stream_id, headers)?;
+
+        // Any new dynamic table entries need to be sent to the peer, whether
+        // or not the header block itself can be sent now.
+        self.send_qpack_instructions(conn)?;
 
         let overhead = octets::varint_len(frame::HEADERS_FRAME_TYPE_ID) +
             octets::varint_len(header_block.len() as u64);
@@ -1066,9 +1195,15 @@ impl Connection {
         match conn.stream_writable(stream_id, overhead + header_block.len()) {
             Ok(true) => (),
 
-            Ok(false) => return Err(Error::StreamBlocked),
+            Ok(false) => {
+                self.qpack_encoder.discard_section(stream_id);
+
+                return Err(Error::StreamBlocked);
+            },
 
             Err(e) => {
+                self.qpack_encoder.discard_section(stream_id);
+
                 if conn.stream_finished(stream_id) {
                     self.streams.remove(&stream_id);
                 }
@@ -1147,8 +1282,9 @@ impl Connection {
         let mut d = [42; 10];
         let mut b = octets::OctetsMut::with_slice(&mut d);
 
-        // Validate that it is sane to send data on the stream.
-        if stream_id % 4 != 0 {
+        // Validate that it is sane to send data on the stream. Other than
+        // request streams, servers can also send data on push streams.
+        if stream_id % 4 != 0 && !(self.is_server && stream_id % 4 == 3) {
             return Err(Error::FrameUnexpected);
         }
 
@@ -1242,6 +1378,250 @@ impl Connection {
         Ok(written)
     }
 
+    /// Sends a PUSH_PROMISE frame on the specified request stream.
+    ///
+    /// The promised request is encoded from the provided list of headers. The
+    /// response can then be pushed on a newly allocated push stream using
+    /// [`send_push_response()`] with the returned push ID.
+    ///
+    /// On success the newly allocated push ID is returned.
+    ///
+    /// The [`IdError`] error is returned when the push ID would exceed the
+    /// limit advertised by the client with MAX_PUSH_ID, or when the client
+    /// didn't enable server push at all.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_push_response()`]: struct.Connection.html#method.send_push_response
+    /// [`IdError`]: enum.Error.html#variant.IdError
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_push_promise<T: NameValue>(
+        &mut self, conn: &mut super::Connection, stream_id: u64, headers: &[T],
+    ) -> Result<u64> {
+        // Only servers can push, and only on request streams.
+        if !self.is_server ||
+            stream_id % 4 != 0 ||
+            !self.streams.contains_key(&stream_id)
+        {
+            return Err(Error::FrameUnexpected);
+        }
+
+        let push_id = self.next_push_id;
+
+        if !matches!(self.peer_max_push_id, Some(max) if push_id <= max) {
+            return Err(Error::IdError);
+        }
+
+        // If we received a GOAWAY from the client, MUST NOT promise pushes
+        // the client will ignore.
+        if matches!(self.peer_goaway_id, Some(id) if push_id >= id) {
+            return Err(Error::FrameUnexpected);
+        }
+
+        let header_block = self.encode_header_block(stream_id, headers)?;
+
+        self.send_qpack_instructions(conn)?;
+
+        let mut d = [42; 20];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        let payload_len = octets::varint_len(push_id) + header_block.len();
+
+        b.put_varint(frame::PUSH_PROMISE_FRAME_TYPE_ID)?;
+        b.put_varint(payload_len as u64)?;
+        b.put_varint(push_id)?;
+        let off = b.off();
+
+        // The frame needs to be sent atomically, so make sure the stream has
+        // enough capacity.
+        match conn.stream_writable(stream_id, off + header_block.len()) {
+            Ok(true) => (),
+
+            Ok(false) => {
+                self.qpack_encoder.discard_section(stream_id);
+
+                return Err(Error::StreamBlocked);
+            },
+
+            Err(e) => {
+                self.qpack_encoder.discard_section(stream_id);
+
+                return Err(e.into());
+            },
+        };
+
+        conn.stream_send(stream_id, &d[..off], false)?;
+
+        // Sending header block separately avoids unnecessary copy.
+        conn.stream_send(stream_id, &header_block, false)?;
+
+        trace!(
+            "{} tx frm PUSH_PROMISE stream={} push_id={} len={}",
+            conn.trace_id(),
+            stream_id,
+            push_id,
+            header_block.len(),
+        );
+
+        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+            let qlog_headers = headers
+                .iter()
+                .map(|h| qlog::events::h3::HttpHeader {
+                    name: String::from_utf8_lossy(h.name()).into_owned(),
+                    value: String::from_utf8_lossy(h.value()).into_owned(),
+                })
+                .collect();
+
+            let frame = Http3Frame::PushPromise {
+                push_id,
+                headers: qlog_headers,
+            };
+            let ev_data = EventData::H3FrameCreated(H3FrameCreated {
+                stream_id,
+                length: Some(payload_len as u64),
+                frame,
+                raw: None,
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        self.pushes.entry(push_id).or_default();
+
+        self.next_push_id += 1;
+
+        Ok(push_id)
+    }
+
+    /// Sends the HTTP/3 response of a promised push.
+    ///
+    /// A new push stream is opened for the push ID returned by
+    /// [`send_push_promise()`], and the provided `headers` are sent on it.
+    /// To include a body, set `fin` as `false` and subsequently call
+    /// [`send_body()`] with the same `conn` and the stream ID returned from
+    /// this method.
+    ///
+    /// On success the push stream's ID is returned.
+    ///
+    /// The [`RequestCancelled`] error is returned when the push was cancelled
+    /// by either endpoint.
+    ///
+    /// The [`StreamBlocked`] error is returned when the underlying QUIC stream
+    /// doesn't have enough capacity for the operation to complete. When this
+    /// happens the application should retry the operation once the stream is
+    /// reported as writable again.
+    ///
+    /// [`send_push_promise()`]: struct.Connection.html#method.send_push_promise
+    /// [`send_body()`]: struct.Connection.html#method.send_body
+    /// [`RequestCancelled`]: enum.Error.html#variant.RequestCancelled
+    /// [`StreamBlocked`]: enum.Error.html#variant.StreamBlocked
+    pub fn send_push_response<T: NameValue>(
+        &mut self, conn: &mut super::Connection, push_id: u64, headers: &[T],
+        fin: bool,
+    ) -> Result<u64> {
+        if !self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        let push_stream_id = match self.pushes.get(&push_id) {
+            Some(push) if push.cancelled => {
+                return Err(Error::RequestCancelled);
+            },
+
+            Some(push) => push.stream_id,
+
+            None => {
+                return Err(Error::IdError);
+            },
+        };
+
+        let stream_id = match push_stream_id {
+            // The push stream was already opened, but the response headers
+            // couldn't be sent, so try again.
+            Some(id) => match self.streams.get(&id) {
+                Some(s) if !s.local_initialized() => id,
+
+                _ => return Err(Error::FrameUnexpected),
+            },
+
+            None => self.open_push_stream(conn, push_id)?,
+        };
+
+        self.send_headers(conn, stream_id, headers, fin)?;
+
+        Ok(stream_id)
+    }
+
+    /// Sends a MAX_PUSH_ID frame to allow the server to push responses.
+    ///
+    /// The server can promise pushes with IDs up to and including `push_id`.
+    /// The limit cannot be reduced, failure to satisfy this condition will
+    /// return an error.
+    ///
+    /// Servers are not allowed to send MAX_PUSH_ID, and the
+    /// [`FrameUnexpected`] error is returned in that case.
+    ///
+    /// [`FrameUnexpected`]: enum.Error.html#variant.FrameUnexpected
+    pub fn send_max_push_id(
+        &mut self, conn: &mut super::Connection, push_id: u64,
+    ) -> Result<()> {
+        if self.is_server {
+            return Err(Error::FrameUnexpected);
+        }
+
+        if matches!(self.local_max_push_id, Some(max) if push_id < max) {
+            return Err(Error::IdError);
+        }
+
+        self.send_control_frame(conn, frame::Frame::MaxPushId { push_id })?;
+
+        self.local_max_push_id = Some(push_id);
+
+        Ok(())
+    }
+
+    /// Cancels a server push.
+    ///
+    /// A CANCEL_PUSH frame is sent to the peer and, if the push stream is
+    /// already open, it is shut down. Clients can use this to refuse promised
+    /// pushes, while servers can use this to abandon pushes they promised.
+    ///
+    /// The [`IdError`] error is returned when the push ID is unknown, and
+    /// [`Done`] is returned if the push was already cancelled.
+    ///
+    /// [`IdError`]: enum.Error.html#variant.IdError
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn cancel_push(
+        &mut self, conn: &mut super::Connection, push_id: u64,
+    ) -> Result<()> {
+        match self.pushes.get(&push_id) {
+            Some(push) if push.cancelled => return Err(Error::Done),
+
+            Some(_) => (),
+
+            None => return Err(Error::IdError),
+        }
+
+        self.send_control_frame(conn, frame::Frame::CancelPush { push_id })?;
+
+        self.push_cancelled(conn, push_id);
+
+        Ok(())
+    }
+
+    /// Returns the push ID of the given push stream, if known.
+    ///
+    /// Clients can use this to associate the response received on a push
+    /// stream with a previously received [`PushPromise`] event.
+    ///
+    /// [`PushPromise`]: enum.Event.html#variant.PushPromise
+    pub fn stream_push_id(&self, stream_id: u64) -> Option<u64> {
+        self.streams.get(&stream_id).and_then(|s| s.push_id())
+    }
+
     /// Returns whether the peer enabled HTTP/3 DATAGRAM frame support.
     ///
     /// Support is signalled by the peer's SETTINGS, so this method always
@@ -1407,6 +1787,67 @@ impl Connection {
         Ok(total)
     }
 
+    /// Takes the next chunk of request or response body data without copying
+    /// it.
+    ///
+    /// This behaves like [`recv_body()`], except that instead of copying data
+    /// into a buffer provided by the application, the buffer the data was
+    /// received into is handed over to the application. At most `max_len`
+    /// bytes are returned, and data from different DATA frames is returned
+    /// in separate chunks.
+    ///
+    /// Once the application is done with the returned data, it needs to
+    /// release it by passing its length to the transport connection's
+    /// [`stream_consume()`] method, for it to be accounted for flow control.
+    ///
+    /// On success the chunk of data is returned, or [`Done`] if there is no
+    /// data to read. If `max_len` is zero, [`BufferTooShort`] is returned.
+    ///
+    /// [`recv_body()`]: struct.Connection.html#method.recv_body
+    /// [`stream_consume()`]: ../struct.Connection.html#method.stream_consume
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
+    pub fn recv_body_zc(
+        &mut self, conn: &mut super::Connection, stream_id: u64, max_len: usize,
+    ) -> Result<crate::SharedBuf> {
+        if max_len == 0 {
+            return Err(Error::BufferTooShort);
+        }
+
+        let stream = self.streams.get_mut(&stream_id).ok_or(Error::Done)?;
+
+        if stream.state() != stream::State::Data {
+            return Err(Error::Done);
+        }
+
+        let (buf, fin) = stream.try_consume_data_zc(conn, max_len)?;
+
+        // If the whole DATA frame was consumed, process incoming data from the
+        // stream, so that if another DATA frame is queued behind it, it can be
+        // returned to the application by the next call.
+        if !fin && stream.state() != stream::State::Data {
+            match self.process_readable_stream(conn, stream_id, false) {
+                Ok(_) => unreachable!(),
+
+                Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            };
+        }
+
+        // While body is being received, the stream is marked as finished only
+        // when all data is read by the application.
+        if conn.stream_finished(stream_id) {
+            self.process_finished_stream(stream_id);
+        }
+
+        if buf.is_empty() {
+            return Err(Error::Done);
+        }
+
+        Ok(buf)
+    }
+
     /// Take the last PRIORITY_UPDATE for a prioritized element ID.
     ///
     /// When the [`poll()`] method returns a [`PriorityUpdate`] event for a
@@ -1480,6 +1921,10 @@ impl Connection {
             return Err(Error::Done);
         }
 
+        // Retry sending QPACK instructions that didn't fit in the streams'
+        // flow control windows before.
+        self.send_qpack_instructions(conn)?;
+
         // Process control streams first.
         if let Some(stream_id) = self.peer_control_stream_id {
             match self.process_control_stream(conn, stream_id) {
@@ -1511,6 +1956,16 @@ impl Connection {
             };
         }
 
+        // Process header blocks that might have been unblocked by new QPACK
+        // encoder instructions.
+        match self.process_blocked_headers(conn) {
+            Ok(ev) => return Ok(ev),
+
+            Err(Error::Done) => (),
+
+            Err(e) => return Err(e),
+        };
+
         // Process finished streams list.
         if let Some(finished) = self.finished_streams.pop_front() {
             return Ok((finished, Event::Finished));
@@ -1566,8 +2021,10 @@ impl Connection {
     ///
     /// When quiche is used in the server role, the `id` parameter is the stream
     /// ID of the highest processed request. This can be any valid ID between 0
-    /// and 2^62-4. However, the ID cannot be increased. Failure to satisfy
-    /// these conditions will return an error.
+    /// and 2^62-4. When quiche is used in the client role, the `id` parameter
+    /// is the lowest push ID the client will not accept. In both cases the ID
+    /// cannot be increased. Failure to satisfy these conditions will return an
+    /// error.
     ///
     /// This method does not close the QUIC connection. Applications are
     /// required to call [`close()`] themselves.
@@ -1576,15 +2033,6 @@ impl Connection {
     pub fn send_goaway(
         &mut self, conn: &mut super::Connection, id: u64,
     ) -> Result<()> {
-        let mut id = id;
-
-        // TODO: server push
-        //
-        // In the meantime always send 0 from client.
-        if !self.is_server {
-            id = 0;
-        }
-
         if self.is_server && id % 4 != 0 {
             return Err(Error::IdError);
         }
@@ -1595,46 +2043,70 @@ impl Connection {
             }
         }
 
-        if let Some(stream_id) = self.control_stream_id {
-            let mut d = [42; 10];
-            let mut b = octets::OctetsMut::with_slice(&mut d);
+        self.send_control_frame(conn, frame::Frame::GoAway { id })?;
 
-            let frame = frame::Frame::GoAway { id };
+        self.local_goaway_id = Some(id);
 
-            let wire_len = frame.to_bytes(&mut b)?;
-            let stream_cap = conn.stream_capacity(stream_id)?;
+        Ok(())
+    }
 
-            if stream_cap < wire_len {
-                return Err(Error::StreamBlocked);
-            }
+    /// Gets the raw settings from peer including unknown and reserved types.
+    ///
+    /// The order of settings is the same as received in the SETTINGS frame.
+    pub fn peer_settings_raw(&self) -> Option<&[(u64, u64)]> {
+        self.peer_settings.raw.as_deref()
+    }
 
-            trace!("{} tx frm {:?}", conn.trace_id(), frame);
+    /// Sends a frame on the local control stream.
+    ///
+    /// The frame is only sent if it fits in the stream's capacity, as control
+    /// frames are not retried.
+    fn send_control_frame(
+        &mut self, conn: &mut super::Connection, frame: frame::Frame,
+    ) -> Result<()> {
+        let stream_id = match self.control_stream_id {
+            Some(v) => v,
 
-            qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
-                let ev_data = EventData::H3FrameCreated(H3FrameCreated {
-                    stream_id,
-                    length: Some(octets::varint_len(id) as u64),
-                    frame: frame.to_qlog(),
-                    raw: None,
-                });
+            None => return Err(Error::InternalError),
+        };
 
-                q.add_event_data_now(ev_data).ok();
-            });
+        let mut d = [42; 10];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
 
-            let off = b.off();
-            conn.stream_send(stream_id, &d[..off], false)?;
+        let wire_len = frame.to_bytes(&mut b)?;
+        let stream_cap = conn.stream_capacity(stream_id)?;
 
-            self.local_goaway_id = Some(id);
+        if stream_cap < wire_len {
+            return Err(Error::StreamBlocked);
         }
 
-        Ok(())
-    }
+        trace!("{} tx frm {:?}", conn.trace_id(), frame);
 
-    /// Gets the raw settings from peer including unknown and reserved types.
-    ///
-    /// The order of settings is the same as received in the SETTINGS frame.
-    pub fn peer_settings_raw(&self) -> Option<&[(u64, u64)]> {
-        self.peer_settings.raw.as_deref()
+        qlog_with_type!(QLOG_FRAME_CREATED, conn.qlog, q, {
+            // Control frames sent here only carry a single varint.
+            let payload_len = match frame {
+                frame::Frame::GoAway { id } |
+                frame::Frame::MaxPushId { push_id: id } |
+                frame::Frame::CancelPush { push_id: id } =>
+                    octets::varint_len(id),
+
+                _ => 0,
+            };
+
+            let ev_data = EventData::H3FrameCreated(H3FrameCreated {
+                stream_id,
+                length: Some(payload_len as u64),
+                frame: frame.to_qlog(),
+                raw: None,
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        let off = b.off();
+        conn.stream_send(stream_id, &d[..off], false)?;
+
+        Ok(())
     }
 
     fn open_uni_stream(
@@ -1653,7 +2125,8 @@ impl Connection {
                 conn.stream_priority(stream_id, 0, true)?;
             },
 
-            // TODO: Server push
+            // Push streams carry responses, so they are scheduled with the
+            // same default priority as request streams.
             stream::HTTP3_PUSH_STREAM_TYPE_ID => (),
 
             // Anything else is a GREASE stream, so make it the least important.
@@ -1674,6 +2147,64 @@ impl Connection {
         Ok(stream_id)
     }
 
+    fn open_push_stream(
+        &mut self, conn: &mut super::Connection, push_id: u64,
+    ) -> Result<u64> {
+        let stream_id =
+            self.open_uni_stream(conn, stream::HTTP3_PUSH_STREAM_TYPE_ID)?;
+
+        let mut d = [0; 8];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        conn.stream_send(stream_id, b.put_varint(push_id)?, false)?;
+
+        self.streams
+            .insert(stream_id, stream::Stream::new(stream_id, true));
+
+        if let Some(push) = self.pushes.get_mut(&push_id) {
+            push.stream_id = Some(stream_id);
+        }
+
+        qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+            let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
+                stream_id,
+                owner: Some(H3Owner::Local),
+                old: None,
+                new: H3StreamType::Push,
+                associated_push_id: Some(push_id),
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        Ok(stream_id)
+    }
+
+    /// Marks the push as cancelled and stops exchanging data on its push
+    /// stream, if any.
+    fn push_cancelled(&mut self, conn: &mut super::Connection, push_id: u64) {
+        let push = self.pushes.entry(push_id).or_default();
+
+        push.cancelled = true;
+
+        if let Some(stream_id) = push.stream_id {
+            let direction = if self.is_server {
+                crate::Shutdown::Write
+            } else {
+                crate::Shutdown::Read
+            };
+
+            // The push stream might have already completed, in which case
+            // there is nothing left to stop.
+            conn.stream_shutdown(
+                stream_id,
+                direction,
+                Error::RequestCancelled.to_wire(),
+            )
+            .ok();
+        }
+    }
+
     fn open_qpack_encoder_stream(
         &mut self, conn: &mut super::Connection,
     ) -> Result<()> {
@@ -1697,6 +2228,49 @@ impl Connection {
         Ok(())
     }
 
+    /// Sends pending QPACK encoder and decoder instructions on the local QPACK
+    /// streams, as much as the streams' capacity allows.
+    fn send_qpack_instructions(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<()> {
+        #[cfg(feature = "qlog")]
+        self.qlog_qpack_events(conn);
+
+        if let Some(stream_id) = self.local_qpack_streams.encoder_stream_id {
+            let buf = self.qpack_encoder.pending_instructions();
+
+            if !buf.is_empty() {
+                let written = match conn.stream_send(stream_id, buf, false) {
+                    Ok(v) => v,
+
+                    Err(crate::Error::Done) => 0,
+
+                    Err(e) => return Err(e.into()),
+                };
+
+                self.qpack_encoder.drain_instructions(written);
+            }
+        }
+
+        if let Some(stream_id) = self.local_qpack_streams.decoder_stream_id {
+            let buf = self.qpack_decoder.pending_instructions();
+
+            if !buf.is_empty() {
+                let written = match conn.stream_send(stream_id, buf, false) {
+                    Ok(v) => v,
+
+                    Err(crate::Error::Done) => 0,
+
+                    Err(e) => return Err(e.into()),
+                };
+
+                self.qpack_decoder.drain_instructions(written);
+            }
+        }
+
+        Ok(())
+    }
+
     fn open_qpack_decoder_stream(
         &mut self, conn: &mut super::Connection,
     ) -> Result<()> {
@@ -1952,10 +2526,22 @@ impl Connection {
     fn process_readable_stream(
         &mut self, conn: &mut super::Connection, stream_id: u64, polling: bool,
     ) -> Result<(u64, Event)> {
-        self.streams
+        // Frames following a header block that is waiting for QPACK encoder
+        // instructions can't be processed until the header block is decoded.
+        if self.is_qpack_blocked(stream_id) {
+            return Err(Error::Done);
+        }
+
+        let stream = self
+            .streams
             .entry(stream_id)
             .or_insert_with(|| stream::Stream::new(stream_id, false));
 
+        // Apply any priority received before the stream was opened.
+        if let Some(priority) = stream.take_pending_priority() {
+            Self::apply_priority(conn, stream_id, &priority)?;
+        }
+
         // We need to get a fresh reference to the stream for each
         // iteration, to avoid borrowing `self` for the entire duration
         // of the loop, because we'll need to borrow it again in the
@@ -2082,6 +2668,8 @@ impl Connection {
                         conn.close(true, e.to_wire(), b"")?;
                         return Err(e);
                     }
+
+                    self.process_push_stream(conn, stream_id, varint)?;
                 },
 
                 stream::State::FrameType => {
@@ -2188,7 +2776,10 @@ impl Connection {
                     {
                         Ok(ev) => return Ok(ev),
 
-                        Err(Error::Done) => (),
+                        Err(Error::Done) =>
+                            if self.is_qpack_blocked(stream_id) {
+                                return Err(Error::Done);
+                            },
 
                         Err(e) => return Err(e),
                     };
@@ -2210,10 +2801,28 @@ impl Connection {
                 stream::State::QpackInstruction => {
                     let mut d = [0; 4096];
 
-                    // Read data from the stream and discard immediately.
+                    // Read data from the stream and process it immediately.
                     loop {
-                        conn.stream_recv(stream_id, &mut d)?;
+                        let (read, _) = match conn.stream_recv(stream_id, &mut d)
+                        {
+                            Ok(v) => v,
+
+                            Err(crate::Error::Done) => break,
+
+                            Err(e) => return Err(e.into()),
+                        };
+
+                        self.process_qpack_instructions(
+                            conn,
+                            stream_id,
+                            &d[..read],
+                        )?;
                     }
+
+                    // Acknowledge new dynamic table entries to the peer.
+                    self.send_qpack_instructions(conn)?;
+
+                    break;
                 },
 
                 stream::State::Drain => {
@@ -2234,7 +2843,92 @@ impl Connection {
         Err(Error::Done)
     }
 
+    fn process_qpack_instructions(
+        &mut self, conn: &mut super::Connection, stream_id: u64, buf: &[u8],
+    ) -> Result<()> {
+        let res = if Some(stream_id) == self.peer_qpack_streams.encoder_stream_id
+        {
+            self.qpack_decoder
+                .control(buf)
+                .map_err(|_| Error::QpackEncoderStreamError)
+        } else {
+            self.qpack_encoder
+                .control(buf)
+                .map_err(|_| Error::QpackDecoderStreamError)
+        };
+
+        #[cfg(feature = "qlog")]
+        self.qlog_qpack_events(conn);
+
+        if let Err(e) = res {
+            conn.close(true, e.to_wire(), b"Error handling QPACK instructions.")?;
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the events generated by the QPACK encoder and decoder to the
+    /// connection's qlog.
+    #[cfg(feature = "qlog")]
+    fn qlog_qpack_events(&mut self, conn: &mut super::Connection) {
+        let now = std::time::Instant::now();
+
+        conn.qlog
+            .add_events(self.qpack_encoder.take_qlog_events(), now);
+        conn.qlog
+            .add_events(self.qpack_decoder.take_qlog_events(), now);
+    }
+
+    fn is_qpack_blocked(&self, stream_id: u64) -> bool {
+        self.qpack_blocked.iter().any(|b| b.stream_id == stream_id)
+    }
+
+    fn process_blocked_headers(
+        &mut self, conn: &mut super::Connection,
+    ) -> Result<(u64, Event)> {
+        // Header blocks that are still blocked are queued again by
+        // process_headers(), so only go through the queue once.
+        for _ in 0..self.qpack_blocked.len() {
+            let blocked = match self.qpack_blocked.pop_front() {
+                Some(v) => v,
+
+                None => break,
+            };
+
+            let stream_id = blocked.stream_id;
+
+            match self.process_headers(
+                conn,
+                stream_id,
+                blocked.header_block,
+                blocked.payload_len,
+                blocked.push_id,
+            ) {
+                Ok(ev) => {
+                    if conn.stream_finished(stream_id) {
+                        self.process_finished_stream(stream_id);
+                    }
+
+                    return Ok(ev);
+                },
+
+                Err(Error::Done) => (),
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::Done)
+    }
+
     fn process_finished_stream(&mut self, stream_id: u64) {
+        // The stream will be finished once its header block is decoded.
+        if self.is_qpack_blocked(stream_id) {
+            return;
+        }
+
         let stream = match self.streams.get_mut(&stream_id) {
             Some(v) => v,
 
@@ -2270,7 +2964,10 @@ impl Connection {
 
         qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
             // HEADERS frames are special case and will be logged below.
-            if !matches!(frame, frame::Frame::Headers { .. }) {
+            if !matches!(
+                frame,
+                frame::Frame::Headers { .. } | frame::Frame::PushPromise { .. }
+            ) {
                 let frame = frame.to_qlog();
                 let ev_data = EventData::H3FrameParsed(H3FrameParsed {
                     stream_id,
@@ -2292,6 +2989,17 @@ impl Connection {
                 raw,
                 ..
             } => {
+                // The dynamic table can only be used if the encoder
+                // instructions can be sent to the peer.
+                if self.local_qpack_streams.encoder_stream_id.is_some() {
+                    self.qpack_encoder.set_max_table_capacity(
+                        qpack_max_table_capacity.unwrap_or(0),
+                    );
+                    self.qpack_encoder.set_max_blocked_streams(
+                        qpack_blocked_streams.unwrap_or(0),
+                    );
+                }
+
                 self.peer_settings = ConnectionSettings {
                     max_field_section_size,
                     qpack_max_table_capacity,
@@ -2325,63 +3033,13 @@ impl Connection {
                     return Err(Error::FrameUnexpected);
                 }
 
-                // Use "infinite" as default value for max_field_section_size if
-                // it is not configured by the application.
-                let max_size = self
-                    .local_settings
-                    .max_field_section_size
-                    .unwrap_or(std::u64::MAX);
-
-                let headers = match self
-                    .qpack_decoder
-                    .decode(&header_block[..], max_size)
-                {
-                    Ok(v) => v,
-
-                    Err(e) => {
-                        let e = match e {
-                            qpack::Error::HeaderListTooLarge =>
-                                Error::ExcessiveLoad,
-
-                            _ => Error::QpackDecompressionFailed,
-                        };
-
-                        conn.close(true, e.to_wire(), b"Error parsing headers.")?;
-
-                        return Err(e);
-                    },
-                };
-
-                qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
-                    let qlog_headers = headers
-                        .iter()
-                        .map(|h| qlog::events::h3::HttpHeader {
-                            name: String::from_utf8_lossy(h.name()).into_owned(),
-                            value: String::from_utf8_lossy(h.value())
-                                .into_owned(),
-                        })
-                        .collect();
-
-                    let frame = Http3Frame::Headers {
-                        headers: qlog_headers,
-                    };
-
-                    let ev_data = EventData::H3FrameParsed(H3FrameParsed {
-                        stream_id,
-                        length: Some(payload_len),
-                        frame,
-                        raw: None,
-                    });
-
-                    q.add_event_data_now(ev_data).ok();
-                });
-
-                let has_body = !conn.stream_finished(stream_id);
-
-                return Ok((stream_id, Event::Headers {
-                    list: headers,
-                    has_body,
-                }));
+                return self.process_headers(
+                    conn,
+                    stream_id,
+                    header_block,
+                    payload_len,
+                    None,
+                );
             },
 
             frame::Frame::Data { .. } => {
@@ -2457,7 +3115,7 @@ impl Connection {
                     return Err(Error::FrameUnexpected);
                 }
 
-                if push_id < self.max_push_id {
+                if matches!(self.peer_max_push_id, Some(max) if push_id < max) {
                     conn.close(
                         true,
                         Error::IdError.to_wire(),
@@ -2467,10 +3125,13 @@ impl Connection {
                     return Err(Error::IdError);
                 }
 
-                self.max_push_id = push_id;
+                self.peer_max_push_id = Some(push_id);
             },
 
-            frame::Frame::PushPromise { .. } => {
+            frame::Frame::PushPromise {
+                push_id,
+                header_block,
+            } => {
                 if self.is_server {
                     conn.close(
                         true,
@@ -2491,10 +3152,29 @@ impl Connection {
                     return Err(Error::FrameUnexpected);
                 }
 
-                // TODO: implement more checks and PUSH_PROMISE event
+                if !matches!(self.local_max_push_id, Some(max) if push_id <= max)
+                {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"PUSH_PROMISE received with ID larger than MAX_PUSH_ID",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                self.pushes.entry(push_id).or_default();
+
+                return self.process_headers(
+                    conn,
+                    stream_id,
+                    header_block,
+                    payload_len,
+                    Some(push_id),
+                );
             },
 
-            frame::Frame::CancelPush { .. } => {
+            frame::Frame::CancelPush { push_id } => {
                 if Some(stream_id) != self.peer_control_stream_id {
                     conn.close(
                         true,
@@ -2505,7 +3185,23 @@ impl Connection {
                     return Err(Error::FrameUnexpected);
                 }
 
-                // TODO: implement CANCEL_PUSH frame
+                let max_push_id = if self.is_server {
+                    self.peer_max_push_id
+                } else {
+                    self.local_max_push_id
+                };
+
+                if !matches!(max_push_id, Some(max) if push_id <= max) {
+                    conn.close(
+                        true,
+                        Error::IdError.to_wire(),
+                        b"CANCEL_PUSH received with ID larger than MAX_PUSH_ID",
+                    )?;
+
+                    return Err(Error::IdError);
+                }
+
+                self.push_cancelled(conn, push_id);
             },
 
             frame::Frame::PriorityUpdateRequest {
@@ -2568,6 +3264,24 @@ impl Connection {
                         || stream::Stream::new(prioritized_element_id, false),
                     );
 
+                // Apply the new priority to the transport stream right away if
+                // it's already open, otherwise defer it until the peer opens
+                // the stream.
+                #[cfg(feature = "sfv")]
+                if let Ok(priority) =
+                    Priority::try_from(priority_field_value.as_slice())
+                {
+                    if conn.streams.get(prioritized_element_id).is_some() {
+                        Self::apply_priority(
+                            conn,
+                            prioritized_element_id,
+                            &priority,
+                        )?;
+                    } else {
+                        stream.set_pending_priority(priority);
+                    }
+                }
+
                 let had_priority_update = stream.has_last_priority_update();
                 stream.set_last_priority_update(Some(priority_field_value));
 
@@ -2622,59 +3336,210 @@ impl Connection {
 
         Err(Error::Done)
     }
-}
 
-/// Generates an HTTP/3 GREASE variable length integer.
-fn grease_value() -> u64 {
-    let n = super::rand::rand_u64_uniform(148_764_065_110_560_899);
-    31 * n + 33
-}
+    fn process_headers(
+        &mut self, conn: &mut super::Connection, stream_id: u64,
+        header_block: Vec<u8>, payload_len: u64, push_id: Option<u64>,
+    ) -> Result<(u64, Event)> {
+        // Use "infinite" as default value for max_field_section_size if
+        // it is not configured by the application.
+        let max_size = self
+            .local_settings
+            .max_field_section_size
+            .unwrap_or(std::u64::MAX);
+
+        let headers = match self.qpack_decoder.decode(
+            stream_id,
+            &header_block[..],
+            max_size,
+        ) {
+            Ok(v) => v,
 
-#[doc(hidden)]
-pub mod testing {
-    use super::*;
+            Err(qpack::Error::Blocked) => {
+                trace!(
+                    "{} headers blocked on QPACK encoder stream={}",
+                    conn.trace_id(),
+                    stream_id
+                );
 
-    use crate::testing;
+                self.qpack_blocked.push_back(BlockedHeaders {
+                    stream_id,
+                    header_block,
+                    payload_len,
+                    push_id,
+                });
 
-    /// Session is an HTTP/3 test helper structure. It holds a client, server
-    /// and pipe that allows them to communicate.
-    ///
-    /// `default()` creates a session with some sensible default
-    /// configuration. `with_configs()` allows for providing a specific
-    /// configuration.
-    ///
-    /// `handshake()` performs all the steps needed to establish an HTTP/3
-    /// connection.
-    ///
-    /// Some utility functions are provided that make it less verbose to send
-    /// request, responses and individual headers. The full quiche API remains
-    /// available for any test that need to do unconventional things (such as
-    /// bad behaviour that triggers errors).
-    pub struct Session {
-        pub pipe: testing::Pipe,
-        pub client: Connection,
-        pub server: Connection,
-    }
+                return Err(Error::Done);
+            },
 
-    impl Session {
-        pub fn default() -> Result<Session> {
-            let mut config = crate::Config::new(crate::PROTOCOL_VERSION)?;
-            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-            config.load_priv_key_from_pem_file("examples/cert.key")?;
-            config.set_application_protos(b"\x02h3")?;
-            config.set_initial_max_data(1500);
-            config.set_initial_max_stream_data_bidi_local(150);
-            config.set_initial_max_stream_data_bidi_remote(150);
-            config.set_initial_max_stream_data_uni(150);
-            config.set_initial_max_streams_bidi(5);
-            config.set_initial_max_streams_uni(5);
-            config.verify_peer(false);
-            config.enable_dgram(true, 3, 3);
-            config.set_ack_delay_exponent(8);
+            Err(e) => {
+                let e = match e {
+                    qpack::Error::HeaderListTooLarge => Error::ExcessiveLoad,
 
-            let h3_config = Config::new()?;
-            Session::with_configs(&mut config, &h3_config)
-        }
+                    _ => Error::QpackDecompressionFailed,
+                };
+
+                conn.close(true, e.to_wire(), b"Error parsing headers.")?;
+
+                return Err(e);
+            },
+        };
+
+        // Acknowledge the header block if it referenced the dynamic table.
+        self.send_qpack_instructions(conn)?;
+
+        qlog_with_type!(QLOG_FRAME_PARSED, conn.qlog, q, {
+            let qlog_headers = headers
+                .iter()
+                .map(|h| qlog::events::h3::HttpHeader {
+                    name: String::from_utf8_lossy(h.name()).into_owned(),
+                    value: String::from_utf8_lossy(h.value()).into_owned(),
+                })
+                .collect();
+
+            let frame = match push_id {
+                Some(push_id) => Http3Frame::PushPromise {
+                    push_id,
+                    headers: qlog_headers,
+                },
+
+                None => Http3Frame::Headers {
+                    headers: qlog_headers,
+                },
+            };
+
+            let ev_data = EventData::H3FrameParsed(H3FrameParsed {
+                stream_id,
+                length: Some(payload_len),
+                frame,
+                raw: None,
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        if let Some(push_id) = push_id {
+            return Ok((stream_id, Event::PushPromise {
+                push_id,
+                list: headers,
+            }));
+        }
+
+        let has_body = !conn.stream_finished(stream_id);
+
+        Ok((stream_id, Event::Headers {
+            list: headers,
+            has_body,
+        }))
+    }
+
+    /// Validates the push ID of a newly received push stream.
+    fn process_push_stream(
+        &mut self, conn: &mut super::Connection, stream_id: u64, push_id: u64,
+    ) -> Result<()> {
+        if !matches!(self.local_max_push_id, Some(max) if push_id <= max) {
+            conn.close(
+                true,
+                Error::IdError.to_wire(),
+                b"Push stream received with ID larger than MAX_PUSH_ID",
+            )?;
+
+            return Err(Error::IdError);
+        }
+
+        let push = self.pushes.entry(push_id).or_default();
+
+        if push.stream_id.is_some() {
+            conn.close(
+                true,
+                Error::IdError.to_wire(),
+                b"Received multiple push streams with the same push ID",
+            )?;
+
+            return Err(Error::IdError);
+        }
+
+        push.stream_id = Some(stream_id);
+
+        qlog_with_type!(QLOG_STREAM_TYPE_SET, conn.qlog, q, {
+            let ev_data = EventData::H3StreamTypeSet(H3StreamTypeSet {
+                stream_id,
+                owner: Some(H3Owner::Remote),
+                old: None,
+                new: H3StreamType::Push,
+                associated_push_id: Some(push_id),
+            });
+
+            q.add_event_data_now(ev_data).ok();
+        });
+
+        // The push was cancelled before its stream arrived, so there is no
+        // point in reading the response.
+        if push.cancelled {
+            conn.stream_shutdown(
+                stream_id,
+                crate::Shutdown::Read,
+                Error::RequestCancelled.to_wire(),
+            )?;
+
+            return Err(Error::Done);
+        }
+
+        Ok(())
+    }
+}
+
+/// Generates an HTTP/3 GREASE variable length integer.
+fn grease_value() -> u64 {
+    let n = super::rand::rand_u64_uniform(148_764_065_110_560_899);
+    31 * n + 33
+}
+
+#[doc(hidden)]
+pub mod testing {
+    use super::*;
+
+    use crate::testing;
+
+    /// Session is an HTTP/3 test helper structure. It holds a client, server
+    /// and pipe that allows them to communicate.
+    ///
+    /// `default()` creates a session with some sensible default
+    /// configuration. `with_configs()` allows for providing a specific
+    /// configuration.
+    ///
+    /// `handshake()` performs all the steps needed to establish an HTTP/3
+    /// connection.
+    ///
+    /// Some utility functions are provided that make it less verbose to send
+    /// request, responses and individual headers. The full quiche API remains
+    /// available for any test that need to do unconventional things (such as
+    /// bad behaviour that triggers errors).
+    pub struct Session {
+        pub pipe: testing::Pipe,
+        pub client: Connection,
+        pub server: Connection,
+    }
+
+    impl Session {
+        pub fn default() -> Result<Session> {
+            let mut config = crate::Config::new(crate::PROTOCOL_VERSION)?;
+            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+            config.load_priv_key_from_pem_file("examples/cert.key")?;
+            config.set_application_protos(b"\x02h3")?;
+            config.set_initial_max_data(1500);
+            config.set_initial_max_stream_data_bidi_local(150);
+            config.set_initial_max_stream_data_bidi_remote(150);
+            config.set_initial_max_stream_data_uni(150);
+            config.set_initial_max_streams_bidi(5);
+            config.set_initial_max_streams_uni(5);
+            config.verify_peer(false);
+            config.enable_dgram(true, 3, 3);
+            config.set_ack_delay_exponent(8);
+
+            let h3_config = Config::new()?;
+            Session::with_configs(&mut config, &h3_config)
+        }
 
         pub fn with_configs(
             config: &mut crate::Config, h3_config: &Config,
@@ -3116,6 +3981,73 @@ mod tests {
         assert_eq!(s.poll_client(), Ok((stream, Event::Finished)));
     }
 
+    #[test]
+    /// Send a request with multiple DATA frames, and read the body without
+    /// copying it.
+    fn request_many_chunks_zero_copy() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, req) = s.send_request(false).unwrap();
+
+        let total_data_frames = 3;
+
+        for _ in 0..total_data_frames - 1 {
+            s.send_body_client(stream, false).unwrap();
+        }
+
+        let body = s.send_body_client(stream, true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: true,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Data)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(
+            s.server
+                .recv_body_zc(&mut s.pipe.server, stream, 0)
+                .unwrap_err(),
+            Error::BufferTooShort
+        );
+
+        for _ in 0..total_data_frames {
+            // Chunks are limited by the maximum length.
+            let buf = s
+                .server
+                .recv_body_zc(&mut s.pipe.server, stream, 4)
+                .unwrap();
+            assert_eq!(&buf[..], &body[..4]);
+
+            // Chunks don't span multiple DATA frames.
+            let buf = s
+                .server
+                .recv_body_zc(&mut s.pipe.server, stream, usize::MAX)
+                .unwrap();
+            assert_eq!(&buf[..], &body[4..]);
+        }
+
+        // All the body data was handed over, and can now be released.
+        assert_eq!(
+            s.pipe
+                .server
+                .stream_consume(stream, body.len() * total_data_frames),
+            Ok(())
+        );
+
+        assert_eq!(
+            s.server
+                .recv_body_zc(&mut s.pipe.server, stream, usize::MAX)
+                .unwrap_err(),
+            Error::Done
+        );
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+    }
+
     #[test]
     /// Send a request with multiple DATA frames, get a response with no body.
     fn request_many_chunks_response_no_body() {
@@ -3492,7 +4424,7 @@ mod tests {
 
         let (stream, req) = s.send_request(false).unwrap();
 
-        let header_block = s.client.encode_header_block(&req).unwrap();
+        let header_block = s.client.encode_header_block(stream, &req).unwrap();
 
         s.send_frame_client(
             frame::Frame::PushPromise {
@@ -3519,6 +4451,13 @@ mod tests {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
+        s.send_frame_client(
+            frame::Frame::MaxPushId { push_id: 1 },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
         s.send_frame_client(
             frame::Frame::CancelPush { push_id: 1 },
             s.client.control_stream_id.unwrap(),
@@ -3559,6 +4498,12 @@ mod tests {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
+        s.client.send_max_push_id(&mut s.pipe.client, 1).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
         s.send_frame_server(
             frame::Frame::CancelPush { push_id: 1 },
             s.server.control_stream_id.unwrap(),
@@ -3570,79 +4515,419 @@ mod tests {
     }
 
     #[test]
-    /// Send a GOAWAY frame from the client.
-    fn goaway_from_client_good() {
+    /// Promise and push a response from the server.
+    fn server_push() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        s.client.send_goaway(&mut s.pipe.client, 100).unwrap();
+        s.client.send_max_push_id(&mut s.pipe.client, 5).unwrap();
 
-        s.advance().ok();
+        let (stream, req) = s.send_request(true).unwrap();
 
-        // TODO: server push
-        assert_eq!(s.poll_server(), Ok((0, Event::GoAway)));
-    }
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
 
-    #[test]
-    /// Send a GOAWAY frame from the server.
-    fn goaway_from_server_good() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
 
-        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+        let push_req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/style.css"),
+        ];
+
+        let push_id = s
+            .server
+            .send_push_promise(&mut s.pipe.server, stream, &push_req)
+            .unwrap();
+        assert_eq!(push_id, 0);
 
         s.advance().ok();
 
-        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
-    }
+        let ev_promise = Event::PushPromise {
+            push_id,
+            list: push_req,
+        };
 
-    #[test]
-    /// A client MUST NOT send a request after it receives GOAWAY.
-    fn client_request_after_goaway() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        assert_eq!(s.poll_client(), Ok((stream, ev_promise)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
 
-        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+        let resp = vec![Header::new(b":status", b"200")];
 
-        s.advance().ok();
+        let push_stream = s
+            .server
+            .send_push_response(&mut s.pipe.server, push_id, &resp, false)
+            .unwrap();
+        assert_eq!(push_stream % 4, 3);
 
-        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+        let body = s.send_body_server(push_stream, true).unwrap();
 
-        assert_eq!(s.send_request(true), Err(Error::FrameUnexpected));
-    }
+        let mut recv_buf = vec![0; body.len()];
 
-    #[test]
-    /// Send a GOAWAY frame from the server, using an invalid goaway ID.
-    fn goaway_from_server_invalid_id() {
-        let mut s = Session::default().unwrap();
-        s.handshake().unwrap();
+        let ev_headers = Event::Headers {
+            list: resp,
+            has_body: true,
+        };
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 1 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        assert_eq!(s.poll_client(), Ok((push_stream, ev_headers)));
+        assert_eq!(s.client.stream_push_id(push_stream), Some(push_id));
 
-        assert_eq!(s.poll_client(), Err(Error::IdError));
+        assert_eq!(s.poll_client(), Ok((push_stream, Event::Data)));
+        assert_eq!(
+            s.recv_body_client(push_stream, &mut recv_buf),
+            Ok(body.len())
+        );
+
+        assert_eq!(s.poll_client(), Ok((push_stream, Event::Finished)));
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        // The same push can't be fulfilled twice.
+        assert_eq!(
+            s.server.send_push_response(
+                &mut s.pipe.server,
+                push_id,
+                &[Header::new(b":status", b"200")],
+                true
+            ),
+            Err(Error::FrameUnexpected)
+        );
     }
 
     #[test]
-    /// Send multiple GOAWAY frames from the server, that increase the goaway
-    /// ID.
-    fn goaway_from_server_increase_id() {
+    /// Pushes are not allowed until the client sends MAX_PUSH_ID, and
+    /// are limited by it afterwards.
+    fn push_promise_limit() {
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 0 },
-            s.server.control_stream_id.unwrap(),
-            false,
-        )
-        .unwrap();
+        let (stream, req) = s.send_request(true).unwrap();
 
-        s.send_frame_server(
-            frame::Frame::GoAway { id: 4 },
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Err(Error::IdError)
+        );
+
+        s.client.send_max_push_id(&mut s.pipe.client, 1).unwrap();
+
+        assert_eq!(
+            s.client.send_max_push_id(&mut s.pipe.client, 0),
+            Err(Error::IdError)
+        );
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Ok(0)
+        );
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Ok(1)
+        );
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Err(Error::IdError)
+        );
+    }
+
+    #[test]
+    /// Send a PUSH_PROMISE frame from the server with a push ID larger than
+    /// the client's limit.
+    fn push_promise_exceeds_max_push_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_max_push_id(&mut s.pipe.client, 1).unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        let header_block = s.server.encode_header_block(stream, &req).unwrap();
+
+        s.send_frame_server(
+            frame::Frame::PushPromise {
+                push_id: 2,
+                header_block,
+            },
+            stream,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Cancel a promised push from the client.
+    fn cancel_push_by_client() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_max_push_id(&mut s.pipe.client, 5).unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        let push_id = s
+            .server
+            .send_push_promise(&mut s.pipe.server, stream, &req)
+            .unwrap();
+
+        s.advance().ok();
+
+        let ev_promise = Event::PushPromise { push_id, list: req };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_promise)));
+
+        assert_eq!(
+            s.client.cancel_push(&mut s.pipe.client, push_id + 1),
+            Err(Error::IdError)
+        );
+
+        assert_eq!(s.client.cancel_push(&mut s.pipe.client, push_id), Ok(()));
+        assert_eq!(
+            s.client.cancel_push(&mut s.pipe.client, push_id),
+            Err(Error::Done)
+        );
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert_eq!(
+            s.server.send_push_response(
+                &mut s.pipe.server,
+                push_id,
+                &[Header::new(b":status", b"200")],
+                true
+            ),
+            Err(Error::RequestCancelled)
+        );
+    }
+
+    #[test]
+    /// Cancel a push from the server after its push stream was opened.
+    fn cancel_push_by_server() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_max_push_id(&mut s.pipe.client, 5).unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        let push_id = s
+            .server
+            .send_push_promise(&mut s.pipe.server, stream, &req)
+            .unwrap();
+
+        s.advance().ok();
+
+        let ev_promise = Event::PushPromise { push_id, list: req };
+
+        assert_eq!(s.poll_client(), Ok((stream, ev_promise)));
+
+        let resp = vec![Header::new(b":status", b"200")];
+
+        let push_stream = s
+            .server
+            .send_push_response(&mut s.pipe.server, push_id, &resp, false)
+            .unwrap();
+
+        assert_eq!(s.server.cancel_push(&mut s.pipe.server, push_id), Ok(()));
+
+        s.advance().ok();
+
+        assert_eq!(
+            s.poll_client(),
+            Ok((push_stream, Event::Reset(Error::RequestCancelled.to_wire())))
+        );
+    }
+
+    #[test]
+    /// Send two push streams with the same push ID from the server.
+    fn push_stream_duplicate_id() {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(10);
+        config.verify_peer(false);
+
+        let h3_config = Config::new().unwrap();
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_max_push_id(&mut s.pipe.client, 5).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        for _ in 0..2 {
+            let stream = s
+                .server
+                .open_uni_stream(
+                    &mut s.pipe.server,
+                    stream::HTTP3_PUSH_STREAM_TYPE_ID,
+                )
+                .unwrap();
+
+            s.pipe.server.stream_send(stream, &[0], false).unwrap();
+        }
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the client.
+    fn goaway_from_client_good() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_goaway(&mut s.pipe.client, 100).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((100, Event::GoAway)));
+    }
+
+    #[test]
+    /// A server MUST NOT promise pushes the client declared it will ignore
+    /// with GOAWAY.
+    fn push_promise_after_client_goaway() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.client.send_max_push_id(&mut s.pipe.client, 10).unwrap();
+        s.client.send_goaway(&mut s.pipe.client, 1).unwrap();
+
+        let (stream, req) = s.send_request(true).unwrap();
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((1, Event::GoAway)));
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Ok(0)
+        );
+
+        assert_eq!(
+            s.server.send_push_promise(&mut s.pipe.server, stream, &req),
+            Err(Error::FrameUnexpected)
+        );
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the server.
+    fn goaway_from_server_good() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+    }
+
+    #[test]
+    /// A client MUST NOT send a request after it receives GOAWAY.
+    fn client_request_after_goaway() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.server.send_goaway(&mut s.pipe.server, 4000).unwrap();
+
+        s.advance().ok();
+
+        assert_eq!(s.poll_client(), Ok((4000, Event::GoAway)));
+
+        assert_eq!(s.send_request(true), Err(Error::FrameUnexpected));
+    }
+
+    #[test]
+    /// Send a GOAWAY frame from the server, using an invalid goaway ID.
+    fn goaway_from_server_invalid_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 1 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_client(), Err(Error::IdError));
+    }
+
+    #[test]
+    /// Send multiple GOAWAY frames from the server, that increase the goaway
+    /// ID.
+    fn goaway_from_server_increase_id() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 0 },
+            s.server.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        s.send_frame_server(
+            frame::Frame::GoAway { id: 4 },
             s.server.control_stream_id.unwrap(),
             false,
         )
@@ -3759,6 +5044,82 @@ mod tests {
         assert_eq!(s.poll_server(), Err(Error::Done));
     }
 
+    #[test]
+    #[cfg(feature = "sfv")]
+    /// Send a PRIORITY_UPDATE for an open request stream, and check that it
+    /// is applied to the transport stream.
+    fn priority_update_request_applied() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let (stream, _) = s.send_request(false).unwrap();
+
+        while s.poll_server().is_ok() {
+            // Do nothing.
+        }
+
+        s.send_frame_client(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: stream,
+                priority_field_value: b"u=1, i".to_vec(),
+            },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_server(), Ok((stream, Event::PriorityUpdate)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        let quic_stream = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(quic_stream.urgency, 1 + PRIORITY_URGENCY_OFFSET);
+        assert!(quic_stream.incremental);
+
+        // The field value can still be taken by the application.
+        assert_eq!(
+            s.server.take_last_priority_update(stream),
+            Ok(b"u=1, i".to_vec())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sfv")]
+    /// Send a PRIORITY_UPDATE for a request stream that is not open yet, and
+    /// check that it is applied once the stream is opened.
+    fn priority_update_request_applied_before_open() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        s.send_frame_client(
+            frame::Frame::PriorityUpdateRequest {
+                prioritized_element_id: 0,
+                priority_field_value: b"u=6".to_vec(),
+            },
+            s.client.control_stream_id.unwrap(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(s.poll_server(), Ok((0, Event::PriorityUpdate)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        assert!(s.pipe.server.streams.get(0).is_none());
+
+        let (stream, req) = s.send_request(true).unwrap();
+        assert_eq!(stream, 0);
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+
+        let quic_stream = s.pipe.server.streams.get(stream).unwrap();
+        assert_eq!(quic_stream.urgency, 6 + PRIORITY_URGENCY_OFFSET);
+        assert!(!quic_stream.incremental);
+    }
+
     #[test]
     /// Send a PRIORITY_UPDATE for request stream from the client.
     fn priority_update_single_stream_rearm() {
@@ -4212,7 +5573,9 @@ mod tests {
         let mut qpack_stream_closed = false;
 
         let stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
-        let d = [0; 1];
+
+        // Set Dynamic Table Capacity instruction with a value of 0.
+        let d = [0x20; 1];
 
         s.pipe.client.stream_send(stream_id, &d, false).unwrap();
         s.pipe.client.stream_send(stream_id, &d, true).unwrap();
@@ -4242,18 +5605,21 @@ mod tests {
     #[test]
     /// Client sends QPACK data.
     fn qpack_data() {
-        // TODO: QPACK instructions are ignored until dynamic table support is
-        // added so we just test that the data is safely ignored.
         let mut s = Session::default().unwrap();
         s.handshake().unwrap();
 
         let e_stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
         let d_stream_id = s.client.local_qpack_streams.decoder_stream_id.unwrap();
-        let d = [0; 20];
+
+        // Set Dynamic Table Capacity instructions with a value of 0.
+        let d = [0x20; 20];
 
         s.pipe.client.stream_send(e_stream_id, &d, false).unwrap();
         s.advance().ok();
 
+        // Stream Cancellation instructions for stream 0.
+        let d = [0x40; 20];
+
         s.pipe.client.stream_send(d_stream_id, &d, false).unwrap();
         s.advance().ok();
 
@@ -4272,6 +5638,154 @@ mod tests {
         }
     }
 
+    #[test]
+    /// Client sends invalid QPACK encoder instructions.
+    fn qpack_encoder_stream_error() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let stream_id = s.client.local_qpack_streams.encoder_stream_id.unwrap();
+
+        // Duplicate instruction referencing an empty dynamic table.
+        let d = [0; 20];
+
+        s.pipe.client.stream_send(stream_id, &d, false).unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Err(Error::QpackEncoderStreamError));
+    }
+
+    #[test]
+    /// Client sends invalid QPACK decoder instructions.
+    fn qpack_decoder_stream_error() {
+        let mut s = Session::default().unwrap();
+        s.handshake().unwrap();
+
+        let stream_id = s.client.local_qpack_streams.decoder_stream_id.unwrap();
+
+        // Insert Count Increment instruction with a value of 0.
+        let d = [0; 20];
+
+        s.pipe.client.stream_send(stream_id, &d, false).unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Err(Error::QpackDecoderStreamError));
+    }
+
+    fn qpack_dynamic_session() -> Session {
+        let mut config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config.set_application_protos(b"\x02h3").unwrap();
+        config.set_initial_max_data(1500);
+        config.set_initial_max_stream_data_bidi_local(150);
+        config.set_initial_max_stream_data_bidi_remote(150);
+        config.set_initial_max_stream_data_uni(150);
+        config.set_initial_max_streams_bidi(5);
+        config.set_initial_max_streams_uni(5);
+        config.verify_peer(false);
+
+        let mut h3_config = Config::new().unwrap();
+        h3_config.set_qpack_max_table_capacity(1024);
+        h3_config.set_qpack_blocked_streams(4);
+        h3_config.set_qpack_insertion_policy(qpack::InsertionPolicy::Always);
+
+        let mut s = Session::with_configs(&mut config, &h3_config).unwrap();
+        s.handshake().unwrap();
+
+        s
+    }
+
+    #[test]
+    /// Headers are compressed using the QPACK dynamic table.
+    fn qpack_dynamic_table() {
+        let mut s = qpack_dynamic_session();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+            Header::new(b"cookie", b"session=a1b2c3d4"),
+        ];
+
+        let ev_headers = Event::Headers {
+            list: req.clone(),
+            has_body: false,
+        };
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers.clone())));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        // Let the client process the server's acknowledgements.
+        s.advance().ok();
+        assert_eq!(s.poll_client(), Err(Error::Done));
+
+        // All fields are now indexed, so the header block is just the prefix
+        // followed by a byte per field.
+        let header_block = s.client.encode_header_block(4, &req).unwrap();
+        assert_eq!(header_block.len(), 2 + req.len());
+        s.client.qpack_encoder.discard_section(4);
+
+        let stream = s
+            .client
+            .send_request(&mut s.pipe.client, &req, true)
+            .unwrap();
+        s.advance().ok();
+
+        assert_eq!(s.poll_server(), Ok((stream, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((stream, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
+    #[test]
+    /// Headers referencing QPACK dynamic table entries that were not received
+    /// yet are delivered once the entries are received.
+    fn qpack_blocked_headers() {
+        let mut s = qpack_dynamic_session();
+
+        let req = vec![
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", b"quic.tech"),
+            Header::new(b":path", b"/test"),
+        ];
+
+        // Send the header block without the encoder instructions it depends
+        // on.
+        let header_block = s.client.encode_header_block(0, &req).unwrap();
+
+        s.send_frame_client(frame::Frame::Headers { header_block }, 0, true)
+            .unwrap();
+
+        assert_eq!(s.poll_server(), Err(Error::Done));
+
+        s.client
+            .send_qpack_instructions(&mut s.pipe.client)
+            .unwrap();
+        s.advance().ok();
+
+        let ev_headers = Event::Headers {
+            list: req,
+            has_body: false,
+        };
+
+        assert_eq!(s.poll_server(), Ok((0, ev_headers)));
+        assert_eq!(s.poll_server(), Ok((0, Event::Finished)));
+        assert_eq!(s.poll_server(), Err(Error::Done));
+    }
+
     #[test]
     /// Tests limits for the stream state buffer maximum size.
     fn max_state_buf_size() {
@@ -24,15 +24,38 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QPackInstruction;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackHeaderBlockRepresentation;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackHeaderBlockRepresentationTypeName as TypeName;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackInstructionTypeName;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackTableType;
+#[cfg(feature = "qlog")]
+use qlog::events::EventData;
+
 use super::Error;
 use super::Result;
 
 use crate::h3::Header;
 
+use super::dynamic_table::DynamicTable;
+
+use super::encoder::encode_int;
+
 use super::INDEXED;
 use super::INDEXED_WITH_POST_BASE;
+use super::INSERT_COUNT_INCREMENT;
+use super::INSERT_WITH_LITERAL_NAME;
+use super::INSERT_WITH_NAME_REF;
 use super::LITERAL;
 use super::LITERAL_WITH_NAME_REF;
+use super::SECTION_ACKNOWLEDGEMENT;
+use super::SET_DYNAMIC_TABLE_CAPACITY;
+use super::STREAM_CANCELLATION;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Representation {
@@ -67,7 +90,22 @@ impl Representation {
 
 /// A QPACK decoder.
 #[derive(Default)]
-pub struct Decoder {}
+pub struct Decoder {
+    table: DynamicTable,
+
+    max_blocked_streams: u64,
+
+    blocked_streams: crate::stream::StreamIdHashSet,
+
+    acked_insert_count: u64,
+
+    instructions: Vec<u8>,
+
+    buf: Vec<u8>,
+
+    #[cfg(feature = "qlog")]
+    qlog_events: Vec<EventData>,
+}
 
 impl Decoder {
     /// Creates a new QPACK decoder.
@@ -75,14 +113,118 @@ impl Decoder {
         Decoder::default()
     }
 
+    /// Sets the maximum capacity of the dynamic table, as advertised by the
+    /// local `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
+    pub fn set_max_table_capacity(&mut self, v: u64) {
+        self.table.set_max_capacity(v);
+    }
+
+    /// Sets the maximum number of streams that can be blocked waiting for
+    /// dynamic table updates, as advertised by the local
+    /// `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
+    pub fn set_max_blocked_streams(&mut self, v: u64) {
+        self.max_blocked_streams = v;
+    }
+
+    /// Returns the decoder stream instructions that still need to be sent to
+    /// the peer.
+    pub fn pending_instructions(&self) -> &[u8] {
+        &self.instructions
+    }
+
+    /// Removes the first `len` bytes of pending instructions, once they have
+    /// been sent on the decoder stream.
+    pub fn drain_instructions(&mut self, len: usize) {
+        self.instructions.drain(..len);
+    }
+
+    /// Returns the qlog events generated since the last call.
+    #[cfg(feature = "qlog")]
+    pub fn take_qlog_events(&mut self) -> Vec<EventData> {
+        std::mem::take(&mut self.qlog_events)
+    }
+
     /// Processes control instructions from the encoder.
-    pub fn control(&mut self, _buf: &mut [u8]) -> Result<()> {
-        // TODO: process control instructions
+    ///
+    /// Incomplete instructions are buffered until the rest of their data is
+    /// provided by a later call.
+    pub fn control(&mut self, buf: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        let buf = std::mem::take(&mut self.buf);
+        let mut b = octets::Octets::with_slice(&buf);
+
+        while b.cap() > 0 {
+            let off = b.off();
+
+            match self.process_instruction(&mut b) {
+                Ok(_) => (),
+
+                // Wait for the rest of the instruction.
+                Err(Error::BufferTooShort) => {
+                    self.buf = buf[off..].to_vec();
+                    break;
+                },
+
+                Err(_) => return Err(Error::EncoderStreamError),
+            }
+        }
+
+        // Let the encoder know about the new entries, so it can start
+        // referencing them without risking to block streams.
+        let increment = self.table.insert_count() - self.acked_insert_count;
+
+        if increment > 0 {
+            let _len =
+                self.emit_instruction(increment, INSERT_COUNT_INCREMENT, 6);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_created(
+                QPackInstruction::InsertCountIncrementInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::InsertCountIncrementInstruction,
+                    increment,
+                },
+                _len,
+            ));
+
+            self.acked_insert_count = self.table.insert_count();
+        }
+
         Ok(())
     }
 
+    /// Notifies the decoder that the header blocks of the given stream will
+    /// not be decoded, for example because the stream was reset.
+    pub fn cancel_stream(&mut self, stream_id: u64) {
+        self.blocked_streams.remove(&stream_id);
+
+        if self.table.max_capacity() > 0 {
+            let _len = self.emit_instruction(stream_id, STREAM_CANCELLATION, 6);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_created(
+                QPackInstruction::StreamCancellationInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::StreamCancellationInstruction,
+                    stream_id: stream_id.to_string(),
+                },
+                _len,
+            ));
+        }
+    }
+
     /// Decodes a QPACK header block into a list of headers.
-    pub fn decode(&mut self, buf: &[u8], max_size: u64) -> Result<Vec<Header>> {
+    ///
+    /// When the header block references dynamic table entries that have not
+    /// been received yet, the [`Blocked`] error is returned and the stream is
+    /// counted as blocked until the header block is successfully decoded, or
+    /// the stream is cancelled.
+    ///
+    /// [`Blocked`]: enum.Error.html#variant.Blocked
+    pub fn decode(
+        &mut self, stream_id: u64, buf: &[u8], max_size: u64,
+    ) -> Result<Vec<Header>> {
         let mut b = octets::Octets::with_slice(buf);
 
         let mut out = Vec::new();
@@ -90,10 +232,52 @@ impl Decoder {
         let mut left = max_size;
 
         let req_insert_count = decode_int(&mut b, 8)?;
-        let base = decode_int(&mut b, 7)?;
+        let req_insert_count =
+            self.decode_required_insert_count(req_insert_count)?;
+
+        let negative = b.peek_u8()? & 0x80 == 0x80;
+        let delta_base = decode_int(&mut b, 7)?;
+
+        let base = if negative {
+            req_insert_count.checked_sub(delta_base + 1)
+        } else {
+            req_insert_count.checked_add(delta_base)
+        }
+        .ok_or(Error::InvalidRequiredInsertCount)?;
 
         trace!("Header count={} base={}", req_insert_count, base);
 
+        if req_insert_count > self.table.insert_count() {
+            if !self.blocked_streams.contains(&stream_id) {
+                if self.blocked_streams.len() as u64 >= self.max_blocked_streams {
+                    return Err(Error::TooManyBlockedStreams);
+                }
+
+                self.blocked_streams.insert(stream_id);
+
+                #[cfg(feature = "qlog")]
+                self.qlog_stream_state_updated(
+                    stream_id,
+                    qlog::events::qpack::QpackStreamState::Blocked,
+                );
+            }
+
+            return Err(Error::Blocked);
+        }
+
+        if self.blocked_streams.remove(&stream_id) {
+            #[cfg(feature = "qlog")]
+            self.qlog_stream_state_updated(
+                stream_id,
+                qlog::events::qpack::QpackStreamState::Unblocked,
+            );
+        }
+
+        let mut max_index = None;
+
+        #[cfg(feature = "qlog")]
+        let mut header_block = Vec::new();
+
         while b.cap() > 0 {
             let first = b.peek_u8()?;
 
@@ -106,12 +290,29 @@ impl Decoder {
 
                     trace!("Indexed index={} static={}", index, s);
 
-                    if !s {
-                        // TODO: implement dynamic table
-                        return Err(Error::InvalidHeaderValue);
-                    }
+                    #[cfg(feature = "qlog")]
+                    header_block.push(
+                        QpackHeaderBlockRepresentation::IndexedHeaderField {
+                            header_field_type: TypeName::IndexedHeaderField,
+                            table_type: qlog_table_type(s),
+                            index,
+                            is_post_base: (!s).then_some(false),
+                        },
+                    );
 
-                    let (name, value) = lookup_static(index)?;
+                    let (name, value) = if s {
+                        lookup_static(index)?
+                    } else {
+                        let index = base
+                            .checked_sub(index + 1)
+                            .ok_or(Error::InvalidDynamicTableIndex)?;
+
+                        self.lookup_dynamic(
+                            index,
+                            req_insert_count,
+                            &mut max_index,
+                        )?
+                    };
 
                     left = left
                         .checked_sub((name.len() + value.len()) as u64)
@@ -126,8 +327,32 @@ impl Decoder {
 
                     trace!("Indexed With Post Base index={}", index);
 
-                    // TODO: implement dynamic table
-                    return Err(Error::InvalidHeaderValue);
+                    #[cfg(feature = "qlog")]
+                    header_block.push(
+                        QpackHeaderBlockRepresentation::IndexedHeaderField {
+                            header_field_type: TypeName::IndexedHeaderField,
+                            table_type: QpackTableType::Dynamic,
+                            index,
+                            is_post_base: Some(true),
+                        },
+                    );
+
+                    let index = base
+                        .checked_add(index)
+                        .ok_or(Error::InvalidDynamicTableIndex)?;
+
+                    let (name, value) = self.lookup_dynamic(
+                        index,
+                        req_insert_count,
+                        &mut max_index,
+                    )?;
+
+                    left = left
+                        .checked_sub((name.len() + value.len()) as u64)
+                        .ok_or(Error::HeaderListTooLarge)?;
+
+                    let hdr = Header::new(name, value);
+                    out.push(hdr);
                 },
 
                 Representation::Literal => {
@@ -143,6 +368,10 @@ impl Decoder {
                     };
 
                     let name = name.to_vec();
+
+                    #[cfg(feature = "qlog")]
+                    let value_huff = is_huffman(&b);
+
                     let value = decode_str(&mut b)?;
 
                     trace!(
@@ -151,6 +380,24 @@ impl Decoder {
                         value,
                     );
 
+                    #[cfg(feature = "qlog")]
+                    header_block.push(
+                        QpackHeaderBlockRepresentation::LiteralHeaderFieldWithoutName {
+                            header_field_type:
+                                TypeName::LiteralHeaderFieldWithoutName,
+                            preserve_literal: first & 0x10 == 0x10,
+                            table_type: QpackTableType::Static,
+                            name_index: 0,
+                            huffman_encoded_name: name_huff,
+                            name_length: name.len() as u64,
+                            name: super::qlog_string(&name),
+                            huffman_encoded_value: value_huff,
+                            value_length: value.len() as u64,
+                            value: super::qlog_string(&value),
+                            is_post_base: None,
+                        },
+                    );
+
                     left = left
                         .checked_sub((name.len() + value.len()) as u64)
                         .ok_or(Error::HeaderListTooLarge)?;
@@ -166,6 +413,10 @@ impl Decoder {
 
                     let s = first & STATIC == STATIC;
                     let name_idx = decode_int(&mut b, 4)?;
+
+                    #[cfg(feature = "qlog")]
+                    let value_huff = is_huffman(&b);
+
                     let value = decode_str(&mut b)?;
 
                     trace!(
@@ -175,12 +426,34 @@ impl Decoder {
                         value
                     );
 
-                    if !s {
-                        // TODO: implement dynamic table
-                        return Err(Error::InvalidHeaderValue);
-                    }
+                    #[cfg(feature = "qlog")]
+                    header_block.push(
+                        QpackHeaderBlockRepresentation::LiteralHeaderFieldWithName {
+                            header_field_type:
+                                TypeName::LiteralHeaderFieldWithName,
+                            preserve_literal: first & 0x20 == 0x20,
+                            table_type: qlog_table_type(s),
+                            name_index: name_idx,
+                            huffman_encoded_value: value_huff,
+                            value_length: value.len() as u64,
+                            value: super::qlog_string(&value),
+                            is_post_base: (!s).then_some(false),
+                        },
+                    );
 
-                    let (name, _) = lookup_static(name_idx)?;
+                    let (name, _) = if s {
+                        lookup_static(name_idx)?
+                    } else {
+                        let index = base
+                            .checked_sub(name_idx + 1)
+                            .ok_or(Error::InvalidDynamicTableIndex)?;
+
+                        self.lookup_dynamic(
+                            index,
+                            req_insert_count,
+                            &mut max_index,
+                        )?
+                    };
 
                     left = left
                         .checked_sub((name.len() + value.len()) as u64)
@@ -194,16 +467,327 @@ impl Decoder {
                 },
 
                 Representation::LiteralWithPostBase => {
-                    trace!("Literal With Post Base");
+                    let name_idx = decode_int(&mut b, 3)?;
+
+                    #[cfg(feature = "qlog")]
+                    let value_huff = is_huffman(&b);
+
+                    let value = decode_str(&mut b)?;
+
+                    trace!(
+                        "Literal With Post Base name_idx={} value={:?}",
+                        name_idx,
+                        value
+                    );
+
+                    #[cfg(feature = "qlog")]
+                    header_block.push(
+                        QpackHeaderBlockRepresentation::LiteralHeaderFieldWithName {
+                            header_field_type:
+                                TypeName::LiteralHeaderFieldWithName,
+                            preserve_literal: first & 0x08 == 0x08,
+                            table_type: QpackTableType::Dynamic,
+                            name_index: name_idx,
+                            huffman_encoded_value: value_huff,
+                            value_length: value.len() as u64,
+                            value: super::qlog_string(&value),
+                            is_post_base: Some(true),
+                        },
+                    );
+
+                    let index = base
+                        .checked_add(name_idx)
+                        .ok_or(Error::InvalidDynamicTableIndex)?;
+
+                    let (name, _) = self.lookup_dynamic(
+                        index,
+                        req_insert_count,
+                        &mut max_index,
+                    )?;
 
-                    // TODO: implement dynamic table
-                    return Err(Error::InvalidHeaderValue);
+                    left = left
+                        .checked_sub((name.len() + value.len()) as u64)
+                        .ok_or(Error::HeaderListTooLarge)?;
+
+                    let hdr = Header(name.to_vec(), value);
+                    out.push(hdr);
                 },
             }
         }
 
+        // The Required Insert Count must match the largest reference exactly.
+        if max_index.map_or(0, |index| index + 1) != req_insert_count {
+            return Err(Error::InvalidRequiredInsertCount);
+        }
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(EventData::QpackHeadersDecoded(
+            qlog::events::qpack::QpackHeadersDecoded {
+                stream_id: Some(stream_id),
+                headers: None,
+                block_prefix: qlog::events::qpack::QpackHeaderBlockPrefix {
+                    required_insert_count: req_insert_count,
+                    sign_bit: negative,
+                    delta_base,
+                },
+                header_block,
+                length: Some(buf.len() as u32),
+                raw: None,
+            },
+        ));
+
+        if req_insert_count > 0 {
+            let _len =
+                self.emit_instruction(stream_id, SECTION_ACKNOWLEDGEMENT, 7);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_created(
+                QPackInstruction::HeaderAcknowledgementInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::HeaderAcknowledgementInstruction,
+                    stream_id: stream_id.to_string(),
+                },
+                _len,
+            ));
+
+            self.acked_insert_count =
+                std::cmp::max(self.acked_insert_count, req_insert_count);
+        }
+
         Ok(out)
     }
+
+    fn lookup_dynamic(
+        &self, index: u64, req_insert_count: u64, max_index: &mut Option<u64>,
+    ) -> Result<(&[u8], &[u8])> {
+        if index >= req_insert_count {
+            return Err(Error::InvalidDynamicTableIndex);
+        }
+
+        let entry = self
+            .table
+            .get(index)
+            .ok_or(Error::InvalidDynamicTableIndex)?;
+
+        *max_index = std::cmp::max(*max_index, Some(index));
+
+        Ok(entry)
+    }
+
+    /// Decodes the Required Insert Count of a header block as described in
+    /// RFC 9204, Section 4.5.1.1.
+    fn decode_required_insert_count(&self, encoded: u64) -> Result<u64> {
+        if encoded == 0 {
+            return Ok(0);
+        }
+
+        let max_entries = self.table.max_entries();
+        let full_range = 2 * max_entries;
+
+        if encoded > full_range {
+            return Err(Error::InvalidRequiredInsertCount);
+        }
+
+        let max_value = self.table.insert_count() + max_entries;
+        let max_wrapped = (max_value / full_range) * full_range;
+
+        let mut req_insert_count = max_wrapped + encoded - 1;
+
+        if req_insert_count > max_value {
+            if req_insert_count <= full_range {
+                return Err(Error::InvalidRequiredInsertCount);
+            }
+
+            req_insert_count -= full_range;
+        }
+
+        if req_insert_count == 0 {
+            return Err(Error::InvalidRequiredInsertCount);
+        }
+
+        Ok(req_insert_count)
+    }
+
+    fn process_instruction(&mut self, b: &mut octets::Octets) -> Result<()> {
+        let first = b.peek_u8()?;
+
+        #[cfg(feature = "qlog")]
+        let start = b.off();
+
+        if first & INSERT_WITH_NAME_REF == INSERT_WITH_NAME_REF {
+            const STATIC: u8 = 0x40;
+
+            let s = first & STATIC == STATIC;
+            let name_idx = decode_int(b, 6)?;
+
+            #[cfg(feature = "qlog")]
+            let value_huff = is_huffman(b);
+
+            let value = decode_str(b)?;
+
+            trace!(
+                "Insert With Name Reference name_idx={} static={} value={:?}",
+                name_idx,
+                s,
+                value
+            );
+
+            let name = if s {
+                lookup_static(name_idx)?.0.to_vec()
+            } else {
+                self.lookup_relative(name_idx)?.0.to_vec()
+            };
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_parsed(
+                QPackInstruction::InsertWithNameReferenceInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::InsertWithNameReferenceInstruction,
+                    table_type: qlog_table_type(s),
+                    name_index: name_idx,
+                    huffman_encoded_value: value_huff,
+                    value_length: value.len() as u64,
+                    value: super::qlog_string(&value),
+                },
+                b.off() - start,
+            ));
+
+            self.insert(name, value)?;
+
+            return Ok(());
+        }
+
+        if first & INSERT_WITH_LITERAL_NAME == INSERT_WITH_LITERAL_NAME {
+            const HUFFMAN: u8 = 0x20;
+
+            let name_huff = first & HUFFMAN == HUFFMAN;
+            let name_len = decode_int(b, 5)? as usize;
+
+            let mut name = b.get_bytes(name_len)?;
+
+            let name = if name_huff {
+                super::huffman::decode(&mut name)?
+            } else {
+                name.to_vec()
+            };
+
+            #[cfg(feature = "qlog")]
+            let value_huff = is_huffman(b);
+
+            let value = decode_str(b)?;
+
+            trace!("Insert With Literal Name name={:?} value={:?}", name, value);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_parsed(
+                QPackInstruction::InsertWithoutNameReferenceInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::InsertWithoutNameReferenceInstruction,
+                    huffman_encoded_name: name_huff,
+                    name_length: name.len() as u64,
+                    name: super::qlog_string(&name),
+                    huffman_encoded_value: value_huff,
+                    value_length: value.len() as u64,
+                    value: super::qlog_string(&value),
+                },
+                b.off() - start,
+            ));
+
+            self.insert(name, value)?;
+
+            return Ok(());
+        }
+
+        if first & SET_DYNAMIC_TABLE_CAPACITY == SET_DYNAMIC_TABLE_CAPACITY {
+            let capacity = decode_int(b, 5)?;
+
+            trace!("Set Dynamic Table Capacity capacity={}", capacity);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_parsed(
+                QPackInstruction::SetDynamicTableCapacityInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::SetDynamicTableCapacityInstruction,
+                    capacity,
+                },
+                b.off() - start,
+            ));
+
+            return self.table.set_capacity(capacity);
+        }
+
+        // Duplicate.
+        let index = decode_int(b, 5)?;
+
+        trace!("Duplicate index={}", index);
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(super::qlog_instruction_parsed(
+            QPackInstruction::DuplicateInstruction {
+                instruction_type: QpackInstructionTypeName::DuplicateInstruction,
+                index,
+            },
+            b.off() - start,
+        ));
+
+        let (name, value) = self.lookup_relative(index)?;
+        let (name, value) = (name.to_vec(), value.to_vec());
+
+        self.insert(name, value)?;
+
+        Ok(())
+    }
+
+    /// Inserts a new entry into the dynamic table.
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        #[cfg(feature = "qlog")]
+        let entry =
+            super::qlog_entry_added(self.table.insert_count(), &name, &value);
+
+        self.table.insert(name, value)?;
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(entry);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "qlog")]
+    fn qlog_stream_state_updated(
+        &mut self, stream_id: u64, state: qlog::events::qpack::QpackStreamState,
+    ) {
+        self.qlog_events.push(EventData::QpackStreamStateUpdated(
+            qlog::events::qpack::QpackStreamStateUpdated { stream_id, state },
+        ));
+    }
+
+    /// Looks up a dynamic table entry using an index relative to the last
+    /// inserted entry, as used by encoder instructions.
+    fn lookup_relative(&self, index: u64) -> Result<(&[u8], &[u8])> {
+        let index = self
+            .table
+            .insert_count()
+            .checked_sub(index + 1)
+            .ok_or(Error::InvalidDynamicTableIndex)?;
+
+        self.table.get(index).ok_or(Error::InvalidDynamicTableIndex)
+    }
+
+    /// Appends a new decoder instruction and returns its length.
+    fn emit_instruction(&mut self, v: u64, first: u8, prefix: usize) -> usize {
+        let mut d = [0; 16];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        // A single integer always fits in the buffer.
+        if encode_int(v, first, prefix, &mut b).is_err() {
+            return 0;
+        }
+
+        let off = b.off();
+        self.instructions.extend_from_slice(&d[..off]);
+
+        off
+    }
 }
 
 fn lookup_static(idx: u64) -> Result<(&'static [u8], &'static [u8])> {
@@ -214,7 +798,7 @@ fn lookup_static(idx: u64) -> Result<(&'static [u8], &'static [u8])> {
     Ok(super::static_table::STATIC_TABLE[idx as usize])
 }
 
-fn decode_int(b: &mut octets::Octets, prefix: usize) -> Result<u64> {
+pub(super) fn decode_int(b: &mut octets::Octets, prefix: usize) -> Result<u64> {
     let mask = 2u64.pow(prefix as u32) - 1;
 
     let mut val = u64::from(b.get_u8()?);
@@ -245,6 +829,20 @@ fn decode_int(b: &mut octets::Octets, prefix: usize) -> Result<u64> {
     Err(Error::BufferTooShort)
 }
 
+#[cfg(feature = "qlog")]
+fn is_huffman(b: &octets::Octets) -> bool {
+    matches!(b.as_ref().first(), Some(first) if first & 0x80 == 0x80)
+}
+
+#[cfg(feature = "qlog")]
+fn qlog_table_type(is_static: bool) -> QpackTableType {
+    if is_static {
+        QpackTableType::Static
+    } else {
+        QpackTableType::Dynamic
+    }
+}
+
 fn decode_str(b: &mut octets::Octets) -> Result<Vec<u8>> {
     let first = b.peek_u8()?;
 
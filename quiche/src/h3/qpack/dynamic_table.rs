@@ -0,0 +1,246 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::collections::VecDeque;
+
+use super::Error;
+use super::Result;
+
+/// The per-entry overhead added to the name and value lengths when computing
+/// the size of a dynamic table entry.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Returns the size of a dynamic table entry with the given name and value.
+pub fn entry_size(name: &[u8], value: &[u8]) -> usize {
+    name.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// The QPACK dynamic table.
+///
+/// Entries are addressed by their absolute index, which is the number of
+/// entries that were inserted before them. The oldest entries are at the front
+/// of the table and are evicted first.
+#[derive(Default)]
+pub struct DynamicTable {
+    entries: VecDeque<(Vec<u8>, Vec<u8>)>,
+
+    size: usize,
+
+    capacity: u64,
+
+    max_capacity: u64,
+
+    insert_count: u64,
+}
+
+impl DynamicTable {
+    /// Returns the current capacity of the table.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the maximum capacity the table can be configured with.
+    pub fn max_capacity(&self) -> u64 {
+        self.max_capacity
+    }
+
+    /// Sets the maximum capacity the table can be configured with.
+    pub fn set_max_capacity(&mut self, v: u64) {
+        self.max_capacity = v;
+    }
+
+    /// Returns the maximum number of entries the table can hold, as used for
+    /// encoding the Required Insert Count of a field section.
+    pub fn max_entries(&self) -> u64 {
+        self.max_capacity / ENTRY_OVERHEAD as u64
+    }
+
+    /// Returns the total number of insertions into the table.
+    pub fn insert_count(&self) -> u64 {
+        self.insert_count
+    }
+
+    /// Returns the absolute index of the oldest entry still in the table.
+    pub fn dropped_count(&self) -> u64 {
+        self.insert_count - self.entries.len() as u64
+    }
+
+    /// Sets the capacity of the table, evicting entries that don't fit.
+    pub fn set_capacity(&mut self, v: u64) -> Result<()> {
+        if v > self.max_capacity {
+            return Err(Error::InvalidDynamicTableCapacity);
+        }
+
+        self.capacity = v;
+
+        self.evict(0);
+
+        Ok(())
+    }
+
+    /// Inserts a new entry, evicting older ones as needed, and returns its
+    /// absolute index.
+    pub fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        let size = entry_size(&name, &value);
+
+        if size as u64 > self.capacity {
+            return Err(Error::InvalidDynamicTableCapacity);
+        }
+
+        self.evict(size);
+
+        self.size += size;
+        self.entries.push_back((name, value));
+
+        self.insert_count += 1;
+
+        Ok(self.insert_count - 1)
+    }
+
+    /// Returns the entry with the given absolute index, if it is still in the
+    /// table.
+    pub fn get(&self, index: u64) -> Option<(&[u8], &[u8])> {
+        if index < self.dropped_count() || index >= self.insert_count {
+            return None;
+        }
+
+        let (name, value) =
+            &self.entries[(index - self.dropped_count()) as usize];
+
+        Some((name, value))
+    }
+
+    /// Looks up an entry matching the given name and value, preferring the
+    /// most recently inserted ones.
+    ///
+    /// Returns the absolute index of the entry and whether the value matched
+    /// as well as the name.
+    pub fn find(&self, name: &[u8], value: &[u8]) -> Option<(u64, bool)> {
+        let mut name_match = None;
+
+        for (i, e) in self.entries.iter().enumerate().rev() {
+            if e.0 != name {
+                continue;
+            }
+
+            let index = self.dropped_count() + i as u64;
+
+            if e.1 == value {
+                return Some((index, true));
+            }
+
+            if name_match.is_none() {
+                name_match = Some((index, false));
+            }
+        }
+
+        name_match
+    }
+
+    /// Returns whether an entry of the given size can be inserted without
+    /// evicting any entry whose absolute index is `evictable_below` or
+    /// greater.
+    pub fn can_insert(&self, size: usize, evictable_below: u64) -> bool {
+        if size as u64 > self.capacity {
+            return false;
+        }
+
+        let mut available = self.capacity as usize - self.size;
+
+        for (index, e) in (self.dropped_count()..).zip(&self.entries) {
+            if available >= size {
+                break;
+            }
+
+            if index >= evictable_below {
+                return false;
+            }
+
+            available += entry_size(&e.0, &e.1);
+        }
+
+        available >= size
+    }
+
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.capacity as usize {
+            let (name, value) = match self.entries.pop_front() {
+                Some(v) => v,
+
+                None => break,
+            };
+
+            self.size -= entry_size(&name, &value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_evict() {
+        let mut table = DynamicTable::default();
+        table.set_max_capacity(100);
+
+        assert_eq!(
+            table.insert(b"foo".to_vec(), b"bar".to_vec()),
+            Err(Error::InvalidDynamicTableCapacity)
+        );
+
+        assert_eq!(
+            table.set_capacity(200),
+            Err(Error::InvalidDynamicTableCapacity)
+        );
+        assert_eq!(table.set_capacity(80), Ok(()));
+
+        // Each entry takes 38 bytes, so only two fit.
+        assert_eq!(table.insert(b"foo".to_vec(), b"bar".to_vec()), Ok(0));
+        assert_eq!(table.insert(b"foo".to_vec(), b"baz".to_vec()), Ok(1));
+
+        assert_eq!(table.get(0), Some((&b"foo"[..], &b"bar"[..])));
+        assert_eq!(table.find(b"foo", b"qux"), Some((1, false)));
+
+        assert!(table.can_insert(38, 1));
+        assert!(!table.can_insert(38, 0));
+        assert!(!table.can_insert(81, 2));
+
+        assert_eq!(table.insert(b"foo".to_vec(), b"qux".to_vec()), Ok(2));
+
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.dropped_count(), 1);
+        assert_eq!(table.insert_count(), 3);
+        assert_eq!(table.find(b"foo", b"bar"), Some((2, false)));
+        assert_eq!(table.find(b"foo", b"baz"), Some((1, true)));
+
+        // Shrinking the table evicts the oldest entries.
+        assert_eq!(table.set_capacity(40), Ok(()));
+        assert_eq!(table.get(1), None);
+        assert_eq!(table.get(2), Some((&b"foo"[..], &b"qux"[..])));
+        assert_eq!(table.find(b"bar", b"foo"), None);
+    }
+}
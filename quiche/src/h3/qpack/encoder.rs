@@ -24,17 +24,189 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QPackInstruction;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackHeaderBlockRepresentation;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackHeaderBlockRepresentationTypeName;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackInstructionTypeName;
+#[cfg(feature = "qlog")]
+use qlog::events::qpack::QpackTableType;
+#[cfg(feature = "qlog")]
+use qlog::events::EventData;
+
+use super::Error;
 use super::Result;
 
 use crate::h3::NameValue;
 
+use super::dynamic_table::entry_size;
+use super::dynamic_table::DynamicTable;
+
 use super::INDEXED;
+use super::INSERT_WITH_LITERAL_NAME;
+use super::INSERT_WITH_NAME_REF;
 use super::LITERAL;
 use super::LITERAL_WITH_NAME_REF;
+use super::SECTION_ACKNOWLEDGEMENT;
+use super::SET_DYNAMIC_TABLE_CAPACITY;
+use super::STREAM_CANCELLATION;
+
+/// The number of recently encoded fields remembered by the
+/// [`InsertionPolicy::Repeated`] policy.
+const FIELD_HISTORY_LEN: usize = 64;
+
+/// Determines which header fields the QPACK encoder inserts into the dynamic
+/// table.
+///
+/// Fields are only ever inserted when the peer advertised a non-zero
+/// `SETTINGS_QPACK_MAX_TABLE_CAPACITY`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InsertionPolicy {
+    /// Never insert fields, only static table and literal representations are
+    /// used.
+    #[default]
+    Never    = 0,
+
+    /// Insert every field that is not fully matched by the static table.
+    Always   = 1,
+
+    /// Insert fields the second time they are encoded within a short window,
+    /// so that one-off values don't evict frequently used entries.
+    Repeated = 2,
+}
+
+/// A header block that references the dynamic table and has not been
+/// acknowledged by the peer yet.
+#[derive(Clone, Copy)]
+struct Section {
+    required_insert_count: u64,
+
+    min_index: u64,
+}
+
+impl Default for Section {
+    fn default() -> Self {
+        Section {
+            required_insert_count: 0,
+            min_index: std::u64::MAX,
+        }
+    }
+}
+
+impl Section {
+    fn reference(&mut self, index: u64) {
+        self.required_insert_count =
+            std::cmp::max(self.required_insert_count, index + 1);
+        self.min_index = std::cmp::min(self.min_index, index);
+    }
+}
+
+/// How a single field is represented in a header block.
+enum Field {
+    Static(u64),
+    StaticNameRef(u64),
+    Dynamic(u64),
+    DynamicNameRef(u64),
+    Literal,
+}
+
+#[cfg(feature = "qlog")]
+impl Field {
+    fn to_qlog<T: NameValue>(
+        &self, h: &T, req_insert_count: u64,
+    ) -> QpackHeaderBlockRepresentation {
+        use QpackHeaderBlockRepresentationTypeName as TypeName;
+
+        match *self {
+            Field::Static(idx) =>
+                QpackHeaderBlockRepresentation::IndexedHeaderField {
+                    header_field_type: TypeName::IndexedHeaderField,
+                    table_type: QpackTableType::Static,
+                    index: idx,
+                    is_post_base: None,
+                },
+
+            Field::Dynamic(index) =>
+                QpackHeaderBlockRepresentation::IndexedHeaderField {
+                    header_field_type: TypeName::IndexedHeaderField,
+                    table_type: QpackTableType::Dynamic,
+                    index: req_insert_count - 1 - index,
+                    is_post_base: Some(false),
+                },
+
+            Field::StaticNameRef(idx) =>
+                QpackHeaderBlockRepresentation::LiteralHeaderFieldWithName {
+                    header_field_type: TypeName::LiteralHeaderFieldWithName,
+                    preserve_literal: false,
+                    table_type: QpackTableType::Static,
+                    name_index: idx,
+                    huffman_encoded_value: true,
+                    value_length: h.value().len() as u64,
+                    value: super::qlog_string(h.value()),
+                    is_post_base: None,
+                },
+
+            Field::DynamicNameRef(index) =>
+                QpackHeaderBlockRepresentation::LiteralHeaderFieldWithName {
+                    header_field_type: TypeName::LiteralHeaderFieldWithName,
+                    preserve_literal: false,
+                    table_type: QpackTableType::Dynamic,
+                    name_index: req_insert_count - 1 - index,
+                    huffman_encoded_value: true,
+                    value_length: h.value().len() as u64,
+                    value: super::qlog_string(h.value()),
+                    is_post_base: Some(false),
+                },
+
+            Field::Literal =>
+                QpackHeaderBlockRepresentation::LiteralHeaderFieldWithoutName {
+                    header_field_type: TypeName::LiteralHeaderFieldWithoutName,
+                    preserve_literal: false,
+                    table_type: QpackTableType::Static,
+                    name_index: 0,
+                    huffman_encoded_name: true,
+                    name_length: h.name().len() as u64,
+                    name: super::qlog_string(h.name()),
+                    huffman_encoded_value: true,
+                    value_length: h.value().len() as u64,
+                    value: super::qlog_string(h.value()),
+                    is_post_base: None,
+                },
+        }
+    }
+}
 
 /// A QPACK encoder.
 #[derive(Default)]
-pub struct Encoder {}
+pub struct Encoder {
+    table: DynamicTable,
+
+    policy: InsertionPolicy,
+
+    max_blocked_streams: u64,
+
+    known_received_count: u64,
+
+    sections: crate::stream::StreamIdHashMap<VecDeque<Section>>,
+
+    history: VecDeque<u64>,
+
+    instructions: Vec<u8>,
+
+    buf: Vec<u8>,
+
+    #[cfg(feature = "qlog")]
+    qlog_events: Vec<EventData>,
+}
 
 impl Encoder {
     /// Creates a new QPACK encoder.
@@ -42,28 +214,142 @@ impl Encoder {
         Encoder::default()
     }
 
+    /// Sets the policy used to decide which fields are inserted into the
+    /// dynamic table.
+    ///
+    /// The default value is [`InsertionPolicy::Never`].
+    pub fn set_insertion_policy(&mut self, v: InsertionPolicy) {
+        self.policy = v;
+    }
+
+    /// Sets the maximum capacity of the dynamic table, as advertised by the
+    /// peer's `SETTINGS_QPACK_MAX_TABLE_CAPACITY` setting.
+    pub fn set_max_table_capacity(&mut self, v: u64) {
+        self.table.set_max_capacity(v);
+    }
+
+    /// Sets the maximum number of streams that can be blocked waiting for
+    /// dynamic table updates, as advertised by the peer's
+    /// `SETTINGS_QPACK_BLOCKED_STREAMS` setting.
+    pub fn set_max_blocked_streams(&mut self, v: u64) {
+        self.max_blocked_streams = v;
+    }
+
+    /// Returns the encoder stream instructions that still need to be sent to
+    /// the peer.
+    pub fn pending_instructions(&self) -> &[u8] {
+        &self.instructions
+    }
+
+    /// Removes the first `len` bytes of pending instructions, once they have
+    /// been sent on the encoder stream.
+    pub fn drain_instructions(&mut self, len: usize) {
+        self.instructions.drain(..len);
+    }
+
+    /// Returns the qlog events generated since the last call.
+    #[cfg(feature = "qlog")]
+    pub fn take_qlog_events(&mut self) -> Vec<EventData> {
+        std::mem::take(&mut self.qlog_events)
+    }
+
+    /// Processes instructions received on the peer's decoder stream.
+    ///
+    /// Incomplete instructions are buffered until the rest of their data is
+    /// provided by a later call.
+    pub fn control(&mut self, buf: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        let buf = std::mem::take(&mut self.buf);
+        let mut b = octets::Octets::with_slice(&buf);
+
+        while b.cap() > 0 {
+            let off = b.off();
+
+            match self.process_instruction(&mut b) {
+                Ok(_) => (),
+
+                // Wait for the rest of the instruction.
+                Err(Error::BufferTooShort) => {
+                    self.buf = buf[off..].to_vec();
+                    break;
+                },
+
+                Err(_) => return Err(Error::DecoderStreamError),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets about the last header block encoded for the given stream.
+    ///
+    /// This needs to be called when the header block could not be sent, as
+    /// the peer will never acknowledge it.
+    pub fn discard_section(&mut self, stream_id: u64) {
+        if let Some(sections) = self.sections.get_mut(&stream_id) {
+            sections.pop_back();
+
+            if sections.is_empty() {
+                self.sections.remove(&stream_id);
+            }
+        }
+    }
+
     /// Encodes a list of headers into a QPACK header block.
+    ///
+    /// Depending on the insertion policy, this might also generate encoder
+    /// stream instructions, which can be retrieved using
+    /// [`pending_instructions()`](struct.Encoder.html#method.
+    /// pending_instructions).
     pub fn encode<T: NameValue>(
-        &mut self, headers: &[T], out: &mut [u8],
+        &mut self, stream_id: u64, headers: &[T], out: &mut [u8],
     ) -> Result<usize> {
+        let mut section = Section::default();
+
+        let mut blocking = self.is_blocking(stream_id);
+
+        let fields: Vec<Field> = headers
+            .iter()
+            .map(|h| self.encode_field(h, &mut section, &mut blocking))
+            .collect();
+
         let mut b = octets::OctetsMut::with_slice(out);
 
-        // Required Insert Count.
-        encode_int(0, 0, 8, &mut b)?;
+        let req_insert_count = section.required_insert_count;
 
-        // Base.
+        #[cfg(feature = "qlog")]
+        let header_block: Vec<_> = headers
+            .iter()
+            .zip(&fields)
+            .map(|(h, field)| field.to_qlog(h, req_insert_count))
+            .collect();
+
+        // Required Insert Count.
+        encode_int(
+            encode_required_insert_count(
+                req_insert_count,
+                self.table.max_entries(),
+            ),
+            0,
+            8,
+            &mut b,
+        )?;
+
+        // Base, which is always the same as the Required Insert Count, so
+        // that only relative indices are used.
         encode_int(0, 0, 7, &mut b)?;
 
-        for h in headers {
-            match lookup_static(h) {
-                Some((idx, true)) => {
+        for (h, field) in headers.iter().zip(fields) {
+            match field {
+                Field::Static(idx) => {
                     const STATIC: u8 = 0x40;
 
                     // Encode as statically indexed.
                     encode_int(idx, INDEXED | STATIC, 6, &mut b)?;
                 },
 
-                Some((idx, false)) => {
+                Field::StaticNameRef(idx) => {
                     const STATIC: u8 = 0x10;
 
                     // Encode value as literal with static name reference.
@@ -71,7 +357,22 @@ impl Encoder {
                     encode_str(h.value(), 7, &mut b)?;
                 },
 
-                None => {
+                Field::Dynamic(index) => {
+                    let rel = req_insert_count - 1 - index;
+
+                    // Encode as dynamically indexed.
+                    encode_int(rel, INDEXED, 6, &mut b)?;
+                },
+
+                Field::DynamicNameRef(index) => {
+                    let rel = req_insert_count - 1 - index;
+
+                    // Encode value as literal with dynamic name reference.
+                    encode_int(rel, LITERAL_WITH_NAME_REF, 4, &mut b)?;
+                    encode_str(h.value(), 7, &mut b)?;
+                },
+
+                Field::Literal => {
                     // Encode as fully literal.
                     let name_len =
                         super::huffman::encode_output_length(h.name(), true)?;
@@ -85,8 +386,390 @@ impl Encoder {
             };
         }
 
+        if req_insert_count > 0 {
+            self.sections
+                .entry(stream_id)
+                .or_default()
+                .push_back(section);
+        }
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(EventData::QpackHeadersEncoded(
+            qlog::events::qpack::QpackHeadersEncoded {
+                stream_id: Some(stream_id),
+                headers: None,
+                block_prefix: qlog::events::qpack::QpackHeaderBlockPrefix {
+                    required_insert_count: req_insert_count,
+                    sign_bit: false,
+                    delta_base: 0,
+                },
+                header_block,
+                length: Some(b.off() as u32),
+                raw: None,
+            },
+        ));
+
         Ok(b.off())
     }
+
+    fn encode_field<T: NameValue>(
+        &mut self, h: &T, section: &mut Section, blocking: &mut bool,
+    ) -> Field {
+        let static_match = lookup_static(h);
+
+        if let Some((idx, true)) = static_match {
+            return Field::Static(idx);
+        }
+
+        let dynamic_match = if self.table.max_capacity() > 0 {
+            let name = h.name().to_ascii_lowercase();
+
+            match self.table.find(&name, h.value()) {
+                Some((index, true)) => Some((index, true)),
+
+                m if self.should_insert(&name, h.value()) => self
+                    .insert(&name, h.value(), static_match, m, section)
+                    .map(|index| (index, true))
+                    .or(m),
+
+                m => m,
+            }
+        } else {
+            None
+        };
+
+        if let Some((index, true)) = dynamic_match {
+            if self.can_reference(index, blocking) {
+                section.reference(index);
+                return Field::Dynamic(index);
+            }
+        }
+
+        if let Some((idx, false)) = static_match {
+            return Field::StaticNameRef(idx);
+        }
+
+        if let Some((index, _)) = dynamic_match {
+            if self.can_reference(index, blocking) {
+                section.reference(index);
+                return Field::DynamicNameRef(index);
+            }
+        }
+
+        Field::Literal
+    }
+
+    fn should_insert(&mut self, name: &[u8], value: &[u8]) -> bool {
+        match self.policy {
+            InsertionPolicy::Never => false,
+
+            InsertionPolicy::Always => true,
+
+            InsertionPolicy::Repeated => {
+                let mut hasher = DefaultHasher::new();
+                name.hash(&mut hasher);
+                value.hash(&mut hasher);
+
+                let hash = hasher.finish();
+
+                if self.history.contains(&hash) {
+                    return true;
+                }
+
+                if self.history.len() == FIELD_HISTORY_LEN {
+                    self.history.pop_front();
+                }
+
+                self.history.push_back(hash);
+
+                false
+            },
+        }
+    }
+
+    /// Inserts a field into the dynamic table and generates the matching
+    /// encoder instruction. Returns the absolute index of the new entry.
+    fn insert(
+        &mut self, name: &[u8], value: &[u8], static_match: Option<(u64, bool)>,
+        dynamic_match: Option<(u64, bool)>, section: &Section,
+    ) -> Option<u64> {
+        let evictable_below = self.evictable_below(section);
+
+        // Start using the whole table the first time something is inserted.
+        if self.table.capacity() < self.table.max_capacity() &&
+            self.table.insert_count() == 0
+        {
+            let capacity = self.table.max_capacity();
+
+            self.table.set_capacity(capacity).ok()?;
+
+            let _len = self.emit_instruction(0, |b| {
+                encode_int(capacity, SET_DYNAMIC_TABLE_CAPACITY, 5, b)
+            })?;
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_created(
+                QPackInstruction::SetDynamicTableCapacityInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::SetDynamicTableCapacityInstruction,
+                    capacity,
+                },
+                _len,
+            ));
+        }
+
+        if !self
+            .table
+            .can_insert(entry_size(name, value), evictable_below)
+        {
+            return None;
+        }
+
+        let insert_count = self.table.insert_count();
+
+        let _len = self.emit_instruction(name.len() + value.len(), |b| {
+            match (static_match, dynamic_match) {
+                (Some((idx, _)), _) => {
+                    const STATIC: u8 = 0x40;
+
+                    encode_int(idx, INSERT_WITH_NAME_REF | STATIC, 6, b)?;
+                },
+
+                (None, Some((index, _))) => {
+                    let rel = insert_count - 1 - index;
+
+                    encode_int(rel, INSERT_WITH_NAME_REF, 6, b)?;
+                },
+
+                (None, None) => {
+                    const HUFFMAN: u8 = 0x20;
+
+                    let name_len =
+                        super::huffman::encode_output_length(name, true)?;
+
+                    encode_int(
+                        name_len as u64,
+                        INSERT_WITH_LITERAL_NAME | HUFFMAN,
+                        5,
+                        b,
+                    )?;
+
+                    super::huffman::encode(name, b, true)?;
+                },
+            }
+
+            encode_str(value, 7, b)
+        })?;
+
+        #[cfg(feature = "qlog")]
+        {
+            let instruction = match (static_match, dynamic_match) {
+                (Some((idx, _)), _) =>
+                    QPackInstruction::InsertWithNameReferenceInstruction {
+                        instruction_type:
+                            QpackInstructionTypeName::InsertWithNameReferenceInstruction,
+                        table_type: QpackTableType::Static,
+                        name_index: idx,
+                        huffman_encoded_value: true,
+                        value_length: value.len() as u64,
+                        value: super::qlog_string(value),
+                    },
+
+                (None, Some((index, _))) =>
+                    QPackInstruction::InsertWithNameReferenceInstruction {
+                        instruction_type:
+                            QpackInstructionTypeName::InsertWithNameReferenceInstruction,
+                        table_type: QpackTableType::Dynamic,
+                        name_index: insert_count - 1 - index,
+                        huffman_encoded_value: true,
+                        value_length: value.len() as u64,
+                        value: super::qlog_string(value),
+                    },
+
+                (None, None) =>
+                    QPackInstruction::InsertWithoutNameReferenceInstruction {
+                        instruction_type:
+                            QpackInstructionTypeName::InsertWithoutNameReferenceInstruction,
+                        huffman_encoded_name: true,
+                        name_length: name.len() as u64,
+                        name: super::qlog_string(name),
+                        huffman_encoded_value: true,
+                        value_length: value.len() as u64,
+                        value: super::qlog_string(value),
+                    },
+            };
+
+            self.qlog_events
+                .push(super::qlog_instruction_created(instruction, _len));
+            self.qlog_events.push(super::qlog_entry_added(
+                insert_count,
+                name,
+                value,
+            ));
+        }
+
+        self.table.insert(name.to_vec(), value.to_vec()).ok()
+    }
+
+    /// Appends a new encoder instruction and returns its length.
+    fn emit_instruction<F>(&mut self, strings_len: usize, f: F) -> Option<usize>
+    where
+        F: FnOnce(&mut octets::OctetsMut) -> Result<()>,
+    {
+        // Huffman encoding can expand strings by up to 4 times in the worst
+        // case, so leave plenty of room.
+        let mut d = vec![0; strings_len * 4 + 32];
+        let mut b = octets::OctetsMut::with_slice(&mut d);
+
+        f(&mut b).ok()?;
+
+        let off = b.off();
+        self.instructions.extend_from_slice(&d[..off]);
+
+        Some(off)
+    }
+
+    /// Returns whether the entry with the given absolute index can be
+    /// referenced by the header block being encoded, possibly making it
+    /// blocking.
+    fn can_reference(&self, index: u64, blocking: &mut bool) -> bool {
+        if index < self.known_received_count || *blocking {
+            return true;
+        }
+
+        if self.blocked_streams() < self.max_blocked_streams {
+            *blocking = true;
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns the absolute index of the oldest entry that can't be evicted
+    /// because it was not acknowledged yet, or it is referenced by a header
+    /// block that was not acknowledged yet.
+    fn evictable_below(&self, section: &Section) -> u64 {
+        self.sections.values().flatten().map(|s| s.min_index).fold(
+            std::cmp::min(self.known_received_count, section.min_index),
+            std::cmp::min,
+        )
+    }
+
+    fn is_blocking(&self, stream_id: u64) -> bool {
+        match self.sections.get(&stream_id) {
+            Some(sections) => sections
+                .iter()
+                .any(|s| s.required_insert_count > self.known_received_count),
+
+            None => false,
+        }
+    }
+
+    fn blocked_streams(&self) -> u64 {
+        self.sections
+            .keys()
+            .filter(|stream_id| self.is_blocking(**stream_id))
+            .count() as u64
+    }
+
+    fn process_instruction(&mut self, b: &mut octets::Octets) -> Result<()> {
+        let first = b.peek_u8()?;
+
+        #[cfg(feature = "qlog")]
+        let start = b.off();
+
+        if first & SECTION_ACKNOWLEDGEMENT == SECTION_ACKNOWLEDGEMENT {
+            let stream_id = super::decoder::decode_int(b, 7)?;
+
+            trace!("Section Acknowledgement stream={}", stream_id);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_parsed(
+                QPackInstruction::HeaderAcknowledgementInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::HeaderAcknowledgementInstruction,
+                    stream_id: stream_id.to_string(),
+                },
+                b.off() - start,
+            ));
+
+            let sections = self
+                .sections
+                .get_mut(&stream_id)
+                .ok_or(Error::DecoderStreamError)?;
+
+            let section =
+                sections.pop_front().ok_or(Error::DecoderStreamError)?;
+
+            if sections.is_empty() {
+                self.sections.remove(&stream_id);
+            }
+
+            self.known_received_count = std::cmp::max(
+                self.known_received_count,
+                section.required_insert_count,
+            );
+
+            return Ok(());
+        }
+
+        if first & STREAM_CANCELLATION == STREAM_CANCELLATION {
+            let stream_id = super::decoder::decode_int(b, 6)?;
+
+            trace!("Stream Cancellation stream={}", stream_id);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_events.push(super::qlog_instruction_parsed(
+                QPackInstruction::StreamCancellationInstruction {
+                    instruction_type:
+                        QpackInstructionTypeName::StreamCancellationInstruction,
+                    stream_id: stream_id.to_string(),
+                },
+                b.off() - start,
+            ));
+
+            self.sections.remove(&stream_id);
+
+            return Ok(());
+        }
+
+        let increment = super::decoder::decode_int(b, 6)?;
+
+        trace!("Insert Count Increment increment={}", increment);
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(super::qlog_instruction_parsed(
+            QPackInstruction::InsertCountIncrementInstruction {
+                instruction_type:
+                    QpackInstructionTypeName::InsertCountIncrementInstruction,
+                increment,
+            },
+            b.off() - start,
+        ));
+
+        let known_received_count = self
+            .known_received_count
+            .checked_add(increment)
+            .ok_or(Error::DecoderStreamError)?;
+
+        if increment == 0 || known_received_count > self.table.insert_count() {
+            return Err(Error::DecoderStreamError);
+        }
+
+        self.known_received_count = known_received_count;
+
+        Ok(())
+    }
+}
+
+/// Encodes the Required Insert Count of a header block as described in
+/// RFC 9204, Section 4.5.1.1.
+fn encode_required_insert_count(req_insert_count: u64, max_entries: u64) -> u64 {
+    if req_insert_count == 0 {
+        return 0;
+    }
+
+    req_insert_count % (2 * max_entries) + 1
 }
 
 fn lookup_static<T: NameValue>(h: &T) -> Option<(u64, bool)> {
@@ -113,7 +796,7 @@ fn lookup_static<T: NameValue>(h: &T) -> Option<(u64, bool)> {
     name_match
 }
 
-fn encode_int(
+pub(super) fn encode_int(
     mut v: u64, first: u8, prefix: usize, b: &mut octets::OctetsMut,
 ) -> Result<()> {
     let mask = 2u64.pow(prefix as u32) - 1;
@@ -31,6 +31,14 @@ const INDEXED_WITH_POST_BASE: u8 = 0b0001_0000;
 const LITERAL: u8 = 0b0010_0000;
 const LITERAL_WITH_NAME_REF: u8 = 0b0100_0000;
 
+const SET_DYNAMIC_TABLE_CAPACITY: u8 = 0b0010_0000;
+const INSERT_WITH_NAME_REF: u8 = 0b1000_0000;
+const INSERT_WITH_LITERAL_NAME: u8 = 0b0100_0000;
+
+const SECTION_ACKNOWLEDGEMENT: u8 = 0b1000_0000;
+const STREAM_CANCELLATION: u8 = 0b0100_0000;
+const INSERT_COUNT_INCREMENT: u8 = 0b0000_0000;
+
 /// A specialized [`Result`] type for quiche QPACK operations.
 ///
 /// This type is used throughout quiche's QPACK public API for any operation
@@ -56,6 +64,31 @@ pub enum Error {
 
     /// The decoded header list exceeded the size limit.
     HeaderListTooLarge,
+
+    /// The QPACK dynamic table index provided doesn't exist.
+    InvalidDynamicTableIndex,
+
+    /// The QPACK dynamic table capacity exceeds the configured maximum, or an
+    /// entry doesn't fit in the table.
+    InvalidDynamicTableCapacity,
+
+    /// The header block's Required Insert Count is not valid.
+    InvalidRequiredInsertCount,
+
+    /// The header block references dynamic table entries that have not been
+    /// received yet. It should be decoded again once the encoder instructions
+    /// inserting them are processed.
+    Blocked,
+
+    /// Decoding the header block would exceed the number of streams allowed to
+    /// be blocked at the same time.
+    TooManyBlockedStreams,
+
+    /// An invalid instruction was received on the QPACK encoder stream.
+    EncoderStreamError,
+
+    /// An invalid instruction was received on the QPACK decoder stream.
+    DecoderStreamError,
 }
 
 impl std::fmt::Display for Error {
@@ -76,12 +109,61 @@ impl std::convert::From<octets::BufferTooShortError> for Error {
     }
 }
 
+#[cfg(feature = "qlog")]
+fn qlog_string(v: &[u8]) -> String {
+    String::from_utf8_lossy(v).into_owned()
+}
+
+#[cfg(feature = "qlog")]
+fn qlog_instruction_created(
+    instruction: qlog::events::qpack::QPackInstruction, length: usize,
+) -> qlog::events::EventData {
+    qlog::events::EventData::QpackInstructionCreated(
+        qlog::events::qpack::QpackInstructionCreated {
+            instruction,
+            length: Some(length as u32),
+            raw: None,
+        },
+    )
+}
+
+#[cfg(feature = "qlog")]
+fn qlog_instruction_parsed(
+    instruction: qlog::events::qpack::QPackInstruction, length: usize,
+) -> qlog::events::EventData {
+    qlog::events::EventData::QpackInstructionParsed(
+        qlog::events::qpack::QpackInstructionParsed {
+            instruction,
+            length: Some(length as u32),
+            raw: None,
+        },
+    )
+}
+
+#[cfg(feature = "qlog")]
+fn qlog_entry_added(
+    index: u64, name: &[u8], value: &[u8],
+) -> qlog::events::EventData {
+    qlog::events::EventData::QpackDynamicTableUpdated(
+        qlog::events::qpack::QpackDynamicTableUpdated {
+            update_type: qlog::events::qpack::QpackUpdateType::Added,
+            entries: vec![qlog::events::qpack::QpackDynamicTableEntry {
+                index,
+                name: Some(qlog_string(name)),
+                value: Some(qlog_string(value)),
+            }],
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
 
     use super::*;
 
+    use super::Error;
+
     #[test]
     fn encode_decode() {
         let mut encoded = [0u8; 240];
@@ -99,10 +181,10 @@ mod tests {
         ];
 
         let mut enc = Encoder::new();
-        assert_eq!(enc.encode(&headers, &mut encoded), Ok(240));
+        assert_eq!(enc.encode(0, &headers, &mut encoded), Ok(240));
 
         let mut dec = Decoder::new();
-        assert_eq!(dec.decode(&mut encoded, std::u64::MAX), Ok(headers));
+        assert_eq!(dec.decode(0, &mut encoded, std::u64::MAX), Ok(headers));
     }
 
     #[test]
@@ -127,10 +209,10 @@ mod tests {
         ];
 
         let mut enc = Encoder::new();
-        assert_eq!(enc.encode(&headers_in, &mut encoded), Ok(35));
+        assert_eq!(enc.encode(0, &headers_in, &mut encoded), Ok(35));
 
         let mut dec = Decoder::new();
-        let headers_out = dec.decode(&mut encoded, std::u64::MAX).unwrap();
+        let headers_out = dec.decode(0, &mut encoded, std::u64::MAX).unwrap();
 
         assert_eq!(headers_expected, headers_out);
 
@@ -144,19 +226,316 @@ mod tests {
         ];
 
         let mut enc = Encoder::new();
-        assert_eq!(enc.encode(&headers_in, &mut encoded), Ok(35));
+        assert_eq!(enc.encode(0, &headers_in, &mut encoded), Ok(35));
 
         let mut dec = Decoder::new();
-        let headers_out = dec.decode(&mut encoded, std::u64::MAX).unwrap();
+        let headers_out = dec.decode(0, &mut encoded, std::u64::MAX).unwrap();
 
         assert_eq!(headers_expected, headers_out);
     }
+
+    fn dynamic_pair(blocked_streams: u64) -> (Encoder, Decoder) {
+        let mut enc = Encoder::new();
+        enc.set_insertion_policy(InsertionPolicy::Always);
+        enc.set_max_table_capacity(4096);
+        enc.set_max_blocked_streams(blocked_streams);
+
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(4096);
+        dec.set_max_blocked_streams(blocked_streams);
+
+        (enc, dec)
+    }
+
+    fn dynamic_headers() -> Vec<h3::Header> {
+        vec![
+            h3::Header::new(b":method", b"GET"),
+            h3::Header::new(b":authority", b"api.example.com"),
+            h3::Header::new(b"cookie", b"session=8f4e0c1a27b94d6e"),
+            h3::Header::new(b"x-api-key", b"aae0d2c8d2bd4b5cb8bd"),
+        ]
+    }
+
+    #[test]
+    fn dynamic_table() {
+        let mut encoded = [0u8; 240];
+
+        let headers = dynamic_headers();
+
+        let (mut enc, mut dec) = dynamic_pair(1);
+
+        let len = enc.encode(0, &headers, &mut encoded).unwrap();
+
+        // The header block references entries the decoder doesn't have yet.
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Err(Error::Blocked)
+        );
+
+        assert_eq!(dec.control(enc.pending_instructions()), Ok(()));
+        enc.drain_instructions(enc.pending_instructions().len());
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Ok(headers.clone())
+        );
+
+        // Insert Count Increment and Section Acknowledgement.
+        assert_eq!(dec.pending_instructions(), [0x03, 0x80]);
+        assert_eq!(enc.control(dec.pending_instructions()), Ok(()));
+        dec.drain_instructions(dec.pending_instructions().len());
+
+        // Now all fields can be encoded as indexed.
+        assert_eq!(enc.encode(4, &headers, &mut encoded), Ok(6));
+        assert!(enc.pending_instructions().is_empty());
+
+        assert_eq!(
+            dec.decode(4, &encoded[..6], std::u64::MAX),
+            Ok(headers.clone())
+        );
+    }
+
+    #[test]
+    fn dynamic_table_blocked_streams() {
+        let mut encoded = [0u8; 240];
+
+        let headers = dynamic_headers();
+
+        // The encoder won't block streams unless allowed to.
+        let (mut enc, mut dec) = dynamic_pair(0);
+
+        let len = enc.encode(0, &headers, &mut encoded).unwrap();
+        assert!(!enc.pending_instructions().is_empty());
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Ok(headers.clone())
+        );
+
+        // The decoder enforces its own limit.
+        let (mut enc, _) = dynamic_pair(1);
+        let (_, mut dec) = dynamic_pair(0);
+
+        let len = enc.encode(0, &headers, &mut encoded).unwrap();
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Err(Error::TooManyBlockedStreams)
+        );
+
+        // A stream only counts once, but others can't be blocked.
+        let (mut enc, mut dec) = dynamic_pair(1);
+
+        let len = enc.encode(0, &headers, &mut encoded).unwrap();
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Err(Error::Blocked)
+        );
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Err(Error::Blocked)
+        );
+        assert_eq!(
+            dec.decode(4, &encoded[..len], std::u64::MAX),
+            Err(Error::TooManyBlockedStreams)
+        );
+
+        dec.cancel_stream(0);
+        assert_eq!(dec.pending_instructions(), [0x40]);
+
+        assert_eq!(
+            dec.decode(4, &encoded[..len], std::u64::MAX),
+            Err(Error::Blocked)
+        );
+    }
+
+    #[test]
+    fn dynamic_table_eviction() {
+        let mut first_block = [0u8; 64];
+        let mut second_block = [0u8; 64];
+
+        let (mut enc, mut dec) = dynamic_pair(1);
+
+        // Only a single entry fits in the table.
+        enc.set_max_table_capacity(64);
+        dec.set_max_table_capacity(64);
+
+        let first = [h3::Header::new(b"foo", b"bar")];
+        let second = [h3::Header::new(b"foo", b"baz")];
+
+        let first_len = enc.encode(0, &first, &mut first_block).unwrap();
+        let instructions_len = enc.pending_instructions().len();
+
+        // The first entry can't be evicted until it's acknowledged.
+        let second_len = enc.encode(4, &second, &mut second_block).unwrap();
+        assert_eq!(enc.pending_instructions().len(), instructions_len);
+
+        assert_eq!(dec.control(enc.pending_instructions()), Ok(()));
+        enc.drain_instructions(instructions_len);
+
+        assert_eq!(
+            dec.decode(0, &first_block[..first_len], std::u64::MAX),
+            Ok(first.to_vec())
+        );
+        assert_eq!(
+            dec.decode(4, &second_block[..second_len], std::u64::MAX),
+            Ok(second.to_vec())
+        );
+
+        assert_eq!(enc.control(dec.pending_instructions()), Ok(()));
+        dec.drain_instructions(dec.pending_instructions().len());
+
+        let second_len = enc.encode(8, &second, &mut second_block).unwrap();
+        assert!(!enc.pending_instructions().is_empty());
+
+        assert_eq!(dec.control(enc.pending_instructions()), Ok(()));
+        assert_eq!(
+            dec.decode(8, &second_block[..second_len], std::u64::MAX),
+            Ok(second.to_vec())
+        );
+    }
+
+    #[test]
+    fn repeated_insertion_policy() {
+        let mut encoded = [0u8; 240];
+
+        let headers = dynamic_headers();
+
+        let (mut enc, _) = dynamic_pair(1);
+        enc.set_insertion_policy(InsertionPolicy::Repeated);
+
+        assert!(enc.encode(0, &headers, &mut encoded).is_ok());
+        assert!(enc.pending_instructions().is_empty());
+
+        assert!(enc.encode(4, &headers, &mut encoded).is_ok());
+        assert!(!enc.pending_instructions().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "qlog")]
+    fn dynamic_table_qlog() {
+        use qlog::events::qpack::QpackStreamState;
+        use qlog::events::EventData;
+
+        let mut encoded = [0u8; 240];
+
+        let headers = dynamic_headers();
+
+        let (mut enc, mut dec) = dynamic_pair(1);
+
+        let len = enc.encode(0, &headers, &mut encoded).unwrap();
+
+        let events = enc.take_qlog_events();
+
+        // Capacity, then one insertion and table update per field.
+        let created = events
+            .iter()
+            .filter(|e| matches!(e, EventData::QpackInstructionCreated(_)))
+            .count();
+        let added = events
+            .iter()
+            .filter(|e| matches!(e, EventData::QpackDynamicTableUpdated(_)))
+            .count();
+        assert_eq!(created, 1 + added);
+
+        match events.last() {
+            Some(EventData::QpackHeadersEncoded(ev)) => {
+                assert_eq!(ev.stream_id, Some(0));
+                assert_eq!(ev.header_block.len(), headers.len());
+                assert_eq!(ev.length, Some(len as u32));
+            },
+
+            _ => panic!("unexpected event"),
+        }
+
+        assert!(enc.take_qlog_events().is_empty());
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Err(Error::Blocked)
+        );
+
+        assert!(matches!(
+            dec.take_qlog_events()[..],
+            [EventData::QpackStreamStateUpdated(ref ev)]
+                if ev.state == QpackStreamState::Blocked
+        ));
+
+        assert_eq!(dec.control(enc.pending_instructions()), Ok(()));
+
+        let events = dec.take_qlog_events();
+        let parsed = events
+            .iter()
+            .filter(|e| matches!(e, EventData::QpackInstructionParsed(_)))
+            .count();
+        assert_eq!(parsed, created);
+
+        // Insert Count Increment.
+        assert!(matches!(
+            events.last(),
+            Some(EventData::QpackInstructionCreated(_))
+        ));
+
+        assert_eq!(
+            dec.decode(0, &encoded[..len], std::u64::MAX),
+            Ok(headers.clone())
+        );
+
+        let events = dec.take_qlog_events();
+        assert!(
+            matches!(events[0], EventData::QpackStreamStateUpdated(ref ev)
+            if ev.state == QpackStreamState::Unblocked)
+        );
+        assert!(matches!(events[1], EventData::QpackHeadersDecoded(ref ev)
+            if ev.header_block.len() == headers.len()));
+
+        // Section Acknowledgement.
+        assert!(matches!(events[2], EventData::QpackInstructionCreated(_)));
+    }
+
+    #[test]
+    fn invalid_instructions() {
+        let mut enc = Encoder::new();
+        let mut dec = Decoder::new();
+
+        // Duplicate of an entry that doesn't exist.
+        assert_eq!(dec.control(&[0x00]), Err(Error::EncoderStreamError));
+
+        // Capacity larger than the maximum.
+        let mut dec = Decoder::new();
+        assert_eq!(dec.control(&[0x21]), Err(Error::EncoderStreamError));
+
+        // Incomplete instructions are buffered.
+        let mut dec = Decoder::new();
+        dec.set_max_table_capacity(4096);
+        assert_eq!(dec.control(&[0x3f]), Ok(()));
+        assert_eq!(dec.control(&[0xe1, 0x1f]), Ok(()));
+        assert_eq!(dec.control(&[0x49, b'x']), Ok(()));
+        assert_eq!(dec.control(b"-api-key\x04abc"), Ok(()));
+        assert!(dec.pending_instructions().is_empty());
+        assert_eq!(dec.control(b"d"), Ok(()));
+        assert_eq!(dec.pending_instructions(), [0x01]);
+
+        // Insert Count Increment of zero.
+        assert_eq!(enc.control(&[0x00]), Err(Error::DecoderStreamError));
+
+        // Acknowledgement of a section that was never sent.
+        let mut enc = Encoder::new();
+        assert_eq!(enc.control(&[0x81]), Err(Error::DecoderStreamError));
+
+        // Stream Cancellation is always valid.
+        let mut enc = Encoder::new();
+        assert_eq!(enc.control(&[0x41]), Ok(()));
+    }
 }
 
 pub use decoder::Decoder;
 pub use encoder::Encoder;
+pub use encoder::InsertionPolicy;
 
 mod decoder;
+mod dynamic_table;
 mod encoder;
 mod huffman;
 mod static_table;
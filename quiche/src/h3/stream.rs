@@ -142,6 +142,9 @@ pub struct Stream {
     /// The type of the frame currently being parsed.
     frame_type: Option<u64>,
 
+    /// The push ID carried by a push stream, once parsed.
+    push_id: Option<u64>,
+
     /// Whether the stream was created locally, or by the peer.
     is_local: bool,
 
@@ -156,6 +159,10 @@ pub struct Stream {
 
     /// The last `PRIORITY_UPDATE` frame encoded field value, if any.
     last_priority_update: Option<Vec<u8>>,
+
+    /// The priority received in a `PRIORITY_UPDATE` frame that still needs to
+    /// be applied to the transport stream, if any.
+    pending_priority: Option<crate::h3::Priority>,
 }
 
 impl Stream {
@@ -189,6 +196,8 @@ impl Stream {
 
             frame_type: None,
 
+            push_id: None,
+
             is_local,
             remote_initialized: false,
             local_initialized: false,
@@ -196,6 +205,8 @@ impl Stream {
             data_event_triggered: false,
 
             last_priority_update: None,
+
+            pending_priority: None,
         }
     }
 
@@ -233,16 +244,21 @@ impl Stream {
     }
 
     /// Sets the push ID and transitions to the next state.
-    pub fn set_push_id(&mut self, _id: u64) -> Result<()> {
+    pub fn set_push_id(&mut self, id: u64) -> Result<()> {
         assert_eq!(self.state, State::PushId);
 
-        // TODO: implement push ID.
+        self.push_id = Some(id);
 
         self.state_transition(State::FrameType, 1, true)?;
 
         Ok(())
     }
 
+    /// Returns the push ID of a push stream, if known.
+    pub fn push_id(&self) -> Option<u64> {
+        self.push_id
+    }
+
     /// Sets the frame type and transitions to the next state.
     pub fn set_frame_type(&mut self, ty: u64) -> Result<()> {
         assert_eq!(self.state, State::FrameType);
@@ -516,6 +532,40 @@ impl Stream {
         Ok((len, fin))
     }
 
+    /// Tries to take DATA payload from the transport stream without copying
+    /// it.
+    pub fn try_consume_data_zc(
+        &mut self, conn: &mut crate::Connection, max_len: usize,
+    ) -> Result<(crate::SharedBuf, bool)> {
+        let left = std::cmp::min(max_len, self.state_len - self.state_off);
+
+        let (buf, fin) = match conn.stream_recv_zc(self.id, left) {
+            Ok(v) => v,
+
+            Err(e) => {
+                // The stream is not readable anymore, so re-arm the Data event.
+                if e == crate::Error::Done {
+                    self.reset_data_event();
+                }
+
+                return Err(e.into());
+            },
+        };
+
+        self.state_off += buf.len();
+
+        // The stream is not readable anymore, so re-arm the Data event.
+        if !conn.stream_readable(self.id) {
+            self.reset_data_event();
+        }
+
+        if self.state_buffer_complete() {
+            self.state_transition(State::FrameType, 1, true)?;
+        }
+
+        Ok((buf, fin))
+    }
+
     /// Marks the stream as finished.
     pub fn finished(&mut self) {
         let _ = self.state_transition(State::Finished, 0, false);
@@ -576,6 +626,19 @@ impl Stream {
         self.last_priority_update.is_some()
     }
 
+    /// Sets the priority that needs to be applied to the transport stream once
+    /// it is opened.
+    #[cfg(feature = "sfv")]
+    pub fn set_pending_priority(&mut self, priority: crate::h3::Priority) {
+        self.pending_priority = Some(priority);
+    }
+
+    /// Takes the priority that needs to be applied to the transport stream and
+    /// leaves `None` in its place.
+    pub fn take_pending_priority(&mut self) -> Option<crate::h3::Priority> {
+        self.pending_priority.take()
+    }
+
     /// Returns true if the state buffer has enough data to complete the state.
     fn state_buffer_complete(&self) -> bool {
         self.state_off == self.state_len
@@ -980,6 +1043,7 @@ mod tests {
 
         stream.set_push_id(push_id).unwrap();
         assert_eq!(stream.state, State::FrameType);
+        assert_eq!(stream.push_id(), Some(1));
 
         // Parse the HEADERS frame type.
         stream.try_fill_buffer_for_tests(&mut cursor).unwrap();
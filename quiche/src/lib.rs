@@ -55,13 +55,16 @@
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let server_name = "quic.tech";
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let to = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "0.0.0.0:0".parse().unwrap();
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
 //! // Client connection.
-//! let conn = quiche::connect(Some(&server_name), &scid, to, &mut config)?;
+//! let conn =
+//!     quiche::connect(Some(&server_name), &scid, local, peer, &mut config)?;
 //!
 //! // Server connection.
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! let conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! let conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! # Ok::<(), quiche::Error>(())
 //! ```
 //!
@@ -71,7 +74,8 @@
 //! The application also need to pass the address of the remote peer of the
 //! connection: in the case of a client that would be the address of the server
 //! it is trying to connect to, and for a server that is the address of the
-//! client that initiated the connection.
+//! client that initiated the connection. The local address the connection is
+//! using (e.g. the address the UDP socket is bound to) is also required.
 //!
 //! ## Handling incoming packets
 //!
@@ -83,12 +87,17 @@
 //! # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! loop {
 //!     let (read, from) = socket.recv_from(&mut buf).unwrap();
 //!
-//!     let recv_info = quiche::RecvInfo { from };
+//!     let recv_info = quiche::RecvInfo {
+//!         from,
+//!         to: socket.local_addr().unwrap(),
+//!         ecn: quiche::EcnCodepoint::NotEct,
+//!     };
 //!
 //!     let read = match conn.recv(&mut buf[..read], recv_info) {
 //!         Ok(v) => v,
@@ -109,7 +118,7 @@
 //!
 //! The application has to pass a [`RecvInfo`] structure in order to provide
 //! additional information about the received packet (such as the address it
-//! was received from).
+//! was received from, and the local address it was received on).
 //!
 //! ## Generating outgoing packets
 //!
@@ -121,8 +130,9 @@
 //! # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! loop {
 //!     let (write, send_info) = match conn.send(&mut out) {
 //!         Ok(v) => v,
@@ -154,8 +164,9 @@
 //! ```
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! let timeout = conn.timeout();
 //! # Ok::<(), quiche::Error>(())
 //! ```
@@ -170,8 +181,9 @@
 //! # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! // Timeout expired, handle it.
 //! conn.on_timeout();
 //!
@@ -225,8 +237,9 @@
 //! ```no_run
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! if conn.is_established() {
 //!     // Handshake completed, send some data on stream 0.
 //!     conn.stream_send(0, b"hello", true)?;
@@ -245,8 +258,9 @@
 //! # let mut buf = [0; 512];
 //! # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
 //! # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-//! # let from = "127.0.0.1:1234".parse().unwrap();
-//! # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+//! # let peer = "127.0.0.1:1234".parse().unwrap();
+//! # let local = "127.0.0.1:4321".parse().unwrap();
+//! # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 //! if conn.is_established() {
 //!     // Iterate over readable streams.
 //!     for stream_id in conn.readable() {
@@ -302,10 +316,29 @@
 //! config.set_cc_algorithm_name("reno").unwrap();
 //! ```
 //!
+//! Applications can also provide their own congestion control algorithm, by
+//! implementing the [`CongestionControl`] trait and registering a factory
+//! creating instances of it with [`set_cc_factory()`]:
+//!
+//! ```
+//! struct FixedWindow;
+//!
+//! impl quiche::CongestionControl for FixedWindow {
+//!     fn cwnd(&self) -> usize {
+//!         64 * 1024
+//!     }
+//! }
+//!
+//! let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+//! config.set_cc_factory(|| Box::new(FixedWindow));
+//! ```
+//!
 //! Note that the CC algorithm should be configured before calling [`connect()`]
 //! or [`accept()`]. Otherwise the connection will use a default CC algorithm.
 //!
 //! [`CongestionControlAlgorithm`]: enum.CongestionControlAlgorithm.html
+//! [`CongestionControl`]: trait.CongestionControl.html
+//! [`set_cc_factory()`]: struct.Config.html#method.set_cc_factory
 //!
 //! ## Feature flags
 //!
@@ -335,13 +368,23 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::ConnectionClosedTrigger;
+#[cfg(feature = "qlog")]
+use qlog::events::connectivity::ConnectivityEventType;
 #[cfg(feature = "qlog")]
 use qlog::events::connectivity::TransportOwner;
 #[cfg(feature = "qlog")]
+use qlog::events::quic::PacketDroppedTrigger;
+#[cfg(feature = "qlog")]
 use qlog::events::quic::RecoveryEventType;
 #[cfg(feature = "qlog")]
+use qlog::events::quic::SecurityEventType;
+#[cfg(feature = "qlog")]
 use qlog::events::quic::TransportEventType;
 #[cfg(feature = "qlog")]
+use qlog::events::security::KeyUpdateOrRetiredTrigger;
+#[cfg(feature = "qlog")]
 use qlog::events::DataRecipient;
 #[cfg(feature = "qlog")]
 use qlog::events::Event;
@@ -357,23 +400,49 @@ use qlog::events::RawInfo;
 use std::cmp;
 use std::time;
 
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::SocketAddrV6;
 
 use std::str::FromStr;
 
+use std::convert::TryInto;
+
 use std::collections::VecDeque;
 
 /// The current QUIC wire version.
 pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_V1;
 
-/// Supported QUIC versions.
+/// QUIC version 1, as defined in RFC 9000.
+pub const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+
+/// QUIC version 2, as defined in RFC 9369.
+pub const PROTOCOL_VERSION_V2: u32 = 0x6b33_43cf;
+
+/// Supported draft QUIC versions.
 ///
-/// Note that the older ones might not be fully supported.
-const PROTOCOL_VERSION_V1: u32 = 0x0000_0001;
+/// Note that these might not be fully supported.
+#[cfg(feature = "draft-versions")]
 const PROTOCOL_VERSION_DRAFT27: u32 = 0xff00_001b;
+#[cfg(feature = "draft-versions")]
 const PROTOCOL_VERSION_DRAFT28: u32 = 0xff00_001c;
+#[cfg(feature = "draft-versions")]
 const PROTOCOL_VERSION_DRAFT29: u32 = 0xff00_001d;
 
+/// All the supported QUIC versions, in order of preference.
+const SUPPORTED_VERSIONS: &[u32] = &[
+    PROTOCOL_VERSION_V1,
+    PROTOCOL_VERSION_V2,
+    #[cfg(feature = "draft-versions")]
+    PROTOCOL_VERSION_DRAFT29,
+    #[cfg(feature = "draft-versions")]
+    PROTOCOL_VERSION_DRAFT28,
+    #[cfg(feature = "draft-versions")]
+    PROTOCOL_VERSION_DRAFT27,
+];
+
 /// The maximum length of a connection ID.
 pub const MAX_CONN_ID_LEN: usize = crate::packet::MAX_CID_LEN as usize;
 
@@ -427,6 +496,12 @@ const MAX_CONNECTION_WINDOW: u64 = 24 * 1024 * 1024;
 // the stream flow control window.
 const CONNECTION_WINDOW_FACTOR: f64 = 1.5;
 
+// The default lifetime of tokens sent in NEW_TOKEN frames, in milliseconds.
+const DEFAULT_ADDRESS_TOKEN_LIFETIME: u64 = 24 * 60 * 60 * 1000;
+
+// The maximum number of received NEW_TOKEN tokens that are kept.
+const MAX_NEW_TOKENS: usize = 8;
+
 /// A specialized [`Result`] type for quiche operations.
 ///
 /// This type is used throughout quiche's public API for any operation that
@@ -496,6 +571,17 @@ pub enum Error {
 
     /// Error in congestion control.
     CongestionControl,
+
+    /// Too many identifiers were provided.
+    IdLimit,
+
+    /// Not enough available identifiers.
+    OutOfIdentifiers,
+
+    /// The version negotiation failed, e.g. because the peer's
+    /// version_information transport parameter doesn't match the negotiated
+    /// version.
+    VersionNegotiation,
 }
 
 impl Error {
@@ -508,6 +594,8 @@ impl Error {
             Error::FlowControl => 0x3,
             Error::StreamLimit => 0x4,
             Error::FinalSize => 0x6,
+            Error::IdLimit => 0x9,
+            Error::VersionNegotiation => 0x11,
             _ => 0xa,
         }
     }
@@ -531,6 +619,9 @@ impl Error {
             Error::CongestionControl => -14,
             Error::StreamStopped { .. } => -15,
             Error::StreamReset { .. } => -16,
+            Error::IdLimit => -17,
+            Error::OutOfIdentifiers => -18,
+            Error::VersionNegotiation => -19,
         }
     }
 }
@@ -553,17 +644,60 @@ impl std::convert::From<octets::BufferTooShortError> for Error {
     }
 }
 
+/// The ECN codepoint of an IP packet.
+///
+/// The values match the ECN field of the IP header, as defined in [RFC 3168].
+///
+/// [RFC 3168]: https://www.rfc-editor.org/rfc/rfc3168
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EcnCodepoint {
+    /// Not ECN-Capable Transport.
+    NotEct = 0,
+
+    /// ECN-Capable Transport, ECT(1).
+    Ect1   = 1,
+
+    /// ECN-Capable Transport, ECT(0).
+    Ect0   = 2,
+
+    /// Congestion Experienced.
+    Ce     = 3,
+}
+
+impl From<u8> for EcnCodepoint {
+    /// Extracts the ECN codepoint from the given IP TOS or Traffic Class
+    /// value.
+    fn from(v: u8) -> Self {
+        match v & 0x03 {
+            0x01 => EcnCodepoint::Ect1,
+            0x02 => EcnCodepoint::Ect0,
+            0x03 => EcnCodepoint::Ce,
+            _ => EcnCodepoint::NotEct,
+        }
+    }
+}
+
 /// Ancillary information about incoming packets.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RecvInfo {
-    /// The address the packet was received from.
+    /// The remote address the packet was received from.
     pub from: SocketAddr,
+
+    /// The local address the packet was received on.
+    pub to: SocketAddr,
+
+    /// The ECN codepoint of the IP packet carrying the QUIC packet.
+    pub ecn: EcnCodepoint,
 }
 
 /// Ancillary information about outgoing packets.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SendInfo {
-    /// The address the packet should be sent to.
+    /// The local address the packet should be sent from.
+    pub from: SocketAddr,
+
+    /// The remote address the packet should be sent to.
     pub to: SocketAddr,
 
     /// The time to send the packet out.
@@ -572,6 +706,51 @@ pub struct SendInfo {
     ///
     /// [Pacing]: index.html#pacing
     pub at: time::Instant,
+
+    /// The ECN codepoint the packet should be sent with.
+    ///
+    /// This is always [`EcnCodepoint::NotEct`] unless ECN was enabled with
+    /// [`enable_ecn()`].
+    ///
+    /// [`EcnCodepoint::NotEct`]: enum.EcnCodepoint.html#variant.NotEct
+    /// [`enable_ecn()`]: struct.Config.html#method.enable_ecn
+    pub ecn: EcnCodepoint,
+}
+
+/// A server's preferred address, as carried by the `preferred_address`
+/// transport parameter.
+///
+/// Clients migrate to the preferred address of the server's address family
+/// once the handshake is confirmed, using the connection ID provided with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreferredAddress {
+    /// The preferred IPv4 address, if any.
+    pub ipv4: Option<SocketAddrV4>,
+
+    /// The preferred IPv6 address, if any.
+    pub ipv6: Option<SocketAddrV6>,
+
+    /// The connection ID to use on the preferred address.
+    pub cid: ConnectionId<'static>,
+
+    /// The stateless reset token of the connection ID.
+    pub reset_token: u128,
+}
+
+impl PreferredAddress {
+    /// Returns the preferred address of the same family as `addr`, if any.
+    fn matching(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        match addr {
+            SocketAddr::V4(_) => self.ipv4.map(SocketAddr::V4),
+
+            SocketAddr::V6(_) => self.ipv6.map(SocketAddr::V6),
+        }
+    }
+
+    /// Returns true if `addr` is one of the preferred addresses.
+    fn contains(&self, addr: &SocketAddr) -> bool {
+        self.matching(addr) == Some(*addr)
+    }
 }
 
 /// Represents information carried by `CONNECTION_CLOSE` frames.
@@ -622,6 +801,8 @@ pub struct Config {
 
     version: u32,
 
+    versions: Vec<u32>,
+
     tls_ctx: tls::Context,
 
     application_protos: Vec<Vec<u8>>,
@@ -630,8 +811,16 @@ pub struct Config {
 
     cc_algorithm: CongestionControlAlgorithm,
 
+    cc_factory: Option<std::sync::Arc<CongestionControlFactory>>,
+
     hystart: bool,
 
+    ecn: bool,
+
+    pmtud: bool,
+
+    ack_frequency: bool,
+
     dgram_recv_max_queue_len: usize,
     dgram_send_max_queue_len: usize,
 
@@ -639,6 +828,15 @@ pub struct Config {
 
     max_connection_window: u64,
     max_stream_window: u64,
+
+    stream_scheduler: StreamScheduler,
+
+    max_packets_per_key: u64,
+
+    address_token_key: Option<Vec<u8>>,
+    address_token_lifetime: u64,
+
+    stateless_reset_key: Option<Vec<u8>>,
 }
 
 // See https://quicwg.org/base-drafts/rfc9000.html#section-15
@@ -681,11 +879,16 @@ impl Config {
         Ok(Config {
             local_transport_params: TransportParams::default(),
             version,
+            versions: preferred_versions(version),
             tls_ctx,
             application_protos: Vec::new(),
             grease: true,
             cc_algorithm: CongestionControlAlgorithm::CUBIC,
+            cc_factory: None,
             hystart: true,
+            ecn: false,
+            pmtud: false,
+            ack_frequency: false,
 
             dgram_recv_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
             dgram_send_max_queue_len: DEFAULT_MAX_DGRAM_QUEUE_LEN,
@@ -694,6 +897,15 @@ impl Config {
 
             max_connection_window: MAX_CONNECTION_WINDOW,
             max_stream_window: stream::MAX_STREAM_WINDOW,
+
+            stream_scheduler: StreamScheduler::Strict,
+
+            max_packets_per_key: std::u64::MAX,
+
+            address_token_key: None,
+            address_token_lifetime: DEFAULT_ADDRESS_TOKEN_LIFETIME,
+
+            stateless_reset_key: None,
         })
     }
 
@@ -963,6 +1175,18 @@ impl Config {
         self.local_transport_params.max_ack_delay = v;
     }
 
+    /// Sets the `min_ack_delay` transport parameter, in microseconds.
+    ///
+    /// Advertising this parameter allows the peer to change how often ACKs
+    /// are sent, using the ACK_FREQUENCY and IMMEDIATE_ACK frames defined by
+    /// draft-ietf-quic-ack-frequency. The value must not exceed the
+    /// `max_ack_delay` transport parameter.
+    ///
+    /// The parameter isn't sent by default.
+    pub fn set_min_ack_delay(&mut self, v: u64) {
+        self.local_transport_params.min_ack_delay = Some(v);
+    }
+
     /// Sets the `disable_active_migration` transport parameter.
     ///
     /// The default value is `false`.
@@ -970,6 +1194,32 @@ impl Config {
         self.local_transport_params.disable_active_migration = v;
     }
 
+    /// Sets the `preferred_address` transport parameter.
+    ///
+    /// This is only used by servers. Clients migrate to the preferred address
+    /// once the handshake is confirmed, even if active migration is disabled,
+    /// so the application needs to route packets received on the preferred
+    /// address, and carrying the connection ID `cid`, to the connection.
+    ///
+    /// As connection IDs must not be shared between connections, this
+    /// should be called with a new connection ID before accepting each
+    /// connection.
+    pub fn set_preferred_address(&mut self, v: PreferredAddress) {
+        self.local_transport_params.preferred_address = Some(v);
+    }
+
+    /// Sets the `active_connection_id_limit` transport parameter.
+    ///
+    /// This is the maximum number of connection IDs provided by the peer that
+    /// are stored locally. Values lower than 2 are ignored.
+    ///
+    /// The default value is `2`.
+    pub fn set_active_connection_id_limit(&mut self, v: u64) {
+        if v >= 2 {
+            self.local_transport_params.active_conn_id_limit = v;
+        }
+    }
+
     /// Sets the congestion control algorithm used by string.
     ///
     /// The default value is `cubic`. On error `Error::CongestionControl`
@@ -995,6 +1245,38 @@ impl Config {
         self.cc_algorithm = algo;
     }
 
+    /// Sets a factory creating application-provided congestion controllers.
+    ///
+    /// A new controller is created for each network path used by a
+    /// connection. When a factory is set, it overrides the algorithm selected
+    /// with [`set_cc_algorithm()`] or [`set_cc_algorithm_name()`].
+    ///
+    /// ## Examples:
+    ///
+    /// ```
+    /// struct FixedWindow(usize);
+    ///
+    /// impl quiche::CongestionControl for FixedWindow {
+    ///     fn cwnd(&self) -> usize {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// # let mut config = quiche::Config::new(0xbabababa)?;
+    /// config.set_cc_factory(|| Box::new(FixedWindow(64 * 1024)));
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    ///
+    /// [`set_cc_algorithm()`]: struct.Config.html#method.set_cc_algorithm
+    /// [`set_cc_algorithm_name()`]:
+    /// struct.Config.html#method.set_cc_algorithm_name
+    pub fn set_cc_factory<F>(&mut self, factory: F)
+    where
+        F: Fn() -> Box<dyn CongestionControl> + Send + Sync + 'static,
+    {
+        self.cc_factory = Some(std::sync::Arc::new(factory));
+    }
+
     /// Configures whether to enable HyStart++.
     ///
     /// The default value is `true`.
@@ -1002,6 +1284,56 @@ impl Config {
         self.hystart = v;
     }
 
+    /// Configures whether to mark outgoing packets with ECN.
+    ///
+    /// When enabled, packets are marked with the ECT(0) codepoint as
+    /// indicated by [`SendInfo`], and ECN is validated on each path: marking
+    /// stops on paths that don't correctly report ECN counts. Reported ECN-CE
+    /// marks are treated as congestion signals.
+    ///
+    /// The application is responsible for setting the codepoint on outgoing
+    /// UDP datagrams. ECN counts of received packets are always reported to
+    /// the peer, provided the application sets the [`RecvInfo`] codepoint.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`SendInfo`]: struct.SendInfo.html
+    /// [`RecvInfo`]: struct.RecvInfo.html
+    pub fn enable_ecn(&mut self, v: bool) {
+        self.ecn = v;
+    }
+
+    /// Configures whether to enable Path MTU discovery.
+    ///
+    /// When enabled, the maximum datagram size starts at 1200 bytes and is
+    /// raised by sending padded probe packets, up to the size configured
+    /// with [`set_max_send_udp_payload_size()`]. If larger packets stop
+    /// being delivered, the size falls back to 1200 bytes and the search
+    /// restarts. The loss of a probe isn't treated as a congestion signal.
+    ///
+    /// The current size is reported by [`Stats::pmtu`].
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`set_max_send_udp_payload_size()`]:
+    /// struct.Config.html#method.set_max_send_udp_payload_size
+    /// [`Stats::pmtu`]: struct.Stats.html#structfield.pmtu
+    pub fn discover_pmtu(&mut self, v: bool) {
+        self.pmtud = v;
+    }
+
+    /// Configures whether to tune the peer's ACK frequency automatically.
+    ///
+    /// When enabled and the peer advertises the `min_ack_delay` transport
+    /// parameter, ACK_FREQUENCY frames are sent so that the peer acknowledges
+    /// a few times per congestion window, instead of every other packet.
+    /// This reduces the cost of processing ACKs on bulk transfers.
+    ///
+    /// The default value is `false`.
+    pub fn enable_ack_frequency(&mut self, v: bool) {
+        self.ack_frequency = v;
+    }
+
     /// Configures whether to enable receiving DATAGRAM frames.
     ///
     /// When enabled, the `max_datagram_frame_size` transport parameter is set
@@ -1033,6 +1365,110 @@ impl Config {
     pub fn set_max_stream_window(&mut self, v: u64) {
         self.max_stream_window = v;
     }
+
+    /// Sets the algorithm used to schedule stream data.
+    ///
+    /// With the round-robin schedulers the streams' urgency is ignored, and
+    /// the bandwidth is shared according to the weights set with
+    /// [`stream_weight()`].
+    ///
+    /// The default value is `StreamScheduler::Strict`.
+    ///
+    /// [`stream_weight()`]: struct.Connection.html#method.stream_weight
+    pub fn set_stream_scheduler(&mut self, scheduler: StreamScheduler) {
+        self.stream_scheduler = scheduler;
+    }
+
+    /// Sets the maximum number of packets sent with the same 1-RTT keys.
+    ///
+    /// Once the limit is reached a key update is initiated. The limit is
+    /// capped by the confidentiality limit of the negotiated AEAD, which is
+    /// also the default.
+    pub fn set_max_packets_per_key(&mut self, v: u64) {
+        self.max_packets_per_key = v;
+    }
+
+    /// Configures the key used to authenticate address validation tokens.
+    ///
+    /// The key must be 16 bytes long. When set, servers send a token in a
+    /// NEW_TOKEN frame once the handshake is confirmed, and consider the
+    /// address of clients that present a valid one in later connections as
+    /// validated. The same key is used by [`mint_retry_token()`] and
+    /// [`validate_token()`].
+    ///
+    /// [`mint_retry_token()`]: fn.mint_retry_token.html
+    /// [`validate_token()`]: fn.validate_token.html
+    pub fn set_address_token_key(&mut self, key: &[u8]) -> Result<()> {
+        if key.len() != token::KEY_LEN {
+            return Err(Error::CryptoFail);
+        }
+
+        self.address_token_key = Some(key.to_vec());
+
+        Ok(())
+    }
+
+    /// Sets the lifetime of tokens sent in NEW_TOKEN frames, in milliseconds.
+    ///
+    /// The default value is 24 hours.
+    pub fn set_address_token_lifetime(&mut self, v: u64) {
+        self.address_token_lifetime = v;
+    }
+
+    /// Configures the QUIC versions supported by the endpoint, in order of
+    /// preference.
+    ///
+    /// Servers only accept connections using one of these versions, and list
+    /// them in Version Negotiation packets sent with
+    /// [`negotiate_version_with_config()`]. Servers also upgrade connections
+    /// to the most preferred version the client supports, when compatible
+    /// with the one the client used. Clients select the most preferred
+    /// version offered by the server after receiving a Version Negotiation
+    /// packet.
+    ///
+    /// The default value is the version passed to [`Config::new()`],
+    /// followed by all other supported versions.
+    ///
+    /// Returns [`UnknownVersion`] if the list is empty or includes a version
+    /// that is not supported.
+    ///
+    /// [`negotiate_version_with_config()`]: fn.negotiate_version_with_config.html
+    /// [`Config::new()`]: struct.Config.html#method.new
+    /// [`UnknownVersion`]: enum.Error.html#variant.UnknownVersion
+    pub fn set_supported_versions(&mut self, versions: &[u32]) -> Result<()> {
+        if versions.is_empty() ||
+            versions.iter().any(|&v| !version_is_supported(v))
+        {
+            return Err(Error::UnknownVersion);
+        }
+
+        self.versions = versions.to_vec();
+
+        Ok(())
+    }
+
+    /// Returns true if the given protocol version is supported by the
+    /// endpoint.
+    ///
+    /// See [`set_supported_versions()`].
+    ///
+    /// [`set_supported_versions()`]: struct.Config.html#method.set_supported_versions
+    pub fn version_is_supported(&self, version: u32) -> bool {
+        self.versions.contains(&version)
+    }
+
+    /// Configures the static key used to derive stateless reset tokens.
+    ///
+    /// When set, servers advertise the token derived for their initial
+    /// connection ID in the `stateless_reset_token` transport parameter. The
+    /// same key should be used with [`stateless_reset()`] to reset
+    /// connections whose state was lost, e.g. after a restart, so it should be
+    /// kept across restarts and shared by all servers behind the same address.
+    ///
+    /// [`stateless_reset()`]: fn.stateless_reset.html
+    pub fn set_stateless_reset_key(&mut self, key: &[u8]) {
+        self.stateless_reset_key = Some(key.to_vec());
+    }
 }
 
 /// A QUIC connection.
@@ -1040,11 +1476,16 @@ pub struct Connection {
     /// QUIC wire version used for the connection.
     version: u32,
 
-    /// Peer's connection ID.
-    dcid: ConnectionId<'static>,
+    /// QUIC wire version used by the client's first Initial packet. It only
+    /// differs from `version` when the connection was upgraded to a
+    /// compatible version.
+    original_version: u32,
 
-    /// Local connection ID.
-    scid: ConnectionId<'static>,
+    /// Supported QUIC wire versions, in order of preference.
+    versions: Vec<u32>,
+
+    /// Connection identifiers.
+    ids: cid::ConnectionIdentifiers,
 
     /// Unique opaque ID for the connection that can be used for logging.
     trace_id: String,
@@ -1067,10 +1508,13 @@ pub struct Connection {
     /// client. On the server this is empty.
     session: Option<Vec<u8>>,
 
-    /// Loss recovery and congestion control state.
-    recovery: recovery::Recovery,
+    /// The network paths used by the connection, each with its own loss
+    /// recovery and congestion control state.
+    paths: path::PathMap,
 
-    peer_addr: SocketAddr,
+    /// Loss recovery and congestion control configuration used for new
+    /// paths.
+    recovery_config: recovery::RecoveryConfig,
 
     /// List of supported application protocols.
     application_protos: Vec<Vec<u8>>,
@@ -1105,9 +1549,11 @@ pub struct Connection {
     /// Last tx_data before running a full send() loop.
     last_tx_data: u64,
 
-    /// Total number of bytes the server can send before the peer's address
-    /// is verified.
-    max_send_bytes: usize,
+    /// Total number of lost packets on paths that are no longer in use.
+    evicted_lost_count: usize,
+
+    /// Total number of bytes lost on paths that are no longer in use.
+    evicted_lost_bytes: u64,
 
     /// Total number of bytes retransmitted over the connection.
     /// This counts only STREAM and CRYPTO data.
@@ -1133,6 +1579,18 @@ pub struct Connection {
     /// Received address verification token.
     token: Option<Vec<u8>>,
 
+    /// Key used to mint and validate address validation tokens.
+    address_token_key: Option<Vec<u8>>,
+
+    /// Lifetime of tokens sent in NEW_TOKEN frames.
+    address_token_lifetime: time::Duration,
+
+    /// Whether a NEW_TOKEN frame has been sent.
+    new_token_sent: bool,
+
+    /// Tokens received in NEW_TOKEN frames.
+    new_tokens: VecDeque<Vec<u8>>,
+
     /// Error code and reason to be sent to the peer in a CONNECTION_CLOSE
     /// frame.
     local_error: Option<ConnectionError>,
@@ -1141,9 +1599,6 @@ pub struct Connection {
     /// frame.
     peer_error: Option<ConnectionError>,
 
-    /// Received path challenge.
-    challenge: Option<[u8; 8]>,
-
     /// The connection-level limit at which send blocking occurred.
     blocked_limit: Option<u64>,
 
@@ -1175,9 +1630,6 @@ pub struct Connection {
     /// Whether the peer already updated its connection ID.
     got_peer_conn_id: bool,
 
-    /// Whether the peer's address has been verified.
-    verified_peer_address: bool,
-
     /// Whether the peer has verified our address.
     peer_verified_address: bool,
 
@@ -1196,6 +1648,13 @@ pub struct Connection {
     /// Whether the connection handshake has been confirmed.
     handshake_confirmed: bool,
 
+    /// The current 1-RTT key phase.
+    key_phase: bool,
+
+    /// Maximum number of packets sent with the same 1-RTT keys before a key
+    /// update is initiated.
+    max_packets_per_key: u64,
+
     /// Whether an ack-eliciting packet has been sent since last receiving a
     /// packet.
     ack_eliciting_sent: bool,
@@ -1206,6 +1665,13 @@ pub struct Connection {
     // Whether the connection was timed out
     timed_out: bool,
 
+    // Whether the connection was closed by a stateless reset.
+    stateless_reset: bool,
+
+    // Whether the client already tried to migrate to the server's preferred
+    // address.
+    preferred_address_used: bool,
+
     /// Whether to send GREASE.
     grease: bool,
 
@@ -1221,6 +1687,13 @@ pub struct Connection {
 
     /// Whether to emit DATAGRAM frames in the next packet.
     emit_dgram: bool,
+
+    /// ACK frequency state.
+    ack_freq: ack_freq::AckFrequency,
+
+    /// Whether to tune the peer's ACK frequency based on the congestion
+    /// window.
+    ack_freq_auto: bool,
 }
 
 /// Creates a new server-side connection.
@@ -1230,6 +1703,9 @@ pub struct Connection {
 /// client sent before a stateless retry (this is only required when using
 /// the [`retry()`] function).
 ///
+/// The `local` and `peer` parameters are the addresses of the server and of
+/// the client that initiated the connection, respectively.
+///
 /// [`retry()`]: fn.retry.html
 ///
 /// ## Examples:
@@ -1237,16 +1713,17 @@ pub struct Connection {
 /// ```no_run
 /// # let mut config = quiche::Config::new(0xbabababa)?;
 /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-/// # let from = "127.0.0.1:1234".parse().unwrap();
-/// let conn = quiche::accept(&scid, None, from, &mut config)?;
+/// # let local = "127.0.0.1:4321".parse().unwrap();
+/// # let peer = "127.0.0.1:1234".parse().unwrap();
+/// let conn = quiche::accept(&scid, None, local, peer, &mut config)?;
 /// # Ok::<(), quiche::Error>(())
 /// ```
 #[inline]
 pub fn accept(
-    scid: &ConnectionId, odcid: Option<&ConnectionId>, from: SocketAddr,
-    config: &mut Config,
+    scid: &ConnectionId, odcid: Option<&ConnectionId>, local: SocketAddr,
+    peer: SocketAddr, config: &mut Config,
 ) -> Result<Connection> {
-    let conn = Connection::new(scid, odcid, from, config, true)?;
+    let conn = Connection::new(scid, odcid, local, peer, config, true)?;
 
     Ok(conn)
 }
@@ -1257,22 +1734,27 @@ pub fn accept(
 /// while the optional `server_name` parameter is used to verify the peer's
 /// certificate.
 ///
+/// The `local` and `peer` parameters are the local address the connection is
+/// using and the address of the server, respectively.
+///
 /// ## Examples:
 ///
 /// ```no_run
 /// # let mut config = quiche::Config::new(0xbabababa)?;
 /// # let server_name = "quic.tech";
 /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-/// # let to = "127.0.0.1:1234".parse().unwrap();
-/// let conn = quiche::connect(Some(&server_name), &scid, to, &mut config)?;
+/// # let local = "0.0.0.0:0".parse().unwrap();
+/// # let peer = "127.0.0.1:1234".parse().unwrap();
+/// let conn =
+///     quiche::connect(Some(&server_name), &scid, local, peer, &mut config)?;
 /// # Ok::<(), quiche::Error>(())
 /// ```
 #[inline]
 pub fn connect(
-    server_name: Option<&str>, scid: &ConnectionId, to: SocketAddr,
-    config: &mut Config,
+    server_name: Option<&str>, scid: &ConnectionId, local: SocketAddr,
+    peer: SocketAddr, config: &mut Config,
 ) -> Result<Connection> {
-    let mut conn = Connection::new(scid, None, to, config, false)?;
+    let mut conn = Connection::new(scid, None, local, peer, config, false)?;
 
     if let Some(server_name) = server_name {
         conn.handshake.set_host_name(server_name)?;
@@ -1287,6 +1769,10 @@ pub fn connect(
 /// destination connection ID extracted from the received client's Initial
 /// packet that advertises an unsupported version.
 ///
+/// All the supported versions are listed. Use
+/// [`negotiate_version_with_config()`] to only list the versions configured
+/// with [`set_supported_versions()`].
+///
 /// ## Examples:
 ///
 /// ```no_run
@@ -1304,11 +1790,50 @@ pub fn connect(
 /// }
 /// # Ok::<(), quiche::Error>(())
 /// ```
+///
+/// [`negotiate_version_with_config()`]: fn.negotiate_version_with_config.html
+/// [`set_supported_versions()`]: struct.Config.html#method.set_supported_versions
 #[inline]
 pub fn negotiate_version(
     scid: &ConnectionId, dcid: &ConnectionId, out: &mut [u8],
 ) -> Result<usize> {
-    packet::negotiate_version(scid, dcid, out)
+    packet::negotiate_version(scid, dcid, SUPPORTED_VERSIONS, out)
+}
+
+/// Writes a version negotiation packet listing the versions supported by the
+/// given configuration.
+///
+/// This is like [`negotiate_version()`], but only the versions configured
+/// with [`set_supported_versions()`] are listed, in order of preference.
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut buf = [0; 512];
+/// # let mut out = [0; 512];
+/// # let config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// let (len, src) = socket.recv_from(&mut buf).unwrap();
+///
+/// let hdr =
+///     quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
+///
+/// if !config.version_is_supported(hdr.version) {
+///     let len = quiche::negotiate_version_with_config(
+///         &config, &hdr.scid, &hdr.dcid, &mut out,
+///     )?;
+///     socket.send_to(&out[..len], &src).unwrap();
+/// }
+/// # Ok::<(), quiche::Error>(())
+/// ```
+///
+/// [`negotiate_version()`]: fn.negotiate_version.html
+/// [`set_supported_versions()`]: struct.Config.html#method.set_supported_versions
+#[inline]
+pub fn negotiate_version_with_config(
+    config: &Config, scid: &ConnectionId, dcid: &ConnectionId, out: &mut [u8],
+) -> Result<usize> {
+    packet::negotiate_version(scid, dcid, &config.versions, out)
 }
 
 /// Writes a stateless retry packet.
@@ -1322,9 +1847,12 @@ pub fn negotiate_version(
 /// token to be sent to the client, and verifying tokens sent back by the
 /// client. The generated token should include the `dcid` parameter, such
 /// that it can be later extracted from the token and passed to the
-/// [`accept()`] function as its `odcid` parameter.
+/// [`accept()`] function as its `odcid` parameter. The [`mint_retry_token()`]
+/// and [`validate_token()`] functions can be used for this purpose.
 ///
 /// [`accept()`]: fn.accept.html
+/// [`mint_retry_token()`]: fn.mint_retry_token.html
+/// [`validate_token()`]: fn.validate_token.html
 ///
 /// ## Examples:
 ///
@@ -1334,24 +1862,25 @@ pub fn negotiate_version(
 /// # let mut out = [0; 512];
 /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
 /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
-/// # fn mint_token(hdr: &quiche::Header, src: &std::net::SocketAddr) -> Vec<u8> {
-/// #     vec![]
-/// # }
-/// # fn validate_token<'a>(src: &std::net::SocketAddr, token: &'a [u8]) -> Option<quiche::ConnectionId<'a>> {
-/// #     None
-/// # }
+/// # let local = socket.local_addr().unwrap();
 /// let (len, src) = socket.recv_from(&mut buf).unwrap();
 ///
-/// let hdr = quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
+/// let hdr =
+///     quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN)?;
 ///
 /// let token = hdr.token.as_ref().unwrap();
 ///
 /// // No token sent by client, create a new one.
 /// if token.is_empty() {
-///     let new_token = mint_token(&hdr, &src);
+///     let new_token = quiche::mint_retry_token(&config, &hdr.dcid, src)?;
 ///
 ///     let len = quiche::retry(
-///         &hdr.scid, &hdr.dcid, &scid, &new_token, hdr.version, &mut out,
+///         &hdr.scid,
+///         &hdr.dcid,
+///         &scid,
+///         &new_token,
+///         hdr.version,
+///         &mut out,
 ///     )?;
 ///
 ///     socket.send_to(&out[..len], &src).unwrap();
@@ -1359,14 +1888,17 @@ pub fn negotiate_version(
 /// }
 ///
 /// // Client sent token, validate it.
-/// let odcid = validate_token(&src, token);
+/// let odcid = match quiche::validate_token(&config, token, src) {
+///     Some(quiche::AddressToken::Retry { odcid }) => Some(odcid),
+///
+///     // The client's address is validated by the connection itself.
+///     Some(quiche::AddressToken::NewToken) => None,
 ///
-/// if odcid.is_none() {
 ///     // Invalid address validation token.
-///     return Ok(());
-/// }
+///     None => return Ok(()),
+/// };
 ///
-/// let conn = quiche::accept(&scid, odcid.as_ref(), src, &mut config)?;
+/// let conn = quiche::accept(&scid, odcid.as_ref(), local, src, &mut config)?;
 /// # Ok::<(), quiche::Error>(())
 /// ```
 #[inline]
@@ -1377,27 +1909,191 @@ pub fn retry(
     packet::retry(scid, dcid, new_scid, token, version, out)
 }
 
+/// Mints an address validation token to be sent in a Retry packet.
+///
+/// The token is authenticated and encrypted with the key configured with
+/// [`set_address_token_key()`], and is bound to the IP address of `peer`. It
+/// carries the `odcid` parameter, the destination connection ID of the
+/// client's Initial packet, which is returned by [`validate_token()`] when
+/// the client sends the token back.
+///
+/// [`set_address_token_key()`]:
+/// struct.Config.html#method.set_address_token_key
+/// [`validate_token()`]: fn.validate_token.html
+pub fn mint_retry_token(
+    config: &Config, odcid: &ConnectionId, peer: SocketAddr,
+) -> Result<Vec<u8>> {
+    match config.address_token_key {
+        Some(ref key) => token::mint_retry_token(key, odcid, &peer),
+
+        None => Err(Error::InvalidState),
+    }
+}
+
+/// Validates an address validation token sent by the client `peer` in an
+/// Initial packet.
+///
+/// Returns `None` if the token is invalid, expired, was minted for a
+/// different address, or if no key was configured with
+/// [`set_address_token_key()`].
+///
+/// [`set_address_token_key()`]:
+/// struct.Config.html#method.set_address_token_key
+pub fn validate_token(
+    config: &Config, token: &[u8], peer: SocketAddr,
+) -> Option<AddressToken> {
+    match config.address_token_key {
+        Some(ref key) => token::validate(key, token, &peer),
+
+        None => None,
+    }
+}
+
+/// Derives the stateless reset token of the given connection ID.
+///
+/// The token is derived from the key configured with
+/// [`set_stateless_reset_key()`], and should be passed to
+/// [`new_source_cid()`] when providing additional connection IDs to the peer.
+///
+/// [`set_stateless_reset_key()`]:
+/// struct.Config.html#method.set_stateless_reset_key
+/// [`new_source_cid()`]: struct.Connection.html#method.new_source_cid
+pub fn stateless_reset_token(
+    config: &Config, cid: &ConnectionId,
+) -> Result<u128> {
+    match config.stateless_reset_key {
+        Some(ref key) => Ok(crypto::derive_stateless_reset_token(key, cid)),
+
+        None => Err(Error::InvalidState),
+    }
+}
+
+/// Writes a stateless reset packet into the provided buffer.
+///
+/// This should be used in response to a packet of `recv_len` bytes carrying
+/// the unknown destination connection ID `dcid`, e.g. after a server restart.
+/// The reset is always smaller than the packet that triggered it, to prevent
+/// loops between endpoints, so [`Done`] is returned if the received packet is
+/// too small to be answered.
+///
+/// [`Done`]: enum.Error.html#variant.Done
+///
+/// ## Examples:
+///
+/// ```no_run
+/// # let mut config = quiche::Config::new(0xbabababa)?;
+/// # let mut buf = [0; 512];
+/// # let mut out = [0; 512];
+/// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// # let key = [0; 32];
+/// config.set_stateless_reset_key(&key);
+///
+/// let (len, src) = socket.recv_from(&mut buf).unwrap();
+///
+/// let hdr = quiche::Header::from_slice(&mut buf[..len], 16)?;
+///
+/// // No connection matching the destination connection ID was found.
+/// if hdr.ty == quiche::Type::Short {
+///     let len = quiche::stateless_reset(&config, &hdr.dcid, len, &mut out)?;
+///
+///     socket.send_to(&out[..len], &src).unwrap();
+/// }
+/// # Ok::<(), quiche::Error>(())
+/// ```
+pub fn stateless_reset(
+    config: &Config, dcid: &ConnectionId, recv_len: usize, out: &mut [u8],
+) -> Result<usize> {
+    let token = stateless_reset_token(config, dcid)?;
+
+    packet::stateless_reset(token, recv_len, out)
+}
+
 /// Returns true if the given protocol version is supported.
+///
+/// Note that draft versions are only supported when the `draft-versions`
+/// feature is enabled.
 #[inline]
 pub fn version_is_supported(version: u32) -> bool {
+    SUPPORTED_VERSIONS.contains(&version)
+}
+
+/// Returns true if the given protocol version is a draft version.
+#[cfg(feature = "draft-versions")]
+fn version_is_draft(version: u32) -> bool {
     matches!(
         version,
-        PROTOCOL_VERSION_V1 |
-            PROTOCOL_VERSION_DRAFT27 |
+        PROTOCOL_VERSION_DRAFT27 |
             PROTOCOL_VERSION_DRAFT28 |
             PROTOCOL_VERSION_DRAFT29
     )
 }
 
-/// Pushes a frame to the output packet if there is enough space.
-///
-/// Returns `true` on success, `false` otherwise. In case of failure it means
-/// there is no room to add the frame in the packet. You may retry to add the
-/// frame later.
-macro_rules! push_frame_to_pkt {
-    ($out:expr, $frames:expr, $frame:expr, $left:expr) => {{
-        if $frame.wire_len() <= $left {
-            $left -= $frame.wire_len();
+#[cfg(not(feature = "draft-versions"))]
+fn version_is_draft(_version: u32) -> bool {
+    false
+}
+
+/// Returns true if the given protocol version authenticates connection IDs
+/// using transport parameters, which draft-27 doesn't.
+#[cfg(feature = "draft-versions")]
+fn version_authenticates_connection_ids(version: u32) -> bool {
+    version != PROTOCOL_VERSION_DRAFT27
+}
+
+#[cfg(not(feature = "draft-versions"))]
+fn version_authenticates_connection_ids(_version: u32) -> bool {
+    true
+}
+
+/// Returns the supported versions in order of preference, starting with the
+/// given one.
+fn preferred_versions(version: u32) -> Vec<u32> {
+    let mut versions = Vec::with_capacity(SUPPORTED_VERSIONS.len());
+
+    if version_is_supported(version) {
+        versions.push(version);
+    }
+
+    versions.extend(SUPPORTED_VERSIONS.iter().filter(|&&v| v != version));
+
+    versions
+}
+
+/// Returns true if a connection can switch between the given versions
+/// without an extra round trip, using compatible version negotiation.
+fn versions_are_compatible(a: u32, b: u32) -> bool {
+    let is_compatible =
+        |v| matches!(v, PROTOCOL_VERSION_V1 | PROTOCOL_VERSION_V2);
+
+    is_compatible(a) && is_compatible(b)
+}
+
+/// Returns the versions an endpoint using `version` advertises in its
+/// version_information transport parameter, in order of preference.
+///
+/// These are the given version, followed by the compatible ones in
+/// `supported`.
+fn available_versions(version: u32, supported: &[u32]) -> Vec<u32> {
+    let mut versions = vec![version];
+
+    versions.extend(
+        supported
+            .iter()
+            .filter(|&&v| v != version && versions_are_compatible(v, version)),
+    );
+
+    versions
+}
+
+/// Pushes a frame to the output packet if there is enough space.
+///
+/// Returns `true` on success, `false` otherwise. In case of failure it means
+/// there is no room to add the frame in the packet. You may retry to add the
+/// frame later.
+macro_rules! push_frame_to_pkt {
+    ($out:expr, $frames:expr, $frame:expr, $left:expr) => {{
+        if $frame.wire_len() <= $left {
+            $left -= $frame.wire_len();
 
             $frame.to_bytes(&mut $out)?;
 
@@ -1445,6 +2141,10 @@ macro_rules! qlog_with_type {
 const QLOG_PARAMS_SET: EventType =
     EventType::TransportEventType(TransportEventType::ParametersSet);
 
+#[cfg(feature = "qlog")]
+const QLOG_VERSION_INFO: EventType =
+    EventType::TransportEventType(TransportEventType::VersionInformation);
+
 #[cfg(feature = "qlog")]
 const QLOG_PACKET_RX: EventType =
     EventType::TransportEventType(TransportEventType::PacketReceived);
@@ -1461,11 +2161,41 @@ const QLOG_DATA_MV: EventType =
 const QLOG_METRICS: EventType =
     EventType::RecoveryEventType(RecoveryEventType::MetricsUpdated);
 
+#[cfg(feature = "qlog")]
+const QLOG_PACKET_DROPPED: EventType =
+    EventType::TransportEventType(TransportEventType::PacketDropped);
+
+#[cfg(feature = "qlog")]
+const QLOG_CONNECTION_CLOSED: EventType =
+    EventType::ConnectivityEventType(ConnectivityEventType::ConnectionClosed);
+
+#[cfg(feature = "qlog")]
+const QLOG_KEY_UPDATED: EventType =
+    EventType::SecurityEventType(SecurityEventType::KeyUpdated);
+
 #[cfg(feature = "qlog")]
 struct QlogInfo {
     streamer: Option<qlog::streamer::QlogStreamer>,
     logged_peer_params: bool,
     level: EventImportance,
+    key_generation: u32,
+}
+
+#[cfg(feature = "qlog")]
+impl QlogInfo {
+    /// Writes the given events, skipping the ones not included in the
+    /// configured level.
+    fn add_events(&mut self, events: Vec<EventData>, now: time::Instant) {
+        if let Some(q) = &mut self.streamer {
+            for ev_data in events {
+                let ty = EventType::from(&ev_data);
+
+                if EventImportance::from(ty).is_contained_in(&self.level) {
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                }
+            }
+        }
+    }
 }
 
 #[cfg(feature = "qlog")]
@@ -1475,33 +2205,72 @@ impl Default for QlogInfo {
             streamer: None,
             logged_peer_params: false,
             level: EventImportance::Base,
+            key_generation: 0,
         }
     }
 }
 
 impl Connection {
     fn new(
-        scid: &ConnectionId, odcid: Option<&ConnectionId>, peer: SocketAddr,
-        config: &mut Config, is_server: bool,
+        scid: &ConnectionId, odcid: Option<&ConnectionId>, local: SocketAddr,
+        peer: SocketAddr, config: &mut Config, is_server: bool,
     ) -> Result<Connection> {
         let tls = config.tls_ctx.new_handshake()?;
-        Connection::with_tls(scid, odcid, peer, config, tls, is_server)
+        Connection::with_tls(scid, odcid, local, peer, config, tls, is_server)
     }
 
     fn with_tls(
-        scid: &ConnectionId, odcid: Option<&ConnectionId>, peer: SocketAddr,
-        config: &mut Config, tls: tls::Handshake, is_server: bool,
+        scid: &ConnectionId, odcid: Option<&ConnectionId>, local: SocketAddr,
+        peer: SocketAddr, config: &mut Config, tls: tls::Handshake,
+        is_server: bool,
     ) -> Result<Connection> {
         let max_rx_data = config.local_transport_params.initial_max_data;
 
         let scid_as_hex: Vec<String> =
             scid.iter().map(|b| format!("{:02x}", b)).collect();
 
+        let recovery_config = recovery::RecoveryConfig::from_config(config);
+
+        let mut path = path::Path::new(
+            local,
+            peer,
+            &recovery_config,
+            true,
+            time::Instant::now(),
+        );
+
+        // If we did stateless retry assume the peer's address is verified.
+        // Clients are never limited by the anti-amplification limit on the
+        // initial path.
+        path.verified_peer_address = odcid.is_some() || !is_server;
+
+        let active_conn_id_limit =
+            config.local_transport_params.active_conn_id_limit as usize;
+
+        let paths = path::PathMap::new(path, active_conn_id_limit);
+
+        // Only servers can provide a stateless reset token for the initial
+        // connection ID.
+        let reset_token = match config.stateless_reset_key {
+            Some(ref key) if is_server =>
+                Some(crypto::derive_stateless_reset_token(key, scid)),
+
+            _ => None,
+        };
+
         let mut conn = Connection {
             version: config.version,
 
-            dcid: ConnectionId::default(),
-            scid: scid.to_vec().into(),
+            original_version: config.version,
+
+            versions: config.versions.clone(),
+
+            ids: cid::ConnectionIdentifiers::new(
+                active_conn_id_limit,
+                scid,
+                0,
+                reset_token,
+            ),
 
             trace_id: scid_as_hex.join(""),
 
@@ -1519,9 +2288,9 @@ impl Connection {
 
             session: None,
 
-            recovery: recovery::Recovery::new(config),
+            paths,
 
-            peer_addr: peer,
+            recovery_config,
 
             application_protos: config.application_protos.clone(),
 
@@ -1545,14 +2314,16 @@ impl Connection {
             max_tx_data: 0,
             last_tx_data: 0,
 
-            stream_retrans_bytes: 0,
+            evicted_lost_count: 0,
+            evicted_lost_bytes: 0,
 
-            max_send_bytes: 0,
+            stream_retrans_bytes: 0,
 
             streams: stream::StreamMap::new(
                 config.local_transport_params.initial_max_streams_bidi,
                 config.local_transport_params.initial_max_streams_uni,
                 config.max_stream_window,
+                config.stream_scheduler,
             ),
 
             odcid: None,
@@ -1561,12 +2332,20 @@ impl Connection {
 
             token: None,
 
+            address_token_key: config.address_token_key.clone(),
+
+            address_token_lifetime: time::Duration::from_millis(
+                config.address_token_lifetime,
+            ),
+
+            new_token_sent: false,
+
+            new_tokens: VecDeque::new(),
+
             local_error: None,
 
             peer_error: None,
 
-            challenge: None,
-
             blocked_limit: None,
 
             idle_timer: None,
@@ -1587,9 +2366,6 @@ impl Connection {
 
             got_peer_conn_id: false,
 
-            // If we did stateless retry assume the peer's address is verified.
-            verified_peer_address: odcid.is_some(),
-
             // Assume clients validate the server's address implicitly.
             peer_verified_address: is_server,
 
@@ -1602,12 +2378,20 @@ impl Connection {
 
             handshake_confirmed: false,
 
+            key_phase: false,
+
+            max_packets_per_key: config.max_packets_per_key,
+
             ack_eliciting_sent: false,
 
             closed: false,
 
             timed_out: false,
 
+            stateless_reset: false,
+
+            preferred_address_used: false,
+
             grease: config.grease,
 
             keylog: None,
@@ -1624,6 +2408,12 @@ impl Connection {
             ),
 
             emit_dgram: true,
+
+            ack_freq: ack_freq::AckFrequency::new(time::Duration::from_millis(
+                config.local_transport_params.max_ack_delay,
+            )),
+
+            ack_freq_auto: config.ack_frequency,
         };
 
         if let Some(odcid) = odcid {
@@ -1639,10 +2429,44 @@ impl Connection {
         conn.local_transport_params.initial_source_connection_id =
             Some(scid.to_vec().into());
 
+        conn.local_transport_params.stateless_reset_token =
+            reset_token.map(|v| v.to_be_bytes().to_vec());
+
+        // The connection ID provided with the preferred address has sequence
+        // number 1, and is only advertised in the transport parameter.
+        if is_server {
+            if let Some(ref pa) = conn.local_transport_params.preferred_address {
+                conn.ids.new_scid(
+                    pa.cid.clone(),
+                    Some(pa.reset_token),
+                    false,
+                    None,
+                    false,
+                )?;
+            }
+        } else {
+            conn.local_transport_params.preferred_address = None;
+        }
+
         conn.handshake.init(is_server)?;
 
+        // Servers supporting multiple compatible versions upgrade clients to
+        // the most preferred one, once their transport parameters are
+        // received.
+        let compatible_versions = config
+            .versions
+            .iter()
+            .filter(|&&v| versions_are_compatible(v, v))
+            .count();
+
+        if is_server && compatible_versions > 1 {
+            conn.handshake.enable_compatible_version_negotiation();
+        }
+
+        conn.set_version_information();
+
         conn.handshake
-            .use_legacy_codepoint(config.version != PROTOCOL_VERSION_V1);
+            .use_legacy_codepoint(version_is_draft(config.version));
 
         conn.encode_transport_params()?;
 
@@ -1658,7 +2482,8 @@ impl Connection {
                 conn.is_server,
             )?;
 
-            conn.dcid = dcid.to_vec().into();
+            conn.ids
+                .set_initial_dcid(dcid.to_vec().into(), None, Some(0));
 
             conn.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_open =
                 Some(aead_open);
@@ -1668,8 +2493,6 @@ impl Connection {
             conn.derived_initial_secrets = true;
         }
 
-        conn.recovery.on_init();
-
         Ok(conn)
     }
 
@@ -1800,6 +2623,27 @@ impl Connection {
         Ok(())
     }
 
+    /// Configures the address validation token sent in Initial packets.
+    ///
+    /// On the client, this can be used to send a token received from the
+    /// server during a previous connection, as returned by
+    /// [`new_token_next()`], so that the server can validate the client's
+    /// address without a Retry round trip.
+    ///
+    /// This must only be called immediately after creating a connection, that
+    /// is, before any packet is sent or received.
+    ///
+    /// [`new_token_next()`]: struct.Connection.html#method.new_token_next
+    pub fn set_token(&mut self, token: &[u8]) -> Result<()> {
+        if self.is_server {
+            return Err(Error::InvalidState);
+        }
+
+        self.token = Some(token.to_vec());
+
+        Ok(())
+    }
+
     /// Processes QUIC packets received from the peer.
     ///
     /// On success the number of bytes processed from the input buffer is
@@ -1820,12 +2664,17 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// loop {
     ///     let (read, from) = socket.recv_from(&mut buf).unwrap();
     ///
-    ///     let recv_info = quiche::RecvInfo { from };
+    ///     let recv_info = quiche::RecvInfo {
+    ///         from,
+    ///         to: socket.local_addr().unwrap(),
+    ///         ecn: quiche::EcnCodepoint::NotEct,
+    ///     };
     ///
     ///     let read = match conn.recv(&mut buf[..read], recv_info) {
     ///         Ok(v) => v,
@@ -1839,6 +2688,23 @@ impl Connection {
     /// # Ok::<(), quiche::Error>(())
     /// ```
     pub fn recv(&mut self, buf: &mut [u8], info: RecvInfo) -> Result<usize> {
+        self.recv_at(buf, info, time::Instant::now())
+    }
+
+    /// Processes QUIC packets received from the peer at the given time.
+    ///
+    /// This behaves like [`recv()`], except that the current time is provided
+    /// by the application instead of being read from the system clock. This
+    /// makes it possible to drive the connection on virtual time, for example
+    /// in simulations.
+    ///
+    /// The time passed to this and the other `_at()` methods must never go
+    /// backwards.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    pub fn recv_at(
+        &mut self, buf: &mut [u8], info: RecvInfo, now: time::Instant,
+    ) -> Result<usize> {
         let len = buf.len();
 
         if len == 0 {
@@ -1852,32 +2718,71 @@ impl Connection {
         //
         // It doesn't matter if the packets received were valid or not, we only
         // need to track the total amount of bytes received.
-        if !self.verified_peer_address {
-            self.max_send_bytes += len * MAX_AMPLIFICATION_FACTOR;
+        //
+        // Note that packets received from a new address get their credit when
+        // the corresponding path is created.
+        if let Some(pid) = self.paths.path_id_from_addrs(&(info.to, info.from)) {
+            let path = self.paths.get_mut(pid)?;
+
+            if !path.verified_peer_address {
+                path.max_send_bytes += len * MAX_AMPLIFICATION_FACTOR;
+            }
         }
 
+        // The last bytes of the datagram, in case it turns out to be a
+        // stateless reset.
+        let reset_token = packet::stateless_reset_token(buf);
+
         let mut done = 0;
         let mut left = len;
 
         // Process coalesced packets.
         while left > 0 {
-            let read = match self.recv_single(&mut buf[len - left..len], &info) {
-                Ok(v) => v,
+            let read =
+                match self.recv_single(&mut buf[len - left..len], &info, now) {
+                    Ok(v) => v,
 
-                Err(Error::Done) => left,
+                    Err(Error::Done) => {
+                        // A packet that couldn't be processed might be a
+                        // stateless reset, in which case
+                        // the connection is drained without
+                        // sending anything else to the peer.
+                        if let Some(token) = reset_token {
+                            if !self.is_closed() &&
+                                !self.is_draining() &&
+                                self.ids.is_stateless_reset_token(token)
+                            {
+                                trace!(
+                                    "{} stateless reset received",
+                                    self.trace_id
+                                );
+
+                                self.stateless_reset = true;
+
+                                let pto = self.paths.get_active().recovery.pto();
+                                self.draining_timer = Some(now + (pto * 3));
+
+                                return Ok(len);
+                            }
+                        }
+
+                        left
+                    },
 
-                Err(e) => {
-                    // In case of error processing the incoming packet, close
-                    // the connection.
-                    self.close(false, e.to_wire(), b"").ok();
-                    return Err(e);
-                },
-            };
+                    Err(e) => {
+                        // In case of error processing the incoming packet, close
+                        // the connection.
+                        self.close(false, e.to_wire(), b"").ok();
+                        return Err(e);
+                    },
+                };
 
             done += read;
             left -= read;
         }
 
+        self.migrate_to_preferred_address(now);
+
         // Process previously undecryptable 0-RTT packets if the decryption key
         // is now available.
         if self.pkt_num_spaces[packet::EPOCH_APPLICATION]
@@ -1886,7 +2791,7 @@ impl Connection {
         {
             while let Some((mut pkt, info)) = self.undecryptable_pkts.pop_front()
             {
-                if let Err(e) = self.recv(&mut pkt, info) {
+                if let Err(e) = self.recv_at(&mut pkt, info, now) {
                     self.undecryptable_pkts.clear();
 
                     // Even though the packet was previously "accepted", it
@@ -1909,8 +2814,10 @@ impl Connection {
     /// On error, an error other than [`Done`] is returned.
     ///
     /// [`Done`]: enum.Error.html#variant.Done
-    fn recv_single(&mut self, buf: &mut [u8], info: &RecvInfo) -> Result<usize> {
-        let now = time::Instant::now();
+    fn recv_single(
+        &mut self, buf: &mut [u8], info: &RecvInfo, now: time::Instant,
+    ) -> Result<usize> {
+        let buf_len = buf.len();
 
         if buf.is_empty() {
             return Err(Error::Done);
@@ -1928,15 +2835,26 @@ impl Connection {
 
         let mut b = octets::OctetsMut::with_slice(buf);
 
-        let mut hdr =
-            Header::from_bytes(&mut b, self.scid.len()).map_err(|e| {
-                drop_pkt_on_err(
+        let mut hdr = match Header::from_bytes(&mut b, self.source_id().len()) {
+            Ok(v) => v,
+
+            Err(e) => {
+                #[cfg(feature = "qlog")]
+                self.qlog_packet_dropped(
+                    None,
+                    buf_len,
+                    PacketDroppedTrigger::HeaderParserError,
+                    now,
+                );
+
+                return Err(drop_pkt_on_err(
                     e,
                     self.recv_count,
                     self.is_server,
                     &self.trace_id,
-                )
-            })?;
+                ));
+            },
+        };
 
         if hdr.ty == packet::Type::VersionNegotiation {
             // Version negotiation packets can only be sent by the server.
@@ -1955,11 +2873,11 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            if hdr.dcid != self.scid {
+            if hdr.dcid != self.source_id() {
                 return Err(Error::Done);
             }
 
-            if hdr.scid != self.dcid {
+            if hdr.scid != self.destination_id() {
                 return Err(Error::Done);
             }
 
@@ -1973,24 +2891,13 @@ impl Connection {
                 return Err(Error::Done);
             }
 
-            let supported_versions =
-                versions.iter().filter(|&&v| version_is_supported(v));
+            // Select the most preferred version offered by the server.
+            let version =
+                self.versions.iter().find(|v| versions.contains(v)).cloned();
 
-            let mut found_version = false;
-
-            for &v in supported_versions {
-                found_version = true;
-
-                // The final version takes precedence over draft ones.
-                if v == PROTOCOL_VERSION_V1 {
-                    self.version = v;
-                    break;
-                }
-
-                self.version = cmp::max(self.version, v);
-            }
-
-            if !found_version {
+            if let Some(version) = version {
+                self.version = version;
+            } else {
                 // We don't support any of the versions offered.
                 //
                 // While a man-in-the-middle attacker might be able to
@@ -2003,9 +2910,13 @@ impl Connection {
 
             self.did_version_negotiation = true;
 
+            // The incompatible version negotiation replaces the version the
+            // client originally attempted.
+            self.original_version = self.version;
+
             // Derive Initial secrets based on the new version.
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
-                &self.dcid,
+                &self.destination_id(),
                 self.version,
                 self.is_server,
             )?;
@@ -2021,10 +2932,11 @@ impl Connection {
                 Some(aead_seal);
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(version_is_draft(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
+            self.set_version_information();
             self.encode_transport_params()?;
 
             return Err(Error::Done);
@@ -2042,8 +2954,12 @@ impl Connection {
             }
 
             // Check if Retry packet is valid.
-            if packet::verify_retry_integrity(&b, &self.dcid, self.version)
-                .is_err()
+            if packet::verify_retry_integrity(
+                &b,
+                &self.destination_id(),
+                self.version,
+            )
+            .is_err()
             {
                 return Err(Error::Done);
             }
@@ -2054,11 +2970,11 @@ impl Connection {
             self.did_retry = true;
 
             // Remember peer's new connection ID.
-            self.odcid = Some(self.dcid.clone());
+            self.odcid = Some(self.destination_id().into_owned());
 
-            self.dcid = hdr.scid.clone();
+            self.ids.set_initial_dcid(hdr.scid.clone(), None, Some(0));
 
-            self.rscid = Some(self.dcid.clone());
+            self.rscid = Some(self.destination_id().into_owned());
 
             // Derive Initial secrets using the new connection ID.
             let (aead_open, aead_seal) = crypto::derive_initial_key_material(
@@ -2081,24 +2997,70 @@ impl Connection {
         }
 
         if self.is_server && !self.did_version_negotiation {
-            if !version_is_supported(hdr.version) {
+            if !self.versions.contains(&hdr.version) {
                 return Err(Error::UnknownVersion);
             }
 
             self.version = hdr.version;
+            self.original_version = hdr.version;
             self.did_version_negotiation = true;
 
             self.handshake
-                .use_legacy_codepoint(self.version != PROTOCOL_VERSION_V1);
+                .use_legacy_codepoint(version_is_draft(self.version));
 
             // Encode transport parameters again, as the new version might be
             // using a different format.
+            self.set_version_information();
             self.encode_transport_params()?;
         }
 
-        if hdr.ty != packet::Type::Short && hdr.version != self.version {
+        // The server might upgrade the connection to a compatible version, in
+        // which case its first Initial packet uses the new version.
+        if !self.is_server &&
+            !self.got_peer_conn_id &&
+            hdr.ty == packet::Type::Initial &&
+            hdr.version != self.version &&
+            versions_are_compatible(hdr.version, self.version)
+        {
+            trace!(
+                "{} server upgraded version {:x} to {:x}",
+                self.trace_id,
+                self.version,
+                hdr.version
+            );
+
+            self.version = hdr.version;
+
+            // Derive Initial secrets based on the new version.
+            let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &self.destination_id(),
+                self.version,
+                self.is_server,
+            )?;
+
+            self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_open =
+                Some(aead_open);
+            self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_seal =
+                Some(aead_seal);
+        }
+
+        // 0-RTT packets keep using the original version, even when the
+        // connection was upgraded to a compatible version.
+        let version_matches = hdr.version == self.version ||
+            (hdr.ty == packet::Type::ZeroRTT &&
+                hdr.version == self.original_version);
+
+        if hdr.ty != packet::Type::Short && !version_matches {
             // At this point version negotiation was already performed, so
             // ignore packets that don't match the connection's version.
+            #[cfg(feature = "qlog")]
+            self.qlog_packet_dropped(
+                None,
+                buf_len,
+                PacketDroppedTrigger::UnexpectedVersion,
+                now,
+            );
+
             return Err(Error::Done);
         }
 
@@ -2107,19 +3069,39 @@ impl Connection {
         let payload_len = if hdr.ty == packet::Type::Short {
             b.cap()
         } else {
-            b.get_varint().map_err(|e| {
-                drop_pkt_on_err(
-                    e.into(),
-                    self.recv_count,
-                    self.is_server,
-                    &self.trace_id,
-                )
-            })? as usize
+            match b.get_varint() {
+                Ok(v) => v as usize,
+
+                Err(e) => {
+                    #[cfg(feature = "qlog")]
+                    self.qlog_packet_dropped(
+                        None,
+                        buf_len,
+                        PacketDroppedTrigger::HeaderParserError,
+                        now,
+                    );
+
+                    return Err(drop_pkt_on_err(
+                        e.into(),
+                        self.recv_count,
+                        self.is_server,
+                        &self.trace_id,
+                    ));
+                },
+            }
         };
 
         // Make sure the buffer is same or larger than an explicit
         // payload length.
         if payload_len > b.cap() {
+            #[cfg(feature = "qlog")]
+            self.qlog_packet_dropped(
+                None,
+                buf_len,
+                PacketDroppedTrigger::HeaderParserError,
+                now,
+            );
+
             return Err(drop_pkt_on_err(
                 Error::InvalidPacket,
                 self.recv_count,
@@ -2147,6 +3129,34 @@ impl Connection {
         // Select packet number space epoch based on the received packet's type.
         let epoch = hdr.ty.to_epoch()?;
 
+        // Short header packets must use one of the source connection IDs we
+        // provided to the peer. Long header packets only use the initial one.
+        let recv_scid_seq = if hdr.ty == packet::Type::Short {
+            match self.ids.find_scid_seq(&hdr.dcid) {
+                Some((seq, _)) => Some(seq),
+
+                None => {
+                    trace!(
+                        "{} dropped packet with unknown dcid {:?}",
+                        self.trace_id,
+                        hdr.dcid
+                    );
+
+                    #[cfg(feature = "qlog")]
+                    self.qlog_packet_dropped(
+                        None,
+                        buf_len,
+                        PacketDroppedTrigger::UnknownConnectionId,
+                        now,
+                    );
+
+                    return Err(Error::Done);
+                },
+            }
+        } else {
+            None
+        };
+
         // Select AEAD context used to open incoming packet.
         let aead = if hdr.ty == packet::Type::ZeroRTT {
             // Only use 0-RTT key if incoming packet is 0-RTT.
@@ -2177,6 +3187,14 @@ impl Connection {
                     return Ok(pkt_len);
                 }
 
+                #[cfg(feature = "qlog")]
+                self.qlog_packet_dropped(
+                    None,
+                    buf_len,
+                    PacketDroppedTrigger::KeysUnavailable,
+                    now,
+                );
+
                 let e = drop_pkt_on_err(
                     Error::CryptoFail,
                     self.recv_count,
@@ -2190,9 +3208,22 @@ impl Connection {
 
         let aead_tag_len = aead.alg().tag_len();
 
-        packet::decrypt_hdr(&mut b, &mut hdr, aead).map_err(|e| {
-            drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
-        })?;
+        if let Err(e) = packet::decrypt_hdr(&mut b, &mut hdr, aead) {
+            #[cfg(feature = "qlog")]
+            self.qlog_packet_dropped(
+                None,
+                buf_len,
+                PacketDroppedTrigger::PayloadDecryptError,
+                now,
+            );
+
+            return Err(drop_pkt_on_err(
+                e,
+                self.recv_count,
+                self.is_server,
+                &self.trace_id,
+            ));
+        }
 
         let pn = packet::decode_pkt_num(
             self.pkt_num_spaces[epoch].largest_rx_pkt_num,
@@ -2210,22 +3241,91 @@ impl Connection {
             pn
         );
 
+        // Next generation keys derived for a peer-initiated key update. They
+        // are only installed once the packet has been authenticated.
+        let mut aead_next = None;
+
+        let mut aead = aead;
+
+        if hdr.ty == packet::Type::Short && hdr.key_phase != self.key_phase {
+            match self.pkt_num_spaces[epoch].key_update {
+                // Delayed packet protected with the previous keys.
+                Some(ref key_update) if pn < key_update.pn_on_update =>
+                    aead = &key_update.crypto_open,
+
+                _ => {
+                    let seal = self.pkt_num_spaces[epoch]
+                        .crypto_seal
+                        .as_ref()
+                        .ok_or(Error::InvalidState)?;
+
+                    let next = aead
+                        .derive_next_packet_key()
+                        .and_then(|open| {
+                            Ok((open, seal.derive_next_packet_key()?))
+                        })
+                        .map_err(|e| {
+                            drop_pkt_on_err(
+                                e,
+                                self.recv_count,
+                                self.is_server,
+                                &self.trace_id,
+                            )
+                        })?;
+
+                    aead = &aead_next.insert(next).0;
+                },
+            }
+        }
+
         #[cfg(feature = "qlog")]
         let mut qlog_frames = vec![];
 
-        let mut payload = packet::decrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            aead,
-        )
-        .map_err(|e| {
-            drop_pkt_on_err(e, self.recv_count, self.is_server, &self.trace_id)
-        })?;
+        let mut payload =
+            match packet::decrypt_pkt(&mut b, pn, pn_len, payload_len, aead) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    #[cfg(feature = "qlog")]
+                    self.qlog_packet_dropped(
+                        Some(qlog::events::quic::PacketHeader::with_type(
+                            hdr.ty.to_qlog(),
+                            pn,
+                            Some(hdr.version),
+                            Some(&hdr.scid),
+                            Some(&hdr.dcid),
+                        )),
+                        buf_len,
+                        PacketDroppedTrigger::PayloadDecryptError,
+                        now,
+                    );
+
+                    return Err(drop_pkt_on_err(
+                        e,
+                        self.recv_count,
+                        self.is_server,
+                        &self.trace_id,
+                    ));
+                },
+            };
 
         if self.pkt_num_spaces[epoch].recv_pkt_num.contains(pn) {
             trace!("{} ignored duplicate packet {}", self.trace_id, pn);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_packet_dropped(
+                Some(qlog::events::quic::PacketHeader::with_type(
+                    hdr.ty.to_qlog(),
+                    pn,
+                    Some(hdr.version),
+                    Some(&hdr.scid),
+                    Some(&hdr.dcid),
+                )),
+                buf_len,
+                PacketDroppedTrigger::Duplicate,
+                now,
+            );
+
             return Err(Error::Done);
         }
 
@@ -2234,24 +3334,64 @@ impl Connection {
             return Err(Error::InvalidPacket);
         }
 
+        // Keep track of ECN marks, to report them back to the peer.
+        if info.ecn != EcnCodepoint::NotEct {
+            let counts = self.pkt_num_spaces[epoch]
+                .ecn_counts
+                .get_or_insert_with(Default::default);
+
+            match info.ecn {
+                EcnCodepoint::Ect0 => counts.ect0_count += 1,
+
+                EcnCodepoint::Ect1 => counts.ect1_count += 1,
+
+                EcnCodepoint::Ce => counts.ecn_ce_count += 1,
+
+                EcnCodepoint::NotEct => (),
+            }
+        }
+
+        if let Some((open_next, seal_next)) = aead_next {
+            trace!("{} peer-initiated key update", self.trace_id);
+
+            self.update_keys(open_next, seal_next, now);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_key_updated(KeyUpdateOrRetiredTrigger::RemoteUpdate, now);
+        }
+
+        if hdr.ty == packet::Type::Short && hdr.key_phase == self.key_phase {
+            if let Some(key_update) =
+                self.pkt_num_spaces[epoch].key_update.as_mut()
+            {
+                key_update.pn_on_update = cmp::min(key_update.pn_on_update, pn);
+            }
+        }
+
+        // Now that the packet has been authenticated, find the path it was
+        // received on, creating a new one if needed.
+        let recv_pid =
+            self.get_or_create_recv_path_id(recv_scid_seq, buf_len, info, now)?;
+
         if !self.is_server && !self.got_peer_conn_id {
             if self.odcid.is_none() {
-                self.odcid = Some(self.dcid.clone());
+                self.odcid = Some(self.destination_id().into_owned());
             }
 
             // Replace the randomly generated destination connection ID with
             // the one supplied by the server.
-            self.dcid = hdr.scid.clone();
+            self.ids
+                .set_initial_dcid(hdr.scid.clone(), None, Some(recv_pid));
 
             self.got_peer_conn_id = true;
         }
 
         if self.is_server && !self.got_peer_conn_id {
-            self.dcid = hdr.scid.clone();
+            self.ids
+                .set_initial_dcid(hdr.scid.clone(), None, Some(recv_pid));
 
             if !self.did_retry &&
-                (self.version >= PROTOCOL_VERSION_DRAFT28 ||
-                    self.version == PROTOCOL_VERSION_V1)
+                version_authenticates_connection_ids(self.version)
             {
                 self.local_transport_params
                     .original_destination_connection_id =
@@ -2260,6 +3400,20 @@ impl Connection {
                 self.encode_transport_params()?;
             }
 
+            // A token received in a previous connection validates the
+            // client's address.
+            if let (Some(key), Some(token)) =
+                (&self.address_token_key, &hdr.token)
+            {
+                let path = self.paths.get_mut(recv_pid)?;
+
+                if token::validate(key, token, &path.peer_addr()) ==
+                    Some(AddressToken::NewToken)
+                {
+                    path.verified_peer_address = true;
+                }
+            }
+
             self.got_peer_conn_id = true;
         }
 
@@ -2268,6 +3422,10 @@ impl Connection {
         // ACK and PADDING.
         let mut ack_elicited = false;
 
+        // Track whether this packet only contains probing frames, so that we
+        // can detect when the peer migrates to a new path.
+        let mut probing = true;
+
         // Process packet payload. If a frame cannot be processed, store the
         // error and stop further packet processing.
         let mut frame_processing_err = None;
@@ -2283,7 +3441,12 @@ impl Connection {
                 ack_elicited = true;
             }
 
-            if let Err(e) = self.process_frame(frame, epoch, now) {
+            if !frame.probing() {
+                probing = false;
+            }
+
+            if let Err(e) = self.process_frame(frame, &hdr, recv_pid, epoch, now)
+            {
                 frame_processing_err = Some(e);
                 break;
             }
@@ -2323,11 +3486,16 @@ impl Connection {
         });
 
         qlog_with_type!(QLOG_PACKET_RX, self.qlog, q, {
-            if let Some(ev_data) = self.recovery.maybe_qlog() {
+            let recovery = &mut self.paths.get_active_mut().recovery;
+
+            if let Some(ev_data) = recovery.maybe_qlog() {
                 q.add_event_data_with_instant(ev_data, now).ok();
             }
         });
 
+        #[cfg(feature = "qlog")]
+        self.qlog_recovery_events(now);
+
         if let Some(e) = frame_processing_err {
             // Any frame error is terminal, so now just return.
             return Err(e);
@@ -2337,21 +3505,59 @@ impl Connection {
         // established (i.e. after frames have been fully parsed) and only
         // once per connection.
         if self.is_established() {
-            qlog_with_type!(QLOG_PARAMS_SET, self.qlog, q, {
+            qlog_with_type!(QLOG_VERSION_INFO, self.qlog, q, {
                 if !self.qlog.logged_peer_params {
-                    let ev_data = self
-                        .peer_transport_params
-                        .to_qlog(TransportOwner::Remote, self.handshake.cipher());
+                    let to_qlog = |vi: &Option<VersionInformation>| {
+                        vi.as_ref().map(|vi| {
+                            vi.available_versions
+                                .iter()
+                                .map(|v| format!("{:x}", v))
+                                .collect()
+                        })
+                    };
 
-                    q.add_event_data_with_instant(ev_data, now).ok();
+                    let local =
+                        to_qlog(&self.local_transport_params.version_information);
+                    let peer =
+                        to_qlog(&self.peer_transport_params.version_information);
 
-                    self.qlog.logged_peer_params = true;
-                }
+                    let (server_versions, client_versions) = if self.is_server {
+                        (local, peer)
+                    } else {
+                        (peer, local)
+                    };
+
+                    let ev_data = EventData::VersionInformation(
+                        qlog::events::quic::VersionInformation {
+                            server_versions,
+                            client_versions,
+                            chosen_version: Some(format!("{:x}", self.version)),
+                        },
+                    );
+
+                    q.add_event_data_with_instant(ev_data, now).ok();
+                }
+            });
+
+            qlog_with_type!(QLOG_PARAMS_SET, self.qlog, q, {
+                if !self.qlog.logged_peer_params {
+                    let ev_data = self
+                        .peer_transport_params
+                        .to_qlog(TransportOwner::Remote, self.handshake.cipher());
+
+                    q.add_event_data_with_instant(ev_data, now).ok();
+
+                    self.qlog.logged_peer_params = true;
+                }
             });
         }
 
-        // Process acked frames.
-        for acked in self.recovery.acked[epoch].drain(..) {
+        // Process acked frames. Note that packets can be acked on any path.
+        for acked in self
+            .paths
+            .iter_mut()
+            .flat_map(|(_, p)| p.recovery.acked[epoch].drain(..))
+        {
             match acked {
                 frame::Frame::ACK { ranges, .. } => {
                     // Stop acknowledging packets less than or equal to the
@@ -2432,12 +3638,58 @@ impl Connection {
 
         self.pkt_num_spaces[epoch].recv_pkt_need_ack.push_item(pn);
 
-        self.pkt_num_spaces[epoch].ack_elicited =
-            cmp::max(self.pkt_num_spaces[epoch].ack_elicited, ack_elicited);
+        if ack_elicited {
+            let largest_rx_pkt_num =
+                self.pkt_num_spaces[epoch].largest_rx_pkt_num;
+
+            // The number of packets missing below the received one, when it
+            // fills or opens a gap.
+            let reordering = if pn < largest_rx_pkt_num {
+                largest_rx_pkt_num - pn
+            } else {
+                (pn - largest_rx_pkt_num).saturating_sub(1)
+            };
+
+            let space = &mut self.pkt_num_spaces[epoch];
+
+            space.ack_eliciting_unacked += 1;
+
+            // The ACK frequency only applies to the application data space.
+            if epoch != packet::EPOCH_APPLICATION ||
+                self.ack_freq.should_ack_immediately(
+                    space.ack_eliciting_unacked,
+                    reordering,
+                )
+            {
+                space.ack_elicited = true;
+            } else if space.ack_timer.is_none() {
+                space.ack_timer =
+                    Some(now + self.ack_freq.policy().max_ack_delay);
+            }
+        }
 
         self.pkt_num_spaces[epoch].largest_rx_pkt_num =
             cmp::max(self.pkt_num_spaces[epoch].largest_rx_pkt_num, pn);
 
+        if !probing {
+            self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num = cmp::max(
+                self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num,
+                pn,
+            );
+
+            // Only the client can migrate, by sending a non-probing packet
+            // on a new path. Packets that were reordered don't cause a
+            // migration.
+            let active_pid = self.paths.get_active_path_id()?;
+
+            if self.is_server &&
+                recv_pid != active_pid &&
+                self.pkt_num_spaces[epoch].largest_rx_non_probing_pkt_num == pn
+            {
+                self.on_peer_migrated(recv_pid)?;
+            }
+        }
+
         if let Some(idle_timeout) = self.idle_timeout() {
             self.idle_timer = Some(now + idle_timeout);
         }
@@ -2446,6 +3698,7 @@ impl Connection {
         self.update_tx_cap();
 
         self.recv_count += 1;
+        self.paths.get_mut(recv_pid)?.recv_count += 1;
 
         let read = b.off() + aead_tag_len;
 
@@ -2457,7 +3710,7 @@ impl Connection {
         if self.is_server && hdr.ty == packet::Type::Handshake {
             self.drop_epoch_state(packet::EPOCH_INITIAL, now);
 
-            self.verified_peer_address = true;
+            self.paths.get_mut(recv_pid)?.verified_peer_address = true;
         }
 
         self.ack_eliciting_sent = false;
@@ -2500,8 +3753,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// loop {
     ///     let (write, send_info) = match conn.send(&mut out) {
     ///         Ok(v) => v,
@@ -2522,6 +3776,86 @@ impl Connection {
     /// # Ok::<(), quiche::Error>(())
     /// ```
     pub fn send(&mut self, out: &mut [u8]) -> Result<(usize, SendInfo)> {
+        self.send_at(out, time::Instant::now())
+    }
+
+    /// Writes a single QUIC packet to be sent to the peer at the given time.
+    ///
+    /// This behaves like [`send()`], except that the current time is provided
+    /// by the application, like for [`recv_at()`].
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn send_at(
+        &mut self, out: &mut [u8], now: time::Instant,
+    ) -> Result<(usize, SendInfo)> {
+        self.send_on_path_at(out, None, None, now)
+    }
+
+    /// Writes a single QUIC packet to be sent to the peer from the local
+    /// address `from` to the peer address `to`.
+    ///
+    /// This behaves like [`send()`], except that only packets for the paths
+    /// matching the given addresses are generated. When `None`, an address
+    /// matches any path. This allows applications using several sockets to
+    /// generate packets for each of them.
+    ///
+    /// Returns [`InvalidState`] if no path matches the given addresses.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut out = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = socket.local_addr().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// for peer in conn.paths_iter(local) {
+    ///     loop {
+    ///         let (write, send_info) =
+    ///             match conn.send_on_path(&mut out, Some(local), Some(peer)) {
+    ///                 Ok(v) => v,
+    ///
+    ///                 Err(quiche::Error::Done) => {
+    ///                     // Done writing for this path.
+    ///                     break;
+    ///                 },
+    ///
+    ///                 Err(e) => {
+    ///                     // An error occurred, handle it.
+    ///                     break;
+    ///                 },
+    ///             };
+    ///
+    ///         socket.send_to(&out[..write], &send_info.to).unwrap();
+    ///     }
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn send_on_path(
+        &mut self, out: &mut [u8], from: Option<SocketAddr>,
+        to: Option<SocketAddr>,
+    ) -> Result<(usize, SendInfo)> {
+        self.send_on_path_at(out, from, to, time::Instant::now())
+    }
+
+    /// Writes a single QUIC packet to be sent to the peer from the local
+    /// address `from` to the peer address `to`, at the given time.
+    ///
+    /// This behaves like [`send_on_path()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`send_on_path()`]: struct.Connection.html#method.send_on_path
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn send_on_path_at(
+        &mut self, out: &mut [u8], from: Option<SocketAddr>,
+        to: Option<SocketAddr>, now: time::Instant,
+    ) -> Result<(usize, SendInfo)> {
         if out.is_empty() {
             return Err(Error::BufferTooShort);
         }
@@ -2542,7 +3876,7 @@ impl Connection {
         {
             while let Some((mut pkt, info)) = self.undecryptable_pkts.pop_front()
             {
-                if self.recv(&mut pkt, info).is_err() {
+                if self.recv_at(&mut pkt, info, now).is_err() {
                     self.undecryptable_pkts.clear();
 
                     // Forwarding the error value here could confuse
@@ -2570,17 +3904,54 @@ impl Connection {
         // maximum UDP payload size limit.
         let mut left = cmp::min(out.len(), self.max_send_udp_payload_size());
 
-        // Limit data sent by the server based on the amount of data received
-        // from the client before its address is validated.
-        if !self.verified_peer_address && self.is_server {
-            left = cmp::min(left, self.max_send_bytes);
+        let send_pid = match self.get_send_path_id(from, to, left) {
+            Ok(v) => v,
+
+            Err(Error::Done) => {
+                self.last_tx_data = self.tx_data;
+
+                return Err(Error::Done);
+            },
+
+            Err(e) => return Err(e),
+        };
+
+        // Limit data sent on the path based on the amount of data received
+        // from the peer on it, until its address is validated.
+        let send_path = self.paths.get(send_pid)?;
+
+        if !send_path.verified_peer_address {
+            left = cmp::min(left, send_path.max_send_bytes);
+        }
+
+        // All packets coalesced in the datagram share the same ECN codepoint.
+        let ecn = send_path.recovery.ecn_codepoint();
+
+        // Send a PMTU probe in its own datagram when one is due. The probe is
+        // larger than the current maximum datagram size.
+        let mut pmtud_probe = false;
+
+        if self.handshake_confirmed && send_path.active() {
+            let recovery = &mut self.paths.get_mut(send_pid)?.recovery;
+
+            if let Some(size) = recovery.pmtud_probe_size(now) {
+                if size <= out.len() {
+                    left = size;
+                    pmtud_probe = true;
+                }
+            }
         }
 
         // Generate coalesced packets.
         while left > 0 {
-            let (ty, written) = match self
-                .send_single(&mut out[done..done + left], has_initial)
-            {
+            let (ty, written) = match self.send_single(
+                &mut out[done..done + left],
+                send_pid,
+                has_initial,
+                ecn,
+                pmtud_probe,
+                now,
+            ) {
                 Ok(v) => v,
 
                 Err(Error::BufferTooShort) | Err(Error::Done) => break,
@@ -2603,7 +3974,7 @@ impl Connection {
             // When sending multiple PTO probes, don't coalesce them together,
             // so they are sent on separate UDP datagrams.
             if let Ok(epoch) = ty.to_epoch() {
-                if self.recovery.loss_probes[epoch] > 0 {
+                if self.paths.get(send_pid)?.recovery.loss_probes[epoch] > 0 {
                     break;
                 }
             }
@@ -2626,20 +3997,24 @@ impl Connection {
             done += pad_len;
         }
 
+        let send_path = self.paths.get(send_pid)?;
+
         let info = SendInfo {
-            to: self.peer_addr,
+            from: send_path.local_addr(),
+            to: send_path.peer_addr(),
+
+            at: send_path.recovery.get_packet_send_time(),
 
-            at: self.recovery.get_packet_send_time(),
+            ecn,
         };
 
         Ok((done, info))
     }
 
     fn send_single(
-        &mut self, out: &mut [u8], has_initial: bool,
+        &mut self, out: &mut [u8], send_pid: usize, has_initial: bool,
+        ecn: EcnCodepoint, pmtud_probe: bool, now: time::Instant,
     ) -> Result<(packet::Type, usize)> {
-        let now = time::Instant::now();
-
         if out.is_empty() {
             return Err(Error::BufferTooShort);
         }
@@ -2652,100 +4027,148 @@ impl Connection {
 
         let mut b = octets::OctetsMut::with_slice(out);
 
-        let pkt_type = self.write_pkt_type()?;
+        let pkt_type = self.write_pkt_type(send_pid)?;
 
         let epoch = pkt_type.to_epoch()?;
 
-        // Process lost frames.
-        for lost in self.recovery.lost[epoch].drain(..) {
-            match lost {
-                frame::Frame::CryptoHeader { offset, length } => {
-                    self.pkt_num_spaces[epoch]
-                        .crypto_stream
-                        .send
-                        .retransmit(offset, length);
+        // Non-active paths are only used to send probing frames, which can
+        // only be sent in 1-RTT packets.
+        let is_active_path = self.paths.get(send_pid)?.active();
 
-                    self.stream_retrans_bytes += length as u64;
+        if !is_active_path && pkt_type != packet::Type::Short {
+            return Err(Error::Done);
+        }
 
-                    self.retrans_count += 1;
-                },
+        // Process lost frames. Note that frames lost on any path can be
+        // retransmitted on the current one.
+        for (_, p) in self.paths.iter_mut() {
+            let mut lost_challenge = false;
 
-                frame::Frame::StreamHeader {
-                    stream_id,
-                    offset,
-                    length,
-                    fin,
-                } => {
-                    let stream = match self.streams.get_mut(stream_id) {
-                        Some(v) => v,
+            for lost in p.recovery.lost[epoch].drain(..) {
+                match lost {
+                    frame::Frame::CryptoHeader { offset, length } => {
+                        self.pkt_num_spaces[epoch]
+                            .crypto_stream
+                            .send
+                            .retransmit(offset, length);
 
-                        None => continue,
-                    };
+                        self.stream_retrans_bytes += length as u64;
+
+                        self.retrans_count += 1;
+                    },
 
-                    let was_flushable = stream.is_flushable();
+                    frame::Frame::StreamHeader {
+                        stream_id,
+                        offset,
+                        length,
+                        fin,
+                    } => {
+                        let stream = match self.streams.get_mut(stream_id) {
+                            Some(v) => v,
 
-                    let empty_fin = length == 0 && fin;
+                            None => continue,
+                        };
 
-                    stream.send.retransmit(offset, length);
+                        let was_flushable = stream.is_flushable();
 
-                    // If the stream is now flushable push it to the flushable
-                    // queue, but only if it wasn't already queued.
-                    //
-                    // Consider the stream flushable also when we are sending a
-                    // zero-length frame that has the fin flag set.
-                    if (stream.is_flushable() || empty_fin) && !was_flushable {
-                        let urgency = stream.urgency;
-                        let incremental = stream.incremental;
-                        self.streams.push_flushable(
-                            stream_id,
-                            urgency,
-                            incremental,
-                        );
-                    }
+                        let empty_fin = length == 0 && fin;
 
-                    self.stream_retrans_bytes += length as u64;
+                        stream.send.retransmit(offset, length);
 
-                    self.retrans_count += 1;
-                },
+                        // If the stream is now flushable push it to the flushable
+                        // queue, but only if it wasn't already queued.
+                        //
+                        // Consider the stream flushable also when we are sending
+                        // a zero-length frame that has
+                        // the fin flag set.
+                        if (stream.is_flushable() || empty_fin) && !was_flushable
+                        {
+                            let urgency = stream.urgency;
+                            let incremental = stream.incremental;
+                            self.streams.push_flushable(
+                                stream_id,
+                                urgency,
+                                incremental,
+                            );
+                        }
 
-                frame::Frame::ACK { .. } => {
-                    self.pkt_num_spaces[epoch].ack_elicited = true;
-                },
+                        self.stream_retrans_bytes += length as u64;
 
-                frame::Frame::ResetStream {
-                    stream_id,
-                    error_code,
-                    final_size,
-                } =>
-                    if self.streams.get(stream_id).is_some() {
-                        self.streams
-                            .mark_reset(stream_id, true, error_code, final_size);
+                        self.retrans_count += 1;
                     },
 
-                // Retransmit HANDSHAKE_DONE only if it hasn't been acked at
-                // least once already.
-                frame::Frame::HandshakeDone if !self.handshake_done_acked => {
-                    self.handshake_done_sent = false;
-                },
+                    frame::Frame::ACK { .. } => {
+                        self.pkt_num_spaces[epoch].ack_elicited = true;
+                    },
 
-                frame::Frame::MaxStreamData { stream_id, .. } => {
-                    if self.streams.get(stream_id).is_some() {
-                        self.streams.mark_almost_full(stream_id, true);
-                    }
-                },
+                    frame::Frame::ResetStream {
+                        stream_id,
+                        error_code,
+                        final_size,
+                    } =>
+                        if self.streams.get(stream_id).is_some() {
+                            self.streams.mark_reset(
+                                stream_id, true, error_code, final_size,
+                            );
+                        },
+
+                    // Retransmit HANDSHAKE_DONE only if it hasn't been acked at
+                    // least once already.
+                    frame::Frame::HandshakeDone if !self.handshake_done_acked => {
+                        self.handshake_done_sent = false;
+                    },
 
-                frame::Frame::MaxData { .. } => {
-                    self.almost_full = true;
-                },
+                    frame::Frame::NewToken { .. } => {
+                        self.new_token_sent = false;
+                    },
 
-                _ => (),
+                    frame::Frame::AckFrequency { seq_num, .. } => {
+                        self.ack_freq.on_frame_lost(seq_num);
+                    },
+
+                    frame::Frame::MaxStreamData { stream_id, .. } => {
+                        if self.streams.get(stream_id).is_some() {
+                            self.streams.mark_almost_full(stream_id, true);
+                        }
+                    },
+
+                    frame::Frame::MaxData { .. } => {
+                        self.almost_full = true;
+                    },
+
+                    frame::Frame::NewConnectionId { seq_num, .. } => {
+                        self.ids.mark_advertise_new_scid_seq(seq_num, true);
+                    },
+
+                    frame::Frame::RetireConnectionId { seq_num } => {
+                        self.ids.mark_retire_dcid_seq(seq_num, true);
+                    },
+
+                    frame::Frame::PathChallenge { .. } => {
+                        lost_challenge = true;
+                    },
+
+                    _ => (),
+                }
+            }
+
+            // Only retry the path validation if it is still in progress.
+            if lost_challenge && p.under_validation() {
+                p.request_validation();
             }
         }
 
         let mut left = b.cap();
 
         // Limit output packet size by congestion window size.
-        left = cmp::min(left, self.recovery.cwnd_available());
+        left =
+            cmp::min(left, self.paths.get(send_pid)?.recovery.cwnd_available());
+
+        // Switch to new keys once too many packets were sent with the current
+        // ones.
+        if pkt_type == packet::Type::Short && self.key_update_needed() {
+            self.initiate_key_update(now)?;
+        }
 
         let pn = self.pkt_num_spaces[epoch].next_pkt_num;
         let pn_len = packet::pkt_num_len(pn)?;
@@ -2755,13 +4178,42 @@ impl Connection {
             .crypto_overhead()
             .ok_or(Error::Done)?;
 
+        let (dcid, scid) = {
+            let path = self.paths.get(send_pid)?;
+
+            let dcid_seq = path.active_dcid_seq.ok_or(Error::OutOfIdentifiers)?;
+
+            let dcid = self.ids.get_dcid(dcid_seq)?.cid.clone();
+
+            // Fall back to the oldest source connection ID if the one used on
+            // the path was retired.
+            let scid = match path
+                .active_scid_seq
+                .and_then(|seq| self.ids.get_scid(seq).ok())
+            {
+                Some(e) => e.cid.clone(),
+
+                None => self.ids.oldest_scid().cid.clone(),
+            };
+
+            (dcid, scid)
+        };
+
+        // 0-RTT packets keep using the original version, even when the
+        // connection was upgraded to a compatible version.
+        let version = if pkt_type == packet::Type::ZeroRTT {
+            self.original_version
+        } else {
+            self.version
+        };
+
         let hdr = Header {
             ty: pkt_type,
 
-            version: self.version,
+            version,
 
-            dcid: ConnectionId::from_ref(&self.dcid),
-            scid: ConnectionId::from_ref(&self.scid),
+            dcid,
+            scid,
 
             pkt_num: 0,
             pkt_num_len: pn_len,
@@ -2776,7 +4228,7 @@ impl Connection {
             },
 
             versions: None,
-            key_phase: false,
+            key_phase: self.key_phase,
         };
 
         hdr.to_bytes(&mut b)?;
@@ -2802,14 +4254,20 @@ impl Connection {
                 // This usually happens when we try to send a new packet but
                 // failed because cwnd is almost full. In such case app_limited
                 // is set to false here to make cwnd grow when ACK is received.
-                self.recovery.update_app_limited(false);
+                self.paths
+                    .get_mut(send_pid)?
+                    .recovery
+                    .update_app_limited(false);
                 return Err(Error::Done);
             },
         }
 
         // Make sure there is enough space for the minimum payload length.
         if left < PAYLOAD_MIN_LEN {
-            self.recovery.update_app_limited(false);
+            self.paths
+                .get_mut(send_pid)?
+                .recovery
+                .update_app_limited(false);
             return Err(Error::Done);
         }
 
@@ -2834,10 +4292,14 @@ impl Connection {
         let payload_offset = b.off();
 
         // Create ACK frame.
+        //
+        // Only probing frames are sent on non-active paths, so that the peer
+        // doesn't consider them as a migration.
         if self.pkt_num_spaces[epoch].recv_pkt_need_ack.len() > 0 &&
             (self.pkt_num_spaces[epoch].ack_elicited ||
-                self.recovery.loss_probes[epoch] > 0) &&
-            !is_closing
+                self.paths.get(send_pid)?.recovery.loss_probes[epoch] > 0) &&
+            !is_closing &&
+            is_active_path
         {
             let ack_delay =
                 self.pkt_num_spaces[epoch].largest_rx_pkt_time.elapsed();
@@ -2849,15 +4311,45 @@ impl Connection {
             let frame = frame::Frame::ACK {
                 ack_delay,
                 ranges: self.pkt_num_spaces[epoch].recv_pkt_need_ack.clone(),
-                ecn_counts: None, // sending ECN is not supported at this time
+                ecn_counts: self.pkt_num_spaces[epoch].ecn_counts,
             };
 
             if push_frame_to_pkt!(b, frames, frame, left) {
-                self.pkt_num_spaces[epoch].ack_elicited = false;
+                let space = &mut self.pkt_num_spaces[epoch];
+
+                space.ack_elicited = false;
+                space.ack_eliciting_unacked = 0;
+                space.ack_timer = None;
             }
         }
 
-        if pkt_type == packet::Type::Short && !is_closing {
+        // Create PMTU probe.
+        //
+        // The probe is made of a PING frame padded to fill the datagram, and
+        // doesn't carry any other frame, as it's more likely to be lost.
+        let mut is_pmtud_probe = false;
+
+        if pmtud_probe &&
+            pkt_type == packet::Type::Short &&
+            !is_closing &&
+            is_active_path
+        {
+            let frame = frame::Frame::Ping;
+
+            if push_frame_to_pkt!(b, frames, frame, left) {
+                if left > 0 {
+                    let frame = frame::Frame::Padding { len: left };
+
+                    push_frame_to_pkt!(b, frames, frame, left);
+                }
+
+                ack_eliciting = true;
+                in_flight = true;
+                is_pmtud_probe = true;
+            }
+        }
+
+        if pkt_type == packet::Type::Short && !is_closing && is_active_path {
             // Create HANDSHAKE_DONE frame.
             if self.should_send_handshake_done() {
                 let frame = frame::Frame::HandshakeDone;
@@ -2870,6 +4362,50 @@ impl Connection {
                 }
             }
 
+            // Create ACK_FREQUENCY frame.
+            if self.ack_freq_auto {
+                self.update_ack_frequency(send_pid)?;
+            }
+
+            if let Some((seq_num, policy)) = self.ack_freq.pending() {
+                let frame = frame::Frame::AckFrequency {
+                    seq_num,
+                    ack_eliciting_threshold: policy.ack_eliciting_threshold,
+                    request_max_ack_delay: policy.max_ack_delay.as_micros()
+                        as u64,
+                    reordering_threshold: policy.reordering_threshold,
+                };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.ack_freq.on_frame_sent();
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
+
+            // Create NEW_TOKEN frame.
+            if self.should_send_new_token() {
+                let token = match self.address_token_key {
+                    Some(ref key) => token::mint_new_token(
+                        key,
+                        &self.paths.get(send_pid)?.peer_addr(),
+                        self.address_token_lifetime,
+                    )?,
+
+                    None => return Err(Error::InvalidState),
+                };
+
+                let frame = frame::Frame::NewToken { token };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.new_token_sent = true;
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
+            }
+
             // Create MAX_STREAMS_BIDI frame.
             if self.streams.should_update_max_streams_bidi() {
                 let frame = frame::Frame::MaxStreamsBidi {
@@ -2924,7 +4460,9 @@ impl Connection {
                 };
 
                 // Autotune the stream window size.
-                stream.recv.autotune_window(now, self.recovery.rtt());
+                stream
+                    .recv
+                    .autotune_window(now, self.paths.get_active().recovery.rtt());
 
                 let frame = frame::Frame::MaxStreamData {
                     stream_id,
@@ -2956,7 +4494,8 @@ impl Connection {
             // Create MAX_DATA frame as needed.
             if self.almost_full && self.max_rx_data() < self.max_rx_data_next() {
                 // Autotune the connection window size.
-                self.flow_control.autotune_window(now, self.recovery.rtt());
+                self.flow_control
+                    .autotune_window(now, self.paths.get_active().recovery.rtt());
 
                 let frame = frame::Frame::MaxData {
                     max: self.max_rx_data_next(),
@@ -3030,6 +4569,41 @@ impl Connection {
                     in_flight = true;
                 }
             }
+
+            // Create NEW_CONNECTION_ID frames as needed.
+            while let Some(seq_num) = self.ids.next_advertise_new_scid_seq() {
+                let e = self.ids.get_scid(seq_num)?;
+
+                let frame = frame::Frame::NewConnectionId {
+                    seq_num,
+                    retire_prior_to: self.ids.retire_prior_to(),
+                    conn_id: e.cid.to_vec(),
+                    reset_token: e.reset_token.unwrap_or(0).to_be_bytes(),
+                };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.ids.mark_advertise_new_scid_seq(seq_num, false);
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                } else {
+                    break;
+                }
+            }
+
+            // Create RETIRE_CONNECTION_ID frames as needed.
+            while let Some(seq_num) = self.ids.next_retire_dcid_seq() {
+                let frame = frame::Frame::RetireConnectionId { seq_num };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    self.ids.mark_retire_dcid_seq(seq_num, false);
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                } else {
+                    break;
+                }
+            }
         }
 
         // Create CONNECTION_CLOSE frame.
@@ -3043,8 +4617,8 @@ impl Connection {
                     };
 
                     if push_frame_to_pkt!(b, frames, frame, left) {
-                        self.draining_timer =
-                            Some(now + (self.recovery.pto() * 3));
+                        let pto = self.paths.get_active().recovery.pto();
+                        self.draining_timer = Some(now + (pto * 3));
 
                         ack_eliciting = true;
                         in_flight = true;
@@ -3059,7 +4633,8 @@ impl Connection {
                 };
 
                 if push_frame_to_pkt!(b, frames, frame, left) {
-                    self.draining_timer = Some(now + (self.recovery.pto() * 3));
+                    let pto = self.paths.get_active().recovery.pto();
+                    self.draining_timer = Some(now + (pto * 3));
 
                     ack_eliciting = true;
                     in_flight = true;
@@ -3067,22 +4642,48 @@ impl Connection {
             }
         }
 
-        // Create PATH_RESPONSE frame.
-        if let Some(challenge) = self.challenge {
-            let frame = frame::Frame::PathResponse { data: challenge };
+        // Data of the PATH_CHALLENGE frame sent in this packet, if any.
+        let mut challenge_data = None;
 
-            if push_frame_to_pkt!(b, frames, frame, left) {
-                self.challenge = None;
+        if pkt_type == packet::Type::Short && !is_closing {
+            let path = self.paths.get_mut(send_pid)?;
 
-                ack_eliciting = true;
-                in_flight = true;
+            // Create PATH_RESPONSE frames as needed, on the path the
+            // corresponding challenges were received on.
+            while let Some(data) = path.pop_received_challenge() {
+                let frame = frame::Frame::PathResponse { data };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    ack_eliciting = true;
+                    in_flight = true;
+                } else {
+                    // Try again in the next packet.
+                    path.on_challenge_received(data);
+                    break;
+                }
+            }
+
+            // Create PATH_CHALLENGE frame if the path needs validation.
+            if path.validation_requested() {
+                let mut data = [0; 8];
+                rand::rand_bytes(&mut data[..]);
+
+                let frame = frame::Frame::PathChallenge { data };
+
+                if push_frame_to_pkt!(b, frames, frame, left) {
+                    challenge_data = Some(data);
+
+                    ack_eliciting = true;
+                    in_flight = true;
+                }
             }
         }
 
         // Create CRYPTO frame.
         if self.pkt_num_spaces[epoch].crypto_stream.is_flushable() &&
             left > frame::MAX_CRYPTO_OVERHEAD &&
-            !is_closing
+            !is_closing &&
+            is_active_path
         {
             let crypto_off =
                 self.pkt_num_spaces[epoch].crypto_stream.send.off_front();
@@ -3165,6 +4766,7 @@ impl Connection {
         if (pkt_type == packet::Type::Short || pkt_type == packet::Type::ZeroRTT) &&
             left > frame::MAX_DGRAM_OVERHEAD &&
             !is_closing &&
+            is_active_path &&
             do_dgram
         {
             if let Some(max_dgram_payload) = self.dgram_max_writable_len() {
@@ -3242,6 +4844,7 @@ impl Connection {
         if (pkt_type == packet::Type::Short || pkt_type == packet::Type::ZeroRTT) &&
             left > frame::MAX_STREAM_OVERHEAD &&
             !is_closing &&
+            is_active_path &&
             !dgram_emitted
         {
             while let Some(stream_id) = self.streams.pop_flushable() {
@@ -3325,12 +4928,17 @@ impl Connection {
                     has_data = true;
                 }
 
-                // If the stream is still flushable, push it to the back of the
-                // queue again.
+                // If the stream is still flushable, push it to the queue
+                // again.
                 if stream.is_flushable() {
                     let urgency = stream.urgency;
                     let incremental = stream.incremental;
-                    self.streams.push_flushable(stream_id, urgency, incremental);
+                    self.streams.requeue_flushable(
+                        stream_id,
+                        urgency,
+                        incremental,
+                        len,
+                    );
                 }
 
                 // When fuzzing, try to coalesce multiple STREAM frames in the
@@ -3347,11 +4955,29 @@ impl Connection {
         // Alternate trying to send DATAGRAMs next time.
         self.emit_dgram = !dgram_emitted;
 
+        // Create IMMEDIATE_ACK for PTO probe, so that the peer doesn't delay
+        // its ACK, if it supports it.
+        if self.paths.get(send_pid)?.recovery.loss_probes[epoch] > 0 &&
+            self.peer_transport_params.min_ack_delay.is_some() &&
+            pkt_type == packet::Type::Short &&
+            left >= 1 &&
+            !is_closing &&
+            is_active_path
+        {
+            let frame = frame::Frame::ImmediateAck;
+
+            if push_frame_to_pkt!(b, frames, frame, left) {
+                ack_eliciting = true;
+                in_flight = true;
+            }
+        }
+
         // Create PING for PTO probe if no other ack-eliciting frame is sent.
-        if self.recovery.loss_probes[epoch] > 0 &&
+        if self.paths.get(send_pid)?.recovery.loss_probes[epoch] > 0 &&
             !ack_eliciting &&
             left >= 1 &&
-            !is_closing
+            !is_closing &&
+            is_active_path
         {
             let frame = frame::Frame::Ping;
 
@@ -3362,17 +4988,51 @@ impl Connection {
         }
 
         if ack_eliciting {
-            self.recovery.loss_probes[epoch] =
-                self.recovery.loss_probes[epoch].saturating_sub(1);
+            let recovery = &mut self.paths.get_mut(send_pid)?.recovery;
+
+            recovery.loss_probes[epoch] =
+                recovery.loss_probes[epoch].saturating_sub(1);
         }
 
         if frames.is_empty() {
             // When we reach this point we are not able to write more, so set
             // app_limited to false.
-            self.recovery.update_app_limited(false);
+            self.paths
+                .get_mut(send_pid)?
+                .recovery
+                .update_app_limited(false);
             return Err(Error::Done);
         }
 
+        // Datagrams carrying PATH_CHALLENGE or PATH_RESPONSE frames need to
+        // be expanded to at least the minimum QUIC datagram size, to make sure
+        // the path supports it. This is done with PADDING frames, as data
+        // can't be appended to a 1-RTT packet.
+        //
+        // Note that `left` already takes into account the anti-amplification
+        // limit, so the packet might end up being smaller.
+        if frames.iter().any(|f| {
+            matches!(
+                f,
+                frame::Frame::PathChallenge { .. } |
+                    frame::Frame::PathResponse { .. }
+            )
+        }) {
+            let pkt_len = b.off() + crypto_overhead;
+
+            if pkt_len < MIN_CLIENT_INITIAL_LEN {
+                let pad_len = cmp::min(left, MIN_CLIENT_INITIAL_LEN - pkt_len);
+
+                if pad_len > 0 {
+                    let frame = frame::Frame::Padding { len: pad_len };
+
+                    if push_frame_to_pkt!(b, frames, frame, left) {
+                        in_flight = true;
+                    }
+                }
+            }
+        }
+
         // When coalescing a 1-RTT packet, we can't add padding in the UDP
         // datagram, so use PADDING frames instead.
         //
@@ -3494,42 +5154,69 @@ impl Connection {
             first_sent_time: now,
             is_app_limited: false,
             has_data,
+            ecn_marked: ecn != EcnCodepoint::NotEct,
         };
 
-        if in_flight && self.delivery_rate_check_if_app_limited() {
-            self.recovery.delivery_rate_update_app_limited(true);
+        let delivery_rate_app_limited =
+            in_flight && self.delivery_rate_check_if_app_limited();
+
+        let handshake_status = self.handshake_status();
+
+        // Start the path validation timer, making sure it lasts for longer
+        // than a PTO on both the active and the validated path.
+        let active_pto = self.paths.get_active().recovery.pto();
+
+        let path = self.paths.get_mut(send_pid)?;
+
+        if delivery_rate_app_limited {
+            path.recovery.delivery_rate_update_app_limited(true);
         }
 
-        self.recovery.on_packet_sent(
+        path.recovery.on_packet_sent(
             sent_pkt,
             epoch,
-            self.handshake_status(),
+            handshake_status,
             now,
             &self.trace_id,
         );
 
+        if is_pmtud_probe {
+            path.recovery.on_pmtud_probe_sent(pn, written);
+        }
+
+        if let Some(data) = challenge_data {
+            let pto = cmp::max(active_pto, path.recovery.pto());
+
+            path.on_challenge_sent(data, written, now, pto * 3);
+        }
+
         qlog_with_type!(QLOG_METRICS, self.qlog, q, {
-            if let Some(ev_data) = self.recovery.maybe_qlog() {
+            if let Some(ev_data) = path.recovery.maybe_qlog() {
                 q.add_event_data_with_instant(ev_data, now).ok();
             }
         });
 
+        #[cfg(feature = "qlog")]
+        self.qlog.add_events(path.recovery.take_qlog_events(), now);
+
+        if self.dgram_send_queue.byte_size() > path.recovery.cwnd_available() {
+            path.recovery.update_app_limited(false);
+        }
+
+        path.max_send_bytes = path.max_send_bytes.saturating_sub(written);
+
+        path.sent_count += 1;
+
         self.pkt_num_spaces[epoch].next_pkt_num += 1;
 
         self.sent_count += 1;
         self.sent_bytes += written as u64;
 
-        if self.dgram_send_queue.byte_size() > self.recovery.cwnd_available() {
-            self.recovery.update_app_limited(false);
-        }
-
         // On the client, drop initial state after sending an Handshake packet.
         if !self.is_server && hdr.ty == packet::Type::Handshake {
             self.drop_epoch_state(packet::EPOCH_INITIAL, now);
         }
 
-        self.max_send_bytes = self.max_send_bytes.saturating_sub(written);
-
         // (Re)start the idle timer if we are sending the first ack-eliciting
         // packet since last receiving a packet.
         if ack_eliciting && !self.ack_eliciting_sent {
@@ -3555,7 +5242,7 @@ impl Connection {
     /// multiple packets.
     #[inline]
     pub fn send_quantum(&mut self) -> usize {
-        self.recovery.send_quantum()
+        self.paths.get_active().recovery.send_quantum()
     }
 
     /// Reads contiguous data from a stream into the provided slice.
@@ -3579,8 +5266,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// # let stream_id = 0;
     /// while let Ok((read, fin)) = conn.stream_recv(stream_id, &mut buf) {
     ///     println!("Got {} bytes on stream {}", read, stream_id);
@@ -3590,6 +5278,123 @@ impl Connection {
     pub fn stream_recv(
         &mut self, stream_id: u64, out: &mut [u8],
     ) -> Result<(usize, bool)> {
+        self.stream_recv_with(stream_id, |recv| {
+            let (read, fin) = recv.emit(out)?;
+
+            Ok(((), read, fin))
+        })
+        .map(|(_, read, fin)| (read, fin))
+    }
+
+    /// Reads the next chunk of contiguous data from a stream without copying
+    /// it.
+    ///
+    /// This behaves like [`stream_recv()`], except that instead of copying
+    /// data into a buffer provided by the application, the stream hands over
+    /// (up to `max_len` bytes of) the buffer the data was received into.
+    ///
+    /// The returned data is only considered consumed for flow control purposes
+    /// once the application releases it with [`stream_consume()`], so the peer
+    /// can't send more data than the stream's flow control window while the
+    /// application holds on to the returned buffers. All the data returned
+    /// must eventually be released, even if the stream is then reset or shut
+    /// down.
+    ///
+    /// On success the chunk of data and a flag indicating the fin state is
+    /// returned as a tuple, or [`Done`] if there is no data to read. If
+    /// `max_len` is zero, [`BufferTooShort`] is returned.
+    ///
+    /// [`stream_recv()`]: struct.Connection.html#method.stream_recv
+    /// [`stream_consume()`]: struct.Connection.html#method.stream_consume
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let stream_id = 0;
+    /// while let Ok((chunk, fin)) = conn.stream_recv_zc(stream_id, usize::MAX) {
+    ///     println!("Got {} bytes on stream {}", chunk.len(), stream_id);
+    ///
+    ///     conn.stream_consume(stream_id, chunk.len())?;
+    /// }
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn stream_recv_zc(
+        &mut self, stream_id: u64, max_len: usize,
+    ) -> Result<(SharedBuf, bool)> {
+        self.stream_recv_with(stream_id, |recv| {
+            let (buf, fin) = recv.emit_shared(max_len)?;
+
+            // The data is only consumed once released by the application.
+            Ok((buf, 0, fin))
+        })
+        .map(|(buf, _, fin)| (buf, fin))
+    }
+
+    /// Releases data returned by [`stream_recv_zc()`].
+    ///
+    /// This tells the stream that the application is done with the next `len`
+    /// bytes of the data it was handed without copying, so that they are
+    /// accounted for flow control, and more data can be received in their
+    /// place.
+    ///
+    /// The [`InvalidStreamState`] error is returned if `len` is larger than
+    /// the amount of data returned and not yet released.
+    ///
+    /// [`stream_recv_zc()`]: struct.Connection.html#method.stream_recv_zc
+    /// [`InvalidStreamState`]: enum.Error.html#variant.InvalidStreamState
+    pub fn stream_consume(&mut self, stream_id: u64, len: usize) -> Result<()> {
+        let stream = self
+            .streams
+            .get_mut(stream_id)
+            .ok_or(Error::InvalidStreamState(stream_id))?;
+
+        if !stream.recv.consume(len) {
+            return Err(Error::InvalidStreamState(stream_id));
+        }
+
+        let local = stream.local;
+
+        let almost_full = stream.recv.almost_full();
+
+        let complete = stream.is_complete();
+
+        self.flow_control.add_consumed(len as u64);
+
+        if almost_full {
+            self.streams.mark_almost_full(stream_id, true);
+        }
+
+        if complete {
+            self.streams.collect(stream_id, local);
+        }
+
+        if self.should_update_max_data() {
+            self.almost_full = true;
+        }
+
+        Ok(())
+    }
+
+    /// Reads data from a stream, using `read` to take the data out of the
+    /// stream's receive buffer.
+    ///
+    /// Besides its own result, `read` returns the number of bytes consumed for
+    /// flow control purposes and the fin state.
+    fn stream_recv_with<T, F>(
+        &mut self, stream_id: u64, read: F,
+    ) -> Result<(T, usize, bool)>
+    where
+        F: FnOnce(&mut stream::RecvBuf) -> Result<(T, usize, bool)>,
+    {
         // We can't read on our own unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             stream::is_local(stream_id, self.is_server)
@@ -3611,7 +5416,7 @@ impl Connection {
         #[cfg(feature = "qlog")]
         let offset = stream.recv.off_front();
 
-        let (read, fin) = match stream.recv.emit(out) {
+        let (out, consumed, fin) = match read(&mut stream.recv) {
             Ok(v) => v,
 
             Err(e) => {
@@ -3628,7 +5433,10 @@ impl Connection {
             },
         };
 
-        self.flow_control.add_consumed(read as u64);
+        #[cfg(feature = "qlog")]
+        let length = stream.recv.off_front() - offset;
+
+        self.flow_control.add_consumed(consumed as u64);
 
         let readable = stream.is_readable();
 
@@ -3650,7 +5458,7 @@ impl Connection {
             let ev_data = EventData::DataMoved(qlog::events::quic::DataMoved {
                 stream_id: Some(stream_id),
                 offset: Some(offset),
-                length: Some(read as u64),
+                length: Some(length),
                 from: Some(DataRecipient::Transport),
                 to: Some(DataRecipient::Application),
                 data: None,
@@ -3664,7 +5472,7 @@ impl Connection {
             self.almost_full = true;
         }
 
-        Ok((read, fin))
+        Ok((out, consumed, fin))
     }
 
     /// Writes data to a stream.
@@ -3707,8 +5515,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// # let stream_id = 0;
     /// conn.stream_send(stream_id, b"hello", true)?;
     /// # Ok::<(), quiche::Error>(())
@@ -3716,6 +5525,67 @@ impl Connection {
     pub fn stream_send(
         &mut self, stream_id: u64, buf: &[u8], fin: bool,
     ) -> Result<usize> {
+        self.stream_send_with(stream_id, buf.len(), fin, |send, len, fin| {
+            send.write(&buf[..len], fin)
+        })
+    }
+
+    /// Writes data to a stream without copying it.
+    ///
+    /// This behaves like [`stream_send()`], except that the data is not copied
+    /// into the stream's send buffer. Instead, the underlying buffer is shared
+    /// with the stream (and with any packet retransmitting the data) until all
+    /// of it is acknowledged by the peer.
+    ///
+    /// As with [`stream_send()`], the number of written bytes returned can be
+    /// lower than the length of the input buffer. Since cloning a
+    /// [`SharedBuf`] doesn't copy its data, the application can keep the
+    /// buffer around and retry sending the rest of it with
+    /// [`SharedBuf::advance()`].
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    /// [`SharedBuf`]: struct.SharedBuf.html
+    /// [`SharedBuf::advance()`]: struct.SharedBuf.html#method.advance
+    ///
+    /// ## Examples:
+    ///
+    /// ```no_run
+    /// # let mut buf = [0; 512];
+    /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+    /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
+    /// # let stream_id = 0;
+    /// let mut body = quiche::SharedBuf::new(vec![0; 1_000_000]);
+    ///
+    /// let written = conn.stream_send_zc(stream_id, &body, true)?;
+    ///
+    /// // Keep the rest of the body around to send it later.
+    /// body.advance(written);
+    /// # Ok::<(), quiche::Error>(())
+    /// ```
+    pub fn stream_send_zc(
+        &mut self, stream_id: u64, buf: &SharedBuf, fin: bool,
+    ) -> Result<usize> {
+        self.stream_send_with(stream_id, buf.len(), fin, |send, len, fin| {
+            // Only queue as much data as the connection allows.
+            let mut buf = buf.clone();
+            buf.truncate(len);
+
+            send.write_shared(&buf, fin)
+        })
+    }
+
+    /// Writes `buf_len` bytes of data to a stream, using `write` to insert the
+    /// data into the stream's send buffer.
+    fn stream_send_with<F>(
+        &mut self, stream_id: u64, buf_len: usize, fin: bool, write: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(&mut stream::SendBuf, usize, bool) -> Result<usize>,
+    {
         // We can't write on the peer's unidirectional streams.
         if !stream::is_bidi(stream_id) &&
             !stream::is_local(stream_id, self.is_server)
@@ -3728,7 +5598,7 @@ impl Connection {
         //
         // Note that this is separate from "send capacity" as that also takes
         // congestion control into consideration.
-        if self.max_tx_data - self.tx_data < buf.len() as u64 {
+        if self.max_tx_data - self.tx_data < buf_len as u64 {
             self.blocked_limit = Some(self.max_tx_data);
         }
 
@@ -3738,14 +5608,14 @@ impl Connection {
         // When the cap is zero, the method returns Ok(0) *only* when the passed
         // buffer is empty. We return Error::Done otherwise.
         let cap = self.tx_cap;
-        if cap == 0 && !(fin && buf.is_empty()) {
+        if cap == 0 && !(fin && buf_len == 0) {
             return Err(Error::Done);
         }
 
-        let (buf, fin) = if cap < buf.len() {
-            (&buf[..cap], false)
+        let (len, fin) = if cap < buf_len {
+            (cap, false)
         } else {
-            (buf, fin)
+            (buf_len, fin)
         };
 
         // Get existing stream or create a new one.
@@ -3756,7 +5626,7 @@ impl Connection {
 
         let was_flushable = stream.is_flushable();
 
-        let sent = match stream.send.write(buf, fin) {
+        let sent = match write(&mut stream.send, len, fin) {
             Ok(v) => v,
 
             Err(e) => {
@@ -3772,9 +5642,9 @@ impl Connection {
 
         let writable = stream.is_writable();
 
-        let empty_fin = buf.is_empty() && fin;
+        let empty_fin = len == 0 && fin;
 
-        if sent < buf.len() {
+        if sent < len {
             let max_off = stream.send.max_off();
 
             if stream.send.blocked_at() != Some(max_off) {
@@ -3817,7 +5687,7 @@ impl Connection {
             q.add_event_data_with_instant(ev_data, now).ok();
         });
 
-        if sent == 0 && !buf.is_empty() {
+        if sent == 0 && len != 0 {
             return Err(Error::Done);
         }
 
@@ -3852,7 +5722,38 @@ impl Connection {
         stream.urgency = urgency;
         stream.incremental = incremental;
 
-        // TODO: reprioritization
+        // If the stream is already queued for sending, move it to the queue
+        // matching its new priority.
+        self.streams
+            .update_flushable_priority(stream_id, urgency, incremental);
+
+        Ok(())
+    }
+
+    /// Sets the weight for a stream.
+    ///
+    /// A stream's weight determines the share of the bandwidth it gets when
+    /// one of the round-robin schedulers is used, relative to the other
+    /// streams (streams with higher weight are allowed to send more data in
+    /// each round). Streams are created with a default weight of `16`, and a
+    /// weight of `0` is treated as `1`.
+    ///
+    /// The weight is ignored when using the default strict priority scheduler.
+    ///
+    /// The target stream is created if it did not exist before calling this
+    /// method.
+    pub fn stream_weight(&mut self, stream_id: u64, weight: u8) -> Result<()> {
+        // Get existing stream or create a new one, but if the stream
+        // has already been closed and collected, ignore the weight.
+        let stream = match self.get_or_create_stream(stream_id, true) {
+            Ok(v) => v,
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        stream.weight = cmp::max(weight, 1);
 
         Ok(())
     }
@@ -4090,8 +5991,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// // Iterate over readable streams.
     /// for stream_id in conn.readable() {
     ///     // Stream is readable, read until there's no more data.
@@ -4125,8 +6027,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// // Iterate over writable streams.
     /// for stream_id in conn.writable() {
     ///     // Stream is writable, write some data.
@@ -4164,7 +6067,10 @@ impl Connection {
         if self.is_established() {
             // We cap the maximum packet size to 16KB or so, so that it can be
             // always encoded with a 2-byte varint.
-            cmp::min(16383, self.recovery.max_datagram_size())
+            let max_datagram_size =
+                self.paths.get_active().recovery.max_datagram_size();
+
+            cmp::min(16383, max_datagram_size)
         } else {
             // Allow for 1200 bytes (minimum QUIC packet size) during the
             // handshake.
@@ -4191,8 +6097,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// let mut dgram_buf = [0; 512];
     /// while let Ok((len)) = conn.dgram_recv(&mut dgram_buf) {
     ///     println!("Got {} bytes of DATAGRAM", len);
@@ -4304,8 +6211,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// conn.dgram_send(b"hello")?;
     /// # Ok::<(), quiche::Error>(())
     /// ```
@@ -4322,8 +6230,10 @@ impl Connection {
 
         self.dgram_send_queue.push(buf.to_vec())?;
 
-        if self.dgram_send_queue.byte_size() > self.recovery.cwnd_available() {
-            self.recovery.update_app_limited(false);
+        let recovery = &mut self.paths.get_active_mut().recovery;
+
+        if self.dgram_send_queue.byte_size() > recovery.cwnd_available() {
+            recovery.update_app_limited(false);
         }
 
         Ok(())
@@ -4348,8 +6258,10 @@ impl Connection {
 
         self.dgram_send_queue.push(buf)?;
 
-        if self.dgram_send_queue.byte_size() > self.recovery.cwnd_available() {
-            self.recovery.update_app_limited(false);
+        let recovery = &mut self.paths.get_active_mut().recovery;
+
+        if self.dgram_send_queue.byte_size() > recovery.cwnd_available() {
+            recovery.update_app_limited(false);
         }
 
         Ok(())
@@ -4364,8 +6276,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// conn.dgram_send(b"hello")?;
     /// conn.dgram_purge_outgoing(&|d: &[u8]| -> bool { d[0] == 0 });
     /// # Ok::<(), quiche::Error>(())
@@ -4387,8 +6300,9 @@ impl Connection {
     /// # let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
     /// # let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
     /// # let scid = quiche::ConnectionId::from_ref(&[0xba; 16]);
-    /// # let from = "127.0.0.1:1234".parse().unwrap();
-    /// # let mut conn = quiche::accept(&scid, None, from, &mut config)?;
+    /// # let peer = "127.0.0.1:1234".parse().unwrap();
+    /// # let local = "127.0.0.1:4321".parse().unwrap();
+    /// # let mut conn = quiche::accept(&scid, None, local, peer, &mut config)?;
     /// if let Some(payload_size) = conn.dgram_max_writable_len() {
     ///     if payload_size > 5 {
     ///         conn.dgram_send(b"hello")?;
@@ -4405,7 +6319,7 @@ impl Connection {
                 let mut max_len = self.max_send_udp_payload_size();
                 // ...subtract the Short packet header overhead...
                 // (1 byte of pkt_len + len of dcid)
-                max_len = max_len.saturating_sub(1 + self.dcid.len());
+                max_len = max_len.saturating_sub(1 + self.destination_id().len());
                 // ...subtract the packet number (max len)...
                 max_len = max_len.saturating_sub(packet::MAX_PKT_NUM_LEN);
                 // ...subtract the crypto overhead...
@@ -4428,6 +6342,58 @@ impl Connection {
             .is_some()
     }
 
+    /// Requests the peer to change how often it sends ACKs.
+    ///
+    /// The peer sends an ACK once more than `ack_eliciting_threshold`
+    /// ack-eliciting packets are unacknowledged, or at most `max_ack_delay`
+    /// after receiving one. It also acknowledges immediately when
+    /// `reordering_threshold` packets are missing, unless it is 0. The delay
+    /// is raised to the peer's `min_ack_delay` if lower.
+    ///
+    /// This disables the automatic tuning configured with
+    /// [`enable_ack_frequency()`].
+    ///
+    /// Returns [`InvalidState`] if the peer didn't advertise the
+    /// `min_ack_delay` transport parameter.
+    ///
+    /// [`enable_ack_frequency()`]:
+    /// struct.Config.html#method.enable_ack_frequency
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn set_ack_frequency(
+        &mut self, ack_eliciting_threshold: u64, max_ack_delay: time::Duration,
+        reordering_threshold: u64,
+    ) -> Result<()> {
+        let min_ack_delay = match self.peer_transport_params.min_ack_delay {
+            Some(v) => time::Duration::from_micros(v),
+
+            None => return Err(Error::InvalidState),
+        };
+
+        let max_ack_delay = cmp::max(max_ack_delay, min_ack_delay);
+
+        // The PTO needs to account for the requested delay.
+        let pto_ack_delay = cmp::max(
+            max_ack_delay,
+            time::Duration::from_millis(self.peer_transport_params.max_ack_delay),
+        );
+
+        self.recovery_config.max_ack_delay = pto_ack_delay;
+
+        for (_, p) in self.paths.iter_mut() {
+            p.recovery.max_ack_delay = pto_ack_delay;
+        }
+
+        self.ack_freq_auto = false;
+
+        self.ack_freq.request(ack_freq::AckPolicy {
+            ack_eliciting_threshold,
+            max_ack_delay,
+            reordering_threshold,
+        });
+
+        Ok(())
+    }
+
     /// Returns the amount of time until the next timeout event.
     ///
     /// Once the given duration has elapsed, the [`on_timeout()`] method should
@@ -4435,44 +6401,78 @@ impl Connection {
     ///
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     pub fn timeout(&self) -> Option<time::Duration> {
+        self.timeout_at(time::Instant::now())
+    }
+
+    /// Returns the amount of time from `now` until the next timeout event.
+    ///
+    /// This behaves like [`timeout()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn timeout_at(&self, now: time::Instant) -> Option<time::Duration> {
+        self.timeout_instant()
+            .map(|timeout| timeout.saturating_duration_since(now))
+    }
+
+    /// Returns the point in time of the next timeout event.
+    ///
+    /// Once that point in time is reached, the [`on_timeout()`] method should
+    /// be called. A timeout of `None` means that the timer should be disarmed.
+    ///
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    pub fn timeout_instant(&self) -> Option<time::Instant> {
         if self.is_closed() {
             return None;
         }
 
-        let timeout = if self.is_draining() {
+        if self.is_draining() {
             // Draining timer takes precedence over all other timers. If it is
             // set it means the connection is closing so there's no point in
             // processing the other timers.
             self.draining_timer
         } else {
-            // Use the lowest timer value (i.e. "sooner") among idle and loss
-            // detection timers. If they are both unset (i.e. `None`) then the
-            // result is `None`, but if at least one of them is set then a
-            // `Some(...)` value is returned.
-            let timers = [self.idle_timer, self.recovery.loss_detection_timer()];
-
-            timers.iter().filter_map(|&x| x).min()
-        };
+            // Use the lowest timer value (i.e. "sooner") among idle, loss
+            // detection and path validation timers. If they are all unset
+            // (i.e. `None`) then the result is `None`, but if at least one of
+            // them is set then a `Some(...)` value is returned.
+            let path_timers = self.paths.iter().flat_map(|(_, p)| {
+                [p.recovery.loss_detection_timer(), p.validation_timer()]
+            });
 
-        if let Some(timeout) = timeout {
-            let now = time::Instant::now();
+            let key_update_timer = self.pkt_num_spaces[packet::EPOCH_APPLICATION]
+                .key_update
+                .as_ref()
+                .map(|key_update| key_update.timer);
 
-            if timeout <= now {
-                return Some(time::Duration::ZERO);
-            }
+            let ack_timer =
+                self.pkt_num_spaces[packet::EPOCH_APPLICATION].ack_timer;
 
-            return Some(timeout.duration_since(now));
+            path_timers
+                .chain(std::iter::once(self.idle_timer))
+                .chain(std::iter::once(key_update_timer))
+                .chain(std::iter::once(ack_timer))
+                .flatten()
+                .min()
         }
-
-        None
     }
 
     /// Processes a timeout event.
     ///
     /// If no timeout has occurred it does nothing.
     pub fn on_timeout(&mut self) {
-        let now = time::Instant::now();
+        self.on_timeout_at(time::Instant::now())
+    }
 
+    /// Processes a timeout event at the given time.
+    ///
+    /// This behaves like [`on_timeout()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn on_timeout_at(&mut self, now: time::Instant) {
         if let Some(draining_timer) = self.draining_timer {
             if draining_timer <= now {
                 trace!("{} draining timeout expired", self.trace_id);
@@ -4494,6 +6494,21 @@ impl Connection {
             if timer <= now {
                 trace!("{} idle timeout expired", self.trace_id);
 
+                #[cfg(feature = "qlog")]
+                {
+                    let trigger = if self.is_established() {
+                        ConnectionClosedTrigger::IdleTimeout
+                    } else {
+                        ConnectionClosedTrigger::HandshakeTimeout
+                    };
+
+                    self.qlog_connection_closed(
+                        TransportOwner::Local,
+                        trigger,
+                        now,
+                    );
+                }
+
                 qlog_with!(self.qlog, q, {
                     q.finish_log().ok();
                 });
@@ -4504,21 +6519,98 @@ impl Connection {
             }
         }
 
-        if let Some(timer) = self.recovery.loss_detection_timer() {
+        // Drop the previous 1-RTT keys once the key update grace period is
+        // over.
+        let space = &mut self.pkt_num_spaces[packet::EPOCH_APPLICATION];
+
+        if let Some(timer) = space.key_update.as_ref().map(|k| k.timer) {
             if timer <= now {
-                trace!("{} loss detection timeout expired", self.trace_id);
+                trace!("{} key update timeout expired", self.trace_id);
 
-                self.recovery.on_loss_detection_timeout(
-                    self.handshake_status(),
-                    now,
-                    &self.trace_id,
-                );
+                space.key_update = None;
+            }
+        }
 
-                qlog_with_type!(QLOG_METRICS, self.qlog, q, {
-                    if let Some(ev_data) = self.recovery.maybe_qlog() {
-                        q.add_event_data_with_instant(ev_data, now).ok();
-                    }
-                });
+        // Send the delayed ACK.
+        if let Some(timer) = space.ack_timer {
+            if timer <= now {
+                trace!("{} ack delay timeout expired", self.trace_id);
+
+                space.ack_elicited = true;
+                space.ack_timer = None;
+            }
+        }
+
+        let handshake_status = self.handshake_status();
+
+        for (_, p) in self.paths.iter_mut() {
+            if let Some(timer) = p.recovery.loss_detection_timer() {
+                if timer <= now {
+                    trace!("{} loss detection timeout expired", self.trace_id);
+
+                    p.recovery.on_loss_detection_timeout(
+                        handshake_status,
+                        now,
+                        &self.trace_id,
+                    );
+
+                    qlog_with_type!(QLOG_METRICS, self.qlog, q, {
+                        if let Some(ev_data) = p.recovery.maybe_qlog() {
+                            q.add_event_data_with_instant(ev_data, now).ok();
+                        }
+                    });
+
+                    #[cfg(feature = "qlog")]
+                    self.qlog.add_events(p.recovery.take_qlog_events(), now);
+                }
+            }
+        }
+
+        let mut active_path_failed = false;
+        let mut failed_paths = Vec::new();
+
+        for (pid, p) in self.paths.iter_mut() {
+            if p.on_validation_timeout(now) {
+                trace!("{} path {} validation failed", self.trace_id, pid);
+
+                active_path_failed |= p.active();
+
+                failed_paths.push((p.local_addr(), p.peer_addr()));
+            }
+        }
+
+        for (local_addr, peer_addr) in failed_paths {
+            self.paths.notify_event(path::PathEvent::FailedValidation(
+                local_addr, peer_addr,
+            ));
+        }
+
+        // If the active path failed validation, fall back to a path that was
+        // previously validated. If there is none, the connection can't be
+        // used anymore so close it silently.
+        if active_path_failed {
+            let validated_pid = self
+                .paths
+                .iter()
+                .find(|(_, p)| p.validated())
+                .map(|(pid, _)| pid);
+
+            match validated_pid {
+                Some(pid) => {
+                    trace!("{} falling back to path {}", self.trace_id, pid);
+
+                    self.paths.set_active_path(pid).ok();
+                },
+
+                None => {
+                    trace!("{} no validated path left", self.trace_id);
+
+                    qlog_with!(self.qlog, q, {
+                        q.finish_log().ok();
+                    });
+
+                    self.closed = true;
+                },
             }
         }
     }
@@ -4542,6 +6634,20 @@ impl Connection {
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     /// [`is_closed()`]: struct.Connection.html#method.is_closed
     pub fn close(&mut self, app: bool, err: u64, reason: &[u8]) -> Result<()> {
+        self.close_at(app, err, reason, time::Instant::now())
+    }
+
+    /// Closes the connection with the given error and reason, at the given
+    /// time.
+    ///
+    /// This behaves like [`close()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`close()`]: struct.Connection.html#method.close
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn close_at(
+        &mut self, app: bool, err: u64, reason: &[u8], now: time::Instant,
+    ) -> Result<()> {
         if self.is_closed() || self.is_draining() {
             return Err(Error::Done);
         }
@@ -4556,6 +6662,22 @@ impl Connection {
             reason: reason.to_vec(),
         });
 
+        #[cfg(feature = "qlog")]
+        {
+            let trigger = if app {
+                ConnectionClosedTrigger::Application
+            } else if err == 0 {
+                ConnectionClosedTrigger::Clean
+            } else {
+                ConnectionClosedTrigger::Error
+            };
+
+            self.qlog_connection_closed(TransportOwner::Local, trigger, now);
+        }
+
+        #[cfg(not(feature = "qlog"))]
+        let _ = now;
+
         // When no packet was successfully processed close connection immediately.
         if self.recv_count == 0 {
             self.closed = true;
@@ -4604,22 +6726,346 @@ impl Connection {
         self.session.as_deref()
     }
 
-    /// Returns the source connection ID.
+    /// Returns the next address validation token received from the server
+    /// in a NEW_TOKEN frame, if any.
     ///
-    /// Note that the value returned can change throughout the connection's
-    /// lifetime.
+    /// This can be used by a client to cache tokens, and send one of them in
+    /// a later connection to the same server using the [`set_token()`]
+    /// method. Each token should only be used once.
+    ///
+    /// [`set_token()`]: struct.Connection.html#method.set_token
     #[inline]
-    pub fn source_id(&self) -> ConnectionId {
-        ConnectionId::from_ref(self.scid.as_ref())
+    pub fn new_token_next(&mut self) -> Option<Vec<u8>> {
+        self.new_tokens.pop_front()
     }
 
-    /// Returns the destination connection ID.
+    /// Returns the source connection ID.
     ///
     /// Note that the value returned can change throughout the connection's
     /// lifetime.
     #[inline]
-    pub fn destination_id(&self) -> ConnectionId {
-        ConnectionId::from_ref(self.dcid.as_ref())
+    pub fn source_id(&self) -> ConnectionId {
+        let e = match self
+            .paths
+            .get_active()
+            .active_scid_seq
+            .and_then(|seq| self.ids.get_scid(seq).ok())
+        {
+            Some(v) => v,
+
+            None => self.ids.oldest_scid(),
+        };
+
+        ConnectionId::from_ref(e.cid.as_ref())
+    }
+
+    /// Returns the destination connection ID.
+    ///
+    /// Note that the value returned can change throughout the connection's
+    /// lifetime.
+    #[inline]
+    pub fn destination_id(&self) -> ConnectionId {
+        let e = match self
+            .paths
+            .get_active()
+            .active_dcid_seq
+            .and_then(|seq| self.ids.get_dcid(seq).ok())
+        {
+            Some(v) => v,
+
+            None => self.ids.oldest_dcid(),
+        };
+
+        ConnectionId::from_ref(e.cid.as_ref())
+    }
+
+    /// Provides a new source connection ID to the peer, along with its
+    /// stateless reset token.
+    ///
+    /// On success the sequence number of the new connection ID is returned.
+    /// The new connection ID is advertised to the peer in a NEW_CONNECTION_ID
+    /// frame, so [`send()`] should be called afterwards.
+    ///
+    /// The peer limits the number of active connection IDs it accepts, see
+    /// [`source_cids_left()`]. When the limit is reached, [`IdLimit`] is
+    /// returned, unless `retire_if_needed` is true, in which case the peer is
+    /// asked to retire the oldest active connection ID.
+    ///
+    /// Connection IDs that are retired by the peer can then be retrieved with
+    /// [`retired_scid_next()`], so that the application can stop routing
+    /// packets using them to the connection.
+    ///
+    /// Returns [`InvalidState`] if the local endpoint uses zero-length
+    /// connection IDs, or if `scid` was already provided with a different
+    /// reset token.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    /// [`source_cids_left()`]: struct.Connection.html#method.source_cids_left
+    /// [`retired_scid_next()`]: struct.Connection.html#method.retired_scid_next
+    /// [`IdLimit`]: enum.Error.html#variant.IdLimit
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn new_source_cid(
+        &mut self, scid: &ConnectionId, reset_token: u128, retire_if_needed: bool,
+    ) -> Result<u64> {
+        self.ids.new_scid(
+            scid.to_vec().into(),
+            Some(reset_token),
+            true,
+            None,
+            retire_if_needed,
+        )
+    }
+
+    /// Returns the number of source connection IDs that are active, i.e. that
+    /// were not asked to be retired.
+    #[inline]
+    pub fn active_source_cids(&self) -> usize {
+        self.ids.active_source_cids()
+    }
+
+    /// Returns the number of source connection IDs that can still be provided
+    /// to the peer without exceeding its limit.
+    #[inline]
+    pub fn source_cids_left(&self) -> usize {
+        self.ids
+            .source_conn_id_limit()
+            .saturating_sub(self.active_source_cids())
+    }
+
+    /// Returns the next source connection ID retired by the peer, if any.
+    ///
+    /// The application should stop routing packets using the returned
+    /// connection ID to the connection.
+    #[inline]
+    pub fn retired_scid_next(&mut self) -> Option<ConnectionId<'static>> {
+        self.ids.pop_retired_scid()
+    }
+
+    /// Returns the number of destination connection IDs provided by the peer
+    /// that are not used by any path yet.
+    #[inline]
+    pub fn available_dcids(&self) -> usize {
+        self.ids.available_dcids()
+    }
+
+    /// Retires the destination connection ID with the given sequence number.
+    ///
+    /// Paths using the retired connection ID switch to an unused one. If
+    /// none is available, [`OutOfIdentifiers`] is returned and the connection
+    /// ID is not retired.
+    ///
+    /// Returns [`InvalidState`] if the sequence number is unknown, or if the
+    /// peer uses zero-length connection IDs.
+    ///
+    /// [`OutOfIdentifiers`]: enum.Error.html#variant.OutOfIdentifiers
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn retire_destination_cid(&mut self, dcid_seq: u64) -> Result<()> {
+        if self.ids.zero_length_dcid() {
+            return Err(Error::InvalidState);
+        }
+
+        let in_use = self
+            .paths
+            .iter()
+            .any(|(_, p)| p.active_dcid_seq == Some(dcid_seq));
+
+        if in_use && self.ids.available_dcids() == 0 {
+            return Err(Error::OutOfIdentifiers);
+        }
+
+        self.ids.retire_dcid(dcid_seq)?;
+
+        for (pid, p) in self.paths.iter_mut() {
+            if p.active_dcid_seq != Some(dcid_seq) {
+                continue;
+            }
+
+            p.active_dcid_seq = self.ids.lowest_available_dcid_seq();
+
+            if let Some(seq) = p.active_dcid_seq {
+                self.ids.link_dcid_to_path_id(seq, pid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts probing the network path between `local_addr` and `peer_addr`.
+    ///
+    /// A PATH_CHALLENGE frame is sent on the path with the next packets, and
+    /// the outcome of the validation is reported through
+    /// [`path_event_next()`]. Probing an already known path triggers a new
+    /// validation of it.
+    ///
+    /// Only clients can probe new paths, once the handshake is confirmed, and
+    /// if the peer didn't disable active migration. The new path needs an
+    /// unused destination connection ID, unless the peer uses zero-length
+    /// connection IDs.
+    ///
+    /// On success the sequence number of the destination connection ID used
+    /// on the path is returned.
+    ///
+    /// Returns [`InvalidState`] if the connection is not allowed to use new
+    /// paths, and [`OutOfIdentifiers`] if there is no destination connection
+    /// ID available for the path.
+    ///
+    /// [`path_event_next()`]: struct.Connection.html#method.path_event_next
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`OutOfIdentifiers`]: enum.Error.html#variant.OutOfIdentifiers
+    pub fn probe_path(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<u64> {
+        self.probe_path_at(local_addr, peer_addr, time::Instant::now())
+    }
+
+    /// Starts probing the network path between `local_addr` and `peer_addr`,
+    /// at the given time.
+    ///
+    /// This behaves like [`probe_path()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn probe_path_at(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        now: time::Instant,
+    ) -> Result<u64> {
+        if self.peer_transport_params.disable_active_migration {
+            return Err(Error::InvalidState);
+        }
+
+        let pid = self.get_or_create_local_path_id(local_addr, peer_addr, now)?;
+
+        let path = self.paths.get_mut(pid)?;
+
+        path.request_validation();
+
+        path.active_dcid_seq.ok_or(Error::InvalidState)
+    }
+
+    /// Migrates the connection to a new local address `local_addr`, keeping
+    /// the current peer address.
+    ///
+    /// See [`migrate()`] for details.
+    ///
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    pub fn migrate_source(&mut self, local_addr: SocketAddr) -> Result<u64> {
+        let peer_addr = self.paths.get_active().peer_addr();
+
+        self.migrate(local_addr, peer_addr)
+    }
+
+    /// Migrates the connection to the network path between `local_addr` and
+    /// `peer_addr`.
+    ///
+    /// Non-probing packets are sent on the new path right away. If the path
+    /// was not validated before (e.g. using [`probe_path()`]), its validation
+    /// starts as well, and the connection falls back to the previous path in
+    /// case it fails.
+    ///
+    /// Only clients can migrate, once the handshake is confirmed, and if the
+    /// peer didn't disable active migration.
+    ///
+    /// On success the sequence number of the destination connection ID used
+    /// on the path is returned.
+    ///
+    /// Returns [`InvalidState`] if the connection is not allowed to migrate,
+    /// and [`OutOfIdentifiers`] if there is no destination connection ID
+    /// available for the path.
+    ///
+    /// [`probe_path()`]: struct.Connection.html#method.probe_path
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`OutOfIdentifiers`]: enum.Error.html#variant.OutOfIdentifiers
+    pub fn migrate(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<u64> {
+        self.migrate_at(local_addr, peer_addr, time::Instant::now())
+    }
+
+    /// Migrates the connection to the network path between `local_addr` and
+    /// `peer_addr`, at the given time.
+    ///
+    /// This behaves like [`migrate()`], except that the current time is
+    /// provided by the application, like for [`recv_at()`].
+    ///
+    /// [`migrate()`]: struct.Connection.html#method.migrate
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn migrate_at(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        now: time::Instant,
+    ) -> Result<u64> {
+        if self.peer_transport_params.disable_active_migration {
+            return Err(Error::InvalidState);
+        }
+
+        self.migrate_path(local_addr, peer_addr, now)
+    }
+
+    /// Migrates the connection to the given path, creating it if needed.
+    fn migrate_path(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        now: time::Instant,
+    ) -> Result<u64> {
+        let pid = self.get_or_create_local_path_id(local_addr, peer_addr, now)?;
+
+        let old_pid = self.paths.get_active_path_id()?;
+
+        if pid != old_pid {
+            trace!(
+                "{} migrating from path {} to path {}",
+                self.trace_id,
+                old_pid,
+                pid
+            );
+
+            self.paths.set_active_path(pid)?;
+        }
+
+        let path = self.paths.get_mut(pid)?;
+
+        if !path.validated() && !path.under_validation() {
+            path.request_validation();
+        }
+
+        path.active_dcid_seq.ok_or(Error::InvalidState)
+    }
+
+    /// Returns the next path event, if any.
+    ///
+    /// This can be used to learn about the outcome of path validations, and
+    /// about the paths the peer migrated to.
+    #[inline]
+    pub fn path_event_next(&mut self) -> Option<PathEvent> {
+        self.paths.pop_event()
+    }
+
+    /// Returns whether the network path between `local_addr` and `peer_addr`
+    /// has been validated.
+    ///
+    /// Returns [`InvalidState`] if the path is unknown.
+    ///
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    pub fn is_path_validated(
+        &self, local_addr: SocketAddr, peer_addr: SocketAddr,
+    ) -> Result<bool> {
+        let pid = self
+            .paths
+            .path_id_from_addrs(&(local_addr, peer_addr))
+            .ok_or(Error::InvalidState)?;
+
+        Ok(self.paths.get(pid)?.validated())
+    }
+
+    /// Returns an iterator over the peer addresses of the paths using the
+    /// local address `local_addr`.
+    ///
+    /// This can be used along with [`send_on_path()`] to send packets from a
+    /// specific socket.
+    ///
+    /// [`send_on_path()`]: struct.Connection.html#method.send_on_path
+    #[inline]
+    pub fn paths_iter(&self, local_addr: SocketAddr) -> SocketAddrIter {
+        self.paths.peer_addrs_from(local_addr)
     }
 
     /// Returns true if the connection handshake is complete.
@@ -4679,6 +7125,13 @@ impl Connection {
         self.timed_out
     }
 
+    /// Returns true if the connection was closed by a stateless reset
+    /// received from the peer.
+    #[inline]
+    pub fn is_stateless_reset(&self) -> bool {
+        self.stateless_reset
+    }
+
     /// Returns the error received from the peer, if any.
     ///
     /// Note that a `Some` return value does not necessarily imply
@@ -4708,19 +7161,29 @@ impl Connection {
     /// Collects and returns statistics about the connection.
     #[inline]
     pub fn stats(&self) -> Stats {
+        let recovery = &self.paths.get_active().recovery;
+
         Stats {
             recv: self.recv_count,
             sent: self.sent_count,
-            lost: self.recovery.lost_count,
+            lost: self.evicted_lost_count +
+                self.paths
+                    .iter()
+                    .map(|(_, p)| p.recovery.lost_count)
+                    .sum::<usize>(),
             retrans: self.retrans_count,
-            cwnd: self.recovery.cwnd(),
-            rtt: self.recovery.rtt(),
+            cwnd: recovery.cwnd(),
+            rtt: recovery.rtt(),
             sent_bytes: self.sent_bytes,
-            lost_bytes: self.recovery.bytes_lost,
+            lost_bytes: self.evicted_lost_bytes +
+                self.paths
+                    .iter()
+                    .map(|(_, p)| p.recovery.bytes_lost)
+                    .sum::<u64>(),
             recv_bytes: self.recv_bytes,
             stream_retrans_bytes: self.stream_retrans_bytes,
-            pmtu: self.recovery.max_datagram_size(),
-            delivery_rate: self.recovery.delivery_rate(),
+            pmtu: recovery.max_datagram_size(),
+            delivery_rate: recovery.delivery_rate(),
             peer_max_idle_timeout: self.peer_transport_params.max_idle_timeout,
             peer_max_udp_payload_size: self
                 .peer_transport_params
@@ -4757,8 +7220,56 @@ impl Connection {
         }
     }
 
+    /// Advertises the current version and the compatible ones in the
+    /// version_information transport parameter.
+    fn set_version_information(&mut self) {
+        self.local_transport_params.version_information =
+            Some(VersionInformation {
+                chosen_version: self.version,
+                available_versions: available_versions(
+                    self.version,
+                    &self.versions,
+                ),
+            });
+    }
+
+    /// Upgrades the connection to a compatible version, selected by the
+    /// server during the handshake.
+    fn upgrade_version(&mut self, version: u32) -> Result<()> {
+        trace!(
+            "{} upgraded version {:x} to {:x}",
+            self.trace_id,
+            self.version,
+            version
+        );
+
+        self.version = version;
+
+        // The new version's Initial secrets are derived from the same
+        // connection ID the client used.
+        let dcid = self
+            .local_transport_params
+            .retry_source_connection_id
+            .as_ref()
+            .or(self
+                .local_transport_params
+                .original_destination_connection_id
+                .as_ref())
+            .ok_or(Error::InvalidState)?;
+
+        let (aead_open, aead_seal) =
+            crypto::derive_initial_key_material(dcid, version, self.is_server)?;
+
+        self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_open = Some(aead_open);
+        self.pkt_num_spaces[packet::EPOCH_INITIAL].crypto_seal = Some(aead_seal);
+
+        self.set_version_information();
+
+        Ok(())
+    }
+
     fn encode_transport_params(&mut self) -> Result<()> {
-        let mut raw_params = [0; 128];
+        let mut raw_params = [0; 256];
 
         let raw_params = TransportParams::encode(
             &self.local_transport_params,
@@ -4774,12 +7285,10 @@ impl Connection {
     fn parse_peer_transport_params(
         &mut self, peer_params: TransportParams,
     ) -> Result<()> {
-        if self.version >= PROTOCOL_VERSION_DRAFT28 ||
-            self.version == PROTOCOL_VERSION_V1
-        {
+        if version_authenticates_connection_ids(self.version) {
             // Validate initial_source_connection_id.
             match &peer_params.initial_source_connection_id {
-                Some(v) if v != &self.dcid =>
+                Some(v) if v != &self.destination_id() =>
                     return Err(Error::InvalidTransportParam),
 
                 Some(_) => (),
@@ -4829,6 +7338,37 @@ impl Connection {
             }
         }
 
+        // Validate version_information, to prevent version downgrades.
+        if self.is_server {
+            if let Some(vi) = &peer_params.version_information {
+                if vi.chosen_version != self.original_version {
+                    return Err(Error::VersionNegotiation);
+                }
+            }
+        } else {
+            match &peer_params.version_information {
+                Some(vi) if vi.chosen_version != self.version =>
+                    return Err(Error::VersionNegotiation),
+
+                Some(_) => (),
+
+                // version_information must be sent by a server that upgraded
+                // the connection.
+                None if self.version != self.original_version =>
+                    return Err(Error::VersionNegotiation),
+
+                None => (),
+            }
+        }
+
+        // A server using a zero-length connection ID can't provide a
+        // preferred address.
+        if peer_params.preferred_address.is_some() &&
+            self.destination_id().is_empty()
+        {
+            return Err(Error::InvalidTransportParam);
+        }
+
         self.process_peer_transport_params(peer_params);
 
         self.parsed_peer_transport_params = true;
@@ -4847,11 +7387,48 @@ impl Connection {
         self.streams
             .update_peer_max_streams_uni(peer_params.initial_max_streams_uni);
 
-        self.recovery.max_ack_delay =
+        let max_ack_delay =
             time::Duration::from_millis(peer_params.max_ack_delay);
 
-        self.recovery
-            .update_max_datagram_size(peer_params.max_udp_payload_size as usize);
+        let max_datagram_size = peer_params.max_udp_payload_size as usize;
+
+        // Update the configuration used for new paths as well.
+        self.recovery_config.max_ack_delay = max_ack_delay;
+        self.recovery_config.max_send_udp_payload_size = cmp::min(
+            self.recovery_config.max_send_udp_payload_size,
+            max_datagram_size,
+        );
+
+        for (_, p) in self.paths.iter_mut() {
+            p.recovery.max_ack_delay = max_ack_delay;
+            p.recovery.update_max_datagram_size(max_datagram_size);
+        }
+
+        self.ids
+            .set_source_conn_id_limit(peer_params.active_conn_id_limit);
+
+        // The server's stateless reset token applies to the connection ID it
+        // used during the handshake.
+        if !self.is_server {
+            let reset_token = peer_params
+                .stateless_reset_token
+                .as_ref()
+                .and_then(|v| v.as_slice().try_into().ok())
+                .map(u128::from_be_bytes);
+
+            if reset_token.is_some() {
+                let dcid = self.destination_id().into_owned();
+                let pid = self.paths.get_active_path_id().ok();
+
+                self.ids.set_initial_dcid(dcid, reset_token, pid);
+            }
+
+            // The connection ID provided with the preferred address has
+            // sequence number 1.
+            if let Some(ref pa) = peer_params.preferred_address {
+                self.ids.new_dcid(pa.cid.clone(), 1, pa.reset_token, 0).ok();
+            }
+        }
 
         self.peer_transport_params = peer_params;
     }
@@ -4874,13 +7451,31 @@ impl Connection {
             trace_id: &self.trace_id,
 
             is_server: self.is_server,
+
+            version: self.version,
+
+            original_version: self.original_version,
+
+            versions: &self.versions,
+
+            local_transport_params: &self.local_transport_params,
         };
 
         if self.handshake_completed {
             return self.handshake.process_post_handshake(&mut ex_data);
         }
 
-        match self.handshake.do_handshake(&mut ex_data) {
+        let res = self.handshake.do_handshake(&mut ex_data);
+
+        // The server might have upgraded the connection to a compatible
+        // version while processing the client's transport parameters.
+        let version = ex_data.version;
+
+        if version != self.version {
+            self.upgrade_version(version)?;
+        }
+
+        match res {
             Ok(_) => (),
 
             Err(Error::Done) => {
@@ -4937,7 +7532,7 @@ impl Connection {
     }
 
     /// Selects the packet type for the next outgoing packet.
-    fn write_pkt_type(&self) -> Result<packet::Type> {
+    fn write_pkt_type(&self, send_pid: usize) -> Result<packet::Type> {
         // On error send packet in the latest epoch available, but only send
         // 1-RTT ones when the handshake is completed.
         if self
@@ -4973,20 +7568,33 @@ impl Connection {
             }
 
             // There are lost frames in this packet number space.
-            if !self.recovery.lost[epoch].is_empty() {
-                return Ok(packet::Type::from_epoch(epoch));
+            for (_, p) in self.paths.iter() {
+                if !p.recovery.lost[epoch].is_empty() {
+                    return Ok(packet::Type::from_epoch(epoch));
+                }
             }
 
             // We need to send PTO probe packets.
-            if self.recovery.loss_probes[epoch] > 0 {
+            if self.paths.get(send_pid)?.recovery.loss_probes[epoch] > 0 {
                 return Ok(packet::Type::from_epoch(epoch));
             }
         }
 
+        // If there are pending connection IDs or path probing frames to
+        // send, use the Application epoch.
+        if self.is_established() &&
+            (self.ids.has_new_scids() ||
+                self.ids.has_retire_dcids() ||
+                self.paths.get(send_pid)?.probing_required())
+        {
+            return Ok(packet::Type::Short);
+        }
+
         // If there are flushable, almost full or blocked streams, use the
         // Application epoch.
         if (self.is_established() || self.is_in_early_data()) &&
             (self.should_send_handshake_done() ||
+                self.should_send_new_token() ||
                 self.almost_full ||
                 self.blocked_limit.is_some() ||
                 self.dgram_send_queue.has_pending() ||
@@ -5028,7 +7636,8 @@ impl Connection {
 
     /// Processes an incoming frame.
     fn process_frame(
-        &mut self, frame: frame::Frame, epoch: packet::Epoch, now: time::Instant,
+        &mut self, frame: frame::Frame, hdr: &Header, recv_pid: usize,
+        epoch: packet::Epoch, now: time::Instant,
     ) -> Result<()> {
         trace!("{} rx frm {:?}", self.trace_id, frame);
 
@@ -5038,7 +7647,9 @@ impl Connection {
             frame::Frame::Ping => (),
 
             frame::Frame::ACK {
-                ranges, ack_delay, ..
+                ranges,
+                ack_delay,
+                ecn_counts,
             } => {
                 let ack_delay = ack_delay
                     .checked_mul(2_u64.pow(
@@ -5058,18 +7669,49 @@ impl Connection {
                     self.handshake_confirmed = true;
                 }
 
-                if self.delivery_rate_check_if_app_limited() {
-                    self.recovery.delivery_rate_update_app_limited(true);
+                // If the largest packet number acked exceeds any packet number
+                // we have sent, then the ACK is obviously invalid, so there's
+                // no need to continue further.
+                if let Some(largest_acked) = ranges.last() {
+                    if largest_acked >= self.pkt_num_spaces[epoch].next_pkt_num {
+                        if cfg!(feature = "fuzzing") {
+                            return Ok(());
+                        }
+
+                        return Err(Error::InvalidPacket);
+                    }
+
+                    let space = &mut self.pkt_num_spaces[epoch];
+
+                    if epoch == packet::EPOCH_APPLICATION &&
+                        largest_acked >= space.key_phase_start_pn
+                    {
+                        space.key_phase_acked = true;
+                    }
                 }
 
-                self.recovery.on_ack_received(
-                    &ranges,
-                    ack_delay,
-                    epoch,
-                    self.handshake_status(),
-                    now,
-                    &self.trace_id,
-                )?;
+                let delivery_rate_app_limited =
+                    self.delivery_rate_check_if_app_limited();
+
+                let handshake_status = self.handshake_status();
+
+                // Packet numbers are shared among paths, so the ACK can
+                // acknowledge packets sent on any of them.
+                for (_, p) in self.paths.iter_mut() {
+                    if delivery_rate_app_limited {
+                        p.recovery.delivery_rate_update_app_limited(true);
+                    }
+
+                    p.recovery.on_ack_received(
+                        &ranges,
+                        ack_delay,
+                        ecn_counts.as_ref(),
+                        epoch,
+                        handshake_status,
+                        now,
+                        &self.trace_id,
+                    )?;
+                }
 
                 // Once the handshake is confirmed, we can drop Handshake keys.
                 if self.handshake_confirmed {
@@ -5198,7 +7840,21 @@ impl Connection {
             frame::Frame::CryptoHeader { .. } => unreachable!(),
 
             // TODO: implement stateless retry
-            frame::Frame::NewToken { .. } => (),
+            frame::Frame::NewToken { token } => {
+                if self.is_server {
+                    return Err(Error::InvalidPacket);
+                }
+
+                if token.is_empty() {
+                    return Err(Error::InvalidFrame);
+                }
+
+                if self.new_tokens.len() >= MAX_NEW_TOKENS {
+                    self.new_tokens.pop_front();
+                }
+
+                self.new_tokens.push_back(token);
+            },
 
             frame::Frame::Stream { stream_id, data } => {
                 // Peer can't send on our unidirectional streams.
@@ -5328,17 +7984,67 @@ impl Connection {
                     return Err(Error::InvalidFrame);
                 },
 
-            // TODO: implement connection migration
-            frame::Frame::NewConnectionId { .. } => (),
+            frame::Frame::NewConnectionId {
+                seq_num,
+                retire_prior_to,
+                conn_id,
+                reset_token,
+            } => {
+                // An endpoint using a zero-length connection ID can't be sent
+                // new ones.
+                if self.ids.zero_length_dcid() {
+                    return Err(Error::InvalidState);
+                }
+
+                let retired = self.ids.new_dcid(
+                    conn_id.into(),
+                    seq_num,
+                    u128::from_be_bytes(reset_token),
+                    retire_prior_to,
+                )?;
+
+                // Paths using a retired connection ID need to switch to a new
+                // one, if available.
+                for (retired_seq, _) in retired {
+                    for (pid, p) in self.paths.iter_mut() {
+                        if p.active_dcid_seq != Some(retired_seq) {
+                            continue;
+                        }
+
+                        p.active_dcid_seq = self.ids.lowest_available_dcid_seq();
+
+                        if let Some(dcid_seq) = p.active_dcid_seq {
+                            self.ids.link_dcid_to_path_id(dcid_seq, pid)?;
+                        }
+                    }
+                }
+            },
+
+            frame::Frame::RetireConnectionId { seq_num } => {
+                // An endpoint using a zero-length connection ID can't be asked
+                // to retire one.
+                if self.ids.zero_length_scid() {
+                    return Err(Error::InvalidState);
+                }
+
+                self.ids.retire_scid(seq_num, &hdr.dcid)?;
 
-            // TODO: implement connection migration
-            frame::Frame::RetireConnectionId { .. } => (),
+                for (_, p) in self.paths.iter_mut() {
+                    if p.active_scid_seq == Some(seq_num) {
+                        p.active_scid_seq = None;
+                    }
+                }
+            },
 
             frame::Frame::PathChallenge { data } => {
-                self.challenge = Some(data);
+                self.paths.get_mut(recv_pid)?.on_challenge_received(data);
             },
 
-            frame::Frame::PathResponse { .. } => (),
+            frame::Frame::PathResponse { data } => {
+                if let Some(pid) = self.paths.on_response_received(data) {
+                    trace!("{} path {} validated", self.trace_id, pid);
+                }
+            },
 
             frame::Frame::ConnectionClose {
                 error_code, reason, ..
@@ -5348,7 +8054,24 @@ impl Connection {
                     error_code,
                     reason,
                 });
-                self.draining_timer = Some(now + (self.recovery.pto() * 3));
+
+                #[cfg(feature = "qlog")]
+                {
+                    let trigger = if error_code == 0 {
+                        ConnectionClosedTrigger::Clean
+                    } else {
+                        ConnectionClosedTrigger::Error
+                    };
+
+                    self.qlog_connection_closed(
+                        TransportOwner::Remote,
+                        trigger,
+                        now,
+                    );
+                }
+
+                let pto = self.paths.get_active().recovery.pto();
+                self.draining_timer = Some(now + (pto * 3));
             },
 
             frame::Frame::ApplicationClose { error_code, reason } => {
@@ -5357,7 +8080,16 @@ impl Connection {
                     error_code,
                     reason,
                 });
-                self.draining_timer = Some(now + (self.recovery.pto() * 3));
+
+                #[cfg(feature = "qlog")]
+                self.qlog_connection_closed(
+                    TransportOwner::Remote,
+                    ConnectionClosedTrigger::Application,
+                    now,
+                );
+
+                let pto = self.paths.get_active().recovery.pto();
+                self.draining_timer = Some(now + (pto * 3));
             },
 
             frame::Frame::HandshakeDone => {
@@ -5373,17 +8105,54 @@ impl Connection {
                 self.drop_epoch_state(packet::EPOCH_HANDSHAKE, now);
             },
 
-            frame::Frame::Datagram { data } => {
-                // Close the connection if DATAGRAMs are not enabled.
-                // quiche always advertises support for 64K sized DATAGRAM
-                // frames, as recommended by the standard, so we don't need a
-                // size check.
-                if !self.dgram_enabled() {
+            frame::Frame::AckFrequency {
+                seq_num,
+                ack_eliciting_threshold,
+                request_max_ack_delay,
+                reordering_threshold,
+            } => {
+                // Only endpoints advertising min_ack_delay accept changes to
+                // their ACK frequency.
+                let min_ack_delay =
+                    match self.local_transport_params.min_ack_delay {
+                        Some(v) => v,
+
+                        None => return Err(Error::InvalidState),
+                    };
+
+                if request_max_ack_delay < min_ack_delay {
                     return Err(Error::InvalidState);
                 }
 
-                // If recv queue is full, discard oldest
-                if self.dgram_recv_queue.is_full() {
+                self.ack_freq
+                    .on_frame_received(seq_num, ack_freq::AckPolicy {
+                        ack_eliciting_threshold,
+                        max_ack_delay: time::Duration::from_micros(
+                            request_max_ack_delay,
+                        ),
+                        reordering_threshold,
+                    });
+            },
+
+            frame::Frame::ImmediateAck => {
+                if self.local_transport_params.min_ack_delay.is_none() {
+                    return Err(Error::InvalidState);
+                }
+
+                self.pkt_num_spaces[epoch].ack_elicited = true;
+            },
+
+            frame::Frame::Datagram { data } => {
+                // Close the connection if DATAGRAMs are not enabled.
+                // quiche always advertises support for 64K sized DATAGRAM
+                // frames, as recommended by the standard, so we don't need a
+                // size check.
+                if !self.dgram_enabled() {
+                    return Err(Error::InvalidState);
+                }
+
+                // If recv queue is full, discard oldest
+                if self.dgram_recv_queue.is_full() {
                     self.dgram_recv_queue.pop();
                 }
 
@@ -5396,6 +8165,190 @@ impl Connection {
         Ok(())
     }
 
+    /// Returns true if the packet count limit for the current 1-RTT keys has
+    /// been reached and a key update can be initiated.
+    fn key_update_needed(&self) -> bool {
+        let space = &self.pkt_num_spaces[packet::EPOCH_APPLICATION];
+
+        let limit = match space.crypto_seal {
+            Some(ref seal) => cmp::min(
+                self.max_packets_per_key,
+                seal.alg().confidentiality_limit(),
+            ),
+
+            None => return false,
+        };
+
+        // A new key update can't be initiated before the handshake is
+        // confirmed, or before a packet sent with the current keys is acked.
+        self.handshake_confirmed &&
+            space.key_phase_acked &&
+            space.next_pkt_num - space.key_phase_start_pn >= limit
+    }
+
+    /// Initiates a 1-RTT key update.
+    fn initiate_key_update(&mut self, now: time::Instant) -> Result<()> {
+        let space = &self.pkt_num_spaces[packet::EPOCH_APPLICATION];
+
+        let open_next = space
+            .crypto_open
+            .as_ref()
+            .ok_or(Error::InvalidState)?
+            .derive_next_packet_key()?;
+
+        let seal_next = space
+            .crypto_seal
+            .as_ref()
+            .ok_or(Error::InvalidState)?
+            .derive_next_packet_key()?;
+
+        trace!("{} initiated key update", self.trace_id);
+
+        self.update_keys(open_next, seal_next, now);
+
+        #[cfg(feature = "qlog")]
+        self.qlog_key_updated(KeyUpdateOrRetiredTrigger::LocalUpdate, now);
+
+        Ok(())
+    }
+
+    /// Installs the next generation 1-RTT keys and switches key phase.
+    ///
+    /// The previous decryption keys are retained for three times the PTO so
+    /// that delayed packets can still be processed.
+    fn update_keys(
+        &mut self, open_next: crypto::Open, seal_next: crypto::Seal,
+        now: time::Instant,
+    ) {
+        let pto = self.paths.get_active().recovery.pto();
+
+        let space = &mut self.pkt_num_spaces[packet::EPOCH_APPLICATION];
+
+        space.key_update =
+            space.crypto_open.replace(open_next).map(|crypto_open| {
+                packet::KeyUpdate {
+                    crypto_open,
+                    pn_on_update: std::u64::MAX,
+                    timer: now + pto * 3,
+                }
+            });
+
+        space.crypto_seal = Some(seal_next);
+
+        space.key_phase_start_pn = space.next_pkt_num;
+        space.key_phase_acked = false;
+
+        self.key_phase = !self.key_phase;
+    }
+
+    /// Logs the 1-RTT keys installed by a key update.
+    ///
+    /// The key material itself is never logged.
+    #[cfg(feature = "qlog")]
+    fn qlog_key_updated(
+        &mut self, trigger: KeyUpdateOrRetiredTrigger, now: time::Instant,
+    ) {
+        use qlog::events::security::KeyType;
+
+        self.qlog.key_generation += 1;
+
+        let generation = self.qlog.key_generation;
+
+        qlog_with_type!(QLOG_KEY_UPDATED, self.qlog, q, {
+            for key_type in [KeyType::Server1RttSecret, KeyType::Client1RttSecret]
+            {
+                let ev_data =
+                    EventData::KeyUpdated(qlog::events::security::KeyUpdated {
+                        key_type,
+                        old: None,
+                        new: None,
+                        generation: Some(generation),
+                        trigger: Some(trigger.clone()),
+                    });
+
+                q.add_event_data_with_instant(ev_data, now).ok();
+            }
+        });
+    }
+
+    /// Logs a packet discarded by `recv()`.
+    #[cfg(feature = "qlog")]
+    fn qlog_packet_dropped(
+        &mut self, header: Option<qlog::events::quic::PacketHeader>, len: usize,
+        trigger: PacketDroppedTrigger, now: time::Instant,
+    ) {
+        qlog_with_type!(QLOG_PACKET_DROPPED, self.qlog, q, {
+            let ev_data =
+                EventData::PacketDropped(qlog::events::quic::PacketDropped {
+                    header,
+                    raw: Some(RawInfo {
+                        length: Some(len as u64),
+                        payload_length: None,
+                        data: None,
+                    }),
+                    datagram_id: None,
+                    trigger: Some(trigger),
+                });
+
+            q.add_event_data_with_instant(ev_data, now).ok();
+        });
+    }
+
+    /// Logs the closing of the connection by the given endpoint.
+    #[cfg(feature = "qlog")]
+    fn qlog_connection_closed(
+        &mut self, owner: TransportOwner, trigger: ConnectionClosedTrigger,
+        now: time::Instant,
+    ) {
+        use qlog::events::ApplicationErrorCode;
+        use qlog::events::ConnectionErrorCode;
+
+        let err = match owner {
+            TransportOwner::Local => self.local_error.as_ref(),
+
+            TransportOwner::Remote => self.peer_error.as_ref(),
+        };
+
+        let (connection_code, application_code, reason) = match err {
+            Some(e) if e.is_app => (
+                None,
+                Some(ApplicationErrorCode::Value(e.error_code)),
+                Some(String::from_utf8_lossy(&e.reason).into_owned()),
+            ),
+
+            Some(e) => (
+                Some(ConnectionErrorCode::Value(e.error_code)),
+                None,
+                Some(String::from_utf8_lossy(&e.reason).into_owned()),
+            ),
+
+            None => (None, None, None),
+        };
+
+        qlog_with_type!(QLOG_CONNECTION_CLOSED, self.qlog, q, {
+            let ev_data = EventData::ConnectionClosed(
+                qlog::events::connectivity::ConnectionClosed {
+                    owner: Some(owner),
+                    connection_code,
+                    application_code,
+                    internal_code: None,
+                    reason,
+                    trigger: Some(trigger),
+                },
+            );
+
+            q.add_event_data_with_instant(ev_data, now).ok();
+        });
+    }
+
+    /// Writes the pending recovery events of all paths.
+    #[cfg(feature = "qlog")]
+    fn qlog_recovery_events(&mut self, now: time::Instant) {
+        for (_, p) in self.paths.iter_mut() {
+            self.qlog.add_events(p.recovery.take_qlog_events(), now);
+        }
+    }
+
     /// Drops the keys and recovery state for the given epoch.
     fn drop_epoch_state(&mut self, epoch: packet::Epoch, now: time::Instant) {
         if self.pkt_num_spaces[epoch].crypto_open.is_none() {
@@ -5406,13 +8359,345 @@ impl Connection {
         self.pkt_num_spaces[epoch].crypto_seal = None;
         self.pkt_num_spaces[epoch].clear();
 
-        self.recovery.on_pkt_num_space_discarded(
-            epoch,
-            self.handshake_status(),
+        let handshake_status = self.handshake_status();
+
+        for (_, p) in self.paths.iter_mut() {
+            p.recovery
+                .on_pkt_num_space_discarded(epoch, handshake_status, now);
+        }
+
+        trace!("{} dropped epoch {} state", self.trace_id, epoch);
+    }
+
+    /// Returns the identifier of the path the packet was received on.
+    ///
+    /// If the packet was received from an unknown address, a new path is
+    /// created when allowed, and its validation is requested.
+    fn get_or_create_recv_path_id(
+        &mut self, recv_scid_seq: Option<u64>, buf_len: usize, info: &RecvInfo,
+        now: time::Instant,
+    ) -> Result<usize> {
+        if let Some(pid) = self.paths.path_id_from_addrs(&(info.to, info.from)) {
+            // Reply using the connection ID the peer is currently using on the
+            // path, if any.
+            if let Some(seq) = recv_scid_seq {
+                self.paths.get_mut(pid)?.active_scid_seq = Some(seq);
+            }
+
+            return Ok(pid);
+        }
+
+        // Only the client can migrate, so clients ignore packets received on
+        // unknown paths.
+        if !self.is_server {
+            trace!(
+                "{} dropped packet on unknown path {} -> {}",
+                self.trace_id,
+                info.from,
+                info.to
+            );
+
+            return Err(Error::Done);
+        }
+
+        // The client is not allowed to migrate before the handshake is
+        // confirmed, or when we asked it not to. Migrating to our preferred
+        // address is always allowed, and the client can do it as soon as it
+        // receives HANDSHAKE_DONE, which might be before the handshake is
+        // confirmed on our side.
+        let to_preferred_address =
+            match self.local_transport_params.preferred_address {
+                Some(ref pa) => pa.contains(&info.to),
+
+                None => false,
+            };
+
+        let allowed = if to_preferred_address {
+            self.handshake_completed
+        } else {
+            self.handshake_confirmed &&
+                !self.local_transport_params.disable_active_migration
+        };
+
+        if !allowed {
+            trace!(
+                "{} dropped packet from unexpected address {}",
+                self.trace_id,
+                info.from
+            );
+
+            return Err(Error::Done);
+        }
+
+        let mut path = path::Path::new(
+            info.to,
+            info.from,
+            &self.recovery_config,
+            false,
             now,
         );
 
-        trace!("{} dropped epoch {} state", self.trace_id, epoch);
+        // Until the new path is validated, only allow sending up to three
+        // times the amount of data received on it.
+        path.max_send_bytes = buf_len * MAX_AMPLIFICATION_FACTOR;
+        path.active_scid_seq = recv_scid_seq;
+
+        path.request_validation();
+
+        let (pid, evicted) = self.paths.insert_path(path);
+
+        if let Some((evicted_pid, evicted_path)) = evicted {
+            self.on_path_evicted(evicted_pid, evicted_path);
+        }
+
+        // Use a new connection ID on the new path if the peer provided any,
+        // otherwise keep using the one of the active path, which is allowed
+        // as the peer is the one that changed address.
+        let dcid_seq = if self.ids.zero_length_dcid() {
+            Some(0)
+        } else if let Some(seq) = self.ids.lowest_available_dcid_seq() {
+            self.ids.link_dcid_to_path_id(seq, pid)?;
+
+            Some(seq)
+        } else {
+            self.paths.get_active().active_dcid_seq
+        };
+
+        self.paths.get_mut(pid)?.active_dcid_seq = dcid_seq;
+
+        if let Some(seq) = recv_scid_seq {
+            self.ids.link_scid_to_path_id(seq, pid)?;
+        }
+
+        self.paths
+            .notify_event(path::PathEvent::New(info.to, info.from));
+
+        trace!(
+            "{} created path {} {} -> {}",
+            self.trace_id,
+            pid,
+            info.to,
+            info.from
+        );
+
+        Ok(pid)
+    }
+
+    /// Returns the identifier of the path between `local_addr` and
+    /// `peer_addr`, creating it if needed on behalf of the application.
+    fn get_or_create_local_path_id(
+        &mut self, local_addr: SocketAddr, peer_addr: SocketAddr,
+        now: time::Instant,
+    ) -> Result<usize> {
+        // Only clients can use new paths, and only once the handshake is
+        // confirmed.
+        if self.is_server || !self.handshake_confirmed {
+            return Err(Error::InvalidState);
+        }
+
+        if let Some(pid) = self.paths.path_id_from_addrs(&(local_addr, peer_addr))
+        {
+            return Ok(pid);
+        }
+
+        // The new path needs its own connection ID, to avoid linking it with
+        // the existing ones.
+        let dcid_seq = if self.ids.zero_length_dcid() {
+            0
+        } else {
+            self.ids
+                .lowest_available_dcid_seq()
+                .ok_or(Error::OutOfIdentifiers)?
+        };
+
+        let mut path = path::Path::new(
+            local_addr,
+            peer_addr,
+            &self.recovery_config,
+            false,
+            now,
+        );
+
+        // Only servers are limited by the anti-amplification limit.
+        path.verified_peer_address = true;
+        path.active_dcid_seq = Some(dcid_seq);
+
+        let (pid, evicted) = self.paths.insert_path(path);
+
+        if let Some((evicted_pid, evicted_path)) = evicted {
+            self.on_path_evicted(evicted_pid, evicted_path);
+        }
+
+        if !self.ids.zero_length_dcid() {
+            self.ids.link_dcid_to_path_id(dcid_seq, pid)?;
+        }
+
+        trace!(
+            "{} created path {} {} -> {}",
+            self.trace_id,
+            pid,
+            local_addr,
+            peer_addr
+        );
+
+        Ok(pid)
+    }
+
+    /// Migrates the client to the server's preferred address, if any, once
+    /// the handshake is confirmed.
+    fn migrate_to_preferred_address(&mut self, now: time::Instant) {
+        if self.is_server ||
+            !self.handshake_confirmed ||
+            self.preferred_address_used ||
+            self.is_closed() ||
+            self.is_draining()
+        {
+            return;
+        }
+
+        let local_addr = self.paths.get_active().local_addr();
+
+        let peer_addr = match self
+            .peer_transport_params
+            .preferred_address
+            .as_ref()
+            .and_then(|pa| pa.matching(&local_addr))
+        {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        self.preferred_address_used = true;
+
+        match self.migrate_path(local_addr, peer_addr, now) {
+            Ok(_) => trace!(
+                "{} migrating to preferred address {}",
+                self.trace_id,
+                peer_addr
+            ),
+
+            Err(e) => trace!(
+                "{} failed to migrate to preferred address {}: {:?}",
+                self.trace_id,
+                peer_addr,
+                e
+            ),
+        }
+    }
+
+    /// Releases the resources of a path that was removed from the path map.
+    fn on_path_evicted(&mut self, pid: usize, path: path::Path) {
+        trace!(
+            "{} evicted path {} to {}",
+            self.trace_id,
+            pid,
+            path.peer_addr()
+        );
+
+        self.paths.notify_event(path::PathEvent::Closed(
+            path.local_addr(),
+            path.peer_addr(),
+        ));
+
+        // Keep track of the losses that happened on the path.
+        self.evicted_lost_count += path.recovery.lost_count;
+        self.evicted_lost_bytes += path.recovery.bytes_lost;
+
+        // Make the connection ID used by the path available again, unless
+        // another path is still using it.
+        if let Some(seq) = path.active_dcid_seq {
+            if !self
+                .paths
+                .iter()
+                .any(|(_, p)| p.active_dcid_seq == Some(seq))
+            {
+                self.ids.unlink_dcid(seq);
+            }
+        }
+    }
+
+    /// Returns the identifier of the path to send the next packet on, among
+    /// the ones matching the given addresses.
+    ///
+    /// Non-active paths are only selected when they need to send probing
+    /// frames, and can send enough data to do so.
+    fn get_send_path_id(
+        &self, from: Option<SocketAddr>, to: Option<SocketAddr>, max_len: usize,
+    ) -> Result<usize> {
+        let matches = |p: &path::Path| {
+            (from.is_none() || from == Some(p.local_addr())) &&
+                (to.is_none() || to == Some(p.peer_addr()))
+        };
+
+        if !self.paths.iter().any(|(_, p)| matches(p)) {
+            return Err(Error::InvalidState);
+        }
+
+        if self.is_established() {
+            for (pid, p) in self.paths.iter() {
+                if p.active() || !p.probing_required() || !matches(p) {
+                    continue;
+                }
+
+                if p.active_dcid_seq.is_none() {
+                    continue;
+                }
+
+                let left = if p.verified_peer_address {
+                    max_len
+                } else {
+                    cmp::min(max_len, p.max_send_bytes)
+                };
+
+                if left >= PAYLOAD_MIN_LEN + packet::MAX_PKT_NUM_LEN {
+                    return Ok(pid);
+                }
+            }
+        }
+
+        let active_pid = self.paths.get_active_path_id()?;
+
+        if !matches(self.paths.get(active_pid)?) {
+            return Err(Error::Done);
+        }
+
+        Ok(active_pid)
+    }
+
+    /// Switches the active path to the one the peer migrated to.
+    fn on_peer_migrated(&mut self, new_pid: usize) -> Result<()> {
+        let old_pid = self.paths.get_active_path_id()?;
+
+        trace!(
+            "{} peer migrated from path {} to path {}",
+            self.trace_id,
+            old_pid,
+            new_pid
+        );
+
+        self.paths.set_active_path(new_pid)?;
+
+        let new_path = self.paths.get_mut(new_pid)?;
+
+        let event = path::PathEvent::PeerMigrated(
+            new_path.local_addr(),
+            new_path.peer_addr(),
+        );
+
+        // The new path starts with a fresh congestion controller, which was
+        // created along with the path. Make sure the path gets validated, if
+        // it isn't already.
+        //
+        // If the validation fails, the connection falls back to the previous
+        // path, see `on_timeout()`.
+        if !new_path.validated() && !new_path.under_validation() {
+            new_path.request_validation();
+        }
+
+        self.paths.notify_event(event);
+
+        Ok(())
     }
 
     /// Returns true if the connection-level flow control needs to be updated.
@@ -5438,6 +8723,46 @@ impl Connection {
         self.is_established() && !self.handshake_done_sent && self.is_server
     }
 
+    /// Requests an ACK frequency from the peer based on the congestion window
+    /// of the given path, if the peer supports it.
+    fn update_ack_frequency(&mut self, send_pid: usize) -> Result<()> {
+        let min_ack_delay = match self.peer_transport_params.min_ack_delay {
+            Some(v) => time::Duration::from_micros(v),
+
+            None => return Ok(()),
+        };
+
+        let recovery = &self.paths.get(send_pid)?.recovery;
+
+        let policy = ack_freq::AckPolicy::from_cwnd(
+            recovery.cwnd(),
+            recovery.max_datagram_size(),
+            recovery.rtt(),
+            min_ack_delay,
+            time::Duration::from_millis(self.peer_transport_params.max_ack_delay),
+        );
+
+        // Only update the peer when the threshold changes, so that RTT
+        // variations alone don't trigger new frames.
+        let requested = self.ack_freq.requested();
+
+        if requested.map(|p| p.ack_eliciting_threshold) !=
+            Some(policy.ack_eliciting_threshold)
+        {
+            self.ack_freq.request(policy);
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if a NEW_TOKEN frame needs to be sent.
+    fn should_send_new_token(&self) -> bool {
+        self.is_server &&
+            self.handshake_confirmed &&
+            !self.new_token_sent &&
+            self.address_token_key.is_some()
+    }
+
     /// Returns the idle timeout value.
     ///
     /// `None` is returned if both end-points disabled the idle timeout.
@@ -5465,7 +8790,8 @@ impl Connection {
         };
 
         let idle_timeout = time::Duration::from_millis(idle_timeout);
-        let idle_timeout = cmp::max(idle_timeout, 3 * self.recovery.pto());
+        let idle_timeout =
+            cmp::max(idle_timeout, 3 * self.paths.get_active().recovery.pto());
 
         Some(idle_timeout)
     }
@@ -5485,7 +8811,7 @@ impl Connection {
     /// Updates send capacity.
     fn update_tx_cap(&mut self) {
         self.tx_cap = cmp::min(
-            self.recovery.cwnd_available() as u64,
+            self.paths.get_active().recovery.cwnd_available() as u64,
             self.max_tx_data - self.tx_data,
         ) as usize;
     }
@@ -5506,10 +8832,12 @@ impl Connection {
         // Note that this is equivalent to CheckIfApplicationLimited() from the
         // delivery rate draft. This is also separate from `recovery.app_limited`
         // and only applies to delivery rate calculation.
-        self.tx_cap >= self.recovery.cwnd_available() &&
+        let cwin_available = self.paths.get_active().recovery.cwnd_available();
+
+        self.tx_cap >= cwin_available &&
             (self.tx_data.saturating_sub(self.last_tx_data)) <
-                self.recovery.cwnd_available() as u64 &&
-            self.recovery.cwnd_available() > 0
+                cwin_available as u64 &&
+            cwin_available > 0
     }
 }
 
@@ -5730,11 +9058,20 @@ struct TransportParams {
     pub ack_delay_exponent: u64,
     pub max_ack_delay: u64,
     pub disable_active_migration: bool,
-    // pub preferred_address: ...,
+    pub preferred_address: Option<PreferredAddress>,
     pub active_conn_id_limit: u64,
     pub initial_source_connection_id: Option<ConnectionId<'static>>,
     pub retry_source_connection_id: Option<ConnectionId<'static>>,
     pub max_datagram_frame_size: Option<u64>,
+    pub version_information: Option<VersionInformation>,
+    pub min_ack_delay: Option<u64>,
+}
+
+/// The version_information transport parameter (RFC 9368).
+#[derive(Clone, Debug, PartialEq)]
+struct VersionInformation {
+    pub chosen_version: u32,
+    pub available_versions: Vec<u32>,
 }
 
 impl Default for TransportParams {
@@ -5753,10 +9090,13 @@ impl Default for TransportParams {
             ack_delay_exponent: 3,
             max_ack_delay: 25,
             disable_active_migration: false,
+            preferred_address: None,
             active_conn_id_limit: 2,
             initial_source_connection_id: None,
             retry_source_connection_id: None,
             max_datagram_frame_size: None,
+            version_information: None,
+            min_ack_delay: None,
         }
     }
 }
@@ -5869,8 +9209,48 @@ impl TransportParams {
                         return Err(Error::InvalidTransportParam);
                     }
 
-                    // TODO: decode preferred_address
-                },
+                    let mut ip_v4 = [0; 4];
+                    ip_v4.copy_from_slice(val.get_bytes(4)?.buf());
+                    let ip_v4 = Ipv4Addr::from(ip_v4);
+                    let port_v4 = val.get_u16()?;
+
+                    let mut ip_v6 = [0; 16];
+                    ip_v6.copy_from_slice(val.get_bytes(16)?.buf());
+                    let ip_v6 = Ipv6Addr::from(ip_v6);
+                    let port_v6 = val.get_u16()?;
+
+                    let cid_len = val.get_u8()? as usize;
+
+                    if cid_len == 0 || cid_len > MAX_CONN_ID_LEN {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let cid = val.get_bytes(cid_len)?.to_vec().into();
+
+                    let mut reset_token = [0; 16];
+                    reset_token.copy_from_slice(val.get_bytes(16)?.buf());
+
+                    // An all-zero address and port means the server has no
+                    // preferred address of that family.
+                    let ipv4 = if ip_v4.is_unspecified() && port_v4 == 0 {
+                        None
+                    } else {
+                        Some(SocketAddrV4::new(ip_v4, port_v4))
+                    };
+
+                    let ipv6 = if ip_v6.is_unspecified() && port_v6 == 0 {
+                        None
+                    } else {
+                        Some(SocketAddrV6::new(ip_v6, port_v6, 0, 0))
+                    };
+
+                    tp.preferred_address = Some(PreferredAddress {
+                        ipv4,
+                        ipv6,
+                        cid,
+                        reset_token: u128::from_be_bytes(reset_token),
+                    });
+                },
 
                 0x000e => {
                     let limit = val.get_varint()?;
@@ -5894,15 +9274,44 @@ impl TransportParams {
                     tp.retry_source_connection_id = Some(val.to_vec().into());
                 },
 
+                0x0011 => {
+                    let chosen_version = val.get_u32()?;
+
+                    if chosen_version == 0 {
+                        return Err(Error::InvalidTransportParam);
+                    }
+
+                    let mut available_versions = Vec::new();
+
+                    while val.cap() > 0 {
+                        available_versions.push(val.get_u32()?);
+                    }
+
+                    tp.version_information = Some(VersionInformation {
+                        chosen_version,
+                        available_versions,
+                    });
+                },
+
                 0x0020 => {
                     tp.max_datagram_frame_size = Some(val.get_varint()?);
                 },
 
+                0xff04_de1b => {
+                    tp.min_ack_delay = Some(val.get_varint()?);
+                },
+
                 // Ignore unknown parameters.
                 _ => (),
             }
         }
 
+        if let Some(min_ack_delay) = tp.min_ack_delay {
+            if min_ack_delay > tp.max_ack_delay * 1000 {
+                return Err(Error::InvalidTransportParam);
+            }
+        }
+
         Ok(tp)
     }
 
@@ -6028,7 +9437,31 @@ impl TransportParams {
             TransportParams::encode_param(&mut b, 0x000c, 0)?;
         }
 
-        // TODO: encode preferred_address
+        if is_server {
+            if let Some(ref pa) = tp.preferred_address {
+                TransportParams::encode_param(
+                    &mut b,
+                    0x000d,
+                    4 + 2 + 16 + 2 + 1 + pa.cid.len() + 16,
+                )?;
+
+                let ipv4 = pa.ipv4.unwrap_or_else(|| {
+                    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+                });
+                b.put_bytes(&ipv4.ip().octets())?;
+                b.put_u16(ipv4.port())?;
+
+                let ipv6 = pa.ipv6.unwrap_or_else(|| {
+                    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)
+                });
+                b.put_bytes(&ipv6.ip().octets())?;
+                b.put_u16(ipv6.port())?;
+
+                b.put_u8(pa.cid.len() as u8)?;
+                b.put_bytes(&pa.cid)?;
+                b.put_bytes(&pa.reset_token.to_be_bytes())?;
+            }
+        }
 
         if tp.active_conn_id_limit != 2 {
             TransportParams::encode_param(
@@ -6051,6 +9484,19 @@ impl TransportParams {
             }
         }
 
+        if let Some(ref vi) = tp.version_information {
+            TransportParams::encode_param(
+                &mut b,
+                0x0011,
+                4 + 4 * vi.available_versions.len(),
+            )?;
+            b.put_u32(vi.chosen_version)?;
+
+            for &v in &vi.available_versions {
+                b.put_u32(v)?;
+            }
+        }
+
         if let Some(max_datagram_frame_size) = tp.max_datagram_frame_size {
             TransportParams::encode_param(
                 &mut b,
@@ -6060,6 +9506,15 @@ impl TransportParams {
             b.put_varint(max_datagram_frame_size)?;
         }
 
+        if let Some(min_ack_delay) = tp.min_ack_delay {
+            TransportParams::encode_param(
+                &mut b,
+                0xff04_de1b,
+                octets::varint_len(min_ack_delay),
+            )?;
+            b.put_varint(min_ack_delay)?;
+        }
+
         let out_len = b.off();
 
         Ok(&mut out[..out_len])
@@ -6083,6 +9538,27 @@ impl TransportParams {
             details: None,
         });
 
+        let preferred_address = self.preferred_address.as_ref().map(|pa| {
+            qlog::events::quic::PreferredAddress {
+                ip_v4: pa.ipv4.map(|a| a.ip().to_string()).unwrap_or_default(),
+                ip_v6: pa.ipv6.map(|a| a.ip().to_string()).unwrap_or_default(),
+
+                port_v4: pa.ipv4.map_or(0, |a| a.port()),
+                port_v6: pa.ipv6.map_or(0, |a| a.port()),
+
+                connection_id: format!("{}", qlog::HexSlice::new(&pa.cid)),
+                stateless_reset_token: qlog::Token {
+                    ty: Some(qlog::TokenType::StatelessReset),
+                    length: None,
+                    data: Some(format!(
+                        "{}",
+                        qlog::HexSlice::new(&pa.reset_token.to_be_bytes())
+                    )),
+                    details: None,
+                },
+            }
+        });
+
         EventData::TransportParametersSet(
             qlog::events::quic::TransportParametersSet {
                 owner: Some(owner),
@@ -6116,7 +9592,7 @@ impl TransportParams {
                 initial_max_streams_bidi: Some(self.initial_max_streams_bidi),
                 initial_max_streams_uni: Some(self.initial_max_streams_uni),
 
-                preferred_address: None,
+                preferred_address,
             },
         )
     }
@@ -6166,9 +9642,16 @@ pub mod testing {
                     Some("quic.tech"),
                     &client_scid,
                     client_addr,
+                    server_addr,
+                    config,
+                )?,
+                server: accept(
+                    &server_scid,
+                    None,
+                    server_addr,
+                    client_addr,
                     config,
                 )?,
-                server: accept(&server_scid, None, server_addr, config)?,
             })
         }
 
@@ -6199,9 +9682,16 @@ pub mod testing {
                     Some("quic.tech"),
                     &client_scid,
                     client_addr,
+                    server_addr,
                     client_config,
                 )?,
-                server: accept(&server_scid, None, server_addr, &mut config)?,
+                server: accept(
+                    &server_scid,
+                    None,
+                    server_addr,
+                    client_addr,
+                    &mut config,
+                )?,
             })
         }
 
@@ -6230,9 +9720,16 @@ pub mod testing {
                     Some("quic.tech"),
                     &client_scid,
                     client_addr,
+                    server_addr,
                     &mut config,
                 )?,
-                server: accept(&server_scid, None, server_addr, server_config)?,
+                server: accept(
+                    &server_scid,
+                    None,
+                    server_addr,
+                    client_addr,
+                    server_config,
+                )?,
             })
         }
 
@@ -6274,21 +9771,35 @@ pub mod testing {
         }
 
         pub fn client_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let active_path = self.client.paths.get_active();
+
             let info = RecvInfo {
-                from: self.client.peer_addr,
+                from: active_path.peer_addr(),
+                to: active_path.local_addr(),
+                ecn: EcnCodepoint::NotEct,
             };
 
             self.client.recv(buf, info)
         }
 
         pub fn server_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let active_path = self.server.paths.get_active();
+
             let info = RecvInfo {
-                from: self.server.peer_addr,
+                from: active_path.peer_addr(),
+                to: active_path.local_addr(),
+                ecn: EcnCodepoint::NotEct,
             };
 
             self.server.recv(buf, info)
         }
 
+        pub fn into_simulator(
+            self, link: &netsim::LinkConfig,
+        ) -> netsim::Simulator {
+            netsim::Simulator::new(self.client, self.server, link)
+        }
+
         pub fn send_pkt_to_server(
             &mut self, pkt_type: packet::Type, frames: &[frame::Frame],
             buf: &mut [u8],
@@ -6298,11 +9809,32 @@ pub mod testing {
         }
     }
 
+    /// Returns a configuration using the test certificate, with the given
+    /// connection and per-stream flow control limits and up to 3
+    /// bidirectional streams.
+    pub fn config(max_data: u64, max_stream_data: u64) -> Result<Config> {
+        let mut config = Config::new(crate::PROTOCOL_VERSION)?;
+        config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+        config.load_priv_key_from_pem_file("examples/cert.key")?;
+        config.set_application_protos(b"\x06proto1\x06proto2")?;
+        config.set_initial_max_data(max_data);
+        config.set_initial_max_stream_data_bidi_local(max_stream_data);
+        config.set_initial_max_stream_data_bidi_remote(max_stream_data);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+
+        Ok(config)
+    }
+
     pub fn recv_send(
         conn: &mut Connection, buf: &mut [u8], len: usize,
     ) -> Result<usize> {
+        let active_path = conn.paths.get_active();
+
         let info = RecvInfo {
-            from: conn.peer_addr,
+            from: active_path.peer_addr(),
+            to: active_path.local_addr(),
+            ecn: EcnCodepoint::NotEct,
         };
 
         conn.recv(&mut buf[..len], info)?;
@@ -6321,11 +9853,13 @@ pub mod testing {
     }
 
     pub fn process_flight(
-        conn: &mut Connection, flight: Vec<Vec<u8>>,
+        conn: &mut Connection, flight: Vec<(Vec<u8>, SendInfo)>,
     ) -> Result<()> {
-        for mut pkt in flight {
+        for (mut pkt, si) in flight {
             let info = RecvInfo {
-                from: conn.peer_addr,
+                from: si.from,
+                to: si.to,
+                ecn: si.ecn,
             };
 
             conn.recv(&mut pkt, info)?;
@@ -6334,21 +9868,26 @@ pub mod testing {
         Ok(())
     }
 
-    pub fn emit_flight(conn: &mut Connection) -> Result<Vec<Vec<u8>>> {
+    pub fn emit_flight(
+        conn: &mut Connection,
+    ) -> Result<Vec<(Vec<u8>, SendInfo)>> {
         let mut flight = Vec::new();
 
         loop {
             let mut out = vec![0u8; 65535];
 
-            match conn.send(&mut out) {
-                Ok((written, _)) => out.truncate(written),
+            let info = match conn.send(&mut out) {
+                Ok((written, info)) => {
+                    out.truncate(written);
+                    info
+                },
 
                 Err(Error::Done) => break,
 
                 Err(e) => return Err(e),
             };
 
-            flight.push(out);
+            flight.push((out, info));
         }
 
         if flight.is_empty() {
@@ -6366,6 +9905,9 @@ pub mod testing {
 
         let epoch = pkt_type.to_epoch()?;
 
+        let dcid = conn.destination_id().into_owned();
+        let scid = conn.source_id().into_owned();
+
         let space = &mut conn.pkt_num_spaces[epoch];
 
         let pn = space.next_pkt_num;
@@ -6374,13 +9916,13 @@ pub mod testing {
         let hdr = Header {
             ty: pkt_type,
             version: conn.version,
-            dcid: ConnectionId::from_ref(&conn.dcid),
-            scid: ConnectionId::from_ref(&conn.scid),
+            dcid,
+            scid,
             pkt_num: 0,
             pkt_num_len: pn_len,
             token: conn.token.clone(),
             versions: None,
-            key_phase: false,
+            key_phase: conn.key_phase,
         };
 
         hdr.to_bytes(&mut b)?;
@@ -6427,7 +9969,7 @@ pub mod testing {
     ) -> Result<Vec<frame::Frame>> {
         let mut b = octets::OctetsMut::with_slice(&mut buf[..len]);
 
-        let mut hdr = Header::from_bytes(&mut b, conn.scid.len()).unwrap();
+        let mut hdr = Header::from_bytes(&mut b, conn.source_id().len()).unwrap();
 
         let epoch = hdr.ty.to_epoch()?;
 
@@ -6479,16 +10021,30 @@ mod tests {
             ack_delay_exponent: 20,
             max_ack_delay: 2_u64.pow(14) - 1,
             disable_active_migration: true,
+            preferred_address: Some(PreferredAddress {
+                ipv4: Some("127.0.0.1:4433".parse().unwrap()),
+                ipv6: None,
+                cid: b"preferred".to_vec().into(),
+                reset_token: 0xba,
+            }),
             active_conn_id_limit: 8,
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: Some(b"retry".to_vec().into()),
             max_datagram_frame_size: Some(32),
+            version_information: Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V2,
+                available_versions: vec![
+                    PROTOCOL_VERSION_V2,
+                    PROTOCOL_VERSION_V1,
+                ],
+            }),
+            min_ack_delay: Some(1000),
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, true, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 94);
+        assert_eq!(raw_params.len(), 171);
 
         let new_tp = TransportParams::decode(&raw_params, false).unwrap();
 
@@ -6509,22 +10065,69 @@ mod tests {
             ack_delay_exponent: 20,
             max_ack_delay: 2_u64.pow(14) - 1,
             disable_active_migration: true,
+            preferred_address: None,
             active_conn_id_limit: 8,
             initial_source_connection_id: Some(b"woot woot".to_vec().into()),
             retry_source_connection_id: None,
             max_datagram_frame_size: Some(32),
+            version_information: Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V1,
+                available_versions: vec![
+                    PROTOCOL_VERSION_V1,
+                    PROTOCOL_VERSION_V2,
+                ],
+            }),
+            min_ack_delay: Some(1000),
         };
 
         let mut raw_params = [42; 256];
         let raw_params =
             TransportParams::encode(&tp, false, &mut raw_params).unwrap();
-        assert_eq!(raw_params.len(), 69);
+        assert_eq!(raw_params.len(), 94);
 
         let new_tp = TransportParams::decode(&raw_params, true).unwrap();
 
         assert_eq!(new_tp, tp);
     }
 
+    #[test]
+    fn transport_params_preferred_address() {
+        let mut tp = TransportParams {
+            preferred_address: Some(PreferredAddress {
+                ipv4: None,
+                ipv6: Some("[::1]:4433".parse().unwrap()),
+                cid: ConnectionId::from_vec(vec![0xba; 16]),
+                reset_token: 0x42,
+            }),
+            ..Default::default()
+        };
+
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, true, &mut raw_params).unwrap();
+
+        let new_tp = TransportParams::decode(raw_params, false).unwrap();
+        assert_eq!(new_tp, tp);
+
+        // Only servers can send a preferred address.
+        assert_eq!(
+            TransportParams::decode(raw_params, true),
+            Err(Error::InvalidTransportParam)
+        );
+
+        // The connection ID can't be empty.
+        tp.preferred_address.as_mut().unwrap().cid = ConnectionId::default();
+
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, true, &mut raw_params).unwrap();
+
+        assert_eq!(
+            TransportParams::decode(raw_params, false),
+            Err(Error::InvalidTransportParam)
+        );
+    }
+
     #[test]
     fn unknown_version() {
         let mut config = Config::new(0xbabababa).unwrap();
@@ -6577,185 +10180,263 @@ mod tests {
     }
 
     #[test]
-    fn verify_custom_root() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config.verify_peer(true);
+    fn handshake_v2() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION_V2).unwrap();
         config
-            .load_verify_locations_from_file("examples/rootca.crt")
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
             .unwrap();
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, true)));
     }
 
     #[test]
-    fn missing_initial_source_connection_id() {
+    fn compatible_version_negotiation() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION_V2).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
 
-        // Reset initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = None;
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        // The client starts with version 1, and the server upgrades the
+        // connection to version 2 without an extra round trip.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
-    }
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V1);
 
-    #[test]
-    fn invalid_initial_source_connection_id() {
-        let mut buf = [0; 65535];
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        // Scramble initial_source_connection_id.
-        pipe.client
-            .local_transport_params
-            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
-        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, packet::Type::Initial);
+        assert_eq!(hdr.version, PROTOCOL_VERSION_V2);
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        // Server rejects transport parameters.
-        assert_eq!(
-            pipe.server_recv(&mut buf[..len]),
-            Err(Error::InvalidTransportParam)
-        );
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut buf), Ok((5, true)));
     }
 
     #[test]
-    fn handshake() {
-        let mut pipe = testing::Pipe::default().unwrap();
+    fn compatible_version_negotiation_server_preference() {
+        let mut config = Config::new(PROTOCOL_VERSION_V2).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
+
+        // A server preferring version 1 switches the client to it.
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V1);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V1);
+
+        // A server only supporting version 2 keeps it.
+        let mut server_config = Config::new(PROTOCOL_VERSION).unwrap();
+        server_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        server_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        server_config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
         assert_eq!(
-            pipe.client.application_proto(),
-            pipe.server.application_proto()
+            server_config.set_supported_versions(&[PROTOCOL_VERSION_V2]),
+            Ok(())
         );
 
-        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
-    }
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
 
-    #[test]
-    fn handshake_done() {
-        let mut pipe = testing::Pipe::default().unwrap();
+        let server_scid = ConnectionId::from_ref(&[0xba; 16]);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
 
-        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
-        // triggering 1-RTT packet send with a CRYPTO frame.
-        pipe.server.handshake.set_options(0x0000_4000);
+        pipe.server = accept(
+            &server_scid,
+            None,
+            server_addr,
+            client_addr,
+            &mut server_config,
+        )
+        .unwrap();
 
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(pipe.server.handshake_done_sent);
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
     }
 
     #[test]
-    fn handshake_confirmation() {
-        let mut pipe = testing::Pipe::default().unwrap();
+    fn compatible_version_negotiation_invalid_chosen_version() {
+        let mut buf = [0; 65535];
 
-        // Client sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Server sends initial flight.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        // The client claims to have chosen a different version than the one
+        // used by its first Initial packet, e.g. because of an attacker.
+        pipe.client.local_transport_params.version_information =
+            Some(VersionInformation {
+                chosen_version: PROTOCOL_VERSION_V2,
+                available_versions: vec![PROTOCOL_VERSION_V2],
+            });
+        pipe.client.encode_transport_params().unwrap();
 
-        assert!(!pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::VersionNegotiation)
+        );
+    }
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+    #[test]
+    fn compatible_version_negotiation_0rtt() {
+        let mut buf = [0; 65535];
 
-        // Client sends Handshake packet and completes handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let mut config = Config::new(PROTOCOL_VERSION_V1).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        assert!(!pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        let session = pipe.client.session().unwrap();
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        // Configure session on new connection, with a server that upgrades
+        // the connection to version 2.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        // Server completes handshake and sends HANDSHAKE_DONE.
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        assert_eq!(
+            config.set_supported_versions(&[
+                PROTOCOL_VERSION_V2,
+                PROTOCOL_VERSION_V1
+            ]),
+            Ok(())
+        );
 
-        assert!(pipe.client.is_established());
-        assert!(!pipe.client.handshake_confirmed);
+        let server_scid = ConnectionId::from_ref(&[0xba; 16]);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
 
-        assert!(pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        pipe.server =
+            accept(&server_scid, None, server_addr, client_addr, &mut config)
+                .unwrap();
 
-        testing::process_flight(&mut pipe.client, flight).unwrap();
+        // Client sends 0-RTT data along with its Initial, in version 1.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
 
-        // Client acks 1-RTT packet, and confirms handshake.
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
 
-        assert!(pipe.server.is_established());
-        assert!(!pipe.server.handshake_confirmed);
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
 
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server handshake is confirmed.
         assert!(pipe.client.is_established());
-        assert!(pipe.client.handshake_confirmed);
-
-        assert!(pipe.server.is_established());
-        assert!(pipe.server.handshake_confirmed);
+        assert!(pipe.client.is_resumed());
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
     }
 
     #[test]
-    fn handshake_resumption() {
-        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
+    fn supported_versions() {
+        let mut config = Config::new(PROTOCOL_VERSION_V1).unwrap();
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+        assert!(config.version_is_supported(PROTOCOL_VERSION_V1));
+        assert!(config.version_is_supported(PROTOCOL_VERSION_V2));
+        assert!(!config.version_is_supported(0xbabababa));
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(
+            config.set_supported_versions(&[]),
+            Err(Error::UnknownVersion)
+        );
+        assert_eq!(
+            config.set_supported_versions(&[PROTOCOL_VERSION_V1, 0xbabababa]),
+            Err(Error::UnknownVersion)
+        );
 
-        assert_eq!(pipe.client.is_established(), true);
-        assert_eq!(pipe.server.is_established(), true);
+        assert_eq!(
+            config.set_supported_versions(&[PROTOCOL_VERSION_V2]),
+            Ok(())
+        );
 
-        assert_eq!(pipe.client.is_resumed(), false);
-        assert_eq!(pipe.server.is_resumed(), false);
+        assert!(!config.version_is_supported(PROTOCOL_VERSION_V1));
+        assert!(config.version_is_supported(PROTOCOL_VERSION_V2));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        // Reserved versions are not advertised by default.
+        let config = Config::new(0xbabababa).unwrap();
+        assert_eq!(config.versions, SUPPORTED_VERSIONS);
+    }
 
-        // Configure session on new connection and perform handshake.
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+    #[test]
+    fn version_negotiation_supported_versions() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
@@ -6765,260 +10446,304 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+        assert_eq!(
+            config.set_supported_versions(&[PROTOCOL_VERSION_V2]),
+            Ok(())
+        );
 
         let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
 
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
-        assert_eq!(pipe.handshake(), Ok(()));
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.client.is_established(), true);
-        assert_eq!(pipe.server.is_established(), true);
+        // The server doesn't accept the client's version.
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::UnknownVersion)
+        );
 
-        assert_eq!(pipe.client.is_resumed(), true);
-        assert_eq!(pipe.server.is_resumed(), true);
-    }
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert!(!config.version_is_supported(hdr.version));
 
-    #[test]
-    fn handshake_alpn_mismatch() {
-        let mut buf = [0; 65535];
+        len = crate::negotiate_version_with_config(
+            &config, &hdr.scid, &hdr.dcid, &mut buf,
+        )
+        .unwrap();
 
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
-        config
-            .set_application_protos(b"\x06proto3\x06proto4")
-            .unwrap();
-        config.verify_peer(false);
+        let hdr = Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.versions, Some(vec![PROTOCOL_VERSION_V2]));
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
-        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
 
-        assert_eq!(pipe.client.application_proto(), b"");
-        assert_eq!(pipe.server.application_proto(), b"");
+        // Start over with a new server connection.
+        let server_scid = ConnectionId::from_ref(&[0xba; 16]);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
 
-        // Server should only send one packet in response to ALPN mismatch.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
-        assert_eq!(len, 1200);
+        pipe.server =
+            accept(&server_scid, None, server_addr, client_addr, &mut config)
+                .unwrap();
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
-        assert_eq!(pipe.server.sent_count, 1);
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_V2);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_V2);
     }
 
     #[test]
-    fn handshake_0rtt() {
-        let mut buf = [0; 65535];
-
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
+    fn verify_custom_root() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config.verify_peer(true);
         config
-            .load_priv_key_from_pem_file("examples/cert.key")
+            .load_verify_locations_from_file("examples/rootca.crt")
             .unwrap();
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+    }
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+    #[test]
+    fn missing_initial_source_connection_id() {
+        let mut buf = [0; 65535];
 
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Reset initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = None;
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
 
         // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
+        // Server rejects transport parameters.
+        assert_eq!(
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
+        );
+    }
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+    #[test]
+    fn invalid_initial_source_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Scramble initial_source_connection_id.
+        pipe.client
+            .local_transport_params
+            .initial_source_connection_id = Some(b"bogus value".to_vec().into());
+        assert_eq!(pipe.client.encode_transport_params(), Ok(()));
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
+        // Server rejects transport parameters.
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Ok(1200)
+            pipe.server_recv(&mut buf[..len]),
+            Err(Error::InvalidTransportParam)
         );
+    }
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+    #[test]
+    fn handshake() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(
+            pipe.client.application_proto(),
+            pipe.server.application_proto()
+        );
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
     }
 
     #[test]
-    fn handshake_0rtt_reordered() {
-        let mut buf = [0; 65535];
+    fn handshake_done() {
+        let mut pipe = testing::Pipe::default().unwrap();
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
+        // Disable session tickets on the server (SSL_OP_NO_TICKET) to avoid
+        // triggering 1-RTT packet send with a CRYPTO frame.
+        pipe.server.handshake.set_options(0x0000_4000);
 
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        assert!(pipe.server.handshake_done_sent);
+    }
 
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
+    #[test]
+    fn handshake_confirmation() {
+        let mut pipe = testing::Pipe::default().unwrap();
 
         // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = (&buf[..len]).to_vec();
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
+        // Server sends initial flight.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+        assert!(!pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        let mut zrtt = (&buf[..len]).to_vec();
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
-        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
+        // Client sends Handshake packet and completes handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
 
-        // Initial packet is also received.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+        assert!(!pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
 
-        // 0-RTT stream data is readable.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
-        assert_eq!(&b[..5], b"aaaaa");
+        // Server completes handshake and sends HANDSHAKE_DONE.
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+
+        assert!(pipe.client.is_established());
+        assert!(!pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
+
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        // Client acks 1-RTT packet, and confirms handshake.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(!pipe.server.handshake_confirmed);
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        // Server handshake is confirmed.
+        assert!(pipe.client.is_established());
+        assert!(pipe.client.handshake_confirmed);
+
+        assert!(pipe.server.is_established());
+        assert!(pipe.server.handshake_confirmed);
     }
 
     #[test]
-    fn handshake_0rtt_truncated() {
+    fn key_update() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
-        config
-            .load_cert_chain_from_pem_file("examples/cert.crt")
-            .unwrap();
-        config
-            .load_priv_key_from_pem_file("examples/cert.key")
-            .unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.set_initial_max_data(30);
-        config.set_initial_max_stream_data_bidi_local(15);
-        config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
-
-        // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        assert!(pipe.client.handshake_confirmed);
+        assert!(pipe.server.handshake_confirmed);
 
-        // Configure session on new connection.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
-        assert_eq!(pipe.client.set_session(&session), Ok(()));
+        // Client initiates key update.
+        assert_eq!(
+            pipe.client.initiate_key_update(time::Instant::now()),
+            Ok(())
+        );
 
-        // Client sends initial flight.
-        pipe.client.send(&mut buf).unwrap();
+        assert!(pipe.client.key_phase);
+        assert!(!pipe.server.key_phase);
 
-        // Client sends 0-RTT packet.
-        let pkt_type = packet::Type::ZeroRTT;
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, true),
-        }];
+        // Server follows the peer-initiated key update.
+        assert!(pipe.server.key_phase);
+        assert!(pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
+            .key_update
+            .is_some());
 
-        let len =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, true)));
 
-        // Simulate a truncated packet by sending one byte less.
-        let mut zrtt = (&buf[..len - 1]).to_vec();
+        assert_eq!(pipe.server.stream_send(4, b"world", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // 0-RTT packet is received before the Initial one.
-        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
+        assert_eq!(pipe.client.stream_recv(4, &mut buf), Ok((5, true)));
 
-        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+        // Server initiates key update.
+        assert_eq!(
+            pipe.server.initiate_key_update(time::Instant::now()),
+            Ok(())
+        );
 
-        assert!(pipe.server.is_closed());
+        assert_eq!(pipe.server.stream_send(1, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.client.key_phase);
+        assert!(!pipe.server.key_phase);
+
+        assert_eq!(pipe.client.stream_recv(1, &mut buf), Ok((5, true)));
     }
 
     #[test]
-    /// Tests that a pre-v1 client can connect to a v1-enabled server, by making
-    /// the server downgrade to the pre-v1 version.
-    fn handshake_downgrade_v1() {
-        let mut config = Config::new(PROTOCOL_VERSION_DRAFT29).unwrap();
-        config
-            .set_application_protos(b"\x06proto1\x06proto2")
-            .unwrap();
-        config.verify_peer(false);
+    fn key_update_delayed_packet() {
+        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.version, PROTOCOL_VERSION_DRAFT29);
-        assert_eq!(pipe.server.version, PROTOCOL_VERSION_DRAFT29);
+        // Client sends a packet protected with the current keys, which gets
+        // delayed.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut delayed = buf[..len].to_vec();
+
+        // Server initiates key update, and client follows.
+        assert_eq!(
+            pipe.server.initiate_key_update(time::Instant::now()),
+            Ok(())
+        );
+
+        assert_eq!(pipe.server.stream_send(1, b"hello", true), Ok(5));
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        assert!(pipe.client.key_phase);
+
+        // Client sends a packet protected with the new keys.
+        assert_eq!(pipe.client.stream_send(4, b"b", true), Ok(1));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        // The delayed packet is decrypted with the previous keys.
+        let len = delayed.len();
+        assert_eq!(pipe.server_recv(&mut delayed), Ok(len));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((2, true)));
+        assert_eq!(&buf[..2], b"ab");
+
+        // Previous keys are dropped after the grace period.
+        let space = &mut pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION];
+        space.key_update.as_mut().unwrap().timer = time::Instant::now();
+
+        pipe.server.on_timeout();
+
+        assert!(pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
+            .key_update
+            .is_none());
     }
 
     #[test]
-    fn limit_handshake_data() {
+    fn key_update_packet_limit() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(PROTOCOL_VERSION).unwrap();
         config
-            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .load_cert_chain_from_pem_file("examples/cert.crt")
             .unwrap();
         config
             .load_priv_key_from_pem_file("examples/cert.key")
@@ -7026,44 +10751,173 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.set_max_packets_per_key(10);
 
-        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let flight = testing::emit_flight(&mut pipe.client).unwrap();
-        let client_sent = flight.iter().fold(0, |out, p| out + p.len());
-        testing::process_flight(&mut pipe.server, flight).unwrap();
+        // Keys are not updated before the limit is reached.
+        assert!(!pipe.client.key_phase);
+        assert!(!pipe.server.key_phase);
 
-        let flight = testing::emit_flight(&mut pipe.server).unwrap();
-        let server_sent = flight.iter().fold(0, |out, p| out + p.len());
+        let data = [42; 1000];
 
-        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
+        let mut recv = 0;
+
+        for _ in 0..50 {
+            assert_eq!(pipe.client.stream_send(0, &data, false), Ok(1000));
+            assert_eq!(pipe.advance(), Ok(()));
+
+            while let Ok((len, _)) = pipe.server.stream_recv(0, &mut buf) {
+                recv += len;
+            }
+        }
+
+        assert_eq!(recv, 50 * data.len());
+
+        // Keys were updated several times.
+        let space = &pipe.client.pkt_num_spaces[packet::EPOCH_APPLICATION];
+        assert!(space.key_phase_start_pn > 10);
+        assert!(space.next_pkt_num - space.key_phase_start_pn <= 10);
+
+        assert_eq!(pipe.client.key_phase, pipe.server.key_phase);
+    }
+
+    #[cfg(feature = "qlog")]
+    #[derive(Clone, Default)]
+    struct QlogBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "qlog")]
+    impl QlogBuf {
+        fn attach(&self, conn: &mut Connection, level: QlogLevel) {
+            conn.set_qlog_with_level(
+                Box::new(self.clone()),
+                "title".to_string(),
+                "description".to_string(),
+                level,
+            );
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[cfg(feature = "qlog")]
+    impl std::io::Write for QlogBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn stream() {
+    #[cfg(feature = "qlog")]
+    fn qlog_key_updated_and_connection_closed() {
         let mut pipe = testing::Pipe::default().unwrap();
+
+        let client_log = QlogBuf::default();
+        let server_log = QlogBuf::default();
+        client_log.attach(&mut pipe.client, QlogLevel::Base);
+        server_log.attach(&mut pipe.server, QlogLevel::Base);
+
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(
+            pipe.client.initiate_key_update(time::Instant::now()),
+            Ok(())
+        );
         assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(!pipe.server.stream_finished(4));
+        let log = client_log.contents();
+        assert!(log.contains("\"security:key_updated\""));
+        assert!(log.contains("\"trigger\":\"local_update\""));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        let log = server_log.contents();
+        assert!(log.contains("\"security:key_updated\""));
+        assert!(log.contains("\"trigger\":\"remote_update\""));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        assert_eq!(pipe.client.close(true, 0x42, b"bye"), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert!(pipe.server.stream_finished(4));
+        let log = client_log.contents();
+        assert!(log.contains("\"connectivity:connection_closed\""));
+        assert!(log.contains("\"owner\":\"local\""));
+        assert!(log.contains("\"trigger\":\"application\""));
+
+        let log = server_log.contents();
+        assert!(log.contains("\"connectivity:connection_closed\""));
+        assert!(log.contains("\"owner\":\"remote\""));
+        assert!(log.contains("\"application_code\":66"));
+        assert!(log.contains("\"reason\":\"bye\""));
     }
 
     #[test]
-    fn zero_rtt() {
+    #[cfg(feature = "qlog")]
+    fn qlog_packet_dropped_and_lost() {
         let mut buf = [0; 65535];
 
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let client_log = QlogBuf::default();
+        let server_log = QlogBuf::default();
+        client_log.attach(&mut pipe.client, QlogLevel::Extra);
+        server_log.attach(&mut pipe.server, QlogLevel::Base);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client sends one packet per stream, the first one is dropped by the
+        // network and the second one is duplicated.
+        for (i, stream_id) in [0, 4, 8, 0, 4].iter().enumerate() {
+            assert_eq!(pipe.client.stream_send(*stream_id, b"a", false), Ok(1));
+
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            if i == 0 {
+                continue;
+            }
+
+            // Packets are decrypted in place, so keep a copy around.
+            let mut dup = buf[..len].to_vec();
+
+            assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+            if i == 1 {
+                assert_eq!(pipe.server_recv(&mut dup), Ok(len));
+            }
+        }
+
+        let log = server_log.contents();
+        assert!(log.contains("\"transport:packet_dropped\""));
+        assert!(log.contains("\"trigger\":\"duplicate\""));
+
+        // The ACK from the server makes the client declare the first packet
+        // lost.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let log = client_log.contents();
+        assert!(log.contains("\"transport:packets_acked\""));
+        assert!(log.contains("\"recovery:packet_lost\""));
+        assert!(log.contains("\"trigger\":\"reordering_threshold\""));
+        assert!(log.contains("\"recovery:congestion_state_updated\""));
+        assert!(log.contains("\"new\":\"recovery\""));
+        assert!(log.contains("\"recovery:loss_timer_updated\""));
+    }
+
+    #[test]
+    fn handshake_resumption() {
+        const SESSION_TICKET_KEY: [u8; 48] = [0xa; 48];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -7078,37 +10932,124 @@ mod tests {
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
         config.set_initial_max_streams_bidi(3);
-        config.enable_early_data();
-        config.verify_peer(false);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
 
         // Perform initial handshake.
-        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Extract session,
-        let session = pipe.client.session().unwrap();
+        assert_eq!(pipe.client.is_established(), true);
+        assert_eq!(pipe.server.is_established(), true);
 
-        // Configure session on new connection.
+        assert_eq!(pipe.client.is_resumed(), false);
+        assert_eq!(pipe.server.is_resumed(), false);
+
+        // Extract session,
+        let session = pipe.client.session().unwrap();
+
+        // Configure session on new connection and perform handshake.
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_ticket_key(&SESSION_TICKET_KEY).unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.is_established(), true);
+        assert_eq!(pipe.server.is_established(), true);
+
+        assert_eq!(pipe.client.is_resumed(), true);
+        assert_eq!(pipe.server.is_resumed(), true);
+    }
+
+    #[test]
+    fn handshake_alpn_mismatch() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .set_application_protos(b"\x06proto3\x06proto4")
+            .unwrap();
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Err(Error::TlsFail));
+
+        assert_eq!(pipe.client.application_proto(), b"");
+        assert_eq!(pipe.server.application_proto(), b"");
+
+        // Server should only send one packet in response to ALPN mismatch.
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(len, 1200);
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert_eq!(pipe.server.sent_count, 1);
+    }
+
+    #[test]
+    fn handshake_0rtt() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Extract session,
+        let session = pipe.client.session().unwrap();
+
+        // Configure session on new connection.
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.client.set_session(&session), Ok(()));
 
         // Client sends initial flight.
         let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut initial = (&buf[..len]).to_vec();
-
-        assert_eq!(pipe.client.is_in_early_data(), true);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // Client sends 0-RTT data.
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        let mut zrtt = (&buf[..len]).to_vec();
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
+        }];
 
-        // Server receives packets.
-        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
-        assert_eq!(pipe.server.is_in_early_data(), true);
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Ok(1200)
+        );
 
-        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
 
         // 0-RTT stream data is readable.
         let mut r = pipe.server.readable();
@@ -7116,12 +11057,14 @@ mod tests {
         assert_eq!(r.next(), None);
 
         let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn stream_send_on_32bit_arch() {
+    fn handshake_0rtt_reordered() {
+        let mut buf = [0; 65535];
+
         let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
         config
             .load_cert_chain_from_pem_file("examples/cert.crt")
@@ -7132,140 +11075,969 @@ mod tests {
         config
             .set_application_protos(b"\x06proto1\x06proto2")
             .unwrap();
-        config.set_initial_max_data(2_u64.pow(32) + 5);
+        config.set_initial_max_data(30);
         config.set_initial_max_stream_data_bidi_local(15);
         config.set_initial_max_stream_data_bidi_remote(15);
-        config.set_initial_max_stream_data_uni(10);
         config.set_initial_max_streams_bidi(3);
-        config.set_initial_max_streams_uni(0);
+        config.enable_early_data();
         config.verify_peer(false);
 
+        // Perform initial handshake.
         let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
-        // not min(5, cwnd)
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        assert_eq!(pipe.advance(), Ok(()));
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        assert!(!pipe.server.stream_finished(4));
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = (&buf[..len]).to_vec();
+
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
+        }];
+
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        let mut zrtt = (&buf[..len]).to_vec();
+
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 1);
+        assert_eq!(pipe.server.undecryptable_pkts[0].0.len(), zrtt.len());
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Initial packet is also received.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"aaaaa");
     }
 
     #[test]
-    fn empty_stream_frame() {
+    fn handshake_0rtt_truncated() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaa", 0, false),
-        }];
+        // Extract session,
+        let session = pipe.client.session().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
 
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
+        // Client sends initial flight.
+        pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
+        // Client sends 0-RTT packet.
+        let pkt_type = packet::Type::ZeroRTT;
 
         let frames = [frame::Frame::Stream {
             stream_id: 4,
-            data: stream::RangeBuf::from(b"", 5, true),
+            data: stream::RangeBuf::from(b"aaaaa", 0, true),
         }];
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        let len =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
 
-        let mut readable = pipe.server.readable();
-        assert_eq!(readable.next(), Some(4));
+        // Simulate a truncated packet by sending one byte less.
+        let mut zrtt = (&buf[..len - 1]).to_vec();
 
-        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
+        // 0-RTT packet is received before the Initial one.
+        assert_eq!(pipe.server_recv(&mut zrtt), Err(Error::InvalidPacket));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"", 15, true),
-        }];
+        assert_eq!(pipe.server.undecryptable_pkts.len(), 0);
+
+        assert!(pipe.server.is_closed());
+    }
+
+    #[test]
+    #[cfg(feature = "draft-versions")]
+    /// Tests that a pre-v1 client can connect to a v1-enabled server, by making
+    /// the server downgrade to the pre-v1 version.
+    fn handshake_downgrade_v1() {
+        let mut config = Config::new(PROTOCOL_VERSION_DRAFT29).unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_client_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.version, PROTOCOL_VERSION_DRAFT29);
+        assert_eq!(pipe.server.version, PROTOCOL_VERSION_DRAFT29);
+    }
+
+    #[test]
+    fn limit_handshake_data() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert-big.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        let client_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        let server_sent = flight.iter().fold(0, |out, p| out + p.0.len());
+
+        assert_eq!(server_sent, client_sent * MAX_AMPLIFICATION_FACTOR);
+    }
+
+    #[test]
+    fn stream() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.server.stream_finished(4));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
+
+        assert!(pipe.server.stream_finished(4));
+    }
+
+    #[test]
+    fn zero_rtt() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.enable_early_data();
+        config.verify_peer(false);
+
+        // Perform initial handshake.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Extract session,
+        let session = pipe.client.session().unwrap();
+
+        // Configure session on new connection.
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_session(&session), Ok(()));
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut initial = (&buf[..len]).to_vec();
+
+        assert_eq!(pipe.client.is_in_early_data(), true);
+
+        // Client sends 0-RTT data.
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut zrtt = (&buf[..len]).to_vec();
+
+        // Server receives packets.
+        assert_eq!(pipe.server_recv(&mut initial), Ok(initial.len()));
+        assert_eq!(pipe.server.is_in_early_data(), true);
+
+        assert_eq!(pipe.server_recv(&mut zrtt), Ok(zrtt.len()));
+
+        // 0-RTT stream data is readable.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
+    }
+
+    #[test]
+    fn stream_recv_zc() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", false), Ok(12));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"!!!", false), Ok(3));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The stream's flow control limit was reached.
+        assert_eq!(pipe.client.stream_send(0, b"!", true), Err(Error::Done));
+
+        assert_eq!(
+            pipe.server.stream_recv_zc(0, 0).unwrap_err(),
+            Error::BufferTooShort
+        );
+
+        let (buf, fin) = pipe.server.stream_recv_zc(0, 5).unwrap();
+        assert_eq!(&buf[..], b"hello");
+        assert!(!fin);
+
+        let (buf, fin) = pipe.server.stream_recv_zc(0, usize::MAX).unwrap();
+        assert_eq!(&buf[..], b", world");
+        assert!(!fin);
+
+        let (buf, fin) = pipe.server.stream_recv_zc(0, usize::MAX).unwrap();
+        assert_eq!(&buf[..], b"!!!");
+        assert!(!fin);
+
+        assert_eq!(
+            pipe.server.stream_recv_zc(0, usize::MAX).unwrap_err(),
+            Error::Done
+        );
+
+        // Data that wasn't released yet is not accounted for flow control, so
+        // the client can't send more.
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stream_send(0, b"!", true), Err(Error::Done));
+
+        // Only data that was returned can be released.
+        assert_eq!(
+            pipe.server.stream_consume(0, 16),
+            Err(Error::InvalidStreamState(0))
+        );
+        assert_eq!(pipe.server.stream_consume(0, 15), Ok(()));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"!", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let (buf, fin) = pipe.server.stream_recv_zc(0, usize::MAX).unwrap();
+        assert_eq!(&buf[..], b"!");
+        assert!(fin);
+
+        // The stream is kept until all its data is released.
+        assert!(pipe.server.stream_finished(0));
+        assert_eq!(pipe.server.stream_consume(0, 1), Ok(()));
+        assert_eq!(
+            pipe.server.stream_consume(0, 1),
+            Err(Error::InvalidStreamState(0))
+        );
+    }
+
+    #[test]
+    fn stream_send_zc() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut data = SharedBuf::new(b"hello, world, and goodbye".to_vec());
+
+        // Only part of the data fits within the stream's flow control limit.
+        assert_eq!(pipe.client.stream_send_zc(0, &data, true), Ok(15));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        data.advance(15);
+
+        let mut b = [0; 25];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((15, false)));
+        assert_eq!(&b[..15], b"hello, world, a");
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The rest of the data is sent once the peer extends the limit.
+        assert_eq!(pipe.client.stream_send_zc(0, &data, true), Ok(10));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((10, true)));
+        assert_eq!(&b[..10], b"nd goodbye");
+    }
+
+    #[test]
+    fn stream_send_zc_advanced() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let mut data = SharedBuf::new(b"HEADERhello".to_vec());
+
+        // The view starts past the beginning of the underlying buffer, while
+        // the stream starts at offset 0.
+        data.advance(6);
+
+        assert_eq!(pipe.client.stream_send_zc(0, &data, true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((5, true)));
+        assert_eq!(&b[..5], b"hello");
+    }
+
+    #[test]
+    fn stream_send_on_32bit_arch() {
+        let mut config = Config::new(crate::PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(2_u64.pow(32) + 5);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_stream_data_uni(10);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(0);
+        config.verify_peer(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // In 32bit arch, send_capacity() should be min(2^32+5, cwnd),
+        // not min(5, cwnd)
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.server.stream_finished(4));
+    }
+
+    #[test]
+    fn empty_stream_frame() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, false)));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"", 5, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut readable = pipe.server.readable();
+        assert_eq!(readable.next(), Some(4));
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((0, true)));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"", 15, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FinalSize)
+        );
+    }
+
+    #[test]
+    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
+    /// unidirectional stream is forbidden.
+    fn max_stream_data_receive_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client opens unidirectional stream.
+        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Client sends MAX_STREAM_DATA on local unidirectional stream.
+        let frames = [frame::Frame::MaxStreamData {
+            stream_id: 2,
+            max: 1024,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidStreamState(2)),
+        );
+    }
+
+    #[test]
+    fn empty_payload() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Send a packet with no frames.
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn min_payload() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Send a non-ack-eliciting packet.
+        let frames = [frame::Frame::Padding { len: 4 }];
+
+        let pkt_type = packet::Type::Initial;
+        let written =
+            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
+                .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
+
+        assert_eq!(pipe.server.paths.get_active().max_send_bytes, 195);
+
+        // Force server to send a single PING frame.
+        pipe.server.paths.get_active_mut().recovery.loss_probes
+            [packet::EPOCH_INITIAL] = 1;
+
+        // Artificially limit the amount of bytes the server can send.
+        pipe.server.paths.get_active_mut().max_send_bytes = 60;
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn flow_control_limit() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn flow_control_limit_dup() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            // One byte less than stream limit.
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
+            },
+            // Same stream, but one byte more.
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        pipe.server.stream_recv(4, &mut buf).unwrap();
+        pipe.server.stream_recv(8, &mut buf).unwrap();
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 8,
+            data: stream::RangeBuf::from(b"a", 1, false),
+        }];
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(len > 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        // Ignore ACK.
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::MaxStreamData {
+                stream_id: 4,
+                max: 30
+            })
+        );
+        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+    }
+
+    #[test]
+    /// Tests that flow control is properly updated even when a stream is shut
+    /// down.
+    fn flow_control_drain() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Client opens a stream and sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server receives data, without reading it.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        // In the meantime, client sends more data.
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
+
+        // Server shuts down one stream.
+        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
+
+        // Flush connection.
+        assert_eq!(pipe.advance(), Ok(()));
+    }
+
+    #[test]
+    fn stream_flow_control_limit_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn stream_flow_control_limit_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 2,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn stream_flow_control_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        pipe.server.stream_recv(4, &mut buf).unwrap();
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"a", 9, false),
+        }];
+
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(len > 0);
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        // Ignore ACK.
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::MaxStreamData {
+                stream_id: 4,
+                max: 24,
+            })
+        );
+    }
+
+    #[test]
+    fn stream_left_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(3, pipe.client.peer_streams_left_bidi());
+        assert_eq!(3, pipe.server.peer_streams_left_bidi());
+
+        pipe.server.stream_send(1, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_bidi());
+        pipe.server.stream_send(5, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_bidi());
+
+        pipe.server.stream_send(9, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_bidi());
+
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
+    }
+
+    #[test]
+    fn stream_left_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(3, pipe.client.peer_streams_left_uni());
+        assert_eq!(3, pipe.server.peer_streams_left_uni());
+
+        pipe.server.stream_send(3, b"a", false).ok();
+        assert_eq!(2, pipe.server.peer_streams_left_uni());
+        pipe.server.stream_send(7, b"a", false).ok();
+        assert_eq!(1, pipe.server.peer_streams_left_uni());
+
+        pipe.server.stream_send(11, b"a", false).ok();
+        assert_eq!(0, pipe.server.peer_streams_left_uni());
+
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
+    }
+
+    #[test]
+    fn stream_limit_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 24,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 28,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit),
+        );
+    }
+
+    #[test]
+    fn stream_limit_max_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::MaxStreamsBidi {
+            max: MAX_STREAM_ID + 1,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
+    }
+
+    #[test]
+    fn stream_limit_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 2,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 6,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 10,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 14,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 18,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 22,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 26,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FinalSize)
+            Err(Error::StreamLimit),
         );
     }
 
     #[test]
-    /// Tests that receiving a MAX_STREAM_DATA frame for a receive-only
-    /// unidirectional stream is forbidden.
-    fn max_stream_data_receive_uni() {
+    fn stream_limit_max_uni() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens unidirectional stream.
-        assert_eq!(pipe.client.stream_send(2, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
 
-        // Client sends MAX_STREAM_DATA on local unidirectional stream.
-        let frames = [frame::Frame::MaxStreamData {
-            stream_id: 2,
-            max: 1024,
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::MaxStreamsUni {
+            max: MAX_STREAM_ID + 1,
         }];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidStreamState(2)),
+            Err(Error::InvalidFrame),
         );
     }
 
     #[test]
-    fn empty_payload() {
+    fn streams_blocked_max_bidi() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Send a packet with no frames.
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let frames = [frame::Frame::StreamsBlockedBidi {
+            limit: MAX_STREAM_ID + 1,
+        }];
+
         let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &[], &mut buf),
-            Err(Error::InvalidPacket)
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
         );
     }
 
     #[test]
-    fn min_payload() {
+    fn streams_blocked_max_uni() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Send a non-ack-eliciting packet.
-        let frames = [frame::Frame::Padding { len: 4 }];
-
-        let pkt_type = packet::Type::Initial;
-        let written =
-            testing::encode_pkt(&mut pipe.client, pkt_type, &frames, &mut buf)
-                .unwrap();
-        assert_eq!(pipe.server_recv(&mut buf[..written]), Ok(written));
-
-        assert_eq!(pipe.server.max_send_bytes, 195);
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID,
+        }];
 
-        // Force server to send a single PING frame.
-        pipe.server.recovery.loss_probes[packet::EPOCH_INITIAL] = 1;
+        let pkt_type = packet::Type::Short;
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        // Artificially limit the amount of bytes the server can send.
-        pipe.server.max_send_bytes = 60;
+        let frames = [frame::Frame::StreamsBlockedUni {
+            limit: MAX_STREAM_ID + 1,
+        }];
 
-        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame),
+        );
     }
 
     #[test]
-    fn flow_control_limit() {
+    fn stream_data_overlap() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
@@ -7273,152 +12045,213 @@ mod tests {
 
         let frames = [
             frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
             },
             frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
             },
             frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
             },
         ];
 
         let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabbbccc");
     }
 
     #[test]
-    fn flow_control_limit_dup() {
+    fn stream_data_overlap_with_reordering() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
         let frames = [
-            // One byte less than stream limit.
             frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaa", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaa", 0, false),
             },
-            // Same stream, but one byte more.
             frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"ccccc", 6, false),
             },
             frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"bbbbb", 3, false),
             },
         ];
 
         let pkt_type = packet::Type::Short;
         assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
+        assert_eq!(&b[..11], b"aaaaabccccc");
     }
 
     #[test]
-    fn flow_control_update() {
+    /// Tests that receiving a valid RESET_STREAM frame when all data has
+    /// already been read, notifies the application.
+    fn reset_stream_data_recvd() {
+        let mut b = [0; 15];
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
+        // Server gets data and sends data back, closing stream.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
+        assert!(!pipe.server.stream_finished(4));
 
-        pipe.server.stream_recv(4, &mut buf).unwrap();
-        pipe.server.stream_recv(8, &mut buf).unwrap();
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 8,
-            data: stream::RangeBuf::from(b"a", 1, false),
-        }];
+        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
-        assert!(len > 0);
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
+        assert!(pipe.client.stream_finished(4));
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
+            stream_id: 4,
+            error_code: 42,
+            final_size: 5,
+        }];
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        // Server is notified of stream readability, due to reset.
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
 
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 4,
-                max: 30
-            })
+            pipe.server.stream_recv(4, &mut b),
+            Err(Error::StreamReset(42))
         );
-        assert_eq!(iter.next(), Some(&frame::Frame::MaxData { max: 61 }));
+
+        assert!(pipe.server.stream_finished(4));
+
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    /// Tests that flow control is properly updated even when a stream is shut
-    /// down.
-    fn flow_control_drain() {
+    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
+    /// been read, discards all buffered data and notifies the application.
+    fn reset_stream_data_not_recvd() {
+        let mut b = [0; 15];
+        let mut buf = [0; 65535];
+
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client opens a stream and sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
+        // Client sends some data.
+        assert_eq!(pipe.client.stream_send(4, b"h", false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server receives data, without reading it.
+        // Server gets data and sends data back, closing stream.
         let mut r = pipe.server.readable();
         assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
 
-        // In the meantime, client sends more data.
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(4, b"aaaaa", true), Ok(5));
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, false)));
+        assert!(!pipe.server.stream_finished(4));
 
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", false), Ok(5));
-        assert_eq!(pipe.client.stream_send(8, b"aaaaa", true), Ok(5));
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
 
-        // Server shuts down one stream.
-        assert_eq!(pipe.server.stream_shutdown(4, Shutdown::Read, 42), Ok(()));
+        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut r = pipe.client.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
+        assert!(pipe.client.stream_finished(4));
+
+        // Client sends RESET_STREAM, closing stream.
+        let frames = [frame::Frame::ResetStream {
+            stream_id: 4,
+            error_code: 42,
+            final_size: 5,
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
 
+        // Server is notified of stream readability, due to reset.
         let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
         assert_eq!(r.next(), None);
 
-        // Flush connection.
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.stream_recv(4, &mut b),
+            Err(Error::StreamReset(42))
+        );
+
+        assert!(pipe.server.stream_finished(4));
+
+        // Sending RESET_STREAM again shouldn't make stream readable again.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), None);
     }
 
     #[test]
-    fn stream_flow_control_limit_bidi() {
+    /// Tests that RESET_STREAM frames exceeding the connection-level flow
+    /// control limit cause an error.
+    fn reset_stream_flow_control() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
-        }];
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 8,
+                error_code: 0,
+                final_size: 15,
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(
@@ -7428,16 +12261,25 @@ mod tests {
     }
 
     #[test]
-    fn stream_flow_control_limit_uni() {
+    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
+    /// limit cause an error.
+    fn reset_stream_flow_control_stream() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 2,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaa", 0, true),
-        }];
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 0,
+                final_size: 16, // Past stream's flow control limit.
+            },
+        ];
 
         let pkt_type = packet::Type::Short;
         assert_eq!(
@@ -7447,28 +12289,16 @@ mod tests {
     }
 
     #[test]
-    fn stream_flow_control_update() {
+    fn path_challenge() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaa", 0, false),
-        }];
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
 
         let pkt_type = packet::Type::Short;
 
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
-
-        pipe.server.stream_recv(4, &mut buf).unwrap();
-
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"a", 9, false),
-        }];
-
         let len = pipe
             .send_pkt_to_server(pkt_type, &frames, &mut buf)
             .unwrap();
@@ -7484,531 +12314,723 @@ mod tests {
 
         assert_eq!(
             iter.next(),
-            Some(&frame::Frame::MaxStreamData {
-                stream_id: 4,
-                max: 24,
-            })
+            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
         );
     }
 
     #[test]
-    fn stream_left_bidi() {
+    fn path_challenge_padding() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(3, pipe.client.peer_streams_left_bidi());
-        assert_eq!(3, pipe.server.peer_streams_left_bidi());
+        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
 
-        pipe.server.stream_send(1, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_bidi());
-        pipe.server.stream_send(5, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_bidi());
+        let pkt_type = packet::Type::Short;
 
-        pipe.server.stream_send(9, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_bidi());
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        // The datagram carrying the PATH_RESPONSE is expanded.
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
+    }
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+    #[test]
+    fn new_connection_id() {
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
 
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_bidi());
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.active_source_cids(), 1);
+        assert_eq!(pipe.client.source_cids_left(), 2);
+        assert_eq!(pipe.server.available_dcids(), 0);
+
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.client.new_source_cid(&scid, 0x42, false), Ok(1));
+
+        // Providing the same connection ID again is a no-op.
+        assert_eq!(pipe.client.new_source_cid(&scid, 0x42, false), Ok(1));
+
+        // But not with a different reset token.
+        assert_eq!(
+            pipe.client.new_source_cid(&scid, 0x43, false),
+            Err(Error::InvalidState)
+        );
+
+        assert_eq!(pipe.client.active_source_cids(), 2);
+        assert_eq!(pipe.client.source_cids_left(), 1);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.available_dcids(), 1);
+
+        // The server retires the connection ID used during the handshake, and
+        // switches to the new one.
+        let initial_scid = pipe.client.source_id().into_owned();
+
+        assert_eq!(pipe.server.retire_destination_cid(0), Ok(()));
+        assert_eq!(pipe.server.destination_id(), scid);
+        assert_eq!(pipe.server.available_dcids(), 0);
+
+        // There are no more connection IDs available.
+        assert_eq!(
+            pipe.server.retire_destination_cid(1),
+            Err(Error::OutOfIdentifiers)
+        );
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.retired_scid_next(), Some(initial_scid));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+
+        assert_eq!(pipe.client.active_source_cids(), 1);
+        assert_eq!(pipe.client.source_id(), scid);
+
+        // The connection keeps working with the new connection IDs.
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, true)));
     }
 
     #[test]
-    fn stream_left_uni() {
-        let mut buf = [0; 65535];
-
+    fn new_connection_id_limit() {
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        assert_eq!(3, pipe.client.peer_streams_left_uni());
-        assert_eq!(3, pipe.server.peer_streams_left_uni());
+        let initial_scid = pipe.client.source_id().into_owned();
 
-        pipe.server.stream_send(3, b"a", false).ok();
-        assert_eq!(2, pipe.server.peer_streams_left_uni());
-        pipe.server.stream_send(7, b"a", false).ok();
-        assert_eq!(1, pipe.server.peer_streams_left_uni());
+        // The server's limit is the default one, 2.
+        let scid1 = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.client.new_source_cid(&scid1, 0x42, false), Ok(1));
+        assert_eq!(pipe.client.source_cids_left(), 0);
 
-        pipe.server.stream_send(11, b"a", false).ok();
-        assert_eq!(0, pipe.server.peer_streams_left_uni());
+        let scid2 = ConnectionId::from_ref(&[0xbb; 16]);
+        assert_eq!(
+            pipe.client.new_source_cid(&scid2, 0x43, false),
+            Err(Error::IdLimit)
+        );
 
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+        // Ask the peer to retire the oldest connection ID to make room.
+        assert_eq!(pipe.client.new_source_cid(&scid2, 0x43, true), Ok(2));
+        assert_eq!(pipe.client.active_source_cids(), 2);
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The server moved to the oldest connection ID left.
+        assert_eq!(pipe.server.destination_id(), scid1);
+        assert_eq!(pipe.server.available_dcids(), 1);
+
+        assert_eq!(pipe.client.retired_scid_next(), Some(initial_scid));
+        assert_eq!(pipe.client.retired_scid_next(), None);
+    }
+
+    #[test]
+    fn invalid_new_connection_id() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Retire Prior To can't be larger than the sequence number.
+        let frames = [frame::Frame::NewConnectionId {
+            seq_num: 1,
+            retire_prior_to: 2,
+            conn_id: vec![0xba; 16],
+            reset_token: [0xba; 16],
+        }];
 
         let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        assert_eq!(MAX_STREAM_ID - 3, pipe.server.peer_streams_left_uni());
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
+        );
     }
 
     #[test]
-    fn stream_limit_bidi() {
+    fn new_connection_id_exceeds_limit() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
         let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 24,
-                data: stream::RangeBuf::from(b"a", 0, false),
+            frame::Frame::NewConnectionId {
+                seq_num: 1,
+                retire_prior_to: 0,
+                conn_id: vec![0xba; 16],
+                reset_token: [0xba; 16],
             },
-            frame::Frame::Stream {
-                stream_id: 28,
-                data: stream::RangeBuf::from(b"a", 0, false),
+            frame::Frame::NewConnectionId {
+                seq_num: 2,
+                retire_prior_to: 0,
+                conn_id: vec![0xbb; 16],
+                reset_token: [0xbb; 16],
             },
         ];
 
         let pkt_type = packet::Type::Short;
+
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
+            Err(Error::IdLimit)
         );
     }
 
     #[test]
-    fn stream_limit_max_bidi() {
+    fn invalid_retire_connection_id() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsBidi { max: MAX_STREAM_ID }];
+        // The connection ID was never issued.
+        let frames = [frame::Frame::RetireConnectionId { seq_num: 3 }];
 
         let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
 
-        let frames = [frame::Frame::MaxStreamsBidi {
-            max: MAX_STREAM_ID + 1,
-        }];
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidState)
+        );
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The connection ID is the one used by the packet itself.
+        let frames = [frame::Frame::RetireConnectionId { seq_num: 0 }];
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
             pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
+            Err(Error::InvalidState)
         );
     }
 
     #[test]
-    fn stream_limit_uni() {
+    fn connection_migration() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 2,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 6,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 10,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 14,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 18,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 22,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 26,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        // Make sure the handshake is confirmed on the server.
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Provide a spare connection ID to the server.
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.client.new_source_cid(&scid, 0x42, false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.available_dcids(), 1);
+
+        let client_addr = pipe.client.paths.get_active().local_addr();
+        let server_addr = pipe.client.paths.get_active().peer_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
+
+        let old_cwnd = pipe.server.paths.get_active().recovery.cwnd();
+
+        // The client sends a non-probing packet from a new address.
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let info = RecvInfo {
+            from: new_addr,
+            to: server_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, new_addr))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(server_addr, new_addr))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
+
+        // The server migrated to the new path, using a new connection ID.
+        let active = pipe.server.paths.get_active();
+        assert_eq!(active.peer_addr(), new_addr);
+        assert!(!active.validated());
+        assert!(active.validation_requested());
+        assert_eq!(active.recovery.cwnd(), old_cwnd);
+        assert_eq!(pipe.server.destination_id(), scid);
+        assert_eq!(pipe.server.available_dcids(), 0);
+
+        // The server validates the new path.
+        let (len, info) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(info.to, new_addr);
+
+        // Sending is limited by the anti-amplification limit.
+        assert!(len <= 3 * MIN_CLIENT_INITIAL_LEN);
+
+        let mut pkt = buf[..len].to_vec();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut pkt, len).unwrap();
+
+        assert!(frames
+            .iter()
+            .any(|f| matches!(f, frame::Frame::PathChallenge { .. })));
+
+        let info = RecvInfo {
+            from: server_addr,
+            to: client_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.client.recv(&mut buf[..len], info), Ok(len));
+
+        // The client echoes the challenge back.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let info = RecvInfo {
+            from: new_addr,
+            to: server_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+
+        let active = pipe.server.paths.get_active();
+        assert_eq!(active.peer_addr(), new_addr);
+        assert!(active.validated());
+        assert!(active.verified_peer_address);
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, new_addr))
+        );
+        assert_eq!(
+            pipe.server.is_path_validated(server_addr, new_addr),
+            Ok(true)
         );
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, true)));
     }
 
     #[test]
-    fn stream_limit_max_uni() {
-        let mut buf = [0; 65535];
+    fn preferred_address() {
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_disable_active_migration(true);
+
+        let preferred_addr: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        let preferred_cid = ConnectionId::from_vec(vec![0xba; 16]);
+
+        config.set_preferred_address(PreferredAddress {
+            ipv4: Some("127.0.0.1:5555".parse().unwrap()),
+            ipv6: Some("[::1]:5555".parse().unwrap()),
+            cid: preferred_cid.clone(),
+            reset_token: 0x42,
+        });
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::MaxStreamsUni { max: MAX_STREAM_ID }];
+        let client_addr = pipe.client.paths.get_active().local_addr();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(
+            pipe.client.peer_transport_params.preferred_address,
+            config.local_transport_params.preferred_address
+        );
+        assert_eq!(pipe.server.peer_transport_params.preferred_address, None);
 
-        let frames = [frame::Frame::MaxStreamsUni {
-            max: MAX_STREAM_ID + 1,
-        }];
+        // The client migrates to the preferred address of the same family
+        // once the handshake is confirmed, using the connection ID provided
+        // with it, even though active migration is disabled.
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.client.paths.get_active().peer_addr(), preferred_addr);
+        assert_eq!(pipe.client.destination_id(), preferred_cid);
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
+            pipe.client.is_path_validated(client_addr, preferred_addr),
+            Ok(true)
+        );
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(preferred_addr, client_addr))
         );
+
+        // Data flows on the new path.
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, true)));
+
+        let active = pipe.server.paths.get_active();
+        assert_eq!(active.local_addr(), preferred_addr);
+        assert_eq!(active.peer_addr(), client_addr);
+        assert_eq!(pipe.server.source_id(), preferred_cid);
     }
 
     #[test]
-    fn streams_blocked_max_bidi() {
+    fn connection_migration_disabled() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+        config.set_disable_active_migration(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID,
-        }];
+        let old_addr = pipe.server.paths.get_active().peer_addr();
+        let server_addr = pipe.server.paths.get_active().local_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
 
-        let frames = [frame::Frame::StreamsBlockedBidi {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-        let pkt_type = packet::Type::Short;
+        // The packet is silently dropped.
+        let info = RecvInfo {
+            from: new_addr,
+            to: server_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
+
+        assert_eq!(pipe.server.paths.len(), 1);
+        assert_eq!(pipe.server.path_event_next(), None);
+        assert_eq!(pipe.server.paths.get_active().peer_addr(), old_addr);
+
+        let mut b = [0; 15];
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
+            pipe.server.stream_recv(4, &mut b),
+            Err(Error::InvalidStreamState(4))
         );
     }
 
     #[test]
-    fn streams_blocked_max_uni() {
+    fn connection_migration_client_ignores_unknown_address() {
         let mut buf = [0; 65535];
 
         let mut pipe = testing::Pipe::default().unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID,
-        }];
+        let client_addr = pipe.client.paths.get_active().local_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.server.stream_send(1, b"a", true), Ok(1));
 
-        let frames = [frame::Frame::StreamsBlockedUni {
-            limit: MAX_STREAM_ID + 1,
-        }];
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::InvalidFrame),
-        );
+        let info = RecvInfo {
+            from: new_addr,
+            to: client_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.client.recv(&mut buf[..len], info), Ok(len));
+
+        assert_eq!(pipe.client.paths.len(), 1);
+        assert_eq!(pipe.client.path_event_next(), None);
     }
 
     #[test]
-    fn stream_data_overlap() {
+    fn connection_migration_failed_validation() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-        ];
+        let old_addr = pipe.server.paths.get_active().peer_addr();
+        let server_addr = pipe.server.paths.get_active().local_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabbbccc");
-    }
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
 
-    #[test]
-    fn stream_data_overlap_with_reordering() {
-        let mut buf = [0; 65535];
+        let info = RecvInfo {
+            from: new_addr,
+            to: server_addr,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.server.paths.get_active().peer_addr(), new_addr);
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"aaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"ccccc", 6, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 0,
-                data: stream::RangeBuf::from(b"bbbbb", 3, false),
-            },
-        ];
+        // The server sends a challenge on the new path, but never gets a
+        // response.
+        let (_, info) = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(info.to, new_addr);
 
-        let pkt_type = packet::Type::Short;
-        assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+        let timer = pipe.server.paths.get_active().validation_timer().unwrap();
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((11, false)));
-        assert_eq!(&b[..11], b"aaaaabccccc");
+        // Expire the validation timer.
+        pipe.server.on_timeout_at(timer);
+
+        // The server falls back to the previous path.
+        assert!(!pipe.server.is_closed());
+        assert_eq!(pipe.server.paths.get_active().peer_addr(), old_addr);
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, new_addr))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(server_addr, new_addr))
+        );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::FailedValidation(server_addr, new_addr))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has
-    /// already been read, notifies the application.
-    fn reset_stream_data_recvd() {
-        let mut b = [0; 15];
+    fn path_probing() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"hello", false), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let client_addr = pipe.client.paths.get_active().local_addr();
+        let server_addr = pipe.client.paths.get_active().peer_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Servers can't probe paths.
+        assert_eq!(
+            pipe.server.probe_path(server_addr, new_addr),
+            Err(Error::InvalidState)
+        );
 
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5, false)));
-        assert!(!pipe.server.stream_finished(4));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        // The server needs to provide a spare connection ID first.
+        assert_eq!(
+            pipe.client.probe_path(new_addr, server_addr),
+            Err(Error::OutOfIdentifiers)
+        );
 
-        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_source_cid(&scid, 0x42, false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        assert_eq!(pipe.client.probe_path(new_addr, server_addr), Ok(1));
+        assert_eq!(
+            pipe.client.is_path_validated(new_addr, server_addr),
+            Ok(false)
+        );
 
-        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(4));
+        // The probing packet is sent from the new address.
+        let (len, info) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(info.from, new_addr);
+        assert_eq!(info.to, server_addr);
+        assert_eq!(len, MIN_CLIENT_INITIAL_LEN);
 
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
-            stream_id: 4,
-            error_code: 42,
-            final_size: 5,
-        }];
+        let info = RecvInfo {
+            from: info.from,
+            to: info.to,
+            ecn: EcnCodepoint::NotEct,
+        };
+        assert_eq!(pipe.server.recv(&mut buf[..len], info), Ok(len));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Both endpoints validated the new path, but keep using the old one.
+        assert_eq!(
+            pipe.client.path_event_next(),
+            Some(PathEvent::Validated(new_addr, server_addr))
+        );
+        assert_eq!(pipe.client.path_event_next(), None);
 
         assert_eq!(
-            pipe.server.stream_recv(4, &mut b),
-            Err(Error::StreamReset(42))
+            pipe.server.path_event_next(),
+            Some(PathEvent::New(server_addr, new_addr))
         );
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::Validated(server_addr, new_addr))
+        );
+        assert_eq!(pipe.server.path_event_next(), None);
 
-        assert!(pipe.server.stream_finished(4));
+        assert_eq!(
+            pipe.client.is_path_validated(new_addr, server_addr),
+            Ok(true)
+        );
+        assert_eq!(pipe.client.paths.get_active().local_addr(), client_addr);
+        assert_eq!(pipe.server.paths.get_active().peer_addr(), client_addr);
 
-        // Sending RESET_STREAM again shouldn't make stream readable again.
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // Each local address is used by a single path.
+        assert_eq!(
+            pipe.client.paths_iter(client_addr).collect::<Vec<_>>(),
+            vec![server_addr]
+        );
+        assert_eq!(pipe.client.paths_iter(new_addr).collect::<Vec<_>>(), vec![
+            server_addr
+        ]);
+        assert_eq!(pipe.server.paths_iter(server_addr).len(), 2);
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        // The client moves to the probed path.
+        assert_eq!(pipe.client.migrate_source(new_addr), Ok(1));
+        assert_eq!(pipe.client.paths.get_active().local_addr(), new_addr);
+
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(
+            pipe.server.path_event_next(),
+            Some(PathEvent::PeerMigrated(server_addr, new_addr))
+        );
+        assert_eq!(pipe.server.paths.get_active().peer_addr(), new_addr);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, true)));
     }
 
     #[test]
-    /// Tests that receiving a valid RESET_STREAM frame when all data has _not_
-    /// been read, discards all buffered data and notifies the application.
-    fn reset_stream_data_not_recvd() {
-        let mut b = [0; 15];
+    fn send_on_path() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
 
-        // Client sends some data.
-        assert_eq!(pipe.client.stream_send(4, b"h", false), Ok(1));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
         assert_eq!(pipe.advance(), Ok(()));
 
-        // Server gets data and sends data back, closing stream.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, false)));
-        assert!(!pipe.server.stream_finished(4));
-
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        let client_addr = pipe.client.paths.get_active().local_addr();
+        let server_addr = pipe.client.paths.get_active().peer_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        assert_eq!(pipe.server.stream_send(4, b"", true), Ok(0));
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_source_cid(&scid, 0x42, false), Ok(1));
         assert_eq!(pipe.advance(), Ok(()));
 
-        let mut r = pipe.client.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
-
-        assert_eq!(pipe.client.stream_recv(4, &mut b), Ok((0, true)));
-        assert!(pipe.client.stream_finished(4));
-
-        // Client sends RESET_STREAM, closing stream.
-        let frames = [frame::Frame::ResetStream {
-            stream_id: 4,
-            error_code: 42,
-            final_size: 5,
-        }];
+        assert_eq!(pipe.client.probe_path(new_addr, server_addr), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        // Unknown paths can't be used.
+        assert_eq!(
+            pipe.client.send_on_path(&mut buf, Some(server_addr), None),
+            Err(Error::InvalidState)
+        );
 
-        // Server is notified of stream readability, due to reset.
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // Application data is only sent on the active path.
+        let (_, info) = pipe
+            .client
+            .send_on_path(&mut buf, Some(client_addr), None)
+            .unwrap();
+        assert_eq!(info.from, client_addr);
+        assert_eq!(info.to, server_addr);
 
         assert_eq!(
-            pipe.server.stream_recv(4, &mut b),
-            Err(Error::StreamReset(42))
+            pipe.client.send_on_path(
+                &mut buf,
+                Some(client_addr),
+                Some(server_addr)
+            ),
+            Err(Error::Done)
         );
 
-        assert!(pipe.server.stream_finished(4));
+        // While the probed path only carries probing frames.
+        let (len, info) = pipe
+            .client
+            .send_on_path(&mut buf, Some(new_addr), Some(server_addr))
+            .unwrap();
+        assert_eq!(info.from, new_addr);
+        assert_eq!(info.to, server_addr);
 
-        // Sending RESET_STREAM again shouldn't make stream readable again.
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+        let frames =
+            testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), None);
+        assert!(frames.iter().all(|f| f.probing()));
+
+        assert_eq!(
+            pipe.client.send_on_path(&mut buf, Some(new_addr), None),
+            Err(Error::Done)
+        );
     }
 
     #[test]
-    /// Tests that RESET_STREAM frames exceeding the connection-level flow
-    /// control limit cause an error.
-    fn reset_stream_flow_control() {
-        let mut buf = [0; 65535];
+    fn migrate_disabled_by_peer() {
+        let mut server_config = testing::config(30, 15).unwrap();
+        server_config.set_active_connection_id_limit(3);
+        server_config.set_disable_active_migration(true);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe =
+            testing::Pipe::with_server_config(&mut server_config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 8,
-                error_code: 0,
-                final_size: 15,
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            pipe.client.migrate_source(new_addr),
+            Err(Error::InvalidState)
         );
-    }
-
-    #[test]
-    /// Tests that RESET_STREAM frames exceeding the stream-level flow control
-    /// limit cause an error.
-    fn reset_stream_flow_control_stream() {
-        let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
-        assert_eq!(pipe.handshake(), Ok(()));
-
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 4,
-                error_code: 0,
-                final_size: 16, // Past stream's flow control limit.
-            },
-        ];
+        let server_addr = pipe.client.paths.get_active().peer_addr();
 
-        let pkt_type = packet::Type::Short;
         assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+            pipe.client.probe_path(new_addr, server_addr),
+            Err(Error::InvalidState)
         );
     }
 
     #[test]
-    fn path_challenge() {
+    fn migrate_failed_validation() {
         let mut buf = [0; 65535];
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_active_connection_id_limit(3);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let frames = [frame::Frame::PathChallenge { data: [0xba; 8] }];
+        let client_addr = pipe.client.paths.get_active().local_addr();
+        let server_addr = pipe.client.paths.get_active().peer_addr();
+        let new_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
 
-        let pkt_type = packet::Type::Short;
+        let scid = ConnectionId::from_ref(&[0xba; 16]);
+        assert_eq!(pipe.server.new_source_cid(&scid, 0x42, false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let len = pipe
-            .send_pkt_to_server(pkt_type, &frames, &mut buf)
-            .unwrap();
+        // Migrate without probing first. The new path never gets validated.
+        assert_eq!(pipe.client.migrate(new_addr, server_addr), Ok(1));
+        assert_eq!(pipe.client.paths.get_active().local_addr(), new_addr);
 
-        assert!(len > 0);
+        let (_, info) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(info.from, new_addr);
 
-        let frames =
-            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        let timer = pipe.client.paths.get_active().validation_timer().unwrap();
 
-        // Ignore ACK.
-        iter.next().unwrap();
+        pipe.client.on_timeout_at(timer);
 
         assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::PathResponse { data: [0xba; 8] })
+            pipe.client.path_event_next(),
+            Some(PathEvent::FailedValidation(new_addr, server_addr))
         );
+
+        // The client falls back to the previous path.
+        assert!(!pipe.client.is_closed());
+        assert_eq!(pipe.client.paths.get_active().local_addr(), client_addr);
     }
 
     #[test]
@@ -8891,124 +13913,373 @@ mod tests {
         // cannot be authenticated during decryption).
         buf[written - 1] = !buf[written - 1];
 
-        assert_eq!(pipe.server.timeout(), None);
+        assert_eq!(pipe.server.timeout(), None);
+
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::CryptoFail)
+        );
+
+        assert!(pipe.server.is_closed());
+    }
+
+    #[test]
+    /// Tests that invalid Initial packets received to cause
+    /// the client to close the connection immediately.
+    fn invalid_initial_client() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        // Client sends initial flight.
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends initial flight.
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        let written = testing::encode_pkt(
+            &mut pipe.server,
+            packet::Type::Initial,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Corrupt the packets's last byte to make decryption fail (the last
+        // byte is part of the AEAD tag, so changing it means that the packet
+        // cannot be authenticated during decryption).
+        buf[written - 1] = !buf[written - 1];
+
+        // Client will ignore invalid packet.
+        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+
+        // The connection should be alive...
+        assert_eq!(pipe.client.is_closed(), false);
+
+        // ...and the idle timeout should be armed.
+        assert!(pipe.client.idle_timer.is_some());
+    }
+
+    #[test]
+    /// Tests that packets with invalid payload length received before any other
+    /// valid packet cause the server to close the connection immediately.
+    fn invalid_initial_payload() {
+        let mut buf = [0; 65535];
+        let mut pipe = testing::Pipe::default().unwrap();
+
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+
+        let epoch = packet::Type::Initial.to_epoch().unwrap();
+
+        let pn = 0;
+        let pn_len = packet::pkt_num_len(pn).unwrap();
+
+        let hdr = Header {
+            ty: packet::Type::Initial,
+            version: pipe.client.version,
+            dcid: pipe.client.destination_id(),
+            scid: pipe.client.source_id(),
+            pkt_num: 0,
+            pkt_num_len: pn_len,
+            token: pipe.client.token.clone(),
+            versions: None,
+            key_phase: false,
+        };
+
+        hdr.to_bytes(&mut b).unwrap();
+
+        // Payload length is invalid!!!
+        let payload_len = 4096;
+
+        let len = pn_len + payload_len;
+        b.put_varint(len as u64).unwrap();
+
+        packet::encode_pkt_num(pn, &mut b).unwrap();
+
+        let payload_offset = b.off();
+
+        let frames = [frame::Frame::Padding { len: 10 }];
+
+        for frame in &frames {
+            frame.to_bytes(&mut b).unwrap();
+        }
+
+        let space = &mut pipe.client.pkt_num_spaces[epoch];
+
+        // Use correct payload length when encrypting the packet.
+        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+
+        let aead = space.crypto_seal.as_ref().unwrap();
+
+        let written = packet::encrypt_pkt(
+            &mut b,
+            pn,
+            pn_len,
+            payload_len,
+            payload_offset,
+            None,
+            aead,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.server.timeout(), None);
+
+        assert_eq!(
+            pipe.server_recv(&mut buf[..written]),
+            Err(Error::InvalidPacket)
+        );
+
+        assert!(pipe.server.is_closed());
+    }
+
+    #[test]
+    fn ack_frequency() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_min_ack_delay(1000);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The client asks the server to acknowledge every third packet.
+        assert_eq!(
+            pipe.client
+                .set_ack_frequency(2, time::Duration::from_millis(10), 0),
+            Ok(())
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.server.ack_freq.policy(), &ack_freq::AckPolicy {
+            ack_eliciting_threshold: 2,
+            max_ack_delay: time::Duration::from_millis(10),
+            reordering_threshold: 0,
+        });
+
+        // The ACK is delayed.
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+        assert!(pipe.server.timeout() <= Some(time::Duration::from_millis(10)));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        // The third packet is acknowledged immediately.
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        let mut iter = frames.iter();
+        assert!(matches!(iter.next(), Some(frame::Frame::ACK { .. })));
+
+        let space = &pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION];
+        assert_eq!(space.ack_eliciting_unacked, 0);
+        assert_eq!(space.ack_timer, None);
+    }
+
+    #[test]
+    fn ack_frequency_timer() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_min_ack_delay(1000);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The requested delay is raised to the peer's minimum.
+        assert_eq!(
+            pipe.client.set_ack_frequency(10, time::Duration::ZERO, 0),
+            Ok(())
+        );
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
         assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::CryptoFail)
+            pipe.server.ack_freq.policy().max_ack_delay,
+            time::Duration::from_millis(1)
         );
 
-        assert!(pipe.server.is_closed());
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+
+        // The ACK is sent once the timer expires.
+        let timer = pipe.server.timeout_instant().unwrap();
+        pipe.server.on_timeout_at(timer);
+
+        let (len, _) = pipe.server.send_at(&mut buf, timer).unwrap();
+
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        let mut iter = frames.iter();
+        assert!(matches!(iter.next(), Some(frame::Frame::ACK { .. })));
     }
 
     #[test]
-    /// Tests that invalid Initial packets received to cause
-    /// the client to close the connection immediately.
-    fn invalid_initial_client() {
+    fn ack_frequency_immediate_ack() {
         let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::default().unwrap();
 
-        // Client sends initial flight.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_min_ack_delay(1000);
 
-        // Server sends initial flight.
-        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(1200));
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+        assert_eq!(
+            pipe.client
+                .set_ack_frequency(10, time::Duration::from_millis(20), 0),
+            Ok(())
+        );
+        assert_eq!(pipe.advance(), Ok(()));
 
-        let written = testing::encode_pkt(
-            &mut pipe.server,
-            packet::Type::Initial,
+        let frames = [frame::Frame::ImmediateAck];
+
+        let len = testing::encode_pkt(
+            &mut pipe.client,
+            packet::Type::Short,
             &frames,
             &mut buf,
         )
         .unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
-        // Corrupt the packets's last byte to make decryption fail (the last
-        // byte is part of the AEAD tag, so changing it means that the packet
-        // cannot be authenticated during decryption).
-        buf[written - 1] = !buf[written - 1];
-
-        // Client will ignore invalid packet.
-        assert_eq!(pipe.client_recv(&mut buf[..written]), Ok(71));
+        let (len, _) = pipe.server.send(&mut buf).unwrap();
 
-        // The connection should be alive...
-        assert_eq!(pipe.client.is_closed(), false);
+        let frames =
+            testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
 
-        // ...and the idle timeout should be armed.
-        assert!(pipe.client.idle_timer.is_some());
+        let mut iter = frames.iter();
+        assert!(matches!(iter.next(), Some(frame::Frame::ACK { .. })));
     }
 
     #[test]
-    /// Tests that packets with invalid payload length received before any other
-    /// valid packet cause the server to close the connection immediately.
-    fn invalid_initial_payload() {
-        let mut buf = [0; 65535];
-        let mut pipe = testing::Pipe::default().unwrap();
+    fn ack_frequency_auto() {
+        let mut client_config = testing::config(30, 15).unwrap();
+        client_config.set_min_ack_delay(1000);
 
-        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        let mut server_config = testing::config(30, 15).unwrap();
+        server_config.set_min_ack_delay(1000);
+        server_config.enable_ack_frequency(true);
 
-        let epoch = packet::Type::Initial.to_epoch().unwrap();
+        let mut pipe =
+            testing::Pipe::with_client_config(&mut client_config).unwrap();
 
-        let pn = 0;
-        let pn_len = packet::pkt_num_len(pn).unwrap();
+        let server_scid = ConnectionId::from_ref(&[0xba; 16]);
+        let server_addr = "127.0.0.1:4321".parse().unwrap();
+        let client_addr = "127.0.0.1:1234".parse().unwrap();
 
-        let hdr = Header {
-            ty: packet::Type::Initial,
-            version: pipe.client.version,
-            dcid: ConnectionId::from_ref(&pipe.client.dcid),
-            scid: ConnectionId::from_ref(&pipe.client.scid),
-            pkt_num: 0,
-            pkt_num_len: pn_len,
-            token: pipe.client.token.clone(),
-            versions: None,
-            key_phase: false,
-        };
+        pipe.server = accept(
+            &server_scid,
+            None,
+            server_addr,
+            client_addr,
+            &mut server_config,
+        )
+        .unwrap();
 
-        hdr.to_bytes(&mut b).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        // Payload length is invalid!!!
-        let payload_len = 4096;
+        // The policy requested by the server follows its congestion window.
+        let policy = pipe.server.ack_freq.requested().unwrap();
 
-        let len = pn_len + payload_len;
-        b.put_varint(len as u64).unwrap();
+        let recovery = &pipe.server.paths.get_active().recovery;
+        assert_eq!(
+            policy.ack_eliciting_threshold,
+            ack_freq::AckPolicy::from_cwnd(
+                recovery.cwnd(),
+                recovery.max_datagram_size(),
+                recovery.rtt(),
+                time::Duration::from_millis(1),
+                time::Duration::from_millis(25),
+            )
+            .ack_eliciting_threshold
+        );
+        assert!(policy.ack_eliciting_threshold > 0);
 
-        packet::encode_pkt_num(pn, &mut b).unwrap();
+        assert_eq!(pipe.client.ack_freq.policy(), &policy);
 
-        let payload_offset = b.off();
+        // The client doesn't tune the server's ACK frequency.
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.ack_freq.requested(), None);
+    }
 
-        let frames = [frame::Frame::Padding { len: 10 }];
+    #[test]
+    fn ack_frequency_not_supported() {
+        let mut buf = [0; 65535];
 
-        for frame in &frames {
-            frame.to_bytes(&mut b).unwrap();
-        }
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let space = &mut pipe.client.pkt_num_spaces[epoch];
+        assert_eq!(
+            pipe.client
+                .set_ack_frequency(2, time::Duration::from_millis(10), 0),
+            Err(Error::InvalidState)
+        );
 
-        // Use correct payload length when encrypting the packet.
-        let payload_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+        let frames = [frame::Frame::AckFrequency {
+            seq_num: 0,
+            ack_eliciting_threshold: 2,
+            request_max_ack_delay: 10_000,
+            reordering_threshold: 0,
+        }];
 
-        let aead = space.crypto_seal.as_ref().unwrap();
+        assert_eq!(
+            pipe.send_pkt_to_server(packet::Type::Short, &frames, &mut buf),
+            Err(Error::InvalidState)
+        );
+    }
 
-        let written = packet::encrypt_pkt(
-            &mut b,
-            pn,
-            pn_len,
-            payload_len,
-            payload_offset,
-            None,
-            aead,
-        )
-        .unwrap();
+    #[test]
+    fn ack_frequency_invalid_delay() {
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.server.timeout(), None);
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_min_ack_delay(1000);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The requested delay is lower than the advertised min_ack_delay.
+        let frames = [frame::Frame::AckFrequency {
+            seq_num: 0,
+            ack_eliciting_threshold: 2,
+            request_max_ack_delay: 500,
+            reordering_threshold: 0,
+        }];
 
         assert_eq!(
-            pipe.server_recv(&mut buf[..written]),
-            Err(Error::InvalidPacket)
+            pipe.send_pkt_to_server(packet::Type::Short, &frames, &mut buf),
+            Err(Error::InvalidState)
         );
+    }
 
-        assert!(pipe.server.is_closed());
+    #[test]
+    fn transport_params_invalid_min_ack_delay() {
+        let tp = TransportParams {
+            max_ack_delay: 25,
+            min_ack_delay: Some(25_001),
+            ..Default::default()
+        };
+
+        let mut raw_params = [42; 256];
+        let raw_params =
+            TransportParams::encode(&tp, true, &mut raw_params).unwrap();
+
+        assert_eq!(
+            TransportParams::decode(raw_params, false),
+            Err(Error::InvalidTransportParam)
+        );
     }
 
     #[test]
@@ -9316,70 +14587,266 @@ mod tests {
     }
 
     #[test]
-    /// Tests that completed streams are garbage collected.
-    fn collect_streams() {
-        let mut buf = [0; 65535];
+    /// Tests that completed streams are garbage collected.
+    fn collect_streams() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.client.stream_finished(0));
+        assert!(!pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 1);
+
+        let mut b = [0; 5];
+        pipe.server.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(!pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.streams.len(), 1);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        let mut b = [0; 5];
+        pipe.client.stream_recv(0, &mut b).unwrap();
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.streams.len(), 0);
+        assert_eq!(pipe.server.streams.len(), 0);
+
+        assert!(pipe.client.stream_finished(0));
+        assert!(pipe.server.stream_finished(0));
+
+        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 0,
+            data: stream::RangeBuf::from(b"aa", 0, false),
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
+    }
+
+    #[test]
+    fn config_set_cc_algorithm_name() {
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+
+        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+        assert_eq!(config.set_cc_algorithm_name("cubic"), Ok(()));
+        assert_eq!(config.set_cc_algorithm_name("bbr"), Ok(()));
+        assert_eq!(config.set_cc_algorithm_name("bbr2"), Ok(()));
+
+        // Unknown name.
+        assert_eq!(
+            config.set_cc_algorithm_name("???"),
+            Err(Error::CongestionControl)
+        );
+    }
+
+    #[test]
+    fn ecn_validation() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(100000, 100000).unwrap();
+        config.enable_ecn(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        // Packets are marked while ECN is tested on the path.
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(flight[0].1.ecn, EcnCodepoint::Ect0);
+
+        testing::process_flight(&mut pipe.server, flight).unwrap();
+
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::EPOCH_INITIAL].ecn_counts,
+            Some(frame::EcnCounts {
+                ect0_count: 1,
+                ect1_count: 0,
+                ecn_ce_count: 0,
+            })
+        );
+
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        for _ in 0..10 {
+            assert_eq!(pipe.client.stream_send(0, &[42; 1000], false), Ok(1000));
+            assert_eq!(pipe.advance(), Ok(()));
+        }
+
+        // The peer reported the ECN marks, so the path was validated.
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::Ect0
+        );
+        assert_eq!(
+            pipe.server.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::Ect0
+        );
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+
+        let (_, si) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(si.ecn, EcnCodepoint::Ect0);
+    }
+
+    #[test]
+    fn ecn_validation_failure() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(100000, 100000).unwrap();
+        config.enable_ecn(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        // ECN marks are cleared by the network.
+        while !pipe.client.is_established() || !pipe.server.is_established() {
+            let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+            flight
+                .iter_mut()
+                .for_each(|(_, si)| si.ecn = EcnCodepoint::NotEct);
+            testing::process_flight(&mut pipe.server, flight).unwrap();
+
+            let flight = testing::emit_flight(&mut pipe.server).unwrap();
+            testing::process_flight(&mut pipe.client, flight).unwrap();
+        }
+
+        // The client stops marking packets, as the peer didn't report them.
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::NotEct
+        );
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+
+        let (_, si) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(si.ecn, EcnCodepoint::NotEct);
+
+        // The server's marks were reported back, so it keeps marking.
+        assert_eq!(
+            pipe.server.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::Ect0
+        );
+    }
+
+    #[test]
+    fn ecn_ce_congestion_event() {
+        let mut config = testing::config(100000, 100000).unwrap();
+        config.enable_ecn(true);
 
-        let mut pipe = testing::Pipe::default().unwrap();
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
         assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::Ect0
+        );
 
-        assert_eq!(pipe.client.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let cwnd = pipe.client.paths.get_active().recovery.cwnd();
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(!pipe.server.stream_finished(0));
+        // The network marks the client's packets with ECN-CE.
+        assert_eq!(pipe.client.stream_send(0, &[42; 5000], false), Ok(5000));
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 1);
+        let mut flight = testing::emit_flight(&mut pipe.client).unwrap();
+        flight
+            .iter_mut()
+            .for_each(|(_, si)| si.ecn = EcnCodepoint::Ce);
+        testing::process_flight(&mut pipe.server, flight).unwrap();
 
-        let mut b = [0; 5];
-        pipe.server.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(
+            pipe.server.pkt_num_spaces[packet::EPOCH_APPLICATION]
+                .ecn_counts
+                .unwrap()
+                .ecn_ce_count,
+            5
+        );
 
-        assert_eq!(pipe.server.stream_send(0, b"aaaaa", true), Ok(5));
-        assert_eq!(pipe.advance(), Ok(()));
+        let flight = testing::emit_flight(&mut pipe.server).unwrap();
+        testing::process_flight(&mut pipe.client, flight).unwrap();
 
-        assert!(!pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+        // The congestion window is reduced without any packet being lost.
+        assert!(pipe.client.paths.get_active().recovery.cwnd() < cwnd);
+        assert_eq!(pipe.client.stats().lost, 0);
 
-        assert_eq!(pipe.client.streams.len(), 1);
-        assert_eq!(pipe.server.streams.len(), 0);
+        // ECN is still used on the path.
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.ecn_codepoint(),
+            EcnCodepoint::Ect0
+        );
+    }
 
-        let mut b = [0; 5];
-        pipe.client.stream_recv(0, &mut b).unwrap();
-        assert_eq!(pipe.advance(), Ok(()));
+    #[test]
+    fn custom_cc() {
+        struct FixedWindow;
 
-        assert_eq!(pipe.client.streams.len(), 0);
-        assert_eq!(pipe.server.streams.len(), 0);
+        impl CongestionControl for FixedWindow {
+            fn cwnd(&self) -> usize {
+                3000
+            }
+        }
 
-        assert!(pipe.client.stream_finished(0));
-        assert!(pipe.server.stream_finished(0));
+        let mut buf = [0; 65535];
 
-        assert_eq!(pipe.client.stream_send(0, b"", true), Err(Error::Done));
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_initial_max_data(50000);
+        config.set_initial_max_stream_data_bidi_local(50000);
+        config.set_initial_max_stream_data_bidi_remote(50000);
+        config.set_initial_max_streams_bidi(3);
+        config.verify_peer(false);
+        config.set_cc_factory(|| Box::new(FixedWindow));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 0,
-            data: stream::RangeBuf::from(b"aa", 0, false),
-        }];
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
 
-        let pkt_type = packet::Type::Short;
-        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(39));
-    }
+        assert_eq!(pipe.client.paths.get_active().recovery.cwnd(), 3000);
 
-    #[test]
-    fn config_set_cc_algorithm_name() {
-        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        // The client can only send as much data as the controller allows.
+        let data = [42; 10000];
+        assert_eq!(pipe.client.stream_send(0, &data, false), Ok(3000));
 
-        assert_eq!(config.set_cc_algorithm_name("reno"), Ok(()));
+        // All data is eventually delivered.
+        let mut sent = 3000;
+        let mut recv = 0;
 
-        // Unknown name.
-        assert_eq!(
-            config.set_cc_algorithm_name("???"),
-            Err(Error::CongestionControl)
-        );
+        while recv < data.len() {
+            assert_eq!(pipe.advance(), Ok(()));
+
+            while let Ok((len, _)) = pipe.server.stream_recv(0, &mut buf) {
+                recv += len;
+            }
+
+            if sent < data.len() {
+                sent += pipe.client.stream_send(0, &data[sent..], false).unwrap();
+            }
+        }
+
+        assert_eq!(recv, data.len());
     }
 
     #[test]
@@ -9445,7 +14912,86 @@ mod tests {
 
         // Server accepts connection.
         let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
+        let to = "127.0.0.1:4321".parse().unwrap();
+        pipe.server = accept(&scid, Some(&odcid), to, from, &mut config).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+    }
+
+    #[test]
+    fn retry_with_address_token() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_address_token_key(&[0xba; 16]).unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        let from = "127.0.0.1:1234".parse().unwrap();
+        let to = "127.0.0.1:4321".parse().unwrap();
+
+        // Client sends initial flight.
+        let (mut len, _) = pipe.client.send(&mut buf).unwrap();
+
+        // Server sends Retry packet.
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+
+        assert_eq!(validate_token(&config, &hdr.token.unwrap(), from), None);
+
+        let odcid = hdr.dcid.clone();
+
+        let mut scid = [0; MAX_CONN_ID_LEN];
+        rand::rand_bytes(&mut scid[..]);
+        let scid = ConnectionId::from_ref(&scid);
+
+        let token = mint_retry_token(&config, &hdr.dcid, from).unwrap();
+
+        len = crate::retry(
+            &hdr.scid,
+            &hdr.dcid,
+            &scid,
+            &token,
+            hdr.version,
+            &mut buf,
+        )
+        .unwrap();
+
+        // Client receives Retry and sends new Initial.
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        let token = hdr.token.unwrap();
+
+        // The token is bound to the client's address.
+        let other = "127.0.0.2:1234".parse().unwrap();
+        assert_eq!(validate_token(&config, &token, other), None);
+
+        let odcid = match validate_token(&config, &token, from) {
+            Some(AddressToken::Retry { odcid: v }) => {
+                assert_eq!(v, odcid);
+                v
+            },
+
+            v => panic!("unexpected token {:?}", v),
+        };
+
+        // Server accepts connection.
+        pipe.server = accept(&scid, Some(&odcid), to, from, &mut config).unwrap();
         assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
         assert_eq!(pipe.advance(), Ok(()));
@@ -9454,6 +15000,181 @@ mod tests {
         assert!(pipe.server.is_established());
     }
 
+    #[test]
+    fn new_token() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(PROTOCOL_VERSION).unwrap();
+        config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+        config
+            .set_application_protos(b"\x06proto1\x06proto2")
+            .unwrap();
+        config.set_address_token_key(&[0xba; 16]).unwrap();
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // Server sent a single token after the handshake.
+        let token = pipe.client.new_token_next().unwrap();
+        assert_eq!(pipe.client.new_token_next(), None);
+
+        let from = "127.0.0.1:1234".parse().unwrap();
+        assert_eq!(
+            validate_token(&config, &token, from),
+            Some(AddressToken::NewToken)
+        );
+
+        // A returning client sends the token in its Initial packet, which
+        // validates its address.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.client.set_token(&token), Ok(()));
+        assert_eq!(pipe.server.set_token(&token), Err(Error::InvalidState));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = Header::from_slice(&mut buf[..len], MAX_CONN_ID_LEN).unwrap();
+        assert_eq!(hdr.token, Some(token.clone()));
+
+        assert!(!pipe.server.paths.get_active().verified_peer_address);
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert!(pipe.server.paths.get_active().verified_peer_address);
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.server.is_established());
+
+        // An invalid token doesn't validate the address.
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+
+        let mut token = token;
+        token[0] ^= 0x01;
+        assert_eq!(pipe.client.set_token(&token), Ok(()));
+
+        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
+        assert!(!pipe.server.paths.get_active().verified_peer_address);
+
+        assert_eq!(pipe.advance(), Ok(()));
+        assert!(pipe.server.is_established());
+    }
+
+    #[test]
+    fn new_token_on_server() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let frames = [frame::Frame::NewToken {
+            token: vec![0xba; 16],
+        }];
+
+        let pkt_type = packet::Type::Short;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidPacket)
+        );
+    }
+
+    #[test]
+    fn stateless_reset() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_stateless_reset_key(&[0xba; 32]);
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The server advertised the token derived for its connection ID.
+        let token =
+            crate::stateless_reset_token(&config, &pipe.server.source_id())
+                .unwrap();
+        assert_eq!(
+            pipe.client.peer_transport_params.stateless_reset_token,
+            Some(token.to_be_bytes().to_vec())
+        );
+
+        // The server lost its state and resets the connection.
+        let dcid = pipe.client.destination_id().into_owned();
+        let len = crate::stateless_reset(&config, &dcid, 1200, &mut buf).unwrap();
+
+        let now = time::Instant::now();
+
+        let active_path = pipe.client.paths.get_active();
+        let info = RecvInfo {
+            from: active_path.peer_addr(),
+            to: active_path.local_addr(),
+            ecn: EcnCodepoint::NotEct,
+        };
+
+        assert_eq!(pipe.client.recv_at(&mut buf[..len], info, now), Ok(len));
+        assert!(pipe.client.is_stateless_reset());
+        assert!(pipe.client.is_draining());
+        assert!(!pipe.client.is_closed());
+        assert_eq!(pipe.client.peer_error(), None);
+
+        // Nothing else is sent to the peer.
+        assert_eq!(pipe.client.send_at(&mut buf, now), Err(Error::Done));
+
+        // The connection is closed once the draining period is over.
+        let timer = pipe.client.timeout_instant().unwrap();
+
+        pipe.client
+            .on_timeout_at(timer - time::Duration::from_millis(1));
+        assert!(!pipe.client.is_closed());
+
+        pipe.client.on_timeout_at(timer);
+
+        assert!(pipe.client.is_closed());
+        assert!(!pipe.client.is_timed_out());
+    }
+
+    #[test]
+    fn stateless_reset_invalid_token() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(30, 15).unwrap();
+        config.set_stateless_reset_key(&[0xba; 32]);
+
+        let mut pipe = testing::Pipe::with_server_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // A reset with a token derived from a different key is ignored.
+        let mut other = testing::config(30, 15).unwrap();
+        other.set_stateless_reset_key(&[0xbb; 32]);
+
+        let dcid = pipe.client.destination_id().into_owned();
+        let len = crate::stateless_reset(&other, &dcid, 1200, &mut buf).unwrap();
+
+        assert_eq!(pipe.client_recv(&mut buf[..len]), Ok(len));
+        assert!(!pipe.client.is_stateless_reset());
+        assert!(!pipe.client.is_draining());
+
+        // Clients don't advertise a stateless reset token.
+        assert_eq!(
+            pipe.server.peer_transport_params.stateless_reset_token,
+            None
+        );
+
+        // Received packets that are too small can't be answered.
+        assert_eq!(
+            crate::stateless_reset(&config, &dcid, 21, &mut buf),
+            Err(Error::Done)
+        );
+
+        let config = Config::new(PROTOCOL_VERSION).unwrap();
+        assert_eq!(
+            crate::stateless_reset(&config, &dcid, 1200, &mut buf),
+            Err(Error::InvalidState)
+        );
+    }
+
     #[test]
     fn missing_retry_source_connection_id() {
         let mut buf = [0; 65535];
@@ -9501,7 +15222,8 @@ mod tests {
         // Server accepts connection and send first flight. But original
         // destination connection ID is ignored.
         let from = "127.0.0.1:1234".parse().unwrap();
-        pipe.server = accept(&scid, None, from, &mut config).unwrap();
+        let to = "127.0.0.1:4321".parse().unwrap();
+        pipe.server = accept(&scid, None, to, from, &mut config).unwrap();
         assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
         let flight = testing::emit_flight(&mut pipe.server).unwrap();
@@ -9559,8 +15281,9 @@ mod tests {
         // Server accepts connection and send first flight. But original
         // destination connection ID is invalid.
         let from = "127.0.0.1:1234".parse().unwrap();
+        let to = "127.0.0.1:4321".parse().unwrap();
         let odcid = ConnectionId::from_ref(b"bogus value");
-        pipe.server = accept(&scid, Some(&odcid), from, &mut config).unwrap();
+        pipe.server = accept(&scid, Some(&odcid), to, from, &mut config).unwrap();
         assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
         let flight = testing::emit_flight(&mut pipe.server).unwrap();
@@ -9811,7 +15534,10 @@ mod tests {
         assert_eq!(pipe.advance(), Ok(()));
 
         // app_limited should be true because we send less than cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), true);
+        assert_eq!(
+            pipe.server.paths.get_active_mut().recovery.app_limited(),
+            true
+        );
     }
 
     #[test]
@@ -9846,7 +15572,10 @@ mod tests {
 
         // We can't create a new packet header because there is no room by cwnd.
         // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
+        assert_eq!(
+            pipe.server.paths.get_active_mut().recovery.app_limited(),
+            false
+        );
     }
 
     #[test]
@@ -9881,7 +15610,10 @@ mod tests {
 
         // We can't create a new packet header because there is no room by cwnd.
         // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
+        assert_eq!(
+            pipe.server.paths.get_active_mut().recovery.app_limited(),
+            false
+        );
     }
 
     #[test]
@@ -9916,7 +15648,10 @@ mod tests {
 
         // We can't create a new frame because there is no room by cwnd.
         // app_limited should be false because we can't send more by cwnd.
-        assert_eq!(pipe.server.recovery.app_limited(), false);
+        assert_eq!(
+            pipe.server.paths.get_active_mut().recovery.app_limited(),
+            false
+        );
     }
 
     #[test]
@@ -9945,13 +15680,19 @@ mod tests {
 
         // Client's app_limited is true because its bytes-in-flight
         // is much smaller than the current cwnd.
-        assert_eq!(pipe.client.recovery.app_limited(), true);
+        assert_eq!(
+            pipe.client.paths.get_active_mut().recovery.app_limited(),
+            true
+        );
 
         // Client has no new frames to send - returns Done.
         assert_eq!(testing::emit_flight(&mut pipe.client), Err(Error::Done));
 
         // Client's app_limited should remain the same.
-        assert_eq!(pipe.client.recovery.app_limited(), true);
+        assert_eq!(
+            pipe.client.paths.get_active_mut().recovery.app_limited(),
+            true
+        );
     }
 
     #[test]
@@ -10229,9 +15970,6 @@ mod tests {
 
     #[test]
     /// Tests that changing a stream's priority is correctly propagated.
-    ///
-    /// Re-prioritization is not supported, so this should fail.
-    #[should_panic]
     fn stream_reprioritize() {
         let mut buf = [0; 65535];
 
@@ -10347,6 +16085,177 @@ mod tests {
         assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
     }
 
+    #[test]
+    /// Tests that changing the incremental flag of a queued stream is
+    /// correctly propagated.
+    fn stream_reprioritize_incremental() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 1];
+
+        for id in [0, 4, 8] {
+            pipe.server.stream_recv(id, &mut b).unwrap();
+            assert_eq!(pipe.server.stream_priority(id, 42, false), Ok(()));
+            pipe.server.stream_send(id, b"b", false).unwrap();
+        }
+
+        // Stream 0 becomes incremental, so it is scheduled after the
+        // non-incremental streams with the same urgency.
+        assert_eq!(pipe.server.stream_priority(0, 42, true), Ok(()));
+
+        for id in [4, 8, 0] {
+            let (len, _) = pipe.server.send(&mut buf).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+            assert_eq!(
+                frames.iter().next(),
+                Some(&frame::Frame::Stream {
+                    stream_id: id,
+                    data: stream::RangeBuf::from(b"b", 0, false),
+                })
+            );
+        }
+
+        assert_eq!(pipe.server.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn stream_reprioritize_repeatedly() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        let mut b = [0; 1];
+
+        for id in [0, 4, 8] {
+            pipe.server.stream_recv(id, &mut b).unwrap();
+            assert_eq!(pipe.server.stream_priority(id, 42, false), Ok(()));
+            pipe.server.stream_send(id, b"b", false).unwrap();
+        }
+
+        // Stream 4 moves away and back, and is still only scheduled once.
+        assert_eq!(pipe.server.stream_priority(4, 10, false), Ok(()));
+        assert_eq!(pipe.server.stream_priority(4, 42, false), Ok(()));
+
+        // Stream 0 moves to a lower priority, so it is scheduled last.
+        assert_eq!(pipe.server.stream_priority(0, 100, false), Ok(()));
+
+        let mut sent = Vec::new();
+
+        while let Ok((len, _)) = pipe.server.send(&mut buf) {
+            let frames =
+                testing::decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+            sent.extend(frames.iter().filter_map(|f| match f {
+                frame::Frame::Stream { stream_id, .. } => Some(*stream_id),
+
+                _ => None,
+            }));
+        }
+
+        assert_eq!(sent, vec![4, 8, 0]);
+    }
+
+    #[test]
+    /// Tests that streams are scheduled according to their weights with the
+    /// weighted round-robin scheduler, regardless of their urgency.
+    fn stream_scheduler_weighted_round_robin() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(1_000_000, 1_000_000).unwrap();
+        config.set_initial_max_streams_bidi(100);
+        config.set_stream_scheduler(StreamScheduler::WeightedRoundRobin);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Stream 4 has higher urgency, but lower weight.
+        assert_eq!(pipe.client.stream_weight(0, 2), Ok(()));
+        assert_eq!(pipe.client.stream_weight(4, 1), Ok(()));
+        assert_eq!(pipe.client.stream_priority(4, 0, false), Ok(()));
+
+        let out = [b'b'; 5_000];
+
+        assert_eq!(pipe.client.stream_send(0, &out, false), Ok(5_000));
+        assert_eq!(pipe.client.stream_send(4, &out, false), Ok(5_000));
+
+        let mut sent = Vec::new();
+
+        for _ in 0..6 {
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+            for frame in frames {
+                if let frame::Frame::Stream { stream_id, .. } = frame {
+                    sent.push(stream_id);
+                }
+            }
+        }
+
+        assert_eq!(sent, [0, 0, 4, 0, 0, 4]);
+    }
+
+    #[test]
+    /// Tests that streams share the bandwidth according to their weights with
+    /// the deficit round-robin scheduler, without starving any of them.
+    fn stream_scheduler_deficit_round_robin() {
+        let mut buf = [0; 65535];
+
+        let mut config = testing::config(1_000_000, 1_000_000).unwrap();
+        config.set_initial_max_streams_bidi(100);
+        config.set_stream_scheduler(StreamScheduler::DeficitRoundRobin);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // Stream 0 has the highest urgency, but the lowest weight.
+        assert_eq!(pipe.client.stream_weight(0, 1), Ok(()));
+        assert_eq!(pipe.client.stream_weight(4, 8), Ok(()));
+        assert_eq!(pipe.client.stream_priority(0, 0, false), Ok(()));
+
+        let out = [b'b'; 6_000];
+
+        assert_eq!(pipe.client.stream_send(0, &out, false), Ok(6_000));
+        assert_eq!(pipe.client.stream_send(4, &out, false), Ok(6_000));
+
+        let mut sent = Vec::new();
+
+        for _ in 0..8 {
+            let (len, _) = pipe.client.send(&mut buf).unwrap();
+
+            let frames =
+                testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+            for frame in frames {
+                if let frame::Frame::Stream { stream_id, .. } = frame {
+                    sent.push(stream_id);
+                }
+            }
+        }
+
+        // Stream 0 overshoots its share in the first round, so it needs to
+        // wait for stream 4 to catch up before being served again.
+        assert_eq!(sent, [0, 4, 4, 4, 4, 4, 4, 0]);
+    }
+
     #[test]
     /// Tests that streams and datagrams are correctly scheduled.
     fn stream_datagram_priority() {
@@ -10498,18 +16407,22 @@ mod tests {
         assert_eq!(pipe.client.stream_send(4, b"b", false), Ok(1));
         assert!(pipe.client.send(&mut buf).is_ok());
 
-        // Wait until PTO expires. Since the RTT is very low, wait a bit more.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.client.on_timeout();
+        // Move time forward until PTO expires.
+        let timer = pipe.client.timeout_instant().unwrap();
+        pipe.client.on_timeout_at(timer);
 
         let epoch = packet::EPOCH_APPLICATION;
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            1
+        );
 
         // Client retransmits stream data in PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
+        let (len, _) = pipe.client.send_at(&mut buf, timer).unwrap();
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            0
+        );
 
         let frames =
             testing::decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
@@ -10540,37 +16453,48 @@ mod tests {
         let (len, _) = pipe.client.send(&mut buf).unwrap();
         assert_eq!(len, 1200);
 
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.client.on_timeout();
+        // Move time forward until PTO expires.
+        let timer = pipe.client.timeout_instant().unwrap();
+        pipe.client.on_timeout_at(timer);
 
         let epoch = packet::EPOCH_INITIAL;
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            1
+        );
 
         // Client sends PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let (len, _) = pipe.client.send_at(&mut buf, timer).unwrap();
         assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
-
-        // Wait for PTO to expire.
-        let timer = pipe.client.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            0
+        );
 
-        pipe.client.on_timeout();
+        // Move time forward until PTO expires.
+        let timer = pipe.client.timeout_instant().unwrap();
+        pipe.client.on_timeout_at(timer);
 
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 2);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            2
+        );
 
         // Client sends first PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let (len, _) = pipe.client.send_at(&mut buf, timer).unwrap();
         assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 1);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            1
+        );
 
         // Client sends second PTO probe.
-        let (len, _) = pipe.client.send(&mut buf).unwrap();
+        let (len, _) = pipe.client.send_at(&mut buf, timer).unwrap();
         assert_eq!(len, 1200);
-        assert_eq!(pipe.client.recovery.loss_probes[epoch], 0);
+        assert_eq!(
+            pipe.client.paths.get_active().recovery.loss_probes[epoch],
+            0
+        );
     }
 
     #[test]
@@ -10676,13 +16600,33 @@ mod tests {
         testing::process_flight(&mut pipe.client, flight).unwrap();
 
         // Client sends Initial packet with ACK.
-        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
+        let (ty, len) = pipe
+            .client
+            .send_single(
+                &mut buf,
+                0,
+                false,
+                EcnCodepoint::NotEct,
+                false,
+                time::Instant::now(),
+            )
+            .unwrap();
         assert_eq!(ty, Type::Initial);
 
         assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
         // Client sends Handshake packet.
-        let (ty, len) = pipe.client.send_single(&mut buf, false).unwrap();
+        let (ty, len) = pipe
+            .client
+            .send_single(
+                &mut buf,
+                0,
+                false,
+                EcnCodepoint::NotEct,
+                false,
+                time::Instant::now(),
+            )
+            .unwrap();
         assert_eq!(ty, Type::Handshake);
 
         // Packet type is corrupted to Initial.
@@ -10738,14 +16682,14 @@ mod tests {
             assert_eq!(pipe.client.dgram_send(&send_buf), Ok(()));
         }
 
-        assert!(!pipe.client.recovery.app_limited());
+        assert!(!pipe.client.paths.get_active_mut().recovery.app_limited());
         assert_eq!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
 
         let (len, _) = pipe.client.send(&mut buf).unwrap();
 
         assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
         assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
-        assert!(!pipe.client.recovery.app_limited());
+        assert!(!pipe.client.paths.get_active_mut().recovery.app_limited());
 
         assert_eq!(pipe.server_recv(&mut buf[..len]), Ok(len));
 
@@ -10758,7 +16702,7 @@ mod tests {
         assert_ne!(pipe.client.dgram_send_queue.byte_size(), 0);
         assert_ne!(pipe.client.dgram_send_queue.byte_size(), 1_000_000);
 
-        assert!(!pipe.client.recovery.app_limited());
+        assert!(!pipe.client.paths.get_active_mut().recovery.app_limited());
     }
 
     #[test]
@@ -11245,22 +17189,183 @@ mod tests {
                 Some("quic.tech"),
                 &client_scid,
                 client_addr,
+                server_addr,
                 &mut client_config,
             )
             .unwrap(),
-            server: accept(&server_scid, None, server_addr, &mut server_config)
-                .unwrap(),
+            server: accept(
+                &server_scid,
+                None,
+                server_addr,
+                client_addr,
+                &mut server_config,
+            )
+            .unwrap(),
         };
 
         // Before handshake
-        assert_eq!(pipe.server.recovery.max_datagram_size(), 1500);
+        assert_eq!(
+            pipe.server.paths.get_active().recovery.max_datagram_size(),
+            1500
+        );
 
         assert_eq!(pipe.handshake(), Ok(()));
 
         // After handshake, max_datagram_size should match to client's
         // max_recv_udp_payload_size which is smaller
-        assert_eq!(pipe.server.recovery.max_datagram_size(), 1200);
-        assert_eq!(pipe.server.recovery.cwnd(), 12000);
+        assert_eq!(
+            pipe.server.paths.get_active().recovery.max_datagram_size(),
+            1200
+        );
+        assert_eq!(pipe.server.paths.get_active().recovery.cwnd(), 12000);
+    }
+
+    // Exchanges flights between the client and the server, dropping datagrams
+    // larger than `mtu`.
+    fn advance_with_mtu(pipe: &mut testing::Pipe, mtu: usize) -> Result<()> {
+        let mut client_done = false;
+        let mut server_done = false;
+
+        while !client_done || !server_done {
+            match testing::emit_flight(&mut pipe.client) {
+                Ok(mut flight) => {
+                    flight.retain(|(pkt, _)| pkt.len() <= mtu);
+                    testing::process_flight(&mut pipe.server, flight)?;
+                },
+
+                Err(Error::Done) => client_done = true,
+
+                Err(e) => return Err(e),
+            };
+
+            match testing::emit_flight(&mut pipe.server) {
+                Ok(mut flight) => {
+                    flight.retain(|(pkt, _)| pkt.len() <= mtu);
+                    testing::process_flight(&mut pipe.client, flight)?;
+                },
+
+                Err(Error::Done) => server_done = true,
+
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pmtud() {
+        let mut config = testing::config(1000000, 1000000).unwrap();
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        // Start from the base PLPMTU.
+        assert_eq!(pipe.client.stats().pmtu, 1200);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+        assert_eq!(pipe.client.stats().pmtu, 1200);
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1200);
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        // The probe was acked, raising the PMTU.
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+        assert_eq!(pipe.server.stats().pmtu, 1500);
+        assert_eq!(pipe.client.max_send_udp_payload_size(), 1500);
+
+        // Packets now use the larger size.
+        assert_eq!(pipe.client.stream_send(4, &[42; 5000], true), Ok(5000));
+
+        let flight = testing::emit_flight(&mut pipe.client).unwrap();
+        assert_eq!(flight[0].0.len(), 1500);
+    }
+
+    #[test]
+    fn pmtud_disabled() {
+        let mut config = testing::config(1000000, 1000000).unwrap();
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
+        config.discover_pmtu(false);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+    }
+
+    #[test]
+    fn pmtud_search() {
+        let mut config = testing::config(1000000, 1000000).unwrap();
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        // The path drops datagrams larger than 1350 bytes.
+        for _ in 0..100 {
+            assert_eq!(pipe.client.stream_send(0, &[42; 100], false), Ok(100));
+            assert_eq!(advance_with_mtu(&mut pipe, 1350), Ok(()));
+        }
+
+        let pmtu = pipe.client.stats().pmtu;
+        assert!(pmtu <= 1350 && pmtu > 1300, "pmtu={}", pmtu);
+
+        // Probe losses are not counted as congestion.
+        assert_eq!(pipe.client.stats().lost, 0);
+    }
+
+    #[test]
+    fn pmtud_black_hole() {
+        let mut config = testing::config(1000000, 1000000).unwrap();
+        config.set_max_send_udp_payload_size(1500);
+        config.discover_pmtu(true);
+
+        let mut pipe = testing::Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"hello", false), Ok(5));
+        assert_eq!(pipe.advance(), Ok(()));
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+
+        // The path MTU decreases, so full sized packets are dropped while
+        // smaller ones are still delivered.
+        for _ in 0..10 {
+            assert_eq!(pipe.client.stream_send(0, &[42; 1500], false), Ok(1500));
+            assert_eq!(advance_with_mtu(&mut pipe, 1300), Ok(()));
+
+            // Acknowledging the small packets that follow the dropped ones
+            // gets them declared lost by the packet threshold, regardless of
+            // how much time elapsed.
+            for _ in 0..3 {
+                assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+                assert_eq!(advance_with_mtu(&mut pipe, 1300), Ok(()));
+            }
+        }
+
+        // The black hole was detected and the search restarted below the
+        // previous size.
+        let pmtu = pipe.client.stats().pmtu;
+        assert!(pmtu <= 1300, "pmtu={}", pmtu);
+
+        for _ in 0..100 {
+            assert_eq!(pipe.client.stream_send(0, &[42; 100], false), Ok(100));
+            assert_eq!(advance_with_mtu(&mut pipe, 1300), Ok(()));
+        }
+
+        let pmtu = pipe.client.stats().pmtu;
+        assert!(pmtu <= 1300 && pmtu > 1250, "pmtu={}", pmtu);
     }
 
     #[test]
@@ -11328,6 +17433,71 @@ mod tests {
         assert_eq!(pipe.advance(), Ok(()));
     }
 
+    #[test]
+    fn virtual_time_idle_timeout() {
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let now = time::Instant::now();
+
+        // Jump ahead to right before the idle timeout expires.
+        let before = now + time::Duration::from_secs(179);
+        pipe.client.on_timeout_at(before);
+        assert!(!pipe.client.is_closed());
+        assert!(pipe.client.timeout_at(before) > Some(time::Duration::ZERO));
+
+        // And then past it, without actually waiting.
+        let after = now + time::Duration::from_secs(181);
+        assert_eq!(pipe.client.timeout_at(after), Some(time::Duration::ZERO));
+
+        pipe.client.on_timeout_at(after);
+        assert!(pipe.client.is_closed());
+        assert!(pipe.client.is_timed_out());
+        assert_eq!(pipe.client.timeout_instant(), None);
+    }
+
+    #[test]
+    fn virtual_time_loss_recovery() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = testing::Pipe::default().unwrap();
+        assert_eq!(pipe.handshake(), Ok(()));
+
+        let now = time::Instant::now();
+
+        // The packet carrying the stream data is lost.
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert!(pipe.client.send_at(&mut buf, now).is_ok());
+        assert_eq!(pipe.client.send_at(&mut buf, now), Err(Error::Done));
+
+        // Fire the PTO as soon as it is due.
+        let pto = pipe
+            .client
+            .paths
+            .get_active()
+            .recovery
+            .loss_detection_timer()
+            .unwrap();
+        assert!(pto > now);
+        assert_eq!(pipe.client.timeout_at(pto), Some(time::Duration::ZERO));
+
+        pipe.client.on_timeout_at(pto);
+
+        // The probes retransmit the data to the server.
+        while let Ok((len, info)) = pipe.client.send_at(&mut buf, pto) {
+            let info = RecvInfo {
+                from: info.from,
+                to: info.to,
+                ecn: info.ecn,
+            };
+
+            assert_eq!(pipe.server.recv_at(&mut buf[..len], info, pto), Ok(len));
+        }
+
+        assert_eq!(pipe.server.stream_recv(4, &mut buf), Ok((5, true)));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
     #[cfg(feature = "boringssl-boring-crate")]
     #[test]
     fn user_provided_boring_ctx() -> Result<()> {
@@ -11383,9 +17553,16 @@ mod tests {
                 Some("quic.tech"),
                 &client_scid,
                 client_addr,
+                server_addr,
                 &mut client_config,
             )?,
-            server: accept(&server_scid, None, server_addr, &mut server_config)?,
+            server: accept(
+                &server_scid,
+                None,
+                server_addr,
+                client_addr,
+                &mut server_config,
+            )?,
         };
 
         assert_eq!(pipe.handshake(), Ok(()));
@@ -11430,15 +17607,13 @@ mod tests {
         assert_eq!(pipe.server.stream_send(8, &buf, false), Ok(800));
         assert_eq!(pipe.server.stream_send(4, &buf, false), Err(Error::Done));
 
-        // Wait for PTO to expire.
-        let timer = pipe.server.timeout().unwrap();
-        std::thread::sleep(timer + time::Duration::from_millis(1));
-
-        pipe.server.on_timeout();
+        // Move time forward until PTO expires.
+        let timer = pipe.server.timeout_instant().unwrap();
+        pipe.server.on_timeout_at(timer);
 
         // Server sends PTO probe (not limited to cwnd),
         // to update last_tx_data.
-        let (len, _) = pipe.server.send(&mut buf).unwrap();
+        let (len, _) = pipe.server.send_at(&mut buf, timer).unwrap();
         assert_eq!(len, 1200);
 
         // Client sends STOP_SENDING to decrease tx_data
@@ -11455,25 +17630,46 @@ mod tests {
     }
 }
 
+pub use crate::endpoint::Endpoint;
+
 pub use crate::packet::ConnectionId;
 pub use crate::packet::Header;
 pub use crate::packet::Type;
 
+pub use crate::path::PathEvent;
+pub use crate::path::SocketAddrIter;
+
+pub use crate::recovery::AckedPacket;
+pub use crate::recovery::CongestionControl;
 pub use crate::recovery::CongestionControlAlgorithm;
+pub use crate::recovery::CongestionControlFactory;
+pub use crate::recovery::DeliveryRateSample;
+pub use crate::recovery::RttSample;
 
+pub use crate::stream::SharedBuf;
+pub use crate::stream::StreamBuf;
 pub use crate::stream::StreamIter;
+pub use crate::stream::StreamScheduler;
+
+pub use crate::token::AddressToken;
 
+mod ack_freq;
+mod cid;
 mod crypto;
 mod dgram;
+mod endpoint;
 #[cfg(feature = "ffi")]
 mod ffi;
 mod flowcontrol;
 mod frame;
 pub mod h3;
 mod minmax;
+pub mod netsim;
 mod packet;
+mod path;
 mod rand;
 mod ranges;
 mod recovery;
 mod stream;
 mod tls;
+mod token;
@@ -0,0 +1,757 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Network simulator running connections on virtual time.
+//!
+//! A [`Simulator`] moves datagrams between a client and a server connection
+//! over two simulated one-way links, one per direction. Each [`LinkConfig`]
+//! describes the propagation delay, the bandwidth and bottleneck queue, and
+//! the impairments (loss, reordering, duplication and MTU limit) of a link.
+//!
+//! Time only advances when the simulator jumps to the next event, which is
+//! either the arrival of a datagram or the expiration of a connection timer,
+//! so long transfers over slow links run as fast as the CPU allows. Random
+//! impairments are drawn from a seeded generator, so a simulation with the
+//! same links and the same sequence of datagrams behaves the same every time.
+//!
+//! [`Simulator`]: struct.Simulator.html
+//! [`LinkConfig`]: struct.LinkConfig.html
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use std::time;
+
+use crate::Connection;
+use crate::Error;
+use crate::RecvInfo;
+use crate::Result;
+use crate::SendInfo;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// How datagrams are randomly lost on a link.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    /// No datagram is lost.
+    None,
+
+    /// Each datagram is lost independently with the given probability.
+    Random(f64),
+
+    /// Datagrams are lost in bursts, following a Gilbert-Elliott model.
+    ///
+    /// For each datagram, the link may enter or leave the lossy state. All
+    /// datagrams are lost while the link is in the lossy state.
+    Burst {
+        /// The probability of entering the lossy state.
+        enter: f64,
+
+        /// The probability of leaving the lossy state.
+        exit: f64,
+    },
+}
+
+/// The properties of a simulated one-way link.
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// The one-way propagation delay.
+    pub delay: time::Duration,
+
+    /// The bandwidth of the bottleneck, in bits per second. When `None` the
+    /// bandwidth is unlimited and datagrams are never queued.
+    pub bandwidth: Option<u64>,
+
+    /// The size of the bottleneck queue, in bytes. Datagrams arriving when
+    /// the queue is full are dropped. When `None` the queue is unbounded.
+    pub queue_size: Option<usize>,
+
+    /// How datagrams are randomly lost.
+    pub loss: Loss,
+
+    /// The probability of a datagram being delayed by `reorder_delay`, so
+    /// that it is overtaken by the following ones.
+    pub reorder: f64,
+
+    /// The extra delay of reordered datagrams.
+    pub reorder_delay: time::Duration,
+
+    /// The probability of a datagram being delivered twice.
+    pub duplicate: f64,
+
+    /// The largest datagram the link can carry. Larger datagrams are dropped.
+    pub mtu: Option<usize>,
+
+    /// The seed of the generator used for random impairments.
+    pub seed: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            delay: time::Duration::ZERO,
+            bandwidth: None,
+            queue_size: None,
+            loss: Loss::None,
+            reorder: 0.0,
+            reorder_delay: time::Duration::ZERO,
+            duplicate: 0.0,
+            mtu: None,
+            seed: 0,
+        }
+    }
+}
+
+/// Statistics about the datagrams that went through a link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// The number of datagrams sent over the link.
+    pub sent: usize,
+
+    /// The number of bytes sent over the link.
+    pub sent_bytes: usize,
+
+    /// The number of datagrams delivered, including duplicates.
+    pub delivered: usize,
+
+    /// The number of bytes delivered, including duplicates.
+    pub delivered_bytes: usize,
+
+    /// The number of datagrams randomly lost.
+    pub lost: usize,
+
+    /// The number of datagrams dropped because the queue was full.
+    pub queue_drops: usize,
+
+    /// The number of datagrams dropped because they exceeded the MTU.
+    pub mtu_drops: usize,
+
+    /// The number of datagrams reordered.
+    pub reordered: usize,
+
+    /// The number of datagrams duplicated.
+    pub duplicated: usize,
+}
+
+/// A client and a server connection communicating over simulated links.
+pub struct Simulator {
+    /// The client connection.
+    pub client: Connection,
+
+    /// The server connection.
+    pub server: Connection,
+
+    start: time::Instant,
+
+    now: time::Instant,
+
+    to_server: Link,
+
+    to_client: Link,
+
+    out: Vec<u8>,
+}
+
+impl Simulator {
+    /// Creates a simulator with the same link properties in both directions.
+    ///
+    /// The two directions use different seeds derived from the configured
+    /// one, so that their random impairments are not correlated.
+    pub fn new(
+        client: Connection, server: Connection, link: &LinkConfig,
+    ) -> Simulator {
+        let mut to_client = link.clone();
+        to_client.seed = !link.seed;
+
+        Simulator::with_links(client, server, link.clone(), to_client)
+    }
+
+    /// Creates a simulator with different link properties for each
+    /// direction.
+    pub fn with_links(
+        client: Connection, server: Connection, to_server: LinkConfig,
+        to_client: LinkConfig,
+    ) -> Simulator {
+        let now = time::Instant::now();
+
+        Simulator {
+            client,
+            server,
+            start: now,
+            now,
+            to_server: Link::new(to_server),
+            to_client: Link::new(to_client),
+            out: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Returns the current virtual time.
+    pub fn now(&self) -> time::Instant {
+        self.now
+    }
+
+    /// Returns the virtual time elapsed since the simulator was created.
+    pub fn elapsed(&self) -> time::Duration {
+        self.now - self.start
+    }
+
+    /// Returns the statistics of the link from the client to the server.
+    pub fn client_to_server_stats(&self) -> LinkStats {
+        self.to_server.stats
+    }
+
+    /// Returns the statistics of the link from the server to the client.
+    pub fn server_to_client_stats(&self) -> LinkStats {
+        self.to_client.stats
+    }
+
+    /// Advances the simulation to the next event.
+    ///
+    /// Both connections first send all the datagrams they can at the current
+    /// time. The virtual time then jumps to the next datagram arrival or timer
+    /// expiration, and the corresponding datagrams and timeouts are processed.
+    ///
+    /// Returns `false` when there is no event left, which is the case when
+    /// both connections are closed and no datagram is in flight.
+    pub fn step(&mut self) -> Result<bool> {
+        self.flush()?;
+
+        let next = match self.next_event() {
+            Some(v) => v,
+
+            None => return Ok(false),
+        };
+
+        self.advance_to(std::cmp::max(next, self.now));
+
+        Ok(true)
+    }
+
+    /// Runs the simulation until `deadline`, or until `done` returns `true`.
+    ///
+    /// The `done` callback is invoked after every event, and can be used to
+    /// drive the application, for example by sending and receiving stream
+    /// data. Returns whether `done` returned `true` before the deadline.
+    pub fn run_until<F>(
+        &mut self, deadline: time::Instant, mut done: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(&mut Simulator) -> bool,
+    {
+        loop {
+            if done(self) {
+                return Ok(true);
+            }
+
+            self.flush()?;
+
+            match self.next_event() {
+                Some(next) if next <= deadline => {
+                    self.advance_to(std::cmp::max(next, self.now));
+                },
+
+                _ => {
+                    self.now = std::cmp::max(deadline, self.now);
+
+                    return Ok(done(self));
+                },
+            }
+        }
+    }
+
+    /// Runs the simulation for the given amount of virtual time.
+    pub fn run_for(&mut self, duration: time::Duration) -> Result<()> {
+        let deadline = self.now + duration;
+
+        self.run_until(deadline, |_| false)?;
+
+        Ok(())
+    }
+
+    /// Runs the simulation until both connections are established.
+    ///
+    /// Returns [`Done`] if the handshake doesn't complete before the
+    /// connections are closed.
+    ///
+    /// [`Done`]: ../enum.Error.html#variant.Done
+    pub fn handshake(&mut self) -> Result<()> {
+        while !self.client.is_established() || !self.server.is_established() {
+            if self.client.is_closed() ||
+                self.server.is_closed() ||
+                !self.step()?
+            {
+                return Err(Error::Done);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let now = self.now;
+
+        send_all(&mut self.client, &mut self.to_server, &mut self.out, now)?;
+        send_all(&mut self.server, &mut self.to_client, &mut self.out, now)?;
+
+        Ok(())
+    }
+
+    fn next_event(&self) -> Option<time::Instant> {
+        [
+            self.to_server.next_arrival(),
+            self.to_client.next_arrival(),
+            self.client.timeout_instant(),
+            self.server.timeout_instant(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    fn advance_to(&mut self, now: time::Instant) {
+        self.now = now;
+
+        while let Some((mut buf, info)) = self.to_server.pop_arrived(now) {
+            // Errors close the connection, which is observable by the caller.
+            self.server.recv_at(&mut buf, info, now).ok();
+        }
+
+        while let Some((mut buf, info)) = self.to_client.pop_arrived(now) {
+            self.client.recv_at(&mut buf, info, now).ok();
+        }
+
+        for conn in [&mut self.client, &mut self.server] {
+            if matches!(conn.timeout_instant(), Some(t) if t <= now) {
+                conn.on_timeout_at(now);
+            }
+        }
+    }
+}
+
+fn send_all(
+    conn: &mut Connection, link: &mut Link, out: &mut [u8], now: time::Instant,
+) -> Result<()> {
+    loop {
+        let (written, info) = match conn.send_at(out, now) {
+            Ok(v) => v,
+
+            Err(Error::Done) => return Ok(()),
+
+            Err(e) => return Err(e),
+        };
+
+        link.send(&out[..written], &info, now);
+    }
+}
+
+struct Link {
+    config: LinkConfig,
+
+    stats: LinkStats,
+
+    rng: Rng,
+
+    /// Whether the burst loss model is in the lossy state.
+    lossy: bool,
+
+    /// The time each queued datagram leaves the bottleneck, and its size.
+    queue: VecDeque<(time::Instant, usize)>,
+
+    queue_bytes: usize,
+
+    /// The datagrams in flight, keyed by arrival time and sequence number.
+    in_flight: BTreeMap<(time::Instant, u64), (Vec<u8>, RecvInfo)>,
+
+    next_seq: u64,
+}
+
+impl Link {
+    fn new(config: LinkConfig) -> Link {
+        let rng = Rng::new(config.seed);
+
+        Link {
+            config,
+            stats: LinkStats::default(),
+            rng,
+            lossy: false,
+            queue: VecDeque::new(),
+            queue_bytes: 0,
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn send(&mut self, buf: &[u8], info: &SendInfo, now: time::Instant) {
+        self.stats.sent += 1;
+        self.stats.sent_bytes += buf.len();
+
+        if matches!(self.config.mtu, Some(mtu) if buf.len() > mtu) {
+            self.stats.mtu_drops += 1;
+            return;
+        }
+
+        if self.is_lost() {
+            self.stats.lost += 1;
+            return;
+        }
+
+        // Paced datagrams only enter the link at their scheduled time.
+        let sent = std::cmp::max(now, info.at);
+
+        let mut arrival = match self.enqueue(buf.len(), sent) {
+            Some(v) => v + self.config.delay,
+
+            None => {
+                self.stats.queue_drops += 1;
+                return;
+            },
+        };
+
+        if self.rng.chance(self.config.reorder) {
+            self.stats.reordered += 1;
+            arrival += self.config.reorder_delay;
+        }
+
+        let info = RecvInfo {
+            from: info.from,
+            to: info.to,
+            ecn: info.ecn,
+        };
+
+        if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            self.push(arrival, buf.to_vec(), info);
+        }
+
+        self.push(arrival, buf.to_vec(), info);
+    }
+
+    /// Returns the time the datagram leaves the bottleneck queue, or `None`
+    /// if it doesn't fit in the queue.
+    fn enqueue(
+        &mut self, len: usize, sent: time::Instant,
+    ) -> Option<time::Instant> {
+        let bandwidth = match self.config.bandwidth {
+            Some(v) => v,
+
+            None => return Some(sent),
+        };
+
+        while let Some(&(departure, size)) = self.queue.front() {
+            if departure > sent {
+                break;
+            }
+
+            self.queue.pop_front();
+            self.queue_bytes -= size;
+        }
+
+        if matches!(self.config.queue_size, Some(max) if self.queue_bytes + len > max)
+        {
+            return None;
+        }
+
+        let start = match self.queue.back() {
+            Some(&(departure, _)) => std::cmp::max(departure, sent),
+
+            None => sent,
+        };
+
+        let tx_time = time::Duration::from_nanos(
+            (len as u64 * 8).saturating_mul(1_000_000_000) / bandwidth.max(1),
+        );
+
+        let departure = start + tx_time;
+
+        self.queue.push_back((departure, len));
+        self.queue_bytes += len;
+
+        Some(departure)
+    }
+
+    fn is_lost(&mut self) -> bool {
+        match self.config.loss {
+            Loss::None => false,
+
+            Loss::Random(p) => self.rng.chance(p),
+
+            Loss::Burst { enter, exit } => {
+                self.lossy = if self.lossy {
+                    !self.rng.chance(exit)
+                } else {
+                    self.rng.chance(enter)
+                };
+
+                self.lossy
+            },
+        }
+    }
+
+    fn push(&mut self, arrival: time::Instant, buf: Vec<u8>, info: RecvInfo) {
+        self.in_flight.insert((arrival, self.next_seq), (buf, info));
+        self.next_seq += 1;
+    }
+
+    fn next_arrival(&self) -> Option<time::Instant> {
+        self.in_flight.keys().next().map(|(arrival, _)| *arrival)
+    }
+
+    fn pop_arrived(&mut self, now: time::Instant) -> Option<(Vec<u8>, RecvInfo)> {
+        let key = *self.in_flight.keys().next()?;
+
+        if key.0 > now {
+            return None;
+        }
+
+        let (buf, info) = self.in_flight.remove(&key)?;
+
+        self.stats.delivered += 1;
+        self.stats.delivered_bytes += buf.len();
+
+        Some((buf, info))
+    }
+}
+
+/// A small deterministic pseudo-random generator (SplitMix64).
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns `true` with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+
+        // Use the top 53 bits to build a uniform value in [0, 1).
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    fn simulator(link: &LinkConfig) -> Simulator {
+        let mut config = testing::config(10_000_000, 10_000_000).unwrap();
+        config.set_max_idle_timeout(180_000);
+        config.grease(false);
+
+        testing::Pipe::with_config(&mut config)
+            .unwrap()
+            .into_simulator(link)
+    }
+
+    /// Transfers `len` bytes from the client to the server, and returns
+    /// whether the transfer completed within `timeout` of virtual time.
+    fn transfer(
+        sim: &mut Simulator, len: usize, timeout: time::Duration,
+    ) -> bool {
+        let data = vec![42; len];
+        let mut buf = [0; 65535];
+
+        let mut sent = 0;
+        let mut received = 0;
+
+        let deadline = sim.now() + timeout;
+
+        sim.run_until(deadline, |sim| {
+            if sent < len {
+                sent +=
+                    sim.client.stream_send(0, &data[sent..], true).unwrap_or(0);
+            }
+
+            while let Ok((read, fin)) = sim.server.stream_recv(0, &mut buf) {
+                received += read;
+
+                if fin {
+                    return true;
+                }
+            }
+
+            false
+        })
+        .unwrap() &&
+            received == len
+    }
+
+    #[test]
+    fn delay() {
+        let mut sim = simulator(&LinkConfig {
+            delay: time::Duration::from_millis(50),
+            ..Default::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+
+        // The server is established once it receives the client's Finished,
+        // after one and a half round-trips.
+        assert!(sim.elapsed() >= time::Duration::from_millis(150));
+        assert!(sim.elapsed() < time::Duration::from_millis(200));
+
+        let rtt = sim.client.stats().rtt;
+        assert!(rtt >= time::Duration::from_millis(100), "rtt={:?}", rtt);
+        assert!(rtt < time::Duration::from_millis(110), "rtt={:?}", rtt);
+
+        assert_eq!(sim.client_to_server_stats().lost, 0);
+    }
+
+    #[test]
+    fn bandwidth() {
+        let mut sim = simulator(&LinkConfig {
+            delay: time::Duration::from_millis(10),
+            bandwidth: Some(10_000_000),
+            queue_size: Some(100_000),
+            ..Default::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+
+        let start = sim.now();
+
+        assert!(transfer(&mut sim, 1_000_000, time::Duration::from_secs(10)));
+
+        // 1MB at 10Mbps takes at least 800ms.
+        let elapsed = sim.now() - start;
+        assert!(elapsed >= time::Duration::from_millis(800), "{:?}", elapsed);
+        assert!(elapsed < time::Duration::from_millis(2000), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn random_loss() {
+        let mut sim = simulator(&LinkConfig {
+            delay: time::Duration::from_millis(10),
+            loss: Loss::Random(0.05),
+            seed: 1,
+            ..Default::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+        assert!(transfer(&mut sim, 500_000, time::Duration::from_secs(30)));
+
+        assert!(sim.client_to_server_stats().lost > 0);
+        assert!(sim.client.stats().lost > 0);
+        assert!(sim.client.stats().retrans > 0);
+    }
+
+    #[test]
+    fn burst_loss() {
+        let mut sim = simulator(&LinkConfig {
+            delay: time::Duration::from_millis(10),
+            loss: Loss::Burst {
+                enter: 0.01,
+                exit: 0.2,
+            },
+            seed: 2,
+            ..Default::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+        assert!(transfer(&mut sim, 500_000, time::Duration::from_secs(30)));
+
+        assert!(sim.client_to_server_stats().lost > 0);
+    }
+
+    #[test]
+    fn reorder_and_duplicate() {
+        let mut sim = simulator(&LinkConfig {
+            delay: time::Duration::from_millis(10),
+            reorder: 0.1,
+            reorder_delay: time::Duration::from_millis(5),
+            duplicate: 0.05,
+            seed: 3,
+            ..Default::default()
+        });
+
+        assert_eq!(sim.handshake(), Ok(()));
+        assert!(transfer(&mut sim, 500_000, time::Duration::from_secs(30)));
+
+        let stats = sim.client_to_server_stats();
+        assert!(stats.reordered > 0);
+        assert!(stats.duplicated > 0);
+        assert!(stats.delivered <= stats.sent + stats.duplicated);
+    }
+
+    #[test]
+    fn mtu() {
+        let mut sim = simulator(&LinkConfig {
+            mtu: Some(1000),
+            ..Default::default()
+        });
+
+        // The client's Initial is too large for the link, so the handshake
+        // eventually times out. This only takes virtual time.
+        assert_eq!(sim.handshake(), Err(Error::Done));
+        assert!(sim.client.is_timed_out());
+        assert!(sim.client_to_server_stats().mtu_drops > 0);
+        assert!(sim.elapsed() >= time::Duration::from_secs(180));
+    }
+
+    #[test]
+    fn deterministic() {
+        let link = LinkConfig {
+            delay: time::Duration::from_millis(20),
+            bandwidth: Some(20_000_000),
+            queue_size: Some(50_000),
+            loss: Loss::Random(0.02),
+            seed: 4,
+            ..Default::default()
+        };
+
+        let mut stats = Vec::new();
+
+        for _ in 0..2 {
+            let mut sim = simulator(&link);
+
+            assert_eq!(sim.handshake(), Ok(()));
+            assert!(transfer(&mut sim, 200_000, time::Duration::from_secs(30)));
+
+            stats.push((
+                sim.client_to_server_stats(),
+                sim.server_to_client_stats(),
+            ));
+        }
+
+        assert_eq!(stats[0], stats[1]);
+    }
+}
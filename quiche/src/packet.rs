@@ -24,6 +24,7 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use std::cmp;
 use std::time;
 
 use ring::aead;
@@ -32,6 +33,7 @@ use crate::Error;
 use crate::Result;
 
 use crate::crypto;
+use crate::frame;
 use crate::rand;
 use crate::ranges;
 use crate::stream;
@@ -47,6 +49,15 @@ pub const MAX_CID_LEN: u8 = 20;
 
 pub const MAX_PKT_NUM_LEN: usize = 4;
 
+/// The minimum length of a stateless reset, made of the first byte, at least
+/// 4 unpredictable bytes and the stateless reset token.
+pub const MIN_STATELESS_RESET_LEN: usize = 21;
+
+/// The maximum length of the stateless resets we send.
+const MAX_STATELESS_RESET_LEN: usize = 43;
+
+const STATELESS_RESET_TOKEN_LEN: usize = 16;
+
 const SAMPLE_LEN: usize = 16;
 
 pub const EPOCH_INITIAL: usize = 0;
@@ -110,6 +121,50 @@ impl Type {
         }
     }
 
+    /// Decodes the long header packet type bits for the given version.
+    ///
+    /// QUIC version 2 uses different codepoints than version 1 (RFC 9369).
+    fn from_long_type(ty: u8, version: u32) -> Result<Type> {
+        if version == crate::PROTOCOL_VERSION_V2 {
+            return match ty {
+                0x00 => Ok(Type::Retry),
+                0x01 => Ok(Type::Initial),
+                0x02 => Ok(Type::ZeroRTT),
+                0x03 => Ok(Type::Handshake),
+                _ => Err(Error::InvalidPacket),
+            };
+        }
+
+        match ty {
+            0x00 => Ok(Type::Initial),
+            0x01 => Ok(Type::ZeroRTT),
+            0x02 => Ok(Type::Handshake),
+            0x03 => Ok(Type::Retry),
+            _ => Err(Error::InvalidPacket),
+        }
+    }
+
+    /// Encodes the long header packet type bits for the given version.
+    fn to_long_type(self, version: u32) -> Result<u8> {
+        if version == crate::PROTOCOL_VERSION_V2 {
+            return match self {
+                Type::Retry => Ok(0x00),
+                Type::Initial => Ok(0x01),
+                Type::ZeroRTT => Ok(0x02),
+                Type::Handshake => Ok(0x03),
+                _ => Err(Error::InvalidPacket),
+            };
+        }
+
+        match self {
+            Type::Initial => Ok(0x00),
+            Type::ZeroRTT => Ok(0x01),
+            Type::Handshake => Ok(0x02),
+            Type::Retry => Ok(0x03),
+            _ => Err(Error::InvalidPacket),
+        }
+    }
+
     #[cfg(feature = "qlog")]
     pub(crate) fn to_qlog(self) -> qlog::events::quic::PacketType {
         match self {
@@ -327,13 +382,7 @@ impl<'a> Header<'a> {
         let ty = if version == 0 {
             Type::VersionNegotiation
         } else {
-            match (first & TYPE_MASK) >> 4 {
-                0x00 => Type::Initial,
-                0x01 => Type::ZeroRTT,
-                0x02 => Type::Handshake,
-                0x03 => Type::Retry,
-                _ => return Err(Error::InvalidPacket),
-            }
+            Type::from_long_type((first & TYPE_MASK) >> 4, version)?
         };
 
         let dcid_len = b.get_u8()?;
@@ -423,13 +472,7 @@ impl<'a> Header<'a> {
         }
 
         // Encode long header.
-        let ty: u8 = match self.ty {
-            Type::Initial => 0x00,
-            Type::ZeroRTT => 0x01,
-            Type::Handshake => 0x02,
-            Type::Retry => 0x03,
-            _ => return Err(Error::InvalidPacket),
-        };
+        let ty = self.ty.to_long_type(self.version)?;
 
         first |= FORM_BIT | FIXED_BIT | (ty << 4);
 
@@ -685,7 +728,7 @@ pub fn encode_pkt_num(pn: u64, b: &mut octets::OctetsMut) -> Result<()> {
 }
 
 pub fn negotiate_version(
-    scid: &[u8], dcid: &[u8], out: &mut [u8],
+    scid: &[u8], dcid: &[u8], versions: &[u32], out: &mut [u8],
 ) -> Result<usize> {
     let mut b = octets::OctetsMut::with_slice(out);
 
@@ -698,10 +741,10 @@ pub fn negotiate_version(
     b.put_bytes(scid)?;
     b.put_u8(dcid.len() as u8)?;
     b.put_bytes(dcid)?;
-    b.put_u32(crate::PROTOCOL_VERSION_V1)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT29)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT28)?;
-    b.put_u32(crate::PROTOCOL_VERSION_DRAFT27)?;
+
+    for &version in versions {
+        b.put_u32(version)?;
+    }
 
     Ok(b.off())
 }
@@ -737,6 +780,49 @@ pub fn retry(
     Ok(b.off())
 }
 
+/// Writes a stateless reset with the given token, in response to a packet of
+/// `recv_len` bytes.
+///
+/// The stateless reset is always smaller than the packet it responds to, to
+/// avoid stateless reset loops between endpoints.
+pub fn stateless_reset(
+    token: u128, recv_len: usize, out: &mut [u8],
+) -> Result<usize> {
+    if recv_len <= MIN_STATELESS_RESET_LEN {
+        return Err(Error::Done);
+    }
+
+    let len = cmp::min(recv_len - 1, MAX_STATELESS_RESET_LEN);
+
+    if out.len() < len {
+        return Err(Error::BufferTooShort);
+    }
+
+    let token_off = len - STATELESS_RESET_TOKEN_LEN;
+
+    // The stateless reset looks like a short header packet, followed by
+    // unpredictable bytes.
+    rand::rand_bytes(&mut out[..token_off]);
+    out[0] = (out[0] & !FORM_BIT) | FIXED_BIT;
+
+    out[token_off..len].copy_from_slice(&token.to_be_bytes());
+
+    Ok(len)
+}
+
+/// Returns the token carried by the last bytes of the UDP datagram `buf`, if
+/// it might be a stateless reset.
+pub fn stateless_reset_token(buf: &[u8]) -> Option<u128> {
+    if buf.len() < MIN_STATELESS_RESET_LEN || buf[0] & FORM_BIT != 0 {
+        return None;
+    }
+
+    let mut token = [0; STATELESS_RESET_TOKEN_LEN];
+    token.copy_from_slice(&buf[buf.len() - STATELESS_RESET_TOKEN_LEN..]);
+
+    Some(u128::from_be_bytes(token))
+}
+
 pub fn verify_retry_integrity(
     b: &octets::OctetsMut, odcid: &[u8], version: u32,
 ) -> Result<()> {
@@ -763,31 +849,49 @@ fn compute_retry_integrity_tag(
         0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
     ];
 
+    const RETRY_INTEGRITY_KEY_V2: [u8; 16] = [
+        0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce,
+        0xad, 0x7c, 0xcc, 0x92,
+    ];
+
+    const RETRY_INTEGRITY_NONCE_V2: [u8; aead::NONCE_LEN] = [
+        0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+    ];
+
+    #[cfg(feature = "draft-versions")]
     const RETRY_INTEGRITY_KEY_DRAFT29: [u8; 16] = [
         0xcc, 0xce, 0x18, 0x7e, 0xd0, 0x9a, 0x09, 0xd0, 0x57, 0x28, 0x15, 0x5a,
         0x6c, 0xb9, 0x6b, 0xe1,
     ];
 
+    #[cfg(feature = "draft-versions")]
     const RETRY_INTEGRITY_NONCE_DRAFT29: [u8; aead::NONCE_LEN] = [
         0xe5, 0x49, 0x30, 0xf9, 0x7f, 0x21, 0x36, 0xf0, 0x53, 0x0a, 0x8c, 0x1c,
     ];
 
+    #[cfg(feature = "draft-versions")]
     const RETRY_INTEGRITY_KEY_DRAFT27: [u8; 16] = [
         0x4d, 0x32, 0xec, 0xdb, 0x2a, 0x21, 0x33, 0xc8, 0x41, 0xe4, 0x04, 0x3d,
         0xf2, 0x7d, 0x44, 0x30,
     ];
 
+    #[cfg(feature = "draft-versions")]
     const RETRY_INTEGRITY_NONCE_DRAFT27: [u8; aead::NONCE_LEN] = [
         0x4d, 0x16, 0x11, 0xd0, 0x55, 0x13, 0xa5, 0x52, 0xc5, 0x87, 0xd5, 0x75,
     ];
 
     let (key, nonce) = match version {
+        #[cfg(feature = "draft-versions")]
         crate::PROTOCOL_VERSION_DRAFT27 | crate::PROTOCOL_VERSION_DRAFT28 =>
             (&RETRY_INTEGRITY_KEY_DRAFT27, RETRY_INTEGRITY_NONCE_DRAFT27),
 
+        #[cfg(feature = "draft-versions")]
         crate::PROTOCOL_VERSION_DRAFT29 =>
             (&RETRY_INTEGRITY_KEY_DRAFT29, RETRY_INTEGRITY_NONCE_DRAFT29),
 
+        crate::PROTOCOL_VERSION_V2 =>
+            (&RETRY_INTEGRITY_KEY_V2, RETRY_INTEGRITY_NONCE_V2),
+
         _ => (&RETRY_INTEGRITY_KEY_V1, RETRY_INTEGRITY_NONCE_V1),
     };
 
@@ -814,11 +918,26 @@ fn compute_retry_integrity_tag(
         .map_err(|_| Error::CryptoFail)
 }
 
+/// Previous generation 1-RTT keys, kept around after a key update to process
+/// delayed packets.
+pub struct KeyUpdate {
+    /// The packet protection keys of the previous key phase.
+    pub crypto_open: crypto::Open,
+
+    /// The lowest packet number received with the current keys.
+    pub pn_on_update: u64,
+
+    /// The time at which the previous keys are discarded.
+    pub timer: time::Instant,
+}
+
 pub struct PktNumSpace {
     pub largest_rx_pkt_num: u64,
 
     pub largest_rx_pkt_time: time::Instant,
 
+    pub largest_rx_non_probing_pkt_num: u64,
+
     pub next_pkt_num: u64,
 
     pub recv_pkt_need_ack: ranges::RangeSet,
@@ -827,12 +946,25 @@ pub struct PktNumSpace {
 
     pub ack_elicited: bool,
 
+    /// The number of ack-eliciting packets received since the last ACK.
+    pub ack_eliciting_unacked: u64,
+
+    /// When a delayed ACK needs to be sent.
+    pub ack_timer: Option<time::Instant>,
+
+    pub ecn_counts: Option<frame::EcnCounts>,
+
     pub crypto_open: Option<crypto::Open>,
     pub crypto_seal: Option<crypto::Seal>,
 
     pub crypto_0rtt_open: Option<crypto::Open>,
     pub crypto_0rtt_seal: Option<crypto::Seal>,
 
+    pub key_update: Option<KeyUpdate>,
+
+    pub key_phase_start_pn: u64,
+    pub key_phase_acked: bool,
+
     pub crypto_stream: stream::Stream,
 }
 
@@ -843,6 +975,8 @@ impl PktNumSpace {
 
             largest_rx_pkt_time: time::Instant::now(),
 
+            largest_rx_non_probing_pkt_num: 0,
+
             next_pkt_num: 0,
 
             recv_pkt_need_ack: ranges::RangeSet::new(crate::MAX_ACK_RANGES),
@@ -851,12 +985,23 @@ impl PktNumSpace {
 
             ack_elicited: false,
 
+            ack_eliciting_unacked: 0,
+
+            ack_timer: None,
+
+            ecn_counts: None,
+
             crypto_open: None,
             crypto_seal: None,
 
             crypto_0rtt_open: None,
             crypto_0rtt_seal: None,
 
+            key_update: None,
+
+            key_phase_start_pn: 0,
+            key_phase_acked: false,
+
             crypto_stream: stream::Stream::new(
                 std::u64::MAX,
                 std::u64::MAX,
@@ -877,6 +1022,8 @@ impl PktNumSpace {
         );
 
         self.ack_elicited = false;
+        self.ack_eliciting_unacked = 0;
+        self.ack_timer = None;
     }
 
     pub fn crypto_overhead(&self) -> Option<usize> {
@@ -972,6 +1119,100 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn retry_integrity_v1() {
+        let mut pkt = [
+            0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
+            0x2a, 0x42, 0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0x04, 0xa2,
+            0x65, 0xba, 0x2e, 0xff, 0x4d, 0x82, 0x90, 0x58, 0xfb, 0x3f, 0x0f,
+            0x24, 0x96, 0xba,
+        ];
+
+        let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+        let hdr = Header::from_bytes(&mut b, 0).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+        assert_eq!(hdr.token, Some(b"token".to_vec()));
+
+        assert_eq!(
+            verify_retry_integrity(&b, &odcid, crate::PROTOCOL_VERSION_V1),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn retry_integrity_v2() {
+        let mut pkt = [
+            0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
+            0x2a, 0x42, 0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xc8, 0x64,
+            0x6c, 0xe8, 0xbf, 0xe3, 0x39, 0x52, 0xd9, 0x55, 0x54, 0x36, 0x65,
+            0xdc, 0xc7, 0xb6,
+        ];
+
+        let odcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let mut b = octets::OctetsMut::with_slice(&mut pkt);
+
+        let hdr = Header::from_bytes(&mut b, 0).unwrap();
+        assert_eq!(hdr.ty, Type::Retry);
+        assert_eq!(hdr.token, Some(b"token".to_vec()));
+
+        assert_eq!(
+            verify_retry_integrity(&b, &odcid, crate::PROTOCOL_VERSION_V2),
+            Ok(())
+        );
+
+        // Version 1 uses different integrity keys.
+        assert_eq!(
+            verify_retry_integrity(&b, &odcid, crate::PROTOCOL_VERSION_V1),
+            Err(Error::CryptoFail)
+        );
+    }
+
+    #[test]
+    fn stateless_reset() {
+        let token = 0xbaba_baba_baba_baba_baba_baba_baba_baba;
+
+        let mut d = [0; 100];
+
+        // The stateless reset is smaller than the packet it responds to.
+        assert_eq!(super::stateless_reset(token, 30, &mut d), Ok(29));
+        assert_eq!(d[0] & FORM_BIT, 0);
+        assert_eq!(d[0] & FIXED_BIT, FIXED_BIT);
+        assert_eq!(stateless_reset_token(&d[..29]), Some(token));
+
+        let mut b = octets::OctetsMut::with_slice(&mut d[..29]);
+        assert_eq!(Header::from_bytes(&mut b, 8).unwrap().ty, Type::Short);
+
+        assert_eq!(
+            super::stateless_reset(token, 1200, &mut d),
+            Ok(MAX_STATELESS_RESET_LEN)
+        );
+        assert_eq!(
+            stateless_reset_token(&d[..MAX_STATELESS_RESET_LEN]),
+            Some(token)
+        );
+
+        // Packets that are too small don't trigger a stateless reset.
+        assert_eq!(
+            super::stateless_reset(token, MIN_STATELESS_RESET_LEN, &mut d),
+            Err(Error::Done)
+        );
+
+        assert_eq!(
+            super::stateless_reset(token, 1200, &mut d[..30]),
+            Err(Error::BufferTooShort)
+        );
+
+        // Long header packets are not stateless resets.
+        d[0] |= FORM_BIT;
+        assert_eq!(stateless_reset_token(&d[..MAX_STATELESS_RESET_LEN]), None);
+
+        assert_eq!(stateless_reset_token(&d[..20]), None);
+    }
+
     #[test]
     fn initial() {
         let hdr = Header {
@@ -996,6 +1237,43 @@ mod tests {
         assert_eq!(Header::from_bytes(&mut b, 9).unwrap(), hdr);
     }
 
+    #[test]
+    fn long_header_types_v2() {
+        for &(ty, bits) in &[
+            (Type::Retry, 0x00),
+            (Type::Initial, 0x01),
+            (Type::ZeroRTT, 0x02),
+            (Type::Handshake, 0x03),
+        ] {
+            let hdr = Header {
+                ty,
+                version: crate::PROTOCOL_VERSION_V2,
+                dcid: vec![0xba; 9].into(),
+                scid: vec![0xbb; 7].into(),
+                pkt_num: 0,
+                pkt_num_len: 0,
+                token: Some(vec![0x05, 0x06, 0x07, 0x08]),
+                versions: None,
+                key_phase: false,
+            };
+
+            let mut d = [0; 50];
+
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            assert!(hdr.to_bytes(&mut b).is_ok());
+
+            // Add fake retry integrity token.
+            b.put_bytes(&[0xba; 16]).unwrap();
+
+            assert_eq!((d[0] & TYPE_MASK) >> 4, bits);
+
+            let mut b = octets::OctetsMut::with_slice(&mut d);
+            let parsed = Header::from_bytes(&mut b, 9).unwrap();
+            assert_eq!(parsed.ty, ty);
+            assert_eq!(parsed.version, crate::PROTOCOL_VERSION_V2);
+        }
+    }
+
     #[test]
     fn initial_v1_dcid_too_long() {
         let hdr = Header {
@@ -1414,6 +1692,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn decrypt_client_initial_draft29() {
         let mut pkt = [
             0xc5, 0xff, 0x00, 0x00, 0x1d, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e,
@@ -1556,6 +1835,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn decrypt_client_initial_draft28() {
         let mut pkt = [
             0xc0, 0xff, 0x00, 0x00, 0x1c, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e,
@@ -1733,6 +2013,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn decrypt_server_initial_draft29() {
         let mut pkt = [
             0xca, 0xff, 0x00, 0x00, 0x1d, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
@@ -1768,6 +2049,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn decrypt_server_initial_draft28() {
         let mut pkt = [
             0xc9, 0xff, 0x00, 0x00, 0x1c, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
@@ -1819,7 +2101,9 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Open::from_secret(alg, &secret).unwrap();
+        let aead =
+            crypto::Open::from_secret(alg, &secret, crate::PROTOCOL_VERSION_V1)
+                .unwrap();
 
         let mut hdr = Header::from_bytes(&mut b, 0).unwrap();
         assert_eq!(hdr.ty, Type::Short);
@@ -2121,6 +2405,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn encrypt_client_initial_draft29() {
         let mut header = [
             0xc3, 0xff, 0x00, 0x00, 0x1d, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e,
@@ -2363,6 +2648,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn encrypt_client_initial_draft28() {
         let mut header = [
             0xc3, 0xff, 0x00, 0x00, 0x1c, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e,
@@ -2645,6 +2931,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn encrypt_server_initial_draft29() {
         let mut header = [
             0xc1, 0xff, 0x00, 0x00, 0x1d, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
@@ -2685,6 +2972,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "draft-versions")]
     fn encrypt_server_initial_draft28() {
         let mut header = [
             0xc1, 0xff, 0x00, 0x00, 0x1c, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50,
@@ -2751,7 +3039,9 @@ mod tests {
 
         let alg = crypto::Algorithm::ChaCha20_Poly1305;
 
-        let aead = crypto::Seal::from_secret(alg, &secret).unwrap();
+        let aead =
+            crypto::Seal::from_secret(alg, &secret, crate::PROTOCOL_VERSION_V1)
+                .unwrap();
 
         let pn = 654_360_564;
         let pn_len = 3;
@@ -0,0 +1,722 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cmp;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use std::net::SocketAddr;
+
+use crate::Error;
+use crate::Result;
+
+use crate::recovery;
+
+// The maximum number of PATH_CHALLENGE frames received on a path that are
+// kept around waiting for a response to be sent.
+const MAX_RECEIVED_CHALLENGES: usize = 3;
+
+/// The different states of the path validation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PathState {
+    /// The path failed its validation.
+    Failed,
+
+    /// The path exists, but no path validation has been performed.
+    Unknown,
+
+    /// The path is under validation.
+    Validating,
+
+    /// The path has been validated.
+    Validated,
+}
+
+/// A path-related event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathEvent {
+    /// A new network path (local address, peer address) has been seen on a
+    /// received packet. Note that this event is only triggered for servers, as
+    /// the client is responsible for initiating new paths. The application may
+    /// then probe this new path, if desired.
+    New(SocketAddr, SocketAddr),
+
+    /// The related network path between local `SocketAddr` and peer
+    /// `SocketAddr` has been validated.
+    Validated(SocketAddr, SocketAddr),
+
+    /// The related network path between local `SocketAddr` and peer
+    /// `SocketAddr` failed to be validated. This network path will not be used
+    /// anymore, unless the application requests probing this path again.
+    FailedValidation(SocketAddr, SocketAddr),
+
+    /// The related network path between local `SocketAddr` and peer
+    /// `SocketAddr` has been closed and is now unusable on this connection.
+    Closed(SocketAddr, SocketAddr),
+
+    /// The peer initiated a connection migration, and the connection now uses
+    /// the network path between local `SocketAddr` and peer `SocketAddr`.
+    PeerMigrated(SocketAddr, SocketAddr),
+}
+
+/// A network path on which QUIC packets can be sent.
+pub struct Path {
+    /// The local address of the path.
+    local_addr: SocketAddr,
+
+    /// The remote address of the path.
+    peer_addr: SocketAddr,
+
+    /// The sequence number of the source connection ID used on this path.
+    pub active_scid_seq: Option<u64>,
+
+    /// The sequence number of the destination connection ID used on this path.
+    pub active_dcid_seq: Option<u64>,
+
+    /// The current validation state of the path.
+    state: PathState,
+
+    /// Whether this is the path used to send non-probing packets.
+    active: bool,
+
+    /// Loss recovery and congestion control state.
+    pub recovery: recovery::Recovery,
+
+    /// Pending PATH_CHALLENGE data, along with the size of the packet that
+    /// carried them and the time they were sent.
+    in_flight_challenges: VecDeque<([u8; 8], usize, Instant)>,
+
+    /// Received PATH_CHALLENGE data that need to be echoed back.
+    received_challenges: VecDeque<[u8; 8]>,
+
+    /// Whether a PATH_CHALLENGE frame needs to be sent on this path.
+    challenge_requested: bool,
+
+    /// The time at which the path validation is considered failed.
+    validation_timer: Option<Instant>,
+
+    /// The number of bytes that can be sent before the peer address is
+    /// verified.
+    pub max_send_bytes: usize,
+
+    /// Whether the peer address has been verified.
+    pub verified_peer_address: bool,
+
+    /// The number of packets received on this path.
+    pub recv_count: usize,
+
+    /// The number of packets sent on this path.
+    pub sent_count: usize,
+}
+
+impl Path {
+    /// Creates a new path.
+    ///
+    /// The initial path, i.e. the one used during the handshake, is
+    /// considered validated, as the handshake itself validates it.
+    pub fn new(
+        local_addr: SocketAddr, peer_addr: SocketAddr,
+        recovery_config: &recovery::RecoveryConfig, is_initial: bool,
+        now: Instant,
+    ) -> Self {
+        let (state, active_scid_seq, active_dcid_seq) = if is_initial {
+            (PathState::Validated, Some(0), Some(0))
+        } else {
+            (PathState::Unknown, None, None)
+        };
+
+        let mut recovery =
+            recovery::Recovery::new_with_config(recovery_config, now);
+        recovery.on_init(now);
+
+        Path {
+            local_addr,
+            peer_addr,
+            active_scid_seq,
+            active_dcid_seq,
+            state,
+            active: false,
+            recovery,
+            in_flight_challenges: VecDeque::new(),
+            received_challenges: VecDeque::new(),
+            challenge_requested: false,
+            validation_timer: None,
+            max_send_bytes: 0,
+            verified_peer_address: false,
+            recv_count: 0,
+            sent_count: 0,
+        }
+    }
+
+    /// Returns the local address of the path.
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the remote address of the path.
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns whether the path is the active one.
+    #[inline]
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns whether the path has been validated.
+    #[inline]
+    pub fn validated(&self) -> bool {
+        self.state == PathState::Validated
+    }
+
+    /// Returns whether the path failed its validation.
+    #[inline]
+    pub fn validation_failed(&self) -> bool {
+        self.state == PathState::Failed
+    }
+
+    /// Returns whether the path is under validation.
+    #[inline]
+    pub fn under_validation(&self) -> bool {
+        matches!(self.state, PathState::Validating)
+    }
+
+    /// Requests the validation of the path, i.e. that a PATH_CHALLENGE frame
+    /// is sent on it.
+    #[inline]
+    pub fn request_validation(&mut self) {
+        self.challenge_requested = true;
+    }
+
+    /// Returns whether a PATH_CHALLENGE frame needs to be sent on this path.
+    #[inline]
+    pub fn validation_requested(&self) -> bool {
+        self.challenge_requested
+    }
+
+    /// Returns whether probing frames need to be sent on this path.
+    pub fn probing_required(&self) -> bool {
+        !self.received_challenges.is_empty() || self.validation_requested()
+    }
+
+    /// Records that a PATH_CHALLENGE frame with the given data was sent in a
+    /// packet of `pkt_size` bytes, and arms the validation timer.
+    pub fn on_challenge_sent(
+        &mut self, data: [u8; 8], pkt_size: usize, now: Instant,
+        timeout: Duration,
+    ) {
+        self.in_flight_challenges.push_back((data, pkt_size, now));
+
+        self.challenge_requested = false;
+
+        // Only arm the timer when validation starts, so that retransmissions
+        // don't extend the validation period.
+        if self.state != PathState::Validating {
+            self.validation_timer = Some(now + timeout);
+        }
+
+        self.state = cmp::max(self.state, PathState::Validating);
+    }
+
+    /// Queues the data of a received PATH_CHALLENGE frame, so that it can be
+    /// echoed back in a PATH_RESPONSE frame.
+    pub fn on_challenge_received(&mut self, data: [u8; 8]) {
+        // Only keep the most recent challenges around.
+        if self.received_challenges.len() >= MAX_RECEIVED_CHALLENGES {
+            self.received_challenges.pop_front();
+        }
+
+        self.received_challenges.push_back(data);
+    }
+
+    /// Returns the data of the next PATH_CHALLENGE frame to respond to.
+    pub fn pop_received_challenge(&mut self) -> Option<[u8; 8]> {
+        self.received_challenges.pop_front()
+    }
+
+    /// Returns whether the given PATH_RESPONSE data matches a PATH_CHALLENGE
+    /// sent on this path.
+    pub fn has_pending_challenge(&self, data: [u8; 8]) -> bool {
+        self.in_flight_challenges.iter().any(|(d, ..)| *d == data)
+    }
+
+    /// Processes the data of a received PATH_RESPONSE frame.
+    ///
+    /// Returns true if the path was validated as a result.
+    pub fn on_response_received(&mut self, data: [u8; 8]) -> bool {
+        if !self.has_pending_challenge(data) {
+            return false;
+        }
+
+        self.in_flight_challenges.clear();
+        self.challenge_requested = false;
+        self.validation_timer = None;
+
+        self.verified_peer_address = true;
+
+        let was_validated = self.validated();
+
+        self.state = PathState::Validated;
+
+        !was_validated
+    }
+
+    /// Marks the path as having failed its validation.
+    pub fn on_failed_validation(&mut self) {
+        self.state = PathState::Failed;
+        self.in_flight_challenges.clear();
+        self.challenge_requested = false;
+        self.validation_timer = None;
+    }
+
+    /// Returns the time at which the path validation is considered failed.
+    #[inline]
+    pub fn validation_timer(&self) -> Option<Instant> {
+        self.validation_timer
+    }
+
+    /// Checks whether the path validation timer expired, in which case the
+    /// path is marked as failed.
+    ///
+    /// Returns true if the path failed as a result.
+    pub fn on_validation_timeout(&mut self, now: Instant) -> bool {
+        match self.validation_timer {
+            Some(t) if t <= now => {
+                self.on_failed_validation();
+                true
+            },
+
+            _ => false,
+        }
+    }
+}
+
+/// The set of network paths known by a connection.
+pub struct PathMap {
+    /// The paths, indexed by a path identifier.
+    paths: BTreeMap<usize, Path>,
+
+    /// The identifier to assign to the next path.
+    next_path_id: usize,
+
+    /// The maximum number of concurrent paths.
+    max_concurrent_paths: usize,
+
+    /// Path-related events to be reported to the application.
+    events: VecDeque<PathEvent>,
+}
+
+impl PathMap {
+    /// Creates a new path map using `initial_path` as the active path, with
+    /// path identifier 0.
+    pub fn new(mut initial_path: Path, max_concurrent_paths: usize) -> Self {
+        initial_path.active = true;
+
+        let mut paths = BTreeMap::new();
+        paths.insert(0, initial_path);
+
+        PathMap {
+            paths,
+            next_path_id: 1,
+            max_concurrent_paths: cmp::max(2, max_concurrent_paths),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Returns the path with the given identifier.
+    pub fn get(&self, path_id: usize) -> Result<&Path> {
+        self.paths.get(&path_id).ok_or(Error::InvalidState)
+    }
+
+    /// Returns the mutable path with the given identifier.
+    pub fn get_mut(&mut self, path_id: usize) -> Result<&mut Path> {
+        self.paths.get_mut(&path_id).ok_or(Error::InvalidState)
+    }
+
+    /// Returns the identifier of the active path.
+    pub fn get_active_path_id(&self) -> Result<usize> {
+        self.paths
+            .iter()
+            .find(|(_, p)| p.active)
+            .map(|(pid, _)| *pid)
+            .ok_or(Error::InvalidState)
+    }
+
+    /// Returns the active path.
+    ///
+    /// There is always an active path, as it can't be removed from the map.
+    pub fn get_active(&self) -> &Path {
+        self.paths
+            .values()
+            .find(|p| p.active)
+            .expect("no active path")
+    }
+
+    /// Returns the mutable active path.
+    pub fn get_active_mut(&mut self) -> &mut Path {
+        self.paths
+            .values_mut()
+            .find(|p| p.active)
+            .expect("no active path")
+    }
+
+    /// Returns the identifier of the path with the given local and peer
+    /// addresses.
+    pub fn path_id_from_addrs(
+        &self, addrs: &(SocketAddr, SocketAddr),
+    ) -> Option<usize> {
+        self.paths
+            .iter()
+            .find(|(_, p)| (p.local_addr, p.peer_addr) == *addrs)
+            .map(|(pid, _)| *pid)
+    }
+
+    /// Returns an iterator over the paths and their identifiers.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Path)> {
+        self.paths.iter().map(|(pid, p)| (*pid, p))
+    }
+
+    /// Returns a mutable iterator over the paths and their identifiers.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut Path)> {
+        self.paths.iter_mut().map(|(pid, p)| (*pid, p))
+    }
+
+    /// Returns the number of paths.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Inserts a new path, and returns its identifier.
+    ///
+    /// If the maximum number of concurrent paths is reached, the oldest
+    /// non-active path is evicted, preferring paths that failed validation,
+    /// and returned along with its identifier.
+    pub fn insert_path(&mut self, path: Path) -> (usize, Option<(usize, Path)>) {
+        let mut evicted = None;
+
+        if self.len() >= self.max_concurrent_paths {
+            let victim = self
+                .paths
+                .iter()
+                .filter(|(_, p)| !p.active)
+                .min_by_key(|(pid, p)| (!p.validation_failed(), **pid))
+                .map(|(pid, _)| *pid);
+
+            if let Some(pid) = victim {
+                evicted = self.paths.remove(&pid).map(|p| (pid, p));
+            }
+        }
+
+        let pid = self.next_path_id;
+        self.next_path_id += 1;
+
+        self.paths.insert(pid, path);
+
+        (pid, evicted)
+    }
+
+    /// Sets the path with the given identifier as the active path.
+    pub fn set_active_path(&mut self, path_id: usize) -> Result<()> {
+        if !self.paths.contains_key(&path_id) {
+            return Err(Error::InvalidState);
+        }
+
+        for (pid, p) in self.paths.iter_mut() {
+            p.active = *pid == path_id;
+        }
+
+        Ok(())
+    }
+
+    /// Processes the data of a received PATH_RESPONSE frame, on whichever
+    /// path sent the corresponding challenge.
+    ///
+    /// Returns the identifier of the path that got validated as a result, if
+    /// any.
+    pub fn on_response_received(&mut self, data: [u8; 8]) -> Option<usize> {
+        let (pid, p) = self
+            .paths
+            .iter_mut()
+            .find(|(_, p)| p.has_pending_challenge(data))?;
+
+        if !p.on_response_received(data) {
+            return None;
+        }
+
+        let event = PathEvent::Validated(p.local_addr, p.peer_addr);
+        let pid = *pid;
+
+        self.notify_event(event);
+
+        Some(pid)
+    }
+
+    /// Queues a path event to be reported to the application.
+    #[inline]
+    pub fn notify_event(&mut self, ev: PathEvent) {
+        self.events.push_back(ev);
+    }
+
+    /// Returns the next path event to be reported to the application, if any.
+    #[inline]
+    pub fn pop_event(&mut self) -> Option<PathEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns the peer addresses of the paths using the given local address.
+    pub fn peer_addrs_from(&self, local_addr: SocketAddr) -> SocketAddrIter {
+        SocketAddrIter {
+            sockaddrs: self
+                .paths
+                .values()
+                .filter(|p| p.local_addr == local_addr)
+                .map(|p| p.peer_addr)
+                .collect(),
+        }
+    }
+}
+
+/// An iterator over socket addresses.
+#[derive(Default)]
+pub struct SocketAddrIter {
+    sockaddrs: Vec<SocketAddr>,
+}
+
+impl Iterator for SocketAddrIter {
+    type Item = SocketAddr;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.sockaddrs.pop()
+    }
+}
+
+impl ExactSizeIterator for SocketAddrIter {
+    #[inline]
+    fn len(&self) -> usize {
+        self.sockaddrs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_validation() {
+        let config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let recovery_config = recovery::RecoveryConfig::from_config(&config);
+
+        let local_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let initial = Path::new(
+            local_addr,
+            "127.0.0.1:1234".parse().unwrap(),
+            &recovery_config,
+            true,
+            Instant::now(),
+        );
+        assert!(initial.validated());
+
+        let mut path_mgr = PathMap::new(initial, 2);
+
+        let probed = Path::new(
+            local_addr,
+            "127.0.0.1:5678".parse().unwrap(),
+            &recovery_config,
+            false,
+            Instant::now(),
+        );
+        let (pid, evicted) = path_mgr.insert_path(probed);
+        assert_eq!(pid, 1);
+        assert!(evicted.is_none());
+
+        let now = Instant::now();
+
+        let path = path_mgr.get_mut(pid).unwrap();
+        assert!(!path.validated());
+        assert!(!path.under_validation());
+        assert!(!path.probing_required());
+
+        path.request_validation();
+        assert!(path.probing_required());
+
+        path.on_challenge_sent([0xba; 8], 1200, now, Duration::from_secs(1));
+        assert!(path.under_validation());
+        assert!(!path.probing_required());
+        assert_eq!(path.validation_timer(), Some(now + Duration::from_secs(1)));
+
+        // Unknown responses are ignored.
+        assert_eq!(path_mgr.on_response_received([0xbb; 8]), None);
+
+        assert_eq!(path_mgr.on_response_received([0xba; 8]), Some(pid));
+        assert_eq!(
+            path_mgr.pop_event(),
+            Some(PathEvent::Validated(
+                local_addr,
+                "127.0.0.1:5678".parse().unwrap()
+            ))
+        );
+        assert_eq!(path_mgr.pop_event(), None);
+
+        let path = path_mgr.get(pid).unwrap();
+        assert!(path.validated());
+        assert!(path.verified_peer_address);
+        assert_eq!(path.validation_timer(), None);
+
+        assert_eq!(path_mgr.get_active_path_id(), Ok(0));
+        assert_eq!(path_mgr.set_active_path(pid), Ok(()));
+        assert_eq!(path_mgr.get_active_path_id(), Ok(pid));
+    }
+
+    #[test]
+    fn path_validation_timeout() {
+        let config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let recovery_config = recovery::RecoveryConfig::from_config(&config);
+
+        let local_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let mut path = Path::new(
+            local_addr,
+            "127.0.0.1:5678".parse().unwrap(),
+            &recovery_config,
+            false,
+            Instant::now(),
+        );
+
+        let now = Instant::now();
+
+        path.request_validation();
+        path.on_challenge_sent([0xba; 8], 1200, now, Duration::from_secs(1));
+
+        assert!(!path.on_validation_timeout(now));
+        assert!(path.on_validation_timeout(now + Duration::from_secs(1)));
+        assert!(path.validation_failed());
+
+        // A late response doesn't validate the path.
+        assert!(!path.on_response_received([0xba; 8]));
+    }
+
+    #[test]
+    fn path_received_challenges() {
+        let config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let recovery_config = recovery::RecoveryConfig::from_config(&config);
+
+        let local_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let mut path = Path::new(
+            local_addr,
+            "127.0.0.1:5678".parse().unwrap(),
+            &recovery_config,
+            false,
+            Instant::now(),
+        );
+
+        for i in 0..5 {
+            path.on_challenge_received([i; 8]);
+        }
+
+        assert!(path.probing_required());
+
+        // Only the most recent challenges are kept.
+        assert_eq!(path.pop_received_challenge(), Some([2; 8]));
+        assert_eq!(path.pop_received_challenge(), Some([3; 8]));
+        assert_eq!(path.pop_received_challenge(), Some([4; 8]));
+        assert_eq!(path.pop_received_challenge(), None);
+
+        assert!(!path.probing_required());
+    }
+
+    #[test]
+    fn path_eviction() {
+        let config = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        let recovery_config = recovery::RecoveryConfig::from_config(&config);
+
+        let local_addr = "127.0.0.1:4321".parse().unwrap();
+
+        let initial = Path::new(
+            local_addr,
+            "127.0.0.1:1234".parse().unwrap(),
+            &recovery_config,
+            true,
+            Instant::now(),
+        );
+
+        let mut path_mgr = PathMap::new(initial, 3);
+
+        for port in 2000..2002 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+            path_mgr.insert_path(Path::new(
+                local_addr,
+                addr,
+                &recovery_config,
+                false,
+                Instant::now(),
+            ));
+        }
+
+        assert_eq!(path_mgr.len(), 3);
+
+        path_mgr.get_mut(2).unwrap().on_failed_validation();
+
+        // The failed path is evicted first, the active one never is.
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let (pid, evicted) = path_mgr.insert_path(Path::new(
+            local_addr,
+            addr,
+            &recovery_config,
+            false,
+            Instant::now(),
+        ));
+        assert_eq!(pid, 3);
+        assert_eq!(evicted.map(|(pid, _)| pid), Some(2));
+
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let (_, evicted) = path_mgr.insert_path(Path::new(
+            local_addr,
+            addr,
+            &recovery_config,
+            false,
+            Instant::now(),
+        ));
+        assert_eq!(evicted.map(|(pid, _)| pid), Some(1));
+
+        assert_eq!(path_mgr.get_active_path_id(), Ok(0));
+        assert_eq!(path_mgr.path_id_from_addrs(&(local_addr, addr)), Some(4));
+
+        let mut peers = path_mgr.peer_addrs_from(local_addr);
+        assert_eq!(peers.len(), 3);
+        assert!(peers.any(|a| a == addr));
+        assert_eq!(path_mgr.peer_addrs_from(addr).len(), 0);
+    }
+}
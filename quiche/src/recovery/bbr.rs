@@ -0,0 +1,949 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! BBR Congestion Control
+//!
+//! This implementation is based on the following draft:
+//! <https://tools.ietf.org/html/draft-cardwell-iccrg-bbr-congestion-control-00>
+
+use std::cmp;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::minmax;
+use crate::packet;
+use crate::recovery;
+
+use crate::recovery::Acked;
+use crate::recovery::CongestionControlOps;
+use crate::recovery::Recovery;
+
+pub static BBR: CongestionControlOps = CongestionControlOps {
+    on_init,
+    on_packet_sent,
+    on_packets_acked,
+    congestion_event,
+    collapse_cwnd,
+    checkpoint,
+    rollback,
+    has_custom_pacing,
+    debug_fmt,
+};
+
+/// The length of the BtlBw max filter window, in round trips.
+const BTLBW_FILTER_LEN: u32 = 10;
+
+/// The length of the RTprop min filter window.
+const RTPROP_FILTER_LEN: Duration = Duration::from_secs(10);
+
+/// The gain allowing the sending rate to double each round in Startup
+/// (2/ln(2)).
+const BBR_HIGH_GAIN: f64 = 2.89;
+
+/// The minimal cwnd value BBR tries to target, in packets.
+const BBR_MIN_PIPE_CWND_PKTS: usize = 4;
+
+/// The number of phases in the ProbeBW gain cycle.
+const BBR_GAIN_CYCLE_LEN: usize = 8;
+
+/// The pacing gains used by each phase of the ProbeBW gain cycle.
+const BBR_PACING_GAIN_CYCLE: [f64; BBR_GAIN_CYCLE_LEN] =
+    [5.0 / 4.0, 3.0 / 4.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// The cwnd gain used in ProbeBW.
+const BBR_CWND_GAIN: f64 = 2.0;
+
+/// The minimum time spent at BBR_MIN_PIPE_CWND_PKTS in ProbeRTT.
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+
+/// The BtlBw growth needed in Startup for the pipe not to be considered full.
+const BTLBW_GROWTH_TARGET: f64 = 1.25;
+
+/// The number of rounds without enough BtlBw growth after which the pipe is
+/// considered full.
+const BTLBW_GROWTH_ROUNDS: usize = 3;
+
+/// Pacing rates (in bytes per second) below which smaller send quanta are
+/// used.
+const SEND_QUANTUM_THRESHOLD_LOW: u64 = 1_200_000 / 8;
+
+const SEND_QUANTUM_THRESHOLD_HIGH: u64 = 24_000_000 / 8;
+
+const MAX_SEND_QUANTUM: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BBRStateMachine {
+    Startup,
+    Drain,
+    ProbeBW,
+    ProbeRTT,
+}
+
+/// BBR per-connection state.
+pub struct State {
+    state: BBRStateMachine,
+
+    // Bottleneck bandwidth estimate, in bytes per second.
+    btlbw: u64,
+
+    btlbw_filter: minmax::Minmax<u64>,
+
+    // Round-trip propagation time estimate, `None` until the first sample.
+    rtprop: Option<Duration>,
+
+    rtprop_stamp: Option<Instant>,
+
+    rtprop_expired: bool,
+
+    pacing_gain: f64,
+
+    cwnd_gain: f64,
+
+    target_cwnd: usize,
+
+    filled_pipe: bool,
+
+    full_bw: u64,
+
+    full_bw_count: usize,
+
+    round_count: u64,
+
+    round_start: bool,
+
+    next_round_delivered: usize,
+
+    cycle_stamp: Option<Instant>,
+
+    cycle_index: usize,
+
+    probe_rtt_done_stamp: Option<Instant>,
+
+    probe_rtt_round_done: bool,
+
+    in_recovery: bool,
+
+    packet_conservation: bool,
+
+    prior_cwnd: usize,
+
+    idle_restart: bool,
+
+    prior_bytes_in_flight: usize,
+
+    newly_acked_bytes: usize,
+
+    newly_lost_bytes: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            state: BBRStateMachine::Startup,
+
+            btlbw: 0,
+
+            btlbw_filter: minmax::Minmax::new(0),
+
+            rtprop: None,
+
+            rtprop_stamp: None,
+
+            rtprop_expired: false,
+
+            pacing_gain: BBR_HIGH_GAIN,
+
+            cwnd_gain: BBR_HIGH_GAIN,
+
+            target_cwnd: 0,
+
+            filled_pipe: false,
+
+            full_bw: 0,
+
+            full_bw_count: 0,
+
+            round_count: 0,
+
+            round_start: false,
+
+            next_round_delivered: 0,
+
+            cycle_stamp: None,
+
+            cycle_index: 0,
+
+            probe_rtt_done_stamp: None,
+
+            probe_rtt_round_done: false,
+
+            in_recovery: false,
+
+            packet_conservation: false,
+
+            prior_cwnd: 0,
+
+            idle_restart: false,
+
+            prior_bytes_in_flight: 0,
+
+            newly_acked_bytes: 0,
+
+            newly_lost_bytes: 0,
+        }
+    }
+}
+
+pub fn on_init(r: &mut Recovery, now: Instant) {
+    r.bbr_state.rtprop = r.smoothed_rtt;
+    r.bbr_state.rtprop_stamp = Some(now);
+    r.bbr_state.next_round_delivered = r.delivery_rate.delivered();
+
+    init_pacing_rate(r);
+    enter_startup(r);
+}
+
+fn on_packet_sent(r: &mut Recovery, sent_bytes: usize, _now: Instant) {
+    // Restarting from idle, pace at the estimated bandwidth to avoid sending
+    // a burst.
+    if r.bytes_in_flight == 0 && r.delivery_rate.app_limited() {
+        r.bbr_state.idle_restart = true;
+
+        if r.bbr_state.state == BBRStateMachine::ProbeBW {
+            set_pacing_rate_with_gain(r, 1.0);
+        }
+    }
+
+    r.bytes_in_flight += sent_bytes;
+}
+
+fn on_packets_acked(
+    r: &mut Recovery, packets: &[Acked], _epoch: packet::Epoch, now: Instant,
+) {
+    let newly_acked_bytes = packets.iter().map(|p| p.size).sum();
+
+    r.bbr_state.prior_bytes_in_flight = r.bytes_in_flight;
+    r.bbr_state.newly_acked_bytes = newly_acked_bytes;
+
+    r.bytes_in_flight = r.bytes_in_flight.saturating_sub(newly_acked_bytes);
+
+    // Exit recovery once a packet sent after its start is acked.
+    if let Some(pkt) = packets.last() {
+        if r.bbr_state.in_recovery && !r.in_congestion_recovery(pkt.time_sent) {
+            exit_recovery(r);
+        }
+    }
+
+    update_model_and_state(r, now);
+    update_control_parameters(r);
+
+    r.bbr_state.newly_lost_bytes = 0;
+}
+
+fn congestion_event(
+    r: &mut Recovery, lost_bytes: usize, time_sent: Instant,
+    _epoch: packet::Epoch, now: Instant,
+) {
+    // Losses are accounted for in the cwnd on the next ACK.
+    r.bbr_state.newly_lost_bytes += lost_bytes;
+
+    // Start a new congestion event if packet was sent after the
+    // start of the previous congestion recovery period.
+    if !r.in_congestion_recovery(time_sent) {
+        r.congestion_recovery_start_time = Some(now);
+
+        enter_recovery(r);
+    }
+}
+
+fn collapse_cwnd(r: &mut Recovery, _now: Instant) {
+    r.bbr_state.prior_cwnd = save_cwnd(r);
+
+    r.congestion_window = r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS;
+}
+
+fn checkpoint(_r: &mut Recovery) {}
+
+fn rollback(_r: &mut Recovery) -> bool {
+    false
+}
+
+fn has_custom_pacing() -> bool {
+    true
+}
+
+fn debug_fmt(r: &Recovery, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let bbr = &r.bbr_state;
+
+    write!(
+        f,
+        "bbr={{ state={:?} btlbw={} rtprop={:?} pacing_gain={} cwnd_gain={} \
+         target_cwnd={} send_quantum={} filled_pipe={} round_count={} \
+         in_recovery={} }}",
+        bbr.state,
+        bbr.btlbw,
+        bbr.rtprop,
+        bbr.pacing_gain,
+        bbr.cwnd_gain,
+        bbr.target_cwnd,
+        r.send_quantum,
+        bbr.filled_pipe,
+        bbr.round_count,
+        bbr.in_recovery,
+    )
+}
+
+fn init_pacing_rate(r: &mut Recovery) {
+    let srtt = r.smoothed_rtt.unwrap_or_else(|| Duration::from_millis(1));
+
+    let nominal_bandwidth = r.congestion_window as f64 / srtt.as_secs_f64();
+
+    r.pacing_rate = (BBR_HIGH_GAIN * nominal_bandwidth) as u64;
+}
+
+fn min_pipe_cwnd(r: &Recovery) -> usize {
+    r.max_datagram_size * BBR_MIN_PIPE_CWND_PKTS
+}
+
+// The amount of data in flight needed to fully use the estimated BDP, scaled
+// by `gain`.
+fn inflight(r: &Recovery, gain: f64) -> usize {
+    let rtprop = match r.bbr_state.rtprop {
+        Some(v) => v,
+
+        // No valid RTT samples yet.
+        None => return r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS,
+    };
+
+    // Allow enough data in flight to account for send and receive offloads.
+    let quanta = 3 * r.send_quantum;
+
+    let estimated_bdp = r.bbr_state.btlbw as f64 * rtprop.as_secs_f64();
+
+    (gain * estimated_bdp) as usize + quanta
+}
+
+fn enter_startup(r: &mut Recovery) {
+    r.bbr_state.state = BBRStateMachine::Startup;
+    r.bbr_state.pacing_gain = BBR_HIGH_GAIN;
+    r.bbr_state.cwnd_gain = BBR_HIGH_GAIN;
+}
+
+fn enter_drain(r: &mut Recovery) {
+    r.bbr_state.state = BBRStateMachine::Drain;
+    r.bbr_state.pacing_gain = 1.0 / BBR_HIGH_GAIN;
+    r.bbr_state.cwnd_gain = BBR_HIGH_GAIN;
+}
+
+fn enter_probe_bw(r: &mut Recovery, now: Instant) {
+    r.bbr_state.state = BBRStateMachine::ProbeBW;
+    r.bbr_state.pacing_gain = 1.0;
+    r.bbr_state.cwnd_gain = BBR_CWND_GAIN;
+
+    // Start at a random phase, other than the one draining the queue.
+    r.bbr_state.cycle_index = BBR_GAIN_CYCLE_LEN -
+        1 -
+        crate::rand::rand_u64_uniform(BBR_GAIN_CYCLE_LEN as u64 - 1) as usize;
+
+    advance_cycle_phase(r, now);
+}
+
+fn enter_probe_rtt(r: &mut Recovery) {
+    r.bbr_state.state = BBRStateMachine::ProbeRTT;
+    r.bbr_state.pacing_gain = 1.0;
+    r.bbr_state.cwnd_gain = 1.0;
+}
+
+fn exit_probe_rtt(r: &mut Recovery, now: Instant) {
+    if r.bbr_state.filled_pipe {
+        enter_probe_bw(r, now);
+    } else {
+        enter_startup(r);
+    }
+}
+
+fn enter_recovery(r: &mut Recovery) {
+    r.bbr_state.prior_cwnd = save_cwnd(r);
+
+    r.congestion_window = r.bytes_in_flight + r.max_datagram_size;
+
+    r.bbr_state.in_recovery = true;
+
+    // Packets sent before the start of recovery take one round trip to be
+    // acked, so use packet conservation until recovery ends.
+    r.bbr_state.packet_conservation = true;
+}
+
+fn exit_recovery(r: &mut Recovery) {
+    r.bbr_state.in_recovery = false;
+    r.bbr_state.packet_conservation = false;
+
+    restore_cwnd(r);
+}
+
+fn save_cwnd(r: &Recovery) -> usize {
+    if !r.bbr_state.in_recovery && r.bbr_state.state != BBRStateMachine::ProbeRTT
+    {
+        r.congestion_window
+    } else {
+        cmp::max(r.bbr_state.prior_cwnd, r.congestion_window)
+    }
+}
+
+fn restore_cwnd(r: &mut Recovery) {
+    r.congestion_window = cmp::max(r.congestion_window, r.bbr_state.prior_cwnd);
+}
+
+fn update_model_and_state(r: &mut Recovery, now: Instant) {
+    update_btlbw(r, now);
+    check_cycle_phase(r, now);
+    check_full_pipe(r);
+    check_drain(r, now);
+    update_rtprop(r, now);
+    check_probe_rtt(r, now);
+}
+
+fn update_control_parameters(r: &mut Recovery) {
+    set_pacing_rate_with_gain(r, r.bbr_state.pacing_gain);
+    set_send_quantum(r);
+    set_cwnd(r);
+}
+
+fn update_round(r: &mut Recovery) {
+    let bbr = &mut r.bbr_state;
+
+    if r.delivery_rate.sample_prior_delivered() >= bbr.next_round_delivered {
+        bbr.next_round_delivered = r.delivery_rate.delivered();
+        bbr.round_count += 1;
+        bbr.round_start = true;
+    } else {
+        bbr.round_start = false;
+    }
+}
+
+fn update_btlbw(r: &mut Recovery, now: Instant) {
+    update_round(r);
+
+    let delivery_rate = r.delivery_rate.sample_delivery_rate();
+
+    if delivery_rate >= r.bbr_state.btlbw ||
+        !r.delivery_rate.sample_is_app_limited()
+    {
+        // The filter is windowed by time, so use the smoothed RTT to
+        // approximate its length in round trips.
+        let win = r.rtt() * BTLBW_FILTER_LEN;
+
+        r.bbr_state.btlbw =
+            r.bbr_state
+                .btlbw_filter
+                .running_max(win, now, delivery_rate);
+    }
+}
+
+fn check_cycle_phase(r: &mut Recovery, now: Instant) {
+    if r.bbr_state.state == BBRStateMachine::ProbeBW &&
+        is_next_cycle_phase(r, now)
+    {
+        advance_cycle_phase(r, now);
+    }
+}
+
+fn is_next_cycle_phase(r: &Recovery, now: Instant) -> bool {
+    let bbr = &r.bbr_state;
+
+    let is_full_length = match (bbr.rtprop, bbr.cycle_stamp) {
+        (Some(rtprop), Some(cycle_stamp)) =>
+            now.saturating_duration_since(cycle_stamp) > rtprop,
+
+        _ => true,
+    };
+
+    // Probing for more bandwidth, keep going until losses or the inflight
+    // target is reached.
+    if bbr.pacing_gain > 1.0 {
+        return is_full_length &&
+            (bbr.newly_lost_bytes > 0 ||
+                bbr.prior_bytes_in_flight >= inflight(r, bbr.pacing_gain));
+    }
+
+    // Draining the queue created while probing, stop as soon as it's empty.
+    if bbr.pacing_gain < 1.0 {
+        return is_full_length || bbr.prior_bytes_in_flight <= inflight(r, 1.0);
+    }
+
+    is_full_length
+}
+
+fn advance_cycle_phase(r: &mut Recovery, now: Instant) {
+    let bbr = &mut r.bbr_state;
+
+    bbr.cycle_stamp = Some(now);
+    bbr.cycle_index = (bbr.cycle_index + 1) % BBR_GAIN_CYCLE_LEN;
+    bbr.pacing_gain = BBR_PACING_GAIN_CYCLE[bbr.cycle_index];
+}
+
+fn check_full_pipe(r: &mut Recovery) {
+    let bbr = &mut r.bbr_state;
+
+    if bbr.filled_pipe ||
+        !bbr.round_start ||
+        r.delivery_rate.sample_is_app_limited()
+    {
+        return;
+    }
+
+    // Still growing?
+    if bbr.btlbw as f64 >= bbr.full_bw as f64 * BTLBW_GROWTH_TARGET {
+        bbr.full_bw = bbr.btlbw;
+        bbr.full_bw_count = 0;
+        return;
+    }
+
+    bbr.full_bw_count += 1;
+
+    if bbr.full_bw_count >= BTLBW_GROWTH_ROUNDS {
+        bbr.filled_pipe = true;
+    }
+}
+
+fn check_drain(r: &mut Recovery, now: Instant) {
+    if r.bbr_state.state == BBRStateMachine::Startup && r.bbr_state.filled_pipe {
+        enter_drain(r);
+    }
+
+    if r.bbr_state.state == BBRStateMachine::Drain &&
+        r.bytes_in_flight <= inflight(r, 1.0)
+    {
+        enter_probe_bw(r, now);
+    }
+}
+
+fn update_rtprop(r: &mut Recovery, now: Instant) {
+    let bbr = &mut r.bbr_state;
+
+    let sample_rtt = r.delivery_rate.sample_rtt();
+
+    bbr.rtprop_expired = match bbr.rtprop_stamp {
+        Some(rtprop_stamp) => now > rtprop_stamp + RTPROP_FILTER_LEN,
+
+        None => false,
+    };
+
+    match bbr.rtprop {
+        Some(rtprop) if sample_rtt > rtprop && !bbr.rtprop_expired => (),
+
+        _ => {
+            bbr.rtprop = Some(sample_rtt);
+            bbr.rtprop_stamp = Some(now);
+        },
+    }
+}
+
+fn check_probe_rtt(r: &mut Recovery, now: Instant) {
+    if r.bbr_state.state != BBRStateMachine::ProbeRTT &&
+        r.bbr_state.rtprop_expired &&
+        !r.bbr_state.idle_restart
+    {
+        enter_probe_rtt(r);
+
+        r.bbr_state.prior_cwnd = save_cwnd(r);
+        r.bbr_state.probe_rtt_done_stamp = None;
+    }
+
+    if r.bbr_state.state == BBRStateMachine::ProbeRTT {
+        handle_probe_rtt(r, now);
+    }
+
+    r.bbr_state.idle_restart = false;
+}
+
+fn handle_probe_rtt(r: &mut Recovery, now: Instant) {
+    // Ignore low rate samples during ProbeRTT.
+    r.delivery_rate.update_app_limited(true);
+
+    match r.bbr_state.probe_rtt_done_stamp {
+        Some(done_stamp) => {
+            if r.bbr_state.round_start {
+                r.bbr_state.probe_rtt_round_done = true;
+            }
+
+            if r.bbr_state.probe_rtt_round_done && now > done_stamp {
+                r.bbr_state.rtprop_stamp = Some(now);
+
+                restore_cwnd(r);
+                exit_probe_rtt(r, now);
+            }
+        },
+
+        None =>
+            if r.bytes_in_flight <= min_pipe_cwnd(r) {
+                r.bbr_state.probe_rtt_done_stamp = Some(now + PROBE_RTT_DURATION);
+                r.bbr_state.probe_rtt_round_done = false;
+                r.bbr_state.next_round_delivered = r.delivery_rate.delivered();
+            },
+    }
+}
+
+fn set_pacing_rate_with_gain(r: &mut Recovery, pacing_gain: f64) {
+    let rate = (pacing_gain * r.bbr_state.btlbw as f64) as u64;
+
+    if r.bbr_state.filled_pipe || rate > r.pacing_rate {
+        r.pacing_rate = rate;
+    }
+}
+
+fn set_send_quantum(r: &mut Recovery) {
+    r.send_quantum = match r.pacing_rate {
+        rate if rate < SEND_QUANTUM_THRESHOLD_LOW => r.max_datagram_size,
+
+        rate if rate < SEND_QUANTUM_THRESHOLD_HIGH => r.max_datagram_size * 2,
+
+        // Send up to 1ms worth of data at once.
+        rate => cmp::min((rate / 1000) as usize, MAX_SEND_QUANTUM),
+    };
+}
+
+fn set_cwnd(r: &mut Recovery) {
+    r.bbr_state.target_cwnd = inflight(r, r.bbr_state.cwnd_gain);
+
+    modulate_cwnd_for_recovery(r);
+
+    if !r.bbr_state.packet_conservation {
+        let newly_acked_bytes = r.bbr_state.newly_acked_bytes;
+
+        if r.bbr_state.filled_pipe {
+            r.congestion_window = cmp::min(
+                r.congestion_window + newly_acked_bytes,
+                r.bbr_state.target_cwnd,
+            );
+        } else if r.congestion_window < r.bbr_state.target_cwnd ||
+            r.delivery_rate.delivered() <
+                r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS
+        {
+            r.congestion_window += newly_acked_bytes;
+        }
+
+        r.congestion_window = cmp::max(r.congestion_window, min_pipe_cwnd(r));
+    }
+
+    modulate_cwnd_for_probe_rtt(r);
+}
+
+fn modulate_cwnd_for_recovery(r: &mut Recovery) {
+    if r.bbr_state.newly_lost_bytes > 0 {
+        r.congestion_window = cmp::max(
+            r.congestion_window
+                .saturating_sub(r.bbr_state.newly_lost_bytes),
+            r.max_datagram_size,
+        );
+    }
+
+    if r.bbr_state.packet_conservation {
+        r.congestion_window = cmp::max(
+            r.congestion_window,
+            r.bytes_in_flight + r.bbr_state.newly_acked_bytes,
+        );
+    }
+}
+
+fn modulate_cwnd_for_probe_rtt(r: &mut Recovery) {
+    if r.bbr_state.state == BBRStateMachine::ProbeRTT {
+        r.congestion_window = cmp::min(r.congestion_window, min_pipe_cwnd(r));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ranges;
+    use crate::recovery::HandshakeStatus;
+    use crate::recovery::Sent;
+
+    fn new_recovery() -> Recovery {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::BBR);
+
+        let mut r = Recovery::new(&cfg);
+        r.on_init(Instant::now());
+
+        r
+    }
+
+    fn send_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        for pn in pkt_nums {
+            let pkt = Sent {
+                pkt_num: pn,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: r.max_datagram_size,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                pkt,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+    }
+
+    fn ack_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(pkt_nums);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            ),
+            Ok(())
+        );
+    }
+
+    // Sends and acks `pkts_per_round` packets per round trip, until the
+    // connection leaves Startup.
+    fn fill_pipe(
+        r: &mut Recovery, pkts_per_round: u64, rtt: Duration, mut now: Instant,
+    ) -> (u64, Instant) {
+        let mut pn = 0;
+
+        while r.bbr_state.state == BBRStateMachine::Startup {
+            send_packets(r, pn..pn + pkts_per_round, now);
+
+            now += rtt;
+
+            ack_packets(r, pn..pn + pkts_per_round, now);
+
+            pn += pkts_per_round;
+
+            assert!(r.bbr_state.round_count < 10);
+        }
+
+        (pn, now)
+    }
+
+    #[test]
+    fn bbr_init() {
+        let r = new_recovery();
+
+        assert_eq!(
+            r.cwnd(),
+            r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS
+        );
+        assert_eq!(r.bytes_in_flight, 0);
+
+        assert_eq!(r.bbr_state.state, BBRStateMachine::Startup);
+        assert_eq!(r.bbr_state.pacing_gain, BBR_HIGH_GAIN);
+
+        // Pacing starts from the initial window over 1ms.
+        assert_eq!(
+            r.pacing_rate,
+            (BBR_HIGH_GAIN * r.cwnd() as f64 / 0.001) as u64
+        );
+    }
+
+    #[test]
+    fn bbr_init_time() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::BBR);
+
+        let mut r = Recovery::new(&cfg);
+
+        // The connection runs on a clock well ahead of the wall clock.
+        let mut now = Instant::now() + Duration::from_secs(60);
+
+        r.on_init(now);
+        assert_eq!(r.bbr_state.rtprop_stamp, Some(now));
+
+        send_packets(&mut r, 0..1, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..1, now);
+
+        // The min RTT was stamped with the caller's time, so it didn't expire.
+        assert!(!r.bbr_state.rtprop_expired);
+        assert_ne!(r.bbr_state.state, BBRStateMachine::ProbeRTT);
+    }
+
+    #[test]
+    fn bbr_send() {
+        let mut r = new_recovery();
+        let now = Instant::now();
+
+        send_packets(&mut r, 0..1, now);
+
+        assert_eq!(r.bytes_in_flight, r.max_datagram_size);
+    }
+
+    #[test]
+    fn bbr_startup() {
+        let mut r = new_recovery();
+        let mut now = Instant::now();
+        let mss = r.max_datagram_size;
+
+        send_packets(&mut r, 0..5, now);
+
+        let cwnd_prev = r.cwnd();
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..5, now);
+
+        assert_eq!(r.bbr_state.state, BBRStateMachine::Startup);
+        assert_eq!(r.bytes_in_flight, 0);
+
+        // The window grows by the amount of acked data.
+        assert_eq!(r.cwnd(), cwnd_prev + mss * 5);
+
+        assert_eq!(r.bbr_state.rtprop, Some(Duration::from_millis(50)));
+        assert_eq!(r.bbr_state.round_count, 1);
+        assert_eq!(r.bbr_state.btlbw, r.delivery_rate());
+        assert!(r.bbr_state.btlbw > 0);
+    }
+
+    #[test]
+    fn bbr_congestion_event() {
+        let mut r = new_recovery();
+        let mut now = Instant::now();
+        let mss = r.max_datagram_size;
+
+        send_packets(&mut r, 0..5, now);
+
+        now += Duration::from_millis(50);
+
+        // Acking the last packet causes the first two to be lost, due to the
+        // packet reordering threshold.
+        ack_packets(&mut r, 4..5, now);
+
+        assert_eq!(r.lost_count, 2);
+
+        assert!(r.bbr_state.in_recovery);
+        assert!(r.bbr_state.packet_conservation);
+
+        // The window only allows one packet to be sent for each one acked.
+        assert_eq!(r.bytes_in_flight, mss * 2);
+        assert_eq!(r.cwnd(), r.bytes_in_flight + mss);
+
+        // Acking a packet sent after recovery started ends it.
+        now += Duration::from_millis(1);
+
+        send_packets(&mut r, 5..6, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 2..6, now);
+
+        assert_eq!(r.lost_count, 2);
+
+        assert!(!r.bbr_state.in_recovery);
+        assert!(!r.bbr_state.packet_conservation);
+
+        // The window is restored, and then keeps growing as in Startup.
+        assert_eq!(r.cwnd(), r.bbr_state.prior_cwnd + mss * 3);
+    }
+
+    #[test]
+    fn bbr_drain_and_probe_bw() {
+        let mut r = new_recovery();
+        let rtt = Duration::from_millis(50);
+
+        let (..) = fill_pipe(&mut r, 10, rtt, Instant::now());
+
+        assert!(r.bbr_state.filled_pipe);
+
+        // All data was acked, so Drain ends immediately.
+        assert_eq!(r.bbr_state.state, BBRStateMachine::ProbeBW);
+        assert_eq!(r.bbr_state.cwnd_gain, BBR_CWND_GAIN);
+        assert_ne!(r.bbr_state.pacing_gain, 3.0 / 4.0);
+
+        // The bandwidth estimate converged to 10 packets per round trip.
+        let btlbw = (10 * r.max_datagram_size) as f64 / rtt.as_secs_f64();
+        assert_eq!(r.bbr_state.btlbw, btlbw as u64);
+
+        assert_eq!(
+            r.pacing_rate,
+            (r.bbr_state.pacing_gain * btlbw as u64 as f64) as u64
+        );
+    }
+
+    #[test]
+    fn bbr_probe_rtt() {
+        let mut r = new_recovery();
+        let rtt = Duration::from_millis(50);
+
+        let (pn, now) = fill_pipe(&mut r, 10, rtt, Instant::now());
+
+        assert_eq!(r.bbr_state.state, BBRStateMachine::ProbeBW);
+
+        // Let the RTprop estimate expire, with a slightly higher RTT sample.
+        let sent_time = now + RTPROP_FILTER_LEN;
+        let now = sent_time + rtt * 2;
+
+        send_packets(&mut r, pn..pn + 1, sent_time);
+        ack_packets(&mut r, pn..pn + 1, now);
+
+        assert_eq!(r.bbr_state.state, BBRStateMachine::ProbeRTT);
+        assert_eq!(r.cwnd(), min_pipe_cwnd(&r));
+        assert_eq!(r.bbr_state.rtprop, Some(rtt * 2));
+
+        // Nothing is in flight, so ProbeRTT is scheduled to end.
+        assert_eq!(
+            r.bbr_state.probe_rtt_done_stamp,
+            Some(now + PROBE_RTT_DURATION)
+        );
+
+        // After a round trip and PROBE_RTT_DURATION, ProbeBW resumes.
+        let now = now + PROBE_RTT_DURATION;
+
+        send_packets(&mut r, pn + 1..pn + 2, now);
+        ack_packets(&mut r, pn + 1..pn + 2, now + rtt);
+
+        assert_eq!(r.bbr_state.state, BBRStateMachine::ProbeBW);
+        assert!(r.cwnd() > min_pipe_cwnd(&r));
+    }
+}
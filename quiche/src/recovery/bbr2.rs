@@ -0,0 +1,1640 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! BBRv2 Congestion Control
+//!
+//! This implementation is based on the following draft:
+//! <https://tools.ietf.org/html/draft-cardwell-iccrg-bbr-congestion-control-02>
+
+use std::cmp;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::minmax;
+use crate::packet;
+use crate::recovery;
+
+use crate::recovery::Acked;
+use crate::recovery::CongestionControlOps;
+use crate::recovery::Recovery;
+
+pub static BBR2: CongestionControlOps = CongestionControlOps {
+    on_init,
+    on_packet_sent,
+    on_packets_acked,
+    congestion_event,
+    collapse_cwnd,
+    checkpoint,
+    rollback,
+    has_custom_pacing,
+    debug_fmt,
+};
+
+/// The pacing gain used in Startup (4 * ln(2)).
+const BBR_STARTUP_PACING_GAIN: f64 = 2.77;
+
+/// The cwnd gain used in Startup.
+const BBR_STARTUP_CWND_GAIN: f64 = 2.0;
+
+/// The pacing gain used in Drain, to drain the queue created in Startup.
+const BBR_DRAIN_PACING_GAIN: f64 = 1.0 / 2.885;
+
+/// The cwnd gain used in ProbeBW.
+const BBR_CWND_GAIN: f64 = 2.0;
+
+/// The pacing gains used in the ProbeBW_DOWN and ProbeBW_UP phases.
+const BBR_PROBE_BW_DOWN_PACING_GAIN: f64 = 3.0 / 4.0;
+
+const BBR_PROBE_BW_UP_PACING_GAIN: f64 = 5.0 / 4.0;
+
+/// The percentage by which the pacing rate is kept below the estimated
+/// bandwidth, to avoid building queues.
+const BBR_PACING_MARGIN_PERCENT: f64 = 0.01;
+
+/// The highest tolerated loss rate in a round trip, when probing for
+/// bandwidth.
+const BBR_LOSS_THRESH: f64 = 0.02;
+
+/// The multiplicative decrease applied to the lower bounds on loss.
+const BBR_BETA: f64 = 0.7;
+
+/// The fraction of `inflight_hi` left unused, to leave space for other flows.
+const BBR_HEADROOM: f64 = 0.15;
+
+/// The minimal cwnd value BBR tries to target, in packets.
+const BBR_MIN_PIPE_CWND_PKTS: usize = 4;
+
+/// The number of loss events in a round trip after which the pipe is
+/// considered full in Startup.
+const BBR_STARTUP_FULL_LOSS_CNT: usize = 6;
+
+/// The BW growth needed in Startup for the pipe not to be considered full.
+const BBR_FULL_BW_GROWTH_TARGET: f64 = 1.25;
+
+/// The number of rounds without enough BW growth after which the pipe is
+/// considered full.
+const BBR_FULL_BW_GROWTH_ROUNDS: usize = 3;
+
+/// The length of the ExtraACKed max filter window, in round trips.
+const BBR_EXTRA_ACKED_FILTER_LEN: u32 = 10;
+
+/// The length of the MinRTT min filter window.
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+
+/// The cwnd gain used in ProbeRTT.
+const PROBE_RTT_CWND_GAIN: f64 = 0.5;
+
+/// The minimum time spent in ProbeRTT.
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+
+/// The maximum time between ProbeRTT phases.
+const PROBE_RTT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The maximum number of rounds between bandwidth probes, when coexisting
+/// with Reno flows.
+const BBR_MAX_RENO_ROUNDS: usize = 63;
+
+/// The maximum number of rounds over which `inflight_hi` growth is doubled.
+const BBR_MAX_PROBE_UP_ROUNDS: u32 = 30;
+
+/// Pacing rates (in bytes per second) below which smaller send quanta are
+/// used.
+const SEND_QUANTUM_THRESHOLD_LOW: u64 = 1_200_000 / 8;
+
+const SEND_QUANTUM_THRESHOLD_HIGH: u64 = 24_000_000 / 8;
+
+const MAX_SEND_QUANTUM: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BBR2StateMachine {
+    Startup,
+    Drain,
+    ProbeBWDown,
+    ProbeBWCruise,
+    ProbeBWRefill,
+    ProbeBWUp,
+    ProbeRTT,
+}
+
+/// Where the ACKs being received stand with respect to bandwidth probing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BBR2AckPhase {
+    Init,
+    ProbeStarting,
+    ProbeFeedback,
+    ProbeStopping,
+    Refilling,
+}
+
+/// BBRv2 per-connection state.
+pub struct State {
+    state: BBR2StateMachine,
+
+    pacing_gain: f64,
+
+    cwnd_gain: f64,
+
+    // Bandwidth estimate used by the model, in bytes per second.
+    bw: u64,
+
+    // Maximum bandwidth over the last two ProbeBW cycles.
+    max_bw: u64,
+
+    max_bw_filter: [u64; 2],
+
+    cycle_count: u64,
+
+    // Short-term lower bounds, `u64::MAX` and `usize::MAX` when unset.
+    bw_lo: u64,
+
+    inflight_lo: usize,
+
+    // Long-term upper bound, `usize::MAX` when unset.
+    inflight_hi: usize,
+
+    bw_latest: u64,
+
+    inflight_latest: usize,
+
+    min_rtt: Option<Duration>,
+
+    min_rtt_stamp: Option<Instant>,
+
+    probe_rtt_min_delay: Option<Duration>,
+
+    probe_rtt_min_stamp: Option<Instant>,
+
+    probe_rtt_expired: bool,
+
+    probe_rtt_done_stamp: Option<Instant>,
+
+    probe_rtt_round_done: bool,
+
+    extra_acked: usize,
+
+    extra_acked_filter: minmax::Minmax<usize>,
+
+    extra_acked_interval_start: Option<Instant>,
+
+    extra_acked_delivered: usize,
+
+    max_inflight: usize,
+
+    filled_pipe: bool,
+
+    full_bw: u64,
+
+    full_bw_count: usize,
+
+    round_count: u64,
+
+    round_start: bool,
+
+    next_round_delivered: usize,
+
+    loss_round_start: bool,
+
+    loss_round_delivered: usize,
+
+    loss_in_round: bool,
+
+    loss_events_in_round: usize,
+
+    loss_round_lost_bytes: usize,
+
+    rounds_since_probe: usize,
+
+    bw_probe_wait: Duration,
+
+    bw_probe_samples: bool,
+
+    bw_probe_up_rounds: u32,
+
+    bw_probe_up_acks: usize,
+
+    probe_up_cnt: usize,
+
+    cycle_stamp: Option<Instant>,
+
+    ack_phase: BBR2AckPhase,
+
+    in_recovery: bool,
+
+    packet_conservation: bool,
+
+    prior_cwnd: usize,
+
+    idle_restart: bool,
+
+    prior_bytes_in_flight: usize,
+
+    newly_acked_bytes: usize,
+
+    newly_lost_bytes: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            state: BBR2StateMachine::Startup,
+
+            pacing_gain: BBR_STARTUP_PACING_GAIN,
+
+            cwnd_gain: BBR_STARTUP_CWND_GAIN,
+
+            bw: 0,
+
+            max_bw: 0,
+
+            max_bw_filter: [0; 2],
+
+            cycle_count: 0,
+
+            bw_lo: u64::MAX,
+
+            inflight_lo: usize::MAX,
+
+            inflight_hi: usize::MAX,
+
+            bw_latest: 0,
+
+            inflight_latest: 0,
+
+            min_rtt: None,
+
+            min_rtt_stamp: None,
+
+            probe_rtt_min_delay: None,
+
+            probe_rtt_min_stamp: None,
+
+            probe_rtt_expired: false,
+
+            probe_rtt_done_stamp: None,
+
+            probe_rtt_round_done: false,
+
+            extra_acked: 0,
+
+            extra_acked_filter: minmax::Minmax::new(0),
+
+            extra_acked_interval_start: None,
+
+            extra_acked_delivered: 0,
+
+            max_inflight: 0,
+
+            filled_pipe: false,
+
+            full_bw: 0,
+
+            full_bw_count: 0,
+
+            round_count: 0,
+
+            round_start: false,
+
+            next_round_delivered: 0,
+
+            loss_round_start: false,
+
+            loss_round_delivered: 0,
+
+            loss_in_round: false,
+
+            loss_events_in_round: 0,
+
+            loss_round_lost_bytes: 0,
+
+            rounds_since_probe: 0,
+
+            bw_probe_wait: Duration::ZERO,
+
+            bw_probe_samples: false,
+
+            bw_probe_up_rounds: 0,
+
+            bw_probe_up_acks: 0,
+
+            probe_up_cnt: usize::MAX,
+
+            cycle_stamp: None,
+
+            ack_phase: BBR2AckPhase::Init,
+
+            in_recovery: false,
+
+            packet_conservation: false,
+
+            prior_cwnd: 0,
+
+            idle_restart: false,
+
+            prior_bytes_in_flight: 0,
+
+            newly_acked_bytes: 0,
+
+            newly_lost_bytes: 0,
+        }
+    }
+}
+
+pub fn on_init(r: &mut Recovery, now: Instant) {
+    r.bbr2_state.min_rtt = r.smoothed_rtt;
+    r.bbr2_state.min_rtt_stamp = Some(now);
+    r.bbr2_state.probe_rtt_min_delay = r.smoothed_rtt;
+    r.bbr2_state.probe_rtt_min_stamp = Some(now);
+    r.bbr2_state.extra_acked_interval_start = Some(now);
+    r.bbr2_state.next_round_delivered = r.delivery_rate.delivered();
+
+    init_pacing_rate(r);
+    enter_startup(r);
+}
+
+fn on_packet_sent(r: &mut Recovery, sent_bytes: usize, now: Instant) {
+    // Restarting from idle, pace at the estimated bandwidth to avoid sending
+    // a burst.
+    if r.bytes_in_flight == 0 && r.delivery_rate.app_limited() {
+        r.bbr2_state.idle_restart = true;
+        r.bbr2_state.extra_acked_interval_start = Some(now);
+
+        if is_in_probe_bw_state(r) {
+            set_pacing_rate_with_gain(r, 1.0);
+        }
+    }
+
+    r.bytes_in_flight += sent_bytes;
+}
+
+fn on_packets_acked(
+    r: &mut Recovery, packets: &[Acked], _epoch: packet::Epoch, now: Instant,
+) {
+    let newly_acked_bytes = packets.iter().map(|p| p.size).sum();
+
+    r.bbr2_state.prior_bytes_in_flight = r.bytes_in_flight;
+    r.bbr2_state.newly_acked_bytes = newly_acked_bytes;
+
+    r.bytes_in_flight = r.bytes_in_flight.saturating_sub(newly_acked_bytes);
+
+    // Exit recovery once a packet sent after its start is acked.
+    if let Some(pkt) = packets.last() {
+        if r.bbr2_state.in_recovery && !r.in_congestion_recovery(pkt.time_sent) {
+            exit_recovery(r);
+        }
+    }
+
+    update_model_and_state(r, now);
+    update_control_parameters(r);
+
+    r.bbr2_state.newly_lost_bytes = 0;
+}
+
+fn congestion_event(
+    r: &mut Recovery, lost_bytes: usize, time_sent: Instant,
+    _epoch: packet::Epoch, now: Instant,
+) {
+    // Losses are accounted for in the cwnd on the next ACK.
+    r.bbr2_state.newly_lost_bytes += lost_bytes;
+
+    r.bbr2_state.loss_in_round = true;
+    r.bbr2_state.loss_events_in_round += 1;
+    r.bbr2_state.loss_round_lost_bytes += lost_bytes;
+
+    // Stop probing as soon as the loss rate is too high.
+    let tx_in_flight = r.bytes_in_flight + lost_bytes;
+
+    if r.bbr2_state.bw_probe_samples && is_inflight_too_high(r, tx_in_flight) {
+        handle_inflight_too_high(r, tx_in_flight, now);
+    }
+
+    // Start a new congestion event if packet was sent after the
+    // start of the previous congestion recovery period.
+    if !r.in_congestion_recovery(time_sent) {
+        r.congestion_recovery_start_time = Some(now);
+
+        enter_recovery(r);
+    }
+}
+
+fn collapse_cwnd(r: &mut Recovery, _now: Instant) {
+    r.bbr2_state.prior_cwnd = save_cwnd(r);
+
+    r.congestion_window = r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS;
+}
+
+fn checkpoint(_r: &mut Recovery) {}
+
+fn rollback(_r: &mut Recovery) -> bool {
+    false
+}
+
+fn has_custom_pacing() -> bool {
+    true
+}
+
+fn debug_fmt(r: &Recovery, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let bbr = &r.bbr2_state;
+
+    write!(
+        f,
+        "bbr2={{ state={:?} bw={} max_bw={} min_rtt={:?} inflight_hi={} \
+         inflight_lo={} pacing_gain={} cwnd_gain={} max_inflight={} \
+         send_quantum={} filled_pipe={} round_count={} in_recovery={} }}",
+        bbr.state,
+        bbr.bw,
+        bbr.max_bw,
+        bbr.min_rtt,
+        bbr.inflight_hi,
+        bbr.inflight_lo,
+        bbr.pacing_gain,
+        bbr.cwnd_gain,
+        bbr.max_inflight,
+        r.send_quantum,
+        bbr.filled_pipe,
+        bbr.round_count,
+        bbr.in_recovery,
+    )
+}
+
+fn init_pacing_rate(r: &mut Recovery) {
+    let srtt = r.smoothed_rtt.unwrap_or_else(|| Duration::from_millis(1));
+
+    let nominal_bandwidth = r.congestion_window as f64 / srtt.as_secs_f64();
+
+    r.pacing_rate = (BBR_STARTUP_PACING_GAIN * nominal_bandwidth) as u64;
+}
+
+fn is_in_probe_bw_state(r: &Recovery) -> bool {
+    matches!(
+        r.bbr2_state.state,
+        BBR2StateMachine::ProbeBWDown |
+            BBR2StateMachine::ProbeBWCruise |
+            BBR2StateMachine::ProbeBWRefill |
+            BBR2StateMachine::ProbeBWUp
+    )
+}
+
+fn is_probing_bw(r: &Recovery) -> bool {
+    matches!(
+        r.bbr2_state.state,
+        BBR2StateMachine::Startup |
+            BBR2StateMachine::ProbeBWRefill |
+            BBR2StateMachine::ProbeBWUp
+    )
+}
+
+fn min_pipe_cwnd(r: &Recovery) -> usize {
+    r.max_datagram_size * BBR_MIN_PIPE_CWND_PKTS
+}
+
+// The estimated BDP for the given bandwidth, scaled by `gain`.
+fn bdp_multiple(r: &Recovery, bw: u64, gain: f64) -> usize {
+    match r.bbr2_state.min_rtt {
+        Some(min_rtt) => (gain * bw as f64 * min_rtt.as_secs_f64()) as usize,
+
+        // No valid RTT samples yet.
+        None => r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS,
+    }
+}
+
+fn quantization_budget(r: &Recovery, inflight: usize) -> usize {
+    // Allow enough data in flight to account for send and receive offloads.
+    let offload_budget = 3 * r.send_quantum;
+
+    let mut inflight = cmp::max(inflight, offload_budget);
+    inflight = cmp::max(inflight, min_pipe_cwnd(r));
+
+    if r.bbr2_state.state == BBR2StateMachine::ProbeBWUp {
+        inflight += 2 * r.max_datagram_size;
+    }
+
+    inflight
+}
+
+// The amount of data in flight needed to fully use the given bandwidth,
+// scaled by `gain`.
+fn inflight(r: &Recovery, bw: u64, gain: f64) -> usize {
+    quantization_budget(r, bdp_multiple(r, bw, gain))
+}
+
+fn inflight_with_headroom(r: &Recovery) -> usize {
+    let inflight_hi = r.bbr2_state.inflight_hi;
+
+    if inflight_hi == usize::MAX {
+        return usize::MAX;
+    }
+
+    let headroom = cmp::max(
+        r.max_datagram_size,
+        (BBR_HEADROOM * inflight_hi as f64) as usize,
+    );
+
+    cmp::max(inflight_hi.saturating_sub(headroom), min_pipe_cwnd(r))
+}
+
+fn target_inflight(r: &Recovery) -> usize {
+    cmp::min(bdp_multiple(r, r.bbr2_state.bw, 1.0), r.congestion_window)
+}
+
+fn probe_rtt_cwnd(r: &Recovery) -> usize {
+    cmp::max(
+        bdp_multiple(r, r.bbr2_state.bw, PROBE_RTT_CWND_GAIN),
+        min_pipe_cwnd(r),
+    )
+}
+
+fn enter_startup(r: &mut Recovery) {
+    r.bbr2_state.state = BBR2StateMachine::Startup;
+    r.bbr2_state.pacing_gain = BBR_STARTUP_PACING_GAIN;
+    r.bbr2_state.cwnd_gain = BBR_STARTUP_CWND_GAIN;
+}
+
+fn enter_drain(r: &mut Recovery) {
+    r.bbr2_state.state = BBR2StateMachine::Drain;
+    r.bbr2_state.pacing_gain = BBR_DRAIN_PACING_GAIN;
+    r.bbr2_state.cwnd_gain = BBR_STARTUP_CWND_GAIN;
+}
+
+fn start_probe_bw_down(r: &mut Recovery, now: Instant) {
+    reset_congestion_signals(r);
+
+    r.bbr2_state.probe_up_cnt = usize::MAX;
+
+    pick_probe_wait(r);
+
+    r.bbr2_state.cycle_stamp = Some(now);
+    r.bbr2_state.ack_phase = BBR2AckPhase::ProbeStopping;
+
+    start_round(r);
+
+    r.bbr2_state.state = BBR2StateMachine::ProbeBWDown;
+    r.bbr2_state.pacing_gain = BBR_PROBE_BW_DOWN_PACING_GAIN;
+    r.bbr2_state.cwnd_gain = BBR_CWND_GAIN;
+}
+
+fn start_probe_bw_cruise(r: &mut Recovery) {
+    r.bbr2_state.state = BBR2StateMachine::ProbeBWCruise;
+    r.bbr2_state.pacing_gain = 1.0;
+    r.bbr2_state.cwnd_gain = BBR_CWND_GAIN;
+}
+
+fn start_probe_bw_refill(r: &mut Recovery) {
+    reset_lower_bounds(r);
+
+    r.bbr2_state.bw_probe_up_rounds = 0;
+    r.bbr2_state.bw_probe_up_acks = 0;
+    r.bbr2_state.ack_phase = BBR2AckPhase::Refilling;
+
+    start_round(r);
+
+    r.bbr2_state.state = BBR2StateMachine::ProbeBWRefill;
+    r.bbr2_state.pacing_gain = 1.0;
+    r.bbr2_state.cwnd_gain = BBR_CWND_GAIN;
+}
+
+fn start_probe_bw_up(r: &mut Recovery, now: Instant) {
+    r.bbr2_state.ack_phase = BBR2AckPhase::ProbeStarting;
+
+    start_round(r);
+
+    r.bbr2_state.cycle_stamp = Some(now);
+    r.bbr2_state.state = BBR2StateMachine::ProbeBWUp;
+    r.bbr2_state.pacing_gain = BBR_PROBE_BW_UP_PACING_GAIN;
+    r.bbr2_state.cwnd_gain = BBR_CWND_GAIN;
+
+    raise_inflight_hi_slope(r);
+}
+
+fn enter_probe_rtt(r: &mut Recovery) {
+    r.bbr2_state.state = BBR2StateMachine::ProbeRTT;
+    r.bbr2_state.pacing_gain = 1.0;
+    r.bbr2_state.cwnd_gain = PROBE_RTT_CWND_GAIN;
+}
+
+fn exit_probe_rtt(r: &mut Recovery, now: Instant) {
+    reset_lower_bounds(r);
+
+    if r.bbr2_state.filled_pipe {
+        start_probe_bw_down(r, now);
+        start_probe_bw_cruise(r);
+    } else {
+        enter_startup(r);
+    }
+}
+
+fn enter_recovery(r: &mut Recovery) {
+    r.bbr2_state.prior_cwnd = save_cwnd(r);
+
+    r.congestion_window = r.bytes_in_flight + r.max_datagram_size;
+
+    r.bbr2_state.in_recovery = true;
+
+    // Packets sent before the start of recovery take one round trip to be
+    // acked, so use packet conservation until recovery ends.
+    r.bbr2_state.packet_conservation = true;
+}
+
+fn exit_recovery(r: &mut Recovery) {
+    r.bbr2_state.in_recovery = false;
+    r.bbr2_state.packet_conservation = false;
+
+    restore_cwnd(r);
+}
+
+fn save_cwnd(r: &Recovery) -> usize {
+    if !r.bbr2_state.in_recovery &&
+        r.bbr2_state.state != BBR2StateMachine::ProbeRTT
+    {
+        r.congestion_window
+    } else {
+        cmp::max(r.bbr2_state.prior_cwnd, r.congestion_window)
+    }
+}
+
+fn restore_cwnd(r: &mut Recovery) {
+    r.congestion_window = cmp::max(r.congestion_window, r.bbr2_state.prior_cwnd);
+}
+
+fn update_model_and_state(r: &mut Recovery, now: Instant) {
+    update_latest_delivery_signals(r);
+    update_congestion_signals(r);
+    update_ack_aggregation(r, now);
+    check_startup_done(r);
+    check_drain(r, now);
+    update_probe_bw_cycle_phase(r, now);
+    update_min_rtt(r, now);
+    check_probe_rtt(r, now);
+    advance_latest_delivery_signals(r);
+    bound_bw_for_model(r);
+}
+
+fn update_control_parameters(r: &mut Recovery) {
+    set_pacing_rate_with_gain(r, r.bbr2_state.pacing_gain);
+    set_send_quantum(r);
+    set_cwnd(r);
+}
+
+fn start_round(r: &mut Recovery) {
+    r.bbr2_state.next_round_delivered = r.delivery_rate.delivered();
+}
+
+fn update_round(r: &mut Recovery) {
+    if r.delivery_rate.sample_prior_delivered() >=
+        r.bbr2_state.next_round_delivered
+    {
+        start_round(r);
+
+        r.bbr2_state.round_count += 1;
+        r.bbr2_state.rounds_since_probe += 1;
+        r.bbr2_state.round_start = true;
+    } else {
+        r.bbr2_state.round_start = false;
+    }
+}
+
+fn update_latest_delivery_signals(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    bbr.loss_round_start = false;
+
+    bbr.bw_latest =
+        cmp::max(bbr.bw_latest, r.delivery_rate.sample_delivery_rate());
+    bbr.inflight_latest =
+        cmp::max(bbr.inflight_latest, r.delivery_rate.sample_delivered());
+
+    if r.delivery_rate.sample_prior_delivered() >= bbr.loss_round_delivered {
+        bbr.loss_round_delivered = r.delivery_rate.delivered();
+        bbr.loss_round_start = true;
+    }
+}
+
+fn advance_latest_delivery_signals(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    if bbr.loss_round_start {
+        bbr.bw_latest = r.delivery_rate.sample_delivery_rate();
+        bbr.inflight_latest = r.delivery_rate.sample_delivered();
+
+        bbr.loss_events_in_round = 0;
+        bbr.loss_round_lost_bytes = 0;
+    }
+}
+
+fn reset_congestion_signals(r: &mut Recovery) {
+    r.bbr2_state.loss_in_round = false;
+    r.bbr2_state.bw_latest = 0;
+    r.bbr2_state.inflight_latest = 0;
+}
+
+fn update_congestion_signals(r: &mut Recovery) {
+    update_max_bw(r);
+
+    if !r.bbr2_state.loss_round_start {
+        return;
+    }
+
+    adapt_lower_bounds_from_congestion(r);
+
+    r.bbr2_state.loss_in_round = false;
+}
+
+fn adapt_lower_bounds_from_congestion(r: &mut Recovery) {
+    if is_probing_bw(r) {
+        return;
+    }
+
+    if r.bbr2_state.loss_in_round {
+        init_lower_bounds(r);
+        loss_lower_bounds(r);
+    }
+}
+
+fn init_lower_bounds(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    if bbr.bw_lo == u64::MAX {
+        bbr.bw_lo = bbr.max_bw;
+    }
+
+    if bbr.inflight_lo == usize::MAX {
+        bbr.inflight_lo = r.congestion_window;
+    }
+}
+
+fn loss_lower_bounds(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    bbr.bw_lo = cmp::max(bbr.bw_latest, (BBR_BETA * bbr.bw_lo as f64) as u64);
+    bbr.inflight_lo = cmp::max(
+        bbr.inflight_latest,
+        (BBR_BETA * bbr.inflight_lo as f64) as usize,
+    );
+}
+
+fn reset_lower_bounds(r: &mut Recovery) {
+    r.bbr2_state.bw_lo = u64::MAX;
+    r.bbr2_state.inflight_lo = usize::MAX;
+}
+
+fn bound_bw_for_model(r: &mut Recovery) {
+    r.bbr2_state.bw = cmp::min(r.bbr2_state.max_bw, r.bbr2_state.bw_lo);
+}
+
+fn update_max_bw(r: &mut Recovery) {
+    update_round(r);
+
+    let delivery_rate = r.delivery_rate.sample_delivery_rate();
+
+    if delivery_rate >= r.bbr2_state.max_bw ||
+        !r.delivery_rate.sample_is_app_limited()
+    {
+        let bbr = &mut r.bbr2_state;
+
+        // The filter is windowed by ProbeBW cycles.
+        let i = (bbr.cycle_count % 2) as usize;
+
+        bbr.max_bw_filter[i] = cmp::max(bbr.max_bw_filter[i], delivery_rate);
+        bbr.max_bw = cmp::max(bbr.max_bw_filter[0], bbr.max_bw_filter[1]);
+    }
+}
+
+fn advance_max_bw_filter(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    bbr.cycle_count += 1;
+
+    // Forget the samples from two cycles ago.
+    bbr.max_bw_filter[(bbr.cycle_count % 2) as usize] = 0;
+    bbr.max_bw = cmp::max(bbr.max_bw_filter[0], bbr.max_bw_filter[1]);
+}
+
+fn update_ack_aggregation(r: &mut Recovery, now: Instant) {
+    let bbr = &mut r.bbr2_state;
+
+    let interval = match bbr.extra_acked_interval_start {
+        Some(start) => now.saturating_duration_since(start),
+
+        None => Duration::ZERO,
+    };
+
+    let mut expected_delivered =
+        (bbr.bw as f64 * interval.as_secs_f64()) as usize;
+
+    // Reset the aggregation epoch if the ACK rate is below the expected one.
+    if bbr.extra_acked_delivered <= expected_delivered {
+        bbr.extra_acked_delivered = 0;
+        bbr.extra_acked_interval_start = Some(now);
+
+        expected_delivered = 0;
+    }
+
+    bbr.extra_acked_delivered += bbr.newly_acked_bytes;
+
+    let extra = cmp::min(
+        bbr.extra_acked_delivered - expected_delivered,
+        r.congestion_window,
+    );
+
+    // The filter is windowed by time, so use the smoothed RTT to
+    // approximate its length in round trips.
+    let win = r.rtt() * BBR_EXTRA_ACKED_FILTER_LEN;
+
+    r.bbr2_state.extra_acked =
+        r.bbr2_state.extra_acked_filter.running_max(win, now, extra);
+}
+
+fn check_startup_done(r: &mut Recovery) {
+    check_startup_full_bandwidth(r);
+    check_startup_high_loss(r);
+
+    if r.bbr2_state.state == BBR2StateMachine::Startup && r.bbr2_state.filled_pipe
+    {
+        enter_drain(r);
+    }
+}
+
+fn check_startup_full_bandwidth(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    if bbr.filled_pipe ||
+        !bbr.round_start ||
+        r.delivery_rate.sample_is_app_limited()
+    {
+        return;
+    }
+
+    // Still growing?
+    if bbr.max_bw as f64 >= bbr.full_bw as f64 * BBR_FULL_BW_GROWTH_TARGET {
+        bbr.full_bw = bbr.max_bw;
+        bbr.full_bw_count = 0;
+        return;
+    }
+
+    bbr.full_bw_count += 1;
+
+    if bbr.full_bw_count >= BBR_FULL_BW_GROWTH_ROUNDS {
+        bbr.filled_pipe = true;
+    }
+}
+
+fn check_startup_high_loss(r: &mut Recovery) {
+    let bbr = &r.bbr2_state;
+
+    if bbr.filled_pipe ||
+        bbr.state != BBR2StateMachine::Startup ||
+        !bbr.loss_round_start ||
+        bbr.loss_events_in_round < BBR_STARTUP_FULL_LOSS_CNT
+    {
+        return;
+    }
+
+    if is_inflight_too_high(r, bbr.prior_bytes_in_flight) {
+        let inflight_hi = cmp::max(
+            bdp_multiple(r, r.bbr2_state.max_bw, 1.0),
+            r.bbr2_state.inflight_latest,
+        );
+
+        r.bbr2_state.inflight_hi = inflight_hi;
+        r.bbr2_state.filled_pipe = true;
+    }
+}
+
+fn check_drain(r: &mut Recovery, now: Instant) {
+    if r.bbr2_state.state == BBR2StateMachine::Drain &&
+        r.bytes_in_flight <= inflight(r, r.bbr2_state.max_bw, 1.0)
+    {
+        start_probe_bw_down(r, now);
+    }
+}
+
+fn update_probe_bw_cycle_phase(r: &mut Recovery, now: Instant) {
+    if !r.bbr2_state.filled_pipe {
+        return;
+    }
+
+    adapt_upper_bounds(r, now);
+
+    match r.bbr2_state.state {
+        BBR2StateMachine::ProbeBWDown => {
+            if check_time_to_probe_bw(r, now) {
+                return;
+            }
+
+            if check_time_to_cruise(r) {
+                start_probe_bw_cruise(r);
+            }
+        },
+
+        BBR2StateMachine::ProbeBWCruise => {
+            check_time_to_probe_bw(r, now);
+        },
+
+        // After one round of refilling, start probing.
+        BBR2StateMachine::ProbeBWRefill if r.bbr2_state.round_start => {
+            r.bbr2_state.bw_probe_samples = true;
+
+            start_probe_bw_up(r, now);
+        },
+
+        BBR2StateMachine::ProbeBWUp => {
+            let target =
+                inflight(r, r.bbr2_state.max_bw, BBR_PROBE_BW_UP_PACING_GAIN);
+
+            if has_elapsed_in_phase(r, r.bbr2_state.min_rtt, now) &&
+                r.bytes_in_flight > target
+            {
+                start_probe_bw_down(r, now);
+            }
+        },
+
+        _ => (),
+    }
+}
+
+fn has_elapsed_in_phase(
+    r: &Recovery, interval: Option<Duration>, now: Instant,
+) -> bool {
+    match (interval, r.bbr2_state.cycle_stamp) {
+        (Some(interval), Some(cycle_stamp)) => now > cycle_stamp + interval,
+
+        _ => true,
+    }
+}
+
+fn pick_probe_wait(r: &mut Recovery) {
+    // Randomize the time between probes, to desynchronize flows.
+    r.bbr2_state.rounds_since_probe = crate::rand::rand_u64_uniform(2) as usize;
+    r.bbr2_state.bw_probe_wait =
+        Duration::from_millis(2000 + crate::rand::rand_u64_uniform(1000));
+}
+
+fn is_reno_coexistence_probe_time(r: &Recovery) -> bool {
+    let reno_rounds = target_inflight(r) / r.max_datagram_size;
+
+    r.bbr2_state.rounds_since_probe >= cmp::min(reno_rounds, BBR_MAX_RENO_ROUNDS)
+}
+
+fn check_time_to_probe_bw(r: &mut Recovery, now: Instant) -> bool {
+    if has_elapsed_in_phase(r, Some(r.bbr2_state.bw_probe_wait), now) ||
+        is_reno_coexistence_probe_time(r)
+    {
+        start_probe_bw_refill(r);
+
+        return true;
+    }
+
+    false
+}
+
+fn check_time_to_cruise(r: &Recovery) -> bool {
+    if r.bytes_in_flight > inflight_with_headroom(r) {
+        return false;
+    }
+
+    r.bytes_in_flight <= inflight(r, r.bbr2_state.max_bw, 1.0)
+}
+
+fn adapt_upper_bounds(r: &mut Recovery, now: Instant) {
+    if r.bbr2_state.round_start {
+        match r.bbr2_state.ack_phase {
+            BBR2AckPhase::ProbeStarting =>
+                r.bbr2_state.ack_phase = BBR2AckPhase::ProbeFeedback,
+
+            // The samples from the last bandwidth probe are all in, so forget
+            // about the ones from the previous cycle.
+            BBR2AckPhase::ProbeStopping => {
+                r.bbr2_state.bw_probe_samples = false;
+                r.bbr2_state.ack_phase = BBR2AckPhase::Init;
+
+                if is_in_probe_bw_state(r) &&
+                    !r.delivery_rate.sample_is_app_limited()
+                {
+                    advance_max_bw_filter(r);
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    if check_inflight_too_high(r, now) {
+        return;
+    }
+
+    let bbr = &mut r.bbr2_state;
+
+    if bbr.inflight_hi == usize::MAX {
+        return;
+    }
+
+    if bbr.prior_bytes_in_flight > bbr.inflight_hi {
+        bbr.inflight_hi = bbr.prior_bytes_in_flight;
+    }
+
+    if bbr.state == BBR2StateMachine::ProbeBWUp {
+        probe_inflight_hi_upward(r);
+    }
+}
+
+fn is_inflight_too_high(r: &Recovery, tx_in_flight: usize) -> bool {
+    r.bbr2_state.loss_round_lost_bytes as f64 >
+        tx_in_flight as f64 * BBR_LOSS_THRESH
+}
+
+fn check_inflight_too_high(r: &mut Recovery, now: Instant) -> bool {
+    let tx_in_flight = r.bbr2_state.prior_bytes_in_flight;
+
+    if is_inflight_too_high(r, tx_in_flight) {
+        if r.bbr2_state.bw_probe_samples {
+            handle_inflight_too_high(r, tx_in_flight, now);
+        }
+
+        return true;
+    }
+
+    false
+}
+
+fn handle_inflight_too_high(r: &mut Recovery, tx_in_flight: usize, now: Instant) {
+    r.bbr2_state.bw_probe_samples = false;
+
+    if !r.delivery_rate.sample_is_app_limited() {
+        r.bbr2_state.inflight_hi = cmp::max(
+            tx_in_flight,
+            (target_inflight(r) as f64 * BBR_BETA) as usize,
+        );
+    }
+
+    if r.bbr2_state.state == BBR2StateMachine::ProbeBWUp {
+        start_probe_bw_down(r, now);
+    }
+}
+
+fn raise_inflight_hi_slope(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    // Double the growth of inflight_hi every round.
+    let growth_this_round =
+        (r.max_datagram_size as u64) << bbr.bw_probe_up_rounds;
+
+    bbr.bw_probe_up_rounds =
+        cmp::min(bbr.bw_probe_up_rounds + 1, BBR_MAX_PROBE_UP_ROUNDS);
+
+    let probe_up_pkts =
+        cmp::max(r.congestion_window as u64 / growth_this_round, 1);
+
+    bbr.probe_up_cnt = probe_up_pkts as usize * r.max_datagram_size;
+}
+
+fn probe_inflight_hi_upward(r: &mut Recovery) {
+    let bbr = &mut r.bbr2_state;
+
+    let is_cwnd_limited =
+        bbr.prior_bytes_in_flight + r.max_datagram_size > r.congestion_window;
+
+    if !is_cwnd_limited || r.congestion_window < bbr.inflight_hi {
+        return;
+    }
+
+    bbr.bw_probe_up_acks += bbr.newly_acked_bytes;
+
+    // Grow inflight_hi by one packet every `probe_up_cnt` bytes acked.
+    if bbr.bw_probe_up_acks >= bbr.probe_up_cnt {
+        let delta = bbr.bw_probe_up_acks / bbr.probe_up_cnt;
+
+        bbr.bw_probe_up_acks -= delta * bbr.probe_up_cnt;
+        bbr.inflight_hi += delta * r.max_datagram_size;
+    }
+
+    if bbr.round_start {
+        raise_inflight_hi_slope(r);
+    }
+}
+
+fn update_min_rtt(r: &mut Recovery, now: Instant) {
+    let bbr = &mut r.bbr2_state;
+
+    let sample_rtt = r.delivery_rate.sample_rtt();
+
+    bbr.probe_rtt_expired = match bbr.probe_rtt_min_stamp {
+        Some(stamp) => now > stamp + PROBE_RTT_INTERVAL,
+
+        None => false,
+    };
+
+    match bbr.probe_rtt_min_delay {
+        Some(delay) if sample_rtt >= delay && !bbr.probe_rtt_expired => (),
+
+        _ => {
+            bbr.probe_rtt_min_delay = Some(sample_rtt);
+            bbr.probe_rtt_min_stamp = Some(now);
+        },
+    }
+
+    let min_rtt_expired = match bbr.min_rtt_stamp {
+        Some(stamp) => now > stamp + MIN_RTT_FILTER_LEN,
+
+        None => false,
+    };
+
+    let probe_rtt_min_delay = bbr.probe_rtt_min_delay;
+
+    match bbr.min_rtt {
+        Some(min_rtt)
+            if probe_rtt_min_delay >= Some(min_rtt) && !min_rtt_expired =>
+            (),
+
+        _ => {
+            bbr.min_rtt = probe_rtt_min_delay;
+            bbr.min_rtt_stamp = bbr.probe_rtt_min_stamp;
+        },
+    }
+}
+
+fn check_probe_rtt(r: &mut Recovery, now: Instant) {
+    if r.bbr2_state.state != BBR2StateMachine::ProbeRTT &&
+        r.bbr2_state.probe_rtt_expired &&
+        !r.bbr2_state.idle_restart
+    {
+        enter_probe_rtt(r);
+
+        r.bbr2_state.prior_cwnd = save_cwnd(r);
+        r.bbr2_state.probe_rtt_done_stamp = None;
+        r.bbr2_state.ack_phase = BBR2AckPhase::ProbeStopping;
+
+        start_round(r);
+    }
+
+    if r.bbr2_state.state == BBR2StateMachine::ProbeRTT {
+        handle_probe_rtt(r, now);
+    }
+
+    if r.delivery_rate.sample_delivered() > 0 {
+        r.bbr2_state.idle_restart = false;
+    }
+}
+
+fn handle_probe_rtt(r: &mut Recovery, now: Instant) {
+    // Ignore low rate samples during ProbeRTT.
+    r.delivery_rate.update_app_limited(true);
+
+    match r.bbr2_state.probe_rtt_done_stamp {
+        Some(done_stamp) => {
+            if r.bbr2_state.round_start {
+                r.bbr2_state.probe_rtt_round_done = true;
+            }
+
+            if r.bbr2_state.probe_rtt_round_done && now > done_stamp {
+                r.bbr2_state.probe_rtt_min_stamp = Some(now);
+
+                restore_cwnd(r);
+                exit_probe_rtt(r, now);
+            }
+        },
+
+        None =>
+            if r.bytes_in_flight <= probe_rtt_cwnd(r) {
+                r.bbr2_state.probe_rtt_done_stamp =
+                    Some(now + PROBE_RTT_DURATION);
+                r.bbr2_state.probe_rtt_round_done = false;
+
+                start_round(r);
+            },
+    }
+}
+
+fn set_pacing_rate_with_gain(r: &mut Recovery, pacing_gain: f64) {
+    let rate = (pacing_gain *
+        r.bbr2_state.bw as f64 *
+        (1.0 - BBR_PACING_MARGIN_PERCENT)) as u64;
+
+    if r.bbr2_state.filled_pipe || rate > r.pacing_rate {
+        r.pacing_rate = rate;
+    }
+}
+
+fn set_send_quantum(r: &mut Recovery) {
+    r.send_quantum = match r.pacing_rate {
+        rate if rate < SEND_QUANTUM_THRESHOLD_LOW => r.max_datagram_size,
+
+        rate if rate < SEND_QUANTUM_THRESHOLD_HIGH => r.max_datagram_size * 2,
+
+        // Send up to 1ms worth of data at once.
+        rate => cmp::min((rate / 1000) as usize, MAX_SEND_QUANTUM),
+    };
+}
+
+fn update_max_inflight(r: &mut Recovery) {
+    let inflight = bdp_multiple(r, r.bbr2_state.bw, r.bbr2_state.cwnd_gain) +
+        r.bbr2_state.extra_acked;
+
+    r.bbr2_state.max_inflight = quantization_budget(r, inflight);
+}
+
+fn set_cwnd(r: &mut Recovery) {
+    update_max_inflight(r);
+    modulate_cwnd_for_recovery(r);
+
+    if !r.bbr2_state.packet_conservation {
+        let newly_acked_bytes = r.bbr2_state.newly_acked_bytes;
+
+        if r.bbr2_state.filled_pipe {
+            r.congestion_window = cmp::min(
+                r.congestion_window + newly_acked_bytes,
+                r.bbr2_state.max_inflight,
+            );
+        } else if r.congestion_window < r.bbr2_state.max_inflight ||
+            r.delivery_rate.delivered() <
+                r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS
+        {
+            r.congestion_window += newly_acked_bytes;
+        }
+
+        r.congestion_window = cmp::max(r.congestion_window, min_pipe_cwnd(r));
+    }
+
+    bound_cwnd_for_probe_rtt(r);
+    bound_cwnd_for_model(r);
+}
+
+fn modulate_cwnd_for_recovery(r: &mut Recovery) {
+    if r.bbr2_state.newly_lost_bytes > 0 {
+        r.congestion_window = cmp::max(
+            r.congestion_window
+                .saturating_sub(r.bbr2_state.newly_lost_bytes),
+            r.max_datagram_size,
+        );
+    }
+
+    if r.bbr2_state.packet_conservation {
+        r.congestion_window = cmp::max(
+            r.congestion_window,
+            r.bytes_in_flight + r.bbr2_state.newly_acked_bytes,
+        );
+    }
+}
+
+fn bound_cwnd_for_probe_rtt(r: &mut Recovery) {
+    if r.bbr2_state.state == BBR2StateMachine::ProbeRTT {
+        r.congestion_window = cmp::min(r.congestion_window, probe_rtt_cwnd(r));
+    }
+}
+
+fn bound_cwnd_for_model(r: &mut Recovery) {
+    let mut cap = match r.bbr2_state.state {
+        BBR2StateMachine::ProbeBWDown |
+        BBR2StateMachine::ProbeBWRefill |
+        BBR2StateMachine::ProbeBWUp => r.bbr2_state.inflight_hi,
+
+        BBR2StateMachine::ProbeBWCruise | BBR2StateMachine::ProbeRTT =>
+            inflight_with_headroom(r),
+
+        _ => usize::MAX,
+    };
+
+    cap = cmp::min(cap, r.bbr2_state.inflight_lo);
+    cap = cmp::max(cap, min_pipe_cwnd(r));
+
+    r.congestion_window = cmp::min(r.congestion_window, cap);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ranges;
+    use crate::recovery::HandshakeStatus;
+    use crate::recovery::Sent;
+
+    fn new_recovery() -> Recovery {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::BBR2);
+
+        let mut r = Recovery::new(&cfg);
+        r.on_init(Instant::now());
+
+        r
+    }
+
+    fn send_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        for pn in pkt_nums {
+            let pkt = Sent {
+                pkt_num: pn,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: r.max_datagram_size,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                pkt,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+    }
+
+    fn ack_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(pkt_nums);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            ),
+            Ok(())
+        );
+    }
+
+    // Sends and acks `pkts_per_round` packets per round trip, until `done`
+    // returns true.
+    fn run_rounds(
+        r: &mut Recovery, mut pn: u64, pkts_per_round: u64, rtt: Duration,
+        mut now: Instant, done: fn(&Recovery) -> bool,
+    ) -> (u64, Instant) {
+        let mut rounds = 0;
+
+        while !done(r) {
+            send_packets(r, pn..pn + pkts_per_round, now);
+
+            now += rtt;
+
+            ack_packets(r, pn..pn + pkts_per_round, now);
+
+            pn += pkts_per_round;
+
+            rounds += 1;
+            assert!(rounds < 100);
+        }
+
+        (pn, now)
+    }
+
+    #[test]
+    fn bbr2_init() {
+        let r = new_recovery();
+
+        assert_eq!(
+            r.cwnd(),
+            r.max_datagram_size * recovery::INITIAL_WINDOW_PACKETS
+        );
+        assert_eq!(r.bytes_in_flight, 0);
+
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::Startup);
+        assert_eq!(r.bbr2_state.pacing_gain, BBR_STARTUP_PACING_GAIN);
+
+        // Pacing starts from the initial window over 1ms.
+        assert_eq!(
+            r.pacing_rate,
+            (BBR_STARTUP_PACING_GAIN * r.cwnd() as f64 / 0.001) as u64
+        );
+    }
+
+    #[test]
+    fn bbr2_init_time() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::BBR2);
+
+        let mut r = Recovery::new(&cfg);
+
+        // The connection runs on a clock well ahead of the wall clock.
+        let mut now = Instant::now() + Duration::from_secs(60);
+
+        r.on_init(now);
+        assert_eq!(r.bbr2_state.probe_rtt_min_stamp, Some(now));
+
+        send_packets(&mut r, 0..1, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..1, now);
+
+        // The min RTT was stamped with the caller's time, so it didn't expire.
+        assert!(!r.bbr2_state.probe_rtt_expired);
+        assert_ne!(r.bbr2_state.state, BBR2StateMachine::ProbeRTT);
+    }
+
+    #[test]
+    fn bbr2_send() {
+        let mut r = new_recovery();
+        let now = Instant::now();
+
+        send_packets(&mut r, 0..1, now);
+
+        assert_eq!(r.bytes_in_flight, r.max_datagram_size);
+    }
+
+    #[test]
+    fn bbr2_startup() {
+        let mut r = new_recovery();
+        let mut now = Instant::now();
+        let mss = r.max_datagram_size;
+
+        send_packets(&mut r, 0..5, now);
+
+        let cwnd_prev = r.cwnd();
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..5, now);
+
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::Startup);
+        assert_eq!(r.bytes_in_flight, 0);
+
+        // The window grows by the amount of acked data.
+        assert_eq!(r.cwnd(), cwnd_prev + mss * 5);
+
+        assert_eq!(r.bbr2_state.min_rtt, Some(Duration::from_millis(50)));
+        assert_eq!(r.bbr2_state.round_count, 1);
+        assert_eq!(r.bbr2_state.max_bw, r.delivery_rate());
+        assert_eq!(r.bbr2_state.bw, r.bbr2_state.max_bw);
+        assert!(r.bbr2_state.bw > 0);
+    }
+
+    #[test]
+    fn bbr2_congestion_event() {
+        let mut r = new_recovery();
+        let mut now = Instant::now();
+        let mss = r.max_datagram_size;
+
+        send_packets(&mut r, 0..5, now);
+
+        now += Duration::from_millis(50);
+
+        // Acking the last packet causes the first two to be lost, due to the
+        // packet reordering threshold.
+        ack_packets(&mut r, 4..5, now);
+
+        assert_eq!(r.lost_count, 2);
+
+        assert!(r.bbr2_state.in_recovery);
+        assert!(r.bbr2_state.packet_conservation);
+
+        // The window only allows one packet to be sent for each one acked.
+        assert_eq!(r.bytes_in_flight, mss * 2);
+        assert_eq!(r.cwnd(), r.bytes_in_flight + mss);
+
+        // Acking a packet sent after recovery started ends it.
+        now += Duration::from_millis(1);
+
+        send_packets(&mut r, 5..6, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 2..6, now);
+
+        assert!(!r.bbr2_state.in_recovery);
+        assert!(!r.bbr2_state.packet_conservation);
+
+        // Losses in Startup don't set any bound on the model.
+        assert_eq!(r.bbr2_state.bw_lo, u64::MAX);
+        assert_eq!(r.bbr2_state.inflight_lo, usize::MAX);
+        assert_eq!(r.bbr2_state.inflight_hi, usize::MAX);
+    }
+
+    #[test]
+    fn bbr2_drain_and_probe_bw() {
+        let mut r = new_recovery();
+        let rtt = Duration::from_millis(50);
+
+        run_rounds(&mut r, 0, 10, rtt, Instant::now(), |r| {
+            r.bbr2_state.filled_pipe
+        });
+
+        // All data was acked, so Drain and ProbeBW_DOWN end immediately.
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::ProbeBWCruise);
+        assert_eq!(r.bbr2_state.pacing_gain, 1.0);
+        assert_eq!(r.bbr2_state.cwnd_gain, BBR_CWND_GAIN);
+
+        // The bandwidth estimate converged to 10 packets per round trip.
+        let bw = (10 * r.max_datagram_size) as f64 / rtt.as_secs_f64();
+        assert_eq!(r.bbr2_state.bw, bw as u64);
+
+        assert_eq!(
+            r.pacing_rate,
+            (bw as u64 as f64 * (1.0 - BBR_PACING_MARGIN_PERCENT)) as u64
+        );
+    }
+
+    #[test]
+    fn bbr2_probe_bw_up() {
+        let mut r = new_recovery();
+        let rtt = Duration::from_millis(50);
+
+        let (pn, now) = run_rounds(&mut r, 0, 10, rtt, Instant::now(), |r| {
+            r.bbr2_state.filled_pipe
+        });
+
+        // Bandwidth probing starts after at most as many rounds as the
+        // estimated BDP in packets, to coexist with Reno flows.
+        let (pn, now) = run_rounds(&mut r, pn, 10, rtt, now, |r| {
+            r.bbr2_state.state == BBR2StateMachine::ProbeBWUp
+        });
+
+        assert!(r.bbr2_state.round_count < 20);
+        assert_eq!(r.bbr2_state.pacing_gain, BBR_PROBE_BW_UP_PACING_GAIN);
+        assert!(r.bbr2_state.bw_probe_samples);
+        assert_eq!(r.bbr2_state.inflight_hi, usize::MAX);
+
+        // Too many losses while probing stop it, and bound inflight.
+        send_packets(&mut r, pn..pn + 10, now);
+
+        ack_packets(&mut r, pn + 5..pn + 10, now + rtt);
+
+        assert_eq!(r.lost_count, 5);
+
+        // Nothing is left in flight, so ProbeBW_DOWN ends immediately.
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::ProbeBWCruise);
+        assert_eq!(r.bbr2_state.pacing_gain, 1.0);
+        assert!(!r.bbr2_state.bw_probe_samples);
+        assert!(r.bbr2_state.inflight_hi < usize::MAX);
+        assert!(r.cwnd() <= r.bbr2_state.inflight_hi);
+    }
+
+    #[test]
+    fn bbr2_probe_rtt() {
+        let mut r = new_recovery();
+        let rtt = Duration::from_millis(50);
+
+        let (pn, now) = run_rounds(&mut r, 0, 10, rtt, Instant::now(), |r| {
+            r.bbr2_state.filled_pipe
+        });
+
+        // Let the ProbeRTT interval expire, with a slightly higher RTT sample.
+        let sent_time = now + PROBE_RTT_INTERVAL;
+        let now = sent_time + rtt * 2;
+
+        send_packets(&mut r, pn..pn + 1, sent_time);
+        ack_packets(&mut r, pn..pn + 1, now);
+
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::ProbeRTT);
+        assert_eq!(r.cwnd(), probe_rtt_cwnd(&r));
+        assert!(r.cwnd() < r.bbr2_state.prior_cwnd);
+
+        // The min RTT filter didn't expire yet.
+        assert_eq!(r.bbr2_state.min_rtt, Some(rtt));
+        assert_eq!(r.bbr2_state.probe_rtt_min_delay, Some(rtt * 2));
+
+        // Nothing is in flight, so ProbeRTT is scheduled to end.
+        assert_eq!(
+            r.bbr2_state.probe_rtt_done_stamp,
+            Some(now + PROBE_RTT_DURATION)
+        );
+
+        // After a round trip and PROBE_RTT_DURATION, ProbeBW resumes.
+        let now = now + PROBE_RTT_DURATION;
+
+        send_packets(&mut r, pn + 1..pn + 2, now);
+        ack_packets(&mut r, pn + 1..pn + 2, now + rtt);
+
+        assert_eq!(r.bbr2_state.state, BBR2StateMachine::ProbeBWCruise);
+
+        // The window is restored, up to what the model allows.
+        assert_eq!(r.cwnd(), r.bbr2_state.max_inflight);
+        assert!(r.cwnd() > probe_rtt_cwnd(&r));
+    }
+}
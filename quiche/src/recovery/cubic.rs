@@ -145,9 +145,9 @@ impl State {
     }
 }
 
-fn on_init(_r: &mut Recovery) {}
+fn on_init(_r: &mut Recovery, _now: Instant) {}
 
-fn collapse_cwnd(r: &mut Recovery) {
+fn collapse_cwnd(r: &mut Recovery, now: Instant) {
     let cubic = &mut r.cubic_state;
 
     r.congestion_recovery_start_time = None;
@@ -163,7 +163,7 @@ fn collapse_cwnd(r: &mut Recovery) {
 
     cubic.cwnd_inc = 0;
 
-    reno::collapse_cwnd(r);
+    reno::collapse_cwnd(r, now);
 }
 
 fn on_packet_sent(r: &mut Recovery, sent_bytes: usize, now: Instant) {
@@ -478,6 +478,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -526,6 +527,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -673,7 +675,7 @@ mod tests {
         );
 
         // After persistent congestion, cwnd should be the minimum window
-        r.collapse_cwnd();
+        r.collapse_cwnd(now);
         assert_eq!(
             r.cwnd(),
             r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS
@@ -724,6 +726,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // 1st round.
@@ -872,6 +875,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // 1st round.
@@ -0,0 +1,498 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Application-provided congestion control.
+//!
+//! This forwards congestion control events to the [`CongestionControl`]
+//! instance created by the application, and applies its congestion window
+//! and pacing rate to the path.
+//!
+//! [`CongestionControl`]: ../trait.CongestionControl.html
+
+use std::time::Instant;
+
+use crate::packet;
+
+use crate::recovery::Acked;
+use crate::recovery::AckedPacket;
+use crate::recovery::CongestionControlOps;
+use crate::recovery::DeliveryRateSample;
+use crate::recovery::Recovery;
+use crate::recovery::PACING_MULTIPLIER;
+
+pub static CUSTOM: CongestionControlOps = CongestionControlOps {
+    on_init,
+    on_packet_sent,
+    on_packets_acked,
+    congestion_event,
+    collapse_cwnd,
+    checkpoint,
+    rollback,
+    has_custom_pacing,
+    debug_fmt,
+};
+
+pub fn on_init(r: &mut Recovery, _now: Instant) {
+    let max_datagram_size = r.max_datagram_size;
+
+    if let Some(cc) = r.custom_cc.as_mut() {
+        cc.on_init(max_datagram_size);
+    }
+
+    update_control_parameters(r);
+}
+
+fn on_packet_sent(r: &mut Recovery, sent_bytes: usize, now: Instant) {
+    r.bytes_in_flight += sent_bytes;
+
+    let bytes_in_flight = r.bytes_in_flight;
+
+    if let Some(cc) = r.custom_cc.as_mut() {
+        cc.on_packet_sent(sent_bytes, bytes_in_flight, now);
+    }
+
+    update_control_parameters(r);
+}
+
+fn on_packets_acked(
+    r: &mut Recovery, packets: &[Acked], _epoch: packet::Epoch, now: Instant,
+) {
+    let acked_bytes: usize = packets.iter().map(|p| p.size).sum();
+
+    r.bytes_in_flight = r.bytes_in_flight.saturating_sub(acked_bytes);
+
+    let bytes_in_flight = r.bytes_in_flight;
+
+    // Only report reliable rate samples.
+    let rate_sample = if !r.delivery_rate.sample_interval().is_zero() {
+        Some(DeliveryRateSample {
+            delivery_rate: r.delivery_rate.sample_delivery_rate(),
+            delivered: r.delivery_rate.sample_delivered(),
+            interval: r.delivery_rate.sample_interval(),
+            rtt: r.delivery_rate.sample_rtt(),
+            is_app_limited: r.delivery_rate.sample_is_app_limited(),
+        })
+    } else {
+        None
+    };
+
+    let packets: Vec<AckedPacket> = packets
+        .iter()
+        .map(|p| AckedPacket {
+            pkt_num: p.pkt_num,
+            size: p.size,
+            time_sent: p.time_sent,
+            rtt: p.rtt,
+        })
+        .collect();
+
+    if let Some(cc) = r.custom_cc.as_mut() {
+        if let Some(sample) = rate_sample {
+            cc.on_delivery_rate_sample(&sample);
+        }
+
+        cc.on_packets_acked(&packets, bytes_in_flight, now);
+    }
+
+    update_control_parameters(r);
+}
+
+fn congestion_event(
+    r: &mut Recovery, lost_bytes: usize, time_sent: Instant,
+    _epoch: packet::Epoch, now: Instant,
+) {
+    let bytes_in_flight = r.bytes_in_flight;
+
+    if let Some(cc) = r.custom_cc.as_mut() {
+        cc.on_packets_lost(lost_bytes, time_sent, bytes_in_flight, now);
+    }
+
+    update_control_parameters(r);
+}
+
+fn collapse_cwnd(r: &mut Recovery, now: Instant) {
+    if let Some(cc) = r.custom_cc.as_mut() {
+        cc.on_persistent_congestion(now);
+    }
+
+    update_control_parameters(r);
+}
+
+fn checkpoint(_r: &mut Recovery) {}
+
+fn rollback(r: &mut Recovery) -> bool {
+    if let Some(cc) = r.custom_cc.as_mut() {
+        cc.on_spurious_loss();
+    }
+
+    update_control_parameters(r);
+
+    true
+}
+
+fn has_custom_pacing() -> bool {
+    true
+}
+
+fn debug_fmt(r: &Recovery, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "custom_cc={{ cwnd={} }}", r.congestion_window)
+}
+
+// Applies the controller's congestion window and pacing rate to the path.
+pub fn update_control_parameters(r: &mut Recovery) {
+    let cc = match r.custom_cc.as_ref() {
+        Some(v) => v,
+
+        None => return,
+    };
+
+    r.congestion_window = cc.cwnd();
+
+    match cc.pacing_rate() {
+        Some(rate) => r.set_pacing_rate(rate),
+
+        None =>
+            if let Some(srtt) = r.smoothed_rtt {
+                let rate = PACING_MULTIPLIER * r.congestion_window as f64 /
+                    srtt.as_secs_f64();
+
+                r.set_pacing_rate(rate as u64);
+            },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::ranges;
+    use crate::recovery;
+    use crate::recovery::CongestionControl;
+    use crate::recovery::HandshakeStatus;
+    use crate::recovery::RttSample;
+    use crate::recovery::Sent;
+
+    #[derive(Default)]
+    struct Events {
+        init: Option<usize>,
+
+        sent: Vec<(usize, usize)>,
+
+        acked: Vec<(Vec<u64>, usize)>,
+
+        lost: Vec<(usize, usize)>,
+
+        rtt: Vec<RttSample>,
+
+        delivery_rate: Vec<DeliveryRateSample>,
+
+        max_datagram_size: Option<usize>,
+    }
+
+    // Grows the window by the acked bytes, and halves it on loss.
+    struct TestCC {
+        cwnd: usize,
+
+        pacing_rate: Option<u64>,
+
+        events: Arc<Mutex<Events>>,
+    }
+
+    impl CongestionControl for TestCC {
+        fn cwnd(&self) -> usize {
+            self.cwnd
+        }
+
+        fn pacing_rate(&self) -> Option<u64> {
+            self.pacing_rate
+        }
+
+        fn on_init(&mut self, max_datagram_size: usize) {
+            self.events.lock().unwrap().init = Some(max_datagram_size);
+        }
+
+        fn on_packet_sent(
+            &mut self, sent_bytes: usize, bytes_in_flight: usize, _now: Instant,
+        ) {
+            let mut events = self.events.lock().unwrap();
+            events.sent.push((sent_bytes, bytes_in_flight));
+        }
+
+        fn on_packets_acked(
+            &mut self, packets: &[AckedPacket], bytes_in_flight: usize,
+            _now: Instant,
+        ) {
+            self.cwnd += packets.iter().map(|p| p.size).sum::<usize>();
+
+            let pkt_nums = packets.iter().map(|p| p.pkt_num).collect();
+
+            let mut events = self.events.lock().unwrap();
+            events.acked.push((pkt_nums, bytes_in_flight));
+        }
+
+        fn on_packets_lost(
+            &mut self, lost_bytes: usize, _largest_lost_time_sent: Instant,
+            bytes_in_flight: usize, _now: Instant,
+        ) {
+            self.cwnd /= 2;
+
+            let mut events = self.events.lock().unwrap();
+            events.lost.push((lost_bytes, bytes_in_flight));
+        }
+
+        fn on_rtt_sample(&mut self, sample: &RttSample) {
+            self.events.lock().unwrap().rtt.push(*sample);
+        }
+
+        fn on_delivery_rate_sample(&mut self, sample: &DeliveryRateSample) {
+            self.events.lock().unwrap().delivery_rate.push(*sample);
+        }
+
+        fn on_max_datagram_size_update(&mut self, max_datagram_size: usize) {
+            self.events.lock().unwrap().max_datagram_size =
+                Some(max_datagram_size);
+        }
+    }
+
+    fn new_recovery(
+        cwnd: usize, pacing_rate: Option<u64>,
+    ) -> (Recovery, Arc<Mutex<Events>>) {
+        let events = Arc::new(Mutex::new(Events::default()));
+
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+
+        let cc_events = events.clone();
+        cfg.set_cc_factory(move || {
+            Box::new(TestCC {
+                cwnd,
+                pacing_rate,
+                events: cc_events.clone(),
+            })
+        });
+
+        let mut r = Recovery::new(&cfg);
+        r.on_init(Instant::now());
+
+        (r, events)
+    }
+
+    fn send_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        for pn in pkt_nums {
+            let pkt = Sent {
+                pkt_num: pn,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                pkt,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+    }
+
+    fn ack_packets(
+        r: &mut Recovery, pkt_nums: std::ops::Range<u64>, now: Instant,
+    ) {
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(pkt_nums);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                25,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn custom_init() {
+        let (r, events) = new_recovery(20000, None);
+
+        assert_eq!(events.lock().unwrap().init, Some(r.max_datagram_size));
+
+        assert_eq!(r.cwnd(), 20000);
+        assert_eq!(r.cwnd_available(), 20000);
+    }
+
+    #[test]
+    fn custom_send_and_ack() {
+        let (mut r, events) = new_recovery(20000, None);
+        let mut now = Instant::now();
+
+        send_packets(&mut r, 0..5, now);
+
+        assert_eq!(r.bytes_in_flight, 5000);
+        assert_eq!(r.cwnd_available(), 15000);
+
+        assert_eq!(events.lock().unwrap().sent, vec![
+            (1000, 1000),
+            (1000, 2000),
+            (1000, 3000),
+            (1000, 4000),
+            (1000, 5000),
+        ]);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..5, now);
+
+        assert_eq!(r.bytes_in_flight, 0);
+        assert_eq!(r.cwnd(), 25000);
+
+        let events = events.lock().unwrap();
+
+        assert_eq!(events.acked, vec![(vec![0, 1, 2, 3, 4], 0)]);
+
+        assert_eq!(events.rtt, vec![RttSample {
+            latest_rtt: Duration::from_millis(50),
+            smoothed_rtt: Duration::from_millis(50),
+            min_rtt: Duration::from_millis(50),
+            rttvar: Duration::from_millis(25),
+        }]);
+
+        assert_eq!(events.delivery_rate, vec![DeliveryRateSample {
+            delivery_rate: 100000,
+            delivered: 5000,
+            interval: Duration::from_millis(50),
+            rtt: Duration::from_millis(50),
+            is_app_limited: false,
+        }]);
+    }
+
+    #[test]
+    fn custom_loss() {
+        let (mut r, events) = new_recovery(20000, None);
+        let mut now = Instant::now();
+
+        send_packets(&mut r, 0..5, now);
+
+        now += Duration::from_millis(50);
+
+        // Acking the last packet causes the first two to be lost, due to the
+        // packet reordering threshold.
+        ack_packets(&mut r, 4..5, now);
+
+        assert_eq!(r.lost_count, 2);
+        assert_eq!(r.bytes_in_flight, 2000);
+
+        // The window is halved on loss, and then grows by the acked packet.
+        assert_eq!(r.cwnd(), 11000);
+
+        let events = events.lock().unwrap();
+
+        assert_eq!(events.lost, vec![(2000, 3000)]);
+        assert_eq!(events.acked, vec![(vec![4], 2000)]);
+    }
+
+    #[test]
+    fn custom_pacing() {
+        // Pacing rate derived from the window and smoothed RTT.
+        let (mut r, _) = new_recovery(20000, None);
+        let mut now = Instant::now();
+
+        assert_eq!(r.pacing_rate, 0);
+
+        send_packets(&mut r, 0..1, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..1, now);
+
+        assert_eq!(r.pacing_rate, (PACING_MULTIPLIER * 21000.0 / 0.05) as u64);
+
+        // Pacing rate set by the controller.
+        let (mut r, _) = new_recovery(20000, Some(1_000_000));
+        let mut now = Instant::now();
+
+        assert_eq!(r.pacing_rate, 1_000_000);
+
+        send_packets(&mut r, 0..1, now);
+
+        now += Duration::from_millis(50);
+
+        ack_packets(&mut r, 0..1, now);
+
+        assert_eq!(r.pacing_rate, 1_000_000);
+    }
+
+    #[test]
+    fn custom_max_datagram_size_update() {
+        let (mut r, events) = new_recovery(20000, None);
+
+        r.update_max_datagram_size(1000);
+
+        assert_eq!(events.lock().unwrap().max_datagram_size, Some(1000));
+
+        // The window is owned by the controller.
+        assert_eq!(r.cwnd(), 20000);
+        assert_eq!(r.max_datagram_size(), 1000);
+    }
+
+    #[test]
+    fn custom_overrides_algorithm() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_factory(|| {
+            Box::new(TestCC {
+                cwnd: 1234,
+                pacing_rate: None,
+                events: Default::default(),
+            })
+        });
+        cfg.set_cc_algorithm(recovery::CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+        r.on_init(Instant::now());
+
+        assert_eq!(r.cwnd(), 1234);
+    }
+}
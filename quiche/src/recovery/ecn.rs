@@ -0,0 +1,329 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! ECN validation.
+//!
+//! This implementation is based on the following RFC:
+//! <https://www.rfc-editor.org/rfc/rfc9000.html#section-13.4.2>
+
+use crate::frame::EcnCounts;
+use crate::packet;
+use crate::EcnCodepoint;
+
+/// Number of packets marked with ECT(0) while testing the path.
+pub const ECN_TESTING_COUNT: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EcnState {
+    /// ECT(0) marked packets are being sent to test the path.
+    Testing,
+
+    /// Marking is paused until the testing packets are acknowledged.
+    Unknown,
+
+    /// The path supports ECN.
+    Capable,
+
+    /// ECN validation failed, or ECN is disabled.
+    Failed,
+}
+
+pub struct Ecn {
+    state: EcnState,
+
+    testing_sent: usize,
+
+    testing_lost: usize,
+
+    peer_counts: [EcnCounts; packet::EPOCH_COUNT],
+}
+
+impl std::fmt::Debug for Ecn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "state={:?} ", self.state)?;
+        write!(f, "testing_sent={} ", self.testing_sent)?;
+        write!(f, "testing_lost={}", self.testing_lost)?;
+
+        Ok(())
+    }
+}
+
+impl Ecn {
+    pub fn new(enabled: bool) -> Self {
+        Ecn {
+            state: if enabled {
+                EcnState::Testing
+            } else {
+                EcnState::Failed
+            },
+
+            testing_sent: 0,
+
+            testing_lost: 0,
+
+            peer_counts: Default::default(),
+        }
+    }
+
+    /// Returns the codepoint new packets should be marked with.
+    pub fn codepoint(&self) -> EcnCodepoint {
+        match self.state {
+            EcnState::Testing | EcnState::Capable => EcnCodepoint::Ect0,
+
+            EcnState::Unknown | EcnState::Failed => EcnCodepoint::NotEct,
+        }
+    }
+
+    pub fn on_packet_sent(&mut self, ecn_marked: bool) {
+        if ecn_marked && self.state == EcnState::Testing {
+            self.testing_sent += 1;
+
+            if self.testing_sent >= ECN_TESTING_COUNT {
+                self.state = EcnState::Unknown;
+            }
+        }
+    }
+
+    /// Called when `lost` ECT(0) marked packets are declared lost.
+    pub fn on_packets_lost(&mut self, lost: usize) {
+        if self.state != EcnState::Testing && self.state != EcnState::Unknown {
+            return;
+        }
+
+        self.testing_lost += lost;
+
+        // All testing packets were lost, so the path might be dropping ECN
+        // marked packets.
+        if self.state == EcnState::Unknown &&
+            self.testing_lost >= self.testing_sent
+        {
+            self.state = EcnState::Failed;
+        }
+    }
+
+    /// Validates the ECN counts carried by an ACK frame, and returns the
+    /// increase of the ECN-CE count reported by the peer.
+    ///
+    /// `newly_acked_ect0` is the number of newly acknowledged packets that
+    /// were sent with the ECT(0) codepoint.
+    pub fn on_ack_received(
+        &mut self, counts: Option<&EcnCounts>, newly_acked_ect0: u64,
+        largest_acked_increased: bool, epoch: packet::Epoch,
+    ) -> u64 {
+        if self.state == EcnState::Failed {
+            return 0;
+        }
+
+        let counts = match counts {
+            Some(v) => v,
+
+            None => {
+                // The peer doesn't report ECN counts for marked packets.
+                if newly_acked_ect0 > 0 {
+                    self.state = EcnState::Failed;
+                }
+
+                return 0;
+            },
+        };
+
+        // Validating counts from reordered ACK frames can fail spuriously.
+        if !largest_acked_increased {
+            return 0;
+        }
+
+        let prev = &self.peer_counts[epoch];
+
+        if counts.ect0_count < prev.ect0_count ||
+            counts.ect1_count < prev.ect1_count ||
+            counts.ecn_ce_count < prev.ecn_ce_count
+        {
+            self.state = EcnState::Failed;
+            return 0;
+        }
+
+        let ect0_inc = counts.ect0_count - prev.ect0_count;
+        let ect1_inc = counts.ect1_count - prev.ect1_count;
+        let ce_inc = counts.ecn_ce_count - prev.ecn_ce_count;
+
+        // ECT(1) is never sent, and every newly acked ECT(0) packet must be
+        // accounted for as either ECT(0) or ECN-CE.
+        if ect1_inc > 0 || ect0_inc + ce_inc < newly_acked_ect0 {
+            self.state = EcnState::Failed;
+            return 0;
+        }
+
+        self.peer_counts[epoch] = *counts;
+
+        if newly_acked_ect0 > 0 &&
+            (self.state == EcnState::Testing ||
+                self.state == EcnState::Unknown)
+        {
+            self.state = EcnState::Capable;
+        }
+
+        ce_inc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(ect0: u64, ect1: u64, ce: u64) -> EcnCounts {
+        EcnCounts {
+            ect0_count: ect0,
+            ect1_count: ect1,
+            ecn_ce_count: ce,
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let ecn = Ecn::new(false);
+
+        assert_eq!(ecn.state, EcnState::Failed);
+        assert_eq!(ecn.codepoint(), EcnCodepoint::NotEct);
+    }
+
+    #[test]
+    fn testing() {
+        let mut ecn = Ecn::new(true);
+
+        for _ in 0..ECN_TESTING_COUNT - 1 {
+            assert_eq!(ecn.codepoint(), EcnCodepoint::Ect0);
+            ecn.on_packet_sent(true);
+        }
+
+        assert_eq!(ecn.state, EcnState::Testing);
+
+        ecn.on_packet_sent(true);
+
+        assert_eq!(ecn.state, EcnState::Unknown);
+        assert_eq!(ecn.codepoint(), EcnCodepoint::NotEct);
+
+        let epoch = packet::EPOCH_APPLICATION;
+
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(3, 0, 0)), 3, true, epoch),
+            0
+        );
+
+        assert_eq!(ecn.state, EcnState::Capable);
+        assert_eq!(ecn.codepoint(), EcnCodepoint::Ect0);
+
+        // CE marks are reported.
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(4, 0, 2)), 3, true, epoch),
+            2
+        );
+
+        assert_eq!(ecn.state, EcnState::Capable);
+    }
+
+    #[test]
+    fn testing_lost() {
+        let mut ecn = Ecn::new(true);
+
+        for _ in 0..ECN_TESTING_COUNT {
+            ecn.on_packet_sent(true);
+        }
+
+        ecn.on_packets_lost(ECN_TESTING_COUNT - 1);
+        assert_eq!(ecn.state, EcnState::Unknown);
+
+        ecn.on_packets_lost(1);
+        assert_eq!(ecn.state, EcnState::Failed);
+        assert_eq!(ecn.codepoint(), EcnCodepoint::NotEct);
+    }
+
+    #[test]
+    fn validation_failure() {
+        let epoch = packet::EPOCH_APPLICATION;
+
+        // Missing counts.
+        let mut ecn = Ecn::new(true);
+        ecn.on_packet_sent(true);
+        assert_eq!(ecn.on_ack_received(None, 1, true, epoch), 0);
+        assert_eq!(ecn.state, EcnState::Failed);
+
+        // Counts not covering the newly acked packets (e.g. bleached marks).
+        let mut ecn = Ecn::new(true);
+        ecn.on_packet_sent(true);
+        ecn.on_packet_sent(true);
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(1, 0, 0)), 2, true, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Failed);
+
+        // Unexpected ECT(1) marks.
+        let mut ecn = Ecn::new(true);
+        ecn.on_packet_sent(true);
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(1, 1, 0)), 1, true, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Failed);
+
+        // Decreasing counts.
+        let mut ecn = Ecn::new(true);
+        ecn.on_packet_sent(true);
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(2, 0, 0)), 1, true, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Capable);
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(1, 0, 0)), 0, true, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Failed);
+    }
+
+    #[test]
+    fn reordered_ack() {
+        let epoch = packet::EPOCH_APPLICATION;
+
+        let mut ecn = Ecn::new(true);
+        ecn.on_packet_sent(true);
+        ecn.on_packet_sent(true);
+
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(2, 0, 0)), 1, true, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Capable);
+
+        // An ACK that doesn't increase the largest acked packet is not used
+        // for validation.
+        assert_eq!(
+            ecn.on_ack_received(Some(&counts(1, 0, 0)), 1, false, epoch),
+            0
+        );
+        assert_eq!(ecn.state, EcnState::Capable);
+    }
+}
@@ -33,8 +33,10 @@ use std::time::Instant;
 
 use std::collections::VecDeque;
 
+use std::sync::Arc;
+
 use crate::Config;
-use crate::Error;
+use crate::EcnCodepoint;
 use crate::Result;
 
 use crate::frame;
@@ -52,6 +54,8 @@ const MAX_PACKET_THRESHOLD: u64 = 20;
 
 const INITIAL_TIME_THRESHOLD: f64 = 9.0 / 8.0;
 
+const MAX_TIME_THRESHOLD: f64 = 2.0;
+
 const GRANULARITY: Duration = Duration::from_millis(1);
 
 const INITIAL_RTT: Duration = Duration::from_millis(333);
@@ -71,6 +75,37 @@ const LOSS_REDUCTION_FACTOR: f64 = 0.5;
 
 const PACING_MULTIPLIER: f64 = 1.25;
 
+#[derive(Clone)]
+pub struct RecoveryConfig {
+    pub max_send_udp_payload_size: usize,
+    pub max_ack_delay: Duration,
+    cc_ops: &'static CongestionControlOps,
+    cc_factory: Option<Arc<CongestionControlFactory>>,
+    hystart: bool,
+    ecn: bool,
+    pmtud: bool,
+}
+
+impl RecoveryConfig {
+    pub fn from_config(config: &Config) -> Self {
+        let cc_ops = match config.cc_factory {
+            Some(_) => &custom::CUSTOM,
+
+            None => config.cc_algorithm.into(),
+        };
+
+        Self {
+            max_send_udp_payload_size: config.max_send_udp_payload_size,
+            max_ack_delay: Duration::ZERO,
+            cc_ops,
+            cc_factory: config.cc_factory.clone(),
+            hystart: config.hystart,
+            ecn: config.ecn,
+            pmtud: config.pmtud,
+        }
+    }
+}
+
 pub struct Recovery {
     loss_detection_timer: Option<Instant>,
 
@@ -80,8 +115,6 @@ pub struct Recovery {
 
     largest_acked_pkt: [u64; packet::EPOCH_COUNT],
 
-    largest_sent_pkt: [u64; packet::EPOCH_COUNT],
-
     latest_rtt: Duration,
 
     smoothed_rtt: Option<Duration>,
@@ -92,6 +125,8 @@ pub struct Recovery {
 
     min_rtt: Duration,
 
+    first_rtt_sample: Option<Instant>,
+
     pub max_ack_delay: Duration,
 
     loss_time: [Option<Instant>; packet::EPOCH_COUNT],
@@ -141,9 +176,22 @@ pub struct Recovery {
 
     cubic_state: cubic::State,
 
+    bbr_state: bbr::State,
+
+    bbr2_state: bbr2::State,
+
+    // Application-provided congestion controller.
+    custom_cc: Option<Box<dyn CongestionControl>>,
+
     // HyStart++.
     hystart: hystart::Hystart,
 
+    // ECN validation.
+    ecn: ecn::Ecn,
+
+    // Path MTU discovery.
+    pmtud: pmtud::Pmtud,
+
     // Pacing.
     pacing_rate: u64,
 
@@ -155,13 +203,35 @@ pub struct Recovery {
     #[cfg(feature = "qlog")]
     qlog_metrics: QlogMetrics,
 
+    // Recovery events waiting to be written to qlog.
+    #[cfg(feature = "qlog")]
+    qlog_events: Vec<EventData>,
+
+    #[cfg(feature = "qlog")]
+    qlog_congestion_state: &'static str,
+
     // The maximum size of a data aggregate scheduled and
     // transmitted together.
     send_quantum: usize,
 }
 
 impl Recovery {
-    pub fn new(config: &Config) -> Self {
+    pub fn new_with_config(
+        recovery_config: &RecoveryConfig, now: Instant,
+    ) -> Self {
+        let pmtud = pmtud::Pmtud::new(
+            recovery_config.pmtud,
+            recovery_config.max_send_udp_payload_size,
+        );
+
+        // When PMTU discovery is enabled, start from the base PLPMTU and let
+        // probing raise the datagram size.
+        let max_datagram_size = if pmtud.enabled() {
+            pmtud.current()
+        } else {
+            recovery_config.max_send_udp_payload_size
+        };
+
         Recovery {
             loss_detection_timer: None,
 
@@ -171,8 +241,6 @@ impl Recovery {
 
             largest_acked_pkt: [std::u64::MAX; packet::EPOCH_COUNT],
 
-            largest_sent_pkt: [0; packet::EPOCH_COUNT],
-
             latest_rtt: Duration::ZERO,
 
             // This field should be initialized to `INITIAL_RTT` for the initial
@@ -185,9 +253,11 @@ impl Recovery {
 
             min_rtt: Duration::ZERO,
 
+            first_rtt_sample: None,
+
             rttvar: INITIAL_RTT / 2,
 
-            max_ack_delay: Duration::ZERO,
+            max_ack_delay: recovery_config.max_ack_delay,
 
             loss_time: [None; packet::EPOCH_COUNT],
 
@@ -204,8 +274,7 @@ impl Recovery {
 
             in_flight_count: [0; packet::EPOCH_COUNT],
 
-            congestion_window: config.max_send_udp_payload_size *
-                INITIAL_WINDOW_PACKETS,
+            congestion_window: max_datagram_size * INITIAL_WINDOW_PACKETS,
 
             pkt_thresh: INITIAL_PACKET_THRESHOLD,
 
@@ -225,34 +294,57 @@ impl Recovery {
 
             congestion_recovery_start_time: None,
 
-            max_datagram_size: config.max_send_udp_payload_size,
+            max_datagram_size,
 
-            cc_ops: config.cc_algorithm.into(),
+            cc_ops: recovery_config.cc_ops,
 
             delivery_rate: delivery_rate::Rate::default(),
 
             cubic_state: cubic::State::default(),
 
+            bbr_state: bbr::State::default(),
+
+            bbr2_state: bbr2::State::default(),
+
+            custom_cc: recovery_config.cc_factory.as_ref().map(|f| f()),
+
             app_limited: false,
 
-            hystart: hystart::Hystart::new(config.hystart),
+            hystart: hystart::Hystart::new(recovery_config.hystart),
+
+            ecn: ecn::Ecn::new(recovery_config.ecn),
+
+            pmtud,
 
             pacing_rate: 0,
 
-            last_packet_scheduled_time: Instant::now(),
+            last_packet_scheduled_time: now,
 
             prr: prr::PRR::default(),
 
-            send_quantum: config.max_send_udp_payload_size *
-                INITIAL_WINDOW_PACKETS,
+            send_quantum: max_datagram_size * INITIAL_WINDOW_PACKETS,
 
             #[cfg(feature = "qlog")]
             qlog_metrics: QlogMetrics::default(),
+
+            #[cfg(feature = "qlog")]
+            qlog_events: Vec::new(),
+
+            #[cfg(feature = "qlog")]
+            qlog_congestion_state: QLOG_CC_SLOW_START,
         }
     }
 
-    pub fn on_init(&mut self) {
-        (self.cc_ops.on_init)(self);
+    #[cfg(test)]
+    pub fn new(config: &Config) -> Self {
+        Self::new_with_config(
+            &RecoveryConfig::from_config(config),
+            Instant::now(),
+        )
+    }
+
+    pub fn on_init(&mut self, now: Instant) {
+        (self.cc_ops.on_init)(self, now);
     }
 
     pub fn on_packet_sent(
@@ -264,12 +356,11 @@ impl Recovery {
         let sent_bytes = pkt.size;
         let pkt_num = pkt.pkt_num;
 
-        self.largest_sent_pkt[epoch] =
-            cmp::max(self.largest_sent_pkt[epoch], pkt_num);
-
         self.delivery_rate
             .on_packet_sent(&mut pkt, self.bytes_in_flight, now);
 
+        self.ecn.on_packet_sent(pkt.ecn_marked);
+
         if in_flight {
             if ack_eliciting {
                 self.time_of_last_sent_ack_eliciting_pkt[epoch] = Some(now);
@@ -329,6 +420,11 @@ impl Recovery {
         self.last_packet_scheduled_time
     }
 
+    /// Returns the ECN codepoint new packets should be marked with.
+    pub fn ecn_codepoint(&self) -> EcnCodepoint {
+        self.ecn.codepoint()
+    }
+
     fn schedule_next_packet(
         &mut self, epoch: packet::Epoch, now: Instant, packet_size: usize,
     ) {
@@ -354,23 +450,17 @@ impl Recovery {
         self.last_packet_scheduled_time = cmp::max(now, next_schedule_time);
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn on_ack_received(
         &mut self, ranges: &ranges::RangeSet, ack_delay: u64,
-        epoch: packet::Epoch, handshake_status: HandshakeStatus, now: Instant,
-        trace_id: &str,
+        ecn_counts: Option<&frame::EcnCounts>, epoch: packet::Epoch,
+        handshake_status: HandshakeStatus, now: Instant, trace_id: &str,
     ) -> Result<()> {
         let largest_acked = ranges.last().unwrap();
 
-        // If the largest packet number acked exceeds any packet number we have
-        // sent, then the ACK is obviously invalid, so there's no need to
-        // continue further.
-        if largest_acked > self.largest_sent_pkt[epoch] {
-            if cfg!(feature = "fuzzing") {
-                return Ok(());
-            }
-
-            return Err(Error::InvalidPacket);
-        }
+        let largest_acked_increased = self.largest_acked_pkt[epoch] ==
+            std::u64::MAX ||
+            largest_acked > self.largest_acked_pkt[epoch];
 
         if self.largest_acked_pkt[epoch] == std::u64::MAX {
             self.largest_acked_pkt[epoch] = largest_acked;
@@ -386,8 +476,12 @@ impl Recovery {
 
         let mut newly_acked = Vec::new();
 
+        let mut newly_acked_ect0 = 0;
+
         let mut undo_cwnd = false;
 
+        let mut pmtud_probe_acked = false;
+
         let max_rtt = cmp::max(self.latest_rtt, self.rtt());
 
         // Detect and mark acked packets, without removing them from the sent
@@ -408,6 +502,10 @@ impl Recovery {
             for unacked in unacked_iter {
                 unacked.time_acked = Some(now);
 
+                if unacked.ecn_marked {
+                    newly_acked_ect0 += 1;
+                }
+
                 // Check if acked packet was already declared lost.
                 if unacked.time_lost.is_some() {
                     // Calculate new packet reordering threshold.
@@ -422,11 +520,18 @@ impl Recovery {
 
                     // unacked.time_sent can be in the future due to
                     // pacing.
-                    if now.saturating_duration_since(unacked.time_sent) >
-                        loss_delay
-                    {
-                        // TODO: do time threshold update
-                        self.time_thresh = 5_f64 / 4_f64;
+                    let elapsed =
+                        now.saturating_duration_since(unacked.time_sent);
+
+                    // The packet would not have been declared lost with a
+                    // time threshold covering its actual delay, so raise the
+                    // threshold to tolerate the observed reordering.
+                    if elapsed > loss_delay && !max_rtt.is_zero() {
+                        let time_thresh =
+                            elapsed.as_secs_f64() / max_rtt.as_secs_f64();
+                        let time_thresh = time_thresh.min(MAX_TIME_THRESHOLD);
+
+                        self.time_thresh = self.time_thresh.max(time_thresh);
                     }
 
                     if unacked.in_flight {
@@ -441,6 +546,12 @@ impl Recovery {
                     has_ack_eliciting = true;
                 }
 
+                if self.pmtud.is_probe(unacked.pkt_num) {
+                    pmtud_probe_acked = true;
+                } else {
+                    self.pmtud.on_packet_acked(unacked.size);
+                }
+
                 largest_newly_acked_pkt_num = unacked.pkt_num;
                 largest_newly_acked_sent_time = unacked.time_sent;
 
@@ -478,6 +589,21 @@ impl Recovery {
             (self.cc_ops.rollback)(self);
         }
 
+        if pmtud_probe_acked {
+            let max_datagram_size = self.pmtud.on_probe_acked(now);
+
+            trace!("{} pmtu raised to {}", trace_id, max_datagram_size);
+
+            self.set_max_datagram_size(max_datagram_size);
+        }
+
+        let ce_count = self.ecn.on_ack_received(
+            ecn_counts,
+            newly_acked_ect0,
+            largest_acked_increased,
+            epoch,
+        );
+
         if newly_acked.is_empty() {
             return Ok(());
         }
@@ -501,8 +627,46 @@ impl Recovery {
         // packets list.
         self.detect_lost_packets(epoch, now, trace_id);
 
+        // An increase of the ECN-CE count reported by the peer is a
+        // congestion signal, just like a packet loss.
+        if ce_count > 0 {
+            trace!("{} ecn-ce count increased by {}", trace_id, ce_count);
+
+            self.congestion_event(0, largest_newly_acked_sent_time, epoch, now);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_congestion_state_updated(
+                QLOG_CC_RECOVERY,
+                Some(qlog::events::quic::CongestionStateUpdatedTrigger::Ecn),
+            );
+        }
+
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(EventData::PacketsAcked(
+            qlog::events::quic::PacketsAcked {
+                packet_number_space: Some(qlog_pkt_num_space(epoch)),
+                packet_numbers: Some(
+                    newly_acked.iter().map(|p| p.pkt_num).collect(),
+                ),
+            },
+        ));
+
         self.on_packets_acked(newly_acked, epoch, now);
 
+        #[cfg(feature = "qlog")]
+        {
+            let state =
+                if self.in_congestion_recovery(largest_newly_acked_sent_time) {
+                    QLOG_CC_RECOVERY
+                } else if self.cwnd() < self.ssthresh {
+                    QLOG_CC_SLOW_START
+                } else {
+                    QLOG_CC_CONGESTION_AVOIDANCE
+                };
+
+            self.qlog_congestion_state_updated(state, None);
+        }
+
         self.pto_count = 0;
 
         self.set_loss_detection_timer(handshake_status, now);
@@ -518,6 +682,16 @@ impl Recovery {
     ) {
         let (earliest_loss_time, epoch) = self.loss_time_and_space();
 
+        #[cfg(feature = "qlog")]
+        self.qlog_events.push(EventData::LossTimerUpdated(
+            qlog::events::quic::LossTimerUpdated {
+                timer_type: None,
+                packet_number_space: None,
+                event_type: qlog::events::quic::LossTimerEventType::Expired,
+                delta: None,
+            },
+        ));
+
         if earliest_loss_time.is_some() {
             // Time threshold loss detection.
             self.detect_lost_packets(epoch, now, trace_id);
@@ -636,9 +810,33 @@ impl Recovery {
     }
 
     pub fn update_max_datagram_size(&mut self, new_max_datagram_size: usize) {
-        let max_datagram_size =
-            cmp::min(self.max_datagram_size, new_max_datagram_size);
+        self.pmtud.set_max(new_max_datagram_size);
+
+        self.set_max_datagram_size(cmp::min(
+            self.max_datagram_size,
+            new_max_datagram_size,
+        ));
+    }
 
+    /// Returns the size of the next PMTU probe, if one should be sent.
+    pub fn pmtud_probe_size(&mut self, now: Instant) -> Option<usize> {
+        let size = self.pmtud.probe_size(now)?;
+
+        // Probes are not sent when they would exceed the congestion window.
+        if self.cwnd_available() < size {
+            return None;
+        }
+
+        Some(size)
+    }
+
+    pub fn on_pmtud_probe_sent(&mut self, pkt_num: u64, size: usize) {
+        self.pmtud.on_probe_sent(pkt_num, size);
+    }
+
+    // Sets the maximum datagram size, which, unlike
+    // `update_max_datagram_size()`, can also increase it.
+    fn set_max_datagram_size(&mut self, max_datagram_size: usize) {
         // Congestion Window is updated only when it's not updated already.
         if self.congestion_window ==
             self.max_datagram_size * INITIAL_WINDOW_PACKETS
@@ -647,6 +845,12 @@ impl Recovery {
         }
 
         self.max_datagram_size = max_datagram_size;
+
+        if let Some(cc) = self.custom_cc.as_mut() {
+            cc.on_max_datagram_size_update(max_datagram_size);
+
+            custom::update_control_parameters(self);
+        }
     }
 
     fn update_rtt(
@@ -657,6 +861,8 @@ impl Recovery {
         match self.smoothed_rtt {
             // First RTT sample.
             None => {
+                self.first_rtt_sample = Some(now);
+
                 self.min_rtt = self.minmax_filter.reset(now, latest_rtt);
 
                 self.smoothed_rtt = Some(latest_rtt);
@@ -685,6 +891,17 @@ impl Recovery {
                 );
             },
         }
+
+        let sample = RttSample {
+            latest_rtt,
+            smoothed_rtt: self.rtt(),
+            min_rtt: self.min_rtt,
+            rttvar: self.rttvar,
+        };
+
+        if let Some(cc) = self.custom_cc.as_mut() {
+            cc.on_rtt_sample(&sample);
+        }
     }
 
     fn loss_time_and_space(&self) -> (Option<Instant>, packet::Epoch) {
@@ -752,22 +969,32 @@ impl Recovery {
     fn set_loss_detection_timer(
         &mut self, handshake_status: HandshakeStatus, now: Instant,
     ) {
-        let (earliest_loss_time, _) = self.loss_time_and_space();
+        #[cfg(feature = "qlog")]
+        let old_timer = self.loss_detection_timer;
 
-        if earliest_loss_time.is_some() {
+        let (earliest_loss_time, epoch) = self.loss_time_and_space();
+
+        let is_pto = if earliest_loss_time.is_some() {
             // Time threshold loss detection.
             self.loss_detection_timer = earliest_loss_time;
-            return;
-        }
-
-        if self.bytes_in_flight == 0 && handshake_status.peer_verified_address {
+            Some((false, epoch))
+        } else if self.bytes_in_flight == 0 &&
+            handshake_status.peer_verified_address
+        {
             self.loss_detection_timer = None;
-            return;
-        }
+            None
+        } else {
+            // PTO timer.
+            let (timeout, epoch) = self.pto_time_and_space(handshake_status, now);
+            self.loss_detection_timer = timeout;
+            Some((true, epoch))
+        };
 
-        // PTO timer.
-        let (timeout, _) = self.pto_time_and_space(handshake_status, now);
-        self.loss_detection_timer = timeout;
+        #[cfg(feature = "qlog")]
+        self.qlog_loss_timer_updated(old_timer, is_pto, now);
+
+        #[cfg(not(feature = "qlog"))]
+        let _ = is_pto;
     }
 
     fn detect_lost_packets(
@@ -788,6 +1015,12 @@ impl Recovery {
 
         let mut lost_bytes = 0;
 
+        let mut lost_ecn_marked = 0;
+
+        let mut pmtud_probe_lost_bytes = 0;
+
+        let mut pmtud_black_hole = false;
+
         let mut largest_lost_pkt = None;
 
         let unacked_iter = self.sent[epoch]
@@ -802,10 +1035,63 @@ impl Recovery {
             if unacked.time_sent <= lost_send_time ||
                 largest_acked >= unacked.pkt_num + self.pkt_thresh
             {
+                #[cfg(feature = "qlog")]
+                {
+                    use qlog::events::quic::PacketLostTrigger;
+
+                    let trigger =
+                        if largest_acked >= unacked.pkt_num + self.pkt_thresh {
+                            PacketLostTrigger::ReorderingThreshold
+                        } else {
+                            PacketLostTrigger::TimeThreshold
+                        };
+
+                    let header = qlog::events::quic::PacketHeader::with_type(
+                        packet::Type::from_epoch(epoch).to_qlog(),
+                        unacked.pkt_num,
+                        None,
+                        None,
+                        None,
+                    );
+
+                    self.qlog_events.push(EventData::PacketLost(
+                        qlog::events::quic::PacketLost {
+                            header: Some(header),
+                            frames: None,
+                            trigger: Some(trigger),
+                        },
+                    ));
+                }
+
                 self.lost[epoch].append(&mut unacked.frames);
 
                 unacked.time_lost = Some(now);
 
+                if unacked.ecn_marked {
+                    lost_ecn_marked += 1;
+                }
+
+                // Probes are lost because of their size, so their loss is
+                // not a signal of congestion.
+                if self.pmtud.is_probe(unacked.pkt_num) {
+                    self.pmtud.on_probe_lost(now);
+
+                    if unacked.in_flight {
+                        pmtud_probe_lost_bytes += unacked.size;
+
+                        self.in_flight_count[epoch] =
+                            self.in_flight_count[epoch].saturating_sub(1);
+                    }
+
+                    trace!("{} pmtu probe {} lost", trace_id, unacked.pkt_num);
+
+                    continue;
+                }
+
+                if self.pmtud.on_packet_lost(unacked.size) {
+                    pmtud_black_hole = true;
+                }
+
                 if unacked.in_flight {
                     lost_bytes += unacked.size;
 
@@ -839,6 +1125,17 @@ impl Recovery {
 
         self.bytes_lost += lost_bytes as u64;
 
+        self.ecn.on_packets_lost(lost_ecn_marked);
+
+        self.bytes_in_flight =
+            self.bytes_in_flight.saturating_sub(pmtud_probe_lost_bytes);
+
+        if pmtud_black_hole {
+            trace!("{} pmtu black hole detected", trace_id);
+
+            self.set_max_datagram_size(self.pmtud.current());
+        }
+
         if let Some(pkt) = largest_lost_pkt {
             self.on_packets_lost(lost_bytes, &pkt, epoch, now);
         }
@@ -901,10 +1198,68 @@ impl Recovery {
         }
     }
 
-    fn in_persistent_congestion(&mut self, _largest_lost_pkt_num: u64) -> bool {
-        let _congestion_period = self.pto() * PERSISTENT_CONGESTION_THRESHOLD;
+    fn persistent_congestion_duration(&self) -> Duration {
+        (self.pto() + self.max_ack_delay) * PERSISTENT_CONGESTION_THRESHOLD
+    }
+
+    // Persistent congestion is established when two ack-eliciting packets
+    // were declared lost, none of the packets sent between them were acked
+    // and the time between them exceeds the persistent congestion duration.
+    //
+    // Only packets sent after the first RTT sample are considered, and only
+    // within a single packet number space.
+    fn in_persistent_congestion(
+        &self, epoch: packet::Epoch, now: Instant,
+    ) -> bool {
+        let first_rtt_sample = match self.first_rtt_sample {
+            Some(v) => v,
+
+            None => return false,
+        };
+
+        let congestion_period = self.persistent_congestion_duration();
+
+        // Send time of the first ack-eliciting packet of the current run of
+        // lost packets, and whether the run includes newly lost packets.
+        let mut run_start: Option<Instant> = None;
+        let mut newly_lost = false;
+
+        for pkt in self.sent[epoch]
+            .iter()
+            .filter(|p| p.time_sent >= first_rtt_sample)
+        {
+            let time_lost = match pkt.time_lost {
+                Some(v) if pkt.time_acked.is_none() => v,
+
+                // An acked or outstanding packet ends the run.
+                _ => {
+                    run_start = None;
+                    newly_lost = false;
+                    continue;
+                },
+            };
+
+            if time_lost == now {
+                newly_lost = true;
+            }
+
+            if !pkt.ack_eliciting {
+                continue;
+            }
+
+            match run_start {
+                None => run_start = Some(pkt.time_sent),
+
+                Some(start) =>
+                    if newly_lost &&
+                        pkt.time_sent.saturating_duration_since(start) >
+                            congestion_period
+                    {
+                        return true;
+                    },
+            }
+        }
 
-        // TODO: properly detect persistent congestion
         false
     }
 
@@ -916,8 +1271,19 @@ impl Recovery {
 
         self.congestion_event(lost_bytes, largest_lost_pkt.time_sent, epoch, now);
 
-        if self.in_persistent_congestion(largest_lost_pkt.pkt_num) {
-            self.collapse_cwnd();
+        #[cfg(feature = "qlog")]
+        self.qlog_congestion_state_updated(QLOG_CC_RECOVERY, None);
+
+        if self.in_persistent_congestion(epoch, now) {
+            self.collapse_cwnd(now);
+
+            #[cfg(feature = "qlog")]
+            self.qlog_congestion_state_updated(
+                QLOG_CC_SLOW_START,
+                Some(
+                    qlog::events::quic::CongestionStateUpdatedTrigger::PersistentCongestion,
+                ),
+            );
         }
     }
 
@@ -932,8 +1298,8 @@ impl Recovery {
         (self.cc_ops.congestion_event)(self, lost_bytes, time_sent, epoch, now);
     }
 
-    fn collapse_cwnd(&mut self) {
-        (self.cc_ops.collapse_cwnd)(self);
+    fn collapse_cwnd(&mut self, now: Instant) {
+        (self.cc_ops.collapse_cwnd)(self, now);
     }
 
     pub fn update_app_limited(&mut self, v: bool) {
@@ -963,6 +1329,71 @@ impl Recovery {
         self.qlog_metrics.maybe_update(qlog_metrics)
     }
 
+    /// Returns the recovery events generated since the last call.
+    #[cfg(feature = "qlog")]
+    pub fn take_qlog_events(&mut self) -> Vec<EventData> {
+        std::mem::take(&mut self.qlog_events)
+    }
+
+    #[cfg(feature = "qlog")]
+    fn qlog_congestion_state_updated(
+        &mut self, new: &'static str,
+        trigger: Option<qlog::events::quic::CongestionStateUpdatedTrigger>,
+    ) {
+        if self.qlog_congestion_state == new {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.qlog_congestion_state, new);
+
+        self.qlog_events.push(EventData::CongestionStateUpdated(
+            qlog::events::quic::CongestionStateUpdated {
+                old: Some(old.to_string()),
+                new: new.to_string(),
+                trigger,
+            },
+        ));
+    }
+
+    #[cfg(feature = "qlog")]
+    fn qlog_loss_timer_updated(
+        &mut self, old_timer: Option<Instant>,
+        is_pto: Option<(bool, packet::Epoch)>, now: Instant,
+    ) {
+        use qlog::events::quic::LossTimerEventType;
+        use qlog::events::quic::TimerType;
+
+        if old_timer == self.loss_detection_timer {
+            return;
+        }
+
+        let ev = match (self.loss_detection_timer, is_pto) {
+            (Some(timer), Some((is_pto, epoch))) =>
+                qlog::events::quic::LossTimerUpdated {
+                    timer_type: Some(if is_pto {
+                        TimerType::Pto
+                    } else {
+                        TimerType::Ack
+                    }),
+                    packet_number_space: Some(qlog_pkt_num_space(epoch)),
+                    event_type: LossTimerEventType::Set,
+                    delta: Some(
+                        timer.saturating_duration_since(now).as_secs_f32() *
+                            1000.0,
+                    ),
+                },
+
+            _ => qlog::events::quic::LossTimerUpdated {
+                timer_type: None,
+                packet_number_space: None,
+                event_type: LossTimerEventType::Cancelled,
+                delta: None,
+            },
+        };
+
+        self.qlog_events.push(EventData::LossTimerUpdated(ev));
+    }
+
     pub fn send_quantum(&self) -> usize {
         self.send_quantum
     }
@@ -979,6 +1410,10 @@ pub enum CongestionControlAlgorithm {
     Reno  = 0,
     /// CUBIC congestion control algorithm (default). `cubic` in a string form.
     CUBIC = 1,
+    /// BBR congestion control algorithm. `bbr` in a string form.
+    BBR   = 2,
+    /// BBRv2 congestion control algorithm. `bbr2` in a string form.
+    BBR2  = 3,
 }
 
 impl FromStr for CongestionControlAlgorithm {
@@ -991,6 +1426,8 @@ impl FromStr for CongestionControlAlgorithm {
         match name {
             "reno" => Ok(CongestionControlAlgorithm::Reno),
             "cubic" => Ok(CongestionControlAlgorithm::CUBIC),
+            "bbr" => Ok(CongestionControlAlgorithm::BBR),
+            "bbr2" => Ok(CongestionControlAlgorithm::BBR2),
 
             _ => Err(crate::Error::CongestionControl),
         }
@@ -998,7 +1435,7 @@ impl FromStr for CongestionControlAlgorithm {
 }
 
 pub struct CongestionControlOps {
-    pub on_init: fn(r: &mut Recovery),
+    pub on_init: fn(r: &mut Recovery, now: Instant),
 
     pub on_packet_sent: fn(r: &mut Recovery, sent_bytes: usize, now: Instant),
 
@@ -1017,7 +1454,7 @@ pub struct CongestionControlOps {
         now: Instant,
     ),
 
-    pub collapse_cwnd: fn(r: &mut Recovery),
+    pub collapse_cwnd: fn(r: &mut Recovery, now: Instant),
 
     pub checkpoint: fn(r: &mut Recovery),
 
@@ -1034,10 +1471,151 @@ impl From<CongestionControlAlgorithm> for &'static CongestionControlOps {
         match algo {
             CongestionControlAlgorithm::Reno => &reno::RENO,
             CongestionControlAlgorithm::CUBIC => &cubic::CUBIC,
+            CongestionControlAlgorithm::BBR => &bbr::BBR,
+            CongestionControlAlgorithm::BBR2 => &bbr2::BBR2,
         }
     }
 }
 
+/// A congestion controller implemented by the application.
+///
+/// Controllers are created by the factory registered with
+/// [`Config::set_cc_factory()`], once for each network path used by a
+/// connection, and are notified of the path's transmission events in order to
+/// update their congestion window and, optionally, their pacing rate.
+///
+/// All the `on_*` methods have a default empty implementation, so that
+/// controllers only need to implement the events they rely on.
+///
+/// [`Config::set_cc_factory()`]: struct.Config.html#method.set_cc_factory
+pub trait CongestionControl: Send + Sync {
+    /// Returns the current congestion window, in bytes.
+    fn cwnd(&self) -> usize;
+
+    /// Returns the current pacing rate, in bytes per second.
+    ///
+    /// When `None` is returned, packets are paced according to the congestion
+    /// window and the smoothed RTT.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+
+    /// Called once, before any packet is sent on the path.
+    fn on_init(&mut self, _max_datagram_size: usize) {}
+
+    /// Called when an in-flight packet of `sent_bytes` bytes is sent.
+    ///
+    /// `bytes_in_flight` already includes the sent packet.
+    fn on_packet_sent(
+        &mut self, _sent_bytes: usize, _bytes_in_flight: usize, _now: Instant,
+    ) {
+    }
+
+    /// Called when one or more in-flight packets are acknowledged.
+    ///
+    /// `bytes_in_flight` already excludes the acknowledged packets.
+    fn on_packets_acked(
+        &mut self, _packets: &[AckedPacket], _bytes_in_flight: usize,
+        _now: Instant,
+    ) {
+    }
+
+    /// Called when one or more in-flight packets, totalling `lost_bytes`
+    /// bytes, are declared lost.
+    ///
+    /// `largest_lost_time_sent` is the time the most recently sent of the lost
+    /// packets was sent at, and can be used to tell whether the losses belong
+    /// to a new congestion event. `bytes_in_flight` already excludes the lost
+    /// packets.
+    fn on_packets_lost(
+        &mut self, _lost_bytes: usize, _largest_lost_time_sent: Instant,
+        _bytes_in_flight: usize, _now: Instant,
+    ) {
+    }
+
+    /// Called when persistent congestion is detected on the path.
+    fn on_persistent_congestion(&mut self, _now: Instant) {}
+
+    /// Called when packets previously declared lost are acknowledged, so that
+    /// the reaction to their loss can be undone.
+    fn on_spurious_loss(&mut self) {}
+
+    /// Called when a new RTT sample is taken.
+    fn on_rtt_sample(&mut self, _sample: &RttSample) {}
+
+    /// Called when a new delivery rate sample is generated.
+    fn on_delivery_rate_sample(&mut self, _sample: &DeliveryRateSample) {}
+
+    /// Called when the maximum datagram size of the path changes.
+    fn on_max_datagram_size_update(&mut self, _max_datagram_size: usize) {}
+}
+
+/// A newly acknowledged packet, as seen by a [`CongestionControl`].
+///
+/// [`CongestionControl`]: trait.CongestionControl.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AckedPacket {
+    /// The packet number.
+    pub pkt_num: u64,
+
+    /// The size of the packet, in bytes.
+    pub size: usize,
+
+    /// The time the packet was sent at.
+    pub time_sent: Instant,
+
+    /// The time elapsed between sending the packet and receiving its
+    /// acknowledgement.
+    pub rtt: Duration,
+}
+
+/// An RTT sample, as seen by a [`CongestionControl`].
+///
+/// [`CongestionControl`]: trait.CongestionControl.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RttSample {
+    /// The most recent RTT sample.
+    pub latest_rtt: Duration,
+
+    /// The smoothed RTT, including the new sample.
+    pub smoothed_rtt: Duration,
+
+    /// The minimum RTT observed over a recent window.
+    pub min_rtt: Duration,
+
+    /// The RTT variation, including the new sample.
+    pub rttvar: Duration,
+}
+
+/// A delivery rate sample, as seen by a [`CongestionControl`].
+///
+/// [`CongestionControl`]: trait.CongestionControl.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeliveryRateSample {
+    /// The estimated delivery rate, in bytes per second.
+    pub delivery_rate: u64,
+
+    /// The number of bytes delivered over the sampling interval.
+    pub delivered: usize,
+
+    /// The sampling interval.
+    pub interval: Duration,
+
+    /// The RTT of the most recently sent packet in the sample.
+    pub rtt: Duration,
+
+    /// Whether the sample was taken while the application was not sending
+    /// enough data to fully use the congestion window, and so might
+    /// underestimate the available bandwidth.
+    pub is_app_limited: bool,
+}
+
+/// A function creating new [`CongestionControl`] instances.
+///
+/// [`CongestionControl`]: trait.CongestionControl.html
+pub type CongestionControlFactory =
+    dyn Fn() -> Box<dyn CongestionControl> + Send + Sync;
+
 impl std::fmt::Debug for Recovery {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self.loss_detection_timer {
@@ -1084,6 +1662,10 @@ impl std::fmt::Debug for Recovery {
             write!(f, "hystart={:?} ", self.hystart)?;
         }
 
+        if self.pmtud.enabled() {
+            write!(f, "pmtud={:?} ", self.pmtud)?;
+        }
+
         // CC-specific debug info
         (self.cc_ops.debug_fmt)(self, f)?;
 
@@ -1118,6 +1700,8 @@ pub struct Sent {
     pub is_app_limited: bool,
 
     pub has_data: bool,
+
+    pub ecn_marked: bool,
 }
 
 impl std::fmt::Debug for Sent {
@@ -1130,6 +1714,7 @@ impl std::fmt::Debug for Sent {
         write!(f, "first_sent_time={:?} ", self.first_sent_time.elapsed())?;
         write!(f, "is_app_limited={} ", self.is_app_limited)?;
         write!(f, "has_data={} ", self.has_data)?;
+        write!(f, "ecn_marked={} ", self.ecn_marked)?;
 
         Ok(())
     }
@@ -1184,6 +1769,29 @@ fn sub_abs(lhs: Duration, rhs: Duration) -> Duration {
     }
 }
 
+#[cfg(feature = "qlog")]
+const QLOG_CC_SLOW_START: &str = "slow_start";
+
+#[cfg(feature = "qlog")]
+const QLOG_CC_CONGESTION_AVOIDANCE: &str = "congestion_avoidance";
+
+#[cfg(feature = "qlog")]
+const QLOG_CC_RECOVERY: &str = "recovery";
+
+#[cfg(feature = "qlog")]
+fn qlog_pkt_num_space(
+    epoch: packet::Epoch,
+) -> qlog::events::quic::PacketNumberSpace {
+    match epoch {
+        packet::EPOCH_INITIAL => qlog::events::quic::PacketNumberSpace::Initial,
+
+        packet::EPOCH_HANDSHAKE =>
+            qlog::events::quic::PacketNumberSpace::Handshake,
+
+        _ => qlog::events::quic::PacketNumberSpace::ApplicationData,
+    }
+}
+
 // We don't need to log all qlog metrics every time there is a recovery event.
 // Instead, we can log only the MetricsUpdated event data fields that we care
 // about, only when they change. To support this, the QLogMetrics structure
@@ -1293,6 +1901,8 @@ impl QlogMetrics {
 mod tests {
     use super::*;
 
+    use crate::Error;
+
     #[test]
     fn lookup_cc_algo_ok() {
         let algo = CongestionControlAlgorithm::from_str("reno").unwrap();
@@ -1315,7 +1925,7 @@ mod tests {
         let mut r = Recovery::new(&cfg);
 
         // cwnd will be reset.
-        r.collapse_cwnd();
+        r.collapse_cwnd(Instant::now());
         assert_eq!(r.cwnd(), r.max_datagram_size * MINIMUM_WINDOW_PACKETS);
     }
 
@@ -1345,6 +1955,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1371,6 +1982,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1397,6 +2009,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1423,6 +2036,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1446,6 +2060,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1481,6 +2096,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1507,6 +2123,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1531,6 +2148,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1578,6 +2196,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1604,6 +2223,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1630,6 +2250,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1656,6 +2277,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1680,6 +2302,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1738,6 +2361,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1764,6 +2388,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1790,6 +2415,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1816,6 +2442,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1839,6 +2466,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1858,6 +2486,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 25,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1884,6 +2513,425 @@ mod tests {
         assert_eq!(r.sent[packet::EPOCH_APPLICATION].len(), 0);
     }
 
+    #[test]
+    fn time_threshold_on_reordering() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        // Start by sending a few packets.
+        for pkt_num in 0..4 {
+            let p = Sent {
+                pkt_num,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        assert_eq!(r.bytes_in_flight, 4000);
+
+        // Wait for 10ms.
+        now += Duration::from_millis(10);
+
+        // The first packet is declared lost by packet threshold.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(1..4);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.lost_count, 1);
+        assert_eq!(r.time_thresh, INITIAL_TIME_THRESHOLD);
+
+        // The first packet is acked after 1.5 RTT.
+        now += Duration::from_millis(5);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        // Spurious loss.
+        assert_eq!(r.lost_spurious_count, 1);
+
+        // Both reordering thresholds were increased.
+        assert_eq!(r.pkt_thresh, 4);
+        assert_eq!(r.time_thresh, 1.5);
+
+        // Packets sent now are declared lost after the new time threshold.
+        let p = Sent {
+            pkt_num: 4,
+            frames: vec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            ecn_marked: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::EPOCH_APPLICATION,
+            HandshakeStatus::default(),
+            now,
+            "",
+        );
+
+        let p = Sent {
+            pkt_num: 5,
+            frames: vec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            ecn_marked: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::EPOCH_APPLICATION,
+            HandshakeStatus::default(),
+            now,
+            "",
+        );
+
+        let sent_time = now;
+
+        now += Duration::from_millis(10);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(5..6);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            r.loss_detection_timer(),
+            Some(sent_time + Duration::from_millis(15))
+        );
+
+        // The time threshold is capped.
+        r.on_loss_detection_timeout(
+            HandshakeStatus::default(),
+            sent_time + Duration::from_millis(15),
+            "",
+        );
+        assert_eq!(r.lost_count, 2);
+
+        now = sent_time + Duration::from_millis(100);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(4..5);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.lost_spurious_count, 2);
+        assert_eq!(r.time_thresh, MAX_TIME_THRESHOLD);
+    }
+
+    #[test]
+    fn persistent_congestion() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        // Get a first RTT sample.
+        let p = Sent {
+            pkt_num: 0,
+            frames: vec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            ecn_marked: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::EPOCH_APPLICATION,
+            HandshakeStatus::default(),
+            now,
+            "",
+        );
+
+        now += Duration::from_millis(10);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        let congestion_period = r.persistent_congestion_duration();
+
+        // Send packets spread over more than the persistent congestion
+        // duration, followed by a few more that will be acked.
+        for pkt_num in 1..7 {
+            if pkt_num > 1 && pkt_num <= 3 {
+                now += congestion_period / 2 + Duration::from_millis(1);
+            }
+
+            let p = Sent {
+                pkt_num,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        now += Duration::from_millis(10);
+
+        // Only the last packet is acked, so the earlier ones are lost.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(6..7);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.lost_count, 3);
+
+        // The congestion window is collapsed.
+        assert_eq!(r.cwnd(), r.max_datagram_size * MINIMUM_WINDOW_PACKETS);
+    }
+
+    #[test]
+    fn persistent_congestion_interrupted() {
+        let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
+        cfg.set_cc_algorithm(CongestionControlAlgorithm::Reno);
+
+        let mut r = Recovery::new(&cfg);
+
+        let mut now = Instant::now();
+
+        // Get a first RTT sample.
+        let p = Sent {
+            pkt_num: 0,
+            frames: vec![],
+            time_sent: now,
+            time_acked: None,
+            time_lost: None,
+            size: 1000,
+            ack_eliciting: true,
+            in_flight: true,
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            is_app_limited: false,
+            has_data: false,
+            ecn_marked: false,
+        };
+
+        r.on_packet_sent(
+            p,
+            packet::EPOCH_APPLICATION,
+            HandshakeStatus::default(),
+            now,
+            "",
+        );
+
+        now += Duration::from_millis(10);
+
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(0..1);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        let congestion_period = r.persistent_congestion_duration();
+
+        for pkt_num in 1..7 {
+            if pkt_num > 1 && pkt_num <= 3 {
+                now += congestion_period / 2 + Duration::from_millis(1);
+            }
+
+            let p = Sent {
+                pkt_num,
+                frames: vec![],
+                time_sent: now,
+                time_acked: None,
+                time_lost: None,
+                size: 1000,
+                ack_eliciting: true,
+                in_flight: true,
+                delivered: 0,
+                delivered_time: now,
+                first_sent_time: now,
+                is_app_limited: false,
+                has_data: false,
+                ecn_marked: false,
+            };
+
+            r.on_packet_sent(
+                p,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                "",
+            );
+        }
+
+        now += Duration::from_millis(10);
+
+        // The packet sent in the middle of the period is acked.
+        let mut acked = ranges::RangeSet::default();
+        acked.insert(2..3);
+        acked.insert(6..7);
+
+        assert_eq!(
+            r.on_ack_received(
+                &acked,
+                0,
+                None,
+                packet::EPOCH_APPLICATION,
+                HandshakeStatus::default(),
+                now,
+                ""
+            ),
+            Ok(())
+        );
+
+        assert_eq!(r.lost_count, 2);
+
+        // The congestion window is only reduced.
+        assert_eq!(r.cwnd(), r.max_datagram_size * INITIAL_WINDOW_PACKETS / 2);
+    }
+
     #[test]
     fn pacing() {
         let mut cfg = crate::Config::new(crate::PROTOCOL_VERSION).unwrap();
@@ -1910,6 +2958,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1937,6 +2986,7 @@ mod tests {
             r.on_ack_received(
                 &acked,
                 10,
+                None,
                 packet::EPOCH_APPLICATION,
                 HandshakeStatus::default(),
                 now,
@@ -1964,6 +3014,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -1995,6 +3046,7 @@ mod tests {
             first_sent_time: now,
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         r.on_packet_sent(
@@ -2020,8 +3072,13 @@ mod tests {
     }
 }
 
+mod bbr;
+mod bbr2;
 mod cubic;
+mod custom;
 mod delivery_rate;
+mod ecn;
 mod hystart;
+mod pmtud;
 mod prr;
 mod reno;
@@ -0,0 +1,375 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Datagram Packetization Layer Path MTU Discovery.
+//!
+//! This implementation is based on the following RFC:
+//! <https://www.rfc-editor.org/rfc/rfc8899.html>
+
+use std::cmp;
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// The base PLPMTU, which is the minimum datagram size supported by QUIC.
+pub const BASE_PLPMTU: usize = 1200;
+
+/// The maximum PLPMTU, so that the packet length can always be encoded with a
+/// 2-byte varint.
+const MAX_PLPMTU: usize = 16383;
+
+/// Number of consecutive lost probes after which a probe size is considered
+/// unsupported by the path.
+const MAX_PROBES: usize = 3;
+
+/// The search completes once the distance between the largest acknowledged
+/// size and the smallest failed size is below this value.
+const SEARCH_GRANULARITY: usize = 16;
+
+/// Time after which a completed search is restarted, in order to detect an
+/// increase of the path MTU.
+const RAISE_TIMER: Duration = Duration::from_secs(600);
+
+/// Number of consecutive lost packets larger than the base PLPMTU after which
+/// a black hole is detected.
+const BLACK_HOLE_THRESHOLD: usize = 6;
+
+pub struct Pmtud {
+    enabled: bool,
+
+    /// The largest datagram size validated on the path.
+    current: usize,
+
+    /// The upper bound of the search.
+    max: usize,
+
+    /// The smallest probe size that failed to be delivered.
+    failed: usize,
+
+    /// The packet number and size of the probe in flight.
+    probe: Option<(u64, usize)>,
+
+    probes_lost: usize,
+
+    /// Time after which the search is restarted.
+    raise_time: Option<Instant>,
+
+    black_hole_lost: usize,
+}
+
+impl std::fmt::Debug for Pmtud {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "current={} ", self.current)?;
+        write!(f, "max={} ", self.max)?;
+        write!(f, "failed={} ", self.failed)?;
+        write!(f, "probe={:?}", self.probe)?;
+
+        Ok(())
+    }
+}
+
+impl Pmtud {
+    pub fn new(enabled: bool, max: usize) -> Self {
+        let max = cmp::min(max, MAX_PLPMTU);
+
+        Pmtud {
+            enabled,
+
+            current: cmp::min(BASE_PLPMTU, max),
+
+            max,
+
+            failed: max + 1,
+
+            probe: None,
+
+            probes_lost: 0,
+
+            raise_time: None,
+
+            black_hole_lost: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the largest datagram size validated on the path.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Lowers the upper bound of the search, e.g. to the peer's
+    /// `max_udp_payload_size` transport parameter.
+    pub fn set_max(&mut self, max: usize) {
+        self.max = cmp::min(self.max, max);
+        self.failed = cmp::min(self.failed, self.max + 1);
+        self.current = cmp::min(self.current, self.max);
+    }
+
+    /// Returns the size of the next probe, if one should be sent.
+    pub fn probe_size(&mut self, now: Instant) -> Option<usize> {
+        if !self.enabled || self.probe.is_some() {
+            return None;
+        }
+
+        if let Some(raise_time) = self.raise_time {
+            if now < raise_time {
+                return None;
+            }
+
+            // Restart the search from the current size.
+            self.raise_time = None;
+            self.failed = self.max + 1;
+        }
+
+        if self.search_done() {
+            return None;
+        }
+
+        // Optimistically probe for the upper bound first, as it's likely to
+        // be supported on paths where it has been configured explicitly.
+        if self.failed > self.max {
+            return Some(self.max);
+        }
+
+        Some((self.current + self.failed) / 2)
+    }
+
+    pub fn on_probe_sent(&mut self, pkt_num: u64, size: usize) {
+        self.probe = Some((pkt_num, size));
+    }
+
+    pub fn is_probe(&self, pkt_num: u64) -> bool {
+        matches!(self.probe, Some((probe_pkt_num, _)) if probe_pkt_num == pkt_num)
+    }
+
+    /// Called when the probe is acknowledged. Returns the new PLPMTU.
+    pub fn on_probe_acked(&mut self, now: Instant) -> usize {
+        if let Some((_, size)) = self.probe.take() {
+            self.current = cmp::max(self.current, size);
+            self.probes_lost = 0;
+
+            self.on_search_step(now);
+        }
+
+        self.current
+    }
+
+    /// Called when the probe is declared lost.
+    pub fn on_probe_lost(&mut self, now: Instant) {
+        if let Some((_, size)) = self.probe.take() {
+            self.probes_lost += 1;
+
+            if self.probes_lost >= MAX_PROBES {
+                self.failed = cmp::min(self.failed, size);
+                self.probes_lost = 0;
+            }
+
+            self.on_search_step(now);
+        }
+    }
+
+    /// Called when a packet that is not a probe is acknowledged.
+    pub fn on_packet_acked(&mut self, size: usize) {
+        if size > BASE_PLPMTU {
+            self.black_hole_lost = 0;
+        }
+    }
+
+    /// Called when a packet that is not a probe is declared lost. Returns
+    /// true when a black hole is detected, in which case the PLPMTU falls
+    /// back to the base PLPMTU.
+    pub fn on_packet_lost(&mut self, size: usize) -> bool {
+        if !self.enabled || size <= BASE_PLPMTU {
+            return false;
+        }
+
+        self.black_hole_lost += 1;
+
+        if self.black_hole_lost < BLACK_HOLE_THRESHOLD ||
+            self.current <= BASE_PLPMTU
+        {
+            return false;
+        }
+
+        // Search again below the size that stopped working.
+        self.failed = self.current;
+        self.current = cmp::min(BASE_PLPMTU, self.max);
+        self.probe = None;
+        self.probes_lost = 0;
+        self.raise_time = None;
+        self.black_hole_lost = 0;
+
+        true
+    }
+
+    fn search_done(&self) -> bool {
+        self.failed.saturating_sub(self.current) <= SEARCH_GRANULARITY
+    }
+
+    fn on_search_step(&mut self, now: Instant) {
+        if self.search_done() && self.current < self.max {
+            self.raise_time = Some(now + RAISE_TIMER);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let mut pmtud = Pmtud::new(false, 1500);
+
+        assert_eq!(pmtud.current(), BASE_PLPMTU);
+        assert_eq!(pmtud.probe_size(Instant::now()), None);
+        assert!(!pmtud.on_packet_lost(1500));
+    }
+
+    #[test]
+    fn probe_max() {
+        let now = Instant::now();
+
+        let mut pmtud = Pmtud::new(true, 1500);
+
+        assert_eq!(pmtud.probe_size(now), Some(1500));
+
+        pmtud.on_probe_sent(5, 1500);
+        assert!(pmtud.is_probe(5));
+        assert!(!pmtud.is_probe(6));
+
+        // Only one probe at a time.
+        assert_eq!(pmtud.probe_size(now), None);
+
+        assert_eq!(pmtud.on_probe_acked(now), 1500);
+        assert!(!pmtud.is_probe(5));
+
+        // Search is complete.
+        assert_eq!(pmtud.probe_size(now), None);
+        assert_eq!(pmtud.probe_size(now + RAISE_TIMER), None);
+    }
+
+    #[test]
+    fn search() {
+        let mut now = Instant::now();
+
+        // The path only supports datagrams up to 1400 bytes.
+        let path_mtu = 1400;
+
+        let mut pmtud = Pmtud::new(true, 9000);
+        let mut pkt_num = 0;
+
+        while let Some(size) = pmtud.probe_size(now) {
+            pmtud.on_probe_sent(pkt_num, size);
+            pkt_num += 1;
+
+            if size <= path_mtu {
+                pmtud.on_probe_acked(now);
+            } else {
+                pmtud.on_probe_lost(now);
+            }
+        }
+
+        assert!(pmtud.current() <= path_mtu);
+        assert!(pmtud.current() > path_mtu - SEARCH_GRANULARITY);
+
+        // The search is restarted after the raise timer expires.
+        now += RAISE_TIMER;
+        assert_eq!(pmtud.probe_size(now), Some(9000));
+    }
+
+    #[test]
+    fn probe_lost() {
+        let now = Instant::now();
+
+        let mut pmtud = Pmtud::new(true, 1500);
+
+        // The same size is probed again until MAX_PROBES probes are lost.
+        for i in 0..MAX_PROBES {
+            assert_eq!(pmtud.probe_size(now), Some(1500));
+
+            pmtud.on_probe_sent(i as u64, 1500);
+            pmtud.on_probe_lost(now);
+        }
+
+        assert_eq!(pmtud.probe_size(now), Some((BASE_PLPMTU + 1500) / 2));
+        assert_eq!(pmtud.current(), BASE_PLPMTU);
+    }
+
+    #[test]
+    fn set_max() {
+        let now = Instant::now();
+
+        let mut pmtud = Pmtud::new(true, 9000);
+
+        pmtud.set_max(1350);
+        assert_eq!(pmtud.probe_size(now), Some(1350));
+
+        pmtud.on_probe_sent(0, 1350);
+        assert_eq!(pmtud.on_probe_acked(now), 1350);
+
+        pmtud.set_max(1300);
+        assert_eq!(pmtud.current(), 1300);
+        assert_eq!(pmtud.probe_size(now), None);
+    }
+
+    #[test]
+    fn black_hole() {
+        let now = Instant::now();
+
+        let mut pmtud = Pmtud::new(true, 1500);
+
+        pmtud.on_probe_sent(0, 1500);
+        assert_eq!(pmtud.on_probe_acked(now), 1500);
+
+        // Losses of small packets are ignored.
+        for _ in 0..BLACK_HOLE_THRESHOLD {
+            assert!(!pmtud.on_packet_lost(BASE_PLPMTU));
+        }
+
+        // Acknowledging a large packet resets the detection.
+        for _ in 0..BLACK_HOLE_THRESHOLD - 1 {
+            assert!(!pmtud.on_packet_lost(1500));
+        }
+
+        pmtud.on_packet_acked(1500);
+
+        for _ in 0..BLACK_HOLE_THRESHOLD - 1 {
+            assert!(!pmtud.on_packet_lost(1500));
+        }
+
+        assert_eq!(pmtud.current(), 1500);
+
+        assert!(pmtud.on_packet_lost(1500));
+        assert_eq!(pmtud.current(), BASE_PLPMTU);
+
+        // The search restarts below the size that stopped working.
+        assert_eq!(pmtud.probe_size(now), Some((BASE_PLPMTU + 1500) / 2));
+    }
+}
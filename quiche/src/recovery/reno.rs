@@ -50,7 +50,7 @@ pub static RENO: CongestionControlOps = CongestionControlOps {
     debug_fmt,
 };
 
-pub fn on_init(_r: &mut Recovery) {}
+pub fn on_init(_r: &mut Recovery, _now: Instant) {}
 
 pub fn on_packet_sent(r: &mut Recovery, sent_bytes: usize, _now: Instant) {
     r.bytes_in_flight += sent_bytes;
@@ -132,7 +132,7 @@ fn congestion_event(
     }
 }
 
-pub fn collapse_cwnd(r: &mut Recovery) {
+pub fn collapse_cwnd(r: &mut Recovery, _now: Instant) {
     r.congestion_window = r.max_datagram_size * recovery::MINIMUM_WINDOW_PACKETS;
     r.bytes_acked_sl = 0;
     r.bytes_acked_ca = 0;
@@ -210,6 +210,7 @@ mod tests {
             first_sent_time: std::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -259,6 +260,7 @@ mod tests {
             first_sent_time: std::time::Instant::now(),
             is_app_limited: false,
             has_data: false,
+            ecn_marked: false,
         };
 
         // Send initcwnd full MSS packets to become no longer app limited
@@ -46,6 +46,12 @@ use crate::ranges;
 
 const DEFAULT_URGENCY: u8 = 127;
 
+const DEFAULT_WEIGHT: u8 = 16;
+
+// The number of bytes a stream is allowed to send in each round of the deficit
+// round-robin scheduler, per unit of weight.
+const DRR_QUANTUM: i64 = 256;
+
 #[cfg(test)]
 const SEND_BUFFER_SIZE: usize = 5;
 
@@ -91,6 +97,36 @@ type BuildStreamIdHasher = std::hash::BuildHasherDefault<StreamIdHasher>;
 pub type StreamIdHashMap<V> = HashMap<u64, V, BuildStreamIdHasher>;
 pub type StreamIdHashSet = HashSet<u64, BuildStreamIdHasher>;
 
+/// Available stream scheduling algorithms.
+///
+/// This enum provides the list of algorithms that can be used to decide which
+/// stream's data is sent next.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum StreamScheduler {
+    /// Strict priority scheduling (default).
+    ///
+    /// Streams with lower urgency are always served first. Streams with the
+    /// same urgency are served in order of their stream IDs if they are not
+    /// incremental, or in a round-robin fashion otherwise.
+    #[default]
+    Strict             = 0,
+
+    /// Weighted round-robin scheduling.
+    ///
+    /// Streams are served in a round-robin fashion regardless of their
+    /// urgency, and in each round a stream can send a number of packets equal
+    /// to its weight.
+    WeightedRoundRobin = 1,
+
+    /// Deficit round-robin scheduling.
+    ///
+    /// Streams are served in a round-robin fashion regardless of their
+    /// urgency, and in each round a stream can send a number of bytes
+    /// proportional to its weight.
+    DeficitRoundRobin  = 2,
+}
+
 /// Keeps track of QUIC streams and enforces stream limits.
 #[derive(Default)]
 pub struct StreamMap {
@@ -143,6 +179,16 @@ pub struct StreamMap {
     /// round-robin fashion after all non-incremental streams have been flushed.
     flushable: BTreeMap<u8, (BinaryHeap<std::cmp::Reverse<u64>>, VecDeque<u64>)>,
 
+    /// Queue of stream IDs corresponding to flushable streams, used instead of
+    /// `flushable` by the round-robin schedulers.
+    ///
+    /// The stream at the front of the queue is the one currently being served,
+    /// and it stays there until it runs out of credit for the current round.
+    fair_flushable: VecDeque<u64>,
+
+    /// The algorithm used to schedule flushable streams.
+    scheduler: StreamScheduler,
+
     /// Set of stream IDs corresponding to streams that have outstanding data
     /// to read. This is used to generate a `StreamIter` of streams without
     /// having to iterate over the full list of streams.
@@ -182,6 +228,7 @@ pub struct StreamMap {
 impl StreamMap {
     pub fn new(
         max_streams_bidi: u64, max_streams_uni: u64, max_stream_window: u64,
+        scheduler: StreamScheduler,
     ) -> StreamMap {
         StreamMap {
             local_max_streams_bidi: max_streams_bidi,
@@ -192,6 +239,8 @@ impl StreamMap {
 
             max_stream_window,
 
+            scheduler,
+
             ..StreamMap::default()
         }
     }
@@ -328,6 +377,22 @@ impl StreamMap {
     /// unfairly scheduled more often than other streams, and might also cause
     /// spurious cycles through the queue, so it should be avoided.
     pub fn push_flushable(&mut self, stream_id: u64, urgency: u8, incr: bool) {
+        if self.scheduler != StreamScheduler::Strict {
+            // The stream starts a new round from scratch, any credit or debt
+            // left from when it was last served is discarded.
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.deficit = 0;
+            }
+
+            self.fair_flushable.push_back(stream_id);
+
+            return;
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.flushable_queue = Some((urgency, incr));
+        }
+
         // Push the element to the back of the queue corresponding to the given
         // urgency. If the queue doesn't exist yet, create it first.
         let queues = self
@@ -350,6 +415,12 @@ impl StreamMap {
     /// Note that if the stream is still flushable after sending some of its
     /// outstanding data, it needs to be added back to the queue.
     pub fn pop_flushable(&mut self) -> Option<u64> {
+        if self.scheduler != StreamScheduler::Strict {
+            return self.pop_fair_flushable();
+        }
+
+        let streams = &mut self.streams;
+
         // Remove the first element from the queue corresponding to the lowest
         // urgency that has elements.
         let (node, clear) =
@@ -360,6 +431,14 @@ impl StreamMap {
                     queues.1.pop_front()
                 };
 
+                if let Some(stream) = node.and_then(|id| streams.get_mut(&id)) {
+                    stream.flushable_queue = None;
+                }
+
+                // Entries left behind by the stream we just popped are stale
+                // now, and might have surfaced at the top of the heap.
+                Self::skip_stale_flushable(streams, *urgency, &mut queues.0);
+
                 let clear = if queues.0.is_empty() && queues.1.is_empty() {
                     Some(*urgency)
                 } else {
@@ -381,6 +460,158 @@ impl StreamMap {
         node
     }
 
+    /// Drops the entries at the top of the non-incremental queue with the
+    /// given urgency that belong to streams that have since moved to another
+    /// queue, or were already popped.
+    ///
+    /// This keeps the top of each heap a stream that is actually queued there,
+    /// without having to search the heap when a stream's priority changes.
+    fn skip_stale_flushable(
+        streams: &StreamIdHashMap<Stream>, urgency: u8,
+        heap: &mut BinaryHeap<std::cmp::Reverse<u64>>,
+    ) {
+        while let Some(std::cmp::Reverse(stream_id)) = heap.peek() {
+            match streams.get(stream_id) {
+                Some(stream)
+                    if stream.flushable_queue != Some((urgency, false)) =>
+                {
+                    heap.pop();
+                },
+
+                // Let the caller deal with streams that have been collected.
+                _ => break,
+            }
+        }
+    }
+
+    /// Removes and returns the next stream ID to be served by the round-robin
+    /// schedulers.
+    fn pop_fair_flushable(&mut self) -> Option<u64> {
+        loop {
+            let stream_id = self.fair_flushable.pop_front()?;
+
+            let stream = match self.streams.get_mut(&stream_id) {
+                Some(v) => v,
+
+                // Let the caller deal with streams that have been collected.
+                None => return Some(stream_id),
+            };
+
+            // The stream still has credit left from its current turn.
+            if stream.deficit > 0 {
+                return Some(stream_id);
+            }
+
+            // Otherwise this is the start of the stream's turn in a new round,
+            // so give it more credit, based on its weight.
+            let quantum = match self.scheduler {
+                StreamScheduler::DeficitRoundRobin =>
+                    i64::from(stream.weight) * DRR_QUANTUM,
+
+                _ => i64::from(stream.weight),
+            };
+
+            stream.deficit += quantum;
+
+            if stream.deficit > 0 {
+                return Some(stream_id);
+            }
+
+            // The stream is still paying back data sent in excess in previous
+            // rounds, so skip it for this round.
+            self.fair_flushable.push_back(stream_id);
+        }
+    }
+
+    /// Pushes the stream ID back to the flushable streams queue after `sent`
+    /// bytes of its data were sent.
+    ///
+    /// This should be used instead of `push_flushable()` for streams that were
+    /// returned by `pop_flushable()` and are still flushable, so that
+    /// round-robin schedulers can keep serving the stream until it runs out of
+    /// credit for the current round.
+    pub fn requeue_flushable(
+        &mut self, stream_id: u64, urgency: u8, incr: bool, sent: usize,
+    ) {
+        let used = match self.scheduler {
+            StreamScheduler::Strict =>
+                return self.push_flushable(stream_id, urgency, incr),
+
+            StreamScheduler::WeightedRoundRobin => 1,
+
+            StreamScheduler::DeficitRoundRobin => sent as i64,
+        };
+
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(v) => v,
+
+            None => return,
+        };
+
+        stream.deficit -= used;
+
+        if stream.deficit > 0 {
+            self.fair_flushable.push_front(stream_id);
+        } else {
+            self.fair_flushable.push_back(stream_id);
+        }
+    }
+
+    /// Moves the stream ID to the flushable streams queue corresponding to the
+    /// new priority, if the stream is currently queued.
+    ///
+    /// This should be called every time a stream's urgency or incremental flag
+    /// changes, so that the scheduling order reflects the new priority. If the
+    /// stream was not queued, this does nothing.
+    ///
+    /// The stream's entry in a non-incremental queue is left in place, and is
+    /// skipped once it reaches the top of the queue.
+    pub fn update_flushable_priority(
+        &mut self, stream_id: u64, urgency: u8, incr: bool,
+    ) {
+        // Round-robin schedulers don't take the priority into account.
+        if self.scheduler != StreamScheduler::Strict {
+            return;
+        }
+
+        let (old_urgency, old_incr) =
+            match self.streams.get(&stream_id).and_then(|s| s.flushable_queue) {
+                Some(v) => v,
+
+                None => return,
+            };
+
+        if (old_urgency, old_incr) == (urgency, incr) {
+            return;
+        }
+
+        // Queue the stream with its new priority first, so that its old entry
+        // is recognized as stale.
+        self.push_flushable(stream_id, urgency, incr);
+
+        let clear = match self.flushable.get_mut(&old_urgency) {
+            Some(queues) => {
+                if !old_incr {
+                    Self::skip_stale_flushable(
+                        &self.streams,
+                        old_urgency,
+                        &mut queues.0,
+                    );
+                } else {
+                    queues.1.retain(|&x| x != stream_id);
+                }
+
+                queues.0.is_empty() && queues.1.is_empty()
+            },
+
+            None => false,
+        };
+
+        if clear {
+            self.flushable.remove(&old_urgency);
+        }
+    }
+
     /// Adds or removes the stream ID to/from the readable streams set.
     ///
     /// If the stream was already in the list, this does nothing.
@@ -562,7 +793,7 @@ impl StreamMap {
 
     /// Returns true if there are any streams that have data to write.
     pub fn has_flushable(&self) -> bool {
-        !self.flushable.is_empty()
+        !self.flushable.is_empty() || !self.fair_flushable.is_empty()
     }
 
     /// Returns true if there are any streams that have data to read.
@@ -637,6 +868,19 @@ pub struct Stream {
 
     /// Whether the stream can be flushed incrementally. Default is `true`.
     pub incremental: bool,
+
+    /// The stream's weight, used by the round-robin schedulers. Default is
+    /// `DEFAULT_WEIGHT`.
+    pub weight: u8,
+
+    /// The credit the stream has left in the current scheduling round, used
+    /// by the round-robin schedulers. A negative value means that the stream
+    /// sent more than its share in previous rounds.
+    pub deficit: i64,
+
+    /// The urgency and incremental flag of the flushable streams queue the
+    /// stream is currently in, if any, used by the strict priority scheduler.
+    pub flushable_queue: Option<(u8, bool)>,
 }
 
 impl Stream {
@@ -653,6 +897,9 @@ impl Stream {
             data: None,
             urgency: DEFAULT_URGENCY,
             incremental: true,
+            weight: DEFAULT_WEIGHT,
+            deficit: 0,
+            flushable_queue: None,
         }
     }
 
@@ -688,7 +935,7 @@ impl Stream {
         match (self.bidi, self.local) {
             // For bidirectional streams we need to check both receive and send
             // sides for completion.
-            (true, _) => self.recv.is_fin() && self.send.is_complete(),
+            (true, _) => self.recv.is_complete() && self.send.is_complete(),
 
             // For unidirectional streams generated locally, we only need to
             // check the send side for completion.
@@ -696,7 +943,7 @@ impl Stream {
 
             // For unidirectional streams generated by the peer, we only need
             // to check the receive side for completion.
-            (false, false) => self.recv.is_fin(),
+            (false, false) => self.recv.is_complete(),
         }
     }
 }
@@ -770,6 +1017,10 @@ pub struct RecvBuf {
 
     /// Whether incoming data is validated but not buffered.
     drain: bool,
+
+    /// The number of bytes handed over to the application without copying,
+    /// that it has not released yet.
+    lent: u64,
 }
 
 impl RecvBuf {
@@ -950,6 +1201,82 @@ impl RecvBuf {
         Ok((len, self.is_fin()))
     }
 
+    /// Takes the next chunk of contiguous data out of the receive buffer,
+    /// without copying it.
+    ///
+    /// At most `max_len` bytes are returned, and the remaining data in the
+    /// chunk is left in the buffer. If there is no data at the expected read
+    /// offset, the `Done` error is returned, and if `max_len` is zero the
+    /// `BufferTooShort` error is returned.
+    ///
+    /// The returned data is not accounted for flow control until it is
+    /// released with [`consume()`].
+    ///
+    /// On success the chunk of data, and a flag indicating if there is no more
+    /// data in the buffer, are returned as a tuple.
+    ///
+    /// [`consume()`]: struct.RecvBuf.html#method.consume
+    pub fn emit_shared(&mut self, max_len: usize) -> Result<(SharedBuf, bool)> {
+        if max_len == 0 {
+            return Err(Error::BufferTooShort);
+        }
+
+        if !self.ready() {
+            return Err(Error::Done);
+        }
+
+        // The stream was reset, so return the error code instead.
+        if let Some(e) = self.error {
+            return Err(Error::StreamReset(e));
+        }
+
+        let out = {
+            let mut buf = match self.data.peek_mut() {
+                Some(v) => v,
+
+                None => return Err(Error::Done),
+            };
+
+            let buf_len = cmp::min(buf.len(), max_len);
+
+            let out = buf.to_shared(buf_len);
+
+            if buf_len < buf.len() {
+                buf.consume(buf_len);
+            } else {
+                std::collections::binary_heap::PeekMut::pop(buf);
+            }
+
+            out
+        };
+
+        self.off += out.len() as u64;
+
+        self.lent += out.len() as u64;
+
+        Ok((out, self.is_fin()))
+    }
+
+    /// Releases `len` bytes of data previously returned by [`emit_shared()`],
+    /// so that they are accounted for flow control.
+    ///
+    /// Returns `false`, without releasing anything, if more data is released
+    /// than was returned.
+    ///
+    /// [`emit_shared()`]: struct.RecvBuf.html#method.emit_shared
+    pub fn consume(&mut self, len: usize) -> bool {
+        if len as u64 > self.lent {
+            return false;
+        }
+
+        self.lent -= len as u64;
+
+        // Update consumed bytes for flow control.
+        self.flow_control.add_consumed(len as u64);
+
+        true
+    }
+
     /// Resets the stream at the given offset.
     pub fn reset(&mut self, error_code: u64, final_size: u64) -> Result<usize> {
         // Stream's size is already known, forbid changing it.
@@ -1054,6 +1381,12 @@ impl RecvBuf {
         false
     }
 
+    /// Returns true if the receive-side of the stream is complete, and the
+    /// application released all the data it was handed without copying.
+    pub fn is_complete(&self) -> bool {
+        self.is_fin() && self.lent == 0
+    }
+
     /// Returns true if the stream has data to be read.
     fn ready(&self) -> bool {
         let buf = match self.data.peek() {
@@ -1122,17 +1455,41 @@ impl SendBuf {
     /// The number of bytes that were actually stored in the buffer is returned
     /// (this may be lower than the size of the input buffer, in case of partial
     /// writes).
-    pub fn write(&mut self, mut data: &[u8], mut fin: bool) -> Result<usize> {
-        let max_off = self.off + data.len() as u64;
+    pub fn write(&mut self, data: &[u8], fin: bool) -> Result<usize> {
+        self.write_with(data.len(), fin, |range, off, fin| {
+            RangeBuf::from(&data[range], off, fin)
+        })
+    }
+
+    /// Inserts the given shared buffer at the end of the buffer, without
+    /// copying its data.
+    ///
+    /// The number of bytes that were actually stored in the buffer is returned
+    /// (this may be lower than the size of the input buffer, in case of partial
+    /// writes).
+    pub fn write_shared(&mut self, data: &SharedBuf, fin: bool) -> Result<usize> {
+        self.write_with(data.len(), fin, |range, off, fin| {
+            RangeBuf::from_shared(data, range, off, fin)
+        })
+    }
+
+    /// Inserts `len` bytes of data at the end of the buffer, using `make_buf`
+    /// to create a `RangeBuf` for each range of the input data.
+    fn write_with<F>(
+        &mut self, mut len: usize, mut fin: bool, mut make_buf: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(std::ops::Range<usize>, u64, bool) -> RangeBuf,
+    {
+        let max_off = self.off + len as u64;
 
         // Get the stream send capacity. This will return an error if the stream
         // was stopped.
         let capacity = self.cap()?;
 
-        if data.len() > capacity {
+        if len > capacity {
             // Truncate the input buffer according to the stream's capacity.
-            let len = capacity;
-            data = &data[..len];
+            len = capacity;
 
             // We are not buffering the full input, so clear the fin flag.
             fin = false;
@@ -1156,34 +1513,36 @@ impl SendBuf {
 
         // Don't queue data that was already fully acked.
         if self.ack_off() >= max_off {
-            return Ok(data.len());
+            return Ok(len);
         }
 
         // We already recorded the final offset, so we can just discard the
         // empty buffer now.
-        if data.is_empty() {
-            return Ok(data.len());
+        if len == 0 {
+            return Ok(len);
         }
 
-        let mut len = 0;
+        let mut written = 0;
 
         // Split the remaining input data into consistently-sized buffers to
         // avoid fragmentation.
-        for chunk in data.chunks(SEND_BUFFER_SIZE) {
-            len += chunk.len();
+        while written < len {
+            let chunk_len = cmp::min(len - written, SEND_BUFFER_SIZE);
 
-            let fin = len == data.len() && fin;
+            let fin = written + chunk_len == len && fin;
 
-            let buf = RangeBuf::from(chunk, self.off, fin);
+            let buf = make_buf(written..written + chunk_len, self.off, fin);
 
             // The new data can simply be appended at the end of the send buffer.
             self.data.push_back(buf);
 
-            self.off += chunk.len() as u64;
-            self.len += chunk.len() as u64;
+            written += chunk_len;
+
+            self.off += chunk_len as u64;
+            self.len += chunk_len as u64;
         }
 
-        Ok(len)
+        Ok(written)
     }
 
     /// Writes data from the send buffer into the given output buffer.
@@ -1193,11 +1552,7 @@ impl SendBuf {
 
         let mut next_off = out_off;
 
-        while out_len > 0 &&
-            self.ready() &&
-            self.off_front() == next_off &&
-            self.off_front() < self.max_data
-        {
+        while out_len > 0 {
             let buf = match self.data.get_mut(self.pos) {
                 Some(v) => v,
 
@@ -1209,6 +1564,14 @@ impl SendBuf {
                 continue;
             }
 
+            // Only emit contiguous data, up to the flow control limit. This is
+            // checked against the buffer directly, rather than by calling
+            // `off_front()` which would need to skip all the empty buffers
+            // again on each iteration.
+            if buf.off() != next_off || buf.off() >= self.max_data {
+                break;
+            }
+
             let buf_len = cmp::min(buf.len(), out_len);
             let partial = buf_len < buf.len();
 
@@ -1486,28 +1849,150 @@ impl SendBuf {
     }
 }
 
+/// A buffer whose content can be sent on a stream without being copied.
+///
+/// This is implemented for common owned byte buffer types, and applications
+/// can implement it for their own types (e.g. memory-mapped files) in order to
+/// use them with [`stream_send_zc()`].
+///
+/// [`stream_send_zc()`]: struct.Connection.html#method.stream_send_zc
+pub trait StreamBuf: Send + Sync + 'static {
+    /// Returns the content of the buffer.
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl StreamBuf for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl StreamBuf for Box<[u8]> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl StreamBuf for Arc<[u8]> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl StreamBuf for Arc<Vec<u8>> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl StreamBuf for &'static [u8] {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// A reference-counted view into a [`StreamBuf`].
+///
+/// Cloning a `SharedBuf` doesn't copy the underlying data, so the same buffer
+/// can be queued on a stream and kept by the application at the same time,
+/// for example to retry sending the rest of it after a partial write.
+///
+/// [`StreamBuf`]: trait.StreamBuf.html
+#[derive(Clone)]
+pub struct SharedBuf {
+    /// The underlying buffer.
+    data: Arc<dyn StreamBuf>,
+
+    /// The initial offset of the view within the underlying buffer.
+    start: usize,
+
+    /// The final offset of the view within the underlying buffer.
+    end: usize,
+}
+
+impl SharedBuf {
+    /// Creates a new `SharedBuf` taking ownership of the given buffer.
+    pub fn new<B: StreamBuf>(buf: B) -> SharedBuf {
+        let end = buf.as_bytes().len();
+
+        SharedBuf {
+            data: Arc::new(buf),
+            start: 0,
+            end,
+        }
+    }
+
+    /// Returns the length of the buffer.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns true if the buffer has a length of zero bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the starting `count` bytes from the buffer.
+    ///
+    /// This is typically used after a partial write, to retry sending the
+    /// remaining data.
+    pub fn advance(&mut self, count: usize) {
+        assert!(
+            count <= self.len(),
+            "`count` (is {}) should be <= len (is {})",
+            count,
+            self.len()
+        );
+
+        self.start += count;
+    }
+
+    /// Shortens the buffer, keeping the first `len` bytes.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.end = cmp::min(self.end, self.start + len);
+    }
+}
+
+impl std::ops::Deref for SharedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data.as_bytes()[self.start..self.end]
+    }
+}
+
+impl std::fmt::Debug for SharedBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SharedBuf")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
 /// Buffer holding data at a specific offset.
 ///
-/// The data is stored in a `Vec<u8>` in such a way that it can be shared
-/// between multiple `RangeBuf` objects.
+/// The data is stored in a reference-counted `StreamBuf` in such a way that it
+/// can be shared between multiple `RangeBuf` objects, as well as with the
+/// application when using zero-copy sends.
 ///
 /// Each `RangeBuf` will have its own view of that buffer, where the `start`
-/// value indicates the initial offset within the `Vec`, and `len` indicates the
-/// number of bytes, starting from `start` that are included.
+/// value indicates the initial offset within the buffer, and `len` indicates
+/// the number of bytes, starting from `start` that are included.
 ///
-/// In addition, `pos` indicates the current offset within the `Vec`, starting
-/// from the very beginning of the `Vec`.
+/// In addition, `pos` indicates the current offset within the buffer, starting
+/// from the very beginning of the buffer.
 ///
 /// Finally, `off` is the starting offset for the specific `RangeBuf` within the
 /// stream the buffer belongs to.
-#[derive(Clone, Debug, Default, Eq)]
+#[derive(Clone)]
 pub struct RangeBuf {
     /// The internal buffer holding the data.
     ///
     /// To avoid needless allocations when a RangeBuf is split, this field is
     /// reference-counted and can be shared between multiple RangeBuf objects,
     /// and sliced using the `start` and `len` values.
-    data: Arc<Vec<u8>>,
+    data: Arc<dyn StreamBuf>,
 
     /// The initial offset within the internal buffer.
     start: usize,
@@ -1538,6 +2023,21 @@ impl RangeBuf {
         }
     }
 
+    /// Creates a new `RangeBuf` from the given range of a shared buffer,
+    /// without copying the data.
+    pub fn from_shared(
+        buf: &SharedBuf, range: std::ops::Range<usize>, off: u64, fin: bool,
+    ) -> RangeBuf {
+        RangeBuf {
+            data: buf.data.clone(),
+            start: buf.start + range.start,
+            pos: buf.start + range.start,
+            len: range.len(),
+            off,
+            fin,
+        }
+    }
+
     /// Returns whether `self` holds the final offset in the stream.
     pub fn fin(&self) -> bool {
         self.fin
@@ -1545,7 +2045,7 @@ impl RangeBuf {
 
     /// Returns the starting offset of `self`.
     pub fn off(&self) -> u64 {
-        (self.off - self.start as u64) + self.pos as u64
+        self.off + (self.pos - self.start) as u64
     }
 
     /// Returns the final offset of `self`.
@@ -1563,6 +2063,18 @@ impl RangeBuf {
         self.len() == 0
     }
 
+    /// Returns a `SharedBuf` viewing the starting `len` bytes of `self`,
+    /// without copying them.
+    pub fn to_shared(&self, len: usize) -> SharedBuf {
+        let len = cmp::min(len, self.len());
+
+        SharedBuf {
+            data: self.data.clone(),
+            start: self.pos,
+            end: self.pos + len,
+        }
+    }
+
     /// Consumes the starting `count` bytes of `self`.
     pub fn consume(&mut self, count: usize) {
         self.pos += count;
@@ -1598,7 +2110,26 @@ impl std::ops::Deref for RangeBuf {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
-        &self.data[self.pos..self.start + self.len]
+        &self.data.as_bytes()[self.pos..self.start + self.len]
+    }
+}
+
+impl std::fmt::Debug for RangeBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RangeBuf")
+            .field("data", &self.data.as_bytes())
+            .field("start", &self.start)
+            .field("pos", &self.pos)
+            .field("len", &self.len)
+            .field("off", &self.off)
+            .field("fin", &self.fin)
+            .finish()
+    }
+}
+
+impl Default for RangeBuf {
+    fn default() -> RangeBuf {
+        RangeBuf::from(&[], 0, false)
     }
 }
 
@@ -1621,6 +2152,8 @@ impl PartialEq for RangeBuf {
     }
 }
 
+impl Eq for RangeBuf {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1728,6 +2261,60 @@ mod tests {
         assert_eq!(recv.emit(&mut buf), Err(Error::Done));
     }
 
+    #[test]
+    fn shared_read() {
+        let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
+        assert_eq!(recv.len, 0);
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 5, false);
+        let third = RangeBuf::from(b"something", 10, true);
+
+        assert!(recv.write(second).is_ok());
+        assert!(recv.write(third).is_ok());
+        assert_eq!(recv.off, 0);
+
+        assert_eq!(recv.emit_shared(32).unwrap_err(), Error::Done);
+
+        assert!(recv.write(first).is_ok());
+        assert_eq!(recv.len, 19);
+        assert_eq!(recv.off, 0);
+
+        assert_eq!(recv.emit_shared(0).unwrap_err(), Error::BufferTooShort);
+
+        // Each chunk is returned separately, in order.
+        let (buf, fin) = recv.emit_shared(32).unwrap();
+        assert_eq!(&buf[..], b"hello");
+        assert_eq!(fin, false);
+        assert_eq!(recv.off, 5);
+
+        // Chunks are split according to the maximum length.
+        let (buf, fin) = recv.emit_shared(3).unwrap();
+        assert_eq!(&buf[..], b"wor");
+        assert_eq!(fin, false);
+        assert_eq!(recv.off, 8);
+
+        let (buf, fin) = recv.emit_shared(32).unwrap();
+        assert_eq!(&buf[..], b"ld");
+        assert_eq!(fin, false);
+        assert_eq!(recv.off, 10);
+
+        let (buf, fin) = recv.emit_shared(32).unwrap();
+        assert_eq!(&buf[..], b"something");
+        assert_eq!(fin, true);
+        assert_eq!(recv.off, 19);
+
+        assert_eq!(recv.emit_shared(32).unwrap_err(), Error::Done);
+
+        // The buffer is only complete once all the data was released.
+        assert!(recv.is_fin());
+        assert!(!recv.is_complete());
+
+        assert!(!recv.consume(20));
+        assert!(recv.consume(19));
+        assert!(recv.is_complete());
+    }
+
     #[test]
     fn split_read() {
         let mut recv = RecvBuf::new(std::u64::MAX, DEFAULT_STREAM_WINDOW);
@@ -3008,6 +3595,101 @@ mod tests {
         assert_eq!(stream.send.data.len(), 2);
     }
 
+    #[test]
+    fn send_emit_retransmit_shared() {
+        let mut buf = [0; 5];
+
+        let mut stream = Stream::new(0, 10, true, true, DEFAULT_STREAM_WINDOW);
+
+        let mut data = SharedBuf::new(b"helloworldolleh".to_vec());
+
+        // Only the data allowed by flow control is queued.
+        assert_eq!(stream.send.write_shared(&data, true), Ok(10));
+        assert_eq!(stream.send.off_front(), 0);
+        assert_eq!(stream.send.data.len(), 2);
+
+        // The data is shared with the send buffer rather than copied.
+        assert_eq!(Arc::strong_count(&data.data), 3);
+
+        data.advance(10);
+        assert_eq!(&data[..], b"olleh");
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf[..4]), Ok((4, false)));
+        assert_eq!(stream.send.off_front(), 4);
+        assert_eq!(&buf[..4], b"hell");
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf[..5]), Ok((5, false)));
+        assert_eq!(stream.send.off_front(), 9);
+        assert_eq!(&buf[..5], b"oworl");
+
+        stream.send.retransmit(3, 4);
+        assert_eq!(stream.send.off_front(), 3);
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf[..4]), Ok((4, false)));
+        assert_eq!(stream.send.off_front(), 9);
+        assert_eq!(&buf[..4], b"lowo");
+
+        stream.send.update_max_data(15);
+
+        assert_eq!(stream.send.write_shared(&data, true), Ok(5));
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf[..5]), Ok((5, false)));
+        assert_eq!(stream.send.off_front(), 14);
+        assert_eq!(&buf[..5], b"dolle");
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf[..5]), Ok((1, true)));
+        assert_eq!(stream.send.off_front(), 15);
+        assert_eq!(&buf[..1], b"h");
+
+        // Once all data is acked, the send buffer releases the shared buffer.
+        stream.send.ack_and_drop(0, 15);
+        assert_eq!(stream.send.data.len(), 0);
+        assert_eq!(Arc::strong_count(&data.data), 1);
+    }
+
+    #[test]
+    fn send_emit_retransmit_many_buffers() {
+        let mut buf = [0; 64];
+
+        let mut stream =
+            Stream::new(0, 100_000, true, true, DEFAULT_STREAM_WINDOW);
+
+        for _ in 0..10_000 {
+            assert_eq!(stream.send.write(b"a", false), Ok(1));
+        }
+
+        assert_eq!(stream.send.write(b"b", true), Ok(1));
+
+        let mut emitted = 0;
+
+        while stream.send.ready() {
+            emitted += stream.send.emit(&mut buf).unwrap().0;
+        }
+
+        assert_eq!(emitted, 10_001);
+
+        // Only the retransmitted data is emitted, and the empty buffers that
+        // follow it are skipped.
+        stream.send.retransmit(2, 2);
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf), Ok((2, false)));
+        assert_eq!(&buf[..2], b"aa");
+        assert!(!stream.send.ready());
+
+        stream.send.retransmit(10_000, 1);
+
+        assert!(stream.send.ready());
+        assert_eq!(stream.send.emit(&mut buf), Ok((1, true)));
+        assert_eq!(&buf[..1], b"b");
+        assert!(!stream.send.ready());
+    }
+
     #[test]
     fn send_emit_retransmit() {
         let mut buf = [0; 5];
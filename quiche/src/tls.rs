@@ -397,6 +397,12 @@ impl Handshake {
         }
     }
 
+    pub fn enable_compatible_version_negotiation(&mut self) {
+        unsafe {
+            SSL_set_cert_cb(self.as_mut_ptr(), select_version, ptr::null_mut());
+        }
+    }
+
     pub fn set_state(&mut self, is_server: bool) {
         unsafe {
             if is_server {
@@ -703,6 +709,14 @@ pub struct ExData<'a> {
     pub trace_id: &'a str,
 
     pub is_server: bool,
+
+    pub version: u32,
+
+    pub original_version: u32,
+
+    pub versions: &'a [u32],
+
+    pub local_transport_params: &'a super::TransportParams,
 }
 
 fn get_ex_data_from_ptr<'a, T>(ptr: *mut SSL, idx: c_int) -> Option<&'a mut T> {
@@ -759,7 +773,15 @@ extern fn set_read_secret(
     if level != crypto::Level::ZeroRTT || ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let open = match crypto::Open::from_secret(aead, secret) {
+        // 0-RTT packets keep using the original version, even when the
+        // connection was upgraded to a compatible version.
+        let version = if level == crypto::Level::ZeroRTT {
+            ex_data.original_version
+        } else {
+            ex_data.version
+        };
+
+        let open = match crypto::Open::from_secret(aead, secret, version) {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -810,7 +832,13 @@ extern fn set_write_secret(
     if level != crypto::Level::ZeroRTT || !ex_data.is_server {
         let secret = unsafe { slice::from_raw_parts(secret, secret_len) };
 
-        let seal = match crypto::Seal::from_secret(aead, secret) {
+        let version = if level == crypto::Level::ZeroRTT {
+            ex_data.original_version
+        } else {
+            ex_data.version
+        };
+
+        let seal = match crypto::Seal::from_secret(aead, secret, version) {
             Ok(v) => v,
 
             Err(_) => return 0,
@@ -958,6 +986,77 @@ extern fn select_alpn(
     3 // SSL_TLSEXT_ERR_NOACK
 }
 
+extern fn select_version(ssl: *mut SSL, _arg: *mut c_void) -> c_int {
+    let ex_data = match get_ex_data_from_ptr::<ExData>(ssl, *QUICHE_EX_DATA_INDEX)
+    {
+        Some(v) => v,
+
+        None => return 0,
+    };
+
+    let mut handshake = Handshake(ssl);
+
+    // The client's transport parameters have already been parsed at this
+    // point, so select the most preferred version it supports.
+    let client_versions = super::TransportParams::decode(
+        handshake.quic_transport_params(),
+        ex_data.is_server,
+    )
+    .ok()
+    .and_then(|tp| tp.version_information)
+    .map(|vi| vi.available_versions)
+    .unwrap_or_default();
+
+    let version = ex_data.versions.iter().cloned().find(|&v| {
+        super::versions_are_compatible(v, ex_data.version) &&
+            (v == ex_data.version || client_versions.contains(&v))
+    });
+
+    match version {
+        Some(version) if version != ex_data.version => {
+            let mut params = ex_data.local_transport_params.clone();
+
+            params.version_information = Some(super::VersionInformation {
+                chosen_version: version,
+                available_versions: super::available_versions(
+                    version,
+                    ex_data.versions,
+                ),
+            });
+
+            let mut raw_params = [0; 256];
+
+            let res = super::TransportParams::encode(
+                &params,
+                ex_data.is_server,
+                &mut raw_params,
+            )
+            .and_then(|raw_params| {
+                handshake.set_quic_transport_params(raw_params)
+            });
+
+            if res.is_err() {
+                std::mem::forget(handshake);
+                return 0;
+            }
+
+            trace!(
+                "{} selected compatible version {:x}",
+                ex_data.trace_id,
+                version
+            );
+
+            ex_data.version = version;
+        },
+
+        _ => (),
+    }
+
+    std::mem::forget(handshake);
+
+    1
+}
+
 extern fn new_session(ssl: *mut SSL, session: *mut SSL_SESSION) -> c_int {
     let ex_data = match get_ex_data_from_ptr::<ExData>(ssl, *QUICHE_EX_DATA_INDEX)
     {
@@ -1220,6 +1319,11 @@ extern {
 
     fn SSL_set_quic_use_legacy_codepoint(ssl: *mut SSL, use_legacy: c_int);
 
+    fn SSL_set_cert_cb(
+        ssl: *mut SSL, cb: extern fn(ssl: *mut SSL, arg: *mut c_void) -> c_int,
+        arg: *mut c_void,
+    );
+
     fn SSL_set_quic_early_data_context(
         ssl: *mut SSL, context: *const u8, context_len: usize,
     ) -> c_int;
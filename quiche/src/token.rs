@@ -0,0 +1,256 @@
+// Copyright (C) 2022, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Address validation tokens.
+//!
+//! Tokens are authenticated and encrypted with a key configured on the
+//! server, and are bound to the client's IP address. They carry an expiration
+//! time, the kind of token, and for tokens sent in Retry packets the original
+//! destination connection ID.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use ring::aead;
+
+use crate::Error;
+use crate::Result;
+
+use crate::packet::ConnectionId;
+use crate::rand;
+
+/// The length of the key used to protect tokens.
+pub const KEY_LEN: usize = 16;
+
+/// The lifetime of tokens sent in Retry packets.
+pub const RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+const RETRY_TOKEN: u8 = 0x00;
+
+const NEW_TOKEN: u8 = 0x01;
+
+/// A validated address validation token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddressToken {
+    /// A token sent in a Retry packet.
+    Retry {
+        /// The original destination connection ID, to be passed to
+        /// [`accept()`].
+        ///
+        /// [`accept()`]: fn.accept.html
+        odcid: ConnectionId<'static>,
+    },
+
+    /// A token sent in a NEW_TOKEN frame during a previous connection.
+    NewToken,
+}
+
+/// Mints a token of the given kind for the client address `peer`.
+fn mint(
+    key: &[u8], kind: u8, odcid: &[u8], peer: &SocketAddr, lifetime: Duration,
+) -> Result<Vec<u8>> {
+    let key = make_key(key)?;
+
+    let expiry = SystemTime::now()
+        .checked_add(lifetime)
+        .ok_or(Error::InvalidState)?
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::InvalidState)?
+        .as_millis() as u64;
+
+    let mut nonce = [0; aead::NONCE_LEN];
+    rand::rand_bytes(&mut nonce);
+
+    let mut payload = Vec::with_capacity(1 + 8 + odcid.len());
+    payload.push(kind);
+    payload.extend_from_slice(&expiry.to_be_bytes());
+    payload.extend_from_slice(odcid);
+
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::from(addr_bytes(peer)),
+        &mut payload,
+    )
+    .map_err(|_| Error::CryptoFail)?;
+
+    let mut token = nonce.to_vec();
+    token.extend_from_slice(&payload);
+
+    Ok(token)
+}
+
+/// Mints a token to be sent in a Retry packet.
+pub fn mint_retry_token(
+    key: &[u8], odcid: &ConnectionId, peer: &SocketAddr,
+) -> Result<Vec<u8>> {
+    mint(key, RETRY_TOKEN, odcid, peer, RETRY_TOKEN_LIFETIME)
+}
+
+/// Mints a token to be sent in a NEW_TOKEN frame.
+pub fn mint_new_token(
+    key: &[u8], peer: &SocketAddr, lifetime: Duration,
+) -> Result<Vec<u8>> {
+    mint(key, NEW_TOKEN, &[], peer, lifetime)
+}
+
+/// Validates a token received from the client address `peer`.
+///
+/// Returns `None` if the token was not minted with `key` for the same client
+/// address, or if it expired.
+pub fn validate(
+    key: &[u8], token: &[u8], peer: &SocketAddr,
+) -> Option<AddressToken> {
+    let key = make_key(key).ok()?;
+
+    if token.len() < aead::NONCE_LEN {
+        return None;
+    }
+
+    let (nonce, payload) = token.split_at(aead::NONCE_LEN);
+
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+    let mut payload = payload.to_vec();
+
+    let payload = key
+        .open_in_place(nonce, aead::Aad::from(addr_bytes(peer)), &mut payload)
+        .ok()?;
+
+    if payload.len() < 1 + 8 {
+        return None;
+    }
+
+    let mut expiry = [0; 8];
+    expiry.copy_from_slice(&payload[1..9]);
+    let expiry = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(expiry));
+
+    if SystemTime::now() >= expiry {
+        return None;
+    }
+
+    match payload[0] {
+        RETRY_TOKEN => Some(AddressToken::Retry {
+            odcid: ConnectionId::from_vec(payload[9..].to_vec()),
+        }),
+
+        NEW_TOKEN if payload.len() == 1 + 8 => Some(AddressToken::NewToken),
+
+        _ => None,
+    }
+}
+
+fn make_key(key: &[u8]) -> Result<aead::LessSafeKey> {
+    let key = aead::UnboundKey::new(&aead::AES_128_GCM, key)
+        .map_err(|_| Error::CryptoFail)?;
+
+    Ok(aead::LessSafeKey::new(key))
+}
+
+fn addr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    match addr.ip() {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [0xba; KEY_LEN];
+
+    #[test]
+    fn retry_token() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let odcid = ConnectionId::from_ref(&[0xab; 16]);
+
+        let token = mint_retry_token(&KEY, &odcid, &peer).unwrap();
+
+        assert_eq!(
+            validate(&KEY, &token, &peer),
+            Some(AddressToken::Retry {
+                odcid: odcid.into_owned()
+            })
+        );
+
+        // The port is not part of the token.
+        let peer = "127.0.0.1:4321".parse().unwrap();
+        assert!(validate(&KEY, &token, &peer).is_some());
+    }
+
+    #[test]
+    fn new_token() {
+        let peer = "[::1]:1234".parse().unwrap();
+
+        let token =
+            mint_new_token(&KEY, &peer, Duration::from_secs(3600)).unwrap();
+
+        assert_eq!(validate(&KEY, &token, &peer), Some(AddressToken::NewToken));
+    }
+
+    #[test]
+    fn expired() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+
+        let token = mint_new_token(&KEY, &peer, Duration::ZERO).unwrap();
+
+        assert_eq!(validate(&KEY, &token, &peer), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+
+        let token =
+            mint_new_token(&KEY, &peer, Duration::from_secs(3600)).unwrap();
+
+        // Different client address.
+        let other = "127.0.0.2:1234".parse().unwrap();
+        assert_eq!(validate(&KEY, &token, &other), None);
+
+        // Different key.
+        assert_eq!(validate(&[0xbb; KEY_LEN], &token, &peer), None);
+
+        // Tampered token.
+        let mut tampered = token.clone();
+        tampered[aead::NONCE_LEN] ^= 0x01;
+        assert_eq!(validate(&KEY, &tampered, &peer), None);
+
+        // Truncated token.
+        assert_eq!(validate(&KEY, &token[..4], &peer), None);
+        assert_eq!(validate(&KEY, b"", &peer), None);
+
+        // Invalid key length.
+        assert_eq!(
+            mint_new_token(&[0xba; 3], &peer, Duration::ZERO).err(),
+            Some(Error::CryptoFail)
+        );
+    }
+}